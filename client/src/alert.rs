@@ -0,0 +1,90 @@
+//! Audible alerts for messages that mention a highlight word (our nickname
+//! counts as one) or come from a watched user: the terminal bell, or a sound
+//! file played with `paplay` (`afplay` on macOS, PowerShell's SoundPlayer on
+//! Windows).
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+    process::Stdio,
+};
+use tokio::process::Command;
+
+use protocol::Event;
+
+use crate::{config::Alerts, notify::contains_word};
+
+pub struct Alerter {
+    pub enabled: bool,
+    words: Vec<String>,
+    users: BTreeSet<String>,
+    sound: Option<String>,
+}
+
+impl Alerter {
+    pub fn new(settings: &Alerts) -> Self {
+        Alerter {
+            enabled: settings.enabled,
+            words: settings.words.clone(),
+            users: settings.users.iter().cloned().collect(),
+            sound: settings.sound.clone(),
+        }
+    }
+
+    /// Whether a message in `event` is one to alert on, for user `me`.
+    pub fn matches(&self, me: &str, event: &Event) -> bool {
+        let (Event::Dm { from, body, .. } | Event::ChannelMsg { from, body, .. }) = event else {
+            return false;
+        };
+        from != me && (self.users.contains(from) || contains_word(body, me) || self.words.iter().any(|w| contains_word(body, w)))
+    }
+
+    /// Rings if alerts are on and `event` matches.
+    pub fn event(&self, me: &str, event: &Event) {
+        if self.enabled && self.matches(me, event) {
+            self.ring();
+        }
+    }
+
+    fn ring(&self) {
+        if let Some(sound) = &self.sound {
+            let mut cmd = if cfg!(windows) {
+                // Passed in the environment so the path needs no quoting.
+                let mut cmd = Command::new("powershell");
+                cmd.args(["-NoProfile", "-Command", "(New-Object Media.SoundPlayer $env:RUSTCHAT_SOUND).PlaySync()"]).env("RUSTCHAT_SOUND", sound);
+                cmd
+            } else {
+                let mut cmd = Command::new(if cfg!(target_os = "macos") { "afplay" } else { "paplay" });
+                cmd.arg(sound);
+                cmd
+            };
+            let spawned = cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn();
+            if spawned.is_ok() {
+                return;
+            }
+        }
+        let mut out = io::stdout().lock();
+        let _ = out.write_all(b"\x07");
+        let _ = out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel_msg(from: &str, body: &str) -> Event {
+        Event::ChannelMsg { channel: "#x".into(), id: 1, ts: String::new(), from: from.into(), from_id: 2, body: body.into() }
+    }
+
+    #[test]
+    fn matches_words_users_and_nick() {
+        let settings = Alerts { words: vec!["deploy".into()], users: vec!["boss".into()], ..Alerts::default() };
+        let alerter = Alerter::new(&settings);
+        assert!(alerter.matches("me", &channel_msg("bob", "Deploy is done")));
+        assert!(alerter.matches("me", &channel_msg("bob", "ping me")));
+        assert!(alerter.matches("me", &channel_msg("boss", "anything")));
+        assert!(!alerter.matches("me", &channel_msg("bob", "redeployed")));
+        assert!(!alerter.matches("me", &channel_msg("me", "deploy")));
+    }
+}
@@ -0,0 +1,64 @@
+//! Avatars. `/avatar set <file>` uploads a small image for clients that show
+//! pictures; a terminal can't, so the ones `/avatar get <nick>` fetches are
+//! saved as `<data dir>/avatars/<nick>.<ext>` for an image viewer.
+
+use anyhow::{Context, Result, bail};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use protocol::base64;
+
+use crate::chatlog;
+
+/// Bigger files wouldn't fit in a line the server accepts once encoded.
+/// Servers have their own, smaller limit.
+const MAX_UPLOAD: u64 = 48 * 1024;
+
+/// `path`'s contents as base64, for `AVATAR SET`.
+pub fn read(path: &Path) -> Result<String> {
+    let len = fs::metadata(path).with_context(|| format!("can't read {}", path.display()))?.len();
+    if len > MAX_UPLOAD {
+        bail!("{} is {len} bytes, too big for an avatar", path.display());
+    }
+    let bytes = fs::read(path).with_context(|| format!("can't read {}", path.display()))?;
+    Ok(base64::encode(&bytes))
+}
+
+/// Saves `name`'s avatar under `dir`, returning where.
+pub fn save(dir: &Path, name: &str, image: &str) -> Result<PathBuf> {
+    let Some(bytes) = base64::decode(image) else {
+        bail!("the server sent a broken avatar for {name}");
+    };
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.{}", chatlog::file_name(name), extension(&bytes)));
+    fs::write(&path, bytes).with_context(|| format!("can't write {}", path.display()))?;
+    Ok(path)
+}
+
+fn extension(bytes: &[u8]) -> &'static str {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => "png",
+        [0xff, 0xd8, 0xff, ..] => "jpg",
+        [b'G', b'I', b'F', b'8', ..] => "gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "webp",
+        _ => "bin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_and_saves() {
+        let dir = std::env::temp_dir().join(format!("rustchat-avatar-test-{}", std::process::id()));
+        let image = base64::encode(b"GIF89a....");
+        let path = save(&dir, "bob/../x", &image).unwrap();
+        assert_eq!(path, dir.join("bob_.._x.gif"));
+        assert_eq!(read(&path).unwrap(), image);
+        assert!(save(&dir, "bob", "???").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
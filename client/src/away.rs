@@ -0,0 +1,64 @@
+//! Marks us away after a stretch without input and back again on the next
+//! key. Only an away set here is cleared here; one set with `/away` stays
+//! until `/away` is used again.
+
+use tokio::time::Instant;
+
+use protocol::Command;
+
+use crate::config::Away;
+
+pub struct AutoAway {
+    settings: Away,
+    last_input: Instant,
+    away: bool,
+}
+
+impl AutoAway {
+    pub fn new(settings: &Away) -> Self {
+        AutoAway { settings: settings.clone(), last_input: Instant::now(), away: false }
+    }
+
+    /// When we'll count as idle, if we aren't already away.
+    pub fn deadline(&self) -> Option<Instant> {
+        let after = self.settings.after?;
+        (!self.away).then(|| self.last_input + after)
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.away
+    }
+
+    /// The deadline passed: the command marking us away.
+    pub fn idle(&mut self) -> Command {
+        self.away = true;
+        Command::Away(Some(self.settings.message.clone()))
+    }
+
+    /// Something was typed. Returns the command marking us back if we were
+    /// marked away for being idle.
+    pub fn input(&mut self) -> Option<Command> {
+        self.last_input = Instant::now();
+        std::mem::take(&mut self.away).then_some(Command::Away(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn away_when_idle_and_back_on_input() {
+        let mut away = AutoAway::new(&Away { after: Some(Duration::from_secs(60)), message: "afk".into() });
+        assert_eq!(away.input(), None);
+        assert!(away.deadline().is_some());
+        assert_eq!(away.idle(), Command::Away(Some("afk".into())));
+        assert_eq!(away.deadline(), None);
+        assert_eq!(away.input(), Some(Command::Away(None)));
+        assert_eq!(away.input(), None);
+
+        let never = AutoAway::new(&Away { after: None, message: String::new() });
+        assert_eq!(never.deadline(), None);
+    }
+}
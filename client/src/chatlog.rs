@@ -0,0 +1,151 @@
+//! Logs of what was said, one directory per server and one file per
+//! conversation: `<dir>/<server>/<nick>.log` for DMs, `<dir>/<server>/#chan.log`
+//! for channels and `<dir>/<server>/server.log` for everything else. Lines
+//! are appended with a timestamp. DMs in threads the server says are
+//! ephemeral aren't logged.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use protocol::{Command, Event, Wire};
+
+use crate::clock::Stamp;
+
+const STAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const SERVER_LOG: &str = "server";
+
+pub struct ChatLog {
+    pub enabled: bool,
+    dir: PathBuf,
+    stamp: Stamp,
+    files: HashMap<String, File>,
+    /// Who our ephemeral DM threads are with.
+    ephemeral: HashSet<String>,
+}
+
+impl ChatLog {
+    /// Logs for `server` under `dir`. Nothing is created until something is
+    /// logged.
+    pub fn new(dir: PathBuf, server: &str, enabled: bool) -> Self {
+        ChatLog { enabled, dir: dir.join(file_name(server)), stamp: Stamp::new(STAMP_FORMAT), files: HashMap::new(), ephemeral: HashSet::new() }
+    }
+
+    /// Forgets which threads are ephemeral; the server lists them again
+    /// after logging in.
+    pub fn reconnected(&mut self) {
+        self.ephemeral.clear();
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// A line from the server.
+    pub fn received(&mut self, line: &str) -> io::Result<()> {
+        let event = Event::parse(line.as_bytes(), Wire::Text);
+        let stamp = self.stamp.for_event(event.as_ref());
+        match event {
+            Some(Event::Dm { from, .. }) if self.ephemeral.contains(&from) => Ok(()),
+            Some(Event::Dm { from, body, .. }) => self.write(&from, stamp, &format!("<{from}> {body}")),
+            Some(Event::Ephemeral { name, on }) => {
+                if on {
+                    self.ephemeral.insert(name);
+                } else {
+                    self.ephemeral.remove(&name);
+                }
+                self.write(SERVER_LOG, stamp, line)
+            }
+            Some(Event::ChannelMsg { channel, from, body, .. }) => self.write(&channel, stamp, &format!("<{from}> {body}")),
+            _ => self.write(SERVER_LOG, stamp, line),
+        }
+    }
+
+    /// A line we sent, as `me`.
+    pub fn sent(&mut self, me: &str, line: &str) -> io::Result<()> {
+        let stamp = self.stamp.now();
+        match Command::parse(line.as_bytes(), Wire::Text) {
+            Command::To { name, .. } if self.ephemeral.contains(&name) => Ok(()),
+            Command::To { name, body } => self.write(&name, stamp, &format!("<{me}> {body}")),
+            // The server sends channel messages back to their sender too;
+            // they're logged when that arrives.
+            Command::Say { .. } => Ok(()),
+            _ => self.write(SERVER_LOG, stamp, &format!("> {line}")),
+        }
+    }
+
+    fn write(&mut self, conversation: &str, stamp: Option<String>, text: &str) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let file = match self.files.get_mut(conversation) {
+            Some(file) => file,
+            None => {
+                fs::create_dir_all(&self.dir)?;
+                let path = self.dir.join(format!("{}.log", file_name(conversation)));
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                self.files.entry(conversation.to_string()).or_insert(file)
+            }
+        };
+        writeln!(file, "{} {text}", stamp.unwrap_or_default())
+    }
+}
+
+/// `name` made safe to use as a file name: nothing that could leave the
+/// directory or hide the file.
+pub fn file_name(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_alphanumeric() || "#-_.".contains(c) { c } else { '_' }).collect();
+    if name.starts_with('.') || name.is_empty() { format!("_{name}") } else { name }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_file_names() {
+        assert_eq!(file_name("192.0.2.2:5555"), "192.0.2.2_5555");
+        assert_eq!(file_name("#rust"), "#rust");
+        assert_eq!(file_name("../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(file_name(""), "_");
+    }
+
+    #[test]
+    fn one_file_per_conversation() {
+        let dir = std::env::temp_dir().join(format!("rustchat-log-test-{}", std::process::id()));
+        let mut log = ChatLog::new(dir.clone(), "host:1", true);
+        log.received("[2026-10-15T08:30:05Z] #4 seq=2 from bob(7): hi").unwrap();
+        log.sent("me", "TO bob hello").unwrap();
+        log.sent("me", "SAY #rust yo").unwrap();
+        log.received("[2026-10-15T08:30:06Z] #5 in #rust from me(1): yo").unwrap();
+        log.enabled = false;
+        log.received("NOTICE ignored").unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join("host_1").join(name)).unwrap();
+        let bob = read("bob.log");
+        let lines: Vec<&str> = bob.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" <bob> hi") && lines[1].ends_with(" <me> hello"), "{bob}");
+        let rust = read("#rust.log");
+        assert!(rust.lines().count() == 1 && rust.ends_with(" <me> yo\n"), "{rust}");
+        assert!(!dir.join("host_1").join("server.log").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ephemeral_threads_are_not_logged() {
+        let dir = std::env::temp_dir().join(format!("rustchat-log-ephemeral-test-{}", std::process::id()));
+        let mut log = ChatLog::new(dir.clone(), "host:1", true);
+        log.received("EPHEMERAL on bob").unwrap();
+        log.received("[2026-10-15T08:30:05Z] #4 seq=0 from bob(7): gone soon").unwrap();
+        log.sent("me", "TO bob same here").unwrap();
+        assert!(!dir.join("host_1").join("bob.log").exists());
+        log.received("EPHEMERAL off bob").unwrap();
+        log.sent("me", "TO bob kept").unwrap();
+        assert!(fs::read_to_string(dir.join("host_1").join("bob.log")).unwrap().ends_with(" <me> kept\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+//! Timestamps on displayed messages, in local time. Messages that carry a
+//! server timestamp show when the server stamped them; everything else shows
+//! when it arrived.
+
+use std::{
+    ffi::CString,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use protocol::Event;
+
+pub const DEFAULT_FORMAT: &str = "%H:%M";
+
+/// A strftime format; empty turns timestamps off.
+pub struct Stamp {
+    format: Option<CString>,
+}
+
+impl Stamp {
+    pub fn new(format: &str) -> Self {
+        Stamp { format: CString::new(format).ok().filter(|f| !f.is_empty()) }
+    }
+
+    /// The time to show for `event`, if timestamps are on.
+    pub fn for_event(&self, event: Option<&Event>) -> Option<String> {
+        let ts = match event {
+            Some(Event::Dm { ts, .. } | Event::ChannelMsg { ts, .. }) => parse_rfc3339(ts),
+            _ => None,
+        };
+        self.at(ts.unwrap_or_else(now))
+    }
+
+    pub fn now(&self) -> Option<String> {
+        self.at(now())
+    }
+
+    fn at(&self, secs: i64) -> Option<String> {
+        let format = self.format.as_ref()?;
+        let time = secs as libc::time_t;
+        // SAFETY: tm is plain data filled in by localtime_r; strftime writes
+        // at most buf.len() bytes and returns how many it wrote.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if !localtime(time, &mut tm) {
+            return None;
+        }
+        let mut buf = [0u8; 128];
+        let len = unsafe { strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm) };
+        Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+}
+
+#[cfg(unix)]
+use libc::strftime;
+
+// The C runtime has strftime on Windows too; the libc crate just doesn't
+// declare it there.
+#[cfg(windows)]
+unsafe extern "C" {
+    fn strftime(buf: *mut std::ffi::c_char, len: usize, format: *const std::ffi::c_char, tm: *const libc::tm) -> usize;
+}
+
+#[cfg(unix)]
+fn localtime(time: libc::time_t, tm: &mut libc::tm) -> bool {
+    !unsafe { libc::localtime_r(&time, tm) }.is_null()
+}
+
+#[cfg(windows)]
+fn localtime(time: libc::time_t, tm: &mut libc::tm) -> bool {
+    unsafe { libc::localtime_s(tm, &time) == 0 }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Unix seconds from a UTC timestamp like `2026-10-15T08:30:00Z`, the form the
+/// server sends.
+fn parse_rfc3339(s: &str) -> Option<i64> {
+    let (date, time) = s.strip_suffix('Z')?.split_once('T')?;
+    let mut d = date.splitn(3, '-').map(str::parse::<i64>);
+    let (y, m, day) = (d.next()?.ok()?, d.next()?.ok()?, d.next()?.ok()?);
+    let mut t = time.splitn(3, ':').map(str::parse::<i64>);
+    let (h, min, sec) = (t.next()?.ok()?, t.next()?.ok()?, t.next()?.ok()?);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&day) || h > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+    Some(days_from_civil(y, m, day) * 86_400 + h * 3600 + min * 60 + sec)
+}
+
+// Howard Hinnant's days_from_civil, as in the server's clock module.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_server_timestamps() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2026-10-15T08:30:05Z"), Some(1_792_053_005));
+        assert_eq!(parse_rfc3339("2026-10-15 08:30:05"), None);
+        assert_eq!(parse_rfc3339("2026-13-15T08:30:05Z"), None);
+    }
+
+    #[test]
+    fn empty_format_means_no_stamp() {
+        assert_eq!(Stamp::new("").now(), None);
+        assert_eq!(Stamp::new("x%%").now().as_deref(), Some("x%"));
+    }
+}
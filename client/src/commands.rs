@@ -0,0 +1,475 @@
+//! Slash commands typed at the input line, like `/msg bob hi` or `/join #rust`.
+//! Arguments are checked here and turned into wire commands, so a typo gets a
+//! usage line straight away instead of a round trip to the server. Anything
+//! not starting with `/` goes to the server as typed.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use protocol::{AvatarCommand, Command, ExportArgs, FriendCommand, SeqRange, Wire};
+
+pub struct Spec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub about: &'static str,
+}
+
+pub const COMMANDS: &[Spec] = &[
+    Spec { name: "msg", usage: "/msg <nick> <text>", about: "direct message" },
+    Spec { name: "msgid", usage: "/msgid <id> <text>", about: "direct message by connection id" },
+    Spec { name: "say", usage: "/say <#channel> <text>", about: "message a channel" },
+    Spec { name: "join", usage: "/join <#channel> [key]", about: "join a channel" },
+    Spec { name: "part", usage: "/part <#channel>", about: "leave a channel" },
+    Spec { name: "topic", usage: "/topic <#channel> [text]", about: "show a channel's topic; with text, set it (ops)" },
+    Spec { name: "history", usage: "/history <#channel|@nick> [before:<id>] [n]", about: "replay recent messages of a channel or DMs with nick" },
+    Spec { name: "find", usage: "/find <text> [in:#channel] [from:nick] [n]", about: "search your DMs and channels on the server" },
+    Spec { name: "poll", usage: "/poll <#channel> [for <delay>] \"question\" \"option\" \"option\"...", about: "open a poll that closes after the delay, 10m by default" },
+    Spec { name: "vote", usage: "/vote <poll id> <n>", about: "vote for option n in a poll, once" },
+    Spec { name: "voice", usage: "/voice join <#channel> | leave", about: "join a channel's voice session (experimental; audio needs a client that speaks it), or leave" },
+    Spec { name: "upload", usage: "/upload", about: "get a token and the command to upload a file to share; paste the link it answers with" },
+    Spec { name: "register", usage: "/register <password>", about: "register your nick so it needs the password to use, or change it; put the password in your profile" },
+    Spec { name: "signkey", usage: "/signkey [hex public key]", about: "have messages a bot signs with this Ed25519 key marked verified; alone, remove it" },
+    Spec { name: "2fa", usage: "/2fa enable | <code> | disable <code>", about: "get a secret for an authenticator app, confirm it with a code, or turn two-factor off" },
+    Spec { name: "schedule", usage: "/schedule <time> <nick> <message>", about: "have the server send nick a DM at an RFC 3339 time, like 2026-10-15T18:00:00+02:00" },
+    Spec { name: "remind", usage: "/remind me|<nick> in <delay> <text>", about: "have the server DM a reminder after a delay like 10m or 1h30m" },
+    Spec { name: "schedules", usage: "/schedules", about: "list your scheduled DMs and reminders" },
+    Spec { name: "unschedule", usage: "/unschedule <id>", about: "cancel a scheduled DM or reminder" },
+    Spec { name: "resend", usage: "/resend <from_seq> [to_seq]", about: "replay received DMs" },
+    Spec { name: "away", usage: "/away [message]", about: "mark yourself away; alone, back again" },
+    Spec { name: "dnd", usage: "/dnd on|off [allow:friends] [allow:mentions]", about: "have the server hold messages until you're back" },
+    Spec { name: "ephemeral", usage: "/ephemeral on|off <nick>", about: "stop (or go back to) keeping your DMs with nick, for both of you" },
+    Spec { name: "push", usage: "/push [http://host/topic]", about: "get DMs sent while you're offline pushed to an ntfy-style URL; alone, stop" },
+    Spec { name: "whois", usage: "/whois <nick>", about: "whether nick is online, and their profile" },
+    Spec { name: "profile", usage: "/profile set <field> [value] | get <nick>", about: "fill in your profile (realname, pronouns, timezone, bio), or see nick's" },
+    Spec { name: "watch", usage: "/watch [nick]", about: "hear when nick comes, goes or is away; alone, list who you watch" },
+    Spec { name: "unwatch", usage: "/unwatch <nick>", about: "stop watching nick" },
+    Spec { name: "friend", usage: "/friend [add|remove <nick>]", about: "ask nick to be friends (or accept), or drop them; alone, list friends" },
+    Spec { name: "avatar", usage: "/avatar set <image file> | clear | get <nick>", about: "upload a small picture of yourself, or save nick's" },
+    Spec { name: "invite", usage: "/invite [#channel] [once] [secs]", about: "get a link others can connect with" },
+    Spec { name: "ping", usage: "/ping", about: "time a round trip to the server" },
+    Spec { name: "exportme", usage: "/exportme", about: "dump your own data" },
+    Spec { name: "kick", usage: "/kick <nick>", about: "disconnect a user (admin)" },
+    Spec { name: "kickid", usage: "/kickid <id>", about: "disconnect a connection (admin)" },
+    Spec { name: "access", usage: "/access [allow|deny <cidr>]", about: "list who may connect, or add an address range to a list until restart (admin)" },
+    Spec { name: "connections", usage: "/connections", about: "list every connection with its queue, traffic and last activity (admin)" },
+    Spec { name: "drain", usage: "/drain [secs] [host:port]", about: "stop taking connections and have everyone reconnect, then exit (admin)" },
+    Spec { name: "unlock", usage: "/unlock [nick|ip]", about: "end a lockout after failed logins; alone, list them (admin)" },
+    Spec { name: "export", usage: "/export <nick> json|csv [since [until]]", about: "export history (admin)" },
+    Spec { name: "purge", usage: "/purge <nick|#channel>", about: "delete history (admin)" },
+    Spec { name: "retention", usage: "/retention <#channel> [none|<n> messages|<age>]", about: "show or set how much history a channel keeps (admin)" },
+    Spec { name: "mute", usage: "/mute [nick]", about: "no notifications from nick; alone, list muted" },
+    Spec { name: "unmute", usage: "/unmute <nick>", about: "notifications from nick again" },
+    Spec { name: "block", usage: "/block [nick]", about: "have the server refuse nick's DMs and mentions; alone, list blocked" },
+    Spec { name: "unblock", usage: "/unblock <nick>", about: "let nick reach you again" },
+    Spec { name: "e2e", usage: "/e2e <nick>", about: "swap keys with nick and encrypt DMs end to end" },
+    Spec { name: "fingerprint", usage: "/fingerprint <nick>", about: "show your key and nick's to compare with them" },
+    Spec { name: "trust", usage: "/trust <nick>", about: "accept nick's changed key" },
+    Spec { name: "alerts", usage: "/alerts [on|off]", about: "ring on highlights; alone, show whether it does" },
+    Spec { name: "log", usage: "/log [on|off]", about: "log conversations to files; alone, show where" },
+    Spec { name: "search", usage: "/search [text]", about: "highlight text and jump to it, older each time; alone, clear" },
+    Spec { name: "connect", usage: "/connect <profile|invite link> [2fa code]", about: "open another server in a new tab" },
+    Spec { name: "tab", usage: "/tab [n]", about: "switch to tab n (or Alt-n); alone, the next one" },
+    Spec { name: "close", usage: "/close", about: "disconnect and close this tab" },
+    Spec { name: "help", usage: "/help", about: "list commands" },
+    Spec { name: "quit", usage: "/quit [message]", about: "say goodbye to the server and exit" },
+];
+
+/// Slash commands whose first argument is a nickname.
+pub const TAKES_NAME: &[&str] = &["msg", "whois", "watch", "unwatch", "kick", "export", "purge", "mute", "unmute", "block", "unblock", "e2e", "fingerprint", "trust"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    Send(Command),
+    /// Not a slash command; sent as typed.
+    Raw(String),
+    /// `None` asks for the muted list.
+    Mute(Option<String>),
+    Unmute(String),
+    /// Sends our key to a nick.
+    E2e(String),
+    Fingerprint(String),
+    Trust(String),
+    Ping,
+    /// An image file to upload as our avatar.
+    Avatar(PathBuf),
+    /// `None` asks whether alerts are on.
+    Alerts(Option<bool>),
+    /// `None` asks whether logging is on.
+    Log(Option<bool>),
+    /// `None` clears the search.
+    Search(Option<String>),
+    /// A profile or invite link, and a two-factor code for the profile.
+    Connect(String, Option<String>),
+    /// 1-based; `None` is the next tab.
+    Tab(Option<usize>),
+    Close,
+    Help,
+    /// With a parting message for the server.
+    Quit(Option<String>),
+}
+
+/// Reads one input line. `Err` is a message for the user; nothing was sent.
+pub fn parse(line: &str) -> Result<Input, String> {
+    let Some(rest) = line.strip_prefix('/') else {
+        return Ok(Input::Raw(line.to_string()));
+    };
+    let (name, args) = rest.split_once(' ').unwrap_or((rest, ""));
+    let spec = COMMANDS
+        .iter()
+        .find(|s| s.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("unknown command /{name}; /help lists them"))?;
+    let usage = || format!("usage: {}", spec.usage);
+    let (first, rest) = split_arg(args);
+
+    let cmd = match spec.name {
+        "msg" if is_nick(first) && !rest.is_empty() => Command::To { name: first.into(), body: rest.into() },
+        "msgid" if !rest.is_empty() => Command::ToId { id: first.parse().map_err(|_| usage())?, body: rest.into() },
+        "say" if !rest.is_empty() => Command::Say { channel: channel(first)?, body: rest.into() },
+        "join" if rest.split_whitespace().count() <= 1 => Command::Join { channel: channel(first)?, key: rest.split_whitespace().next().map(str::to_string) },
+        "part" if rest.is_empty() => Command::Part(channel(first)?),
+        "topic" => Command::Topic { channel: channel(first)?, topic: Some(rest).filter(|t| !t.is_empty()).map(str::to_string) },
+        "history" => {
+            let args = protocol::parse_history(args).ok_or_else(usage)?;
+            if !args.target.strip_prefix('@').is_some_and(is_nick) {
+                channel(&args.target)?;
+            }
+            Command::History(args)
+        }
+        "retention" => Command::Retention { channel: channel(first)?, policy: Some(rest).filter(|p| !p.is_empty()).map(str::to_string) },
+        "resend" => {
+            let mut p = args.split_whitespace().map(str::parse::<u64>);
+            let range = match (p.next(), p.next(), p.next()) {
+                (Some(Ok(from)), None, None) => SeqRange { from, to: None },
+                (Some(Ok(from)), Some(Ok(to)), None) if to >= from => SeqRange { from, to: Some(to) },
+                _ => return Err(usage()),
+            };
+            Command::Resend(Some(range))
+        }
+        "find" => Command::Search(protocol::parse_search(args).ok_or_else(usage)?),
+        "invite" => Command::Invite(protocol::parse_invite(args).ok_or_else(usage)?),
+        "whois" if is_nick(first) && rest.is_empty() => Command::Whois(first.into()),
+        "watch" if rest.is_empty() => Command::Watch(Some(first).filter(|n| !n.is_empty()).map(str::to_string)),
+        "unwatch" if is_nick(first) && rest.is_empty() => Command::Unwatch(first.into()),
+        "block" if rest.is_empty() => Command::Block(Some(first).filter(|n| !n.is_empty()).map(str::to_string)),
+        "unblock" if is_nick(first) && rest.is_empty() => Command::Unblock(first.into()),
+        "friend" => match (first, rest) {
+            ("" | "list", "") => Command::Friend(FriendCommand::List),
+            ("add", nick) if is_nick(nick) => Command::Friend(FriendCommand::Add(nick.into())),
+            ("remove", nick) if is_nick(nick) => Command::Friend(FriendCommand::Remove(nick.into())),
+            _ => return Err(usage()),
+        },
+        "profile" => Command::Profile(protocol::parse_profile(args).ok_or_else(usage)?),
+        "avatar" => match (first, rest) {
+            ("set", path) if !path.is_empty() => return Ok(Input::Avatar(PathBuf::from(path))),
+            ("clear", "") => Command::Avatar(AvatarCommand::Set(None)),
+            ("get", nick) if is_nick(nick) => Command::Avatar(AvatarCommand::Get(nick.into())),
+            _ => return Err(usage()),
+        },
+        "ephemeral" => match (first, rest) {
+            ("on" | "off", nick) if is_nick(nick) => Command::Ephemeral { name: nick.into(), on: first == "on" },
+            _ => return Err(usage()),
+        },
+        "schedule" => match rest.split_once(' ') {
+            Some((nick, body)) if is_nick(nick) && !body.trim().is_empty() => {
+                Command::Schedule { at: first.into(), name: nick.into(), body: body.trim().into() }
+            }
+            _ => return Err(usage()),
+        },
+        "remind" => match Command::parse(format!("REMIND {args}").as_bytes(), Wire::Text) {
+            cmd @ Command::Remind { .. } => cmd,
+            _ => return Err(usage()),
+        },
+        "poll" => Command::Poll(protocol::parse_poll(args).filter(|p| p.options.len() >= 2).ok_or_else(usage)?),
+        "vote" => match rest.parse() {
+            Ok(choice) => Command::Vote { poll: first.parse().map_err(|_| usage())?, choice },
+            Err(_) => return Err(usage()),
+        },
+        "upload" if args.trim().is_empty() => Command::Upload,
+        "2fa" => match Command::parse(format!("2FA {args}").as_bytes(), Wire::Text) {
+            cmd @ Command::TwoFactor(_) => cmd,
+            _ => return Err(usage()),
+        },
+        "voice" => match Command::parse(format!("VOICE {args}").as_bytes(), Wire::Text) {
+            cmd @ Command::Voice(_) => cmd,
+            _ => return Err(usage()),
+        },
+        "register" if !args.trim().is_empty() => Command::Register(args.trim().to_string()),
+        "signkey" if rest.is_empty() => Command::SignKey(Some(first).filter(|k| !k.is_empty()).map(str::to_string)),
+        "schedules" if args.trim().is_empty() => Command::Schedules,
+        "unschedule" if rest.is_empty() => Command::Unschedule(first.parse().map_err(|_| usage())?),
+        "push" if rest.is_empty() => Command::Push(Some(first).filter(|u| !u.is_empty()).map(str::to_string)),
+        "dnd" => Command::Dnd(protocol::parse_dnd(args).ok_or_else(usage)?),
+        "away" => Command::Away(Some(args.trim()).filter(|m| !m.is_empty()).map(str::to_string)),
+        "exportme" if args.trim().is_empty() => Command::ExportMe,
+        "kick" if is_nick(first) && rest.is_empty() => Command::Kick(first.into()),
+        "kickid" if rest.is_empty() => Command::KickId(Some(first.parse().map_err(|_| usage())?)),
+        "access" => match Command::parse(format!("ACCESS {args}").as_bytes(), Wire::Text) {
+            cmd @ Command::Access(_) => cmd,
+            _ => return Err(usage()),
+        },
+        "connections" if args.trim().is_empty() => Command::Connections,
+        "drain" => match Command::parse(format!("DRAIN {args}").as_bytes(), Wire::Text) {
+            cmd @ Command::Drain { .. } => cmd,
+            _ => return Err(usage()),
+        },
+        "unlock" if rest.is_empty() => Command::Unlock(Some(first).filter(|t| !t.is_empty()).map(str::to_string)),
+        "export" => {
+            let mut p = args.split_whitespace();
+            let (Some(name), Some(format @ ("json" | "csv"))) = (p.next(), p.next()) else {
+                return Err(usage());
+            };
+            let (since, until) = (p.next().map(str::to_string), p.next().map(str::to_string));
+            if p.next().is_some() {
+                return Err(usage());
+            }
+            Command::Export(Some(ExportArgs { name: name.into(), format: format.into(), since, until }))
+        }
+        "purge" if is_nick(first) && rest.is_empty() => Command::Purge(first.into()),
+        "mute" if first.is_empty() => return Ok(Input::Mute(None)),
+        "mute" if is_nick(first) && rest.is_empty() => return Ok(Input::Mute(Some(first.into()))),
+        "unmute" if is_nick(first) && rest.is_empty() => return Ok(Input::Unmute(first.into())),
+        "e2e" if is_nick(first) && rest.is_empty() => return Ok(Input::E2e(first.into())),
+        "fingerprint" if is_nick(first) && rest.is_empty() => return Ok(Input::Fingerprint(first.into())),
+        "trust" if is_nick(first) && rest.is_empty() => return Ok(Input::Trust(first.into())),
+        "ping" if args.trim().is_empty() => return Ok(Input::Ping),
+        "alerts" => return switch(args).map(Input::Alerts).ok_or_else(usage),
+        "log" => return switch(args).map(Input::Log).ok_or_else(usage),
+        "search" => return Ok(Input::Search(Some(args.trim()).filter(|t| !t.is_empty()).map(str::to_string))),
+        "connect" if !first.is_empty() && !rest.contains(' ') => return Ok(Input::Connect(first.into(), Some(rest).filter(|c| !c.is_empty()).map(str::to_string))),
+        "tab" if first.is_empty() => return Ok(Input::Tab(None)),
+        "tab" if rest.is_empty() => return first.parse().ok().filter(|&n| n > 0).map(|n| Input::Tab(Some(n))).ok_or_else(usage),
+        "close" if first.is_empty() => return Ok(Input::Close),
+        "help" => return Ok(Input::Help),
+        "quit" => return Ok(Input::Quit(Some(args.trim()).filter(|m| !m.is_empty()).map(str::to_string))),
+        _ => return Err(usage()),
+    };
+    Ok(Input::Send(cmd))
+}
+
+/// Expands a user-defined alias (see `config`) in place of `/name`. `$1` to
+/// `$9` are replaced by arguments, `$*` by the arguments not used that way
+/// and `$$` by a dollar sign; with no placeholders the arguments are
+/// appended. The result isn't expanded again, so aliases can't loop.
+pub fn expand(aliases: &BTreeMap<String, String>, line: &str) -> Result<String, String> {
+    let Some(rest) = line.strip_prefix('/') else {
+        return Ok(line.to_string());
+    };
+    let (name, args) = rest.split_once(' ').unwrap_or((rest, ""));
+    let Some(template) = aliases.get(&name.to_lowercase()) else {
+        return Ok(line.to_string());
+    };
+    let args: Vec<&str> = args.split_whitespace().collect();
+
+    // `$*` needs to know which arguments were used by number, wherever
+    // they come in the template, so look for those first.
+    let mut numbered = [false; 9];
+    let mut placeholders = false;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c == '$' {
+            match chars.next() {
+                Some(d @ '1'..='9') => numbered[d as usize - '1' as usize] = true,
+                Some('$' | '*') => {}
+                _ => continue,
+            }
+            placeholders = true;
+        }
+    }
+    if let Some(n) = numbered.iter().rposition(|&used| used).filter(|&n| n >= args.len()) {
+        return Err(format!("/{name} needs at least {} argument(s): {template}", n + 1));
+    }
+    let rest = args.iter().enumerate().filter(|(i, _)| !numbered.get(*i).copied().unwrap_or(false)).map(|(_, arg)| *arg);
+    let rest = rest.collect::<Vec<_>>().join(" ");
+    if !placeholders {
+        return Ok(if rest.is_empty() { template.clone() } else { format!("{template} {rest}") });
+    }
+
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().copied()) {
+            ('$', Some('$')) => out.push('$'),
+            ('$', Some('*')) => out.push_str(&rest),
+            ('$', Some(d @ '1'..='9')) => out.push_str(args[d as usize - '1' as usize]),
+            _ => {
+                out.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    Ok(out)
+}
+
+/// The text line to send for `cmd`.
+pub fn wire_line(cmd: &Command) -> String {
+    String::from_utf8_lossy(&cmd.encode(Wire::Text)).into_owned()
+}
+
+/// The `/help` text, one line per command and then per alias.
+pub fn help(aliases: &BTreeMap<String, String>) -> Vec<String> {
+    let width = COMMANDS.iter().map(|s| s.usage.len()).max().unwrap_or(0);
+    let mut lines: Vec<String> = COMMANDS.iter().map(|s| format!("{:width$}  {}", s.usage, s.about)).collect();
+    lines.extend(aliases.iter().map(|(name, expansion)| format!("{:width$}  alias for {expansion}", format!("/{name}"))));
+    lines
+}
+
+/// `on`, `off` or nothing.
+fn switch(args: &str) -> Option<Option<bool>> {
+    match args.trim() {
+        "" => Some(None),
+        "on" => Some(Some(true)),
+        "off" => Some(Some(false)),
+        _ => None,
+    }
+}
+
+/// The first word and whatever follows it.
+fn split_arg(args: &str) -> (&str, &str) {
+    let args = args.trim_start();
+    let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+    (first, rest.trim_start())
+}
+
+fn is_nick(s: &str) -> bool {
+    !s.is_empty() && !s.chars().any(char::is_control)
+}
+
+fn channel(s: &str) -> Result<String, String> {
+    if protocol::is_channel_name(s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!("{s:?} is not a channel name: # followed by up to 32 characters, no spaces"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{AccessCommand, Dnd, HistoryArgs, InviteArgs, PollArgs, ProfileCommand, SearchArgs, TwoFactorCommand, VoiceCommand};
+
+    #[test]
+    fn translates_to_wire_commands() {
+        assert_eq!(parse("/msg bob hi there"), Ok(Input::Send(Command::To { name: "bob".into(), body: "hi there".into() })));
+        assert_eq!(parse("/MSGID 7 hi"), Ok(Input::Send(Command::ToId { id: 7, body: "hi".into() })));
+        assert_eq!(parse("/join #rust"), Ok(Input::Send(Command::Join { channel: "#rust".into(), key: None })));
+        assert_eq!(parse("/join #staff s3cret"), Ok(Input::Send(Command::Join { channel: "#staff".into(), key: Some("s3cret".into()) })));
+        assert_eq!(
+            parse("/history #rust before:120 20"),
+            Ok(Input::Send(Command::History(HistoryArgs { target: "#rust".into(), before: Some(120), limit: Some(20) })))
+        );
+        assert_eq!(parse("/history @bob"), Ok(Input::Send(Command::History(HistoryArgs { target: "@bob".into(), ..HistoryArgs::default() }))));
+        assert_eq!(
+            parse("/find borrow checker in:#rust 5"),
+            Ok(Input::Send(Command::Search(SearchArgs { query: "borrow checker".into(), channel: Some("#rust".into()), from: None, limit: Some(5) })))
+        );
+        assert_eq!(parse("/topic #rust"), Ok(Input::Send(Command::Topic { channel: "#rust".into(), topic: None })));
+        assert_eq!(parse("/resend 3 9"), Ok(Input::Send(Command::Resend(Some(SeqRange { from: 3, to: Some(9) })))));
+        assert_eq!(parse("/quit"), Ok(Input::Quit(None)));
+        assert_eq!(parse("/quit back tomorrow"), Ok(Input::Quit(Some("back tomorrow".into()))));
+        assert_eq!(parse("/away"), Ok(Input::Send(Command::Away(None))));
+        assert_eq!(parse("/dnd on allow:friends"), Ok(Input::Send(Command::Dnd(Dnd { on: true, friends: true, mentions: false }))));
+        assert!(parse("/dnd maybe").is_err());
+        assert_eq!(parse("/push http://ntfy.lan/bob"), Ok(Input::Send(Command::Push(Some("http://ntfy.lan/bob".into())))));
+        assert_eq!(parse("/push"), Ok(Input::Send(Command::Push(None))));
+        assert_eq!(parse("/ephemeral on bob"), Ok(Input::Send(Command::Ephemeral { name: "bob".into(), on: true })));
+        assert!(parse("/ephemeral bob").is_err());
+        assert_eq!(
+            parse("/schedule 2026-10-15T18:00:00Z bob happy birthday"),
+            Ok(Input::Send(Command::Schedule { at: "2026-10-15T18:00:00Z".into(), name: "bob".into(), body: "happy birthday".into() }))
+        );
+        assert!(parse("/schedule 2026-10-15T18:00:00Z bob").is_err());
+        assert_eq!(parse("/unschedule 3"), Ok(Input::Send(Command::Unschedule(3))));
+        assert_eq!(parse("/remind me in 10m tea"), Ok(Input::Send(Command::Remind { name: None, delay: "10m".into(), text: "tea".into() })));
+        assert!(parse("/remind bob 10m tea").is_err());
+        assert_eq!(
+            parse(r#"/poll #rust "Tabs?" "yes" "no""#),
+            Ok(Input::Send(Command::Poll(PollArgs { channel: "#rust".into(), duration: None, question: "Tabs?".into(), options: vec!["yes".into(), "no".into()] })))
+        );
+        assert!(parse(r#"/poll #rust "Tabs?" "yes""#).is_err());
+        assert_eq!(parse("/vote 4 2"), Ok(Input::Send(Command::Vote { poll: 4, choice: 2 })));
+        assert!(parse("/vote 4").is_err());
+        assert_eq!(parse("/upload"), Ok(Input::Send(Command::Upload)));
+        assert!(parse("/upload photo.jpg").is_err());
+        assert_eq!(parse("/register correct horse"), Ok(Input::Send(Command::Register("correct horse".into()))));
+        assert!(parse("/register").is_err());
+        assert_eq!(parse("/signkey 3d4017c3"), Ok(Input::Send(Command::SignKey(Some("3d4017c3".into())))));
+        assert_eq!(parse("/signkey"), Ok(Input::Send(Command::SignKey(None))));
+        assert_eq!(parse("/unlock 192.0.2.7"), Ok(Input::Send(Command::Unlock(Some("192.0.2.7".into())))));
+        assert_eq!(parse("/unlock"), Ok(Input::Send(Command::Unlock(None))));
+        assert_eq!(parse("/connections"), Ok(Input::Send(Command::Connections)));
+        assert_eq!(parse("/drain 30 chat2.example.com:5555"), Ok(Input::Send(Command::Drain { secs: Some(30), to: Some("chat2.example.com:5555".into()) })));
+        assert_eq!(parse("/drain"), Ok(Input::Send(Command::Drain { secs: None, to: None })));
+        assert!(parse("/drain 30 a:1 b:2").is_err());
+        assert_eq!(parse("/access deny 10.6.6.0/24"), Ok(Input::Send(Command::Access(AccessCommand::Deny("10.6.6.0/24".into())))));
+        assert_eq!(parse("/access"), Ok(Input::Send(Command::Access(AccessCommand::List))));
+        assert!(parse("/access deny").is_err());
+        assert_eq!(parse("/2fa enable"), Ok(Input::Send(Command::TwoFactor(TwoFactorCommand::Enable))));
+        assert_eq!(parse("/2fa 081804"), Ok(Input::Send(Command::TwoFactor(TwoFactorCommand::Code("081804".into())))));
+        assert_eq!(parse("/2fa disable"), Err("usage: /2fa enable | <code> | disable <code>".into()));
+        assert_eq!(parse("/voice join #standup"), Ok(Input::Send(Command::Voice(VoiceCommand::Join("#standup".into())))));
+        assert_eq!(parse("/voice leave"), Ok(Input::Send(Command::Voice(VoiceCommand::Leave))));
+        assert_eq!(parse("/voice join standup"), Err("usage: /voice join <#channel> | leave".into()));
+        assert_eq!(parse("/avatar set me small.png"), Ok(Input::Avatar("me small.png".into())));
+        assert_eq!(parse("/avatar get bob"), Ok(Input::Send(Command::Avatar(AvatarCommand::Get("bob".into())))));
+        assert_eq!(parse("/watch"), Ok(Input::Send(Command::Watch(None))));
+        assert_eq!(parse("/unwatch bob"), Ok(Input::Send(Command::Unwatch("bob".into()))));
+        assert_eq!(parse("/friend"), Ok(Input::Send(Command::Friend(FriendCommand::List))));
+        assert_eq!(parse("/friend add bob"), Ok(Input::Send(Command::Friend(FriendCommand::Add("bob".into())))));
+        assert!(parse("/friend add").is_err());
+        assert_eq!(parse("/whois bob"), Ok(Input::Send(Command::Whois("bob".into()))));
+        assert_eq!(
+            parse("/profile set timezone Europe/Berlin"),
+            Ok(Input::Send(Command::Profile(ProfileCommand::Set { field: "timezone".into(), value: Some("Europe/Berlin".into()) })))
+        );
+        assert_eq!(parse("/mute"), Ok(Input::Mute(None)));
+        assert_eq!(parse("/unmute bob"), Ok(Input::Unmute("bob".into())));
+        assert_eq!(parse("/block mallory"), Ok(Input::Send(Command::Block(Some("mallory".into())))));
+        assert_eq!(parse("/unblock"), Err("usage: /unblock <nick>".into()));
+        assert_eq!(parse("/e2e bob"), Ok(Input::E2e("bob".into())));
+        assert_eq!(parse("/fingerprint bob"), Ok(Input::Fingerprint("bob".into())));
+        assert_eq!(parse("/ping"), Ok(Input::Ping));
+        assert_eq!(
+            parse("/invite #rust once"),
+            Ok(Input::Send(Command::Invite(InviteArgs { channel: Some("#rust".into()), once: true, ttl_secs: None })))
+        );
+        assert_eq!(parse("/alerts off"), Ok(Input::Alerts(Some(false))));
+        assert_eq!(parse("/log"), Ok(Input::Log(None)));
+        assert_eq!(parse("/search  two words "), Ok(Input::Search(Some("two words".into()))));
+        assert_eq!(parse("/connect work"), Ok(Input::Connect("work".into(), None)));
+        assert_eq!(parse("/connect work 081804"), Ok(Input::Connect("work".into(), Some("081804".into()))));
+        assert_eq!(parse("/tab 2"), Ok(Input::Tab(Some(2))));
+        assert_eq!(parse("TO bob hi"), Ok(Input::Raw("TO bob hi".into())));
+    }
+
+    #[test]
+    fn expands_aliases() {
+        let aliases = BTreeMap::from([
+            ("b".to_string(), "/msg bob".to_string()),
+            ("tell".to_string(), "/msg $1 [$2] $*".to_string()),
+            ("cost".to_string(), "/say #shop $$5 for $1".to_string()),
+        ]);
+        assert_eq!(expand(&aliases, "/b hi there"), Ok("/msg bob hi there".into()));
+        assert_eq!(expand(&aliases, "/B"), Ok("/msg bob".into()));
+        assert_eq!(expand(&aliases, "/tell carol urgent call me"), Ok("/msg carol [urgent] call me".into()));
+        assert_eq!(expand(&aliases, "/cost tea"), Ok("/say #shop $5 for tea".into()));
+        assert!(expand(&aliases, "/tell carol").unwrap_err().contains("at least 2"));
+        assert_eq!(expand(&aliases, "/msg b hi"), Ok("/msg b hi".into()));
+    }
+
+    #[test]
+    fn rejects_bad_arguments_locally() {
+        assert_eq!(parse("/msg bob"), Err("usage: /msg <nick> <text>".into()));
+        assert_eq!(parse("/kickid seven"), Err("usage: /kickid <id>".into()));
+        assert_eq!(parse("/resend 9 3"), Err("usage: /resend <from_seq> [to_seq]".into()));
+        assert_eq!(parse("/log maybe"), Err("usage: /log [on|off]".into()));
+        assert_eq!(parse("/tab 0"), Err("usage: /tab [n]".into()));
+        assert_eq!(parse("/find in:#rust"), Err("usage: /find <text> [in:#channel] [from:nick] [n]".into()));
+        assert_eq!(parse("/history #rust lots"), Err("usage: /history <#channel|@nick> [before:<id>] [n]".into()));
+        assert_eq!(parse("/profile bob"), Err("usage: /profile set <field> [value] | get <nick>".into()));
+        assert_eq!(parse("/invite soon"), Err("usage: /invite [#channel] [once] [secs]".into()));
+        assert_eq!(parse("/export bob xml"), Err("usage: /export <nick> json|csv [since [until]]".into()));
+        assert!(parse("/join rust").unwrap_err().contains("not a channel name"));
+        assert_eq!(parse("/join #staff two words"), Err("usage: /join <#channel> [key]".into()));
+        assert!(parse("/frobnicate").unwrap_err().starts_with("unknown command"));
+    }
+}
@@ -0,0 +1,319 @@
+//! Client settings, read from `--config <path>` or otherwise
+//! `$XDG_CONFIG_HOME/rustchat/client.toml` (`~/.config/rustchat/client.toml`,
+//! or `%APPDATA%\rustchat\client.toml` on Windows) if it exists. Holds named server profiles, picked with `--profile`:
+//!
+//! ```toml
+//! default_profile = "home"
+//!
+//! [profile.home]
+//! addr = "192.168.1.10:5555"
+//! nick = "alice"
+//! channels = ["#general", "#rust"]
+//! password = "hunter2hunter2"  # if the nick is registered with /register
+//! keyring = true             # or look it up in the OS keyring (see keyring.rs)
+//! two_factor = true          # ask for a code on connect, after /2fa enable
+//!
+//! emoji = true               # have the server turn :smile: into emoji
+//! format = true              # keep *bold*, _italic_ and `code` for the TUI
+//!
+//! [profile.hidden]
+//! addr = "chatxyz.onion:5555"
+//! proxy = "socks5://127.0.0.1:9050"  # user:pass@ before the host if needed
+//!
+//! [notify]
+//! enabled = true             # desktop notifications for DMs and mentions
+//! mute = ["spambot"]         # never for these senders
+//!
+//! [alerts]
+//! enabled = true             # ring on highlights; /alerts on|off at runtime
+//! words = ["deploy"]         # highlight words, besides our nickname
+//! users = ["boss"]           # ring for everything these users say
+//! sound = "/usr/share/sounds/freedesktop/stereo/message.oga"  # else the bell
+//!
+//! [log]
+//! enabled = true             # /log on|off at runtime
+//! dir = "/home/me/chatlogs"  # default: $XDG_DATA_HOME/rustchat/logs
+//!
+//! [away]
+//! after_secs = 600           # mark us away after this long idle; 0 never
+//! message = "idle"
+//!
+//! [alias]
+//! b = "/msg bob"             # /b hi      -> /msg bob hi
+//! tell = "/msg $1 ($2) $*"   # $1.. are arguments, $* the rest, $$ a dollar
+//! ```
+
+use anyhow::{Result, anyhow, bail};
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use protocol::{caps::Cap, toml::Document};
+use rustchat_client::proxy::Proxy;
+
+use crate::commands;
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Used when no `--profile` is given.
+    pub default_profile: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+    /// Slash command name (without the `/`) to what it expands to.
+    pub aliases: BTreeMap<String, String>,
+    pub notify: Notify,
+    pub alerts: Alerts,
+    pub log: Log,
+    pub away: Away,
+}
+
+#[derive(Debug, Clone)]
+pub struct Notify {
+    pub enabled: bool,
+    pub mute: Vec<String>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify { enabled: true, mute: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Alerts {
+    pub enabled: bool,
+    pub words: Vec<String>,
+    pub users: Vec<String>,
+    /// Played instead of the terminal bell.
+    pub sound: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    pub enabled: bool,
+    pub dir: Option<PathBuf>,
+}
+
+impl Log {
+    /// Where logs go: the configured directory, else
+    /// `$XDG_DATA_HOME/rustchat/logs` (`~/.local/share/rustchat/logs`).
+    pub fn dir(&self) -> PathBuf {
+        match &self.dir {
+            Some(dir) => dir.clone(),
+            None => data_dir().join("logs"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Away {
+    /// Idle time before we're marked away; `None` never.
+    pub after: Option<Duration>,
+    pub message: String,
+}
+
+impl Default for Away {
+    fn default() -> Self {
+        Away { after: Some(Duration::from_secs(600)), message: "idle".into() }
+    }
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Alerts { enabled: true, words: Vec::new(), users: Vec::new(), sound: None }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Profile {
+    pub addr: Option<String>,
+    pub nick: Option<String>,
+    /// Joined on connect, and again after every reconnect.
+    pub channels: Vec<String>,
+    pub proxy: Option<Proxy>,
+    /// For a registered nick, sent on every connect.
+    pub password: Option<String>,
+    /// Look the password up in the OS keyring when there isn't one above.
+    pub keyring: bool,
+    /// Prompt for a two-factor code on connect.
+    pub two_factor: bool,
+    /// Ask for the `emoji` cap, so messages come with shortcodes expanded.
+    pub emoji: bool,
+    /// Ask for the `format` cap, so messages keep their formatting.
+    pub format: bool,
+}
+
+impl Profile {
+    /// The caps to ask the server for.
+    pub fn caps(&self) -> Vec<Cap> {
+        let wanted = [(self.emoji, Cap::Emoji), (self.format, Cap::Format)];
+        wanted.into_iter().filter_map(|(want, cap)| want.then_some(cap)).collect()
+    }
+}
+
+impl Config {
+    /// Loads `path`, or the default file if there is one.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| anyhow!("failed to read {}: {e}", path.display()))?;
+        Config::parse(&text).map_err(|e| anyhow!("{}: {e}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut doc = Document::parse(text)?;
+        let mut config = Config { default_profile: doc.take_str("", "default_profile")?, ..Config::default() };
+
+        if let Some(enabled) = doc.take_bool("notify", "enabled")? {
+            config.notify.enabled = enabled;
+        }
+        config.notify.mute = doc.take_list("notify", "mute")?.unwrap_or_default();
+        if let Some(enabled) = doc.take_bool("alerts", "enabled")? {
+            config.alerts.enabled = enabled;
+        }
+        config.alerts.words = doc.take_list("alerts", "words")?.unwrap_or_default();
+        config.alerts.users = doc.take_list("alerts", "users")?.unwrap_or_default();
+        config.alerts.sound = doc.take_str("alerts", "sound")?;
+        config.log.enabled = doc.take_bool("log", "enabled")?.unwrap_or(false);
+        config.log.dir = doc.take_str("log", "dir")?.map(PathBuf::from);
+        if let Some(secs) = doc.take_int("away", "after_secs")? {
+            config.away.after = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(message) = doc.take_str("away", "message")? {
+            config.away.message = message;
+        }
+
+        for name in doc.keys("alias") {
+            let expansion = doc.take_str("alias", &name)?.unwrap_or_default();
+            if commands::COMMANDS.iter().any(|c| c.name.eq_ignore_ascii_case(&name)) {
+                bail!("alias {name} would hide the built-in /{name}");
+            }
+            if name.contains(['/', ' ']) || !expansion.starts_with('/') {
+                bail!("alias {name} must be a plain name expanding to a /command");
+            }
+            config.aliases.insert(name.to_lowercase(), expansion);
+        }
+
+        let sections: Vec<String> = doc.sections().map(str::to_string).collect();
+        for section in sections {
+            let Some(name) = section.strip_prefix("profile.") else {
+                continue;
+            };
+            let profile = Profile {
+                addr: doc.take_str(&section, "addr")?,
+                nick: doc.take_str(&section, "nick")?,
+                channels: doc.take_list(&section, "channels")?.unwrap_or_default(),
+                proxy: doc.take_str(&section, "proxy")?.map(|url| Proxy::parse(&url)).transpose()?,
+                password: doc.take_str(&section, "password")?,
+                keyring: doc.take_bool(&section, "keyring")?.unwrap_or(false),
+                two_factor: doc.take_bool(&section, "two_factor")?.unwrap_or(false),
+                emoji: doc.take_bool(&section, "emoji")?.unwrap_or(false),
+                format: doc.take_bool(&section, "format")?.unwrap_or(false),
+            };
+            config.profiles.insert(name.to_string(), profile);
+        }
+        doc.finish()?;
+
+        if let Some(name) = &config.default_profile
+            && !config.profiles.contains_key(name)
+        {
+            bail!("default_profile {name:?} is not defined");
+        }
+        Ok(config)
+    }
+
+    /// The profile named on the command line, else the default one.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self.profiles.get(name).cloned().ok_or_else(|| anyhow!("no profile named {name:?}")),
+            None => Ok(Profile::default()),
+        }
+    }
+}
+
+/// Where the client keeps its own files: `$XDG_DATA_HOME/rustchat`
+/// (`~/.local/share/rustchat`, or `%APPDATA%\rustchat` on Windows).
+pub fn data_dir() -> PathBuf {
+    xdg_dir("XDG_DATA_HOME", ".local/share").unwrap_or_default().join("rustchat")
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("rustchat").join("client.toml"))
+}
+
+/// `$var`, or `home_relative` under the home directory if it isn't set. On
+/// Windows, where neither usually is, `%APPDATA%`.
+fn xdg_dir(var: &str, home_relative: &str) -> Option<PathBuf> {
+    match std::env::var_os(var).filter(|v| !v.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None if cfg!(windows) => Some(PathBuf::from(std::env::var_os("APPDATA")?)),
+        None => Some(PathBuf::from(std::env::var_os("HOME")?).join(home_relative)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_profiles() {
+        let config = Config::parse(
+            r##"
+            default_profile = "home"
+            [profile.home]
+            addr = "192.168.1.10:5555"
+            nick = "alice"
+            channels = ["#general", "#rust"]
+            password = "hunter2hunter2"
+            two_factor = true
+            emoji = true
+            format = true
+            [profile.work]
+            addr = "chat.example.com:5555"
+            proxy = "socks5://127.0.0.1:9050"
+            keyring = true
+            [alias]
+            b = "/msg bob"
+            [notify]
+            mute = ["spambot"]
+            [alerts]
+            enabled = false
+            words = ["deploy"]
+            [away]
+            after_secs = 0
+            "##,
+        )
+        .unwrap();
+        let home = config.profile(None).unwrap();
+        assert_eq!(home.addr.as_deref(), Some("192.168.1.10:5555"));
+        assert_eq!(home.channels, ["#general", "#rust"]);
+        assert_eq!(home.password.as_deref(), Some("hunter2hunter2"));
+        assert!(home.two_factor);
+        assert!(home.caps() == [Cap::Emoji, Cap::Format]);
+        let work = config.profile(Some("work")).unwrap();
+        assert_eq!(work.nick, None);
+        assert!(work.keyring && !home.keyring);
+        assert!(work.caps().is_empty());
+        assert_eq!(work.proxy.unwrap().addr(), "127.0.0.1:9050");
+        assert!(config.profile(Some("play")).is_err());
+        assert_eq!(config.aliases["b"], "/msg bob");
+        assert!(config.notify.enabled);
+        assert_eq!(config.notify.mute, ["spambot"]);
+        assert!(!config.alerts.enabled);
+        assert_eq!(config.alerts.words, ["deploy"]);
+        assert_eq!(config.away.after, None);
+        assert_eq!(config.away.message, "idle");
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(Config::parse("default_profile = \"home\"").is_err());
+        assert!(Config::parse("[profile.home]\nadress = \"x\"").is_err());
+        assert!(Config::parse("[profile.home]\nchannels = \"#general\"").is_err());
+        assert!(Config::parse("[alias]\nmsg = \"/say #general\"").is_err());
+        assert!(Config::parse("[alias]\nb = \"msg bob\"").is_err());
+        assert!(Config::parse("[profile.home]\nproxy = \"http://proxy:8080\"").is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! The primitives behind end-to-end encrypted DMs, written out here like the
+//! protocol's codecs: X25519 key agreement (RFC 7748), and XChaCha20-Poly1305
+//! from `protocol::xchacha`. Each is checked against the published test
+//! vectors.
+
+pub use protocol::xchacha::{hchacha20, open, random, seal, KEY_LEN, NONCE_LEN};
+
+// ---- X25519 ----
+
+/// A field element mod 2^255 - 19, as five 51-bit limbs.
+type Fe = [u64; 5];
+const MASK51: u64 = (1 << 51) - 1;
+
+fn fe_from_bytes(b: &[u8; 32]) -> Fe {
+    let load = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+    // The top bit is ignored, as RFC 7748 asks.
+    [load(0) & MASK51, (load(6) >> 3) & MASK51, (load(12) >> 6) & MASK51, (load(19) >> 1) & MASK51, (load(24) >> 12) & MASK51]
+}
+
+fn fe_to_bytes(f: &Fe) -> [u8; 32] {
+    let mut t = carry(*f);
+    // t < 2p here; subtract p if t >= p, found by whether t + 19 overflows
+    // 2^255.
+    let mut q = (t[0] + 19) >> 51;
+    for limb in &t[1..] {
+        q = (limb + q) >> 51;
+    }
+    t[0] += 19 * q;
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK51;
+    }
+    t[4] &= MASK51;
+    let words = [t[0] | t[1] << 51, t[1] >> 13 | t[2] << 38, t[2] >> 26 | t[3] << 25, t[3] >> 39 | t[4] << 12];
+    let mut out = [0u8; 32];
+    for (chunk, word) in out.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+fn carry(mut f: Fe) -> Fe {
+    for i in 0..4 {
+        f[i + 1] += f[i] >> 51;
+        f[i] &= MASK51;
+    }
+    f[0] += 19 * (f[4] >> 51);
+    f[4] &= MASK51;
+    f
+}
+
+fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    carry([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3], a[4] + b[4]])
+}
+
+fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    // Add 4p first so no limb goes negative.
+    const FOUR_P: Fe = [0x1f_ffff_ffff_ffb4, 0x1f_ffff_ffff_fffc, 0x1f_ffff_ffff_fffc, 0x1f_ffff_ffff_fffc, 0x1f_ffff_ffff_fffc];
+    carry([
+        a[0] + FOUR_P[0] - b[0],
+        a[1] + FOUR_P[1] - b[1],
+        a[2] + FOUR_P[2] - b[2],
+        a[3] + FOUR_P[3] - b[3],
+        a[4] + FOUR_P[4] - b[4],
+    ])
+}
+
+fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |x: u64, y: u64| u128::from(x) * u128::from(y);
+    let (b1, b2, b3, b4) = (b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19);
+    let c = [
+        m(a[0], b[0]) + m(a[4], b1) + m(a[3], b2) + m(a[2], b3) + m(a[1], b4),
+        m(a[1], b[0]) + m(a[0], b[1]) + m(a[4], b2) + m(a[3], b3) + m(a[2], b4),
+        m(a[2], b[0]) + m(a[1], b[1]) + m(a[0], b[2]) + m(a[4], b3) + m(a[3], b4),
+        m(a[3], b[0]) + m(a[2], b[1]) + m(a[1], b[2]) + m(a[0], b[3]) + m(a[4], b4),
+        m(a[4], b[0]) + m(a[3], b[1]) + m(a[2], b[2]) + m(a[1], b[3]) + m(a[0], b[4]),
+    ];
+    let mut r = [0u64; 5];
+    let mut carried = 0u128;
+    for i in 0..5 {
+        let v = c[i] + carried;
+        r[i] = v as u64 & MASK51;
+        carried = v >> 51;
+    }
+    r[0] += carried as u64 * 19;
+    r[1] += r[0] >> 51;
+    r[0] &= MASK51;
+    r
+}
+
+fn fe_invert(z: &Fe) -> Fe {
+    // z^(p - 2), bit by bit; p - 2 = 2^255 - 21.
+    let mut exp = [0xffu8; 32];
+    exp[0] = 0xeb;
+    exp[31] = 0x7f;
+    let mut r: Fe = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
+        r = fe_mul(&r, &r);
+        if exp[bit / 8] >> (bit % 8) & 1 == 1 {
+            r = fe_mul(&r, z);
+        }
+    }
+    r
+}
+
+fn cswap(swap: u64, a: &mut Fe, b: &mut Fe) {
+    let mask = 0u64.wrapping_sub(swap);
+    for i in 0..5 {
+        let t = mask & (a[i] ^ b[i]);
+        a[i] ^= t;
+        b[i] ^= t;
+    }
+}
+
+/// Scalar multiplication on Curve25519: `scalar` times the point with
+/// u-coordinate `u`.
+pub fn x25519(scalar: &[u8; 32], u: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+
+    let x1 = fe_from_bytes(u);
+    let (mut x2, mut z2): (Fe, Fe) = ([1, 0, 0, 0, 0], [0; 5]);
+    let (mut x3, mut z3): (Fe, Fe) = (x1, [1, 0, 0, 0, 0]);
+    let a24: Fe = [121_665, 0, 0, 0, 0];
+    let mut swap = 0;
+    for t in (0..255).rev() {
+        let bit = u64::from(k[t / 8] >> (t % 8) & 1);
+        swap ^= bit;
+        cswap(swap, &mut x2, &mut x3);
+        cswap(swap, &mut z2, &mut z3);
+        swap = bit;
+
+        let a = fe_add(&x2, &z2);
+        let aa = fe_mul(&a, &a);
+        let b = fe_sub(&x2, &z2);
+        let bb = fe_mul(&b, &b);
+        let e = fe_sub(&aa, &bb);
+        let c = fe_add(&x3, &z3);
+        let d = fe_sub(&x3, &z3);
+        let da = fe_mul(&d, &a);
+        let cb = fe_mul(&c, &b);
+        let sum = fe_add(&da, &cb);
+        x3 = fe_mul(&sum, &sum);
+        let diff = fe_sub(&da, &cb);
+        z3 = fe_mul(&x1, &fe_mul(&diff, &diff));
+        x2 = fe_mul(&aa, &bb);
+        z2 = fe_mul(&e, &fe_add(&aa, &fe_mul(&a24, &e)));
+    }
+    cswap(swap, &mut x2, &mut x3);
+    cswap(swap, &mut z2, &mut z3);
+    fe_to_bytes(&fe_mul(&x2, &fe_invert(&z2)))
+}
+
+/// The public key for `secret`.
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    let mut base = [0u8; 32];
+    base[0] = 9;
+    x25519(secret, &base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let bytes: Vec<u8> = (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn x25519_rfc7748_vectors() {
+        let scalar = hex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4");
+        let u = hex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c");
+        assert_eq!(x25519(&scalar, &u), hex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"));
+
+        let alice = hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = hex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        assert_eq!(public_key(&alice), hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"));
+        assert_eq!(public_key(&bob), hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"));
+        let shared = hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(x25519(&alice, &public_key(&bob)), shared);
+        assert_eq!(x25519(&bob, &public_key(&alice)), shared);
+    }
+}
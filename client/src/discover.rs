@@ -0,0 +1,88 @@
+//! `client discover`: lists the chat servers announcing themselves on the
+//! local network over mDNS.
+//!
+//! ```text
+//! client discover [--wait secs]
+//! ```
+//!
+//! Exit status: 0 if any server answered, 1 if none did, 2 for bad arguments.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    process::ExitCode,
+    time::Duration,
+};
+
+use protocol::mdns::{self, Service};
+use tokio::{
+    net::UdpSocket,
+    time::{Instant, sleep_until, timeout_at},
+};
+
+const DEFAULT_WAIT: Duration = Duration::from_secs(2);
+/// Queries can be lost like any multicast, so ask again after this long.
+const RETRY_AFTER: Duration = Duration::from_millis(700);
+
+pub async fn run(args: &[String]) -> ExitCode {
+    let wait = match args {
+        [] => DEFAULT_WAIT,
+        [flag, secs] if flag == "--wait" && secs.parse::<f64>().is_ok_and(|s| s > 0.0 && s < 3600.0) => {
+            Duration::from_secs_f64(secs.parse().unwrap_or_default())
+        }
+        _ => {
+            eprintln!("usage: client discover [--wait secs]");
+            return ExitCode::from(2);
+        }
+    };
+    match discover(wait).await {
+        Ok(found) if found.is_empty() => {
+            eprintln!("no chat servers found on the local network");
+            ExitCode::from(1)
+        }
+        Ok(found) => {
+            let width = found.iter().map(|s| s.name.chars().count()).max().unwrap_or(0);
+            for service in found {
+                println!("{:width$}  {}  ({}.local)", service.name, service.socket_addr(), service.host);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("discovery failed: {e}");
+            ExitCode::from(1)
+        }
+    }
+}
+
+/// Asks the network who runs a chat server and collects answers for `wait`.
+async fn discover(wait: Duration) -> std::io::Result<Vec<Service>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let group = SocketAddrV4::new(mdns::GROUP, mdns::PORT);
+    let query = mdns::query(std::process::id() as u16);
+    socket.send_to(&query, group).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut retry = Some(Instant::now() + RETRY_AFTER).filter(|&at| at < deadline);
+    let mut found: Vec<Service> = Vec::new();
+    let mut buf = [0u8; 9000];
+    loop {
+        let received = tokio::select! {
+            received = timeout_at(deadline, socket.recv_from(&mut buf)) => received,
+            () = sleep_until(retry.unwrap_or(deadline)), if retry.is_some() => {
+                retry = None;
+                socket.send_to(&query, group).await?;
+                continue;
+            }
+        };
+        let Ok(received) = received else {
+            break;
+        };
+        let (len, _) = received?;
+        for service in mdns::parse_response(&buf[..len]) {
+            if !found.contains(&service) {
+                found.push(service);
+            }
+        }
+    }
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
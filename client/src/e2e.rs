@@ -0,0 +1,350 @@
+//! End-to-end encrypted DMs. Each client has a long-lived X25519 key pair;
+//! `/e2e <nick>` sends our public key to `nick` with `KEY`, and a client that
+//! receives a key answers with its own. Once both sides have the other's key,
+//! DM bodies are sealed with XChaCha20-Poly1305 under the shared secret and
+//! sent as `e2e1:<hex nonce and ciphertext>`, which the server relays like
+//! any other text.
+//!
+//! Keys are trusted on first use: the first key seen for a nick is remembered
+//! in `known_keys`, and a different one later is refused until `/trust` says
+//! otherwise. `/fingerprint` shows both keys for comparing out of band.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use protocol::{Command, Event, Wire};
+
+use crate::crypto::{self, KEY_LEN, NONCE_LEN};
+
+const PREFIX: &str = "e2e1:";
+
+/// Our key pair.
+#[derive(Clone)]
+pub struct Identity {
+    secret: [u8; KEY_LEN],
+    public: [u8; KEY_LEN],
+}
+
+impl Identity {
+    /// The key pair kept in `path`, made and saved there on first use.
+    pub fn load(path: &Path) -> io::Result<Identity> {
+        let secret = match fs::read_to_string(path) {
+            Ok(text) => parse_key(text.trim()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a key", path.display()))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let secret = crypto::random()?;
+                save_secret(path, &secret)?;
+                secret
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Identity { secret, public: crypto::public_key(&secret) })
+    }
+}
+
+fn save_secret(path: &Path, secret: &[u8; KEY_LEN]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    writeln!(options.open(path)?, "{}", to_hex(secret))
+}
+
+/// Peers' public keys on one server, as first seen. They're kept in a file
+/// shared by all servers as `<server> <key> <nick>` lines; a later line for
+/// the same server and nick replaces an earlier one.
+pub struct KnownKeys {
+    path: PathBuf,
+    server: String,
+    keys: HashMap<String, [u8; KEY_LEN]>,
+}
+
+impl KnownKeys {
+    /// The keys for `server` in `path`; none if the file doesn't exist yet.
+    pub fn load(path: PathBuf, server: &str) -> io::Result<Self> {
+        let mut keys = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    let mut p = line.splitn(3, ' ');
+                    if let (Some(s), Some(key), Some(nick)) = (p.next(), p.next(), p.next())
+                        && s == server
+                        && let Some(key) = parse_key(key)
+                    {
+                        keys.insert(nick.to_string(), key);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(KnownKeys { path, server: server.to_string(), keys })
+    }
+
+    fn remember(&mut self, nick: &str, key: [u8; KEY_LEN]) -> io::Result<()> {
+        self.keys.insert(nick.to_string(), key);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{} {} {nick}", self.server, to_hex(&key))
+    }
+}
+
+/// What an incoming line turned out to be.
+pub enum Incoming {
+    /// To show, with an encrypted DM's body replaced by the plaintext.
+    /// `locked` says it was encrypted.
+    Line { line: String, locked: bool },
+    /// `from` sent their key. `reply` offers ours back if they don't have it;
+    /// `first_seen` says it wasn't known before.
+    Key { from: String, reply: Option<Command>, first_seen: bool },
+    /// `from` sent a key other than the one first seen for them. It isn't
+    /// used unless `/trust` accepts it.
+    KeyChanged { from: String },
+}
+
+struct Peer {
+    public: [u8; KEY_LEN],
+    shared: [u8; KEY_LEN],
+}
+
+/// The keys of everyone on one server we've exchanged keys with.
+pub struct E2e {
+    identity: Identity,
+    known: KnownKeys,
+    peers: HashMap<String, Peer>,
+    /// Changed keys waiting for `/trust`.
+    changed: HashMap<String, [u8; KEY_LEN]>,
+    /// Who has been sent our key.
+    offered: HashSet<String>,
+}
+
+impl E2e {
+    pub fn new(identity: Identity, known: KnownKeys) -> Self {
+        E2e { identity, known, peers: HashMap::new(), changed: HashMap::new(), offered: HashSet::new() }
+    }
+
+    /// The command that sends our key to `name`.
+    pub fn offer(&mut self, name: &str) -> Command {
+        self.offered.insert(name.to_string());
+        Command::Key { name: name.to_string(), key: to_hex(&self.identity.public) }
+    }
+
+    /// Whether DMs with `name` are encrypted.
+    pub fn is_encrypted(&self, name: &str) -> bool {
+        self.peers.contains_key(name)
+    }
+
+    /// `line` as it should go to the server: a DM to someone whose key we
+    /// have gets its body encrypted; anything else is left alone.
+    pub fn outgoing(&self, me: &str, line: &str) -> String {
+        match Command::parse(line.as_bytes(), Wire::Text) {
+            Command::To { name, body } if self.is_encrypted(&name) => {
+                let Ok(nonce) = crypto::random::<NONCE_LEN>() else {
+                    return line.to_string();
+                };
+                let sealed = crypto::seal(&self.peers[&name].shared, &nonce, aad(me, &name).as_bytes(), body.as_bytes());
+                let body = format!("{PREFIX}{}{}", to_hex(&nonce), to_hex(&sealed));
+                String::from_utf8_lossy(&Command::To { name, body }.encode(Wire::Text)).into_owned()
+            }
+            _ => line.to_string(),
+        }
+    }
+
+    /// Handles a line from the server, for user `me`.
+    pub fn incoming(&mut self, me: &str, line: &str) -> Incoming {
+        match Event::parse(line.as_bytes(), Wire::Text) {
+            Some(Event::Key { from, key, .. }) => {
+                let Some(key) = parse_key(&key) else {
+                    return Incoming::Line { line: line.to_string(), locked: false };
+                };
+                let first_seen = match self.known.keys.get(&from) {
+                    None => true,
+                    Some(known) if *known == key => false,
+                    Some(_) => {
+                        self.changed.insert(from.clone(), key);
+                        return Incoming::KeyChanged { from };
+                    }
+                };
+                if !self.accept(&from, key) {
+                    return Incoming::Line { line: line.to_string(), locked: false };
+                }
+                if first_seen {
+                    // Best effort: if it can't be saved it's still trusted for
+                    // this session.
+                    let _ = self.known.remember(&from, key);
+                }
+                let reply = (!self.offered.contains(&from)).then(|| self.offer(&from));
+                Incoming::Key { from, reply, first_seen }
+            }
+            Some(Event::Dm { id, seq, ts, from, from_id, body }) if body.starts_with(PREFIX) => {
+                let body = match self.decrypt(&from, me, &body[PREFIX.len()..]) {
+                    Some(plain) => plain,
+                    None => return Incoming::Line { line: line.replacen(&body, "(encrypted message that couldn't be decrypted)", 1), locked: false },
+                };
+                let event = Event::Dm { id, seq, ts, from, from_id, body };
+                Incoming::Line { line: String::from_utf8_lossy(&event.encode(Wire::Text)).into_owned(), locked: true }
+            }
+            _ => Incoming::Line { line: line.to_string(), locked: false },
+        }
+    }
+
+    /// Starts using `key` for `name`; `false` if it's unusable.
+    fn accept(&mut self, name: &str, key: [u8; KEY_LEN]) -> bool {
+        let shared = crypto::x25519(&self.identity.secret, &key);
+        // A low-order point gives an all-zero secret anyone could compute.
+        if shared == [0; KEY_LEN] {
+            return false;
+        }
+        self.peers.insert(name.to_string(), Peer { public: key, shared: crypto::hchacha20(&shared, &[0; 16]) });
+        true
+    }
+
+    /// Accepts the changed key `name` sent, replacing the one first seen. The
+    /// command, if any, offers ours back.
+    pub fn trust(&mut self, name: &str) -> Result<Option<Command>, String> {
+        let key = self.changed.remove(name).ok_or_else(|| format!("{name} hasn't sent a new key"))?;
+        if !self.accept(name, key) {
+            return Err(format!("{name}'s new key is unusable"));
+        }
+        self.known.remember(name, key).map_err(|e| format!("couldn't save {name}'s key: {e}"))?;
+        Ok((!self.offered.contains(name)).then(|| self.offer(name)))
+    }
+
+    /// Lines showing our fingerprint and `name`'s, to compare with them out
+    /// of band.
+    pub fn fingerprints(&self, name: &str) -> Vec<String> {
+        let mut lines = vec![format!("yours: {}", fingerprint(&self.identity.public))];
+        match self.peers.get(name) {
+            Some(peer) => lines.push(format!("{name}'s: {}", fingerprint(&peer.public))),
+            None => lines.push(format!("no key from {name} yet; /e2e {name} asks for it")),
+        }
+        if let Some(key) = self.changed.get(name) {
+            lines.push(format!("{name}'s new, untrusted key: {}; /trust {name} accepts it", fingerprint(key)));
+        }
+        lines
+    }
+
+    fn decrypt(&self, from: &str, to: &str, payload: &str) -> Option<String> {
+        let bytes = from_hex(payload)?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let plain = crypto::open(&self.peers.get(from)?.shared, nonce.try_into().ok()?, aad(from, to).as_bytes(), sealed)?;
+        String::from_utf8(plain).ok()
+    }
+}
+
+/// Binds a message to its sender and recipient so it can't be passed off as
+/// coming from someone else.
+fn aad(from: &str, to: &str) -> String {
+    format!("{from}\n{to}")
+}
+
+/// A public key in groups of four hex digits. Keys are short enough to show
+/// whole, so two different keys never look alike.
+pub fn fingerprint(key: &[u8; KEY_LEN]) -> String {
+    let hex = to_hex(key);
+    let groups: Vec<&str> = (0..hex.len()).step_by(4).map(|i| &hex[i..i + 4]).collect();
+    groups.join(" ")
+}
+
+fn parse_key(hex: &str) -> Option<[u8; KEY_LEN]> {
+    from_hex(hex)?.try_into().ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(seed: u8) -> Identity {
+        let secret = [seed; KEY_LEN];
+        Identity { secret, public: crypto::public_key(&secret) }
+    }
+
+    fn known_keys(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustchat-known-keys-{test}-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn client(seed: u8, known: &Path) -> E2e {
+        E2e::new(identity(seed), KnownKeys::load(known.to_path_buf(), "host:1").unwrap())
+    }
+
+    /// What the server turns `from`'s `TO ...` or `KEY ...` into.
+    fn relay(from: &str, line: &str) -> String {
+        let event = match Command::parse(line.as_bytes(), Wire::Text) {
+            Command::To { body, .. } => Event::Dm { id: 1, seq: 1, ts: "2026-10-15T08:30:00Z".into(), from: from.into(), from_id: 1, body },
+            Command::Key { key, .. } => Event::Key { from: from.into(), from_id: 1, key },
+            other => panic!("{other:?}"),
+        };
+        String::from_utf8(event.encode(Wire::Text)).unwrap()
+    }
+
+    #[test]
+    fn exchanges_keys_and_encrypts_dms() {
+        let (alice_keys, bob_keys) = (known_keys("alice"), known_keys("bob"));
+        let (mut alice, mut bob) = (client(1, &alice_keys), client(2, &bob_keys));
+        assert_eq!(alice.outgoing("alice", "TO bob hi"), "TO bob hi");
+
+        let offer = String::from_utf8(alice.offer("bob").encode(Wire::Text)).unwrap();
+        let Incoming::Key { from, reply: Some(reply), first_seen: true } = bob.incoming("bob", &relay("alice", &offer)) else { panic!() };
+        assert_eq!(from, "alice");
+        let reply = relay("bob", &String::from_utf8(reply.encode(Wire::Text)).unwrap());
+        assert!(matches!(alice.incoming("alice", &reply), Incoming::Key { reply: None, .. }));
+
+        let sent = alice.outgoing("alice", "TO bob hi: there");
+        assert!(sent.starts_with("TO bob e2e1:") && !sent.contains("there"), "{sent}");
+        let Incoming::Line { line, locked: true } = bob.incoming("bob", &relay("alice", &sent)) else { panic!() };
+        assert!(line.ends_with("from alice(1): hi: there"), "{line}");
+
+        // Meant for someone else, so it doesn't authenticate.
+        let Incoming::Line { line, locked: false } = bob.incoming("carol", &relay("alice", &sent)) else { panic!() };
+        assert!(line.ends_with("couldn't be decrypted)"), "{line}");
+        fs::remove_file(alice_keys).unwrap();
+        fs::remove_file(bob_keys).unwrap();
+    }
+
+    #[test]
+    fn trusts_keys_on_first_use() {
+        let path = known_keys("tofu");
+        let key = |seed| relay("alice", &String::from_utf8(client(seed, &known_keys("unused")).offer("bob").encode(Wire::Text)).unwrap());
+        let mut bob = client(2, &path);
+        assert!(matches!(bob.incoming("bob", &key(1)), Incoming::Key { first_seen: true, .. }));
+
+        // Remembered across runs.
+        let mut bob = client(2, &path);
+        assert!(matches!(bob.incoming("bob", &key(1)), Incoming::Key { first_seen: false, .. }));
+        assert!(matches!(bob.incoming("bob", &key(3)), Incoming::KeyChanged { .. }));
+        assert!(bob.fingerprints("alice").last().unwrap().contains("untrusted"));
+        assert!(bob.trust("alice").is_ok());
+        assert!(bob.trust("alice").is_err());
+
+        let mut bob = client(2, &path);
+        assert!(matches!(bob.incoming("bob", &key(3)), Incoming::Key { first_seen: false, .. }));
+        assert_eq!(bob.fingerprints("alice")[1], format!("alice's: {}", fingerprint(&identity(3).public)));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+//! The TUI's input line, with readline-style editing: Up/Down walk through
+//! earlier lines, Ctrl-R searches them, and Tab completes commands (wire and
+//! slash) and nicknames.
+
+use std::collections::BTreeSet;
+
+use crate::{commands, term::Key};
+
+const HISTORY: usize = 500;
+const PROMPT: &str = "> ";
+
+const COMMANDS: &[&str] = &[
+    "EXPORT", "EXPORTME", "JOIN", "KICK", "KICKID", "PART", "PURGE", "RESEND", "SAY", "TO", "TOID",
+];
+/// Commands whose first argument is a nickname.
+const TAKES_NAME: &[&str] = &["EXPORT", "KICK", "PURGE", "TO"];
+
+#[derive(Default)]
+pub struct LineEditor {
+    buf: Vec<char>,
+    /// Insertion point, in chars.
+    cursor: usize,
+    /// Lines entered so far, oldest first.
+    history: Vec<String>,
+    /// The history entry being shown while browsing with Up/Down, and what
+    /// was typed before browsing started.
+    browsing: Option<(usize, Vec<char>)>,
+    search: Option<Search>,
+    completion: Option<Completion>,
+}
+
+/// An in-progress Ctrl-R search. The line shows the current match.
+struct Search {
+    query: String,
+    hit: Option<usize>,
+    /// The line as it was before searching, restored on cancel.
+    original: Vec<char>,
+}
+
+/// Candidates for the word being completed; further Tabs cycle through them.
+struct Completion {
+    start: usize,
+    matches: Vec<String>,
+    next: usize,
+}
+
+impl LineEditor {
+    /// Applies an editing key. On Enter, returns the finished line and clears
+    /// the editor.
+    pub fn key(&mut self, key: Key) -> Option<String> {
+        self.completion = None;
+        if self.search.is_some() && !self.search_key(key) {
+            return None;
+        }
+        match key {
+            Key::Char(c) => {
+                self.buf.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.buf.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.buf.len() => {
+                self.buf.remove(self.cursor);
+            }
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = (self.cursor + 1).min(self.buf.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.buf.len(),
+            Key::Ctrl('u') => {
+                self.buf.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Ctrl('k') => self.buf.truncate(self.cursor),
+            Key::Ctrl('w') => {
+                let mut start = self.cursor;
+                while start > 0 && self.buf[start - 1] == ' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.buf[start - 1] != ' ' {
+                    start -= 1;
+                }
+                self.buf.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Up | Key::Ctrl('p') => self.older(),
+            Key::Down | Key::Ctrl('n') => self.newer(),
+            Key::Ctrl('r') => {
+                let original = self.buf.clone();
+                self.search = Some(Search { query: String::new(), hit: None, original });
+            }
+            Key::Enter => return Some(self.submit()),
+            _ => {}
+        }
+        None
+    }
+
+    /// Handles `key` while searching; `false` if it was used up, `true` if
+    /// the search ended and `key` should also be applied as a normal edit.
+    fn search_key(&mut self, key: Key) -> bool {
+        let Some(search) = &mut self.search else {
+            return true;
+        };
+        let from = match key {
+            Key::Char(c) => {
+                search.query.push(c);
+                search.hit.unwrap_or(self.history.len())
+            }
+            Key::Backspace => {
+                search.query.pop();
+                self.history.len()
+            }
+            // Next older match.
+            Key::Ctrl('r') => search.hit.unwrap_or(self.history.len()).saturating_sub(1),
+            Key::Esc | Key::Ctrl('g') => {
+                let search = self.search.take().unwrap();
+                self.set(search.original);
+                return false;
+            }
+            // Anything else keeps the match and carries on editing it.
+            _ => {
+                self.search = None;
+                return true;
+            }
+        };
+
+        let query = search.query.clone();
+        let found = self.history[..(from + 1).min(self.history.len())].iter().rposition(|h| h.contains(&query));
+        if let Some(hit) = found {
+            let line: Vec<char> = self.history[hit].chars().collect();
+            let at = self.history[hit].find(&query).map_or(0, |i| self.history[hit][..i].chars().count());
+            self.search.as_mut().unwrap().hit = Some(hit);
+            self.buf = line;
+            self.cursor = at;
+        }
+        false
+    }
+
+    /// Completes the word before the cursor: a command keyword at the start
+    /// of the line, otherwise a nickname where the command expects one.
+    pub fn complete(&mut self, names: &BTreeSet<String>) {
+        if self.search.is_some() {
+            return;
+        }
+        if let Some(c) = &mut self.completion {
+            let word = c.matches[c.next].clone();
+            c.next = (c.next + 1) % c.matches.len();
+            let start = c.start;
+            self.replace_word(start, &word);
+            return;
+        }
+
+        let mut start = self.cursor;
+        while start > 0 && self.buf[start - 1] != ' ' {
+            start -= 1;
+        }
+        let word: String = self.buf[start..self.cursor].iter().collect();
+        let before: String = self.buf[..start].iter().collect();
+        let mut matches: Vec<String> = match before.split_whitespace().collect::<Vec<_>>()[..] {
+            [] if word.starts_with('/') => {
+                let lower = word[1..].to_lowercase();
+                commands::COMMANDS.iter().filter(|c| c.name.starts_with(&lower)).map(|c| format!("/{}", c.name)).collect()
+            }
+            [cmd] if cmd.strip_prefix('/').is_some_and(|c| commands::TAKES_NAME.contains(&c.to_lowercase().as_str())) => {
+                names.iter().filter(|n| n.starts_with(&word) && !n.contains(' ')).cloned().collect()
+            }
+            [] => {
+                let upper = word.to_uppercase();
+                COMMANDS.iter().filter(|c| c.starts_with(&upper)).map(|c| c.to_string()).collect()
+            }
+            [cmd] if TAKES_NAME.contains(&cmd.to_uppercase().as_str()) => {
+                names.iter().filter(|n| n.starts_with(&word) && !n.contains(' ')).cloned().collect()
+            }
+            _ => Vec::new(),
+        };
+        match matches.len() {
+            0 => {}
+            1 => {
+                let word = matches.remove(0) + " ";
+                self.replace_word(start, &word);
+            }
+            _ => {
+                let word = matches[0].clone();
+                self.replace_word(start, &word);
+                self.completion = Some(Completion { start, matches, next: 1 });
+            }
+        }
+    }
+
+    fn replace_word(&mut self, start: usize, word: &str) {
+        self.buf.splice(start..self.cursor, word.chars());
+        self.cursor = start + word.chars().count();
+    }
+
+    fn older(&mut self) {
+        let at = match &self.browsing {
+            Some((at, _)) => *at,
+            None => self.history.len(),
+        };
+        if at == 0 {
+            return;
+        }
+        if self.browsing.is_none() {
+            self.browsing = Some((at, self.buf.clone()));
+        }
+        self.browsing.as_mut().unwrap().0 = at - 1;
+        self.set(self.history[at - 1].chars().collect());
+    }
+
+    fn newer(&mut self) {
+        let Some((at, draft)) = self.browsing.take() else {
+            return;
+        };
+        if at + 1 < self.history.len() {
+            self.browsing = Some((at + 1, draft));
+            self.set(self.history[at + 1].chars().collect());
+        } else {
+            self.set(draft);
+        }
+    }
+
+    fn set(&mut self, buf: Vec<char>) {
+        self.cursor = buf.len();
+        self.buf = buf;
+    }
+
+    fn submit(&mut self) -> String {
+        let line: String = self.buf.drain(..).collect();
+        self.cursor = 0;
+        self.browsing = None;
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+            if self.history.len() > HISTORY {
+                self.history.remove(0);
+            }
+        }
+        line
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn prompt(&self) -> String {
+        match &self.search {
+            Some(search) => format!("(search '{}') ", search.query),
+            None => PROMPT.to_string(),
+        }
+    }
+
+    /// The part of the line that fits in `width` columns, keeping the cursor
+    /// in view, and the cursor's column within it.
+    pub fn view(&self, width: usize) -> (String, usize) {
+        let width = width.max(1);
+        let start = (self.cursor + 1).saturating_sub(width);
+        let text = self.buf[start..].iter().take(width).collect();
+        (text, self.cursor - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enter(ed: &mut LineEditor, line: &str) {
+        for c in line.chars() {
+            ed.key(Key::Char(c));
+        }
+        ed.key(Key::Enter);
+    }
+
+    fn line(ed: &LineEditor) -> String {
+        ed.buf.iter().collect()
+    }
+
+    #[test]
+    fn walks_history_and_keeps_the_draft() {
+        let mut ed = LineEditor::default();
+        enter(&mut ed, "TO bob one");
+        enter(&mut ed, "TO bob two");
+        ed.key(Key::Char('x'));
+
+        ed.key(Key::Up);
+        assert_eq!(line(&ed), "TO bob two");
+        ed.key(Key::Up);
+        ed.key(Key::Up);
+        assert_eq!(line(&ed), "TO bob one");
+        ed.key(Key::Down);
+        assert_eq!(line(&ed), "TO bob two");
+        ed.key(Key::Down);
+        assert_eq!(line(&ed), "x");
+    }
+
+    #[test]
+    fn searches_backwards() {
+        let mut ed = LineEditor::default();
+        enter(&mut ed, "TO bob hi");
+        enter(&mut ed, "JOIN #rust");
+        enter(&mut ed, "TO bob bye");
+
+        ed.key(Key::Ctrl('r'));
+        for c in "bob".chars() {
+            ed.key(Key::Char(c));
+        }
+        assert_eq!(line(&ed), "TO bob bye");
+        ed.key(Key::Ctrl('r'));
+        assert_eq!(line(&ed), "TO bob hi");
+        assert_eq!(ed.key(Key::Enter), Some("TO bob hi".into()));
+
+        enter(&mut ed, "draft");
+        ed.key(Key::Char('z'));
+        ed.key(Key::Ctrl('r'));
+        ed.key(Key::Char('#'));
+        assert_eq!(line(&ed), "JOIN #rust");
+        ed.key(Key::Esc);
+        assert_eq!(line(&ed), "z");
+    }
+
+    #[test]
+    fn completes_commands_and_names() {
+        let names = BTreeSet::from(["bob".to_string(), "bobby".to_string(), "carol".to_string()]);
+        let mut ed = LineEditor::default();
+        for c in "t".chars() {
+            ed.key(Key::Char(c));
+        }
+        ed.complete(&names);
+        assert_eq!(line(&ed), "TO");
+        ed.complete(&names);
+        assert_eq!(line(&ed), "TOID");
+
+        ed.key(Key::Ctrl('u'));
+        for c in "TO c".chars() {
+            ed.key(Key::Char(c));
+        }
+        ed.complete(&names);
+        assert_eq!(line(&ed), "TO carol ");
+
+        ed.key(Key::Ctrl('u'));
+        for c in "KICK bo".chars() {
+            ed.key(Key::Char(c));
+        }
+        ed.complete(&names);
+        ed.complete(&names);
+        assert_eq!(line(&ed), "KICK bobby");
+        ed.complete(&names);
+        assert_eq!(line(&ed), "KICK bob");
+
+        ed.key(Key::Ctrl('u'));
+        for c in "/ms".chars() {
+            ed.key(Key::Char(c));
+        }
+        ed.complete(&names);
+        assert_eq!(line(&ed), "/msg");
+        ed.key(Key::Ctrl('u'));
+        for c in "/msg ca".chars() {
+            ed.key(Key::Char(c));
+        }
+        ed.complete(&names);
+        assert_eq!(line(&ed), "/msg carol ");
+    }
+
+    #[test]
+    fn deletes_words() {
+        let mut ed = LineEditor::default();
+        for c in "TO bob hello  ".chars() {
+            ed.key(Key::Char(c));
+        }
+        ed.key(Key::Ctrl('w'));
+        assert_eq!(line(&ed), "TO bob ");
+    }
+}
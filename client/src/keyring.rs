@@ -0,0 +1,47 @@
+//! Passwords kept in the OS's keyring instead of the config file, for
+//! profiles with `keyring = true`. They're looked up through the platform's
+//! own tool, under the service `rustchat` and the account `nick@addr`:
+//!
+//! ```sh
+//! secret-tool store --label rustchat service rustchat account alice@chat.example.com:5555   # libsecret
+//! security add-generic-password -s rustchat -a alice@chat.example.com:5555 -w              # macOS
+//! ```
+//!
+//! If the tool isn't installed or has no such entry, there's no password.
+
+use std::process::Stdio;
+use tokio::process::Command;
+
+const SERVICE: &str = "rustchat";
+
+/// The password stored for `nick` on `addr`, if there is one.
+pub async fn lookup(nick: &str, addr: &str) -> Option<String> {
+    let account = format!("{nick}@{addr}");
+    let output = command(&account)?.stdin(Stdio::null()).stderr(Stdio::null()).output().await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let password = String::from_utf8(output.stdout).ok()?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    (!password.is_empty()).then(|| password.to_string())
+}
+
+#[cfg(target_os = "macos")]
+fn command(account: &str) -> Option<Command> {
+    let mut command = Command::new("security");
+    command.args(["find-generic-password", "-s", SERVICE, "-a", account, "-w"]);
+    Some(command)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn command(account: &str) -> Option<Command> {
+    let mut command = Command::new("secret-tool");
+    command.args(["lookup", "service", SERVICE, "account", account]);
+    Some(command)
+}
+
+/// Windows' Credential Manager has no command to read a password back.
+#[cfg(not(unix))]
+fn command(_account: &str) -> Option<Command> {
+    None
+}
@@ -1,19 +1,67 @@
+mod alert;
+mod avatar;
+mod away;
+mod chatlog;
+mod clock;
+mod commands;
+mod config;
+mod crypto;
+mod discover;
+mod e2e;
+mod editor;
+mod keyring;
+mod notify;
+mod outbox;
+mod ping;
+mod pipe;
+mod send;
+mod style;
+mod term;
+mod tui;
+
+use alert::Alerter;
 use anyhow::{anyhow, Result};
-use std::env;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-    time::{timeout, Duration},
+use chatlog::ChatLog;
+use clock::Stamp;
+use commands::Input;
+use config::Config;
+use e2e::{E2e, Identity, Incoming, KnownKeys};
+use outbox::Outbox;
+use ping::Pinger;
+use protocol::{pow, AvatarCommand, Command, Event, Wire};
+use rustchat_client::{
+    invite::Invite,
+    proxy::Proxy,
+    session::{self, ConnectError, Options, Session, Update},
 };
+use style::Display;
+use std::{env, process::ExitCode};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// Times to ask for a password before giving up.
+const MAX_PASSWORD_TRIES: u32 = 3;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let mut stdin = BufReader::new(tokio::io::stdin()).lines();
     let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("send") => return Ok(send::run(&args[2..]).await),
+        Some("discover") => return Ok(discover::run(&args[2..]).await),
+        _ => {}
+    }
 
     // Config via simple flags.
     let mut address_arg: Option<String> = None;
     let mut nick_arg: Option<String> = None;
+    let mut profile_arg: Option<String> = None;
+    let mut config_arg: Option<String> = None;
+    let mut proxy_arg: Option<String> = None;
+    let mut password_arg: Option<String> = None;
+    let mut plain = false;
+    let mut pipe = false;
+    let mut no_color = false;
+    let mut time_format = clock::DEFAULT_FORMAT.to_string();
     let mut idx = 1;
     while idx < args.len() {
         match args[idx].as_str() {
@@ -25,22 +73,71 @@ async fn main() -> Result<()> {
                 nick_arg = Some(args[idx + 1].clone());
                 idx += 1;
             }
+            "--profile" if idx + 1 < args.len() => {
+                profile_arg = Some(args[idx + 1].clone());
+                idx += 1;
+            }
+            "--config" if idx + 1 < args.len() => {
+                config_arg = Some(args[idx + 1].clone());
+                idx += 1;
+            }
+            "--proxy" if idx + 1 < args.len() => {
+                proxy_arg = Some(args[idx + 1].clone());
+                idx += 1;
+            }
+            // Visible to anyone who can list processes; a profile, the
+            // keyring or the prompt keep it out of sight.
+            "--password" if idx + 1 < args.len() => {
+                password_arg = Some(args[idx + 1].clone());
+                idx += 1;
+            }
+            "--plain" => plain = true,
+            "--pipe" => pipe = true,
+            "--no-color" => no_color = true,
+            "--time-format" if idx + 1 < args.len() => {
+                time_format = args[idx + 1].clone();
+                idx += 1;
+            }
+            link if Invite::is_link(link) => address_arg = Some(link.to_string()),
             _ => {}
         }
         idx += 1;
     }
 
+    // Flags win over the profile.
+    let config = Config::load(config_arg.as_deref())?;
+    let profile = config.profile(profile_arg.as_deref())?;
+    let profile_name = profile_arg.or_else(|| config.default_profile.clone());
+    let caps = profile.caps();
+    let address_arg = address_arg.or(profile.addr);
+    let nick_arg = nick_arg.or(profile.nick);
+    // --proxy also covers profiles opened later with /connect that don't
+    // name their own.
+    let proxy_arg = proxy_arg.map(|url| Proxy::parse(&url)).transpose()?;
+    let mut options = Options { caps, proxy: proxy_arg.clone().or(profile.proxy), password: password_arg.or(profile.password), ..Options::default() };
+    let identity_path = config::data_dir().join("identity");
+    let identity = Identity::load(&identity_path).map_err(|e| anyhow!("{}: {e}", identity_path.display()))?;
+
     // Read server address
     let mut address = address_arg.unwrap_or_else(|| "127.0.0.1:5555".into());
     if address.trim().is_empty() {
-        println!("Enter server address (ip:port):");
+        println!("Enter server address (host[:port] or invite link):");
         while address.trim().is_empty() {
             address = stdin.next_line().await?.unwrap_or_default();
         }
     }
+    // An invite link carries the address, and a token for the handshake.
+    if Invite::is_link(address.trim()) {
+        let invite = Invite::parse(address.trim())?;
+        address = invite.addr;
+        options.token = Some(invite.token);
+    }
 
     // Read nickname
     let mut name = nick_arg.unwrap_or_default();
+    if name.trim().is_empty() && pipe {
+        return Err(anyhow!("--pipe needs --nick or a profile with a nick"));
+    }
     if name.trim().is_empty() {
         println!("Enter your nickname:");
         while name.trim().is_empty() {
@@ -48,56 +145,254 @@ async fn main() -> Result<()> {
         }
     }
 
-    // Connect
-    println!("Connecting to {} ...", address);
-    let stream = TcpStream::connect(address.trim()).await?;
-    let _ = stream.set_nodelay(true);
-    let (reader, mut writer) = stream.into_split();
-
-    // Send nickname
-    writer
-        .write_all(format!("NICK {}\n", name.trim()).as_bytes())
-        .await?;
-
-    // Wait for server welcome/err with a short timeout so we fail fast.
-    let mut incoming = BufReader::new(reader).lines();
-    let first = timeout(Duration::from_secs(5), incoming.next_line())
-        .await
-        .map_err(|_| anyhow!("server did not respond in time"))??;
+    if options.password.is_none() && profile.keyring {
+        options.password = keyring::lookup(name.trim(), address.trim()).await;
+    }
 
-    match first {
-        Some(line) if line.starts_with("WELCOME") => {
-            println!("{line}");
-        }
-        Some(line) => {
-            println!("Connection rejected: {line}");
-            return Ok(());
+    // Codes last seconds, so they're asked for last.
+    if profile.two_factor {
+        if pipe {
+            return Err(anyhow!("--pipe can't ask for a two-factor code; use a profile without two_factor"));
         }
-        None => {
-            return Err(anyhow!("server closed connection during handshake"));
+        println!("Enter your two-factor code:");
+        let mut code = String::new();
+        while code.trim().is_empty() {
+            code = stdin.next_line().await?.unwrap_or_default();
         }
+        options.code = Some(code.trim().to_string());
     }
-    println!("Registered as: {}", name.trim());
 
-    // Listen for incoming messages
-    tokio::spawn(async move {
-        while let Ok(Some(line)) = incoming.next_line().await {
-            println!("{line}");
-            if line.starts_with("[server] disconnected") || line.starts_with("BYE") {
-                break;
+    // Connect
+    let (address, name) = (address.trim().to_string(), name.trim().to_string());
+    if pipe {
+        let conn = session::connect_with(&address, &name, &profile.channels, &options).await?;
+        pipe::run(Session::new(address, name, profile.channels, conn), stdin).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    match &options.proxy {
+        Some(proxy) => println!("Connecting to {address} via {} ...", proxy.addr()),
+        None => println!("Connecting to {} ...", address),
+    }
+    let mut tries = 0;
+    let conn = loop {
+        match session::connect_with(&address, &name, &profile.channels, &options).await {
+            Ok(conn) => break conn,
+            // A registered nick without the right password: ask for it, a
+            // few times, if there's someone at the terminal to answer.
+            Err(ConnectError::Rejected(line)) if session::needs_password(&line) && term::is_tty() && tries < MAX_PASSWORD_TRIES => {
+                tries += 1;
+                println!("{name} is registered. Password:");
+                let hidden = term::Hidden::enter()?;
+                let password = stdin.next_line().await?.unwrap_or_default();
+                drop(hidden);
+                options.password = Some(password.trim_end_matches('\r').to_string()).filter(|p| !p.is_empty());
+            }
+            Err(ConnectError::Rejected(line)) => {
+                println!("Connection rejected: {line}");
+                return Ok(ExitCode::SUCCESS);
             }
+            Err(ConnectError::Failed(e)) => return Err(e),
         }
-        println!("Server closed the connection");
-    });
+    };
+    println!("WELCOME {} {name}", conn.id);
+    println!("Registered as: {name}");
+    let mut session = Session::new(address, name, profile.channels, conn);
 
-    // Forward user input to server
-    while let Some(line) = stdin.next_line().await? {
-        if line.trim().is_empty() {
-            continue;
-        }
-        writer.write_all(line.as_bytes()).await?;
-        writer.write_all(b"\n").await?;
+    // Full-screen UI on a terminal, unless asked for plain lines.
+    let tty = term::is_tty();
+    let display = Display { color: style::enabled(no_color, tty), stamp: Stamp::new(&time_format) };
+    if !plain && tty {
+        let label = profile_name.unwrap_or_else(|| session.addr().to_string());
+        return tui::run(session, label, display, config, identity, proxy_arg).await.map(|()| ExitCode::SUCCESS);
     }
 
-    Ok(())
+    // Print what arrives and forward user input, reconnecting as needed.
+    let mut alerter = Alerter::new(&config.alerts);
+    let mut log = ChatLog::new(config.log.dir(), session.addr(), config.log.enabled);
+    let mut e2e = E2e::new(identity, KnownKeys::load(config::data_dir().join("known_keys"), session.addr())?);
+    let mut pinger = Pinger::new();
+    let mut outbox = Outbox::default();
+    loop {
+        tokio::select! {
+            update = session.next() => match update {
+                Update::Line(line) => {
+                    let (line, locked) = match e2e.incoming(session.name(), &line) {
+                        Incoming::Line { line, locked } => (line, locked),
+                        Incoming::Key { from, reply, first_seen } => {
+                            if let Some(reply) = reply {
+                                session.send_line(&commands::wire_line(&reply)).await;
+                            }
+                            println!("Got {from}'s key; DMs with {from} are now end-to-end encrypted");
+                            if first_seen {
+                                println!("It's the first key seen from {from}: compare /fingerprint {from} with them");
+                            }
+                            continue;
+                        }
+                        Incoming::KeyChanged { from } => {
+                            println!("WARNING: {from} sent a different key from the one first seen for them, so it isn't used.");
+                            println!("Compare /fingerprint {from} with them, then /trust {from} to accept it");
+                            continue;
+                        }
+                    };
+                    let event = Event::parse(line.as_bytes(), Wire::Text);
+                    if let Some(Event::Pong(token)) = &event
+                        && let Some(pong) = pinger.pong(token)
+                    {
+                        println!("Pong from {}: {} ms", session.addr(), pong.rtt.as_millis());
+                        continue;
+                    }
+                    if let Some(Event::Avatar { name, image }) = &event {
+                        match avatar::save(&config::data_dir().join("avatars"), name, image) {
+                            Ok(path) => println!("Saved {name}'s avatar to {}", path.display()),
+                            Err(e) => println!("{e:#}"),
+                        }
+                        continue;
+                    }
+                    if let Some(Event::Challenge { nonce, bits }) = &event {
+                        println!("Registering takes a proof of work ({bits} bits); working it out...");
+                        let (nonce, name, bits) = (nonce.clone(), session.name().to_string(), *bits);
+                        let n = tokio::task::spawn_blocking(move || pow::solve(&nonce, &name, bits)).await?;
+                        session.send(&Command::Proof(n)).await;
+                        continue;
+                    }
+                    if let Err(e) = log.received(&line) {
+                        log.enabled = false;
+                        println!("Logging stopped: {e}");
+                    }
+                    let stamp = display.stamp.for_event(event.as_ref());
+                    let shown = if locked { style::locked(style::server_line(&line)) } else { style::server_line(&line) };
+                    println!("{}", display.stamped(stamp, shown).render(display.color));
+                    if let Some(event) = &event {
+                        alerter.event(session.name(), event);
+                    }
+                    if let Some(Event::Dm { id, .. }) = event {
+                        session.send(&Command::Read(id)).await;
+                    }
+                }
+                Update::Lost { retry_in: Some(delay) } => {
+                    pinger.reset();
+                    outbox.disconnected();
+                    println!("Server closed the connection; reconnecting in {:.1}s", delay.as_secs_f64());
+                }
+                Update::Lost { retry_in: None } => {
+                    println!("Server closed the connection");
+                    return Ok(ExitCode::SUCCESS);
+                }
+                Update::RetryFailed { error, retry_in } => {
+                    println!("Reconnect failed ({error}); retrying in {:.1}s", retry_in.as_secs_f64());
+                }
+                Update::Reconnected { id } => {
+                    println!("Reconnected as {} (#{id})", session.name());
+                    log.reconnected();
+                    if outbox.reconnected() {
+                        println!("{} messages were typed while disconnected. Send them now? [y/N]", outbox.len());
+                    }
+                }
+            },
+            line = stdin.next_line() => match line? {
+                Some(answer) if outbox.is_asking() => {
+                    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                        println!("Dropped {} queued messages", outbox.discard());
+                        continue;
+                    }
+                    for line in outbox.flush() {
+                        session.send_line(&e2e.outgoing(session.name(), &line)).await;
+                        if let Err(e) = log.sent(session.name(), &line) {
+                            log.enabled = false;
+                            println!("Logging stopped: {e}");
+                        }
+                    }
+                }
+                Some(line) if line.trim().is_empty() => {}
+                Some(line) => {
+                    let parsed = commands::expand(&config.aliases, &line).and_then(|line| commands::parse(&line));
+                    let line = match parsed {
+                        Ok(Input::Send(cmd)) => commands::wire_line(&cmd),
+                        Ok(Input::Raw(line)) => line,
+                        Ok(Input::E2e(name)) => commands::wire_line(&e2e.offer(&name)),
+                        Ok(Input::Ping) => commands::wire_line(&pinger.asked()),
+                        Ok(Input::Avatar(path)) => match avatar::read(&path) {
+                            Ok(image) => commands::wire_line(&Command::Avatar(AvatarCommand::Set(Some(image)))),
+                            Err(e) => {
+                                println!("{e:#}");
+                                continue;
+                            }
+                        },
+                        Ok(Input::Fingerprint(name)) => {
+                            e2e.fingerprints(&name).iter().for_each(|line| println!("{line}"));
+                            continue;
+                        }
+                        Ok(Input::Trust(name)) => match e2e.trust(&name) {
+                            Ok(reply) => {
+                                println!("Trusting {name}'s new key");
+                                match reply {
+                                    Some(reply) => commands::wire_line(&reply),
+                                    None => continue,
+                                }
+                            }
+                            Err(msg) => {
+                                println!("{msg}");
+                                continue;
+                            }
+                        },
+                        Ok(Input::Help) => {
+                            commands::help(&config.aliases).iter().for_each(|help| println!("{help}"));
+                            continue;
+                        }
+                        Ok(Input::Mute(_) | Input::Unmute(_)) => {
+                            println!("Notifications are only shown in the full-screen UI");
+                            continue;
+                        }
+                        Ok(Input::Connect(..) | Input::Tab(_) | Input::Close) => {
+                            println!("More than one server needs the full-screen UI");
+                            continue;
+                        }
+                        Ok(Input::Search(_)) => {
+                            println!("Search needs the full-screen UI; use your terminal's search here");
+                            continue;
+                        }
+                        Ok(Input::Alerts(Some(on))) => {
+                            alerter.enabled = on;
+                            println!("Alerts {}", if on { "on" } else { "off" });
+                            continue;
+                        }
+                        Ok(Input::Alerts(None)) => {
+                            println!("Alerts are {}", if alerter.enabled { "on" } else { "off" });
+                            continue;
+                        }
+                        Ok(Input::Log(on)) => {
+                            if let Some(on) = on {
+                                log.enabled = on;
+                            }
+                            println!("Logging to {} is {}", log.dir().display(), if log.enabled { "on" } else { "off" });
+                            continue;
+                        }
+                        Ok(Input::Quit(message)) => {
+                            session.quit(message).await;
+                            return Ok(ExitCode::SUCCESS);
+                        }
+                        Err(msg) => {
+                            println!("{msg}");
+                            continue;
+                        }
+                    };
+                    // Logged as typed, before encryption.
+                    if !session.is_connected() {
+                        if outbox.hold(&line) {
+                            println!("Not connected; queued to send after reconnecting ({} waiting)", outbox.len());
+                        } else {
+                            println!("Not connected; not sent");
+                        }
+                    } else {
+                        session.send_line(&e2e.outgoing(session.name(), &line)).await;
+                        if let Err(e) = log.sent(session.name(), &line) {
+                            log.enabled = false;
+                            println!("Logging stopped: {e}");
+                        }
+                    }
+                }
+                None => return Ok(ExitCode::SUCCESS),
+            },
+        }
+    }
 }
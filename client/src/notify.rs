@@ -0,0 +1,102 @@
+//! Desktop notifications for direct messages and mentions that arrive while
+//! the terminal is in the background. They go through the platform's
+//! command-line notifier, `notify-send` (libnotify), `osascript` on macOS or
+//! PowerShell on Windows; if that isn't installed nothing is shown.
+
+use std::{collections::BTreeSet, process::Stdio};
+use tokio::process::Command;
+
+use protocol::Event;
+
+use crate::config::Notify;
+
+pub struct Notifier {
+    enabled: bool,
+    pub muted: BTreeSet<String>,
+    /// Terminals that don't report focus never lose it, so they get no
+    /// notifications.
+    focused: bool,
+}
+
+impl Notifier {
+    pub fn new(settings: &Notify) -> Self {
+        Notifier { enabled: settings.enabled, muted: settings.mute.iter().cloned().collect(), focused: true }
+    }
+
+    pub fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+    }
+
+    /// Shows `event` if it's a DM or mentions `me`, isn't from a muted
+    /// sender, and the terminal isn't focused.
+    pub fn event(&self, me: &str, event: &Event) {
+        if !self.enabled || self.focused {
+            return;
+        }
+        let (title, from, body) = match event {
+            Event::Dm { from, body, .. } => (from.clone(), from, body),
+            Event::ChannelMsg { channel, from, body, .. } if from != me && contains_word(body, me) => {
+                (format!("{from} in {channel}"), from, body)
+            }
+            _ => return,
+        };
+        if !self.muted.contains(from) {
+            show(&title, body);
+        }
+    }
+}
+
+/// Whether `word` appears in `text` on its own, ignoring case: "bob" is in
+/// "hi Bob!" but not in "bobby".
+pub fn contains_word(text: &str, word: &str) -> bool {
+    let (text, word) = (text.to_lowercase(), word.to_lowercase());
+    if word.is_empty() {
+        return false;
+    }
+    let is_word_char = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    text.match_indices(&word)
+        .any(|(i, _)| !is_word_char(text[..i].chars().next_back()) && !is_word_char(text[i + word.len()..].chars().next()))
+}
+
+const WINDOWS_BALLOON: &str = "Add-Type -AssemblyName System.Windows.Forms; \
+    $n = New-Object System.Windows.Forms.NotifyIcon; $n.Icon = [System.Drawing.SystemIcons]::Information; $n.Visible = $true; \
+    $n.ShowBalloonTip(5000, $env:RUSTCHAT_TITLE, $env:RUSTCHAT_BODY, 'None'); Start-Sleep 6; $n.Dispose()";
+
+fn show(title: &str, body: &str) {
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("osascript");
+        // Passed as arguments so nothing in the message needs quoting.
+        cmd.args(["-e", "on run argv", "-e", "display notification (item 2 of argv) with title (item 1 of argv)", "-e", "end run"]);
+        cmd
+    } else if cfg!(windows) {
+        // A tray balloon, which Windows 10 and later show as a toast. The
+        // text goes in the environment for the same reason as above.
+        let mut cmd = Command::new("powershell");
+        cmd.args(["-NoProfile", "-Command", WINDOWS_BALLOON]).env("RUSTCHAT_TITLE", title).env("RUSTCHAT_BODY", body);
+        cmd
+    } else {
+        let mut cmd = Command::new("notify-send");
+        cmd.args(["--app-name=rustchat", "--"]);
+        cmd
+    };
+    if !cfg!(windows) {
+        cmd.args([title, body]);
+    }
+    // The child is reaped in the background once it exits.
+    let _ = cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_whole_words() {
+        assert!(contains_word("hi Bob!", "bob"));
+        assert!(contains_word("bob", "bob"));
+        assert!(contains_word("ping @bob, please", "bob"));
+        assert!(!contains_word("hi bobby", "bob"));
+        assert!(!contains_word("kabob", "bob"));
+        assert!(!contains_word("anything", ""));
+    }
+}
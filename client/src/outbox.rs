@@ -0,0 +1,74 @@
+//! Messages typed while the connection is down. They're held rather than
+//! lost, and once reconnected they go out only when the user says so, since
+//! they may no longer be wanted.
+
+use protocol::{Command, Wire};
+
+#[derive(Default)]
+pub struct Outbox {
+    lines: Vec<String>,
+    /// Reconnected with lines waiting: the user gets asked about them.
+    asking: bool,
+}
+
+impl Outbox {
+    /// Holds `line` if it's a message; anything else isn't worth sending
+    /// late and gets `false`.
+    pub fn hold(&mut self, line: &str) -> bool {
+        let message = matches!(Command::parse(line.as_bytes(), Wire::Text), Command::To { .. } | Command::ToId { .. } | Command::Say { .. });
+        if message {
+            self.lines.push(line.to_string());
+        }
+        message
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// The connection is back. `true` if there's something to ask about.
+    pub fn reconnected(&mut self) -> bool {
+        self.asking = !self.lines.is_empty();
+        self.asking
+    }
+
+    /// Lost again before the user answered.
+    pub fn disconnected(&mut self) {
+        self.asking = false;
+    }
+
+    pub fn is_asking(&self) -> bool {
+        self.asking
+    }
+
+    /// The user said yes: everything held, oldest first.
+    pub fn flush(&mut self) -> Vec<String> {
+        self.asking = false;
+        std::mem::take(&mut self.lines)
+    }
+
+    /// The user said no. Returns how many were dropped.
+    pub fn discard(&mut self) -> usize {
+        self.flush().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_messages_until_asked() {
+        let mut outbox = Outbox::default();
+        assert!(outbox.hold("TO bob are you there"));
+        assert!(outbox.hold("SAY #rust hi"));
+        assert!(!outbox.hold("JOIN #rust"));
+        assert!(!outbox.is_asking());
+        assert!(outbox.reconnected());
+        outbox.disconnected();
+        assert!(!outbox.is_asking());
+        assert!(outbox.reconnected());
+        assert_eq!(outbox.flush(), ["TO bob are you there", "SAY #rust hi"]);
+        assert!(!outbox.reconnected());
+    }
+}
@@ -0,0 +1,111 @@
+//! Keepalive pings and `/ping`. The TUI pings each server every `INTERVAL`;
+//! a ping left unanswered for `LAG_AFTER` shows the connection as lagging
+//! in the status bar.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+use protocol::Command;
+
+pub const INTERVAL: Duration = Duration::from_secs(15);
+const LAG_AFTER: Duration = Duration::from_secs(5);
+
+pub struct Pinger {
+    next_token: u64,
+    /// Pings not answered yet: token, when it went out, and whether it was
+    /// a `/ping` rather than a keepalive.
+    waiting: Vec<(String, Instant, bool)>,
+    next_keepalive: Instant,
+    /// The last round trip measured.
+    rtt: Option<Duration>,
+}
+
+/// An answered ping.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pong {
+    pub rtt: Duration,
+    /// Asked for with `/ping`, so worth showing.
+    pub asked: bool,
+}
+
+impl Pinger {
+    pub fn new() -> Self {
+        Pinger { next_token: 1, waiting: Vec::new(), next_keepalive: Instant::now() + INTERVAL, rtt: None }
+    }
+
+    /// When the next keepalive is due.
+    pub fn deadline(&self) -> Instant {
+        self.next_keepalive
+    }
+
+    pub fn keepalive(&mut self) -> Command {
+        self.next_keepalive = Instant::now() + INTERVAL;
+        self.ping(false)
+    }
+
+    /// A ping for `/ping`.
+    pub fn asked(&mut self) -> Command {
+        self.ping(true)
+    }
+
+    fn ping(&mut self, asked: bool) -> Command {
+        let token = self.next_token.to_string();
+        self.next_token += 1;
+        self.waiting.push((token.clone(), Instant::now(), asked));
+        Command::Ping(token)
+    }
+
+    /// A pong arrived. `None` if it wasn't for one of our pings, or was for
+    /// one given up on.
+    pub fn pong(&mut self, token: &str) -> Option<Pong> {
+        let i = self.waiting.iter().position(|(t, ..)| t == token)?;
+        let (_, sent, asked) = self.waiting.remove(i);
+        let rtt = sent.elapsed();
+        self.rtt = Some(rtt);
+        Some(Pong { rtt, asked })
+    }
+
+    /// Forgets pings sent on a connection that's gone.
+    pub fn reset(&mut self) {
+        self.waiting.clear();
+        self.rtt = None;
+        self.next_keepalive = Instant::now() + INTERVAL;
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        !self.waiting.is_empty()
+    }
+
+    /// How long the oldest unanswered ping has waited, once that's more
+    /// than `LAG_AFTER`.
+    pub fn lag(&self) -> Option<Duration> {
+        let waited = self.waiting.first()?.1.elapsed();
+        (waited > LAG_AFTER).then_some(waited)
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_pongs_and_spots_lag() {
+        let mut pinger = Pinger::new();
+        let Command::Ping(keepalive) = pinger.keepalive() else { unreachable!() };
+        let Command::Ping(asked) = pinger.asked() else { unreachable!() };
+
+        assert!(pinger.pong(&asked).unwrap().asked);
+        assert_eq!(pinger.pong(&asked), None);
+        assert!(pinger.rtt().is_some());
+        assert_eq!(pinger.lag(), None);
+        pinger.waiting[0].1 -= LAG_AFTER * 2;
+        assert!(pinger.lag().is_some());
+        assert!(!pinger.pong(&keepalive).unwrap().asked);
+        assert_eq!(pinger.lag(), None);
+        assert!(!pinger.is_waiting());
+    }
+}
@@ -0,0 +1,53 @@
+//! `--pipe`: for driving the client from another program. Each stdin line
+//! goes to the server as a raw protocol command, and each event from the
+//! server comes out on stdout as one JSON object per line, starting with the
+//! `welcome`. Connection trouble is reported on stderr so stdout stays
+//! machine-readable. Ends when stdin does.
+
+use anyhow::Result;
+use std::io::Write;
+use tokio::io::{AsyncRead, BufReader, Lines};
+
+use protocol::{Event, Wire};
+use rustchat_client::session::{Session, Update};
+
+pub async fn run<R: AsyncRead + Unpin>(mut session: Session, mut stdin: Lines<BufReader<R>>) -> Result<()> {
+    emit(&Event::Welcome { id: session.id(), name: session.name().to_string() })?;
+    loop {
+        tokio::select! {
+            update = session.next() => match update {
+                // Lines that aren't events (there shouldn't be any) are dropped.
+                Update::Line(line) => {
+                    if let Some(event) = Event::parse(line.as_bytes(), Wire::Text) {
+                        emit(&event)?;
+                    }
+                }
+                Update::Lost { retry_in: Some(delay) } => eprintln!("connection lost; reconnecting in {:.1}s", delay.as_secs_f64()),
+                Update::Lost { retry_in: None } => {
+                    eprintln!("server closed the connection");
+                    return Ok(());
+                }
+                Update::RetryFailed { error, retry_in } => eprintln!("reconnect failed ({error}); retrying in {:.1}s", retry_in.as_secs_f64()),
+                Update::Reconnected { id } => emit(&Event::Welcome { id, name: session.name().to_string() })?,
+            },
+            line = stdin.next_line() => match line? {
+                Some(line) if line.trim().is_empty() => {}
+                Some(line) => {
+                    if !session.send_line(&line).await {
+                        eprintln!("not connected; dropped: {line}");
+                    }
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Writes `event` as a JSON line, flushed so a reader sees it straight away.
+fn emit(event: &Event) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    out.write_all(&event.encode(Wire::Json))?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! `client send`: connects, delivers one DM and exits, for scripts and cron
+//! jobs.
+//!
+//! ```text
+//! client send [--addr host:port|invite link] [--nick name] [--proxy url] [--password pw] [--profile p] [--config file] --to bob message...
+//! ```
+//!
+//! A registered nickname takes its password from `--password`, the profile,
+//! or the keyring if the profile has `keyring = true`.
+//!
+//! Exit status: 0 once the server confirms delivery, 1 if it couldn't deliver
+//! (no such user), 2 for bad arguments, 3 if connecting failed, the nickname
+//! was refused or the server didn't answer in time.
+
+use std::{process::ExitCode, time::Duration};
+
+use protocol::{Command, Event, Receipt, Wire, caps::Cap};
+use rustchat_client::{
+    invite::Invite,
+    proxy::Proxy,
+    session::{self, Session, Update},
+};
+use tokio::time::timeout;
+
+use crate::{config::Config, keyring};
+
+/// How long to wait for the server to confirm delivery.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn run(args: &[String]) -> ExitCode {
+    let Some(opts) = Options::parse(args) else {
+        eprintln!("usage: client send [--addr host:port] [--nick name] [--proxy url] [--password pw] [--profile p] [--config file] --to <name> <message>");
+        return ExitCode::from(2);
+    };
+    let profile = match Config::load(opts.config.as_deref()).and_then(|c| c.profile(opts.profile.as_deref())) {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+    let addr = opts.addr.or(profile.addr).unwrap_or_else(|| "127.0.0.1:5555".into());
+    let Some(nick) = opts.nick.or(profile.nick) else {
+        eprintln!("no nickname: pass --nick or set one in the profile");
+        return ExitCode::from(2);
+    };
+    let proxy = match opts.proxy.map(|url| Proxy::parse(&url)).transpose() {
+        Ok(proxy) => proxy.or(profile.proxy),
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    // An invite link works as the address; its token is needed on invite-only servers.
+    let (addr, token) = if Invite::is_link(&addr) {
+        match Invite::parse(&addr) {
+            Ok(invite) => (invite.addr, Some(invite.token)),
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::from(2);
+            }
+        }
+    } else {
+        (addr, None)
+    };
+
+    let mut password = opts.password.or(profile.password);
+    if password.is_none() && profile.keyring {
+        password = keyring::lookup(&nick, &addr).await;
+    }
+
+    let conn = match session::connect_with(&addr, &nick, &[], &session::Options { caps: vec![Cap::Ack], proxy, token, password, ..Default::default() }).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("connecting to {addr} failed: {e}");
+            return ExitCode::from(3);
+        }
+    };
+    let mut session = Session::new(addr, nick, Vec::new(), conn);
+    session.send(&Command::To { name: opts.to.clone(), body: opts.message }).await;
+
+    let outcome = timeout(ACK_TIMEOUT, async {
+        loop {
+            let Update::Line(line) = session.next().await else {
+                return Err("the connection dropped".to_string());
+            };
+            match Event::parse(line.as_bytes(), Wire::Text) {
+                Some(Event::Receipt { name, state: Receipt::Queued, .. }) if name == opts.to => return Ok(()),
+                Some(Event::Notice(msg)) if msg.starts_with("target ") => return Err(msg),
+                Some(Event::Error(msg)) => return Err(msg),
+                _ => {}
+            }
+        }
+    })
+    .await;
+    match outcome {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(msg)) if msg.starts_with("target ") => {
+            eprintln!("not delivered: {msg}");
+            ExitCode::from(1)
+        }
+        Ok(Err(msg)) => {
+            eprintln!("{msg}");
+            ExitCode::from(3)
+        }
+        Err(_) => {
+            eprintln!("no confirmation from the server within {}s", ACK_TIMEOUT.as_secs());
+            ExitCode::from(3)
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    addr: Option<String>,
+    nick: Option<String>,
+    proxy: Option<String>,
+    password: Option<String>,
+    profile: Option<String>,
+    config: Option<String>,
+    to: String,
+    message: String,
+}
+
+impl Options {
+    /// `None` unless there's a recipient and a non-empty message.
+    fn parse(args: &[String]) -> Option<Self> {
+        let mut opts = Options::default();
+        let mut words = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let slot = match arg.as_str() {
+                "--addr" => &mut opts.addr,
+                "--nick" => &mut opts.nick,
+                "--proxy" => &mut opts.proxy,
+                "--password" => &mut opts.password,
+                "--profile" => &mut opts.profile,
+                "--config" => &mut opts.config,
+                "--to" => {
+                    opts.to = args.next()?.clone();
+                    continue;
+                }
+                _ => {
+                    words.push(arg.as_str());
+                    continue;
+                }
+            };
+            *slot = Some(args.next()?.clone());
+        }
+        opts.message = words.join(" ");
+        (!opts.to.is_empty() && !opts.message.trim().is_empty()).then_some(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let opts = Options::parse(&args("--addr host:1 --to bob backup done --nick cron --password hunter2hunter2")).unwrap();
+        assert_eq!(opts.addr.as_deref(), Some("host:1"));
+        assert_eq!(opts.nick.as_deref(), Some("cron"));
+        assert_eq!(opts.password.as_deref(), Some("hunter2hunter2"));
+        assert_eq!(opts.to, "bob");
+        assert_eq!(opts.message, "backup done");
+        assert_eq!(Options::parse(&args("--to bob")), None);
+        assert_eq!(Options::parse(&args("hello --to")), None);
+    }
+}
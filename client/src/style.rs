@@ -0,0 +1,335 @@
+//! Colored output. Each nickname gets a color derived from the name, so the
+//! same person looks the same every time; server notices and errors get their
+//! own styling. Text is kept as styled spans so the TUI can wrap and pad it by
+//! visible width.
+
+use protocol::{
+    format::{self, Format},
+    Event, FriendStatus, Presence,
+};
+
+use crate::clock::Stamp;
+
+/// Colors nicknames are spread over: the basic and bright ANSI colors,
+/// leaving out black, white and the red used for errors.
+const NICK_COLORS: &[u8] = &[32, 33, 34, 35, 36, 92, 93, 94, 95, 96];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    /// A nickname, with its ANSI color.
+    Nick(u8),
+    Notice,
+    Error,
+    Dim,
+    Bold,
+    Italic,
+    /// `code` in a message.
+    Code,
+    /// A `/search` match.
+    Match,
+}
+
+impl Style {
+    fn sgr(self) -> String {
+        match self {
+            Style::Plain => String::new(),
+            Style::Nick(color) => format!("\x1b[{color}m"),
+            Style::Notice => "\x1b[33m".into(),
+            Style::Error => "\x1b[1;31m".into(),
+            Style::Dim => "\x1b[2m".into(),
+            Style::Bold => "\x1b[1m".into(),
+            Style::Italic => "\x1b[3m".into(),
+            Style::Code => "\x1b[36m".into(),
+            Style::Match => "\x1b[30;43m".into(),
+        }
+    }
+}
+
+/// How messages are shown, in either mode.
+pub struct Display {
+    pub color: bool,
+    pub stamp: Stamp,
+}
+
+impl Display {
+    /// `line` prefixed with its timestamp, if timestamps are on.
+    pub fn stamped(&self, stamp: Option<String>, line: Line) -> Line {
+        match stamp {
+            Some(stamp) => Line::styled(Style::Dim, format!("{stamp} ")).then(line),
+            None => line,
+        }
+    }
+}
+
+/// Whether to use color: not with `--no-color`, not when `NO_COLOR` is set
+/// (see no-color.org), and only on a terminal.
+pub fn enabled(no_color_flag: bool, is_tty: bool) -> bool {
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    is_tty && !no_color_flag && !no_color_env
+}
+
+pub fn nick(name: &str) -> Style {
+    // FNV-1a: stable across runs and platforms, unlike the std hasher.
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ u64::from(b)).wrapping_mul(0x100_0000_01b3));
+    Style::Nick(NICK_COLORS[(hash % NICK_COLORS.len() as u64) as usize])
+}
+
+/// One line of output made of styled spans.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Line {
+    spans: Vec<(Style, String)>,
+}
+
+impl Line {
+    pub fn plain(text: impl Into<String>) -> Self {
+        Line::default().with(Style::Plain, text)
+    }
+
+    pub fn styled(style: Style, text: impl Into<String>) -> Self {
+        Line::default().with(style, text)
+    }
+
+    /// Appends a span. Control characters are dropped so nothing in a
+    /// message can move the cursor or change colors.
+    pub fn with(mut self, style: Style, text: impl Into<String>) -> Self {
+        let text: String = text.into().chars().filter(|c| !c.is_control()).collect();
+        if !text.is_empty() {
+            self.spans.push((style, text));
+        }
+        self
+    }
+
+    pub fn then(mut self, other: Line) -> Self {
+        self.spans.extend(other.spans);
+        self
+    }
+
+    pub fn width(&self) -> usize {
+        self.spans.iter().map(|(_, text)| text.chars().count()).sum()
+    }
+
+    /// With escape codes if `color`, otherwise just the text.
+    pub fn render(&self, color: bool) -> String {
+        let mut out = String::new();
+        for (style, text) in &self.spans {
+            if color && *style != Style::Plain {
+                out.push_str(&style.sgr());
+                out.push_str(text);
+                out.push_str("\x1b[0m");
+            } else {
+                out.push_str(text);
+            }
+        }
+        out
+    }
+
+    /// Whether `highlight` would find `term`.
+    pub fn contains(&self, term: &str) -> bool {
+        let term = term.to_ascii_lowercase();
+        self.spans.iter().any(|(_, text)| text.to_ascii_lowercase().contains(&term))
+    }
+
+    /// With every occurrence of `term` (ignoring ASCII case) restyled as a
+    /// match. Occurrences split across spans aren't found.
+    pub fn highlight(&self, term: &str) -> Line {
+        if term.is_empty() {
+            return self.clone();
+        }
+        let term = term.to_ascii_lowercase();
+        let mut out = Line::default();
+        for (style, text) in &self.spans {
+            let lower = text.to_ascii_lowercase();
+            let mut at = 0;
+            for (i, _) in lower.match_indices(&term) {
+                out = out.with(*style, &text[at..i]).with(Style::Match, &text[i..i + term.len()]);
+                at = i + term.len();
+            }
+            out = out.with(*style, &text[at..]);
+        }
+        out
+    }
+
+    /// Split into lines of at most `width` columns.
+    pub fn wrap(&self, width: usize) -> Vec<Line> {
+        let width = width.max(1);
+        let mut rows = vec![Line::default()];
+        let mut used = 0;
+        for (style, text) in &self.spans {
+            let mut rest: &str = text;
+            while !rest.is_empty() {
+                if used == width {
+                    rows.push(Line::default());
+                    used = 0;
+                }
+                let take = rest.char_indices().nth(width - used).map_or(rest.len(), |(i, _)| i);
+                let (head, tail) = rest.split_at(take);
+                used += head.chars().count();
+                let row = rows.last_mut().unwrap();
+                row.spans.push((*style, head.to_string()));
+                rest = tail;
+            }
+        }
+        rows
+    }
+
+    /// Cut or padded to exactly `width` columns.
+    pub fn fit(&self, width: usize) -> Line {
+        let mut line = self.wrap(width).swap_remove(0);
+        let pad = width.saturating_sub(line.width());
+        if pad > 0 {
+            line.spans.push((Style::Plain, " ".repeat(pad)));
+        }
+        line
+    }
+}
+
+/// Marks a message that was end-to-end encrypted.
+pub fn locked(line: Line) -> Line {
+    Line::styled(Style::Notice, "[e2e] ").then(line)
+}
+
+/// A server line as the plain client prints it: the wire text unchanged, with
+/// the sender's name colored and notices and errors styled.
+pub fn server_line(line: &str) -> Line {
+    match Event::parse(line.as_bytes(), protocol::Wire::Text) {
+        Some(Event::Dm { id, seq, ts, from, from_id, body }) => Line::plain(format!("[{ts}] #{id} seq={seq} from "))
+            .with(nick(&from), from)
+            .with(Style::Plain, format!("({from_id}): {body}")),
+        Some(Event::ChannelMsg { channel, id, ts, from, from_id, body }) => {
+            Line::plain(format!("[{ts}] #{id} in {channel} from "))
+                .with(nick(&from), from)
+                .with(Style::Plain, format!("({from_id}): {body}"))
+        }
+        Some(Event::Presence { name, presence }) => presence_line(&name, &presence),
+        Some(Event::Friend { name, status }) => friend_line(&name, status),
+        Some(Event::Ephemeral { name, on }) => ephemeral_line(&name, on),
+        Some(Event::Preview { id, url, title }) => preview_line(id, &url, &title),
+        Some(Event::Attachment { id, url, size, name }) => attachment_line(id, &url, size, &name),
+        Some(Event::Verified { id, key }) => verified_line(id, &key),
+        Some(Event::Voice { channel, name, joined }) => voice_line(&channel, &name, joined),
+        Some(Event::VoiceToken { channel, port, .. }) => voice_token_line(&channel, port),
+        Some(Event::Notice(_)) => Line::styled(Style::Notice, line),
+        Some(Event::Error(_)) => Line::styled(Style::Error, line),
+        _ => Line::plain(line),
+    }
+}
+
+/// A message body with its `*bold*`, `_italic_` and `` `code` `` shown as
+/// such.
+pub fn formatted(body: &str) -> Line {
+    format::spans(body).into_iter().fold(Line::default(), |line, (format, text)| {
+        let style = match format {
+            Format::Plain => Style::Plain,
+            Format::Bold => Style::Bold,
+            Format::Italic => Style::Italic,
+            Format::Code => Style::Code,
+        };
+        line.with(style, text)
+    })
+}
+
+/// A watched user's presence, in words.
+pub fn presence_line(name: &str, presence: &Presence) -> Line {
+    let what = match presence {
+        Presence::Online => " is online".to_string(),
+        Presence::Away(message) => format!(" is away: {message}"),
+        Presence::Offline => " is offline".to_string(),
+    };
+    Line::styled(nick(name), name).with(Style::Notice, what)
+}
+
+/// A change on the friend list, in words.
+pub fn friend_line(name: &str, status: FriendStatus) -> Line {
+    let what = match status {
+        FriendStatus::Sent => " has been asked to be friends".to_string(),
+        FriendStatus::Received => format!(" wants to be friends: /friend add {name} to accept"),
+        FriendStatus::Friends => " is your friend".to_string(),
+        FriendStatus::Removed => " is off your friend list".to_string(),
+    };
+    Line::styled(nick(name), name).with(Style::Notice, what)
+}
+
+/// The title of a link in message `id`, under the message.
+pub fn preview_line(id: u64, url: &str, title: &str) -> Line {
+    Line::styled(Style::Dim, format!("  ↳ #{id} ")).with(Style::Bold, title).with(Style::Dim, format!(" ({url})"))
+}
+
+/// The file a link in message `id` leads to, under the message.
+pub fn attachment_line(id: u64, url: &str, size: u64, name: &str) -> Line {
+    let size = match size {
+        0..1024 => format!("{size} B"),
+        1024..1_048_576 => format!("{:.1} KiB", size as f64 / 1024.0),
+        _ => format!("{:.1} MiB", size as f64 / 1_048_576.0),
+    };
+    Line::styled(Style::Dim, format!("  ↳ #{id} file ")).with(Style::Bold, name).with(Style::Dim, format!(" ({size}) {url}"))
+}
+
+/// That message `id` was signed with its sender's key, under the message.
+pub fn verified_line(id: u64, key: &str) -> Line {
+    Line::styled(Style::Dim, format!("  ↳ #{id} ")).with(Style::Bold, "verified").with(Style::Dim, format!(" (key {})", key.get(..16).unwrap_or(key)))
+}
+
+/// Someone joining or leaving a channel's voice session, in words.
+pub fn voice_line(channel: &str, name: &str, joined: bool) -> Line {
+    let what = if joined { " is in voice on " } else { " left voice on " };
+    Line::styled(nick(name), name).with(Style::Notice, format!("{what}{channel}"))
+}
+
+/// Our own place in a voice session. This client has no audio, so it says
+/// where the audio would go.
+pub fn voice_token_line(channel: &str, port: u16) -> Line {
+    Line::styled(Style::Notice, format!("in voice on {channel}: audio goes over UDP port {port}, which this client doesn't send or play"))
+}
+
+/// A DM thread becoming ephemeral or not, in words.
+pub fn ephemeral_line(name: &str, on: bool) -> Line {
+    let what = if on { ": messages are now ephemeral, not kept by the server or logged" } else { ": messages are kept again" };
+    Line::styled(Style::Notice, "DMs with ").with(nick(name), name).with(Style::Notice, what)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nick_colors_are_stable() {
+        assert_eq!(nick("alice"), nick("alice"));
+        assert!(["alice", "bob", "carol", "dave"].iter().any(|n| nick(n) != nick("alice")));
+    }
+
+    #[test]
+    fn wraps_across_spans() {
+        let line = Line::plain("ab").with(Style::Bold, "cdef").with(Style::Plain, "g");
+        let rows = line.wrap(3);
+        assert_eq!(rows.iter().map(|r| r.render(false)).collect::<Vec<_>>(), ["abc", "def", "g"]);
+        assert_eq!(rows[0], Line::plain("ab").with(Style::Bold, "c"));
+        assert_eq!(line.fit(9).render(false), "abcdefg  ");
+        assert_eq!(line.fit(2).render(false), "ab");
+    }
+
+    #[test]
+    fn highlights_matches() {
+        let line = Line::plain("say Hi, ").with(Style::Bold, "hi there");
+        assert!(line.contains("HI T") && !line.contains("hi, hi"));
+        assert_eq!(
+            line.highlight("hi"),
+            Line::plain("say ").with(Style::Match, "Hi").with(Style::Plain, ", ").with(Style::Match, "hi").with(Style::Bold, " there")
+        );
+        assert_eq!(line.highlight("zzz"), line);
+    }
+
+    #[test]
+    fn formats_message_bodies() {
+        let line = formatted("a *big* _deal_: `cargo t`");
+        assert_eq!(line.render(false), "a big deal: cargo t");
+        assert_eq!(line, Line::plain("a ").with(Style::Bold, "big").with(Style::Plain, " ").with(Style::Italic, "deal").with(Style::Plain, ": ").with(Style::Code, "cargo t"));
+    }
+
+    #[test]
+    fn keeps_wire_text_in_plain_output() {
+        let line = "[2026-01-01T00:00:00Z] #4 seq=2 from bob(7): hi";
+        assert_eq!(server_line(line).render(false), line);
+        assert_eq!(server_line(line).render(true), format!("[2026-01-01T00:00:00Z] #4 seq=2 from {}bob\x1b[0m(7): hi", nick("bob").sgr()));
+    }
+}
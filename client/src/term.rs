@@ -0,0 +1,410 @@
+//! Raw terminal access for the TUI: switching the terminal into raw mode on
+//! the alternate screen, asking its size, and decoding keypresses from the
+//! bytes it sends.
+
+use std::io::{self, IsTerminal, Write};
+
+/// Raw mode on the alternate screen, with focus reporting and bracketed
+/// paste turned on for terminals that support them. Dropping it puts the terminal back the
+/// way it was, including when unwinding from a panic.
+pub struct Screen {
+    original: sys::Mode,
+}
+
+impl Screen {
+    pub fn enter() -> io::Result<Self> {
+        let original = sys::raw_mode()?;
+        let mut out = io::stdout();
+        out.write_all(b"\x1b[?1049h\x1b[?1004h\x1b[?2004h\x1b[2J")?;
+        out.flush()?;
+        Ok(Screen { original })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        let _ = out.write_all(b"\x1b[0m\x1b[?25h\x1b[?2004l\x1b[?1004l\x1b[?1049l");
+        let _ = out.flush();
+        sys::restore(&self.original);
+    }
+}
+
+/// Typing that isn't shown, for a password. Dropping it turns echo back on.
+pub struct Hidden {
+    original: sys::Mode,
+}
+
+impl Hidden {
+    pub fn enter() -> io::Result<Self> {
+        Ok(Hidden { original: sys::no_echo()? })
+    }
+}
+
+impl Drop for Hidden {
+    fn drop(&mut self) {
+        // The Enter that ended the line wasn't echoed either.
+        println!();
+        sys::restore(&self.original);
+    }
+}
+
+/// Whether both stdin and stdout are terminals, so a TUI makes sense.
+pub fn is_tty() -> bool {
+    io::stdin().is_terminal() && io::stdout().is_terminal()
+}
+
+/// Columns and rows, or 80x24 if the terminal won't say.
+pub fn size() -> (usize, usize) {
+    sys::size().filter(|&(cols, rows)| cols > 0 && rows > 0).unwrap_or((80, 24))
+}
+
+/// Notices the terminal changing size: SIGWINCH on Unix, and on Windows,
+/// where the console only reports it as an input record, by checking every
+/// quarter second.
+pub struct Resized {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    last: (usize, usize),
+}
+
+impl Resized {
+    #[cfg(unix)]
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Resized { signal: signal(SignalKind::window_change())? })
+    }
+
+    #[cfg(windows)]
+    pub fn new() -> io::Result<Self> {
+        Ok(Resized { last: size() })
+    }
+
+    #[cfg(unix)]
+    pub async fn recv(&mut self) {
+        self.signal.recv().await;
+    }
+
+    #[cfg(windows)]
+    pub async fn recv(&mut self) {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+            let now = size();
+            if now != self.last {
+                self.last = now;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+
+    pub type Mode = libc::termios;
+
+    /// Turns off echo, line buffering and signal keys, returning the mode to
+    /// go back to.
+    pub fn raw_mode() -> io::Result<Mode> {
+        // SAFETY: termios is plain data, filled in by tcgetattr before use.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+        raw.c_cflag |= libc::CS8;
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(original)
+    }
+
+    /// Turns off echo alone, so lines are still read whole.
+    pub fn no_echo() -> io::Result<Mode> {
+        // SAFETY: as in raw_mode.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut hidden = original;
+        hidden.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(original)
+    }
+
+    pub fn restore(original: &Mode) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original) };
+    }
+
+    pub fn size() -> Option<(usize, usize)> {
+        // SAFETY: winsize is plain data that TIOCGWINSZ fills in.
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) } == 0;
+        ok.then_some((usize::from(ws.ws_col), usize::from(ws.ws_row)))
+    }
+}
+
+/// The Windows console speaks the same escape sequences once it's asked to:
+/// virtual terminal input makes keys arrive as the bytes a Unix terminal
+/// would send, so the decoder below works unchanged, and virtual terminal
+/// processing makes it understand what we draw.
+#[cfg(windows)]
+mod sys {
+    use std::{ffi::c_void, io};
+
+    type Handle = *mut c_void;
+
+    const STD_INPUT_HANDLE: u32 = -10i32 as u32;
+    const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+    const ENABLE_PROCESSED_INPUT: u32 = 0x0001;
+    const ENABLE_LINE_INPUT: u32 = 0x0002;
+    const ENABLE_ECHO_INPUT: u32 = 0x0004;
+    const ENABLE_VIRTUAL_TERMINAL_INPUT: u32 = 0x0200;
+    const ENABLE_PROCESSED_OUTPUT: u32 = 0x0001;
+    const ENABLE_VIRTUAL_TERMINAL_PROCESSING: u32 = 0x0004;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Coord {
+        x: i16,
+        y: i16,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct SmallRect {
+        left: i16,
+        top: i16,
+        right: i16,
+        bottom: i16,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ScreenBufferInfo {
+        size: Coord,
+        cursor: Coord,
+        attributes: u16,
+        window: SmallRect,
+        max_window: Coord,
+    }
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetStdHandle(which: u32) -> Handle;
+        fn GetConsoleMode(console: Handle, mode: *mut u32) -> i32;
+        fn SetConsoleMode(console: Handle, mode: u32) -> i32;
+        fn GetConsoleScreenBufferInfo(console: Handle, info: *mut ScreenBufferInfo) -> i32;
+    }
+
+    /// The input and output modes before we changed them.
+    pub struct Mode {
+        input: u32,
+        output: u32,
+    }
+
+    pub fn raw_mode() -> io::Result<Mode> {
+        // SAFETY: the handles are the process's own console handles, and the
+        // mode pointers are to live locals.
+        unsafe {
+            let (stdin, stdout) = (GetStdHandle(STD_INPUT_HANDLE), GetStdHandle(STD_OUTPUT_HANDLE));
+            let mut original = Mode { input: 0, output: 0 };
+            if GetConsoleMode(stdin, &mut original.input) == 0 || GetConsoleMode(stdout, &mut original.output) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let input = (original.input & !(ENABLE_PROCESSED_INPUT | ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT)) | ENABLE_VIRTUAL_TERMINAL_INPUT;
+            let output = original.output | ENABLE_PROCESSED_OUTPUT | ENABLE_VIRTUAL_TERMINAL_PROCESSING;
+            if SetConsoleMode(stdin, input) == 0 || SetConsoleMode(stdout, output) == 0 {
+                let err = io::Error::last_os_error();
+                restore(&original);
+                return Err(err);
+            }
+            Ok(original)
+        }
+    }
+
+    /// Turns off echo alone, so lines are still read whole.
+    pub fn no_echo() -> io::Result<Mode> {
+        // SAFETY: as in raw_mode.
+        unsafe {
+            let (stdin, stdout) = (GetStdHandle(STD_INPUT_HANDLE), GetStdHandle(STD_OUTPUT_HANDLE));
+            let mut original = Mode { input: 0, output: 0 };
+            if GetConsoleMode(stdin, &mut original.input) == 0 || GetConsoleMode(stdout, &mut original.output) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if SetConsoleMode(stdin, original.input & !ENABLE_ECHO_INPUT) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(original)
+        }
+    }
+
+    pub fn restore(original: &Mode) {
+        unsafe {
+            SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), original.input);
+            SetConsoleMode(GetStdHandle(STD_OUTPUT_HANDLE), original.output);
+        }
+    }
+
+    pub fn size() -> Option<(usize, usize)> {
+        let mut info = ScreenBufferInfo::default();
+        // SAFETY: info is plain data that the call fills in.
+        let ok = unsafe { GetConsoleScreenBufferInfo(GetStdHandle(STD_OUTPUT_HANDLE), &mut info) } != 0;
+        let (cols, rows) = (info.window.right - info.window.left + 1, info.window.bottom - info.window.top + 1);
+        ok.then(|| (usize::try_from(cols).unwrap_or(0), usize::try_from(rows).unwrap_or(0)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    /// Ctrl plus a letter, as the lowercase letter.
+    Ctrl(char),
+    /// Alt (sent as ESC first) plus a digit.
+    Alt(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Esc,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    /// The terminal window gained or lost focus (see `Screen::enter`).
+    FocusIn,
+    FocusOut,
+    /// Everything between these was pasted rather than typed.
+    PasteStart,
+    PasteEnd,
+}
+
+/// Turns terminal input into keys. A read can end partway through an escape
+/// sequence or a multi-byte character; the rest is held until the next read.
+#[derive(Default)]
+pub struct Decoder {
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(bytes);
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < self.pending.len() {
+            let Some((key, used)) = decode(&self.pending[i..]) else {
+                break;
+            };
+            keys.extend(key);
+            i += used;
+        }
+        self.pending.drain(..i);
+        keys
+    }
+}
+
+/// One key from the front of `buf` and how many bytes it used, `None` if
+/// `buf` ends before the key does. Unrecognised input uses bytes but gives
+/// no key.
+fn decode(buf: &[u8]) -> Option<(Option<Key>, usize)> {
+    let key = match buf[0] {
+        0x1b => return decode_escape(buf),
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        b @ 0x01..=0x1a => Key::Ctrl(char::from(b'a' + b - 1)),
+        b if b < 0x20 => return Some((None, 1)),
+        b => {
+            let len = match b {
+                0x00..=0x7f => 1,
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return Some((None, 1)),
+            };
+            let bytes = buf.get(..len)?;
+            return Some(match std::str::from_utf8(bytes) {
+                Ok(s) => (s.chars().next().map(Key::Char), len),
+                Err(_) => (None, 1),
+            });
+        }
+    };
+    Some((Some(key), 1))
+}
+
+fn decode_escape(buf: &[u8]) -> Option<(Option<Key>, usize)> {
+    match buf.get(1) {
+        // A lone ESC at the end of a read is the Esc key itself.
+        None => return Some((Some(Key::Esc), 1)),
+        Some(b'[' | b'O') => {}
+        Some(&d @ b'0'..=b'9') => return Some((Some(Key::Alt(char::from(d))), 2)),
+        Some(_) => return Some((Some(Key::Esc), 1)),
+    }
+    // CSI/SS3: parameters, then a final byte in @..~
+    let end = buf[2..].iter().position(|b| (0x40..=0x7e).contains(b))? + 2;
+    let key = match &buf[2..=end] {
+        b"A" => Key::Up,
+        b"B" => Key::Down,
+        b"C" => Key::Right,
+        b"D" => Key::Left,
+        b"H" | b"1~" | b"7~" => Key::Home,
+        b"F" | b"4~" | b"8~" => Key::End,
+        b"3~" => Key::Delete,
+        b"5~" => Key::PageUp,
+        b"6~" => Key::PageDown,
+        b"I" => Key::FocusIn,
+        b"O" => Key::FocusOut,
+        b"200~" => Key::PasteStart,
+        b"201~" => Key::PasteEnd,
+        _ => return Some((None, end + 1)),
+    };
+    Some((Some(key), end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_keys() {
+        let mut d = Decoder::default();
+        assert_eq!(
+            d.feed("hé\r\x7f\x03\x1b[A\x1b[3~\x1bOH".as_bytes()),
+            vec![
+                Key::Char('h'),
+                Key::Char('é'),
+                Key::Enter,
+                Key::Backspace,
+                Key::Ctrl('c'),
+                Key::Up,
+                Key::Delete,
+                Key::Home
+            ]
+        );
+    }
+
+    #[test]
+    fn holds_partial_input() {
+        let mut d = Decoder::default();
+        assert_eq!(d.feed(b"\x1b[5"), vec![]);
+        assert_eq!(d.feed(b"~\xc3"), vec![Key::PageUp]);
+        assert_eq!(d.feed(b"\xa9"), vec![Key::Char('é')]);
+        assert_eq!(d.feed(b"\x1b"), vec![Key::Esc]);
+        assert_eq!(d.feed(b"\x1b[O\x1b[I"), vec![Key::FocusOut, Key::FocusIn]);
+        assert_eq!(d.feed(b"\x1b2\x1bx"), vec![Key::Alt('2'), Key::Esc, Key::Char('x')]);
+        assert_eq!(d.feed(b"\x1b[200~a\r"), vec![Key::PasteStart, Key::Char('a'), Key::Enter]);
+        assert_eq!(d.feed(b"b\x1b[201~"), vec![Key::Char('b'), Key::PasteEnd]);
+    }
+}
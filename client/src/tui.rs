@@ -0,0 +1,901 @@
+//! Full-screen terminal UI: messages on the left, known users on the right,
+//! a status bar, and an input line that incoming messages can't clobber.
+//! PageUp/PageDown scroll back through earlier messages and `/search`
+//! highlights text and jumps to it. Each server is a tab of its own; more are
+//! opened with `/connect <profile>` and switched between with Alt-1..9.
+//! End-to-end encrypted DMs are marked with `[e2e]`. After a while without
+//! keystrokes we're marked away on every server, and back on the next key.
+
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    future::{poll_fn, Future},
+    io::{self, Read, Write},
+    path::PathBuf,
+    task::Poll,
+};
+use tokio::{
+    sync::mpsc,
+    time::{interval, sleep_until, Duration, Instant},
+};
+
+use protocol::{pow, AvatarCommand, Command, Event, FriendStatus, Presence, Wire};
+use rustchat_client::{
+    invite::Invite,
+    proxy::Proxy,
+    session::{self, Connection, ConnectError, Options, Session, Update},
+};
+
+use crate::{
+    alert::Alerter,
+    avatar,
+    away::AutoAway,
+    chatlog::ChatLog,
+    commands::{self, Input},
+    config::{self, Config, Profile},
+    e2e::{E2e, Identity, Incoming, KnownKeys},
+    editor::LineEditor,
+    keyring,
+    notify::Notifier,
+    outbox::Outbox,
+    ping::{self, Pinger},
+    style::{self, Display, Line, Style},
+    term::{self, Decoder, Key, Resized, Screen},
+};
+
+/// Messages kept for display per tab; older ones are forgotten.
+const SCROLLBACK: usize = 1000;
+const SIDEBAR_WIDTH: usize = 20;
+
+struct App {
+    tabs: Vec<Tab>,
+    current: usize,
+    display: Display,
+    input: LineEditor,
+    aliases: BTreeMap<String, String>,
+    profiles: BTreeMap<String, Profile>,
+    notifier: Notifier,
+    alerter: Alerter,
+    away: AutoAway,
+    log_dir: PathBuf,
+    log_enabled: bool,
+    identity: Identity,
+    known_keys: PathBuf,
+    /// From `--proxy`, for profiles that don't name their own.
+    proxy: Option<Proxy>,
+    /// Text arriving in a bracketed paste, until the terminal ends it.
+    pasting: Option<String>,
+    /// A multi-line paste waiting for Enter to send it or Esc to drop it.
+    pasted: Vec<String>,
+    /// Finished `/connect` attempts.
+    connected: mpsc::UnboundedSender<Connected>,
+}
+
+/// One server connection and what's been shown for it.
+struct Tab {
+    label: String,
+    session: Session,
+    messages: Vec<Line>,
+    /// Everyone seen in a message so far, plus us.
+    users: BTreeSet<String>,
+    /// The friend list and requests either way, as the server last told us,
+    /// with friends' presence.
+    friends: BTreeMap<String, (FriendStatus, Option<Presence>)>,
+    /// Who our ephemeral DM threads are with.
+    ephemeral: BTreeSet<String>,
+    log: ChatLog,
+    e2e: E2e,
+    /// Keepalives, and the lag and round trip shown in the status bar.
+    ping: Pinger,
+    /// Messages typed while disconnected.
+    outbox: Outbox,
+    /// Rows the view is scrolled up from the newest message.
+    scroll: usize,
+    search: Option<Search>,
+    /// Something arrived since the tab was last looked at.
+    unread: bool,
+    /// DMs that arrived meanwhile, reported read when the tab is looked at.
+    unread_dms: Vec<u64>,
+}
+
+/// A `/search` term, highlighted wherever it's shown.
+struct Search {
+    term: String,
+    /// The message last jumped to; searching again looks further back.
+    hit: Option<usize>,
+}
+
+struct Connected {
+    label: String,
+    addr: String,
+    nick: String,
+    channels: Vec<String>,
+    result: Result<Connection, ConnectError>,
+}
+
+pub async fn run(session: Session, label: String, display: Display, config: Config, identity: Identity, proxy: Option<Proxy>) -> Result<()> {
+    let _screen = Screen::enter()?;
+    let mut keys = stdin_keys();
+    let mut resized = Resized::new()?;
+    // Keeps the reconnect countdown in the status bar moving.
+    let mut tick = interval(Duration::from_secs(1));
+    let (connected, mut connects) = mpsc::unbounded_channel();
+    let mut app = App {
+        tabs: Vec::new(),
+        current: 0,
+        display,
+        input: LineEditor::default(),
+        aliases: config.aliases,
+        profiles: config.profiles,
+        notifier: Notifier::new(&config.notify),
+        alerter: Alerter::new(&config.alerts),
+        away: AutoAway::new(&config.away),
+        log_dir: config.log.dir(),
+        log_enabled: config.log.enabled,
+        identity,
+        known_keys: config::data_dir().join("known_keys"),
+        proxy,
+        pasting: None,
+        pasted: Vec::new(),
+        connected,
+    };
+    app.open(label, session);
+
+    loop {
+        app.draw(&mut io::stdout().lock())?;
+        let keepalive = app.next_keepalive();
+        tokio::select! {
+            (tab, update) = next_update(&mut app.tabs) => app.on_update(tab, update).await,
+            Some(batch) = keys.recv() => {
+                if batch.iter().any(|key| !matches!(key, Key::FocusIn | Key::FocusOut))
+                    && let Some(back) = app.away.input()
+                {
+                    app.send_all(&back).await;
+                }
+                for key in batch {
+                    if let Some(text) = &mut app.pasting {
+                        match key {
+                            Key::PasteEnd => app.on_paste(),
+                            Key::Char(c) => text.push(c),
+                            Key::Enter => text.push('\n'),
+                            Key::Tab => text.push('\t'),
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    match key {
+                        Key::Ctrl('c') => return Ok(()),
+                        Key::PasteStart => app.pasting = Some(String::new()),
+                        Key::Esc if !app.pasted.is_empty() => {
+                            let msg = format!("dropped {} pasted lines", app.pasted.len());
+                            app.pasted.clear();
+                            app.push(Line::styled(Style::Dim, msg));
+                        }
+                        // Each pasted line goes after whatever was typed, so
+                        // "/msg bob " then a paste sends every line to bob.
+                        Key::Enter if !app.pasted.is_empty() => {
+                            let prefix = app.input.key(key).unwrap_or_default();
+                            for line in std::mem::take(&mut app.pasted) {
+                                if !app.submit(format!("{prefix}{line}")).await {
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        Key::Enter if app.input.is_empty() && app.tabs[app.current].outbox.is_asking() => {
+                            for line in app.tab().outbox.flush() {
+                                app.deliver(line).await;
+                            }
+                        }
+                        Key::Esc if app.tabs[app.current].outbox.is_asking() => {
+                            let msg = format!("dropped {} queued messages", app.tab().outbox.discard());
+                            app.push(Line::styled(Style::Dim, msg));
+                        }
+                        Key::Ctrl('d') if app.input.is_empty() => return Ok(()),
+                        Key::Tab => app.input.complete(&app.tabs[app.current].users),
+                        Key::FocusIn | Key::FocusOut => app.notifier.set_focused(key == Key::FocusIn),
+                        Key::PageUp => app.tab().scroll_by(layout().2.saturating_sub(1) as isize),
+                        Key::PageDown => app.tab().scroll_by(-(layout().2.saturating_sub(1) as isize)),
+                        Key::Alt(d @ '1'..='9') => app.switch(d as usize - '1' as usize).await,
+                        key => {
+                            if let Some(line) = app.input.key(key)
+                                && !app.submit(line).await
+                            {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            },
+            Some(connected) = connects.recv() => app.on_connected(connected),
+            _ = sleep_until(app.away.deadline().unwrap_or_else(Instant::now)), if app.away.deadline().is_some() => {
+                let away = app.away.idle();
+                app.send_all(&away).await;
+            }
+            _ = sleep_until(keepalive) => app.keepalive().await,
+            () = resized.recv() => {}
+            _ = tick.tick(), if app.tabs.iter().any(|t| !t.session.is_connected() || t.ping.is_waiting()) => {}
+        }
+    }
+}
+
+/// The next update from any tab's session, with the tab it's for. Cancel-safe
+/// because `Session::next` is.
+async fn next_update(tabs: &mut [Tab]) -> (usize, Update) {
+    let mut pending: Vec<_> = tabs.iter_mut().map(|tab| Box::pin(tab.session.next())).collect();
+    poll_fn(|cx| {
+        for (i, next) in pending.iter_mut().enumerate() {
+            if let Poll::Ready(update) = next.as_mut().poll(cx) {
+                return Poll::Ready((i, update));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Reads stdin on a plain thread: a blocked tokio stdin read can't be
+/// cancelled and would hold up exiting until the next keypress.
+fn stdin_keys() -> mpsc::UnboundedReceiver<Vec<Key>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut decoder = Decoder::default();
+        let mut buf = [0u8; 1024];
+        while let Ok(n @ 1..) = stdin.read(&mut buf) {
+            if tx.send(decoder.feed(&buf[..n])).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+impl Tab {
+    /// Connected (with the last round trip), lagging once a ping goes
+    /// unanswered, or the session's reconnect status.
+    fn health(&self) -> String {
+        if !self.session.is_connected() {
+            return self.session.status();
+        }
+        match (self.ping.lag(), self.ping.rtt()) {
+            (Some(lag), _) => format!("lagging (no answer for {}s)", lag.as_secs()),
+            (None, Some(rtt)) => format!("connected ({} ms)", rtt.as_millis()),
+            (None, None) => "connected".into(),
+        }
+    }
+
+    fn push(&mut self, msg: Line) {
+        // Keep a scrolled-up view where it is.
+        if self.scroll > 0 {
+            self.scroll += msg.wrap(layout().0).len();
+        }
+        self.messages.push(msg);
+        if self.messages.len() > SCROLLBACK {
+            let dropped = self.messages.len() - SCROLLBACK;
+            self.messages.drain(..dropped);
+            if let Some(search) = &mut self.search {
+                search.hit = search.hit.and_then(|hit| hit.checked_sub(dropped));
+            }
+        }
+    }
+
+    /// Scrolls up by `rows`, or down if negative, within the scrollback.
+    fn scroll_by(&mut self, rows: isize) {
+        let (pane, _, height) = layout();
+        let total: usize = self.messages.iter().map(|m| m.wrap(pane).len()).sum();
+        self.scroll = self.scroll.saturating_add_signed(rows).min(total.saturating_sub(height));
+    }
+
+    /// Highlights `term` and scrolls to the newest message containing it,
+    /// or the next older one if it's the same term as last time. `None`
+    /// clears the search. `Err` if there's no match.
+    fn search(&mut self, term: Option<String>) -> Result<(), String> {
+        let Some(term) = term else {
+            self.search = None;
+            return Ok(());
+        };
+        let before = match &self.search {
+            Some(search) if search.term == term => search.hit.unwrap_or(self.messages.len()),
+            _ => self.messages.len(),
+        };
+        let Some(hit) = self.messages[..before].iter().rposition(|m| m.contains(&term)) else {
+            let again = self.search.as_ref().is_some_and(|s| s.term == term && s.hit.is_some());
+            return Err(format!("no {}matches for {term:?}", if again { "older " } else { "" }));
+        };
+        let pane = layout().0;
+        let below: usize = self.messages[hit + 1..].iter().map(|m| m.wrap(pane).len()).sum();
+        self.scroll = 0;
+        self.scroll_by(below as isize);
+        self.search = Some(Search { term, hit: Some(hit) });
+        Ok(())
+    }
+}
+
+impl App {
+    fn tab(&mut self) -> &mut Tab {
+        &mut self.tabs[self.current]
+    }
+
+    /// Adds a tab for `session` and switches to it.
+    fn open(&mut self, label: String, session: Session) {
+        let log = ChatLog::new(self.log_dir.clone(), session.addr(), self.log_enabled);
+        let known = match KnownKeys::load(self.known_keys.clone(), session.addr()) {
+            Ok(known) => known,
+            Err(e) => return self.push(Line::styled(Style::Error, format!("{}: {e}", self.known_keys.display()))),
+        };
+        let tab = Tab {
+            label,
+            users: BTreeSet::from([session.name().to_string()]),
+            friends: BTreeMap::new(),
+            ephemeral: BTreeSet::new(),
+            session,
+            messages: Vec::new(),
+            log,
+            e2e: E2e::new(self.identity.clone(), known),
+            ping: Pinger::new(),
+            outbox: Outbox::default(),
+            scroll: 0,
+            search: None,
+            unread: false,
+            unread_dms: Vec::new(),
+        };
+        self.tabs.push(tab);
+        self.current = self.tabs.len() - 1;
+        let s = &self.tabs[self.current].session;
+        let hint = if self.tabs.len() == 1 { "/help for commands, /quit to leave" } else { "Alt-1..9 or /tab to switch" };
+        let msg = format!("connected to {} as {} (#{}); {hint}", s.addr(), s.name(), s.id());
+        self.push(Line::styled(Style::Dim, msg));
+    }
+
+    /// Sends `cmd` on every tab's connection.
+    async fn send_all(&mut self, cmd: &Command) {
+        for tab in &mut self.tabs {
+            tab.session.send(cmd).await;
+        }
+    }
+
+    fn next_keepalive(&self) -> Instant {
+        self.tabs.iter().map(|t| t.ping.deadline()).min().unwrap_or_else(|| Instant::now() + ping::INTERVAL)
+    }
+
+    /// Pings every connected server that's due one.
+    async fn keepalive(&mut self) {
+        let now = Instant::now();
+        for tab in self.tabs.iter_mut().filter(|t| t.ping.deadline() <= now) {
+            if tab.session.is_connected() {
+                let ping = tab.ping.keepalive();
+                tab.session.send(&ping).await;
+            } else {
+                tab.ping.reset();
+            }
+        }
+    }
+
+    async fn switch(&mut self, tab: usize) {
+        if tab < self.tabs.len() {
+            self.current = tab;
+            let t = &mut self.tabs[tab];
+            t.unread = false;
+            for id in std::mem::take(&mut t.unread_dms) {
+                t.session.send(&Command::Read(id)).await;
+            }
+        }
+    }
+
+    /// Adds a message to the current tab, stamped with the current time.
+    fn push(&mut self, msg: Line) {
+        let current = self.current;
+        self.push_to(current, self.display.stamp.now(), msg);
+    }
+
+    fn push_to(&mut self, tab: usize, stamp: Option<String>, msg: Line) {
+        let msg = self.display.stamped(stamp, msg);
+        self.tabs[tab].push(msg);
+    }
+
+    async fn on_update(&mut self, tab: usize, update: Update) {
+        let stamp = self.display.stamp.now();
+        if !matches!(update, Update::Line(_)) {
+            self.tabs[tab].ping.reset();
+            self.tabs[tab].outbox.disconnected();
+        }
+        let msg = match update {
+            Update::Line(line) => return self.on_line(tab, &line).await,
+            Update::Lost { retry_in: Some(delay) } => {
+                let msg = format!("Server closed the connection; reconnecting in {:.1}s", delay.as_secs_f64());
+                Line::styled(Style::Error, msg)
+            }
+            Update::Lost { retry_in: None } => Line::styled(Style::Error, "Server closed the connection"),
+            Update::RetryFailed { error, retry_in } => {
+                let msg = format!("Reconnect failed ({error}); retrying in {:.1}s", retry_in.as_secs_f64());
+                Line::styled(Style::Error, msg)
+            }
+            Update::Reconnected { id } => {
+                let t = &mut self.tabs[tab];
+                // The server sends the list again after the welcome.
+                t.friends.clear();
+                t.ephemeral.clear();
+                t.log.reconnected();
+                let mut msg = format!("Reconnected as {} (#{id})", t.session.name());
+                if t.outbox.reconnected() {
+                    let _ = write!(msg, "; {} messages typed while disconnected: Enter on an empty line sends them, Esc drops them", t.outbox.len());
+                }
+                Line::styled(Style::Notice, msg)
+            }
+        };
+        self.push_to(tab, stamp, msg);
+    }
+
+    async fn on_line(&mut self, tab: usize, line: &str) {
+        let t = &mut self.tabs[tab];
+        // Keepalive pongs are only shown as the status bar's round trip.
+        if let Some(Event::Pong(token)) = Event::parse(line.as_bytes(), Wire::Text)
+            && let Some(pong) = t.ping.pong(&token)
+        {
+            if pong.asked {
+                let msg = format!("pong from {}: {} ms", t.session.addr(), pong.rtt.as_millis());
+                let stamp = self.display.stamp.now();
+                self.push_to(tab, stamp, Line::styled(Style::Notice, msg));
+            }
+            return;
+        }
+        // Saved rather than shown: it's a screenful of base64.
+        if let Some(Event::Avatar { name, image }) = Event::parse(line.as_bytes(), Wire::Text) {
+            let shown = match avatar::save(&config::data_dir().join("avatars"), &name, &image) {
+                Ok(path) => Line::styled(Style::Notice, format!("saved {name}'s avatar to {}", path.display())),
+                Err(e) => Line::styled(Style::Error, format!("{e:#}")),
+            };
+            let stamp = self.display.stamp.now();
+            return self.push_to(tab, stamp, shown);
+        }
+        // Answered here; it only holds up registering.
+        if let Some(Event::Challenge { nonce, bits }) = Event::parse(line.as_bytes(), Wire::Text) {
+            let name = t.session.name().to_string();
+            if let Ok(n) = tokio::task::spawn_blocking(move || pow::solve(&nonce, &name, bits)).await {
+                t.session.send(&Command::Proof(n)).await;
+            }
+            let stamp = self.display.stamp.now();
+            return self.push_to(tab, stamp, Line::styled(Style::Notice, format!("registering took a proof of work ({bits} bits); sent it")));
+        }
+        let (line, locked) = match t.e2e.incoming(t.session.name(), line) {
+            Incoming::Line { line, locked } => (line, locked),
+            Incoming::Key { from, reply, first_seen } => {
+                if let Some(reply) = reply {
+                    t.session.send_line(&commands::wire_line(&reply)).await;
+                }
+                let mut msg = format!("got {from}'s key; DMs with {from} are now end-to-end encrypted");
+                if first_seen {
+                    let _ = write!(msg, " (first key seen from them: compare /fingerprint {from} with them)");
+                }
+                t.users.insert(from);
+                let stamp = self.display.stamp.now();
+                return self.push_to(tab, stamp, Line::styled(Style::Notice, msg));
+            }
+            Incoming::KeyChanged { from } => {
+                let msg = format!(
+                    "WARNING: {from} sent a different key from the one first seen for them, so it isn't used; compare /fingerprint {from} with them, then /trust {from} to accept it"
+                );
+                let stamp = self.display.stamp.now();
+                return self.push_to(tab, stamp, Line::styled(Style::Error, msg));
+            }
+        };
+        let line = line.as_str();
+        if let Err(e) = self.tabs[tab].log.received(line) {
+            self.log_failed(e);
+        }
+        let me = self.tabs[tab].session.name();
+        let event = Event::parse(line.as_bytes(), Wire::Text);
+        if let Some(event) = &event {
+            self.notifier.event(me, event);
+            self.alerter.event(me, event);
+        }
+        let stamp = self.display.stamp.for_event(event.as_ref());
+        let dm = match &event {
+            Some(Event::Dm { id, .. }) => Some(*id),
+            _ => None,
+        };
+        let Tab { users, friends, ephemeral, .. } = &mut self.tabs[tab];
+        let shown = match event {
+            Some(Event::Dm { from, body, .. }) => {
+                let shown = Line::styled(style::nick(&from), format!("*{from}*")).with(Style::Plain, " ").then(style::formatted(&body));
+                users.insert(from);
+                if locked { style::locked(shown) } else { shown }
+            }
+            Some(Event::ChannelMsg { channel, from, body, .. }) => {
+                let shown = channel_line(&channel, &from, &body);
+                users.insert(from);
+                shown
+            }
+            Some(Event::Presence { name, presence }) => {
+                let shown = style::presence_line(&name, &presence);
+                if let Some((_, known)) = friends.get_mut(&name) {
+                    *known = Some(presence);
+                }
+                users.insert(name);
+                shown
+            }
+            Some(Event::Friend { name, status }) => {
+                let shown = style::friend_line(&name, status);
+                match status {
+                    FriendStatus::Removed => {
+                        friends.remove(&name);
+                    }
+                    status => friends.entry(name).or_insert((status, None)).0 = status,
+                }
+                shown
+            }
+            Some(Event::Ephemeral { name, on }) => {
+                let shown = style::ephemeral_line(&name, on);
+                if on {
+                    ephemeral.insert(name.clone());
+                    users.insert(name);
+                } else {
+                    ephemeral.remove(&name);
+                }
+                shown
+            }
+            Some(Event::Preview { id, url, title }) => style::preview_line(id, &url, &title),
+            Some(Event::Attachment { id, url, size, name }) => style::attachment_line(id, &url, size, &name),
+            Some(Event::Verified { id, key }) => style::verified_line(id, &key),
+            Some(Event::Voice { channel, name, joined }) => style::voice_line(&channel, &name, joined),
+            Some(Event::VoiceToken { channel, port, .. }) => style::voice_token_line(&channel, port),
+            Some(Event::Notice(_)) => Line::styled(Style::Notice, line),
+            Some(Event::Error(_)) => Line::styled(Style::Error, line),
+            _ => Line::plain(line),
+        };
+        self.push_to(tab, stamp, shown);
+        let t = &mut self.tabs[tab];
+        if tab != self.current {
+            t.unread = true;
+            t.unread_dms.extend(dm);
+        } else if let Some(id) = dm {
+            t.session.send(&Command::Read(id)).await;
+        }
+    }
+
+    /// Starts connecting to a profile's server; the tab opens once it's
+    /// registered.
+    /// Opens a tab for a profile, or for an invite link.
+    fn connect(&mut self, name: &str, code: Option<String>) {
+        let nick = self.tabs[self.current].session.name().to_string();
+        let (addr, nick, channels, mut options, keyring) = if Invite::is_link(name) {
+            let invite = match Invite::parse(name) {
+                Ok(invite) => invite,
+                Err(e) => return self.push(Line::styled(Style::Error, e.to_string())),
+            };
+            (invite.addr, nick, Vec::new(), Options { proxy: self.proxy.clone(), token: Some(invite.token), ..Options::default() }, false)
+        } else {
+            let Some(profile) = self.profiles.get(name) else {
+                return self.push(Line::styled(Style::Error, format!("no profile named {name:?}")));
+            };
+            let Some(addr) = profile.addr.clone() else {
+                return self.push(Line::styled(Style::Error, format!("profile {name:?} has no addr")));
+            };
+            let nick = profile.nick.clone().unwrap_or(nick);
+            let options = Options {
+                caps: profile.caps(),
+                proxy: profile.proxy.clone().or_else(|| self.proxy.clone()),
+                password: profile.password.clone(),
+                code,
+                ..Options::default()
+            };
+            (addr, nick, profile.channels.clone(), options, profile.keyring)
+        };
+        let label = if Invite::is_link(name) { addr.clone() } else { name.to_string() };
+        self.push(Line::styled(Style::Dim, format!("connecting to {addr} as {nick} ...")));
+        let connected = self.connected.clone();
+        tokio::spawn(async move {
+            if options.password.is_none() && keyring {
+                options.password = keyring::lookup(&nick, &addr).await;
+            }
+            let result = session::connect_with(&addr, &nick, &channels, &options).await;
+            let _ = connected.send(Connected { label, addr, nick, channels, result });
+        });
+    }
+
+    fn on_connected(&mut self, connected: Connected) {
+        let Connected { label, addr, nick, channels, result } = connected;
+        match result {
+            Ok(conn) => self.open(label, Session::new(addr, nick, channels, conn)),
+            Err(e) => self.push(Line::styled(Style::Error, format!("connecting to {addr} failed: {e}"))),
+        }
+    }
+
+    /// Handles a finished paste. One line is typed into the input; more are
+    /// held until Enter confirms them, rather than each going off as its
+    /// own command the moment it arrives.
+    fn on_paste(&mut self) {
+        let text = self.pasting.take().unwrap_or_default();
+        let lines: Vec<String> = text.lines().filter(|l| !l.trim().is_empty()).map(str::to_string).collect();
+        if lines.len() <= 1 {
+            for c in lines.concat().chars() {
+                self.input.key(Key::Char(c));
+            }
+            return;
+        }
+        let msg = format!("pasted {} lines: Enter sends each one after what's typed so far, Esc drops them", lines.len());
+        self.push(Line::styled(Style::Notice, msg));
+        self.pasted = lines;
+    }
+
+    /// Handles an entered line; `false` once the user asks to quit.
+    async fn submit(&mut self, line: String) -> bool {
+        if line.trim().is_empty() {
+            return true;
+        }
+        let parsed = commands::expand(&self.aliases, &line).and_then(|line| commands::parse(&line));
+        let line = match parsed {
+            Ok(Input::Send(cmd)) => commands::wire_line(&cmd),
+            Ok(Input::Raw(line)) => line,
+            Ok(Input::E2e(name)) => commands::wire_line(&self.tab().e2e.offer(&name)),
+            Ok(Input::Ping) => commands::wire_line(&self.tab().ping.asked()),
+            Ok(Input::Avatar(path)) => match avatar::read(&path) {
+                Ok(image) => commands::wire_line(&Command::Avatar(AvatarCommand::Set(Some(image)))),
+                Err(e) => {
+                    self.push(Line::styled(Style::Error, format!("{e:#}")));
+                    return true;
+                }
+            },
+            Ok(Input::Fingerprint(name)) => {
+                for line in self.tab().e2e.fingerprints(&name) {
+                    self.push(Line::styled(Style::Dim, line));
+                }
+                return true;
+            }
+            Ok(Input::Trust(name)) => match self.tab().e2e.trust(&name) {
+                Ok(reply) => {
+                    self.push(Line::styled(Style::Notice, format!("trusting {name}'s new key")));
+                    match reply {
+                        Some(reply) => commands::wire_line(&reply),
+                        None => return true,
+                    }
+                }
+                Err(msg) => {
+                    self.push(Line::styled(Style::Error, msg));
+                    return true;
+                }
+            },
+            Ok(Input::Mute(None)) => {
+                let muted: Vec<&str> = self.notifier.muted.iter().map(String::as_str).collect();
+                let msg = if muted.is_empty() { "nobody is muted".into() } else { format!("muted: {}", muted.join(", ")) };
+                self.push(Line::styled(Style::Dim, msg));
+                return true;
+            }
+            Ok(Input::Mute(Some(name))) => {
+                self.push(Line::styled(Style::Dim, format!("no more notifications from {name}")));
+                self.notifier.muted.insert(name);
+                return true;
+            }
+            Ok(Input::Unmute(name)) => {
+                let msg = if self.notifier.muted.remove(&name) { format!("notifications from {name} are back on") } else { format!("{name} wasn't muted") };
+                self.push(Line::styled(Style::Dim, msg));
+                return true;
+            }
+            Ok(Input::Alerts(on)) => {
+                if let Some(on) = on {
+                    self.alerter.enabled = on;
+                }
+                let msg = format!("alerts are {}", if self.alerter.enabled { "on" } else { "off" });
+                self.push(Line::styled(Style::Dim, msg));
+                return true;
+            }
+            Ok(Input::Log(on)) => {
+                if let Some(on) = on {
+                    self.log_enabled = on;
+                    self.tabs.iter_mut().for_each(|tab| tab.log.enabled = on);
+                }
+                let msg = format!("logging to {} is {}", self.log_dir.display(), if self.log_enabled { "on" } else { "off" });
+                self.push(Line::styled(Style::Dim, msg));
+                return true;
+            }
+            Ok(Input::Search(term)) => {
+                if let Err(msg) = self.tab().search(term) {
+                    self.push(Line::styled(Style::Error, msg));
+                }
+                return true;
+            }
+            Ok(Input::Connect(name, code)) => {
+                self.connect(&name, code);
+                return true;
+            }
+            Ok(Input::Tab(n)) => {
+                let tab = n.map_or(self.current + 1, |n| n - 1);
+                match n {
+                    None => self.switch(tab % self.tabs.len()).await,
+                    Some(_) if tab < self.tabs.len() => self.switch(tab).await,
+                    Some(n) => self.push(Line::styled(Style::Error, format!("there is no tab {n}"))),
+                }
+                return true;
+            }
+            Ok(Input::Close) => {
+                if self.tabs.len() == 1 {
+                    self.push(Line::styled(Style::Error, "this is the last tab; /quit to leave"));
+                } else {
+                    self.tabs.remove(self.current).session.quit(None).await;
+                    self.switch(self.current.min(self.tabs.len() - 1)).await;
+                }
+                return true;
+            }
+            Ok(Input::Help) => {
+                for help in commands::help(&self.aliases) {
+                    self.push(Line::styled(Style::Dim, help));
+                }
+                return true;
+            }
+            Ok(Input::Quit(message)) => {
+                for tab in &mut self.tabs {
+                    tab.session.quit(message.clone()).await;
+                }
+                return false;
+            }
+            Err(msg) => {
+                self.push(Line::styled(Style::Error, msg));
+                return true;
+            }
+        };
+        if self.tab().session.is_connected() {
+            self.deliver(line).await;
+        } else if self.tab().outbox.hold(&line) {
+            let msg = format!("not connected; queued to send after reconnecting ({} waiting)", self.tab().outbox.len());
+            self.push(Line::styled(Style::Error, msg));
+        } else {
+            self.push(Line::styled(Style::Error, "not connected; not sent"));
+        }
+        true
+    }
+
+    /// Shows a line we're sending, sends it and logs it.
+    async fn deliver(&mut self, line: String) {
+        match Command::parse(line.as_bytes(), Wire::Text) {
+            Command::To { name, body } => {
+                let shown = Line::styled(Style::Dim, "-> ").with(style::nick(&name), format!("*{name}*")).with(Style::Plain, format!(" {body}"));
+                let shown = if self.tab().e2e.is_encrypted(&name) { style::locked(shown) } else { shown };
+                self.push(shown);
+                self.tab().users.insert(name);
+            }
+            Command::Say { channel, body } => {
+                let shown = channel_line(&channel, self.tab().session.name(), &body);
+                self.push(shown);
+            }
+            _ => {}
+        }
+        let tab = self.tab();
+        // Logged as typed, before encryption.
+        tab.session.send_line(&tab.e2e.outgoing(tab.session.name(), &line)).await;
+        if let Err(e) = tab.log.sent(tab.session.name(), &line) {
+            self.log_failed(e);
+        }
+    }
+
+    fn log_failed(&mut self, e: io::Error) {
+        self.log_enabled = false;
+        self.tabs.iter_mut().for_each(|tab| tab.log.enabled = false);
+        self.push(Line::styled(Style::Error, format!("logging stopped: {e}")));
+    }
+
+    /// Redraws the whole screen in one write.
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = term::size();
+        let (pane, sidebar, rows) = layout();
+        let tab = &self.tabs[self.current];
+
+        // The messages that fit, wrapped to the pane, `scroll` rows up from
+        // the newest.
+        let mut lines = Vec::new();
+        for msg in tab.messages.iter().rev() {
+            if lines.len() >= rows + tab.scroll {
+                break;
+            }
+            let msg = match &tab.search {
+                Some(search) => msg.highlight(&search.term),
+                None => msg.clone(),
+            };
+            lines.splice(0..0, msg.wrap(pane));
+        }
+        let end = lines.len().saturating_sub(tab.scroll);
+        let lines = &lines[end.saturating_sub(rows)..end];
+
+        let mut users = vec![Line::styled(Style::Bold, format!(" users ({})", tab.users.len()))];
+        users.extend(tab.users.iter().map(|u| {
+            let line = Line::plain(" ").with(style::nick(u), u);
+            if u == tab.session.name() {
+                line.with(Style::Dim, " *")
+            } else if tab.ephemeral.contains(u) {
+                line.with(Style::Dim, " (ephemeral)")
+            } else {
+                line
+            }
+        }));
+        if !tab.friends.is_empty() {
+            users.push(Line::default());
+            users.push(Line::styled(Style::Bold, format!(" friends ({})", tab.friends.len())));
+            users.extend(tab.friends.iter().map(|(name, (status, presence))| {
+                // + online, ~ away, blank and dim when offline or pending.
+                let (mark, style) = match (status, presence) {
+                    (FriendStatus::Friends, Some(Presence::Online)) => ("+", style::nick(name)),
+                    (FriendStatus::Friends, Some(Presence::Away(_))) => ("~", style::nick(name)),
+                    _ => (" ", Style::Dim),
+                };
+                let line = Line::plain(mark).with(style, name);
+                match status {
+                    FriendStatus::Sent => line.with(Style::Dim, " (asked)"),
+                    FriendStatus::Received => line.with(Style::Dim, " (asks)"),
+                    _ => line,
+                }
+            }));
+        }
+
+        let blank = Line::default();
+        let mut frame = String::from("\x1b[?25l");
+        for row in 0..rows {
+            let line = lines.get(row).unwrap_or(&blank);
+            let _ = write!(frame, "\x1b[{};1H{}", row + 1, line.fit(pane).render(self.display.color));
+            if sidebar > 0 {
+                let user = users.get(row).unwrap_or(&blank);
+                let _ = write!(frame, "{}{}", Line::styled(Style::Dim, "\u{2502}").render(self.display.color), user.fit(sidebar).render(self.display.color));
+            }
+        }
+
+        // The status bar is always reverse video, colors or not. With more
+        // than one tab it starts with the tab list: [current], + for unread.
+        let mut status = String::new();
+        if self.tabs.len() > 1 {
+            for (i, t) in self.tabs.iter().enumerate() {
+                let mark = if t.unread { "+" } else { "" };
+                if i == self.current {
+                    let _ = write!(status, " [{}:{}]", i + 1, t.label);
+                } else {
+                    let _ = write!(status, " {}:{}{mark}", i + 1, t.label);
+                }
+            }
+            status.push_str(" |");
+        }
+        let s = &tab.session;
+        let _ = write!(status, " {} (#{}) @ {} | {}", s.name(), s.id(), s.addr(), tab.health());
+        if tab.scroll > 0 {
+            let _ = write!(status, " | scrolled up {} (PageDown for newer)", tab.scroll);
+        }
+        if let Some(search) = &tab.search {
+            let _ = write!(status, " | search: {}", search.term);
+        }
+        if self.away.is_away() {
+            status.push_str(" | away (idle)");
+        }
+        match tab.outbox.len() {
+            0 => {}
+            n if tab.outbox.is_asking() => {
+                let _ = write!(status, " | {n} queued (Enter sends, Esc drops)");
+            }
+            n => {
+                let _ = write!(status, " | {n} queued");
+            }
+        }
+        if !self.pasted.is_empty() {
+            let _ = write!(status, " | {} pasted lines (Enter sends, Esc drops)", self.pasted.len());
+        }
+        let status = Line::plain(status);
+        let _ = write!(frame, "\x1b[{};1H\x1b[7m{}\x1b[0m", height.saturating_sub(1), status.fit(width).render(false));
+
+        let prompt = self.input.prompt();
+        let prompt_width = prompt.chars().count();
+        let (input, cursor) = self.input.view(width.saturating_sub(prompt_width));
+        let _ = write!(frame, "\x1b[{height};1H\x1b[2K{prompt}{input}");
+        let _ = write!(frame, "\x1b[{height};{}H\x1b[?25h", prompt_width + cursor + 1);
+
+        out.write_all(frame.as_bytes())?;
+        out.flush()
+    }
+}
+
+/// Message pane width, sidebar width (0 when it doesn't fit) and the
+/// number of message rows.
+fn layout() -> (usize, usize, usize) {
+    let (width, height) = term::size();
+    let sidebar = if width >= 3 * SIDEBAR_WIDTH { SIDEBAR_WIDTH } else { 0 };
+    (width - sidebar - usize::from(sidebar > 0), sidebar, height.saturating_sub(2))
+}
+
+fn channel_line(channel: &str, from: &str, body: &str) -> Line {
+    Line::styled(Style::Dim, format!("{channel} ")).with(style::nick(from), format!("<{from}>")).with(Style::Plain, " ").then(style::formatted(body))
+}
@@ -0,0 +1,351 @@
+//! Load generator for a running server. Connects simulated clients, drives
+//! one message pattern for a while, and reports delivery latency and
+//! throughput.
+//!
+//! cargo run --release -p loadtest -- --addr 127.0.0.1:5555 --clients 200 --pattern dm
+//!
+//! Patterns:
+//!   dm         every client sends DMs to random other clients
+//!   broadcast  every client joins one channel and SAYs to it
+//!   churn      half the clients stay connected and receive; the other half
+//!              repeatedly connect, send one DM and disconnect
+//!
+//! Each message body carries its send time, so latency is measured from the
+//! moment the sender wrote it to the moment a recipient read it.
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::Barrier,
+    time::{interval, timeout, Instant, MissedTickBehavior},
+};
+
+use protocol::{Command, Event, Wire, PROTOCOL_VERSION};
+
+/// How long to keep reading after senders stop, for messages still in flight.
+const DRAIN: Duration = Duration::from_secs(2);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Dm,
+    Broadcast,
+    Churn,
+}
+
+#[derive(Debug, Clone)]
+struct Args {
+    addr: String,
+    clients: usize,
+    pattern: Pattern,
+    duration: Duration,
+    /// Messages per second, per sending client.
+    rate: u32,
+    /// Message body size in bytes.
+    size: usize,
+}
+
+impl Args {
+    fn parse() -> Result<Self> {
+        let mut args = Args {
+            addr: "127.0.0.1:5555".into(),
+            clients: 50,
+            pattern: Pattern::Dm,
+            duration: Duration::from_secs(10),
+            rate: 10,
+            size: 64,
+        };
+        let argv: Vec<String> = std::env::args().skip(1).collect();
+        let mut it = argv.iter();
+        while let Some(flag) = it.next() {
+            let mut value = || it.next().ok_or_else(|| anyhow!("{flag} needs a value"));
+            match flag.as_str() {
+                "--addr" => args.addr = value()?.clone(),
+                "--clients" => args.clients = value()?.parse()?,
+                "--pattern" => {
+                    args.pattern = match value()?.as_str() {
+                        "dm" => Pattern::Dm,
+                        "broadcast" => Pattern::Broadcast,
+                        "churn" => Pattern::Churn,
+                        other => bail!("unknown pattern {other:?} (dm, broadcast or churn)"),
+                    }
+                }
+                "--duration" => args.duration = Duration::from_secs(value()?.parse()?),
+                "--rate" => args.rate = value()?.parse()?,
+                "--size" => args.size = value()?.parse()?,
+                other => bail!("unknown flag {other}"),
+            }
+        }
+        if args.clients < 2 {
+            bail!("--clients must be at least 2");
+        }
+        if args.rate == 0 {
+            bail!("--rate must be at least 1");
+        }
+        Ok(args)
+    }
+}
+
+/// Shared by every simulated client.
+struct Run {
+    args: Args,
+    /// Send timestamps in message bodies are microseconds since this.
+    epoch: Instant,
+    /// Prefix for nicknames and the channel, so runs don't collide.
+    tag: String,
+    sent: AtomicU64,
+    churned: AtomicU64,
+}
+
+impl Run {
+    fn name(&self, n: usize) -> String {
+        format!("lt{}-{n}", self.tag)
+    }
+
+    fn channel(&self) -> String {
+        format!("#lt{}", self.tag)
+    }
+
+    fn body(&self) -> String {
+        let stamp = format!("lt:{} ", self.epoch.elapsed().as_micros());
+        let pad = self.args.size.saturating_sub(stamp.len());
+        stamp + &"x".repeat(pad)
+    }
+
+    /// Latency of a message this run sent, from its body.
+    fn latency(&self, body: &str) -> Option<Duration> {
+        let sent = body.strip_prefix("lt:")?.split(' ').next()?.parse().ok()?;
+        Some(self.epoch.elapsed().saturating_sub(Duration::from_micros(sent)))
+    }
+}
+
+/// One connection in the text wire format.
+struct Conn {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Conn {
+    async fn open(addr: &str, name: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let mut conn = Conn { lines: BufReader::new(reader).lines(), writer };
+
+        conn.send(&Command::Hello(PROTOCOL_VERSION)).await?;
+        conn.send(&Command::Nick(name.to_string())).await?;
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        loop {
+            match conn.next_event(deadline).await? {
+                Event::Hello { .. } => {}
+                Event::Welcome { .. } => return Ok(conn),
+                other => bail!("{name}: handshake failed: {other:?}"),
+            }
+        }
+    }
+
+    async fn send(&mut self, cmd: &Command) -> Result<()> {
+        let mut line = cmd.encode(Wire::Text);
+        line.push(b'\n');
+        self.writer.write_all(&line).await?;
+        Ok(())
+    }
+
+    async fn next_event(&mut self, deadline: Instant) -> Result<Event> {
+        let line = tokio::time::timeout_at(deadline, self.lines.next_line())
+            .await
+            .map_err(|_| anyhow!("server did not respond in time"))??
+            .ok_or_else(|| anyhow!("server closed the connection"))?;
+        Event::parse(line.as_bytes(), Wire::Text).ok_or_else(|| anyhow!("unreadable line: {line}"))
+    }
+}
+
+/// Reads until the connection closes or `until`, collecting latencies of
+/// this run's messages.
+async fn receive(run: &Run, mut lines: Lines<BufReader<OwnedReadHalf>>, until: Instant) -> Vec<Duration> {
+    let mut latencies = Vec::new();
+    while let Ok(Ok(Some(line))) = tokio::time::timeout_at(until, lines.next_line()).await {
+        let body = match Event::parse(line.as_bytes(), Wire::Text) {
+            Some(Event::Dm { body, .. } | Event::ChannelMsg { body, .. }) => body,
+            _ => continue,
+        };
+        latencies.extend(run.latency(&body));
+    }
+    latencies
+}
+
+/// Tiny xorshift so picking random peers doesn't need a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// A client that stays connected for the whole run. Returns the latencies it
+/// observed.
+async fn steady_client(run: Arc<Run>, n: usize, start: Arc<Barrier>, sends: bool) -> Result<Vec<Duration>> {
+    let args = &run.args;
+    let mut conn = Conn::open(&args.addr, &run.name(n)).await?;
+    if args.pattern == Pattern::Broadcast {
+        conn.send(&Command::Join { channel: run.channel(), key: None }).await?;
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        while !matches!(conn.next_event(deadline).await?, Event::Notice(_)) {}
+    }
+
+    start.wait().await;
+    let stop = Instant::now() + args.duration;
+    let Conn { lines, mut writer } = conn;
+    let receiver = {
+        let run = run.clone();
+        tokio::spawn(async move { receive(&run, lines, stop + DRAIN).await })
+    };
+
+    if sends {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ n as u64);
+        let mut tick = interval(Duration::from_secs(1) / args.rate);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        while Instant::now() < stop {
+            tick.tick().await;
+            let cmd = match args.pattern {
+                Pattern::Broadcast => Command::Say { channel: run.channel(), body: run.body() },
+                Pattern::Dm | Pattern::Churn => {
+                    let mut to = rng.below(args.clients - 1);
+                    if to >= n {
+                        to += 1;
+                    }
+                    Command::To { name: run.name(to), body: run.body() }
+                }
+            };
+            let mut line = cmd.encode(Wire::Text);
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+            run.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    Ok(receiver.await?)
+}
+
+/// Connects, sends one DM to a steady client, disconnects, and repeats.
+/// Returns how long each handshake took.
+async fn churn_client(run: Arc<Run>, n: usize, steady: usize, start: Arc<Barrier>) -> Result<Vec<Duration>> {
+    let args = &run.args;
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d ^ n as u64);
+    let mut handshakes = Vec::new();
+    let mut tick = interval(Duration::from_secs(1) / args.rate);
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    start.wait().await;
+    let stop = Instant::now() + args.duration;
+    while Instant::now() < stop {
+        tick.tick().await;
+        let began = Instant::now();
+        let mut conn = Conn::open(&args.addr, &run.name(n)).await?;
+        handshakes.push(began.elapsed());
+        conn.send(&Command::To { name: run.name(rng.below(steady)), body: run.body() }).await?;
+        run.sent.fetch_add(1, Ordering::Relaxed);
+        run.churned.fetch_add(1, Ordering::Relaxed);
+        conn.writer.shutdown().await?;
+        // Wait for the server to let the name go before claiming it again.
+        while conn.lines.next_line().await.ok().flatten().is_some() {}
+    }
+    Ok(handshakes)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse()?;
+    let run = Arc::new(Run {
+        args: args.clone(),
+        epoch: Instant::now(),
+        tag: std::process::id().to_string(),
+        sent: AtomicU64::new(0),
+        churned: AtomicU64::new(0),
+    });
+
+    let steady = match args.pattern {
+        Pattern::Churn => args.clients / 2,
+        Pattern::Dm | Pattern::Broadcast => args.clients,
+    };
+    println!(
+        "{} clients ({steady} steady), pattern {:?}, {} msg/s each, {}s against {}",
+        args.clients,
+        args.pattern,
+        args.rate,
+        args.duration.as_secs(),
+        args.addr
+    );
+
+    // Everyone connects before anyone sends.
+    let start = Arc::new(Barrier::new(args.clients + 1));
+    let mut steady_tasks = Vec::new();
+    for n in 0..steady {
+        let sends = args.pattern != Pattern::Churn;
+        steady_tasks.push(tokio::spawn(steady_client(run.clone(), n, start.clone(), sends)));
+    }
+    let mut churn_tasks = Vec::new();
+    for n in steady..args.clients {
+        churn_tasks.push(tokio::spawn(churn_client(run.clone(), n, steady, start.clone())));
+    }
+
+    if timeout(HANDSHAKE_TIMEOUT, start.wait()).await.is_err() {
+        bail!("clients did not all connect within {}s", HANDSHAKE_TIMEOUT.as_secs());
+    }
+    println!("all connected, running");
+
+    let mut latencies = Vec::new();
+    for task in steady_tasks {
+        latencies.extend(task.await??);
+    }
+    let mut handshakes = Vec::new();
+    for task in churn_tasks {
+        handshakes.extend(task.await??);
+    }
+    let secs = args.duration.as_secs_f64();
+    let sent = run.sent.load(Ordering::Relaxed);
+    println!("sent       {sent} ({:.0}/s)", sent as f64 / secs);
+    println!("delivered  {} ({:.0}/s)", latencies.len(), latencies.len() as f64 / secs);
+    report("latency", &mut latencies);
+    if args.pattern == Pattern::Churn {
+        println!("reconnects {}", run.churned.load(Ordering::Relaxed));
+        report("handshake", &mut handshakes);
+    }
+    Ok(())
+}
+
+fn report(label: &str, samples: &mut [Duration]) {
+    if samples.is_empty() {
+        println!("{label:<10} no samples");
+        return;
+    }
+    samples.sort_unstable();
+    let ms = |d: Duration| d.as_secs_f64() * 1e3;
+    println!(
+        "{label:<10} p50 {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+        ms(percentile(samples, 0.50)),
+        ms(percentile(samples, 0.99)),
+        ms(samples[samples.len() - 1])
+    );
+}
+
+/// Nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -0,0 +1,47 @@
+//! Command parsing cost: the text helpers the server matched on before typed
+//! commands, and full `Command::parse` dispatch in each wire format.
+//!
+//! cargo bench -p protocol --bench parse
+
+use std::{hint::black_box, time::Instant};
+
+use protocol::{parse_to, parse_toid, Command, Wire};
+
+const ITERS: u32 = 1_000_000;
+
+/// Average nanoseconds per call of `f`.
+fn bench(label: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ITERS {
+        f();
+    }
+    let ns = start.elapsed().as_secs_f64() * 1e9 / f64::from(ITERS);
+    println!("  {label:<24} {ns:>8.1} ns");
+}
+
+fn main() {
+    let body = "x".repeat(100);
+    let to = format!("TO alice {body}");
+    let toid = format!("TOID 42 {body}");
+
+    println!("text helpers, {ITERS} iterations");
+    bench("parse_to", || {
+        black_box(parse_to(black_box(&to)));
+    });
+    bench("parse_toid", || {
+        black_box(parse_toid(black_box(&toid)));
+    });
+
+    let cmd = Command::To { name: "alice".into(), body };
+    for wire in [Wire::Text, Wire::Json, Wire::MsgPack, Wire::Protobuf] {
+        let frame = cmd.encode(wire);
+        println!("Command::parse, {wire:?}");
+        bench("TO", || {
+            black_box(Command::parse(black_box(&frame), wire));
+        });
+        let frame = Command::Nick("alice".into()).encode(wire);
+        bench("NICK", || {
+            black_box(Command::parse(black_box(&frame), wire));
+        });
+    }
+}
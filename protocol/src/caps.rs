@@ -0,0 +1,165 @@
+//! IRCv3-style capability negotiation: `CAP LS`, `CAP REQ <caps>`, `CAP END`,
+//! all before `NICK`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cap {
+    /// Switch a text connection to JSON lines once registered.
+    Json,
+    /// Raw-deflate every frame after registration. Needs length-prefixed framing.
+    Deflate,
+    /// Report how far each DM we send gets with `Receipt` events: queued,
+    /// written to the recipient, read.
+    Ack,
+    /// Expand `:smile:`-style shortcodes in messages to emoji. Without it,
+    /// message text comes as it was sent.
+    Emoji,
+    /// We render `*bold*`, `_italic_` and `` `code` `` in messages. Without
+    /// it, messages come with the formatting taken out.
+    Format,
+}
+
+impl Cap {
+    pub const ALL: &'static [Cap] = &[Cap::Json, Cap::Deflate, Cap::Ack, Cap::Emoji, Cap::Format];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cap::Json => "json",
+            Cap::Deflate => "deflate",
+            Cap::Ack => "ack",
+            Cap::Emoji => "emoji",
+            Cap::Format => "format",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Cap::ALL.iter().copied().find(|c| c.name().eq_ignore_ascii_case(s))
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// The set of capabilities a connection has been granted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Caps(u32);
+
+impl Caps {
+    pub fn has(self, cap: Cap) -> bool {
+        self.0 & cap.bit() != 0
+    }
+
+    pub fn insert(&mut self, cap: Cap) {
+        self.0 |= cap.bit();
+    }
+
+    pub fn remove(&mut self, cap: Cap) {
+        self.0 &= !cap.bit();
+    }
+
+    /// Every cap that makes sense on a connection with the given framing,
+    /// for the server to narrow down to what it offers.
+    pub fn available(length_framed: bool) -> Caps {
+        let mut caps = Caps::default();
+        for &cap in Cap::ALL {
+            if cap != Cap::Deflate || length_framed {
+                caps.insert(cap);
+            }
+        }
+        caps
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CapCommand {
+    Ls,
+    Req(String),
+    End,
+}
+
+/// Space-separated list of `offered`.
+pub fn advertised(offered: Caps) -> String {
+    Cap::ALL
+        .iter()
+        .filter(|c| offered.has(**c))
+        .map(|c| c.name())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Grants `requested` all-or-nothing, as IRCv3 does, if it's all among
+/// `offered`. Returns whether it was acked.
+pub fn request(caps: &mut Caps, requested: &str, offered: Caps) -> bool {
+    let parsed: Option<Vec<Cap>> = requested
+        .split_whitespace()
+        .map(|s| Cap::parse(s).filter(|c| offered.has(*c)))
+        .collect();
+    match parsed {
+        Some(list) if !list.is_empty() => {
+            for cap in list {
+                caps.insert(cap);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(list: &[Cap]) -> Caps {
+        let mut caps = Caps::default();
+        for &cap in list {
+            caps.insert(cap);
+        }
+        caps
+    }
+
+    #[test]
+    fn parses_names() {
+        for &cap in Cap::ALL {
+            assert_eq!(Cap::parse(cap.name()), Some(cap));
+            assert_eq!(Cap::parse(&cap.name().to_ascii_uppercase()), Some(cap));
+        }
+        assert_eq!(Cap::parse("sasl"), None);
+        assert_eq!(Cap::parse(""), None);
+        assert_eq!(Cap::parse("json "), None);
+    }
+
+    #[test]
+    fn offers_deflate_only_when_length_framed() {
+        assert_eq!(advertised(Caps::available(true)), "json deflate ack emoji format");
+        assert_eq!(advertised(Caps::available(false)), "json ack emoji format");
+        assert_eq!(advertised(Caps::default()), "");
+    }
+
+    #[test]
+    fn grants_all_or_nothing() {
+        let offered = Caps::available(false);
+        let mut granted = Caps::default();
+        assert!(request(&mut granted, "json  ACK", offered));
+        assert_eq!(granted, caps(&[Cap::Json, Cap::Ack]));
+        // One unknown or unoffered cap NAKs the lot, and changes nothing.
+        assert!(!request(&mut granted, "emoji sasl", offered));
+        assert!(!request(&mut granted, "format deflate", offered));
+        assert_eq!(granted, caps(&[Cap::Json, Cap::Ack]));
+        // An empty request is NAKed too.
+        assert!(!request(&mut granted, " ", offered));
+        // Later requests add to what's already granted.
+        assert!(request(&mut granted, "emoji", offered));
+        assert_eq!(granted, caps(&[Cap::Json, Cap::Ack, Cap::Emoji]));
+    }
+
+    #[test]
+    fn duplicates_are_harmless() {
+        let mut granted = Caps::default();
+        assert!(request(&mut granted, "ack ack Ack", Caps::available(true)));
+        assert_eq!(granted, caps(&[Cap::Ack]));
+        assert!(request(&mut granted, "ack", Caps::available(true)));
+        assert_eq!(granted, caps(&[Cap::Ack]));
+        granted.remove(Cap::Ack);
+        assert!(!granted.has(Cap::Ack));
+    }
+}
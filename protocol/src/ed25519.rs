@@ -0,0 +1,487 @@
+//! Ed25519 signatures (RFC 8032), for bots' signed messages. A registered
+//! nickname can tie a public key to itself with `SIGNKEY <hex>`; after that
+//! `SIGNED <signature> <target> <body>` sends `body` to a channel or person
+//! the way `SAY` or `TO` would, if the signature over `signed_text` checks
+//! out, and the server follows the message with a `Verified` event so
+//! recipients know it came from whoever holds the key.
+//!
+//! Written out here like the rest of the protocol's primitives and checked
+//! against the RFC's test vectors below. Signing doesn't branch on the
+//! secret; verifying only handles public data.
+
+pub const KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// What a signed message's signature covers: who sends it, where to, and
+/// what it says.
+pub fn signed_text(from: &str, target: &str, body: &str) -> Vec<u8> {
+    format!("rustchat signed message\n{from}\n{target}\n{body}").into_bytes()
+}
+
+/// The public key for the 32-byte secret `seed`.
+pub fn public_key(seed: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let (a, _) = expand(seed);
+    scalar_mult(&a, &base()).encode()
+}
+
+pub fn sign(seed: &[u8; KEY_LEN], message: &[u8]) -> [u8; SIGNATURE_LEN] {
+    let (a, prefix) = expand(seed);
+    let public = scalar_mult(&a, &base()).encode();
+    let r = reduce(&sha512(&[&prefix[..], message].concat()));
+    let big_r = scalar_mult(&r, &base()).encode();
+    let k = reduce(&sha512(&[&big_r[..], &public, message].concat()));
+    let mut x = [0i64; 64];
+    for (i, &b) in r.iter().enumerate() {
+        x[i] = i64::from(b);
+    }
+    for (i, &ki) in k.iter().enumerate() {
+        for (j, &aj) in a.iter().enumerate() {
+            x[i + j] += i64::from(ki) * i64::from(aj);
+        }
+    }
+    let mut sig = [0; SIGNATURE_LEN];
+    sig[..32].copy_from_slice(&big_r);
+    sig[32..].copy_from_slice(&mod_l(&mut x));
+    sig
+}
+
+pub fn verify(public: &[u8; KEY_LEN], message: &[u8], sig: &[u8; SIGNATURE_LEN]) -> bool {
+    let Some(a) = Point::decode(public) else {
+        return false;
+    };
+    let s: [u8; 32] = sig[32..].try_into().unwrap();
+    // S must be reduced, or one signature would have several encodings.
+    if !less_than_l(&s) {
+        return false;
+    }
+    let k = reduce(&sha512(&[&sig[..32], public, message].concat()));
+    let check = scalar_mult(&s, &base()).add(&scalar_mult(&k, &a.neg()));
+    check.encode()[..] == sig[..32]
+}
+
+/// Whether `key` is a point on the curve, and so could be a public key.
+pub fn is_public_key(key: &[u8; KEY_LEN]) -> bool {
+    Point::decode(key).is_some()
+}
+
+/// The clamped scalar and the nonce prefix from a secret seed.
+fn expand(seed: &[u8; KEY_LEN]) -> ([u8; 32], [u8; 32]) {
+    let h = sha512(seed);
+    let mut a: [u8; 32] = h[..32].try_into().unwrap();
+    a[0] &= 248;
+    a[31] &= 127;
+    a[31] |= 64;
+    (a, h[32..].try_into().unwrap())
+}
+
+// ---- Field arithmetic mod 2^255 - 19 ----
+
+/// Five 51-bit limbs.
+type Fe = [u64; 5];
+const MASK51: u64 = (1 << 51) - 1;
+const ONE: Fe = [1, 0, 0, 0, 0];
+const ZERO: Fe = [0; 5];
+
+fn fe_from_bytes(b: &[u8; 32]) -> Fe {
+    let load = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+    [load(0) & MASK51, (load(6) >> 3) & MASK51, (load(12) >> 6) & MASK51, (load(19) >> 1) & MASK51, (load(24) >> 12) & MASK51]
+}
+
+fn fe_to_bytes(f: &Fe) -> [u8; 32] {
+    let mut t = carry(*f);
+    // Subtract p if t >= p, found by whether t + 19 reaches 2^255.
+    let mut q = (t[0] + 19) >> 51;
+    for limb in &t[1..] {
+        q = (limb + q) >> 51;
+    }
+    t[0] += 19 * q;
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK51;
+    }
+    t[4] &= MASK51;
+    let words = [t[0] | t[1] << 51, t[1] >> 13 | t[2] << 38, t[2] >> 26 | t[3] << 25, t[3] >> 39 | t[4] << 12];
+    let mut out = [0u8; 32];
+    for (chunk, word) in out.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+fn carry(mut f: Fe) -> Fe {
+    for i in 0..4 {
+        f[i + 1] += f[i] >> 51;
+        f[i] &= MASK51;
+    }
+    f[0] += 19 * (f[4] >> 51);
+    f[4] &= MASK51;
+    f
+}
+
+fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    carry([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3], a[4] + b[4]])
+}
+
+fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    // Add 4p first so no limb goes negative.
+    const FOUR_P: Fe = [0x1f_ffff_ffff_ffb4, 0x1f_ffff_ffff_fffc, 0x1f_ffff_ffff_fffc, 0x1f_ffff_ffff_fffc, 0x1f_ffff_ffff_fffc];
+    carry([a[0] + FOUR_P[0] - b[0], a[1] + FOUR_P[1] - b[1], a[2] + FOUR_P[2] - b[2], a[3] + FOUR_P[3] - b[3], a[4] + FOUR_P[4] - b[4]])
+}
+
+fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |x: u64, y: u64| u128::from(x) * u128::from(y);
+    let (b1, b2, b3, b4) = (b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19);
+    let c = [
+        m(a[0], b[0]) + m(a[4], b1) + m(a[3], b2) + m(a[2], b3) + m(a[1], b4),
+        m(a[1], b[0]) + m(a[0], b[1]) + m(a[4], b2) + m(a[3], b3) + m(a[2], b4),
+        m(a[2], b[0]) + m(a[1], b[1]) + m(a[0], b[2]) + m(a[4], b3) + m(a[3], b4),
+        m(a[3], b[0]) + m(a[2], b[1]) + m(a[1], b[2]) + m(a[0], b[3]) + m(a[4], b4),
+        m(a[4], b[0]) + m(a[3], b[1]) + m(a[2], b[2]) + m(a[1], b[3]) + m(a[0], b[4]),
+    ];
+    let mut r = [0u64; 5];
+    let mut carried = 0u128;
+    for i in 0..5 {
+        let v = c[i] + carried;
+        r[i] = v as u64 & MASK51;
+        carried = v >> 51;
+    }
+    r[0] += carried as u64 * 19;
+    r[1] += r[0] >> 51;
+    r[0] &= MASK51;
+    r
+}
+
+/// `z` to the power `exp`, little-endian.
+fn fe_pow(z: &Fe, exp: &[u8; 32]) -> Fe {
+    let mut r = ONE;
+    for bit in (0..256).rev() {
+        r = fe_mul(&r, &r);
+        if exp[bit / 8] >> (bit % 8) & 1 == 1 {
+            r = fe_mul(&r, z);
+        }
+    }
+    r
+}
+
+/// All ones but for the bottom and top bytes, little-endian, which is what
+/// p - 2 and the other exponents made from p look like.
+const fn exponent(low: u8, high: u8) -> [u8; 32] {
+    let mut e = [0xff; 32];
+    e[0] = low;
+    e[31] = high;
+    e
+}
+
+fn fe_invert(z: &Fe) -> Fe {
+    // p - 2
+    fe_pow(z, &exponent(0xeb, 0x7f))
+}
+
+fn fe_eq(a: &Fe, b: &Fe) -> bool {
+    fe_to_bytes(a) == fe_to_bytes(b)
+}
+
+fn fe_is_negative(a: &Fe) -> bool {
+    fe_to_bytes(a)[0] & 1 == 1
+}
+
+fn fe_select(a: &Fe, b: &Fe, pick_b: u64) -> Fe {
+    let mask = pick_b.wrapping_neg();
+    std::array::from_fn(|i| a[i] ^ (mask & (a[i] ^ b[i])))
+}
+
+/// The curve constant -121665/121666, and twice it, little-endian.
+const D: [u8; 32] = [
+    0xa3, 0x78, 0x59, 0x13, 0xca, 0x4d, 0xeb, 0x75, 0xab, 0xd8, 0x41, 0x41, 0x4d, 0x0a, 0x70, 0x00, 0x98, 0xe8, 0x79, 0x77, 0x79, 0x40, 0xc7, 0x8c, 0x73, 0xfe, 0x6f, 0x2b, 0xee, 0x6c, 0x03, 0x52,
+];
+const D2: [u8; 32] = [
+    0x59, 0xf1, 0xb2, 0x26, 0x94, 0x9b, 0xd6, 0xeb, 0x56, 0xb1, 0x83, 0x82, 0x9a, 0x14, 0xe0, 0x00, 0x30, 0xd1, 0xf3, 0xee, 0xf2, 0x80, 0x8e, 0x19, 0xe7, 0xfc, 0xdf, 0x56, 0xdc, 0xd9, 0x06, 0x24,
+];
+
+// ---- Points, in extended coordinates ----
+
+#[derive(Clone, Copy)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+impl Point {
+    const IDENTITY: Point = Point { x: ZERO, y: ONE, z: ONE, t: ZERO };
+
+    fn decode(bytes: &[u8; 32]) -> Option<Point> {
+        let y = fe_from_bytes(bytes);
+        let mut unsigned = *bytes;
+        unsigned[31] &= 0x7f;
+        if fe_to_bytes(&y) != unsigned {
+            // y isn't reduced.
+            return None;
+        }
+        let y2 = fe_mul(&y, &y);
+        let u = fe_sub(&y2, &ONE);
+        let v = fe_add(&fe_mul(&fe_from_bytes(&D), &y2), &ONE);
+        // x = u v^3 (u v^7)^((p - 5) / 8), then fixed up by sqrt(-1) if
+        // that only squares to -u/v.
+        let v3 = fe_mul(&fe_mul(&v, &v), &v);
+        let v7 = fe_mul(&fe_mul(&v3, &v3), &v);
+        let mut x = fe_mul(&fe_mul(&u, &v3), &fe_pow(&fe_mul(&u, &v7), &exponent(0xfd, 0x0f)));
+        let vx2 = fe_mul(&v, &fe_mul(&x, &x));
+        if !fe_eq(&vx2, &u) {
+            if !fe_eq(&vx2, &fe_sub(&ZERO, &u)) {
+                return None;
+            }
+            // sqrt(-1) = 2^((p - 1) / 4)
+            x = fe_mul(&x, &fe_pow(&[2, 0, 0, 0, 0], &exponent(0xfb, 0x1f)));
+        }
+        let sign = bytes[31] >> 7 == 1;
+        if fe_eq(&x, &ZERO) && sign {
+            return None;
+        }
+        if fe_is_negative(&x) != sign {
+            x = fe_sub(&ZERO, &x);
+        }
+        Some(Point { x, y, z: ONE, t: fe_mul(&x, &y) })
+    }
+
+    fn encode(&self) -> [u8; 32] {
+        let zi = fe_invert(&self.z);
+        let (x, y) = (fe_mul(&self.x, &zi), fe_mul(&self.y, &zi));
+        let mut out = fe_to_bytes(&y);
+        out[31] |= u8::from(fe_is_negative(&x)) << 7;
+        out
+    }
+
+    fn neg(&self) -> Point {
+        Point { x: fe_sub(&ZERO, &self.x), t: fe_sub(&ZERO, &self.t), ..*self }
+    }
+
+    /// RFC 8032's addition, which also doubles.
+    fn add(&self, q: &Point) -> Point {
+        let a = fe_mul(&fe_sub(&self.y, &self.x), &fe_sub(&q.y, &q.x));
+        let b = fe_mul(&fe_add(&self.y, &self.x), &fe_add(&q.y, &q.x));
+        let c = fe_mul(&fe_mul(&self.t, &q.t), &fe_from_bytes(&D2));
+        let dd = fe_mul(&fe_add(&self.z, &self.z), &q.z);
+        let (e, f, g, h) = (fe_sub(&b, &a), fe_sub(&dd, &c), fe_add(&dd, &c), fe_add(&b, &a));
+        Point { x: fe_mul(&e, &f), y: fe_mul(&g, &h), z: fe_mul(&f, &g), t: fe_mul(&e, &h) }
+    }
+
+    fn select(&self, other: &Point, pick_other: u64) -> Point {
+        Point {
+            x: fe_select(&self.x, &other.x, pick_other),
+            y: fe_select(&self.y, &other.y, pick_other),
+            z: fe_select(&self.z, &other.z, pick_other),
+            t: fe_select(&self.t, &other.t, pick_other),
+        }
+    }
+}
+
+/// The base point, whose y is 4/5.
+fn base() -> Point {
+    let mut y = [0x66; 32];
+    y[0] = 0x58;
+    Point::decode(&y).unwrap()
+}
+
+/// `[s]p`, adding every time and keeping the sum only for set bits, so the
+/// work doesn't depend on `s`.
+fn scalar_mult(s: &[u8; 32], p: &Point) -> Point {
+    let mut r = Point::IDENTITY;
+    for bit in (0..256).rev() {
+        r = r.add(&r);
+        let sum = r.add(p);
+        r = r.select(&sum, u64::from(s[bit / 8] >> (bit % 8) & 1));
+    }
+    r
+}
+
+// ---- Scalars mod L = 2^252 + 27742317777372353535851937790883648493 ----
+
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+/// `x`, bytes of a number in 64 signed limbs, mod L; as in TweetNaCl.
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+    let mut out = [0; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        out[i] = (x[i] & 255) as u8;
+    }
+    out
+}
+
+fn reduce(hash: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for (limb, &b) in x.iter_mut().zip(hash) {
+        *limb = i64::from(b);
+    }
+    mod_l(&mut x)
+}
+
+fn less_than_l(s: &[u8; 32]) -> bool {
+    for i in (0..32).rev() {
+        match i64::from(s[i]).cmp(&L[i]) {
+            std::cmp::Ordering::Less => return true,
+            std::cmp::Ordering::Greater => return false,
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+    false
+}
+
+// ---- SHA-512 ----
+
+const K: [u64; 80] = [
+    0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc, 0x3956c25bf348b538, 0x59f111f1b605d019, 0x923f82a4af194f9b, 0xab1c5ed5da6d8118,
+    0xd807aa98a3030242, 0x12835b0145706fbe, 0x243185be4ee4b28c, 0x550c7dc3d5ffb4e2, 0x72be5d74f27b896f, 0x80deb1fe3b1696b1, 0x9bdc06a725c71235, 0xc19bf174cf692694,
+    0xe49b69c19ef14ad2, 0xefbe4786384f25e3, 0x0fc19dc68b8cd5b5, 0x240ca1cc77ac9c65, 0x2de92c6f592b0275, 0x4a7484aa6ea6e483, 0x5cb0a9dcbd41fbd4, 0x76f988da831153b5,
+    0x983e5152ee66dfab, 0xa831c66d2db43210, 0xb00327c898fb213f, 0xbf597fc7beef0ee4, 0xc6e00bf33da88fc2, 0xd5a79147930aa725, 0x06ca6351e003826f, 0x142929670a0e6e70,
+    0x27b70a8546d22ffc, 0x2e1b21385c26c926, 0x4d2c6dfc5ac42aed, 0x53380d139d95b3df, 0x650a73548baf63de, 0x766a0abb3c77b2a8, 0x81c2c92e47edaee6, 0x92722c851482353b,
+    0xa2bfe8a14cf10364, 0xa81a664bbc423001, 0xc24b8b70d0f89791, 0xc76c51a30654be30, 0xd192e819d6ef5218, 0xd69906245565a910, 0xf40e35855771202a, 0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8, 0x1e376c085141ab53, 0x2748774cdf8eeb99, 0x34b0bcb5e19b48a8, 0x391c0cb3c5c95a63, 0x4ed8aa4ae3418acb, 0x5b9cca4f7763e373, 0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc, 0x78a5636f43172f60, 0x84c87814a1f0ab72, 0x8cc702081a6439ec, 0x90befffa23631e28, 0xa4506cebde82bde9, 0xbef9a3f7b2c67915, 0xc67178f2e372532b,
+    0xca273eceea26619c, 0xd186b8c721c0c207, 0xeada7dd6cde0eb1e, 0xf57d4f7fee6ed178, 0x06f067aa72176fba, 0x0a637dc5a2c898a6, 0x113f9804bef90dae, 0x1b710b35131c471b,
+    0x28db77f523047d84, 0x32caab7b40c72493, 0x3c9ebe0a15c9bebc, 0x431d67c49c100d4c, 0x4cc5d4becb3e42b6, 0x597f299cfc657e2a, 0x5fcb6fab3ad6faec, 0x6c44198c4a475817,
+];
+
+fn sha512(data: &[u8]) -> [u8; 64] {
+    let mut h: [u64; 8] = [
+        0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1, 0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 128 != 112 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u128 * 8).to_be_bytes());
+    for chunk in message.chunks(128) {
+        let mut w = [0u64; 80];
+        for (i, word) in chunk.chunks(8).enumerate() {
+            w[i] = u64::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            let s0 = w[i - 15].rotate_right(1) ^ w[i - 15].rotate_right(8) ^ (w[i - 15] >> 7);
+            let s1 = w[i - 2].rotate_right(19) ^ w[i - 2].rotate_right(61) ^ (w[i - 2] >> 6);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for (&k, &word) in K.iter().zip(&w) {
+            let s1 = e.rotate_right(14) ^ e.rotate_right(18) ^ e.rotate_right(41);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(k).wrapping_add(word);
+            let s0 = a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (hh, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0; 64];
+    for (bytes, word) in out.chunks_mut(8).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        std::array::from_fn(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap())
+    }
+
+    #[test]
+    fn matches_rfc_8032() {
+        assert_eq!(
+            sha512(b"abc"),
+            hex::<64>("ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f")
+        );
+        // Tests 1, 2 and 3 of section 7.1.
+        let vectors: [(&str, &str, &[u8], &str); 3] = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                b"",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                &[0x72],
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+            (
+                "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+                "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+                &[0xaf, 0x82],
+                "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+            ),
+        ];
+        for (seed, public, message, sig) in vectors {
+            let (seed, public, sig) = (hex::<32>(seed), hex::<32>(public), hex::<64>(sig));
+            assert_eq!(public_key(&seed), public);
+            assert_eq!(sign(&seed, message), sig);
+            assert!(verify(&public, message, &sig));
+        }
+    }
+
+    #[test]
+    fn has_the_right_constants() {
+        let d = fe_sub(&ZERO, &fe_mul(&[121665, 0, 0, 0, 0], &fe_invert(&[121666, 0, 0, 0, 0])));
+        assert_eq!(fe_to_bytes(&d), D);
+        assert_eq!(fe_to_bytes(&fe_add(&d, &d)), D2);
+    }
+
+    #[test]
+    fn rejects_forgeries() {
+        let seed = [7; 32];
+        let public = public_key(&seed);
+        let text = signed_text("deploy-bot", "#ops", "deployed v1.4.2");
+        let sig = sign(&seed, &text);
+        assert!(verify(&public, &text, &sig));
+        assert!(!verify(&public, &signed_text("deploy-bot", "#ops", "deployed v1.4.3"), &sig));
+        assert!(!verify(&public_key(&[8; 32]), &text, &sig));
+        let mut bad = sig;
+        bad[40] ^= 1;
+        assert!(!verify(&public, &text, &bad));
+        // S + L is the same scalar, but not the same signature.
+        let mut x = [0i64; 64];
+        for (i, (&s, &l)) in sig[32..].iter().zip(&L).enumerate() {
+            x[i] += i64::from(s) + l;
+            x[i + 1] += x[i] >> 8;
+            x[i] &= 255;
+        }
+        let mut malleated = sig;
+        for (b, &limb) in malleated[32..].iter_mut().zip(&x) {
+            *b = limb as u8;
+        }
+        assert!(!verify(&public, &text, &malleated));
+        assert!(is_public_key(&public) && !is_public_key(&[0xff; 32]));
+    }
+}
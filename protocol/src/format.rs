@@ -0,0 +1,145 @@
+//! Markdown-lite formatting in message text: `*bold*`, `_italic_` and
+//! `` `code` ``. A marker only opens at the start of the text or after a
+//! space or opening bracket and only closes before a space, punctuation or
+//! the end, so `snake_case` and `2*3*4` stay as they are. Spans don't nest;
+//! markers inside one are text. Anything that doesn't pair up is text too.
+//!
+//! Clients that render it ask for the `format` cap; everyone else gets the
+//! text with the markers of each span taken out.
+
+use std::borrow::Cow;
+
+/// Formatted spans one message may have; markers past these are dropped.
+pub const MAX_SPANS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    Bold,
+    Italic,
+    Code,
+}
+
+impl Format {
+    fn of(marker: char) -> Option<Format> {
+        match marker {
+            '*' => Some(Format::Bold),
+            '_' => Some(Format::Italic),
+            '`' => Some(Format::Code),
+            _ => None,
+        }
+    }
+
+    fn marker(self) -> &'static str {
+        match self {
+            Format::Plain => "",
+            Format::Bold => "*",
+            Format::Italic => "_",
+            Format::Code => "`",
+        }
+    }
+}
+
+fn opens_after(prev: Option<char>) -> bool {
+    prev.is_none_or(|c| c.is_whitespace() || "([{\"'".contains(c))
+}
+
+fn closes_before(next: Option<char>) -> bool {
+    next.is_none_or(|c| c.is_whitespace() || ".,;:!?)]}\"'".contains(c))
+}
+
+/// How much of `rest`, which follows an opening `marker`, is inside the span.
+fn span_len(rest: &str, marker: char) -> Option<usize> {
+    let first = rest.chars().next()?;
+    if first.is_whitespace() || first == marker {
+        return None;
+    }
+    let mut prev = first;
+    for (i, c) in rest.char_indices().skip(1) {
+        if c == marker && !prev.is_whitespace() && closes_before(rest[i + 1..].chars().next()) {
+            return Some(i);
+        }
+        prev = c;
+    }
+    None
+}
+
+/// `text` split into spans, with the markers left out.
+pub fn spans(text: &str) -> Vec<(Format, &str)> {
+    let mut out = Vec::new();
+    let (mut plain_from, mut i, mut prev) = (0, 0, None);
+    while let Some(c) = text[i..].chars().next() {
+        let formatted = Format::of(c).filter(|_| opens_after(prev)).and_then(|format| Some((format, span_len(&text[i + 1..], c)?)));
+        if let Some((format, len)) = formatted {
+            if plain_from < i {
+                out.push((Format::Plain, &text[plain_from..i]));
+            }
+            out.push((format, &text[i + 1..i + 1 + len]));
+            i += len + 2;
+            plain_from = i;
+        } else {
+            i += c.len_utf8();
+        }
+        prev = Some(c);
+    }
+    if plain_from < text.len() {
+        out.push((Format::Plain, &text[plain_from..]));
+    }
+    out
+}
+
+/// `text` with only its first `max` spans still formatted.
+pub fn limit(text: &str, max: usize) -> Cow<'_, str> {
+    let spans = spans(text);
+    if spans.iter().filter(|(format, _)| *format != Format::Plain).count() <= max {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut kept = 0;
+    for (format, span) in spans {
+        let marker = if format != Format::Plain && kept < max { format.marker() } else { "" };
+        kept += usize::from(format != Format::Plain);
+        out.push_str(marker);
+        out.push_str(span);
+        out.push_str(marker);
+    }
+    Cow::Owned(out)
+}
+
+/// `text` without formatting, for clients that don't render it.
+pub fn strip(text: &str) -> Cow<'_, str> {
+    limit(text, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_spans() {
+        assert_eq!(
+            spans("a *big* _deal_, run `cargo *t*`"),
+            [
+                (Format::Plain, "a "),
+                (Format::Bold, "big"),
+                (Format::Plain, " "),
+                (Format::Italic, "deal"),
+                (Format::Plain, ", run "),
+                (Format::Code, "cargo *t*"),
+            ]
+        );
+        for text in ["snake_case_name", "2*3*4", "* not bold *", "**", "_half", "x_y_ z"] {
+            assert_eq!(spans(text), [(Format::Plain, text)], "{text}");
+        }
+        assert_eq!(spans("(*ok*)"), [(Format::Plain, "("), (Format::Bold, "ok"), (Format::Plain, ")")]);
+        assert_eq!(spans(""), []);
+    }
+
+    #[test]
+    fn strips_and_limits() {
+        assert_eq!(strip("a *big* deal, `x_y`"), "a big deal, x_y");
+        assert!(matches!(strip("no format_here"), Cow::Borrowed(_)));
+        assert_eq!(limit("*a* *b* *c*", 2), "*a* *b* c");
+        assert!(matches!(limit("*a* *b*", 2), Cow::Borrowed(_)));
+    }
+}
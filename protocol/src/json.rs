@@ -0,0 +1,376 @@
+//! Just enough JSON for the line protocol: objects whose values are strings,
+//! numbers, booleans or null. Nested objects and arrays are kept as raw text.
+
+use std::{collections::HashMap, fmt::Write as _};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Null,
+    /// A nested object or array, verbatim.
+    Raw(String),
+}
+
+pub type Object = HashMap<String, Value>;
+
+pub fn get_str<'a>(obj: &'a Object, key: &str) -> Option<&'a str> {
+    match obj.get(key)? {
+        Value::Str(s) => Some(s),
+        _ => None,
+    }
+}
+
+pub fn get_u64(obj: &Object, key: &str) -> Option<u64> {
+    match obj.get(key)? {
+        // `u64::MAX as f64` rounds up to 2^64, which doesn't fit.
+        Value::Num(n) if *n >= 0.0 && *n < u64::MAX as f64 && n.fract() == 0.0 => Some(*n as u64),
+        Value::Str(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+pub fn get_bool(obj: &Object, key: &str) -> Option<bool> {
+    match obj.get(key)? {
+        Value::Bool(b) => Some(*b),
+        _ => None,
+    }
+}
+
+pub fn parse_object(s: &str) -> Option<Object> {
+    let mut p = Parser { s: s.as_bytes(), i: 0 };
+    p.ws();
+    p.eat(b'{')?;
+    let mut obj = Object::new();
+    p.ws();
+    if p.eat(b'}').is_none() {
+        loop {
+            p.ws();
+            let key = p.string()?;
+            p.ws();
+            p.eat(b':')?;
+            p.ws();
+            let val = p.value()?;
+            obj.insert(key, val);
+            p.ws();
+            if p.eat(b',').is_some() {
+                continue;
+            }
+            p.eat(b'}')?;
+            break;
+        }
+    }
+    p.ws();
+    (p.i == p.s.len()).then_some(obj)
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    i: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.i).copied()
+    }
+
+    fn eat(&mut self, b: u8) -> Option<()> {
+        (self.peek()? == b).then(|| self.i += 1)
+    }
+
+    fn ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.i += 1;
+        }
+    }
+
+    fn lit(&mut self, word: &[u8]) -> Option<()> {
+        self.s.get(self.i..self.i + word.len()).filter(|w| *w == word)?;
+        self.i += word.len();
+        Some(())
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        match self.peek()? {
+            b'"' => self.string().map(Value::Str),
+            b't' => self.lit(b"true").map(|_| Value::Bool(true)),
+            b'f' => self.lit(b"false").map(|_| Value::Bool(false)),
+            b'n' => self.lit(b"null").map(|_| Value::Null),
+            b'-' | b'0'..=b'9' => self.number().map(Value::Num),
+            b'{' | b'[' => self.nested().map(Value::Raw),
+            _ => None,
+        }
+    }
+
+    /// Skips a balanced object/array and returns its source text. Only the
+    /// brackets are checked, with a stack rather than recursion so deep
+    /// nesting can't overflow ours.
+    fn nested(&mut self) -> Option<String> {
+        let start = self.i;
+        let mut open = Vec::new();
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.string()?;
+                    continue;
+                }
+                b'{' => open.push(b'}'),
+                b'[' => open.push(b']'),
+                close @ (b'}' | b']') => {
+                    if open.pop()? != close {
+                        return None;
+                    }
+                    if open.is_empty() {
+                        self.i += 1;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            self.i += 1;
+        }
+        String::from_utf8(self.s[start..self.i].to_vec()).ok()
+    }
+
+    fn number(&mut self) -> Option<f64> {
+        let start = self.i;
+        while matches!(self.peek(), Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')) {
+            self.i += 1;
+        }
+        std::str::from_utf8(&self.s[start..self.i]).ok()?.parse().ok().filter(|n: &f64| n.is_finite())
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        // `from_str_radix` would also take a sign.
+        let h = self.s.get(self.i..self.i + 4).filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+        self.i += 4;
+        u32::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.eat(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let b = self.peek()?;
+            self.i += 1;
+            match b {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let esc = self.peek()?;
+                    self.i += 1;
+                    let c = match esc {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hi = self.hex4()?;
+                            let cp = if (0xD800..0xDC00).contains(&hi) {
+                                self.lit(b"\\u")?;
+                                let lo = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&lo) {
+                                    return None;
+                                }
+                                0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00)
+                            } else {
+                                hi
+                            };
+                            char::from_u32(cp)?
+                        }
+                        _ => return None,
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                b if b < 0x20 => return None,
+                b => out.push(b),
+            }
+        }
+    }
+}
+
+/// Builds a single-line JSON object field by field.
+pub struct ObjectWriter {
+    out: String,
+}
+
+impl FieldWriter for ObjectWriter {
+    fn new(kind: &str) -> Self {
+        let mut out = String::from("{\"type\":");
+        out.push_str(&quote(kind));
+        ObjectWriter { out }
+    }
+
+    fn str(mut self, key: &str, val: &str) -> Self {
+        let _ = write!(self.out, ",{}:{}", quote(key), quote(val));
+        self
+    }
+
+    fn num(mut self, key: &str, val: u64) -> Self {
+        let _ = write!(self.out, ",{}:{val}", quote(key));
+        self
+    }
+
+    fn bool(mut self, key: &str, val: bool) -> Self {
+        let _ = write!(self.out, ",{}:{val}", quote(key));
+        self
+    }
+
+    /// Embeds `val` verbatim; the caller guarantees it is valid JSON.
+    fn json(mut self, key: &str, val: &str) -> Self {
+        let _ = write!(self.out, ",{}:{val}", quote(key));
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.out.push('}');
+        self.out.into_bytes()
+    }
+}
+
+/// Common shape of the JSON and MessagePack object builders, so commands and
+/// events are described once for both.
+pub trait FieldWriter: Sized {
+    /// Starts an object whose `type` field is `kind`.
+    fn new(kind: &str) -> Self;
+    fn str(self, key: &str, val: &str) -> Self;
+    fn num(self, key: &str, val: u64) -> Self;
+    fn bool(self, key: &str, val: bool) -> Self;
+    /// A value that is itself a JSON document.
+    fn json(self, key: &str, val: &str) -> Self;
+    fn finish(self) -> Vec<u8>;
+
+    fn opt_str(self, key: &str, val: Option<&str>) -> Self {
+        match val {
+            Some(v) => self.str(key, v),
+            None => self,
+        }
+    }
+
+    fn opt_num(self, key: &str, val: Option<u64>) -> Self {
+        match val {
+            Some(v) => self.num(key, v),
+            None => self,
+        }
+    }
+}
+
+/// A string field, also accepting a nested document as its raw text.
+pub fn get_text<'a>(obj: &'a Object, key: &str) -> Option<&'a str> {
+    match obj.get(key)? {
+        Value::Str(s) | Value::Raw(s) => Some(s),
+        _ => None,
+    }
+}
+
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_objects() {
+        let obj = parse_object(r#" {"s":"a\"b\\c\n\u00e9\ud83d\ude00", "n":42, "f":-1.5e2, "t":true, "z":null, "o":{"k":["]",{}]}} "#).unwrap();
+        assert_eq!(get_str(&obj, "s"), Some("a\"b\\c\né😀"));
+        assert_eq!(get_u64(&obj, "n"), Some(42));
+        assert_eq!(obj["f"], Value::Num(-150.0));
+        assert_eq!(get_bool(&obj, "t"), Some(true));
+        assert_eq!(obj["z"], Value::Null);
+        assert_eq!(get_text(&obj, "o"), Some(r#"{"k":["]",{}]}"#));
+        assert_eq!(parse_object("{}"), Some(Object::new()));
+    }
+
+    #[test]
+    fn refuses_malformed_input() {
+        for bad in [
+            // Unterminated
+            r#"{"a":"b"#,
+            r#"{"a":"b\"}"#,
+            r#"{"a":1"#,
+            r#"{"a":{"b":1}"#,
+            r#"{"a":[1,2"#,
+            // Bad escapes
+            r#"{"a":"\x"}"#,
+            r#"{"a":"\u12"}"#,
+            r#"{"a":"\u12G4"}"#,
+            r#"{"a":"\u+041"}"#,
+            // Lone or reversed surrogates
+            r#"{"a":"\ud800"}"#,
+            r#"{"a":"\ud800x"}"#,
+            r#"{"a":"\ud800\u0041"}"#,
+            r#"{"a":"\udc00"}"#,
+            r#"{"a":"\ude00\ud83d"}"#,
+            // Raw control characters
+            "{\"a\":\"line\nbreak\"}",
+            // Mismatched brackets
+            r#"{"a":[1,2}}"#,
+            r#"{"a":{"b":1]}"#,
+            // Trailing garbage and bad syntax
+            r#"{"a":1} x"#,
+            r#"{"a":1}}"#,
+            r#"{"a":1,}"#,
+            r#"{"a" 1}"#,
+            r#"{a:1}"#,
+            r#"{"a":tru}"#,
+            r#"{"a":1-2}"#,
+            r#"["a"]"#,
+            "",
+            // Numbers too big for a double
+            r#"{"a":1e999}"#,
+            r#"{"a":-1e999}"#,
+        ] {
+            assert_eq!(parse_object(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn keeps_integers_in_range() {
+        let obj = parse_object(r#"{"max":18446744073709549568,"over":18446744073709551616,"huge":1e300,"neg":-1,"frac":1.5,"str":"17"}"#).unwrap();
+        assert_eq!(get_u64(&obj, "max"), Some(18_446_744_073_709_549_568));
+        for key in ["over", "huge", "neg", "frac"] {
+            assert_eq!(get_u64(&obj, key), None, "{key}");
+        }
+        assert_eq!(get_u64(&obj, "str"), Some(17));
+    }
+
+    #[test]
+    fn survives_deep_nesting() {
+        let depth = 100_000;
+        let deep = format!(r#"{{"a":{}{}}}"#, "[".repeat(depth), "]".repeat(depth));
+        assert_eq!(get_text(&parse_object(&deep).unwrap(), "a").map(str::len), Some(2 * depth));
+        let unbalanced = format!(r#"{{"a":{}}}"#, "[".repeat(depth));
+        assert_eq!(parse_object(&unbalanced), None);
+    }
+
+    #[test]
+    fn quotes_strings() {
+        let text = "say \"hi\"\\\n\t\u{1}é";
+        assert_eq!(quote(text), r#""say \"hi\"\\\n\t\u0001é""#);
+        let obj = parse_object(&format!("{{\"a\":{}}}", quote(text))).unwrap();
+        assert_eq!(get_str(&obj, "a"), Some(text));
+    }
+}
@@ -0,0 +1,1918 @@
+//! The chat wire protocol, shared by the server and client: typed commands and
+//! events plus their text, JSON, MessagePack and protobuf encodings.
+
+pub mod base64;
+pub mod caps;
+pub mod ed25519;
+pub mod format;
+pub mod json;
+pub mod mdns;
+pub mod msgpack;
+mod pb;
+pub mod pow;
+pub mod toml;
+pub mod voice;
+pub mod xchacha;
+
+use std::borrow::Cow;
+
+use caps::CapCommand;
+use json::{FieldWriter, ObjectWriter};
+use msgpack::MapWriter;
+
+/// Highest protocol version this build speaks, offered in `HELLO`.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Clients asking for anything older are turned away during the handshake.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Every command after the handshake with its arguments, as listed to clients
+/// at WELCOME and in answer to one the server doesn't know.
+pub const USAGE: &str = "TO <name> <msg> | TOID <id> <msg> | KICK <name> | KICKID <id> | EXPORT <name|#chan> <json|csv> [from] [to] | EXPORTME | PURGE <name|#chan> | READ <id> | RESEND <from_seq> [to_seq] | JOIN <#chan> [key] | PART <#chan> | TOPIC <#chan> [text] | HISTORY <#chan|@name> [before:<id>] [limit:<n>] | RETENTION <#chan> [policy] | SEARCH <text> [in:#chan] [from:name] [n] | SAY <#chan> <msg> | KEY <name> <pubkey> | AWAY [msg] | PING [token] | QUIT [msg] | INVITE [#chan] [once] [secs] | PROFILE SET <field> [value] | PROFILE GET <name> | WHOIS <name> | AVATAR SET [base64] | AVATAR GET <name> | WATCH [name] | UNWATCH <name> | FRIEND ADD|REMOVE <name> | FRIEND LIST | BLOCK [name] | UNBLOCK <name> | DND on|off [allow:friends] [allow:mentions] | PUSH [url] | EPHEMERAL on|off <name> | SCHEDULE <time> TO <name> <msg> | SCHEDULES | UNSCHEDULE <id> | REMIND me|<name> in <delay> <text> | POLL <#chan> [for <delay>] \"question\" \"option\"... | VOTE <id> <n> | UPLOAD | VOICE JOIN <#chan> | VOICE LEAVE | REGISTER <password> | PROOF <n> | 2FA ENABLE | 2FA <code> | 2FA DISABLE <code> | UNLOCK [name|ip] | ACCESS [ALLOW|DENY <cidr>] | CONNECTIONS | DRAIN [secs] [host:port] | SIGNKEY [hex] | SIGNED <#chan|name> <signature> <msg>";
+/// Where the server listens, and what clients assume when given a bare host.
+pub const DEFAULT_PORT: u16 = 5555;
+
+/// Per-connection message encoding, fixed by the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wire {
+    Text,
+    Json,
+    Protobuf,
+    MsgPack,
+}
+
+impl Wire {
+    /// A JSON object handshake selects JSON lines. The binary formats are only
+    /// considered on length-prefixed connections: a MessagePack map, otherwise
+    /// protobuf unless the client sent a text handshake command.
+    pub fn detect(frame: &[u8], length_framed: bool) -> Self {
+        let head = frame.trim_ascii_start();
+        if head.starts_with(b"{") {
+            Wire::Json
+        } else if length_framed && frame.first().is_some_and(|b| msgpack::is_map_start(*b)) {
+            Wire::MsgPack
+        } else if length_framed && !is_text_handshake(head) {
+            Wire::Protobuf
+        } else {
+            Wire::Text
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Wire::Text => "text",
+            Wire::Json => "json",
+            Wire::Protobuf => "protobuf",
+            Wire::MsgPack => "msgpack",
+        }
+    }
+}
+
+fn is_text_handshake(head: &[u8]) -> bool {
+    ["NICK", "HELLO", "CAP", "TOKEN"]
+        .iter()
+        .any(|cmd| head.get(..cmd.len()).is_some_and(|h| h.eq_ignore_ascii_case(cmd.as_bytes())))
+}
+
+/// A client request, independent of the wire format it arrived in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Hello(u32),
+    Cap(CapCommand),
+    Nick(String),
+    Kick(String),
+    KickId(Option<u64>),
+    Export(Option<ExportArgs>),
+    ExportMe,
+    Purge(String),
+    Resend(Option<SeqRange>),
+    /// Joins a channel, with its key if it has one.
+    Join { channel: String, key: Option<String> },
+    Part(String),
+    /// Shows a channel's topic, or sets it when `topic` is given.
+    Topic { channel: String, topic: Option<String> },
+    /// Asks for a page of kept messages; they come back as the events they
+    /// were sent as.
+    History(HistoryArgs),
+    /// Looks for kept messages containing some text.
+    Search(SearchArgs),
+    /// Shows how much history a channel keeps, or changes it to `policy`
+    /// (`none`, `<n> messages` or an age like `7d`).
+    Retention { channel: String, policy: Option<String> },
+    Say { channel: String, body: String },
+    To { name: String, body: String },
+    ToId { id: u64, body: String },
+    /// Hands our public key for end-to-end encrypted DMs to `name`; the
+    /// server passes it on without looking inside.
+    Key { name: String, key: String },
+    /// Marks the user away with a message for anyone who DMs them; `None`
+    /// marks them back.
+    Away(Option<String>),
+    /// Asks the server to answer with a `Pong` carrying the same token, to
+    /// check the connection is alive and time the round trip.
+    Ping(String),
+    /// Leaving for good. The server says goodbye and closes the connection;
+    /// the message is only logged.
+    Quit(Option<String>),
+    /// Asks the server for an invite link, answered with a notice.
+    Invite(InviteArgs),
+    /// An invite token, given during the handshake before `NICK`.
+    Token(String),
+    /// Changes a field of our profile, or shows someone's.
+    Profile(ProfileCommand),
+    /// Who `name` is: whether they're online, and their profile.
+    Whois(String),
+    /// Uploads our avatar, or asks for someone's.
+    Avatar(AvatarCommand),
+    /// Adds `name` to our watch list, so their `Presence` changes reach us;
+    /// without a name, asks for the presence of everyone on it.
+    Watch(Option<String>),
+    Unwatch(String),
+    /// Asks someone to be friends or accepts their asking, ends a friendship
+    /// or request, or lists friends.
+    Friend(FriendCommand),
+    /// Stops `name`'s DMs, and channel messages mentioning us, from reaching
+    /// us; without a name, lists who we block.
+    Block(Option<String>),
+    Unblock(String),
+    /// Turns do-not-disturb on or off.
+    Dnd(Dnd),
+    /// We've shown the user the DM with this ID; its sender gets a `Read`
+    /// receipt if they asked for receipts.
+    Read(u64),
+    /// Asks the server to POST to this URL when a DM arrives while we're
+    /// offline; without one, stops it.
+    Push(Option<String>),
+    /// Makes our DM thread with `name` ephemeral, for both of us, or not:
+    /// its messages aren't kept or held for later.
+    Ephemeral { name: String, on: bool },
+    /// Has the server send `name` a DM at `at`, an RFC 3339 time left for
+    /// the server to interpret.
+    Schedule { at: String, name: String, body: String },
+    /// Lists our scheduled DMs that haven't gone out yet.
+    Schedules,
+    Unschedule(u64),
+    /// Has the server DM `text` to `name`, or to us with `None`, after
+    /// `delay`, like `90s` or `1h30m`, left for the server to interpret.
+    Remind { name: Option<String>, delay: String, text: String },
+    Poll(PollArgs),
+    /// Votes for option `choice`, from 1, in an open poll.
+    Vote { poll: u64, choice: u64 },
+    /// Asks for a token to upload files over HTTP with, answered by a notice
+    /// saying where and how.
+    Upload,
+    Voice(VoiceCommand),
+    /// The password for a registered nickname, given during the handshake
+    /// after any `TOKEN` and before `NICK`.
+    Pass(String),
+    /// Registers our nickname with a password, or changes its password, so
+    /// nobody can use it without `PASS`.
+    Register(String),
+    /// Answers the `Challenge` a `REGISTER` got; see `pow`.
+    Proof(u64),
+    TwoFactor(TwoFactorCommand),
+    /// Admin only: ends the login lockout of a nickname or address, or
+    /// without one lists who's locked out.
+    Unlock(Option<String>),
+    /// Admin only: lists who may connect, or adds a range to a list.
+    Access(AccessCommand),
+    /// Sets the Ed25519 public key, in hex, that our `Signed` messages are
+    /// checked against, or without one removes it. Registered nicknames only.
+    SignKey(Option<String>),
+    /// Sends `body` to `target`, a channel if it starts with `#` and a
+    /// nickname otherwise, with `signature`, in hex, over
+    /// `ed25519::signed_text`. Recipients get a `Verified` event with it.
+    Signed { target: String, signature: String, body: String },
+    /// Admin only: lists every connection with its address, queue depth,
+    /// traffic and last activity.
+    Connections,
+    /// Admin only: stops the server taking connections and has everyone
+    /// reconnect, to `to` if given; it exits once they've gone or after
+    /// `secs`, by default `[drain] timeout_secs`.
+    Drain { secs: Option<u64>, to: Option<String> },
+    /// The frame isn't valid UTF-8.
+    BadEncoding,
+    Unknown,
+}
+
+/// Dates are `YYYY-MM-DD` and left for the server to interpret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportArgs {
+    pub name: String,
+    pub format: String,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+/// What an invite is for: joining `channel` once connected, if there is
+/// one. `once` tokens work for a single nickname; `ttl_secs` overrides the
+/// server's default lifetime.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InviteArgs {
+    pub channel: Option<String>,
+    pub once: bool,
+    pub ttl_secs: Option<u64>,
+}
+
+/// A poll to open in `channel`: members vote on `options` until the server
+/// closes it after `duration`, like `30m`, or its own default, and
+/// announces the tally.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PollArgs {
+    pub channel: String,
+    pub duration: Option<String>,
+    pub question: String,
+    pub options: Vec<String>,
+}
+
+/// Do-not-disturb: while `on`, the server holds DMs and channel messages
+/// until it's turned off, except DMs from friends if `friends` and channel
+/// messages naming us if `mentions`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dnd {
+    pub on: bool,
+    pub friends: bool,
+    pub mentions: bool,
+}
+
+/// A page of history: up to `limit` of the newest kept messages of `target`,
+/// a channel or `@name` for the DMs with them, only counting messages with
+/// IDs below `before` if given. Paging back passes the lowest ID seen.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryArgs {
+    pub target: String,
+    pub before: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+/// What `SEARCH` looks for: `query` anywhere in a message, ignoring case,
+/// optionally only in one channel or from one sender, returning at most
+/// `limit` of the newest matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchArgs {
+    pub query: String,
+    pub channel: Option<String>,
+    pub from: Option<String>,
+    pub limit: Option<u64>,
+}
+
+/// `PROFILE SET <field> [value]` sets one of our own profile fields, or
+/// clears it without a value; `PROFILE GET <name>` shows someone's profile.
+/// Which fields exist is up to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileCommand {
+    Set { field: String, value: Option<String> },
+    Get(String),
+}
+
+/// `AVATAR SET [image]` replaces our avatar with a base64 image, or removes
+/// it without one; `AVATAR GET <name>` asks for someone's, which comes back
+/// as an `Avatar` event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AvatarCommand {
+    Set(Option<String>),
+    Get(String),
+}
+
+/// `FRIEND ADD <name>` asks `name` to be friends, or accepts if they
+/// already asked; `FRIEND REMOVE <name>` ends a friendship or withdraws or
+/// declines a request; `FRIEND LIST` answers with a `Friend` event for each
+/// friend and request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FriendCommand {
+    Add(String),
+    Remove(String),
+    List,
+}
+
+/// `VOICE JOIN <#chan>` joins a channel's voice session, answered with a
+/// `VoiceToken`, leaving any other one; `VOICE LEAVE` leaves it. Members of
+/// the channel get a `Voice` event either way. See `voice` for the audio.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoiceCommand {
+    Join(String),
+    Leave,
+}
+
+/// Two-factor codes for a registered nickname. `2FA ENABLE` asks for a new
+/// TOTP secret, and `2FA <code>` from it turns two-factor on; after that,
+/// `2FA <code>` also goes in the handshake, after `PASS`. `2FA DISABLE
+/// <code>` turns it off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TwoFactorCommand {
+    Enable,
+    Disable(String),
+    Code(String),
+}
+
+/// `ACCESS` lists the allow and deny ranges; `ACCESS ALLOW|DENY <cidr>`
+/// adds one until the server restarts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessCommand {
+    List,
+    Allow(String),
+    Deny(String),
+}
+
+/// How far a DM has got, reported to senders with the `ack` capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Receipt {
+    /// In the recipient's queue on the server.
+    Queued,
+    /// Written to the recipient's connection.
+    Written,
+    /// The recipient's client says it was shown.
+    Read,
+}
+
+impl Receipt {
+    pub fn label(self) -> &'static str {
+        match self {
+            Receipt::Queued => "queued",
+            Receipt::Written => "written",
+            Receipt::Read => "read",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Receipt> {
+        [Receipt::Queued, Receipt::Written, Receipt::Read].into_iter().find(|r| r.label() == label)
+    }
+}
+
+/// Where we stand with someone on our friend list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FriendStatus {
+    /// We asked them; they haven't answered.
+    Sent,
+    /// They asked us.
+    Received,
+    Friends,
+    /// Off the list: unfriended, or a request withdrawn or declined.
+    Removed,
+}
+
+impl FriendStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            FriendStatus::Sent => "sent",
+            FriendStatus::Received => "received",
+            FriendStatus::Friends => "friends",
+            FriendStatus::Removed => "removed",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<FriendStatus> {
+        [FriendStatus::Sent, FriendStatus::Received, FriendStatus::Friends, FriendStatus::Removed].into_iter().find(|s| s.label() == label)
+    }
+}
+
+/// Whether someone is around, as watchers see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Presence {
+    Online,
+    /// Online, but marked away with this message.
+    Away(String),
+    Offline,
+}
+
+impl Presence {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Presence::Online => "online",
+            Presence::Away(_) => "away",
+            Presence::Offline => "offline",
+        }
+    }
+}
+
+/// Inclusive range of a recipient's DM sequence numbers; `to: None` is open-ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqRange {
+    pub from: u64,
+    pub to: Option<u64>,
+}
+
+/// Something the server sends to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Hello { version: u32 },
+    Cap { sub: String, caps: String },
+    Welcome { id: u64, name: String },
+    Notice(String),
+    /// `id` is unique per server run and `ts` is an RFC 3339 UTC timestamp,
+    /// both stamped by the server when it accepts the message. `seq` numbers
+    /// the recipient's DMs consecutively so dropped ones show up as a gap.
+    Dm { id: u64, seq: u64, ts: String, from: String, from_id: u64, body: String },
+    /// A message to a channel the recipient has joined, stamped like `Dm`.
+    ChannelMsg { channel: String, id: u64, ts: String, from: String, from_id: u64, body: String },
+    /// A JSON document describing everything stored about the user.
+    Export(String),
+    /// Piece `part` of `parts` of an `Export` too big for one frame, sent
+    /// in order from 1; the pieces' `data` put together is the document.
+    ExportPart { part: u32, parts: u32, data: String },
+    /// `from`'s public key for end-to-end encrypted DMs, relayed by the server.
+    Key { from: String, from_id: u64, key: String },
+    /// The answer to a `Ping`, echoing its token.
+    Pong(String),
+    /// `name`'s avatar, base64, in answer to `AVATAR GET`.
+    Avatar { name: String, image: String },
+    /// Someone on our watch list came, went or changed their away status.
+    Presence { name: String, presence: Presence },
+    /// Our friend list changed for `name`, or is being listed. Friends'
+    /// presence follows as `Presence` events, as if they were watched.
+    Friend { name: String, status: FriendStatus },
+    /// Our DM `id` to `name` got as far as `state`.
+    Receipt { id: u64, name: String, state: Receipt },
+    /// Our DM thread with `name` became ephemeral or stopped being, or is
+    /// ephemeral as we log in.
+    Ephemeral { name: String, on: bool },
+    /// The title of the page at `url`, which the message `id` links to.
+    Preview { id: u64, url: String, title: String },
+    /// The message `id` links to a file uploaded to the server, `size` bytes
+    /// and called `name`.
+    Attachment { id: u64, url: String, size: u64, name: String },
+    /// `name` joined or left `channel`'s voice session, or is in it as we
+    /// join.
+    Voice { channel: String, name: String, joined: bool },
+    /// We're in `channel`'s voice session: send audio to UDP `port` with
+    /// `token`, in hex.
+    VoiceToken { channel: String, port: u16, token: String },
+    /// Registering our nickname takes a `Proof` of work first; see `pow`.
+    Challenge { nonce: String, bits: u32 },
+    /// The message `id` was signed by its sender's key, `key` in hex.
+    Verified { id: u64, key: String },
+    /// The server is draining for a restart: connect again, to `addr` if
+    /// given and otherwise to the same address.
+    Reconnect(Option<String>),
+    Error(String),
+}
+
+impl Command {
+    pub fn parse(frame: &[u8], wire: Wire) -> Command {
+        // The binary formats validate their own strings while decoding.
+        if matches!(wire, Wire::Text | Wire::Json) && text(frame).is_none() {
+            return Command::BadEncoding;
+        }
+        match wire {
+            Wire::Text => text(frame).map(parse_text_command),
+            Wire::Json | Wire::MsgPack => object(frame, wire).and_then(|obj| command_from_object(&obj)),
+            Wire::Protobuf => pb::decode_command(frame),
+        }
+        .unwrap_or(Command::Unknown)
+    }
+
+    pub fn encode(&self, wire: Wire) -> Vec<u8> {
+        match wire {
+            Wire::Text => self.to_text().into_bytes(),
+            Wire::Json => self.write_fields::<ObjectWriter>(),
+            Wire::MsgPack => self.write_fields::<MapWriter>(),
+            Wire::Protobuf => pb::encode_command(self),
+        }
+    }
+
+    /// Every command's `keyword`.
+    pub const KEYWORDS: &'static [&'static str] = &[
+        "hello", "cap", "nick", "kick", "kickid", "export", "exportme", "purge", "resend", "join", "part", "topic", "history", "search", "retention",
+        "say", "to", "toid", "key", "away", "ping", "quit", "invite", "token", "profile", "whois", "avatar", "watch", "unwatch", "friend", "block",
+        "unblock", "dnd", "read", "push", "ephemeral", "schedule", "schedules", "unschedule", "remind", "poll", "vote", "upload", "voice", "pass",
+        "register", "proof", "2fa", "unlock", "access", "signkey", "signed", "connections",
+        "drain",
+    ];
+
+    /// The word the command starts with in text, lowercase; empty for
+    /// `BadEncoding` and `Unknown`.
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Command::Hello(_) => "hello",
+            Command::Cap(_) => "cap",
+            Command::Nick(_) => "nick",
+            Command::Kick(_) => "kick",
+            Command::KickId(_) => "kickid",
+            Command::Export(_) => "export",
+            Command::ExportMe => "exportme",
+            Command::Purge(_) => "purge",
+            Command::Resend(_) => "resend",
+            Command::Join { .. } => "join",
+            Command::Part(_) => "part",
+            Command::Topic { .. } => "topic",
+            Command::History(_) => "history",
+            Command::Search(_) => "search",
+            Command::Retention { .. } => "retention",
+            Command::Say { .. } => "say",
+            Command::To { .. } => "to",
+            Command::ToId { .. } => "toid",
+            Command::Key { .. } => "key",
+            Command::Away(_) => "away",
+            Command::Ping(_) => "ping",
+            Command::Quit(_) => "quit",
+            Command::Invite(_) => "invite",
+            Command::Token(_) => "token",
+            Command::Profile(_) => "profile",
+            Command::Whois(_) => "whois",
+            Command::Avatar(_) => "avatar",
+            Command::Watch(_) => "watch",
+            Command::Unwatch(_) => "unwatch",
+            Command::Friend(_) => "friend",
+            Command::Block(_) => "block",
+            Command::Unblock(_) => "unblock",
+            Command::Dnd(_) => "dnd",
+            Command::Read(_) => "read",
+            Command::Push(_) => "push",
+            Command::Ephemeral { .. } => "ephemeral",
+            Command::Schedule { .. } => "schedule",
+            Command::Schedules => "schedules",
+            Command::Unschedule(_) => "unschedule",
+            Command::Remind { .. } => "remind",
+            Command::Poll(_) => "poll",
+            Command::Vote { .. } => "vote",
+            Command::Upload => "upload",
+            Command::Voice(_) => "voice",
+            Command::Pass(_) => "pass",
+            Command::Register(_) => "register",
+            Command::Proof(_) => "proof",
+            Command::TwoFactor(_) => "2fa",
+            Command::Unlock(_) => "unlock",
+            Command::Access(_) => "access",
+            Command::SignKey(_) => "signkey",
+            Command::Signed { .. } => "signed",
+            Command::Connections => "connections",
+            Command::Drain { .. } => "drain",
+            Command::BadEncoding | Command::Unknown => "",
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Command::Hello(v) => format!("HELLO {v}"),
+            Command::Cap(CapCommand::Ls) => "CAP LS".into(),
+            Command::Cap(CapCommand::Req(caps)) => format!("CAP REQ {caps}"),
+            Command::Cap(CapCommand::End) => "CAP END".into(),
+            Command::Nick(name) => format!("NICK {name}"),
+            Command::Kick(name) => format!("KICK {name}"),
+            Command::KickId(Some(id)) => format!("KICKID {id}"),
+            Command::Export(Some(a)) => {
+                let mut line = format!("EXPORT {} {}", a.name, a.format);
+                if let Some(since) = &a.since {
+                    line = format!("{line} {since}");
+                    if let Some(until) = &a.until {
+                        line = format!("{line} {until}");
+                    }
+                }
+                line
+            }
+            Command::ExportMe => "EXPORTME".into(),
+            Command::Purge(name) => format!("PURGE {name}"),
+            Command::Resend(Some(SeqRange { from, to: Some(to) })) => format!("RESEND {from} {to}"),
+            Command::Resend(Some(SeqRange { from, to: None })) => format!("RESEND {from}"),
+            Command::Join { channel, key: Some(key) } => format!("JOIN {channel} {key}"),
+            Command::Join { channel, key: None } => format!("JOIN {channel}"),
+            Command::Part(channel) => format!("PART {channel}"),
+            Command::Topic { channel, topic: Some(topic) } => format!("TOPIC {channel} {topic}"),
+            Command::Topic { channel, topic: None } => format!("TOPIC {channel}"),
+            Command::History(a) => {
+                let mut line = format!("HISTORY {}", a.target);
+                if let Some(before) = a.before {
+                    line = format!("{line} before:{before}");
+                }
+                if let Some(limit) = a.limit {
+                    line = format!("{line} limit:{limit}");
+                }
+                line
+            }
+            Command::Search(a) => {
+                let mut line = format!("SEARCH {}", a.query);
+                if let Some(channel) = &a.channel {
+                    line = format!("{line} in:{channel}");
+                }
+                if let Some(from) = &a.from {
+                    line = format!("{line} from:{from}");
+                }
+                if let Some(limit) = a.limit {
+                    line = format!("{line} {limit}");
+                }
+                line
+            }
+            Command::Retention { channel, policy: Some(policy) } => format!("RETENTION {channel} {policy}"),
+            Command::Retention { channel, policy: None } => format!("RETENTION {channel}"),
+            Command::Say { channel, body } => format!("SAY {channel} {body}"),
+            Command::To { name, body } => format!("TO {name} {body}"),
+            Command::ToId { id, body } => format!("TOID {id} {body}"),
+            Command::Key { name, key } => format!("KEY {name} {key}"),
+            Command::Away(Some(message)) => format!("AWAY {message}"),
+            Command::Away(None) => "AWAY".into(),
+            Command::Ping(token) => format!("PING {token}").trim_end().to_string(),
+            Command::Quit(Some(message)) => format!("QUIT {message}"),
+            Command::Quit(None) => "QUIT".into(),
+            Command::Invite(a) => {
+                let mut line = String::from("INVITE");
+                if let Some(channel) = &a.channel {
+                    line = format!("{line} {channel}");
+                }
+                if a.once {
+                    line.push_str(" once");
+                }
+                if let Some(secs) = a.ttl_secs {
+                    line = format!("{line} {secs}");
+                }
+                line
+            }
+            Command::Token(token) => format!("TOKEN {token}"),
+            Command::Profile(ProfileCommand::Set { field, value: Some(value) }) => format!("PROFILE SET {field} {value}"),
+            Command::Profile(ProfileCommand::Set { field, value: None }) => format!("PROFILE SET {field}"),
+            Command::Profile(ProfileCommand::Get(name)) => format!("PROFILE GET {name}"),
+            Command::Whois(name) => format!("WHOIS {name}"),
+            Command::Avatar(AvatarCommand::Set(Some(image))) => format!("AVATAR SET {image}"),
+            Command::Avatar(AvatarCommand::Set(None)) => "AVATAR SET".into(),
+            Command::Avatar(AvatarCommand::Get(name)) => format!("AVATAR GET {name}"),
+            Command::Watch(Some(name)) => format!("WATCH {name}"),
+            Command::Watch(None) => "WATCH".into(),
+            Command::Unwatch(name) => format!("UNWATCH {name}"),
+            Command::Block(Some(name)) => format!("BLOCK {name}"),
+            Command::Block(None) => "BLOCK".into(),
+            Command::Unblock(name) => format!("UNBLOCK {name}"),
+            Command::Read(id) => format!("READ {id}"),
+            Command::Push(Some(url)) => format!("PUSH {url}"),
+            Command::Push(None) => "PUSH".into(),
+            // The name goes last since it may contain spaces.
+            Command::Ephemeral { name, on } => format!("EPHEMERAL {} {name}", if *on { "on" } else { "off" }),
+            Command::Schedule { at, name, body } => format!("SCHEDULE {at} TO {name} {body}"),
+            Command::Schedules => "SCHEDULES".into(),
+            Command::Unschedule(id) => format!("UNSCHEDULE {id}"),
+            Command::Remind { name, delay, text } => format!("REMIND {} in {delay} {text}", name.as_deref().unwrap_or("me")),
+            Command::Poll(a) => {
+                let mut line = format!("POLL {}", a.channel);
+                if let Some(duration) = &a.duration {
+                    line = format!("{line} for {duration}");
+                }
+                for text in std::iter::once(&a.question).chain(&a.options) {
+                    line = format!("{line} \"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+                }
+                line
+            }
+            Command::Vote { poll, choice } => format!("VOTE {poll} {choice}"),
+            Command::Upload => "UPLOAD".into(),
+            Command::Voice(VoiceCommand::Join(channel)) => format!("VOICE JOIN {channel}"),
+            Command::Voice(VoiceCommand::Leave) => "VOICE LEAVE".into(),
+            Command::Pass(password) => format!("PASS {password}"),
+            Command::Register(password) => format!("REGISTER {password}"),
+            Command::Proof(n) => format!("PROOF {n}"),
+            Command::TwoFactor(TwoFactorCommand::Enable) => "2FA ENABLE".into(),
+            Command::TwoFactor(TwoFactorCommand::Disable(code)) => format!("2FA DISABLE {code}"),
+            Command::TwoFactor(TwoFactorCommand::Code(code)) => format!("2FA {code}"),
+            Command::Unlock(Some(target)) => format!("UNLOCK {target}"),
+            Command::Unlock(None) => "UNLOCK".into(),
+            Command::Access(AccessCommand::List) => "ACCESS".into(),
+            Command::SignKey(Some(key)) => format!("SIGNKEY {key}"),
+            Command::SignKey(None) => "SIGNKEY".into(),
+            Command::Signed { target, signature, body } => format!("SIGNED {target} {signature} {body}"),
+            Command::Connections => "CONNECTIONS".into(),
+            Command::Drain { secs, to } => {
+                let mut line = String::from("DRAIN");
+                if let Some(secs) = secs {
+                    line.push_str(&format!(" {secs}"));
+                }
+                if let Some(to) = to {
+                    line.push_str(&format!(" {to}"));
+                }
+                line
+            }
+            Command::Access(AccessCommand::Allow(cidr)) => format!("ACCESS ALLOW {cidr}"),
+            Command::Access(AccessCommand::Deny(cidr)) => format!("ACCESS DENY {cidr}"),
+            Command::Dnd(d) => {
+                let mut line = String::from(if d.on { "DND on" } else { "DND off" });
+                if d.friends {
+                    line.push_str(" allow:friends");
+                }
+                if d.mentions {
+                    line.push_str(" allow:mentions");
+                }
+                line
+            }
+            Command::Friend(FriendCommand::Add(name)) => format!("FRIEND ADD {name}"),
+            Command::Friend(FriendCommand::Remove(name)) => format!("FRIEND REMOVE {name}"),
+            Command::Friend(FriendCommand::List) => "FRIEND LIST".into(),
+            Command::KickId(None) | Command::Export(None) | Command::Resend(None) | Command::BadEncoding | Command::Unknown => String::new(),
+        }
+    }
+
+    fn write_fields<W: FieldWriter>(&self) -> Vec<u8> {
+        match self {
+            Command::Hello(v) => W::new("hello").num("version", u64::from(*v)),
+            Command::Cap(cmd) => {
+                let (sub, caps) = match cmd {
+                    CapCommand::Ls => ("LS", ""),
+                    CapCommand::Req(caps) => ("REQ", caps.as_str()),
+                    CapCommand::End => ("END", ""),
+                };
+                W::new("cap").str("sub", sub).str("caps", caps)
+            }
+            Command::Nick(name) => W::new("nick").str("name", name),
+            Command::Kick(name) => W::new("kick").str("name", name),
+            Command::KickId(id) => match id {
+                Some(id) => W::new("kick").num("id", *id),
+                None => W::new("kick"),
+            },
+            Command::Export(Some(a)) => W::new("export")
+                .str("name", &a.name)
+                .str("format", &a.format)
+                .opt_str("since", a.since.as_deref())
+                .opt_str("until", a.until.as_deref()),
+            Command::Export(None) => W::new("export"),
+            Command::ExportMe => W::new("exportme"),
+            Command::Purge(name) => W::new("purge").str("name", name),
+            Command::Resend(range) => match range {
+                Some(SeqRange { from, to: Some(to) }) => W::new("resend").num("from", *from).num("to", *to),
+                Some(SeqRange { from, to: None }) => W::new("resend").num("from", *from),
+                None => W::new("resend"),
+            },
+            Command::Join { channel, key } => W::new("join").str("channel", channel).opt_str("key", key.as_deref()),
+            Command::Part(channel) => W::new("part").str("channel", channel),
+            Command::Topic { channel, topic } => W::new("topic").str("channel", channel).opt_str("topic", topic.as_deref()),
+            Command::History(a) => W::new("history").str("target", &a.target).opt_num("before", a.before).opt_num("limit", a.limit),
+            Command::Search(a) => W::new("search")
+                .str("query", &a.query)
+                .opt_str("channel", a.channel.as_deref())
+                .opt_str("from", a.from.as_deref())
+                .opt_num("limit", a.limit),
+            Command::Retention { channel, policy } => W::new("retention").str("channel", channel).opt_str("policy", policy.as_deref()),
+            Command::Say { channel, body } => W::new("say").str("channel", channel).str("body", body),
+            Command::To { name, body } => W::new("dm").str("to", name).str("body", body),
+            Command::ToId { id, body } => W::new("dm").num("to_id", *id).str("body", body),
+            Command::Key { name, key } => W::new("key").str("to", name).str("key", key),
+            Command::Away(message) => W::new("away").opt_str("message", message.as_deref()),
+            Command::Ping(token) => W::new("ping").str("token", token),
+            Command::Quit(message) => W::new("quit").opt_str("message", message.as_deref()),
+            Command::Invite(a) => {
+                let w = W::new("invite").opt_str("channel", a.channel.as_deref()).bool("once", a.once);
+                match a.ttl_secs {
+                    Some(secs) => w.num("ttl_secs", secs),
+                    None => w,
+                }
+            }
+            Command::Token(token) => W::new("token").str("token", token),
+            Command::Profile(ProfileCommand::Set { field, value }) => W::new("profile").str("sub", "set").str("field", field).opt_str("value", value.as_deref()),
+            Command::Profile(ProfileCommand::Get(name)) => W::new("profile").str("sub", "get").str("name", name),
+            Command::Whois(name) => W::new("whois").str("name", name),
+            Command::Avatar(AvatarCommand::Set(image)) => W::new("avatar").str("sub", "set").opt_str("image", image.as_deref()),
+            Command::Avatar(AvatarCommand::Get(name)) => W::new("avatar").str("sub", "get").str("name", name),
+            Command::Watch(name) => W::new("watch").opt_str("name", name.as_deref()),
+            Command::Unwatch(name) => W::new("unwatch").str("name", name),
+            Command::Friend(FriendCommand::Add(name)) => W::new("friend").str("sub", "add").str("name", name),
+            Command::Friend(FriendCommand::Remove(name)) => W::new("friend").str("sub", "remove").str("name", name),
+            Command::Friend(FriendCommand::List) => W::new("friend").str("sub", "list"),
+            Command::Block(name) => W::new("block").opt_str("name", name.as_deref()),
+            Command::Unblock(name) => W::new("unblock").str("name", name),
+            Command::Read(id) => W::new("read").num("id", *id),
+            Command::Push(url) => W::new("push").opt_str("url", url.as_deref()),
+            Command::Ephemeral { name, on } => W::new("ephemeral").str("name", name).bool("on", *on),
+            Command::Schedule { at, name, body } => W::new("schedule").str("at", at).str("to", name).str("body", body),
+            Command::Schedules => W::new("schedules"),
+            Command::Unschedule(id) => W::new("unschedule").num("id", *id),
+            Command::Remind { name, delay, text } => W::new("remind").opt_str("to", name.as_deref()).str("delay", delay).str("text", text),
+            // One option per line, as they can't contain newlines.
+            Command::Poll(a) => W::new("poll").str("channel", &a.channel).opt_str("duration", a.duration.as_deref()).str("question", &a.question).str("options", &a.options.join("\n")),
+            Command::Vote { poll, choice } => W::new("vote").num("poll", *poll).num("choice", *choice),
+            Command::Upload => W::new("upload"),
+            Command::Voice(VoiceCommand::Join(channel)) => W::new("voice").str("sub", "join").str("channel", channel),
+            Command::Voice(VoiceCommand::Leave) => W::new("voice").str("sub", "leave"),
+            Command::Pass(password) => W::new("pass").str("password", password),
+            Command::Register(password) => W::new("register").str("password", password),
+            Command::Proof(n) => W::new("proof").num("n", *n),
+            Command::TwoFactor(TwoFactorCommand::Enable) => W::new("2fa").str("sub", "enable"),
+            Command::TwoFactor(TwoFactorCommand::Disable(code)) => W::new("2fa").str("sub", "disable").str("code", code),
+            Command::TwoFactor(TwoFactorCommand::Code(code)) => W::new("2fa").str("sub", "code").str("code", code),
+            Command::Unlock(target) => W::new("unlock").opt_str("target", target.as_deref()),
+            Command::Access(AccessCommand::List) => W::new("access").str("sub", "list"),
+            Command::SignKey(key) => W::new("signkey").opt_str("key", key.as_deref()),
+            Command::Signed { target, signature, body } => W::new("signed").str("target", target).str("signature", signature).str("body", body),
+            Command::Connections => W::new("connections"),
+            Command::Drain { secs, to } => W::new("drain").opt_num("secs", *secs).opt_str("to", to.as_deref()),
+            Command::Access(AccessCommand::Allow(cidr)) => W::new("access").str("sub", "allow").str("cidr", cidr),
+            Command::Access(AccessCommand::Deny(cidr)) => W::new("access").str("sub", "deny").str("cidr", cidr),
+            Command::Dnd(d) => W::new("dnd").bool("on", d.on).bool("friends", d.friends).bool("mentions", d.mentions),
+            Command::BadEncoding | Command::Unknown => W::new("unknown"),
+        }
+        .finish()
+    }
+}
+
+impl Event {
+    pub fn notice(text: impl Into<String>) -> Self {
+        Event::Notice(text.into())
+    }
+
+    /// A copy with the body of a DM or channel message passed through `f`,
+    /// if this is one and `f` changed it.
+    pub fn map_body<'a>(&'a self, f: impl FnOnce(&'a str) -> Cow<'a, str>) -> Option<Event> {
+        let (Event::Dm { body, .. } | Event::ChannelMsg { body, .. }) = self else {
+            return None;
+        };
+        let Cow::Owned(changed) = f(body) else {
+            return None;
+        };
+        let mut event = self.clone();
+        if let Event::Dm { body, .. } | Event::ChannelMsg { body, .. } = &mut event {
+            *body = changed;
+        }
+        Some(event)
+    }
+
+    pub fn encode(&self, wire: Wire) -> Vec<u8> {
+        match wire {
+            Wire::Text => self.to_text().into_bytes(),
+            Wire::Json => self.write_fields::<ObjectWriter>(),
+            Wire::MsgPack => self.write_fields::<MapWriter>(),
+            Wire::Protobuf => pb::encode_event(self),
+        }
+    }
+
+    /// The inverse of `encode`, for clients. `None` if the frame isn't an event.
+    pub fn parse(frame: &[u8], wire: Wire) -> Option<Event> {
+        match wire {
+            Wire::Text => parse_text_event(std::str::from_utf8(frame).ok()?.trim_end()),
+            Wire::Json | Wire::MsgPack => event_from_object(&object(frame, wire)?),
+            Wire::Protobuf => pb::decode_event(frame),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Event::Hello { version } => format!("HELLO {version}"),
+            Event::Cap { sub, caps } => format!("CAP {sub} {caps}"),
+            Event::Welcome { id, name } => format!("WELCOME {id} {name}"),
+            Event::Notice(text) => format!("[server] {text}"),
+            Event::Dm { id, seq, ts, from, from_id, body } => {
+                format!("[{ts}] #{id} seq={seq} from {from}({from_id}): {body}")
+            }
+            Event::ChannelMsg { channel, id, ts, from, from_id, body } => {
+                format!("[{ts}] #{id} in {channel} from {from}({from_id}): {body}")
+            }
+            Event::Export(dump) => format!("EXPORT {dump}"),
+            Event::ExportPart { part, parts, data } => format!("EXPORTPART {part}/{parts} {data}"),
+            // The name goes last since it may contain spaces.
+            Event::Key { from, from_id, key } => format!("KEY {from_id} {key} {from}"),
+            Event::Pong(token) => format!("PONG {token}").trim_end().to_string(),
+            // As for `Key`, the name goes last.
+            Event::Avatar { name, image } => format!("AVATAR {image} {name}"),
+            // Names may contain spaces, but not ": ".
+            Event::Presence { name, presence: Presence::Away(message) } => format!("PRESENCE away {name}: {message}"),
+            Event::Presence { name, presence } => format!("PRESENCE {} {name}", presence.label()),
+            Event::Friend { name, status } => format!("FRIEND {} {name}", status.label()),
+            Event::Receipt { id, name, state } => format!("RECEIPT {} {id} {name}", state.label()),
+            Event::Ephemeral { name, on } => format!("EPHEMERAL {} {name}", if *on { "on" } else { "off" }),
+            Event::Preview { id, url, title } => format!("PREVIEW {id} {url} {title}"),
+            // The file name goes last since it may contain spaces.
+            Event::Attachment { id, url, size, name } => format!("ATTACHMENT {id} {url} {size} {name}"),
+            Event::Voice { channel, name, joined } => format!("VOICE {} {channel} {name}", if *joined { "join" } else { "leave" }),
+            Event::VoiceToken { channel, port, token } => format!("VOICETOKEN {channel} {port} {token}"),
+            Event::Challenge { nonce, bits } => format!("CHALLENGE {bits} {nonce}"),
+            Event::Verified { id, key } => format!("VERIFIED {id} {key}"),
+            Event::Reconnect(Some(addr)) => format!("RECONNECT {addr}"),
+            Event::Reconnect(None) => "RECONNECT".into(),
+            Event::Error(text) => format!("ERR {text}"),
+        }
+    }
+
+    fn write_fields<W: FieldWriter>(&self) -> Vec<u8> {
+        match self {
+            Event::Hello { version } => W::new("hello").num("version", u64::from(*version)),
+            Event::Cap { sub, caps } => W::new("cap").str("sub", sub).str("caps", caps),
+            Event::Welcome { id, name } => W::new("welcome").num("id", *id).str("name", name),
+            Event::Notice(text) => W::new("notice").str("text", text),
+            Event::ChannelMsg { channel, id, ts, from, from_id, body } => W::new("channel_msg")
+                .str("channel", channel)
+                .num("id", *id)
+                .str("ts", ts)
+                .str("from", from)
+                .num("from_id", *from_id)
+                .str("body", body),
+            Event::Dm { id, seq, ts, from, from_id, body } => W::new("dm")
+                .num("id", *id)
+                .num("seq", *seq)
+                .str("ts", ts)
+                .str("from", from)
+                .num("from_id", *from_id)
+                .str("body", body),
+            Event::Export(dump) => W::new("export").json("data", dump),
+            Event::ExportPart { part, parts, data } => W::new("export_part").num("part", u64::from(*part)).num("parts", u64::from(*parts)).str("data", data),
+            Event::Key { from, from_id, key } => W::new("key").str("from", from).num("from_id", *from_id).str("key", key),
+            Event::Pong(token) => W::new("pong").str("token", token),
+            Event::Avatar { name, image } => W::new("avatar").str("name", name).str("image", image),
+            Event::Presence { name, presence } => {
+                let away = match presence {
+                    Presence::Away(message) => Some(message.as_str()),
+                    _ => None,
+                };
+                W::new("presence").str("name", name).str("status", presence.label()).opt_str("away", away)
+            }
+            Event::Friend { name, status } => W::new("friend").str("name", name).str("status", status.label()),
+            Event::Receipt { id, name, state } => W::new("receipt").num("id", *id).str("name", name).str("state", state.label()),
+            Event::Ephemeral { name, on } => W::new("ephemeral").str("name", name).bool("on", *on),
+            Event::Preview { id, url, title } => W::new("preview").num("id", *id).str("url", url).str("title", title),
+            Event::Attachment { id, url, size, name } => W::new("attachment").num("id", *id).str("url", url).num("size", *size).str("name", name),
+            Event::Voice { channel, name, joined } => W::new("voice").str("channel", channel).str("name", name).bool("joined", *joined),
+            Event::VoiceToken { channel, port, token } => W::new("voice_token").str("channel", channel).num("port", u64::from(*port)).str("token", token),
+            Event::Challenge { nonce, bits } => W::new("challenge").str("nonce", nonce).num("bits", u64::from(*bits)),
+            Event::Verified { id, key } => W::new("verified").num("id", *id).str("key", key),
+            Event::Reconnect(addr) => W::new("reconnect").opt_str("addr", addr.as_deref()),
+            Event::Error(text) => W::new("error").str("text", text),
+        }
+        .finish()
+    }
+}
+
+/// Strictly UTF-8, trimmed.
+fn text(frame: &[u8]) -> Option<&str> {
+    std::str::from_utf8(frame).ok().map(str::trim)
+}
+
+fn object(frame: &[u8], wire: Wire) -> Option<json::Object> {
+    match wire {
+        Wire::Json => json::parse_object(text(frame)?),
+        _ => msgpack::parse_map(frame),
+    }
+}
+
+fn parse_text_command(line: &str) -> Command {
+    if let Some(version) = split_command(line, "HELLO") {
+        return version.parse().map(Command::Hello).unwrap_or(Command::Unknown);
+    }
+    if let Some(rest) = split_command(line, "CAP") {
+        let (sub, caps) = rest.split_once(' ').unwrap_or((rest, ""));
+        return match sub.to_ascii_uppercase().as_str() {
+            "LS" => Command::Cap(CapCommand::Ls),
+            "REQ" => Command::Cap(CapCommand::Req(caps.to_string())),
+            "END" => Command::Cap(CapCommand::End),
+            _ => Command::Unknown,
+        };
+    }
+    if let Some(nick) = parse_nick(line) {
+        return Command::Nick(nick);
+    }
+    if let Some(target) = line.strip_prefix("KICK ") {
+        return Command::Kick(target.to_string());
+    }
+    if let Some(id_str) = line.strip_prefix("KICKID ") {
+        return Command::KickId(id_str.parse().ok());
+    }
+    if let Some(args) = line.strip_prefix("EXPORT ") {
+        return Command::Export(parse_export(args));
+    }
+    if line == "EXPORTME" {
+        return Command::ExportMe;
+    }
+    if let Some(target) = line.strip_prefix("PURGE ") {
+        return Command::Purge(target.trim().to_string());
+    }
+    if let Some(args) = split_command(line, "RESEND") {
+        return Command::Resend(parse_resend(args));
+    }
+    if let Some(args) = line.strip_prefix("JOIN ") {
+        let mut words = args.split_whitespace();
+        let channel = words.next().unwrap_or_default().to_string();
+        return Command::Join { channel, key: words.next().map(str::to_string) };
+    }
+    if let Some(channel) = line.strip_prefix("PART ") {
+        return Command::Part(channel.trim().to_string());
+    }
+    if let Some(args) = split_command(line, "TOPIC") {
+        let (channel, topic) = args.split_once(' ').unwrap_or((args, ""));
+        let topic = Some(topic.trim()).filter(|t| !t.is_empty()).map(str::to_string);
+        return Command::Topic { channel: channel.to_string(), topic };
+    }
+    if let Some(args) = split_command(line, "HISTORY") {
+        return parse_history(args).map_or(Command::Unknown, Command::History);
+    }
+    if let Some(args) = split_command(line, "SEARCH") {
+        return parse_search(args).map_or(Command::Unknown, Command::Search);
+    }
+    if let Some(args) = split_command(line, "RETENTION") {
+        let (channel, policy) = args.split_once(' ').unwrap_or((args, ""));
+        let policy = Some(policy.trim()).filter(|p| !p.is_empty()).map(str::to_string);
+        return Command::Retention { channel: channel.to_string(), policy };
+    }
+    if let Some((channel, body)) = parse_say(line) {
+        return Command::Say { channel: channel.to_string(), body: body.to_string() };
+    }
+    if let Some((name, body)) = parse_to(line) {
+        return Command::To { name: name.to_string(), body: body.to_string() };
+    }
+    if let Some((id, body)) = parse_toid(line) {
+        return Command::ToId { id, body: body.to_string() };
+    }
+    if let Some(message) = split_command(line, "AWAY") {
+        return Command::Away(Some(message).filter(|m| !m.is_empty()).map(str::to_string));
+    }
+    if let Some(message) = split_command(line, "QUIT") {
+        return Command::Quit(Some(message).filter(|m| !m.is_empty()).map(str::to_string));
+    }
+    if let Some(token) = split_command(line, "PING") {
+        return Command::Ping(token.to_string());
+    }
+    if let Some(args) = split_command(line, "INVITE") {
+        return parse_invite(args).map_or(Command::Unknown, Command::Invite);
+    }
+    if let Some(token) = split_command(line, "TOKEN") {
+        return Command::Token(token.to_string());
+    }
+    if let Some(args) = split_command(line, "PROFILE") {
+        return parse_profile(args).map_or(Command::Unknown, Command::Profile);
+    }
+    if let Some(args) = split_command(line, "AVATAR") {
+        let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+        let rest = rest.trim();
+        return match sub.to_ascii_uppercase().as_str() {
+            "SET" => Command::Avatar(AvatarCommand::Set(Some(rest).filter(|i| !i.is_empty()).map(str::to_string))),
+            "GET" if !rest.is_empty() => Command::Avatar(AvatarCommand::Get(rest.to_string())),
+            _ => Command::Unknown,
+        };
+    }
+    if let Some(name) = split_command(line, "WATCH") {
+        return Command::Watch(Some(name).filter(|n| !n.is_empty()).map(str::to_string));
+    }
+    if let Some(name) = split_command(line, "UNWATCH") {
+        return if name.is_empty() { Command::Unknown } else { Command::Unwatch(name.to_string()) };
+    }
+    if let Some(name) = split_command(line, "BLOCK") {
+        return Command::Block(Some(name).filter(|n| !n.is_empty()).map(str::to_string));
+    }
+    if let Some(name) = split_command(line, "UNBLOCK") {
+        return if name.is_empty() { Command::Unknown } else { Command::Unblock(name.to_string()) };
+    }
+    if let Some(id) = split_command(line, "READ") {
+        return id.parse().map_or(Command::Unknown, Command::Read);
+    }
+    if let Some(url) = split_command(line, "PUSH") {
+        return Command::Push(Some(url).filter(|u| !u.is_empty()).map(str::to_string));
+    }
+    if let Some(args) = split_command(line, "SCHEDULE") {
+        let (at, rest) = args.split_once(' ').unwrap_or((args, ""));
+        return match parse_to(rest.trim_start()) {
+            Some((name, body)) => Command::Schedule { at: at.to_string(), name: name.to_string(), body: body.to_string() },
+            None => Command::Unknown,
+        };
+    }
+    if let Some(args) = split_command(line, "REMIND") {
+        let mut p = args.splitn(4, ' ');
+        return match (p.next(), p.next(), p.next(), p.next()) {
+            (Some(name), Some(word), Some(delay), Some(text)) if word.eq_ignore_ascii_case("in") && !text.trim().is_empty() => {
+                let name = Some(name).filter(|n| !n.eq_ignore_ascii_case("me")).map(str::to_string);
+                Command::Remind { name, delay: delay.to_string(), text: text.to_string() }
+            }
+            _ => Command::Unknown,
+        };
+    }
+    if let Some(args) = split_command(line, "POLL") {
+        return parse_poll(args).map_or(Command::Unknown, Command::Poll);
+    }
+    if let Some(args) = split_command(line, "VOTE") {
+        let mut p = args.split_whitespace();
+        return match (p.next().and_then(|id| id.parse().ok()), p.next().and_then(|n| n.parse().ok()), p.next()) {
+            (Some(poll), Some(choice), None) => Command::Vote { poll, choice },
+            _ => Command::Unknown,
+        };
+    }
+    if line.eq_ignore_ascii_case("UPLOAD") {
+        return Command::Upload;
+    }
+    if let Some(args) = split_command(line, "VOICE") {
+        let (sub, channel) = args.split_once(' ').unwrap_or((args, ""));
+        let channel = channel.trim();
+        return match sub.to_ascii_uppercase().as_str() {
+            "JOIN" if is_channel_name(channel) => Command::Voice(VoiceCommand::Join(channel.to_string())),
+            "LEAVE" if channel.is_empty() => Command::Voice(VoiceCommand::Leave),
+            _ => Command::Unknown,
+        };
+    }
+    if let Some(password) = split_command(line, "PASS") {
+        return Command::Pass(password.to_string());
+    }
+    if let Some(password) = split_command(line, "REGISTER") {
+        return if password.is_empty() { Command::Unknown } else { Command::Register(password.to_string()) };
+    }
+    if let Some(args) = split_command(line, "2FA") {
+        let (sub, code) = args.split_once(' ').unwrap_or((args, ""));
+        let code = code.trim();
+        return match sub.to_ascii_uppercase().as_str() {
+            "ENABLE" if code.is_empty() => Command::TwoFactor(TwoFactorCommand::Enable),
+            "DISABLE" if !code.is_empty() => Command::TwoFactor(TwoFactorCommand::Disable(code.to_string())),
+            "ENABLE" | "DISABLE" => Command::Unknown,
+            _ if !sub.is_empty() && code.is_empty() => Command::TwoFactor(TwoFactorCommand::Code(sub.to_string())),
+            _ => Command::Unknown,
+        };
+    }
+    if let Some(n) = split_command(line, "PROOF") {
+        return n.parse().map_or(Command::Unknown, Command::Proof);
+    }
+    if let Some(target) = split_command(line, "UNLOCK") {
+        return Command::Unlock(Some(target).filter(|t| !t.is_empty()).map(str::to_string));
+    }
+    if let Some(key) = split_command(line, "SIGNKEY") {
+        return Command::SignKey(Some(key).filter(|k| !k.is_empty()).map(str::to_string));
+    }
+    if let Some(args) = split_command(line, "SIGNED") {
+        let mut p = args.splitn(3, ' ');
+        return match (p.next(), p.next(), p.next()) {
+            (Some(target), Some(signature), Some(body)) if !target.is_empty() && !signature.is_empty() => {
+                Command::Signed { target: target.to_string(), signature: signature.to_string(), body: body.to_string() }
+            }
+            _ => Command::Unknown,
+        };
+    }
+    if let Some(args) = split_command(line, "ACCESS") {
+        let (sub, cidr) = args.split_once(' ').unwrap_or((args, ""));
+        let cidr = cidr.trim();
+        return match sub.to_ascii_uppercase().as_str() {
+            "" => Command::Access(AccessCommand::List),
+            "ALLOW" if !cidr.is_empty() => Command::Access(AccessCommand::Allow(cidr.to_string())),
+            "DENY" if !cidr.is_empty() => Command::Access(AccessCommand::Deny(cidr.to_string())),
+            _ => Command::Unknown,
+        };
+    }
+    if line.eq_ignore_ascii_case("CONNECTIONS") {
+        return Command::Connections;
+    }
+    if let Some(args) = split_command(line, "DRAIN") {
+        let mut words = args.split_whitespace().peekable();
+        let secs = words.peek().and_then(|w| w.parse().ok());
+        if secs.is_some() {
+            words.next();
+        }
+        let to = words.next().map(str::to_string);
+        return if words.next().is_some() { Command::Unknown } else { Command::Drain { secs, to } };
+    }
+    if line.eq_ignore_ascii_case("SCHEDULES") {
+        return Command::Schedules;
+    }
+    if let Some(id) = split_command(line, "UNSCHEDULE") {
+        return id.parse().map_or(Command::Unknown, Command::Unschedule);
+    }
+    if let Some(args) = split_command(line, "EPHEMERAL") {
+        let (on, name) = args.split_once(' ').unwrap_or((args, ""));
+        let on = match on.to_ascii_lowercase().as_str() {
+            "on" => true,
+            "off" => false,
+            _ => return Command::Unknown,
+        };
+        let name = name.trim();
+        return if name.is_empty() { Command::Unknown } else { Command::Ephemeral { name: name.to_string(), on } };
+    }
+    if let Some(args) = split_command(line, "DND") {
+        return parse_dnd(args).map_or(Command::Unknown, Command::Dnd);
+    }
+    if let Some(args) = split_command(line, "FRIEND") {
+        let (sub, name) = args.split_once(' ').unwrap_or((args, ""));
+        let name = name.trim().to_string();
+        return match sub.to_ascii_uppercase().as_str() {
+            "ADD" if !name.is_empty() => Command::Friend(FriendCommand::Add(name)),
+            "REMOVE" if !name.is_empty() => Command::Friend(FriendCommand::Remove(name)),
+            "LIST" if name.is_empty() => Command::Friend(FriendCommand::List),
+            _ => Command::Unknown,
+        };
+    }
+    if let Some(name) = split_command(line, "WHOIS") {
+        return if name.is_empty() { Command::Unknown } else { Command::Whois(name.to_string()) };
+    }
+    if let Some((name, key)) = line.strip_prefix("KEY ").and_then(|args| args.rsplit_once(' ')) {
+        return Command::Key { name: name.to_string(), key: key.to_string() };
+    }
+    Command::Unknown
+}
+
+/// Case-insensitive `CMD rest`, returning `rest`.
+fn split_command<'a>(line: &'a str, cmd: &str) -> Option<&'a str> {
+    let (head, rest) = line.split_once(' ').unwrap_or((line, ""));
+    head.eq_ignore_ascii_case(cmd).then(|| rest.trim())
+}
+
+fn command_from_object(obj: &json::Object) -> Option<Command> {
+    let owned = |key| json::get_str(obj, key).map(str::to_string);
+    let cmd = match json::get_str(obj, "type")? {
+        "hello" => Command::Hello(u32::try_from(json::get_u64(obj, "version")?).ok()?),
+        "cap" => Command::Cap(match json::get_str(obj, "sub")?.to_ascii_uppercase().as_str() {
+            "LS" => CapCommand::Ls,
+            "REQ" => CapCommand::Req(owned("caps").unwrap_or_default()),
+            "END" => CapCommand::End,
+            _ => return None,
+        }),
+        "nick" => {
+            let name = json::get_str(obj, "name")?.trim();
+            if name.is_empty() {
+                return None;
+            }
+            Command::Nick(name.to_string())
+        }
+        "dm" => {
+            let body = owned("body")?;
+            match owned("to") {
+                Some(name) => Command::To { name, body },
+                None => Command::ToId { id: json::get_u64(obj, "to_id")?, body },
+            }
+        }
+        "kick" => match owned("name") {
+            Some(name) => Command::Kick(name),
+            None => Command::KickId(json::get_u64(obj, "id")),
+        },
+        "export" => Command::Export(export_from_object(obj)),
+        "exportme" => Command::ExportMe,
+        "purge" => Command::Purge(owned("name")?),
+        "join" => Command::Join { channel: owned("channel")?, key: owned("key").filter(|k| !k.is_empty()) },
+        "part" => Command::Part(owned("channel")?),
+        "topic" => Command::Topic { channel: owned("channel")?, topic: owned("topic").filter(|t| !t.is_empty()) },
+        "history" => Command::History(HistoryArgs { target: owned("target")?, before: json::get_u64(obj, "before"), limit: json::get_u64(obj, "limit") }),
+        "search" => Command::Search(SearchArgs {
+            query: owned("query").filter(|q| !q.trim().is_empty())?,
+            channel: owned("channel"),
+            from: owned("from"),
+            limit: json::get_u64(obj, "limit"),
+        }),
+        "retention" => Command::Retention { channel: owned("channel")?, policy: owned("policy").filter(|p| !p.is_empty()) },
+        "say" => Command::Say { channel: owned("channel")?, body: owned("body")? },
+        "key" => Command::Key { name: owned("to")?, key: owned("key")? },
+        "away" => Command::Away(owned("message").filter(|m| !m.is_empty())),
+        "ping" => Command::Ping(owned("token").unwrap_or_default()),
+        "quit" => Command::Quit(owned("message").filter(|m| !m.is_empty())),
+        "invite" => Command::Invite(InviteArgs {
+            channel: owned("channel"),
+            once: json::get_bool(obj, "once").unwrap_or(false),
+            ttl_secs: json::get_u64(obj, "ttl_secs"),
+        }),
+        "token" => Command::Token(owned("token")?),
+        "profile" => Command::Profile(match json::get_str(obj, "sub")?.to_ascii_lowercase().as_str() {
+            "set" => ProfileCommand::Set { field: owned("field")?, value: owned("value").filter(|v| !v.is_empty()) },
+            "get" => ProfileCommand::Get(owned("name")?),
+            _ => return None,
+        }),
+        "whois" => Command::Whois(owned("name")?),
+        "watch" => Command::Watch(owned("name").filter(|n| !n.is_empty())),
+        "unwatch" => Command::Unwatch(owned("name")?),
+        "block" => Command::Block(owned("name").filter(|n| !n.is_empty())),
+        "unblock" => Command::Unblock(owned("name")?),
+        "read" => Command::Read(json::get_u64(obj, "id")?),
+        "push" => Command::Push(owned("url").filter(|u| !u.is_empty())),
+        "ephemeral" => Command::Ephemeral { name: owned("name")?, on: json::get_bool(obj, "on")? },
+        "schedule" => Command::Schedule { at: owned("at")?, name: owned("to")?, body: owned("body")? },
+        "schedules" => Command::Schedules,
+        "unschedule" => Command::Unschedule(json::get_u64(obj, "id")?),
+        "remind" => Command::Remind { name: owned("to").filter(|n| !n.is_empty()), delay: owned("delay")?, text: owned("text")? },
+        "poll" => Command::Poll(PollArgs {
+            channel: owned("channel")?,
+            duration: owned("duration").filter(|d| !d.is_empty()),
+            question: owned("question")?,
+            options: json::get_str(obj, "options")?.lines().map(str::to_string).collect(),
+        }),
+        "vote" => Command::Vote { poll: json::get_u64(obj, "poll")?, choice: json::get_u64(obj, "choice")? },
+        "upload" => Command::Upload,
+        "voice" => Command::Voice(match json::get_str(obj, "sub")?.to_ascii_lowercase().as_str() {
+            "join" => VoiceCommand::Join(owned("channel")?),
+            "leave" => VoiceCommand::Leave,
+            _ => return None,
+        }),
+        "pass" => Command::Pass(owned("password")?),
+        "register" => Command::Register(owned("password")?),
+        "proof" => Command::Proof(json::get_u64(obj, "n")?),
+        "2fa" => Command::TwoFactor(match json::get_str(obj, "sub")?.to_ascii_lowercase().as_str() {
+            "enable" => TwoFactorCommand::Enable,
+            "disable" => TwoFactorCommand::Disable(owned("code")?),
+            "code" => TwoFactorCommand::Code(owned("code")?),
+            _ => return None,
+        }),
+        "unlock" => Command::Unlock(owned("target").filter(|t| !t.is_empty())),
+        "signkey" => Command::SignKey(owned("key").filter(|k| !k.is_empty())),
+        "signed" => Command::Signed { target: owned("target")?, signature: owned("signature")?, body: owned("body")? },
+        "connections" => Command::Connections,
+        "drain" => Command::Drain { secs: json::get_u64(obj, "secs"), to: owned("to").filter(|t| !t.is_empty()) },
+        "access" => Command::Access(match json::get_str(obj, "sub")?.to_ascii_lowercase().as_str() {
+            "list" => AccessCommand::List,
+            "allow" => AccessCommand::Allow(owned("cidr")?),
+            "deny" => AccessCommand::Deny(owned("cidr")?),
+            _ => return None,
+        }),
+        "dnd" => Command::Dnd(Dnd {
+            on: json::get_bool(obj, "on")?,
+            friends: json::get_bool(obj, "friends").unwrap_or(false),
+            mentions: json::get_bool(obj, "mentions").unwrap_or(false),
+        }),
+        "friend" => Command::Friend(match json::get_str(obj, "sub")?.to_ascii_lowercase().as_str() {
+            "add" => FriendCommand::Add(owned("name")?),
+            "remove" => FriendCommand::Remove(owned("name")?),
+            "list" => FriendCommand::List,
+            _ => return None,
+        }),
+        "avatar" => Command::Avatar(match json::get_str(obj, "sub")?.to_ascii_lowercase().as_str() {
+            "set" => AvatarCommand::Set(owned("image").filter(|i| !i.is_empty())),
+            "get" => AvatarCommand::Get(owned("name")?),
+            _ => return None,
+        }),
+        "resend" => Command::Resend(json::get_u64(obj, "from").map(|from| SeqRange { from, to: json::get_u64(obj, "to") })),
+        _ => return None,
+    };
+    Some(cmd)
+}
+
+fn export_from_object(obj: &json::Object) -> Option<ExportArgs> {
+    let owned = |key| json::get_str(obj, key).map(str::to_string);
+    Some(ExportArgs {
+        name: owned("name")?,
+        format: owned("format")?,
+        since: owned("since"),
+        until: owned("until"),
+    })
+}
+
+fn event_from_object(obj: &json::Object) -> Option<Event> {
+    let owned = |key| json::get_str(obj, key).map(str::to_string);
+    let event = match json::get_str(obj, "type")? {
+        "hello" => Event::Hello { version: u32::try_from(json::get_u64(obj, "version")?).ok()? },
+        "cap" => Event::Cap { sub: owned("sub")?, caps: owned("caps").unwrap_or_default() },
+        "welcome" => Event::Welcome { id: json::get_u64(obj, "id")?, name: owned("name")? },
+        "notice" => Event::Notice(owned("text")?),
+        "dm" => Event::Dm {
+            id: json::get_u64(obj, "id")?,
+            seq: json::get_u64(obj, "seq")?,
+            ts: owned("ts")?,
+            from: owned("from")?,
+            from_id: json::get_u64(obj, "from_id")?,
+            body: owned("body")?,
+        },
+        "channel_msg" => Event::ChannelMsg {
+            channel: owned("channel")?,
+            id: json::get_u64(obj, "id")?,
+            ts: owned("ts")?,
+            from: owned("from")?,
+            from_id: json::get_u64(obj, "from_id")?,
+            body: owned("body")?,
+        },
+        "export" => Event::Export(json::get_text(obj, "data")?.to_string()),
+        "export_part" => Event::ExportPart {
+            part: u32::try_from(json::get_u64(obj, "part")?).ok()?,
+            parts: u32::try_from(json::get_u64(obj, "parts")?).ok()?,
+            data: owned("data")?,
+        },
+        "key" => Event::Key { from: owned("from")?, from_id: json::get_u64(obj, "from_id")?, key: owned("key")? },
+        "pong" => Event::Pong(owned("token").unwrap_or_default()),
+        "avatar" => Event::Avatar { name: owned("name")?, image: owned("image")? },
+        "friend" => Event::Friend { name: owned("name")?, status: FriendStatus::from_label(json::get_str(obj, "status")?)? },
+        "receipt" => Event::Receipt { id: json::get_u64(obj, "id")?, name: owned("name")?, state: Receipt::from_label(json::get_str(obj, "state")?)? },
+        "ephemeral" => Event::Ephemeral { name: owned("name")?, on: json::get_bool(obj, "on")? },
+        "preview" => Event::Preview { id: json::get_u64(obj, "id")?, url: owned("url")?, title: owned("title")? },
+        "voice" => Event::Voice { channel: owned("channel")?, name: owned("name")?, joined: json::get_bool(obj, "joined")? },
+        "voice_token" => Event::VoiceToken { channel: owned("channel")?, port: u16::try_from(json::get_u64(obj, "port")?).ok()?, token: owned("token")? },
+        "verified" => Event::Verified { id: json::get_u64(obj, "id")?, key: owned("key")? },
+        "reconnect" => Event::Reconnect(owned("addr").filter(|a| !a.is_empty())),
+        "challenge" => Event::Challenge { nonce: owned("nonce")?, bits: u32::try_from(json::get_u64(obj, "bits")?).ok()? },
+        "attachment" => Event::Attachment { id: json::get_u64(obj, "id")?, url: owned("url")?, size: json::get_u64(obj, "size")?, name: owned("name")? },
+        "presence" => Event::Presence { name: owned("name")?, presence: presence(json::get_str(obj, "status")?, owned("away"))? },
+        "error" => Event::Error(owned("text")?),
+        _ => return None,
+    };
+    Some(event)
+}
+
+fn parse_text_event(line: &str) -> Option<Event> {
+    if let Some(text) = line.strip_prefix("[server] ") {
+        return Some(Event::Notice(text.to_string()));
+    }
+    if let Some(rest) = line.strip_prefix('[') {
+        // `[<ts>] #<id> seq=<seq> from <name>(<from_id>): <body>` for DMs and
+        // `[<ts>] #<id> in <channel> from ...` for channels; names may
+        // contain spaces but not "): ".
+        let (ts, rest) = rest.split_once("] #")?;
+        let (id, rest) = rest.split_once(' ')?;
+        let (to, rest) = rest.split_once(" from ")?;
+        let (head, body) = rest.split_once("): ")?;
+        let (from, from_id) = head.rsplit_once('(')?;
+        let (id, ts, from, from_id, body) =
+            (id.parse().ok()?, ts.to_string(), from.to_string(), from_id.parse().ok()?, body.to_string());
+        return match (to.strip_prefix("seq="), to.strip_prefix("in ")) {
+            (Some(seq), _) => Some(Event::Dm { id, seq: seq.parse().ok()?, ts, from, from_id, body }),
+            (_, Some(channel)) => Some(Event::ChannelMsg { channel: channel.to_string(), id, ts, from, from_id, body }),
+            _ => None,
+        };
+    }
+    let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let event = match cmd {
+        "HELLO" => Event::Hello { version: rest.parse().ok()? },
+        "CAP" => {
+            let (sub, caps) = rest.split_once(' ').unwrap_or((rest, ""));
+            Event::Cap { sub: sub.to_string(), caps: caps.to_string() }
+        }
+        "WELCOME" => {
+            let (id, name) = rest.split_once(' ')?;
+            Event::Welcome { id: id.parse().ok()?, name: name.to_string() }
+        }
+        "EXPORT" => Event::Export(rest.to_string()),
+        "EXPORTPART" => {
+            let (numbers, data) = rest.split_once(' ').unwrap_or((rest, ""));
+            let (part, parts) = numbers.split_once('/')?;
+            Event::ExportPart { part: part.parse().ok()?, parts: parts.parse().ok()?, data: data.to_string() }
+        }
+        "KEY" => {
+            let (from_id, rest) = rest.split_once(' ')?;
+            let (key, from) = rest.split_once(' ')?;
+            Event::Key { from: from.to_string(), from_id: from_id.parse().ok()?, key: key.to_string() }
+        }
+        "PONG" => Event::Pong(rest.to_string()),
+        "AVATAR" => {
+            let (image, name) = rest.split_once(' ')?;
+            Event::Avatar { name: name.to_string(), image: image.to_string() }
+        }
+        "RECEIPT" => {
+            let (state, rest) = rest.split_once(' ')?;
+            let (id, name) = rest.split_once(' ')?;
+            Event::Receipt { id: id.parse().ok()?, name: name.to_string(), state: Receipt::from_label(state)? }
+        }
+        "PREVIEW" => {
+            let (id, rest) = rest.split_once(' ')?;
+            let (url, title) = rest.split_once(' ')?;
+            Event::Preview { id: id.parse().ok()?, url: url.to_string(), title: title.to_string() }
+        }
+        "VOICE" => {
+            let (joined, rest) = rest.split_once(' ')?;
+            let (channel, name) = rest.split_once(' ')?;
+            let joined = match joined {
+                "join" => true,
+                "leave" => false,
+                _ => return None,
+            };
+            Event::Voice { channel: channel.to_string(), name: name.to_string(), joined }
+        }
+        "VOICETOKEN" => {
+            let mut p = rest.split(' ');
+            let (channel, port, token) = (p.next()?, p.next()?.parse().ok()?, p.next()?);
+            if p.next().is_some() {
+                return None;
+            }
+            Event::VoiceToken { channel: channel.to_string(), port, token: token.to_string() }
+        }
+        "CHALLENGE" => {
+            let (bits, nonce) = rest.split_once(' ')?;
+            Event::Challenge { nonce: nonce.to_string(), bits: bits.parse().ok()? }
+        }
+        "RECONNECT" => Event::Reconnect(Some(rest.trim()).filter(|a| !a.is_empty()).map(str::to_string)),
+        "VERIFIED" => {
+            let (id, key) = rest.split_once(' ')?;
+            Event::Verified { id: id.parse().ok()?, key: key.to_string() }
+        }
+        "ATTACHMENT" => {
+            let mut p = rest.splitn(4, ' ');
+            let (id, url, size, name) = (p.next()?, p.next()?, p.next()?, p.next()?);
+            Event::Attachment { id: id.parse().ok()?, url: url.to_string(), size: size.parse().ok()?, name: name.to_string() }
+        }
+        "EPHEMERAL" => {
+            let (on, name) = rest.split_once(' ')?;
+            Event::Ephemeral { name: name.to_string(), on: match on { "on" => true, "off" => false, _ => return None } }
+        }
+        "FRIEND" => {
+            let (status, name) = rest.split_once(' ')?;
+            Event::Friend { name: name.to_string(), status: FriendStatus::from_label(status)? }
+        }
+        "PRESENCE" => {
+            let (status, rest) = rest.split_once(' ')?;
+            let (name, away) = match status {
+                "away" => rest.split_once(": ").map_or((rest, ""), |(name, away)| (name, away)),
+                _ => (rest, ""),
+            };
+            Event::Presence { name: name.to_string(), presence: presence(status, Some(away.to_string()))? }
+        }
+        "ERR" => Event::Error(rest.to_string()),
+        _ => return None,
+    };
+    Some(event)
+}
+
+pub fn parse_nick(line: &str) -> Option<String> {
+    let (cmd, nick) = line.split_once(' ')?;
+    let nick = nick.trim();
+    if cmd.eq_ignore_ascii_case("NICK") && !nick.is_empty() {
+        Some(nick.to_string())
+    } else {
+        None
+    }
+}
+
+/// A Curve25519 public key as sent in `KEY`: 64 hex digits.
+pub fn is_public_key(key: &str) -> bool {
+    key.len() == 64 && key.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// `#` followed by up to 32 printable characters, no spaces.
+pub fn is_channel_name(name: &str) -> bool {
+    name.strip_prefix('#')
+        .is_some_and(|rest| (1..=32).contains(&rest.chars().count()) && !rest.chars().any(|c| c.is_whitespace() || c.is_control()))
+}
+
+fn parse_export(args: &str) -> Option<ExportArgs> {
+    let mut p = args.split_whitespace();
+    Some(ExportArgs {
+        name: p.next()?.to_string(),
+        format: p.next()?.to_string(),
+        since: p.next().map(str::to_string),
+        until: p.next().map(str::to_string),
+    })
+}
+
+/// `<#chan|@name> [before:<id>] [limit:<n>]`; a bare number is the limit too.
+pub fn parse_history(args: &str) -> Option<HistoryArgs> {
+    let mut words = args.split_whitespace();
+    let mut history = HistoryArgs { target: words.next()?.to_string(), ..HistoryArgs::default() };
+    for word in words {
+        if let Some(before) = word.strip_prefix("before:") {
+            history.before = Some(before.parse().ok()?);
+        } else {
+            history.limit = Some(word.strip_prefix("limit:").unwrap_or(word).parse().ok()?);
+        }
+    }
+    Some(history)
+}
+
+/// `<query> [in:#chan] [from:name] [limit]`; the filters can go anywhere,
+/// and a number at the end is the limit unless it's all the query there is.
+pub fn parse_search(args: &str) -> Option<SearchArgs> {
+    let mut search = SearchArgs::default();
+    let mut words = Vec::new();
+    for word in args.split_whitespace() {
+        if let Some(channel) = word.strip_prefix("in:") {
+            search.channel = Some(channel.to_string()).filter(|c| is_channel_name(c));
+            search.channel.as_ref()?;
+        } else if let Some(from) = word.strip_prefix("from:").filter(|f| !f.is_empty()) {
+            search.from = Some(from.to_string());
+        } else {
+            words.push(word);
+        }
+    }
+    if words.len() > 1 {
+        if let Some(Ok(limit)) = words.last().map(|w| w.parse()) {
+            search.limit = Some(limit);
+            words.pop();
+        }
+    }
+    search.query = words.join(" ");
+    (!search.query.is_empty()).then_some(search)
+}
+
+/// `[#chan] [once] [secs]`, in that order.
+pub fn parse_invite(args: &str) -> Option<InviteArgs> {
+    let mut invite = InviteArgs::default();
+    let mut words = args.split_whitespace().peekable();
+    if let Some(channel) = words.next_if(|w| w.starts_with('#')) {
+        invite.channel = Some(channel.to_string());
+    }
+    invite.once = words.next_if(|w| w.eq_ignore_ascii_case("once")).is_some();
+    if let Some(secs) = words.next() {
+        invite.ttl_secs = Some(secs.parse().ok()?);
+    }
+    words.next().is_none().then_some(invite)
+}
+
+/// `#chan [for <delay>] "question" "option" "option"...`; inside the quotes,
+/// `\"` is a quote and `\\` a backslash.
+pub fn parse_poll(args: &str) -> Option<PollArgs> {
+    let (channel, rest) = args.split_once(' ')?;
+    if !is_channel_name(channel) {
+        return None;
+    }
+    let mut rest = rest.trim_start();
+    let mut duration = None;
+    if let Some(after) = rest.strip_prefix("for ") {
+        let (delay, after) = after.trim_start().split_once(' ')?;
+        duration = Some(delay.to_string());
+        rest = after;
+    }
+    let mut texts = Vec::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => continue,
+            '"' => {}
+            _ => return None,
+        }
+        let mut text = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => text.push(chars.next()?),
+                c => text.push(c),
+            }
+        }
+        texts.push(text);
+    }
+    if texts.is_empty() {
+        return None;
+    }
+    let question = texts.remove(0);
+    Some(PollArgs { channel: channel.to_string(), duration, question, options: texts })
+}
+
+/// `on|off [allow:friends] [allow:mentions]`.
+pub fn parse_dnd(args: &str) -> Option<Dnd> {
+    let mut words = args.split_whitespace();
+    let mut dnd = match words.next()?.to_ascii_lowercase().as_str() {
+        "on" => Dnd { on: true, ..Dnd::default() },
+        "off" => Dnd::default(),
+        _ => return None,
+    };
+    for word in words {
+        match word.to_ascii_lowercase().as_str() {
+            "allow:friends" => dnd.friends = true,
+            "allow:mentions" => dnd.mentions = true,
+            _ => return None,
+        }
+    }
+    Some(dnd)
+}
+
+/// A `Presence` from its label, and the away message that goes with "away".
+fn presence(status: &str, away: Option<String>) -> Option<Presence> {
+    match status {
+        "online" => Some(Presence::Online),
+        "away" => Some(Presence::Away(away.unwrap_or_default())),
+        "offline" => Some(Presence::Offline),
+        _ => None,
+    }
+}
+
+/// `SET <field> [value]` or `GET <name>`.
+pub fn parse_profile(args: &str) -> Option<ProfileCommand> {
+    let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let rest = rest.trim();
+    match sub.to_ascii_uppercase().as_str() {
+        "SET" if !rest.is_empty() => {
+            let (field, value) = rest.split_once(' ').unwrap_or((rest, ""));
+            let value = Some(value.trim()).filter(|v| !v.is_empty()).map(str::to_string);
+            Some(ProfileCommand::Set { field: field.to_string(), value })
+        }
+        "GET" if !rest.is_empty() && !rest.contains(' ') => Some(ProfileCommand::Get(rest.to_string())),
+        _ => None,
+    }
+}
+
+fn parse_resend(args: &str) -> Option<SeqRange> {
+    let mut p = args.split_whitespace();
+    let from = p.next()?.parse().ok()?;
+    let to = match p.next() {
+        Some(to) => Some(to.parse().ok()?),
+        None => None,
+    };
+    Some(SeqRange { from, to })
+}
+
+pub fn parse_to(line: &str) -> Option<(&str, &str)> {
+    let mut p = line.splitn(3, ' ');
+    match (p.next(), p.next(), p.next()) {
+        (Some("TO"), Some(name), Some(rest)) => Some((name, rest)),
+        _ => None,
+    }
+}
+
+pub fn parse_say(line: &str) -> Option<(&str, &str)> {
+    let mut p = line.splitn(3, ' ');
+    match (p.next(), p.next(), p.next()) {
+        (Some("SAY"), Some(channel), Some(rest)) => Some((channel, rest)),
+        _ => None,
+    }
+}
+
+pub fn parse_toid(line: &str) -> Option<(u64, &str)> {
+    let mut p = line.splitn(3, ' ');
+    match (p.next(), p.next(), p.next()) {
+        (Some("TOID"), Some(id_s), Some(rest)) => id_s.parse::<u64>().ok().map(|id| (id, rest)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIRES: [Wire; 4] = [Wire::Text, Wire::Json, Wire::MsgPack, Wire::Protobuf];
+
+    fn commands() -> Vec<Command> {
+        vec![
+            Command::Hello(2),
+            Command::Cap(CapCommand::Ls),
+            Command::Cap(CapCommand::Req("json deflate".into())),
+            Command::Cap(CapCommand::End),
+            Command::Nick("bob".into()),
+            Command::Kick("mallory".into()),
+            Command::KickId(Some(42)),
+            Command::Export(Some(ExportArgs {
+                name: "bob".into(),
+                format: "csv".into(),
+                since: Some("2024-01-01".into()),
+                until: Some("2024-02-01".into()),
+            })),
+            Command::Export(Some(ExportArgs { name: "bob".into(), format: "json".into(), since: None, until: None })),
+            Command::ExportMe,
+            Command::Purge("bob".into()),
+            Command::Resend(Some(SeqRange { from: 0, to: None })),
+            Command::Resend(Some(SeqRange { from: 3, to: Some(9) })),
+            Command::Join { channel: "#rust".into(), key: None },
+            Command::Join { channel: "#staff".into(), key: Some("s3cret".into()) },
+            Command::Part("#rust".into()),
+            Command::Topic { channel: "#rust".into(), topic: Some("all things rust: see the wiki".into()) },
+            Command::Topic { channel: "#rust".into(), topic: None },
+            Command::History(HistoryArgs { target: "#rust".into(), before: Some(1200), limit: Some(50) }),
+            Command::History(HistoryArgs { target: "@alice".into(), before: None, limit: Some(20) }),
+            Command::History(HistoryArgs { target: "#rust".into(), ..HistoryArgs::default() }),
+            Command::Search(SearchArgs { query: "borrow checker".into(), channel: Some("#rust".into()), from: Some("bob".into()), limit: Some(5) }),
+            Command::Search(SearchArgs { query: "lunch".into(), ..SearchArgs::default() }),
+            Command::Retention { channel: "#rust".into(), policy: Some("500 messages".into()) },
+            Command::Retention { channel: "#rust".into(), policy: None },
+            Command::Say { channel: "#rust".into(), body: "hello, channel".into() },
+            Command::To { name: "alice".into(), body: "hi: there, \"friend\"".into() },
+            Command::ToId { id: 7, body: "ünïcödé ✓".into() },
+            Command::Key { name: "alice".into(), key: "ab".repeat(32) },
+            Command::Away(Some("lunch, back at 2".into())),
+            Command::Away(None),
+            Command::Ping("17".into()),
+            Command::Ping(String::new()),
+            Command::Quit(Some("see you tomorrow".into())),
+            Command::Quit(None),
+            Command::Invite(InviteArgs { channel: Some("#rust".into()), once: true, ttl_secs: Some(3600) }),
+            Command::Invite(InviteArgs::default()),
+            Command::Token("3f9a0c".into()),
+            Command::Profile(ProfileCommand::Set { field: "bio".into(), value: Some("rustacean, likes: lifetimes".into()) }),
+            Command::Profile(ProfileCommand::Set { field: "pronouns".into(), value: None }),
+            Command::Profile(ProfileCommand::Get("alice".into())),
+            Command::Whois("alice".into()),
+            Command::Avatar(AvatarCommand::Set(Some("R0lGODlhAQABAAAAACw=".into()))),
+            Command::Avatar(AvatarCommand::Set(None)),
+            Command::Avatar(AvatarCommand::Get("alice".into())),
+            Command::Watch(Some("alice".into())),
+            Command::Watch(None),
+            Command::Unwatch("alice".into()),
+            Command::Friend(FriendCommand::Add("alice smith".into())),
+            Command::Friend(FriendCommand::Remove("alice".into())),
+            Command::Friend(FriendCommand::List),
+            Command::Block(Some("alice".into())),
+            Command::Block(None),
+            Command::Unblock("alice".into()),
+            Command::Dnd(Dnd { on: true, friends: true, mentions: false }),
+            Command::Dnd(Dnd::default()),
+            Command::Read(42),
+            Command::Push(Some("http://ntfy.lan/alice".into())),
+            Command::Push(None),
+            Command::Ephemeral { name: "alice smith".into(), on: true },
+            Command::Ephemeral { name: "bob".into(), on: false },
+            Command::Schedule { at: "2026-10-15T18:00:00+02:00".into(), name: "bob".into(), body: "happy birthday".into() },
+            Command::Schedules,
+            Command::Unschedule(3),
+            Command::Remind { name: None, delay: "10m".into(), text: "tea is ready".into() },
+            Command::Remind { name: Some("bob".into()), delay: "1h30m".into(), text: "standup".into() },
+            Command::Poll(PollArgs { channel: "#rust".into(), duration: Some("30m".into()), question: "Best \"easy\" crate?".into(), options: vec!["tokio".into(), "serde \\ bytes".into()] }),
+            Command::Poll(PollArgs { channel: "#lunch".into(), duration: None, question: "Where?".into(), options: vec!["the noodle place".into(), "pizza".into(), "sushi".into()] }),
+            Command::Vote { poll: 4, choice: 2 },
+            Command::Upload,
+            Command::Voice(VoiceCommand::Join("#standup".into())),
+            Command::Voice(VoiceCommand::Leave),
+            Command::Pass("correct horse".into()),
+            Command::Register("battery staple".into()),
+            Command::Proof(48_213),
+            Command::TwoFactor(TwoFactorCommand::Enable),
+            Command::TwoFactor(TwoFactorCommand::Disable("123456".into())),
+            Command::TwoFactor(TwoFactorCommand::Code("081804".into())),
+            Command::Unlock(Some("192.0.2.7".into())),
+            Command::Unlock(None),
+            Command::Access(AccessCommand::List),
+            Command::Access(AccessCommand::Allow("10.0.0.0/8".into())),
+            Command::Access(AccessCommand::Deny("2001:db8::/32".into())),
+            Command::SignKey(Some("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a".into())),
+            Command::SignKey(None),
+            Command::Signed { target: "#announce".into(), signature: "e5564300c360ac72".into(), body: "v1.2 is out".into() },
+            Command::Signed { target: "alice".into(), signature: "92a009a9f0d4cab8".into(), body: "build passed".into() },
+            Command::Connections,
+            Command::Drain { secs: None, to: None },
+            Command::Drain { secs: Some(30), to: None },
+            Command::Drain { secs: Some(120), to: Some("chat2.example.com:5555".into()) },
+            Command::Drain { secs: None, to: Some("[2001:db8::2]:5555".into()) },
+        ]
+    }
+
+    fn events() -> Vec<Event> {
+        vec![
+            Event::Hello { version: 2 },
+            Event::Cap { sub: "ACK".into(), caps: "json".into() },
+            Event::Welcome { id: 1, name: "bob".into() },
+            Event::notice("user kicked"),
+            Event::Dm {
+                id: 42,
+                seq: 7,
+                ts: "2026-10-15T08:30:00Z".into(),
+                from: "alice".into(),
+                from_id: 3,
+                body: "hello (again): world".into(),
+            },
+            Event::ChannelMsg {
+                channel: "#rust".into(),
+                id: 43,
+                ts: "2026-10-15T08:31:00Z".into(),
+                from: "bob smith".into(),
+                from_id: 4,
+                body: "hi all".into(),
+            },
+            Event::Export(r#"{"id":1,"name":"bob","messages":[]}"#.into()),
+            Event::ExportPart { part: 1, parts: 2, data: r#"{"id":1,"name":"bob","#.into() },
+            Event::ExportPart { part: 2, parts: 2, data: r#" "messages":[]}"#.into() },
+            Event::Key { from: "bob smith".into(), from_id: 4, key: "cd".repeat(32) },
+            Event::Pong("17".into()),
+            Event::Pong(String::new()),
+            Event::Avatar { name: "alice smith".into(), image: "iVBORw0KGgo=".into() },
+            Event::Presence { name: "alice smith".into(), presence: Presence::Online },
+            Event::Presence { name: "bob".into(), presence: Presence::Away("lunch: back at 2".into()) },
+            Event::Presence { name: "bob".into(), presence: Presence::Offline },
+            Event::Friend { name: "alice smith".into(), status: FriendStatus::Received },
+            Event::Friend { name: "bob".into(), status: FriendStatus::Friends },
+            Event::Receipt { id: 7, name: "alice smith".into(), state: Receipt::Written },
+            Event::Ephemeral { name: "alice smith".into(), on: true },
+            Event::Ephemeral { name: "bob".into(), on: false },
+            Event::Preview { id: 12, url: "http://example.com/a?b=c".into(), title: "Example Domain: a page".into() },
+            Event::Voice { channel: "#standup".into(), name: "alice smith".into(), joined: true },
+            Event::Voice { channel: "#standup".into(), name: "bob".into(), joined: false },
+            Event::VoiceToken { channel: "#standup".into(), port: 5557, token: "00112233445566778899aabbccddeeff".into() },
+            Event::Attachment { id: 13, url: "http://192.0.2.1:5556/f/3f9c2a7be01d4c88".into(), size: 48_213, name: "holiday photo.jpg".into() },
+            Event::Challenge { nonce: "5f3a9c0de1b24786".into(), bits: 20 },
+            Event::Verified { id: 42, key: "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a".into() },
+            Event::Reconnect(None),
+            Event::Reconnect(Some("chat2.example.com:5555".into())),
+            Event::Error("name already in use".into()),
+        ]
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        for wire in WIRES {
+            for cmd in commands() {
+                assert_eq!(Command::parse(&cmd.encode(wire), wire), cmd, "{wire:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn commands_start_with_their_keyword() {
+        for cmd in commands().into_iter().filter(|c| !c.to_text().is_empty()) {
+            assert_eq!(cmd.to_text().split(' ').next().unwrap().to_ascii_lowercase(), cmd.keyword(), "{cmd:?}");
+            assert!(Command::KEYWORDS.contains(&cmd.keyword()));
+        }
+    }
+
+    #[test]
+    fn usage_lists_every_command() {
+        let listed: Vec<String> = USAGE.split(" | ").map(|usage| usage.split(' ').next().unwrap().to_ascii_lowercase()).collect();
+        for keyword in Command::KEYWORDS.iter().filter(|k| !["hello", "cap", "token", "pass", "nick"].contains(k)) {
+            assert!(listed.iter().any(|l| l == keyword), "{keyword} isn't in USAGE");
+        }
+    }
+
+    #[test]
+    fn events_round_trip() {
+        for wire in WIRES {
+            for event in events() {
+                assert_eq!(Event::parse(&event.encode(wire), wire), Some(event), "{wire:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn names_with_spaces_survive_json() {
+        let cmd = Command::To { name: "bob smith".into(), body: "hi".into() };
+        assert_eq!(Command::parse(&cmd.encode(Wire::Json), Wire::Json), cmd);
+    }
+
+    #[test]
+    fn text_parsers() {
+        assert_eq!(parse_nick("nick  bob "), Some("bob".into()));
+        assert_eq!(parse_nick("NICK "), None);
+        assert_eq!(parse_to("TO bob hi there"), Some(("bob", "hi there")));
+        assert_eq!(parse_to("TO bob"), None);
+        assert_eq!(parse_toid("TOID 12 yo"), Some((12, "yo")));
+        assert_eq!(parse_toid("TOID x yo"), None);
+        assert_eq!(parse_invite("#rust 600"), Some(InviteArgs { channel: Some("#rust".into()), once: false, ttl_secs: Some(600) }));
+        assert_eq!(parse_invite("ONCE"), Some(InviteArgs { once: true, ..InviteArgs::default() }));
+        assert_eq!(parse_invite("600 once"), None);
+        assert_eq!(parse_profile("set realname  Ada Lovelace"), Some(ProfileCommand::Set { field: "realname".into(), value: Some("Ada Lovelace".into()) }));
+        assert_eq!(parse_profile("GET alice bob"), None);
+        assert_eq!(parse_profile("SET"), None);
+        assert_eq!(parse_dnd("ON allow:mentions"), Some(Dnd { on: true, friends: false, mentions: true }));
+        assert_eq!(parse_dnd("off"), Some(Dnd::default()));
+        assert_eq!(parse_dnd("allow:friends"), None);
+        let poll = parse_poll(r#"#rust  "Tabs or spaces?" "tabs"  "spaces""#).unwrap();
+        assert_eq!((poll.question.as_str(), poll.options.len(), poll.duration), ("Tabs or spaces?", 2, None));
+        assert_eq!(parse_poll(r#"#rust for 1h "q" "a" "b""#).unwrap().duration.as_deref(), Some("1h"));
+        assert_eq!(parse_poll(r#"#rust "unclosed"#), None);
+        assert_eq!(parse_poll(r#"#rust bare "a""#), None);
+        assert_eq!(parse_poll(r#"rust "q" "a""#), None);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert_eq!(Command::parse(b"TO bob \xff", Wire::Text), Command::BadEncoding);
+    }
+
+    #[test]
+    fn detects_wire() {
+        assert_eq!(Wire::detect(b"NICK bob", false), Wire::Text);
+        assert_eq!(Wire::detect(b"{\"type\":\"nick\"}", false), Wire::Json);
+        assert_eq!(Wire::detect(b"HELLO 2", true), Wire::Text);
+        assert_eq!(Wire::detect(&[0x81, 0xa4], true), Wire::MsgPack);
+        assert_eq!(Wire::detect(&[0x0a, 0x05], true), Wire::Protobuf);
+    }
+}
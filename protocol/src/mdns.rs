@@ -0,0 +1,291 @@
+//! DNS-SD over multicast DNS (RFC 6762, RFC 6763), just enough for servers to
+//! announce themselves on the LAN and for `client discover` to find them: PTR
+//! queries for `_rustchat._tcp.local`, answered with PTR, SRV, TXT and A
+//! records. Names are written uncompressed but compressed ones are read, since
+//! other responders on the network use them.
+
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const PORT: u16 = 5353;
+pub const SERVICE: [&str; 3] = ["_rustchat", "_tcp", "local"];
+
+const TTL: u32 = 120;
+/// RFC 6762 §6.7: answers to one-shot queries from other ports shouldn't be
+/// cached for long.
+const LEGACY_TTL: u32 = 10;
+
+const A: u16 = 1;
+const PTR: u16 = 12;
+const TXT: u16 = 16;
+const SRV: u16 = 33;
+const ANY: u16 = 255;
+const IN: u16 = 1;
+/// In a question's class: the asker wants a unicast reply. In a record's:
+/// this record replaces any cached ones with the same name and type.
+const TOP_BIT: u16 = 0x8000;
+const RESPONSE: u16 = 0x8400;
+
+/// One server as it announces itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// The instance name shown to users, e.g. "rustchat on den".
+    pub name: String,
+    /// The host's name, without `.local`.
+    pub host: String,
+    pub addr: Ipv4Addr,
+    pub port: u16,
+}
+
+/// A query for our service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Query {
+    pub id: u16,
+    /// The asker set the unicast-response bit.
+    pub unicast: bool,
+}
+
+impl Service {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::from((self.addr, self.port))
+    }
+
+    /// The PTR, SRV, TXT and A records describing this server. `legacy` is for
+    /// answering a one-shot query sent from a port other than 5353, which
+    /// gets its question and ID echoed back and short-lived records.
+    pub fn response(&self, id: u16, legacy: bool) -> Vec<u8> {
+        let instance: Vec<&str> = [self.name.as_str()].into_iter().chain(SERVICE).collect();
+        let host = [self.host.as_str(), "local"];
+        let (ttl, unique) = if legacy { (LEGACY_TTL, IN) } else { (TTL, IN | TOP_BIT) };
+
+        let mut out = Vec::with_capacity(256);
+        header(&mut out, if legacy { id } else { 0 }, RESPONSE, legacy as u16, 4);
+        if legacy {
+            question(&mut out, &SERVICE, PTR, IN);
+        }
+
+        let mut rdata = Vec::new();
+        name(&mut rdata, &instance);
+        record(&mut out, &SERVICE, PTR, IN, ttl, &rdata);
+
+        rdata.clear();
+        rdata.extend_from_slice(&[0, 0, 0, 0]);
+        rdata.extend_from_slice(&self.port.to_be_bytes());
+        name(&mut rdata, &host);
+        record(&mut out, &instance, SRV, unique, ttl, &rdata);
+
+        let version = format!("v={}", crate::PROTOCOL_VERSION);
+        rdata.clear();
+        rdata.push(version.len() as u8);
+        rdata.extend_from_slice(version.as_bytes());
+        record(&mut out, &instance, TXT, unique, ttl, &rdata);
+
+        record(&mut out, &host, A, unique, ttl, &self.addr.octets());
+        out
+    }
+}
+
+/// A query asking who offers the service, with the unicast-response bit set
+/// so replies come straight back to the asker's port.
+pub fn query(id: u16) -> Vec<u8> {
+    let mut out = Vec::with_capacity(40);
+    header(&mut out, id, 0, 1, 0);
+    question(&mut out, &SERVICE, PTR, IN | TOP_BIT);
+    out
+}
+
+/// The query in `packet`, if it's one that asks for our service.
+pub fn parse_query(packet: &[u8]) -> Option<Query> {
+    let mut r = Reader { buf: packet, pos: 0 };
+    let (id, flags, questions) = (r.u16()?, r.u16()?, r.u16()?);
+    r.pos += 6;
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let mut unicast = None;
+    for _ in 0..questions {
+        let owner = r.name()?;
+        let (kind, class) = (r.u16()?, r.u16()?);
+        if matches!(kind, PTR | ANY) && same_name(&owner, &SERVICE) {
+            unicast = Some(unicast.unwrap_or(false) || class & TOP_BIT != 0);
+        }
+    }
+    Some(Query { id, unicast: unicast? })
+}
+
+/// The complete services described in a response: a PTR record for our
+/// service type plus the SRV and A records it leads to. Others are ignored.
+pub fn parse_response(packet: &[u8]) -> Vec<Service> {
+    parse_records(packet).unwrap_or_default()
+}
+
+fn parse_records(packet: &[u8]) -> Option<Vec<Service>> {
+    let mut r = Reader { buf: packet, pos: 0 };
+    let _id = r.u16()?;
+    if r.u16()? & 0x8000 == 0 {
+        return None;
+    }
+    let questions = r.u16()?;
+    let records = u32::from(r.u16()?) + u32::from(r.u16()?) + u32::from(r.u16()?);
+    for _ in 0..questions {
+        r.name()?;
+        r.pos += 4;
+    }
+
+    let mut instances = Vec::new();
+    let mut srv: BTreeMap<Vec<String>, (u16, Vec<String>)> = BTreeMap::new();
+    let mut a: BTreeMap<Vec<String>, Ipv4Addr> = BTreeMap::new();
+    for _ in 0..records {
+        let owner = r.name()?;
+        let (kind, _class, _ttl, len) = (r.u16()?, r.u16()?, r.u32()?, usize::from(r.u16()?));
+        let end = r.pos.checked_add(len).filter(|&end| end <= packet.len())?;
+        match kind {
+            PTR if same_name(&owner, &SERVICE) => instances.push(r.name()?),
+            SRV if len >= 6 => {
+                r.pos += 4;
+                let port = r.u16()?;
+                srv.insert(lowercase(&owner), (port, r.name()?));
+            }
+            A if len == 4 => {
+                a.insert(lowercase(&owner), Ipv4Addr::new(packet[r.pos], packet[r.pos + 1], packet[r.pos + 2], packet[r.pos + 3]));
+            }
+            _ => {}
+        }
+        r.pos = end;
+    }
+
+    Some(
+        instances
+            .into_iter()
+            .filter_map(|instance| {
+                let (port, target) = srv.get(&lowercase(&instance))?;
+                let addr = *a.get(&lowercase(target))?;
+                Some(Service { name: instance.first()?.clone(), host: target.first()?.clone(), addr, port: *port })
+            })
+            .collect(),
+    )
+}
+
+fn header(out: &mut Vec<u8>, id: u16, flags: u16, questions: u16, answers: u16) {
+    for n in [id, flags, questions, answers, 0, 0] {
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn question(out: &mut Vec<u8>, owner: &[&str], kind: u16, class: u16) {
+    name(out, owner);
+    out.extend_from_slice(&kind.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+}
+
+fn record(out: &mut Vec<u8>, owner: &[&str], kind: u16, class: u16, ttl: u32, rdata: &[u8]) {
+    question(out, owner, kind, class);
+    out.extend_from_slice(&ttl.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+}
+
+/// Labels longer than DNS allows are cut short rather than rejected.
+fn name(out: &mut Vec<u8>, labels: &[&str]) {
+    for label in labels {
+        let mut end = label.len().min(63);
+        while !label.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.push(end as u8);
+        out.extend_from_slice(&label.as_bytes()[..end]);
+    }
+    out.push(0);
+}
+
+fn same_name(labels: &[String], expected: &[&str]) -> bool {
+    labels.len() == expected.len() && labels.iter().zip(expected).all(|(l, e)| l.eq_ignore_ascii_case(e))
+}
+
+fn lowercase(labels: &[String]) -> Vec<String> {
+    labels.iter().map(|l| l.to_ascii_lowercase()).collect()
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.buf.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from(self.u16()?) << 16 | u32::from(self.u16()?))
+    }
+
+    /// A name, following compression pointers. Pointers may only go
+    /// backwards, which also rules out loops.
+    fn name(&mut self) -> Option<Vec<String>> {
+        let mut labels = Vec::new();
+        let mut at = self.pos;
+        let mut resume = None;
+        loop {
+            let len = *self.buf.get(at)?;
+            match len {
+                0 => {
+                    self.pos = resume.unwrap_or(at + 1);
+                    return Some(labels);
+                }
+                1..=63 => {
+                    let label = self.buf.get(at + 1..at + 1 + usize::from(len))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    at += 1 + usize::from(len);
+                }
+                0xc0.. => {
+                    let target = usize::from(u16::from_be_bytes([len, *self.buf.get(at + 1)?]) & 0x3fff);
+                    if target >= at {
+                        return None;
+                    }
+                    resume.get_or_insert(at + 2);
+                    at = target;
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn den() -> Service {
+        Service { name: "rustchat on den".into(), host: "den".into(), addr: Ipv4Addr::new(192, 168, 1, 10), port: 5555 }
+    }
+
+    #[test]
+    fn answers_queries_for_the_service() {
+        assert_eq!(parse_query(&query(7)), Some(Query { id: 7, unicast: true }));
+        assert_eq!(parse_query(&den().response(7, true)), None);
+        assert_eq!(parse_response(&den().response(7, true)), vec![den()]);
+        assert_eq!(parse_response(&den().response(0, false)), vec![den()]);
+        assert!(parse_response(&query(7)).is_empty());
+    }
+
+    #[test]
+    fn reads_compressed_names() {
+        // A PTR answer whose target points back at the question's name.
+        let mut packet = Vec::new();
+        header(&mut packet, 0, RESPONSE, 1, 1);
+        question(&mut packet, &SERVICE, PTR, IN);
+        packet.extend_from_slice(&[0xc0, 12]);
+        packet.extend_from_slice(&[0, PTR as u8, 0, 1, 0, 0, 0, 120, 0, 6, 3, b'a', b'b', b'c', 0xc0, 12]);
+        let mut r = Reader { buf: &packet, pos: packet.len() - 6 };
+        assert_eq!(r.name().unwrap(), ["abc", "_rustchat", "_tcp", "local"]);
+        assert_eq!(r.pos, packet.len());
+        // A pointer to itself.
+        assert_eq!(Reader { buf: &[0xc0, 0], pos: 0 }.name(), None);
+    }
+}
@@ -0,0 +1,240 @@
+//! MessagePack for the same flat objects the JSON mode uses. Decoding yields a
+//! `json::Object` so both modes share one command parser.
+
+use crate::json::{FieldWriter, Object, Value};
+
+pub struct MapWriter {
+    len: usize,
+    body: Vec<u8>,
+}
+
+impl FieldWriter for MapWriter {
+    fn new(kind: &str) -> Self {
+        MapWriter { len: 0, body: Vec::new() }.str("type", kind)
+    }
+
+    fn str(mut self, key: &str, val: &str) -> Self {
+        put_str(&mut self.body, key);
+        put_str(&mut self.body, val);
+        self.len += 1;
+        self
+    }
+
+    fn num(mut self, key: &str, val: u64) -> Self {
+        put_str(&mut self.body, key);
+        put_uint(&mut self.body, val);
+        self.len += 1;
+        self
+    }
+
+    fn bool(mut self, key: &str, val: bool) -> Self {
+        put_str(&mut self.body, key);
+        self.body.push(if val { 0xc3 } else { 0xc2 });
+        self.len += 1;
+        self
+    }
+
+    /// MessagePack has no use for embedded JSON, so it travels as a string.
+    fn json(self, key: &str, val: &str) -> Self {
+        self.str(key, val)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.body.len() + 5);
+        match self.len {
+            n if n < 16 => out.push(0x80 | n as u8),
+            n if n <= 0xffff => {
+                out.push(0xde);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                out.push(0xdf);
+                out.extend_from_slice(&(n as u32).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    let n = s.len();
+    if n < 32 {
+        buf.push(0xa0 | n as u8);
+    } else if n <= 0xff {
+        buf.extend_from_slice(&[0xd9, n as u8]);
+    } else if n <= 0xffff {
+        buf.push(0xda);
+        buf.extend_from_slice(&(n as u16).to_be_bytes());
+    } else {
+        buf.push(0xdb);
+        buf.extend_from_slice(&(n as u32).to_be_bytes());
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_uint(buf: &mut Vec<u8>, v: u64) {
+    if v < 0x80 {
+        buf.push(v as u8);
+    } else if v <= 0xff {
+        buf.extend_from_slice(&[0xcc, v as u8]);
+    } else if v <= 0xffff {
+        buf.push(0xcd);
+        buf.extend_from_slice(&(v as u16).to_be_bytes());
+    } else if v <= 0xffff_ffff {
+        buf.push(0xce);
+        buf.extend_from_slice(&(v as u32).to_be_bytes());
+    } else {
+        buf.push(0xcf);
+        buf.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+/// True if `b` can start a MessagePack map.
+pub fn is_map_start(b: u8) -> bool {
+    matches!(b, 0x80..=0x8f | 0xde | 0xdf)
+}
+
+pub fn parse_map(buf: &[u8]) -> Option<Object> {
+    let mut r = Reader { buf };
+    let len = match r.u8()? {
+        b @ 0x80..=0x8f => usize::from(b & 0x0f),
+        0xde => usize::from(r.be::<2>().map(u16::from_be_bytes)?),
+        0xdf => usize::try_from(r.be::<4>().map(u32::from_be_bytes)?).ok()?,
+        _ => return None,
+    };
+    let mut obj = Object::new();
+    for _ in 0..len {
+        let Value::Str(key) = r.value()? else {
+            return None;
+        };
+        let val = r.value()?;
+        obj.insert(key, val);
+    }
+    r.buf.is_empty().then_some(obj)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let (&b, rest) = self.buf.split_first()?;
+        self.buf = rest;
+        Some(b)
+    }
+
+    fn be<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let head = self.buf.get(..N)?.try_into().ok()?;
+        self.buf = &self.buf[N..];
+        Some(head)
+    }
+
+    fn string(&mut self, len: usize) -> Option<Value> {
+        let s = self.buf.get(..len)?;
+        self.buf = &self.buf[len..];
+        String::from_utf8(s.to_vec()).ok().map(Value::Str)
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        // Like the JSON parser, no NaN or infinity.
+        let num = |n: f64| n.is_finite().then_some(Value::Num(n));
+        match self.u8()? {
+            b @ 0x00..=0x7f => num(f64::from(b)),
+            b @ 0xe0..=0xff => num(f64::from(b as i8)),
+            b @ 0xa0..=0xbf => self.string(usize::from(b & 0x1f)),
+            0xc0 => Some(Value::Null),
+            0xc2 => Some(Value::Bool(false)),
+            0xc3 => Some(Value::Bool(true)),
+            0xca => num(f64::from(f32::from_be_bytes(self.be()?))),
+            0xcb => num(f64::from_be_bytes(self.be()?)),
+            0xcc => num(f64::from(self.u8()?)),
+            0xcd => num(f64::from(u16::from_be_bytes(self.be()?))),
+            0xce => num(f64::from(u32::from_be_bytes(self.be()?))),
+            0xcf => num(u64::from_be_bytes(self.be()?) as f64),
+            0xd0 => num(f64::from(self.u8()? as i8)),
+            0xd1 => num(f64::from(i16::from_be_bytes(self.be()?))),
+            0xd2 => num(f64::from(i32::from_be_bytes(self.be()?))),
+            0xd3 => num(i64::from_be_bytes(self.be()?) as f64),
+            0xd9 => {
+                let len = usize::from(self.u8()?);
+                self.string(len)
+            }
+            0xda => {
+                let len = usize::from(u16::from_be_bytes(self.be()?));
+                self.string(len)
+            }
+            0xdb => {
+                let len = usize::try_from(u32::from_be_bytes(self.be()?)).ok()?;
+                self.string(len)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{get_str, get_u64};
+
+    fn map() -> Vec<u8> {
+        MapWriter::new("dm").str("body", &"x".repeat(300)).num("id", u64::from(u32::MAX) + 1).bool("on", true).finish()
+    }
+
+    #[test]
+    fn round_trips() {
+        let obj = parse_map(&map()).unwrap();
+        assert_eq!(get_str(&obj, "type"), Some("dm"));
+        assert_eq!(get_str(&obj, "body").map(str::len), Some(300));
+        assert_eq!(get_u64(&obj, "id"), Some(u64::from(u32::MAX) + 1));
+        assert_eq!(obj["on"], Value::Bool(true));
+        // Negative fixints and signed ints.
+        assert_eq!(parse_map(&[0x82, 0xa1, b'a', 0xff, 0xa1, b'b', 0xd1, 0xfc, 0x18]).unwrap(), Object::from([("a".into(), Value::Num(-1.0)), ("b".into(), Value::Num(-1000.0))]));
+    }
+
+    #[test]
+    fn refuses_truncated_input() {
+        let buf = map();
+        for end in 0..buf.len() {
+            assert_eq!(parse_map(&buf[..end]), None, "cut at {end}");
+        }
+        // Each multi-byte header cut short.
+        for head in [&[0xde, 0x00][..], &[0xdf, 0x00, 0x00, 0x00], &[0x81, 0xd9], &[0x81, 0xda, 0x00], &[0x81, 0xdb, 0x00, 0x00, 0x00], &[0x81, 0xa1, b'a', 0xcf, 0, 0, 0, 0, 0, 0, 0], &[0x81, 0xa1, b'a', 0xcb, 0, 0]] {
+            assert_eq!(parse_map(head), None, "{head:x?}");
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_lengths() {
+        // Four billion entries promised, one present.
+        assert_eq!(parse_map(&[0xdf, 0xff, 0xff, 0xff, 0xff, 0xa1, b'a', 0x01]), None);
+        assert_eq!(parse_map(&[0xde, 0xff, 0xff, 0xa1, b'a', 0x01]), None);
+        // Strings longer than what's left.
+        assert_eq!(parse_map(&[0x81, 0xa1, b'a', 0xdb, 0xff, 0xff, 0xff, 0xff, b'x']), None);
+        assert_eq!(parse_map(&[0x81, 0xa1, b'a', 0xda, 0x01, 0x00, b'x']), None);
+        assert_eq!(parse_map(&[0x81, 0xa1, b'a', 0xd9, 0x02, b'x']), None);
+        assert_eq!(parse_map(&[0x81, 0xa5, b'a']), None);
+    }
+
+    #[test]
+    fn refuses_other_shapes() {
+        for bad in [
+            // Not a map, or a map with a non-string key.
+            &[0x91, 0x01][..],
+            &[0x81, 0x01, 0x01],
+            // Arrays, binaries and extensions aren't understood as values.
+            &[0x81, 0xa1, b'a', 0x90],
+            &[0x81, 0xa1, b'a', 0xc4, 0x00],
+            &[0x81, 0xa1, b'a', 0xd4, 0x00, 0x00],
+            // Invalid UTF-8, NaN, trailing bytes.
+            &[0x81, 0xa1, b'a', 0xa1, 0xff],
+            &[0x81, 0xa1, b'a', 0xca, 0x7f, 0xc0, 0x00, 0x00],
+            &[0x81, 0xa1, b'a', 0xcb, 0x7f, 0xf0, 0, 0, 0, 0, 0, 0],
+            &[0x80, 0x00],
+        ] {
+            assert_eq!(parse_map(bad), None, "{bad:x?}");
+        }
+    }
+}
@@ -0,0 +1,545 @@
+//! Hand-rolled encoding for the messages in `proto/chat.proto`. Field numbers
+//! here must stay in sync with that file.
+
+use crate::{caps::CapCommand, presence, AccessCommand, AvatarCommand, Command, Dnd, Event, ExportArgs, Receipt, HistoryArgs, FriendCommand, FriendStatus, InviteArgs, PollArgs, Presence, ProfileCommand, SearchArgs, SeqRange, TwoFactorCommand, VoiceCommand};
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_I64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+const WIRE_I32: u8 = 5;
+
+#[derive(Default)]
+struct MessageWriter {
+    buf: Vec<u8>,
+}
+
+impl MessageWriter {
+    fn tag(&mut self, field: u32, wire: u8) {
+        put_varint(&mut self.buf, u64::from(field << 3 | u32::from(wire)));
+    }
+
+    fn uint(mut self, field: u32, v: u64) -> Self {
+        if v != 0 {
+            self.tag(field, WIRE_VARINT);
+            put_varint(&mut self.buf, v);
+        }
+        self
+    }
+
+    /// `oneof` and `optional` members are written even when zero, so their
+    /// presence survives.
+    fn oneof_uint(mut self, field: u32, v: u64) -> Self {
+        self.tag(field, WIRE_VARINT);
+        put_varint(&mut self.buf, v);
+        self
+    }
+
+    fn opt_uint(self, field: u32, v: Option<u64>) -> Self {
+        match v {
+            Some(v) => self.oneof_uint(field, v),
+            None => self,
+        }
+    }
+
+    fn bytes(mut self, field: u32, b: &[u8]) -> Self {
+        self.tag(field, WIRE_LEN);
+        put_varint(&mut self.buf, b.len() as u64);
+        self.buf.extend_from_slice(b);
+        self
+    }
+
+    fn string(self, field: u32, s: &str) -> Self {
+        if s.is_empty() {
+            return self;
+        }
+        self.bytes(field, s.as_bytes())
+    }
+
+    fn message(self, field: u32, m: MessageWriter) -> Self {
+        self.bytes(field, &m.buf)
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Splits a message into `(field number, value)` pairs; fixed-width fields
+/// are skipped since nothing in the schema uses them.
+fn fields(mut buf: &[u8]) -> Option<Vec<(u32, Field<'_>)>> {
+    let mut out = Vec::new();
+    while !buf.is_empty() {
+        let tag = take_varint(&mut buf)?;
+        let field = u32::try_from(tag >> 3).ok().filter(|&f| f != 0)?;
+        match (tag & 7) as u8 {
+            WIRE_VARINT => out.push((field, Field::Varint(take_varint(&mut buf)?))),
+            WIRE_LEN => {
+                let len = usize::try_from(take_varint(&mut buf)?).ok()?;
+                let (val, rest) = (buf.get(..len)?, &buf[len..]);
+                out.push((field, Field::Bytes(val)));
+                buf = rest;
+            }
+            WIRE_I64 => buf = buf.get(8..)?,
+            WIRE_I32 => buf = buf.get(4..)?,
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+fn take_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        // The tenth byte only has room for the top bit.
+        if shift == 63 && b > 1 {
+            return None;
+        }
+        v |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+struct Decoded<'a>(Vec<(u32, Field<'a>)>);
+
+impl<'a> Decoded<'a> {
+    fn parse(buf: &'a [u8]) -> Option<Self> {
+        fields(buf).map(Decoded)
+    }
+
+    // Last occurrence wins, as in the protobuf spec.
+    fn bytes(&self, field: u32) -> Option<&'a [u8]> {
+        self.0.iter().rev().find_map(|(f, v)| match v {
+            Field::Bytes(b) if *f == field => Some(*b),
+            _ => None,
+        })
+    }
+
+    fn string(&self, field: u32) -> Option<String> {
+        self.bytes(field).and_then(|b| String::from_utf8(b.to_vec()).ok())
+    }
+
+    /// Every occurrence of a repeated string field, in order.
+    fn strings(&self, field: u32) -> Vec<String> {
+        self.0.iter().filter_map(|(f, v)| match v {
+            Field::Bytes(b) if *f == field => String::from_utf8(b.to_vec()).ok(),
+            _ => None,
+        }).collect()
+    }
+
+    fn uint(&self, field: u32) -> Option<u64> {
+        self.0.iter().rev().find_map(|(f, v)| match v {
+            Field::Varint(n) if *f == field => Some(*n),
+            _ => None,
+        })
+    }
+
+    /// The last set member of a `oneof` whose fields are all sub-messages.
+    fn oneof(&self) -> Option<(u32, &'a [u8])> {
+        self.0.iter().rev().find_map(|(f, v)| match v {
+            Field::Bytes(b) => Some((*f, *b)),
+            _ => None,
+        })
+    }
+}
+
+pub fn encode_event(event: &Event) -> Vec<u8> {
+    let m = MessageWriter::default;
+    let (field, inner) = match event {
+        Event::Welcome { id, name } => (1, m().uint(1, *id).string(2, name)),
+        Event::Notice(text) => (2, m().string(1, text)),
+        Event::Dm { id, seq, ts, from, from_id, body } => (
+            3,
+            m().string(1, from).uint(2, *from_id).string(3, body).uint(4, *id).string(5, ts).uint(6, *seq),
+        ),
+        Event::Export(dump) => (4, m().string(1, dump)),
+        Event::Error(text) => (5, m().string(1, text)),
+        Event::Hello { version } => (6, m().uint(1, u64::from(*version))),
+        Event::Cap { sub, caps } => (7, m().string(1, sub).string(2, caps)),
+        Event::ChannelMsg { channel, id, ts, from, from_id, body } => (
+            8,
+            m().string(1, channel).string(2, from).uint(3, *from_id).string(4, body).uint(5, *id).string(6, ts),
+        ),
+        Event::Key { from, from_id, key } => (9, m().string(1, from).uint(2, *from_id).string(3, key)),
+        Event::Pong(token) => (10, m().string(1, token)),
+        Event::Avatar { name, image } => (11, m().string(1, name).string(2, image)),
+        Event::Presence { name, presence } => (12, {
+            let inner = m().string(1, name).string(2, presence.label());
+            match presence {
+                Presence::Away(message) => inner.string(3, message),
+                _ => inner,
+            }
+        }),
+        Event::Friend { name, status } => (13, m().string(1, name).string(2, status.label())),
+        Event::Receipt { id, name, state } => (14, m().uint(1, *id).string(2, name).string(3, state.label())),
+        Event::Ephemeral { name, on } => (15, m().string(1, name).uint(2, u64::from(*on))),
+        Event::Preview { id, url, title } => (16, m().uint(1, *id).string(2, url).string(3, title)),
+        Event::Attachment { id, url, size, name } => (17, m().uint(1, *id).string(2, url).uint(3, *size).string(4, name)),
+        Event::Voice { channel, name, joined } => (18, m().string(1, channel).string(2, name).uint(3, u64::from(*joined))),
+        Event::VoiceToken { channel, port, token } => (19, m().string(1, channel).uint(2, u64::from(*port)).string(3, token)),
+        Event::Challenge { nonce, bits } => (20, m().string(1, nonce).uint(2, u64::from(*bits))),
+        Event::Verified { id, key } => (21, m().uint(1, *id).string(2, key)),
+        Event::Reconnect(addr) => (22, m().string(1, addr.as_deref().unwrap_or_default())),
+        Event::ExportPart { part, parts, data } => (23, m().uint(1, u64::from(*part)).uint(2, u64::from(*parts)).string(3, data)),
+    };
+    m().message(field, inner).buf
+}
+
+pub fn decode_event(buf: &[u8]) -> Option<Event> {
+    let (kind, inner) = Decoded::parse(buf)?.oneof()?;
+    let m = Decoded::parse(inner)?;
+    let s = |field| m.string(field).unwrap_or_default();
+    let event = match kind {
+        1 => Event::Welcome { id: m.uint(1).unwrap_or(0), name: s(2) },
+        2 => Event::Notice(s(1)),
+        3 => Event::Dm { id: m.uint(4).unwrap_or(0), seq: m.uint(6).unwrap_or(0), ts: s(5), from: s(1), from_id: m.uint(2).unwrap_or(0), body: s(3) },
+        4 => Event::Export(s(1)),
+        5 => Event::Error(s(1)),
+        6 => Event::Hello { version: u32::try_from(m.uint(1).unwrap_or(0)).ok()? },
+        7 => Event::Cap { sub: s(1), caps: s(2) },
+        8 => Event::ChannelMsg {
+            channel: s(1),
+            id: m.uint(5).unwrap_or(0),
+            ts: s(6),
+            from: s(2),
+            from_id: m.uint(3).unwrap_or(0),
+            body: s(4),
+        },
+        9 => Event::Key { from: s(1), from_id: m.uint(2).unwrap_or(0), key: s(3) },
+        10 => Event::Pong(s(1)),
+        11 => Event::Avatar { name: s(1), image: s(2) },
+        12 => Event::Presence { name: s(1), presence: presence(&s(2), m.string(3))? },
+        13 => Event::Friend { name: s(1), status: FriendStatus::from_label(&s(2))? },
+        14 => Event::Receipt { id: m.uint(1)?, name: s(2), state: Receipt::from_label(&s(3))? },
+        15 => Event::Ephemeral { name: m.string(1)?, on: m.uint(2).is_some_and(|v| v != 0) },
+        16 => Event::Preview { id: m.uint(1)?, url: m.string(2)?, title: m.string(3)? },
+        17 => Event::Attachment { id: m.uint(1)?, url: m.string(2)?, size: m.uint(3).unwrap_or(0), name: m.string(4)? },
+        18 => Event::Voice { channel: m.string(1)?, name: m.string(2)?, joined: m.uint(3).is_some_and(|v| v != 0) },
+        19 => Event::VoiceToken { channel: m.string(1)?, port: u16::try_from(m.uint(2)?).ok()?, token: m.string(3)? },
+        20 => Event::Challenge { nonce: m.string(1)?, bits: u32::try_from(m.uint(2).unwrap_or(0)).ok()? },
+        21 => Event::Verified { id: m.uint(1)?, key: m.string(2)? },
+        22 => Event::Reconnect(m.string(1).filter(|a| !a.is_empty())),
+        23 => Event::ExportPart { part: u32::try_from(m.uint(1)?).ok()?, parts: u32::try_from(m.uint(2)?).ok()?, data: s(3) },
+        _ => return None,
+    };
+    Some(event)
+}
+
+pub fn encode_command(cmd: &Command) -> Vec<u8> {
+    let m = MessageWriter::default;
+    let (field, inner) = match cmd {
+        Command::Nick(name) => (1, m().string(1, name)),
+        Command::To { name, body } => (2, m().string(1, name).string(3, body)),
+        Command::ToId { id, body } => (2, m().oneof_uint(2, *id).string(3, body)),
+        Command::Kick(name) => (3, m().string(1, name)),
+        Command::KickId(id) => (3, id.map_or_else(m, |id| m().oneof_uint(2, id))),
+        Command::Export(args) => (4, match args {
+            Some(a) => m()
+                .string(1, &a.name)
+                .string(2, &a.format)
+                .string(3, a.since.as_deref().unwrap_or_default())
+                .string(4, a.until.as_deref().unwrap_or_default()),
+            None => m(),
+        }),
+        Command::ExportMe => (5, m()),
+        Command::Purge(name) => (6, m().string(1, name)),
+        Command::Hello(version) => (7, m().uint(1, u64::from(*version))),
+        Command::Cap(cap) => (8, match cap {
+            CapCommand::Ls => m().string(1, "LS"),
+            CapCommand::Req(caps) => m().string(1, "REQ").string(2, caps),
+            CapCommand::End => m().string(1, "END"),
+        }),
+        Command::Resend(range) => (9, match range {
+            Some(SeqRange { from, to: Some(to) }) => m().oneof_uint(1, *from).oneof_uint(2, *to),
+            Some(SeqRange { from, to: None }) => m().oneof_uint(1, *from),
+            None => m(),
+        }),
+        Command::Join { channel, key } => (10, m().string(1, channel).string(2, key.as_deref().unwrap_or_default())),
+        Command::Part(channel) => (11, m().string(1, channel)),
+        Command::Say { channel, body } => (12, m().string(1, channel).string(2, body)),
+        Command::Key { name, key } => (13, m().string(1, name).string(2, key)),
+        Command::Away(message) => (14, m().string(1, message.as_deref().unwrap_or_default())),
+        Command::Ping(token) => (15, m().string(1, token)),
+        Command::Quit(message) => (16, m().string(1, message.as_deref().unwrap_or_default())),
+        Command::Invite(a) => (17, {
+            let inner = m().string(1, a.channel.as_deref().unwrap_or_default()).uint(2, u64::from(a.once));
+            match a.ttl_secs {
+                Some(secs) => inner.oneof_uint(3, secs),
+                None => inner,
+            }
+        }),
+        Command::Token(token) => (18, m().string(1, token)),
+        Command::Topic { channel, topic } => (19, m().string(1, channel).string(2, topic.as_deref().unwrap_or_default())),
+        Command::History(a) => (20, m().string(1, &a.target).opt_uint(2, a.limit).opt_uint(3, a.before)),
+        Command::Retention { channel, policy } => (21, m().string(1, channel).string(2, policy.as_deref().unwrap_or_default())),
+        Command::Search(a) => (22, {
+            let inner = m().string(1, &a.query).string(2, a.channel.as_deref().unwrap_or_default()).string(3, a.from.as_deref().unwrap_or_default());
+            inner.opt_uint(4, a.limit)
+        }),
+        Command::Profile(ProfileCommand::Set { field, value }) => (23, m().string(1, "SET").string(3, field).string(4, value.as_deref().unwrap_or_default())),
+        Command::Profile(ProfileCommand::Get(name)) => (23, m().string(1, "GET").string(2, name)),
+        Command::Whois(name) => (24, m().string(1, name)),
+        Command::Avatar(AvatarCommand::Set(image)) => (25, m().string(1, "SET").string(3, image.as_deref().unwrap_or_default())),
+        Command::Avatar(AvatarCommand::Get(name)) => (25, m().string(1, "GET").string(2, name)),
+        Command::Watch(name) => (26, m().string(1, name.as_deref().unwrap_or_default())),
+        Command::Unwatch(name) => (27, m().string(1, name)),
+        Command::Friend(FriendCommand::Add(name)) => (28, m().string(1, "ADD").string(2, name)),
+        Command::Friend(FriendCommand::Remove(name)) => (28, m().string(1, "REMOVE").string(2, name)),
+        Command::Friend(FriendCommand::List) => (28, m().string(1, "LIST")),
+        Command::Block(name) => (29, m().string(1, name.as_deref().unwrap_or_default())),
+        Command::Unblock(name) => (30, m().string(1, name)),
+        Command::Read(id) => (32, m().uint(1, *id)),
+        Command::Push(url) => (33, m().string(1, url.as_deref().unwrap_or_default())),
+        Command::Ephemeral { name, on } => (34, m().string(1, name).uint(2, u64::from(*on))),
+        Command::Schedule { at, name, body } => (35, m().string(1, at).string(2, name).string(3, body)),
+        Command::Schedules => (36, m()),
+        Command::Unschedule(id) => (37, m().uint(1, *id)),
+        Command::Remind { name, delay, text } => (38, m().string(1, name.as_deref().unwrap_or_default()).string(2, delay).string(3, text)),
+        Command::Poll(a) => (39, {
+            let inner = m().string(1, &a.channel).string(2, a.duration.as_deref().unwrap_or_default()).string(3, &a.question);
+            a.options.iter().fold(inner, |inner, option| inner.string(4, option))
+        }),
+        Command::Vote { poll, choice } => (40, m().uint(1, *poll).uint(2, *choice)),
+        Command::Upload => (41, m()),
+        Command::Voice(VoiceCommand::Join(channel)) => (42, m().string(1, "JOIN").string(2, channel)),
+        Command::Voice(VoiceCommand::Leave) => (42, m().string(1, "LEAVE")),
+        Command::Pass(password) => (43, m().string(1, password)),
+        Command::Register(password) => (44, m().string(1, password)),
+        Command::TwoFactor(TwoFactorCommand::Enable) => (45, m().string(1, "ENABLE")),
+        Command::TwoFactor(TwoFactorCommand::Disable(code)) => (45, m().string(1, "DISABLE").string(2, code)),
+        Command::TwoFactor(TwoFactorCommand::Code(code)) => (45, m().string(1, "CODE").string(2, code)),
+        Command::Unlock(target) => (46, m().string(1, target.as_deref().unwrap_or_default())),
+        Command::Access(AccessCommand::List) => (47, m().string(1, "LIST")),
+        Command::Access(AccessCommand::Allow(cidr)) => (47, m().string(1, "ALLOW").string(2, cidr)),
+        Command::Access(AccessCommand::Deny(cidr)) => (47, m().string(1, "DENY").string(2, cidr)),
+        Command::Proof(n) => (48, m().uint(1, *n)),
+        Command::SignKey(key) => (49, m().string(1, key.as_deref().unwrap_or_default())),
+        Command::Signed { target, signature, body } => (50, m().string(1, target).string(2, signature).string(3, body)),
+        Command::Connections => (51, m()),
+        Command::Drain { secs, to } => (52, m().uint(1, secs.unwrap_or(0)).string(2, to.as_deref().unwrap_or_default())),
+        Command::Dnd(d) => (31, m().uint(1, u64::from(d.on)).uint(2, u64::from(d.friends)).uint(3, u64::from(d.mentions))),
+        Command::BadEncoding | Command::Unknown => return Vec::new(),
+    };
+    m().message(field, inner).buf
+}
+
+pub fn decode_command(buf: &[u8]) -> Option<Command> {
+    let (kind, inner) = Decoded::parse(buf)?.oneof()?;
+    let m = Decoded::parse(inner)?;
+    let cmd = match kind {
+        1 => {
+            let name = m.string(1)?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            Command::Nick(name.to_string())
+        }
+        2 => {
+            let body = m.string(3).unwrap_or_default();
+            match m.string(1) {
+                Some(name) => Command::To { name, body },
+                None => Command::ToId { id: m.uint(2).unwrap_or(0), body },
+            }
+        }
+        3 => match m.string(1) {
+            Some(name) => Command::Kick(name),
+            None => Command::KickId(m.uint(2)),
+        },
+        4 => Command::Export(export_args(&m)),
+        5 => Command::ExportMe,
+        6 => Command::Purge(m.string(1).unwrap_or_default()),
+        7 => Command::Hello(u32::try_from(m.uint(1).unwrap_or(0)).ok()?),
+        8 => Command::Cap(match m.string(1)?.to_ascii_uppercase().as_str() {
+            "LS" => CapCommand::Ls,
+            "REQ" => CapCommand::Req(m.string(2).unwrap_or_default()),
+            "END" => CapCommand::End,
+            _ => return None,
+        }),
+        9 => Command::Resend(m.uint(1).map(|from| SeqRange { from, to: m.uint(2) })),
+        10 => Command::Join { channel: m.string(1).unwrap_or_default(), key: m.string(2).filter(|k| !k.is_empty()) },
+        11 => Command::Part(m.string(1).unwrap_or_default()),
+        12 => Command::Say { channel: m.string(1).unwrap_or_default(), body: m.string(2).unwrap_or_default() },
+        13 => Command::Key { name: m.string(1).unwrap_or_default(), key: m.string(2).unwrap_or_default() },
+        14 => Command::Away(m.string(1).filter(|m| !m.is_empty())),
+        15 => Command::Ping(m.string(1).unwrap_or_default()),
+        16 => Command::Quit(m.string(1).filter(|m| !m.is_empty())),
+        17 => Command::Invite(InviteArgs { channel: m.string(1).filter(|c| !c.is_empty()), once: m.uint(2).is_some_and(|v| v != 0), ttl_secs: m.uint(3) }),
+        18 => Command::Token(m.string(1).unwrap_or_default()),
+        19 => Command::Topic { channel: m.string(1).unwrap_or_default(), topic: m.string(2).filter(|t| !t.is_empty()) },
+        20 => Command::History(HistoryArgs { target: m.string(1).unwrap_or_default(), limit: m.uint(2), before: m.uint(3) }),
+        21 => Command::Retention { channel: m.string(1).unwrap_or_default(), policy: m.string(2).filter(|p| !p.is_empty()) },
+        22 => Command::Search(SearchArgs {
+            query: m.string(1).filter(|q| !q.trim().is_empty())?,
+            channel: m.string(2).filter(|c| !c.is_empty()),
+            from: m.string(3).filter(|f| !f.is_empty()),
+            limit: m.uint(4),
+        }),
+        23 => Command::Profile(match m.string(1)?.to_ascii_uppercase().as_str() {
+            "SET" => ProfileCommand::Set { field: m.string(3)?, value: m.string(4).filter(|v| !v.is_empty()) },
+            "GET" => ProfileCommand::Get(m.string(2)?),
+            _ => return None,
+        }),
+        24 => Command::Whois(m.string(1)?),
+        26 => Command::Watch(m.string(1).filter(|n| !n.is_empty())),
+        27 => Command::Unwatch(m.string(1)?),
+        29 => Command::Block(m.string(1).filter(|n| !n.is_empty())),
+        30 => Command::Unblock(m.string(1)?),
+        32 => Command::Read(m.uint(1)?),
+        33 => Command::Push(m.string(1).filter(|u| !u.is_empty())),
+        34 => Command::Ephemeral { name: m.string(1)?, on: m.uint(2).is_some_and(|v| v != 0) },
+        35 => Command::Schedule { at: m.string(1)?, name: m.string(2)?, body: m.string(3)? },
+        36 => Command::Schedules,
+        37 => Command::Unschedule(m.uint(1)?),
+        39 => Command::Poll(PollArgs {
+            channel: m.string(1)?,
+            duration: m.string(2).filter(|d| !d.is_empty()),
+            question: m.string(3)?,
+            options: m.strings(4),
+        }),
+        40 => Command::Vote { poll: m.uint(1)?, choice: m.uint(2)? },
+        41 => Command::Upload,
+        42 => Command::Voice(match m.string(1)?.to_ascii_uppercase().as_str() {
+            "JOIN" => VoiceCommand::Join(m.string(2)?),
+            "LEAVE" => VoiceCommand::Leave,
+            _ => return None,
+        }),
+        43 => Command::Pass(m.string(1)?),
+        44 => Command::Register(m.string(1)?),
+        45 => Command::TwoFactor(match m.string(1)?.to_ascii_uppercase().as_str() {
+            "ENABLE" => TwoFactorCommand::Enable,
+            "DISABLE" => TwoFactorCommand::Disable(m.string(2)?),
+            "CODE" => TwoFactorCommand::Code(m.string(2)?),
+            _ => return None,
+        }),
+        46 => Command::Unlock(m.string(1).filter(|t| !t.is_empty())),
+        47 => Command::Access(match m.string(1)?.to_ascii_uppercase().as_str() {
+            "LIST" => AccessCommand::List,
+            "ALLOW" => AccessCommand::Allow(m.string(2)?),
+            "DENY" => AccessCommand::Deny(m.string(2)?),
+            _ => return None,
+        }),
+        48 => Command::Proof(m.uint(1).unwrap_or(0)),
+        49 => Command::SignKey(m.string(1).filter(|k| !k.is_empty())),
+        50 => Command::Signed { target: m.string(1)?, signature: m.string(2)?, body: m.string(3).unwrap_or_default() },
+        51 => Command::Connections,
+        52 => Command::Drain { secs: m.uint(1).filter(|&s| s > 0), to: m.string(2).filter(|t| !t.is_empty()) },
+        38 => Command::Remind { name: m.string(1).filter(|n| !n.is_empty()), delay: m.string(2)?, text: m.string(3)? },
+        31 => Command::Dnd(Dnd { on: m.uint(1).is_some_and(|v| v != 0), friends: m.uint(2).is_some_and(|v| v != 0), mentions: m.uint(3).is_some_and(|v| v != 0) }),
+        28 => Command::Friend(match m.string(1)?.to_ascii_uppercase().as_str() {
+            "ADD" => FriendCommand::Add(m.string(2)?),
+            "REMOVE" => FriendCommand::Remove(m.string(2)?),
+            "LIST" => FriendCommand::List,
+            _ => return None,
+        }),
+        25 => Command::Avatar(match m.string(1)?.to_ascii_uppercase().as_str() {
+            "SET" => AvatarCommand::Set(m.string(3).filter(|i| !i.is_empty())),
+            "GET" => AvatarCommand::Get(m.string(2)?),
+            _ => return None,
+        }),
+        _ => return None,
+    };
+    Some(cmd)
+}
+
+fn export_args(m: &Decoded) -> Option<ExportArgs> {
+    // proto3 can't tell an empty string from an absent one.
+    let date = |field| m.string(field).filter(|d| !d.is_empty());
+    Some(ExportArgs {
+        name: m.string(1)?,
+        format: m.string(2)?,
+        since: date(3),
+        until: date(4),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_varints() {
+        for v in [0, 1, 127, 128, 300, u64::from(u32::MAX), u64::MAX] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, v);
+            let mut rest = &buf[..];
+            assert_eq!(take_varint(&mut rest), Some(v));
+            assert!(rest.is_empty());
+            // Every proper prefix runs out mid-varint.
+            for end in 0..buf.len() {
+                assert_eq!(take_varint(&mut &buf[..end]), None, "{v} cut at {end}");
+            }
+        }
+        // Eleven bytes, or a tenth byte carrying bits past 64.
+        assert_eq!(take_varint(&mut &[0xff; 10][..]), None);
+        assert_eq!(take_varint(&mut &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02][..]), None);
+    }
+
+    #[test]
+    fn refuses_truncated_messages() {
+        let buf = encode_event(&Event::Dm { id: 7, seq: 3, ts: "2024-01-01T00:00:00Z".into(), from: "alice".into(), from_id: 1, body: "hi".into() });
+        assert!(decode_event(&buf).is_some());
+        for end in 1..buf.len() {
+            assert_eq!(decode_event(&buf[..end]), None, "cut at {end}");
+        }
+    }
+
+    #[test]
+    fn refuses_lengths_past_the_end() {
+        // Field 2 (Notice), 5 bytes claimed, 2 present.
+        assert_eq!(decode_event(&[0x12, 0x05, 0x0a, 0x00]), None);
+        // Inside the notice: field 1, 3 bytes claimed, 1 present.
+        assert_eq!(decode_event(&[0x12, 0x03, 0x0a, 0x03, b'h']), None);
+        // A length that doesn't fit in usize on any target, or only just.
+        let mut huge = vec![0x12];
+        put_varint(&mut huge, u64::MAX);
+        assert_eq!(decode_event(&huge), None);
+        assert_eq!(decode_command(&huge), None);
+    }
+
+    #[test]
+    fn handles_wire_types() {
+        let notice = |extra: &[u8]| {
+            let mut inner = vec![0x0a, 0x02, b'h', b'i'];
+            inner.extend_from_slice(extra);
+            let mut buf = vec![0x12, inner.len() as u8];
+            buf.extend(inner);
+            decode_event(&buf)
+        };
+        let hi = Some(Event::Notice("hi".into()));
+        // Unknown fields of the fixed-width types are skipped...
+        assert_eq!(notice(&[0x11, 1, 2, 3, 4, 5, 6, 7, 8]), hi);
+        assert_eq!(notice(&[0x15, 1, 2, 3, 4]), hi);
+        assert_eq!(notice(&[0x18, 0x01]), hi);
+        // ...unless they run past the end.
+        assert_eq!(notice(&[0x11, 1, 2, 3]), None);
+        assert_eq!(notice(&[0x15, 1]), None);
+        // Groups (3, 4) and the unassigned 6 and 7 aren't accepted at all.
+        for wire in [3, 4, 6, 7] {
+            assert_eq!(notice(&[(2 << 3) | wire, 0]), None, "wire type {wire}");
+        }
+        // Neither is field number 0.
+        assert_eq!(notice(&[0x00, 0x01]), None);
+    }
+
+    #[test]
+    fn skips_unknown_messages() {
+        assert_eq!(decode_event(&[]), None);
+        // Field 1000, far past anything in the schema.
+        assert_eq!(decode_event(&[0xc2, 0x3e, 0x00]), None);
+        assert_eq!(decode_command(&[0xc2, 0x3e, 0x00]), None);
+    }
+}
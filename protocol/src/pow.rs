@@ -0,0 +1,106 @@
+//! The proof of work that registering a new nickname takes, to make
+//! creating accounts in bulk slow. The server answers `REGISTER` with a
+//! `Challenge`: a nonce and a number of bits. The client looks for a number
+//! `n` such that SHA-256 of `<nonce>:<name>:<n>`, `n` in decimal, starts
+//! with that many zero bits, and sends it back with `PROOF <n>`. That takes
+//! about `2^bits` hashes to find and one to check.
+
+/// The most bits a server may ask for; past this, finding an answer takes
+/// minutes.
+pub const MAX_BITS: u32 = 28;
+
+/// Whether `n` answers the challenge for `name`.
+pub fn verify(nonce: &str, name: &str, bits: u32, n: u64) -> bool {
+    bits <= MAX_BITS && zero_bits(&sha256(format!("{nonce}:{name}:{n}").as_bytes())) >= bits
+}
+
+/// The first number that answers the challenge for `name`.
+pub fn solve(nonce: &str, name: &str, bits: u32) -> u64 {
+    let prefix = format!("{nonce}:{name}:");
+    let mut input = prefix.clone().into_bytes();
+    (0..)
+        .find(|n: &u64| {
+            input.truncate(prefix.len());
+            input.extend_from_slice(n.to_string().as_bytes());
+            zero_bits(&sha256(&input)) >= bits
+        })
+        .unwrap()
+}
+
+fn zero_bits(hash: &[u8; 32]) -> u32 {
+    let mut bits = 0;
+    for &b in hash {
+        bits += b.leading_zeros();
+        if b != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for (&k, &word) in K.iter().zip(&w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(k).wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (hh, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0; 32];
+    for (bytes, word) in out.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_like_sha256() {
+        let hex = |bytes: [u8; 32]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(hex(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(sha256(&[b'a'; 1000])), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+
+    #[test]
+    fn solves_and_checks() {
+        let n = solve("c0ffee", "alice", 12);
+        assert!(verify("c0ffee", "alice", 12, n));
+        assert!((0..n).all(|m| !verify("c0ffee", "alice", 12, m)));
+        assert!(verify("c0ffee", "alice", 0, 0));
+        assert!(!verify("c0ffee", "alice", MAX_BITS + 1, n));
+    }
+}
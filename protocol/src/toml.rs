@@ -0,0 +1,201 @@
+//! Just enough TOML for the server and client config files: `[sections]`
+//! (dotted names are kept whole) and `key = value` with strings, integers,
+//! booleans and arrays of strings (which can't contain commas). Settings are
+//! taken out as they're read, so whatever is left at the end is a typo or an
+//! unsupported setting.
+
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Str(String),
+    Int(u64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Parsed `section -> key -> value`; keys before any header are in section "".
+#[derive(Debug, Default)]
+pub struct Document {
+    sections: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+impl Document {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut sections: BTreeMap<String, BTreeMap<String, Value>> = BTreeMap::new();
+        let mut section = String::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| Error(format!("line {}: {msg}", n + 1));
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header.strip_suffix(']').ok_or_else(|| err("unterminated section header"))?;
+                section = header.trim().to_string();
+                sections.entry(section.clone()).or_default();
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| err("expected key = value"))?;
+            let value = parse_value(value.trim()).ok_or_else(|| err("invalid value"))?;
+            sections.entry(section.clone()).or_default().insert(key.trim().to_string(), value);
+        }
+        Ok(Document { sections })
+    }
+
+    /// Section names, in order.
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.keys().map(String::as_str)
+    }
+
+    /// Keys left in `section`, in order.
+    pub fn keys(&self, section: &str) -> Vec<String> {
+        self.sections.get(section).map(|keys| keys.keys().cloned().collect()).unwrap_or_default()
+    }
+
+    /// A command-line override. Strings don't need quoting there.
+    pub fn set(&mut self, section: &str, key: &str, raw: &str) {
+        let value = parse_value(raw).unwrap_or_else(|| Value::Str(raw.to_string()));
+        self.sections.entry(section.to_string()).or_default().insert(key.to_string(), value);
+    }
+
+    fn take(&mut self, section: &str, key: &str) -> Option<Value> {
+        self.sections.get_mut(section)?.remove(key)
+    }
+
+    pub fn take_str(&mut self, section: &str, key: &str) -> Result<Option<String>> {
+        match self.take(section, key) {
+            Some(Value::Str(s)) => Ok(Some(s)),
+            Some(_) => Err(Error(format!("{} must be a string", name(section, key)))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn take_int(&mut self, section: &str, key: &str) -> Result<Option<u64>> {
+        match self.take(section, key) {
+            Some(Value::Int(n)) => Ok(Some(n)),
+            Some(_) => Err(Error(format!("{} must be a non-negative integer", name(section, key)))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn take_bool(&mut self, section: &str, key: &str) -> Result<Option<bool>> {
+        match self.take(section, key) {
+            Some(Value::Bool(b)) => Ok(Some(b)),
+            Some(_) => Err(Error(format!("{} must be true or false", name(section, key)))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn take_list(&mut self, section: &str, key: &str) -> Result<Option<Vec<String>>> {
+        match self.take(section, key) {
+            Some(Value::List(items)) => Ok(Some(items)),
+            Some(_) => Err(Error(format!("{} must be a list of strings", name(section, key)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails on anything not taken.
+    pub fn finish(self) -> Result<()> {
+        for (section, keys) in self.sections {
+            if let Some(key) = keys.keys().next() {
+                return Err(Error(format!("unknown setting {}", name(&section, key))));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn name(section: &str, key: &str) -> String {
+    if section.is_empty() {
+        key.to_string()
+    } else {
+        format!("{section}.{key}")
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    // A `#` inside a quoted string isn't a comment.
+    let mut in_str = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_str = !in_str,
+            '#' if !in_str => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(s: &str) -> Option<Value> {
+    if let Some(inner) = s.strip_prefix('[') {
+        let inner = inner.strip_suffix(']')?.trim();
+        let items = inner.split(',').map(str::trim).filter(|item| !item.is_empty());
+        return items
+            .map(|item| match parse_value(item)? {
+                Value::Str(s) => Some(s),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .map(Value::List);
+    }
+    if let Some(inner) = s.strip_prefix('"') {
+        let inner = inner.strip_suffix('"')?;
+        return (!inner.contains(['"', '\\'])).then(|| Value::Str(inner.to_string()));
+    }
+    match s {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => s.replace('_', "").parse().ok().map(Value::Int),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values_and_sections() {
+        let mut doc = Document::parse(
+            r##"
+            top = "x # not a comment"
+            [profile.home]  # trailing comment
+            port = 5_555
+            tls = false
+            channels = ["#general", "#rust",]
+            empty = []
+            "##,
+        )
+        .unwrap();
+        assert_eq!(doc.sections().collect::<Vec<_>>(), ["", "profile.home"]);
+        assert_eq!(doc.keys("profile.home"), ["channels", "empty", "port", "tls"]);
+        assert_eq!(doc.take_str("", "top").unwrap().as_deref(), Some("x # not a comment"));
+        assert_eq!(doc.take_int("profile.home", "port").unwrap(), Some(5555));
+        assert_eq!(doc.take_bool("profile.home", "tls").unwrap(), Some(false));
+        assert_eq!(doc.take_list("profile.home", "channels").unwrap().unwrap(), ["#general", "#rust"]);
+        assert_eq!(doc.take_list("profile.home", "empty").unwrap(), Some(vec![]));
+        assert!(doc.finish().is_ok());
+    }
+
+    #[test]
+    fn reports_leftovers_and_bad_values() {
+        let mut doc = Document::parse("[a]\nn = 1\nm = \"s\"").unwrap();
+        assert!(doc.take_str("a", "n").is_err());
+        assert_eq!(doc.finish().unwrap_err().to_string(), "unknown setting a.m");
+        assert!(Document::parse("list = [1, 2]").is_err());
+        assert!(Document::parse("[a\nx = 1").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! The UDP side of voice channels. `VOICE JOIN <#chan>` over the chat
+//! connection answers with a `VoiceToken`: the port to send to and a token
+//! for this connection. Each datagram to the server is the token's
+//! `TOKEN_LEN` bytes followed by one Opus frame; an empty frame just tells
+//! the server where to send to, and is worth repeating now and then to keep
+//! NAT mappings open. The server relays each frame to everyone else in the
+//! channel's voice session as the sender's connection ID, 8 bytes big-endian,
+//! followed by the frame.
+
+/// Bytes of token at the start of each datagram to the server.
+pub const TOKEN_LEN: usize = 16;
+/// The largest Opus frame there is; longer datagrams are dropped.
+pub const MAX_FRAME: usize = 1275;
+
+/// `token` as it appears in a `VoiceToken` event.
+pub fn token_hex(token: &[u8; TOKEN_LEN]) -> String {
+    token.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The token from a `VoiceToken` event.
+pub fn parse_token(hex: &str) -> Option<[u8; TOKEN_LEN]> {
+    if hex.len() != TOKEN_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut token = [0; TOKEN_LEN];
+    for (i, b) in token.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(token)
+}
+
+/// A datagram carrying `frame` to the server.
+pub fn outgoing(token: &[u8; TOKEN_LEN], frame: &[u8]) -> Vec<u8> {
+    [token.as_slice(), frame].concat()
+}
+
+/// The token and frame of a datagram to the server.
+pub fn split_outgoing(packet: &[u8]) -> Option<([u8; TOKEN_LEN], &[u8])> {
+    let (token, frame) = packet.split_first_chunk::<TOKEN_LEN>()?;
+    (frame.len() <= MAX_FRAME).then_some((*token, frame))
+}
+
+/// A frame from connection `from`, as relayed.
+pub fn relayed(from: u64, frame: &[u8]) -> Vec<u8> {
+    [from.to_be_bytes().as_slice(), frame].concat()
+}
+
+/// The sender and frame of a relayed datagram.
+pub fn split_relayed(packet: &[u8]) -> Option<(u64, &[u8])> {
+    let (from, frame) = packet.split_first_chunk::<8>()?;
+    Some((u64::from_be_bytes(*from), frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_datagrams() {
+        let token = [0xab; TOKEN_LEN];
+        assert_eq!(parse_token(&token_hex(&token)), Some(token));
+        assert_eq!(parse_token("abcd"), None);
+        assert_eq!(split_outgoing(&outgoing(&token, b"opus")), Some((token, b"opus".as_slice())));
+        assert_eq!(split_outgoing(&outgoing(&token, &[0; MAX_FRAME + 1])), None);
+        assert_eq!(split_outgoing(&token[..4]), None);
+        assert_eq!(split_relayed(&relayed(7, b"opus")), Some((7, b"opus".as_slice())));
+    }
+}
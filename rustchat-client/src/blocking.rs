@@ -0,0 +1,137 @@
+//! [`Client`] for programs that aren't async: scripts, or code built around
+//! threads. It runs a small tokio runtime of its own to drive the connection,
+//! so it mustn't be used from inside another runtime.
+//!
+//! ```no_run
+//! use rustchat_client::{blocking::Client, Event};
+//!
+//! let mut client = Client::connect("127.0.0.1:5555", "echo-bot")?;
+//! for event in client.events() {
+//!     if let Event::Dm { from, body, .. } = event {
+//!         client.send_dm(&from, &body)?;
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use anyhow::Result;
+use tokio::runtime::{self, Runtime};
+
+use crate::{Command, ConnectError, Event, Options};
+use protocol::ed25519;
+
+/// A registered connection to a server. Dropping it disconnects.
+pub struct Client {
+    inner: crate::Client,
+    // Declared last so the client's task is stopped before the runtime goes.
+    _runtime: Runtime,
+}
+
+/// What the server sends, in order; iterating blocks until the next event
+/// arrives and ends once the server has closed the connection for good.
+pub struct Events {
+    inner: crate::Events,
+}
+
+impl Client {
+    /// Connects to `addr` (`host:port`) and registers as `name`, blocking
+    /// until that's done.
+    pub fn connect(addr: &str, name: &str) -> Result<Client, ConnectError> {
+        Client::connect_with(addr, name, &Options::default())
+    }
+
+    /// Like [`Client::connect`], with a password, proxy or the like from
+    /// `options`.
+    pub fn connect_with(addr: &str, name: &str, options: &Options) -> Result<Client, ConnectError> {
+        // One worker is plenty for one connection, and keeps it running
+        // between calls.
+        let runtime = runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build()?;
+        let inner = runtime.block_on(crate::Client::connect_with(addr, name, options))?;
+        Ok(Client { inner, _runtime: runtime })
+    }
+
+    pub fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    /// The connection id the server gave us when we first registered.
+    pub fn id(&self) -> u64 {
+        self.inner.id()
+    }
+
+    /// The events from the server. They can only be taken once; later calls
+    /// get an iterator that has already ended.
+    pub fn events(&mut self) -> Events {
+        Events { inner: self.inner.events() }
+    }
+
+    /// Sends any command. Commands sent while the connection is down are
+    /// dropped; `Err` means the client has shut down for good.
+    pub fn send(&self, cmd: Command) -> Result<()> {
+        self.inner.send(cmd)
+    }
+
+    /// Sends a direct message to `to`.
+    pub fn send_dm(&self, to: &str, body: &str) -> Result<()> {
+        self.inner.send_dm(to, body)
+    }
+
+    /// Joins `channel`, and again after any reconnect.
+    pub fn join(&self, channel: &str) -> Result<()> {
+        self.inner.join(channel)
+    }
+
+    /// Joins a channel that has a key.
+    pub fn join_with_key(&self, channel: &str, key: &str) -> Result<()> {
+        self.inner.join_with_key(channel, key)
+    }
+
+    pub fn part(&self, channel: &str) -> Result<()> {
+        self.inner.part(channel)
+    }
+
+    /// Sends a message to a channel we've joined.
+    pub fn say(&self, channel: &str, body: &str) -> Result<()> {
+        self.inner.say(channel, body)
+    }
+
+    /// Sends `body` to `target`, signed with the key made from `seed`; see
+    /// [`crate::Client::send_signed`].
+    pub fn send_signed(&self, target: &str, body: &str, seed: &[u8; ed25519::KEY_LEN]) -> Result<()> {
+        self.inner.send_signed(target, body, seed)
+    }
+
+    /// Leaves the server. The events end once it has said goodbye.
+    pub fn quit(&self, message: Option<&str>) -> Result<()> {
+        self.inner.quit(message)
+    }
+}
+
+impl Iterator for Events {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.inner.rx.blocking_recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_and_receives_without_async() {
+        // The test server needs a runtime of its own.
+        let server = Runtime::new().unwrap();
+        let addr = server.block_on(crate::tests::echo_server());
+        let mut client = Client::connect(&addr, "bot").unwrap();
+        let mut events = client.events();
+        client.send_dm("echo", "ping").unwrap();
+        match events.next() {
+            Some(Event::Dm { from, body, .. }) => assert_eq!((from.as_str(), body.as_str()), ("echo", "ping")),
+            other => panic!("{other:?}"),
+        }
+        drop(client);
+        assert!(events.next().is_none());
+    }
+}
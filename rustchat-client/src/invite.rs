@@ -0,0 +1,64 @@
+//! Invite links, `rustchat://host:port/#room?token=...`, as handed out by the
+//! server's `INVITE`. The token goes to the server during the handshake
+//! (see [`Options::token`](crate::session::Options)), which joins the room
+//! for us if the invite names one.
+
+use anyhow::{Result, anyhow, bail};
+
+pub const SCHEME: &str = "rustchat://";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    /// `host:port`, or a bare host for the default port.
+    pub addr: String,
+    pub channel: Option<String>,
+    pub token: String,
+}
+
+impl Invite {
+    pub fn is_link(s: &str) -> bool {
+        s.get(..SCHEME.len()).is_some_and(|scheme| scheme.eq_ignore_ascii_case(SCHEME))
+    }
+
+    pub fn parse(link: &str) -> Result<Invite> {
+        if !Invite::is_link(link) {
+            bail!("invite links start with {SCHEME}");
+        }
+        let rest = &link.trim()[SCHEME.len()..];
+        let (addr, rest) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        let rest = rest.strip_prefix('/').unwrap_or(rest);
+        let (channel, query) = rest.split_once('?').ok_or_else(|| anyhow!("invite link has no token"))?;
+        let token = query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .filter(|t| !t.is_empty() && t.chars().all(|c| c.is_ascii_alphanumeric()))
+            .ok_or_else(|| anyhow!("invite link has no token"))?;
+        if addr.is_empty() {
+            bail!("invite link has no server address");
+        }
+        let channel = match channel {
+            "" => None,
+            c if protocol::is_channel_name(c) => Some(c.to_string()),
+            c => bail!("bad channel in invite link: {c}"),
+        };
+        Ok(Invite { addr: addr.to_string(), channel, token: token.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_links() {
+        let invite = Invite::parse("rustchat://192.0.2.2:5555/#rust?token=ab12").unwrap();
+        assert_eq!(invite, Invite { addr: "192.0.2.2:5555".into(), channel: Some("#rust".into()), token: "ab12".into() });
+        let invite = Invite::parse("RUSTCHAT://[::1]:5555/?token=ff").unwrap();
+        assert_eq!((invite.addr.as_str(), invite.channel), ("[::1]:5555", None));
+        assert!(Invite::parse("rustchat://chat.example.com?token=ff").is_ok());
+        assert!(Invite::parse("rustchat://host:5555/#rust").is_err());
+        assert!(Invite::parse("rustchat://host:5555/rust?token=ff").is_err());
+        assert!(Invite::parse("rustchat:///?token=ff").is_err());
+        assert!(Invite::parse("http://host/?token=ff").is_err());
+    }
+}
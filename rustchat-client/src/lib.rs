@@ -0,0 +1,221 @@
+//! A rustchat client for programs: bots, bridges and other front ends.
+//!
+//! [`Client`] connects, registers a nickname and then runs the connection in
+//! the background, reconnecting when it drops. Commands go in through its
+//! methods and what the server says comes out of [`Client::events`]:
+//!
+//! ```no_run
+//! # async fn bot() -> anyhow::Result<()> {
+//! use rustchat_client::{Client, Event};
+//!
+//! let mut client = Client::connect("127.0.0.1:5555", "echo-bot").await?;
+//! let mut events = client.events();
+//! while let Some(event) = events.next().await {
+//!     if let Event::Dm { from, body, .. } = event {
+//!         client.send_dm(&from, &body)?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`blocking::Client`] is the same for programs that aren't async.
+//! [`session::Session`] is the layer underneath, for UIs that want to show
+//! reconnects as they happen.
+
+pub mod blocking;
+pub mod invite;
+pub mod net;
+pub mod proxy;
+pub mod session;
+
+use anyhow::{anyhow, Result};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+pub use protocol::{Command, Event};
+pub use session::{ConnectError, Options};
+
+use protocol::{ed25519, Wire};
+use session::{Session, Update};
+
+/// A registered connection to a server. Dropping it disconnects.
+pub struct Client {
+    name: String,
+    id: u64,
+    commands: mpsc::UnboundedSender<Command>,
+    events: Option<mpsc::UnboundedReceiver<Event>>,
+}
+
+/// What the server sends, in order. This is what a `Stream` of events would
+/// be; `next` and `poll_next` work like tokio's channel receivers.
+pub struct Events {
+    rx: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Client {
+    /// Connects to `addr` (`host:port`) and registers as `name`.
+    pub async fn connect(addr: &str, name: &str) -> Result<Client, ConnectError> {
+        Client::connect_with(addr, name, &Options::default()).await
+    }
+
+    /// Like [`Client::connect`], with a password, proxy or the like from
+    /// `options`.
+    pub async fn connect_with(addr: &str, name: &str, options: &Options) -> Result<Client, ConnectError> {
+        let conn = session::connect_with(addr, name, &[], options).await?;
+        let id = conn.id;
+        let session = Session::new(addr.to_string(), name.to_string(), Vec::new(), conn);
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        tokio::spawn(drive(session, commands_rx, events_tx));
+        Ok(Client { name: name.to_string(), id, commands, events: Some(events) })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The connection id the server gave us when we first registered.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The events from the server. They can only be taken once; later calls
+    /// get a stream that has already ended.
+    pub fn events(&mut self) -> Events {
+        let rx = self.events.take().unwrap_or_else(|| mpsc::unbounded_channel().1);
+        Events { rx }
+    }
+
+    /// Sends any command. Commands sent while the connection is down are
+    /// dropped; `Err` means the client has shut down for good.
+    pub fn send(&self, cmd: Command) -> Result<()> {
+        self.commands.send(cmd).map_err(|_| anyhow!("disconnected from the server"))
+    }
+
+    /// Sends a direct message to `to`.
+    pub fn send_dm(&self, to: &str, body: &str) -> Result<()> {
+        self.send(Command::To { name: to.to_string(), body: body.to_string() })
+    }
+
+    /// Joins `channel`, and again after any reconnect.
+    pub fn join(&self, channel: &str) -> Result<()> {
+        self.send(Command::Join { channel: channel.to_string(), key: None })
+    }
+
+    /// Joins a channel that has a key.
+    pub fn join_with_key(&self, channel: &str, key: &str) -> Result<()> {
+        self.send(Command::Join { channel: channel.to_string(), key: Some(key.to_string()) })
+    }
+
+    pub fn part(&self, channel: &str) -> Result<()> {
+        self.send(Command::Part(channel.to_string()))
+    }
+
+    /// Sends a message to a channel we've joined.
+    pub fn say(&self, channel: &str, body: &str) -> Result<()> {
+        self.send(Command::Say { channel: channel.to_string(), body: body.to_string() })
+    }
+
+    /// Sends `body` to `target`, a `#channel` or a nickname, signed with
+    /// the Ed25519 key made from `seed`. The server only takes it once the
+    /// key's public half is set for our nickname with `SIGNKEY`.
+    pub fn send_signed(&self, target: &str, body: &str, seed: &[u8; ed25519::KEY_LEN]) -> Result<()> {
+        let signature = ed25519::sign(seed, &ed25519::signed_text(&self.name, target, body));
+        let signature = signature.iter().map(|b| format!("{b:02x}")).collect();
+        self.send(Command::Signed { target: target.to_string(), signature, body: body.to_string() })
+    }
+
+    /// Leaves the server. The events end once it has said goodbye.
+    pub fn quit(&self, message: Option<&str>) -> Result<()> {
+        self.send(Command::Quit(message.map(str::to_string)))
+    }
+}
+
+impl Events {
+    /// The next event; `None` once the server has closed the connection for
+    /// good.
+    pub async fn next(&mut self) -> Option<Event> {
+        self.rx.recv().await
+    }
+
+    /// `next` for code that drives futures by hand; it has the signature of
+    /// `Stream::poll_next`.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Runs a client's session until the server lets it go or the client is
+/// dropped.
+async fn drive(mut session: Session, mut commands: mpsc::UnboundedReceiver<Command>, events: mpsc::UnboundedSender<Event>) {
+    loop {
+        tokio::select! {
+            update = session.next() => match update {
+                Update::Line(line) => {
+                    if let Some(event) = Event::parse(line.as_bytes(), Wire::Text) {
+                        // Nobody listening is fine.
+                        let _ = events.send(event);
+                    }
+                }
+                Update::Lost { retry_in: None } => return,
+                Update::Lost { .. } | Update::RetryFailed { .. } | Update::Reconnected { .. } => {}
+            },
+            cmd = commands.recv() => match cmd {
+                Some(cmd) => {
+                    session.send(&cmd).await;
+                }
+                None => return,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::PROTOCOL_VERSION;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    /// A server that registers one client and answers `TO echo <text>` with a
+    /// DM from echo.
+    pub(crate) async fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let reply = match Command::parse(line.as_bytes(), Wire::Text) {
+                    Command::Hello(_) => Event::Hello { version: PROTOCOL_VERSION },
+                    Command::Nick(name) => Event::Welcome { id: 7, name },
+                    Command::To { body, .. } => {
+                        Event::Dm { id: 1, seq: 1, ts: "2026-10-15T08:30:00Z".into(), from: "echo".into(), from_id: 1, body }
+                    }
+                    _ => continue,
+                };
+                let mut reply = reply.encode(Wire::Text);
+                reply.push(b'\n');
+                writer.write_all(&reply).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn sends_and_receives() {
+        let mut client = Client::connect(&echo_server().await, "bot").await.unwrap();
+        assert_eq!((client.name(), client.id()), ("bot", 7));
+        let mut events = client.events();
+        client.send_dm("echo", "ping").unwrap();
+        match events.next().await {
+            Some(Event::Dm { from, body, .. }) => assert_eq!((from.as_str(), body.as_str()), ("echo", "ping")),
+            other => panic!("{other:?}"),
+        }
+        assert!(client.events().next().await.is_none());
+    }
+}
@@ -0,0 +1,112 @@
+//! Turning a server address into a TCP connection. Addresses may be
+//! `host:port`, `[v6]:port`, or a bare host or IPv6 address on the default
+//! port. A name with several A/AAAA records is connected to Happy Eyeballs
+//! style (RFC 8305): addresses are tried in resolver order alternating
+//! between IPv6 and IPv4, each a short while after the one before unless
+//! that one has already failed, and the first to connect wins.
+
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpStream, lookup_host},
+    task::JoinSet,
+    time::sleep,
+};
+
+use protocol::DEFAULT_PORT;
+
+/// How long an attempt gets to itself before the next address is tried too.
+const STAGGER: Duration = Duration::from_millis(250);
+
+/// `addr` with the default port added if it has none.
+pub fn with_default_port(addr: &str) -> String {
+    let addr = addr.trim();
+    if let Some(bracketed) = addr.strip_prefix('[') {
+        return if bracketed.contains("]:") { addr.to_string() } else { format!("{addr}:{DEFAULT_PORT}") };
+    }
+    if addr.parse::<Ipv6Addr>().is_ok() {
+        return format!("[{addr}]:{DEFAULT_PORT}");
+    }
+    if addr.contains(':') { addr.to_string() } else { format!("{addr}:{DEFAULT_PORT}") }
+}
+
+pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+    let addrs = interleave(lookup_host(with_default_port(addr)).await?.collect());
+    let mut addrs = addrs.into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.spawn(TcpStream::connect(addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{addr}: no addresses found"))));
+        }
+        // Until an attempt finishes or it's time to start another. Dropping
+        // the set cancels the ones still going.
+        let more = addrs.peek().is_some();
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = Some(e),
+                Err(e) => last_error = Some(io::Error::other(e)),
+            },
+            _ = sleep(STAGGER), if more => {}
+        }
+    }
+}
+
+/// Resolver order, but alternating address families starting with the
+/// first one's.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut out = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while !preferred.is_empty() || !other.is_empty() {
+        out.extend(preferred.pop());
+        out.extend(other.pop());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn adds_the_default_port() {
+        assert_eq!(with_default_port("chat.example.com"), "chat.example.com:5555");
+        assert_eq!(with_default_port("chat.example.com:6000"), "chat.example.com:6000");
+        assert_eq!(with_default_port("192.0.2.2"), "192.0.2.2:5555");
+        assert_eq!(with_default_port("::1"), "[::1]:5555");
+        assert_eq!(with_default_port("[::1]"), "[::1]:5555");
+        assert_eq!(with_default_port("[::1]:6000"), "[::1]:6000");
+    }
+
+    #[test]
+    fn alternates_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"].iter().map(|a| a.parse().unwrap()).collect();
+        let order: Vec<String> = interleave(addrs).iter().map(ToString::to_string).collect();
+        assert_eq!(order, ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_past_dead_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Whichever of ::1 and 127.0.0.1 localhost gives first, only the
+        // IPv4 one is listening.
+        assert!(connect(&format!("localhost:{port}")).await.is_ok());
+        drop(listener);
+        assert!(connect(&format!("127.0.0.1:{port}")).await.is_err());
+    }
+}
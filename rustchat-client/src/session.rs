@@ -0,0 +1,374 @@
+//! The connection to the server, kept alive across drops. When the connection
+//! is lost the session reconnects on its own, backing off exponentially with
+//! jitter, and registers the same nickname again. A `RECONNECT` from a
+//! server that's draining for a restart drops the connection the same way,
+//! moving to the address it names if there is one.
+
+use anyhow::{anyhow, Error};
+use std::{
+    collections::{HashMap, hash_map::RandomState},
+    fmt,
+    hash::{BuildHasher, Hasher},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    task::JoinHandle,
+    time::{Duration, Instant, sleep_until, timeout},
+};
+
+use protocol::{
+    Command, Event, PROTOCOL_VERSION, TwoFactorCommand, Wire,
+    caps::{Cap, CapCommand},
+};
+
+use crate::{net, proxy::Proxy};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const FIRST_RETRY: Duration = Duration::from_millis(500);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+/// How long `quit` waits for the server to say goodbye.
+const QUIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A registered connection.
+pub struct Connection {
+    pub id: u64,
+    writer: OwnedWriteHalf,
+    incoming: Lines<BufReader<OwnedReadHalf>>,
+    /// How it was made, for reconnecting the same way.
+    options: Options,
+}
+
+/// Extras for [`connect_with`].
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Asked for before registering; the server has to grant all of them.
+    pub caps: Vec<Cap>,
+    pub proxy: Option<Proxy>,
+    /// An invite token from a `rustchat://` link, presented on every
+    /// (re)connect.
+    pub token: Option<String>,
+    /// The password for a registered nickname, also sent on every
+    /// (re)connect.
+    pub password: Option<String>,
+    /// A two-factor code, sent after the password. Each is taken once, so
+    /// reconnecting to an account with two-factor on needs a new one.
+    pub code: Option<String>,
+    /// Keys for the channels that need one, by channel, so rejoining after a
+    /// reconnect works.
+    pub keys: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The server answered, but not with a welcome.
+    Rejected(String),
+    Failed(Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Rejected(line) => write!(f, "rejected: {line}"),
+            ConnectError::Failed(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// Whether `line`, from a [`ConnectError::Rejected`], says the nickname is
+/// registered and needs a password, or that the one sent was wrong.
+pub fn needs_password(line: &str) -> bool {
+    matches!(Event::parse(line.as_bytes(), Wire::Text), Some(Event::Error(text)) if text.contains("send PASS") || text == "wrong password")
+}
+
+impl From<std::io::Error> for ConnectError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectError::Failed(e.into())
+    }
+}
+
+/// Connects, agrees on a protocol version, registers `name` and joins
+/// `channels`.
+pub async fn connect(addr: &str, name: &str, channels: &[String]) -> Result<Connection, ConnectError> {
+    connect_with(addr, name, channels, &Options::default()).await
+}
+
+/// Like [`connect`], through a proxy and asking for capabilities as
+/// `options` says.
+pub async fn connect_with(addr: &str, name: &str, channels: &[String], options: &Options) -> Result<Connection, ConnectError> {
+    let stream = match &options.proxy {
+        Some(proxy) => proxy.connect(&net::with_default_port(addr)).await?,
+        None => net::connect(addr).await?,
+    };
+    let _ = stream.set_nodelay(true);
+    let (reader, writer) = stream.into_split();
+    let mut conn = Connection { id: 0, writer, incoming: BufReader::new(reader).lines(), options: options.clone() };
+
+    conn.send(&Command::Hello(PROTOCOL_VERSION)).await?;
+    match conn.handshake_line().await? {
+        line if matches!(Event::parse(line.as_bytes(), Wire::Text), Some(Event::Hello { .. })) => {}
+        line => return Err(ConnectError::Rejected(line)),
+    }
+
+    if !options.caps.is_empty() {
+        let list = options.caps.iter().map(|c| c.name()).collect::<Vec<_>>().join(" ");
+        conn.send(&Command::Cap(CapCommand::Req(list))).await?;
+        match conn.handshake_line().await? {
+            line if matches!(Event::parse(line.as_bytes(), Wire::Text), Some(Event::Cap { ref sub, .. }) if sub == "ACK") => {}
+            line => return Err(ConnectError::Rejected(line)),
+        }
+        conn.send(&Command::Cap(CapCommand::End)).await?;
+    }
+    if let Some(token) = &options.token {
+        conn.send(&Command::Token(token.clone())).await?;
+    }
+    if let Some(password) = &options.password {
+        conn.send(&Command::Pass(password.clone())).await?;
+    }
+    if let Some(code) = &options.code {
+        conn.send(&Command::TwoFactor(TwoFactorCommand::Code(code.clone()))).await?;
+    }
+
+    conn.send(&Command::Nick(name.to_string())).await?;
+    let line = conn.handshake_line().await?;
+    match Event::parse(line.as_bytes(), Wire::Text) {
+        Some(Event::Welcome { id, .. }) => {
+            conn.id = id;
+            for channel in channels {
+                conn.send(&Command::Join { channel: channel.clone(), key: options.keys.get(channel).cloned() }).await?;
+            }
+            Ok(conn)
+        }
+        _ => Err(ConnectError::Rejected(line)),
+    }
+}
+
+impl Connection {
+    async fn send(&mut self, cmd: &Command) -> std::io::Result<()> {
+        let mut line = cmd.encode(Wire::Text);
+        line.push(b'\n');
+        self.writer.write_all(&line).await
+    }
+
+    async fn handshake_line(&mut self) -> Result<String, ConnectError> {
+        timeout(HANDSHAKE_TIMEOUT, self.incoming.next_line())
+            .await
+            .map_err(|_| ConnectError::Failed(anyhow!("server did not respond in time")))??
+            .ok_or_else(|| ConnectError::Failed(anyhow!("server closed connection during handshake")))
+    }
+}
+
+/// What happened on the session, for the UI to show.
+pub enum Update {
+    Line(String),
+    /// The connection dropped; `retry_in` is `None` if it won't be retried.
+    Lost { retry_in: Option<Duration> },
+    RetryFailed { error: String, retry_in: Duration },
+    Reconnected { id: u64 },
+}
+
+enum State {
+    Connected(Box<Connection>),
+    /// Waiting to try again.
+    Waiting { at: Instant },
+    Connecting(JoinHandle<Result<Connection, ConnectError>>),
+    /// Kicked or shut down by the server; reconnecting would undo that.
+    Closed,
+}
+
+pub struct Session {
+    addr: String,
+    name: String,
+    channels: Vec<String>,
+    id: u64,
+    state: State,
+    attempt: u32,
+    /// The server said it was disconnecting us on purpose.
+    dismissed: bool,
+    options: Options,
+}
+
+impl Session {
+    pub fn new(addr: String, name: String, channels: Vec<String>, conn: Connection) -> Self {
+        let options = conn.options.clone();
+        Session { addr, name, channels, id: conn.id, state: State::Connected(Box::new(conn)), attempt: 0, dismissed: false, options }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected(_))
+    }
+
+    /// One line of connection state for a status bar.
+    pub fn status(&self) -> String {
+        match &self.state {
+            State::Connected(_) => "connected".into(),
+            State::Waiting { at } => {
+                let secs = at.saturating_duration_since(Instant::now()).as_secs_f64().ceil();
+                format!("reconnecting in {secs}s (attempt {})", self.attempt)
+            }
+            State::Connecting(_) => format!("reconnecting (attempt {})", self.attempt),
+            State::Closed => "disconnected".into(),
+        }
+    }
+
+    /// Sends a raw protocol line. `false` if there's no connection to send it
+    /// on; a failed write shows up as the connection being lost.
+    pub async fn send_line(&mut self, line: &str) -> bool {
+        let State::Connected(conn) = &mut self.state else {
+            return false;
+        };
+        let _ = conn.writer.write_all(format!("{line}\n").as_bytes()).await;
+        true
+    }
+
+    /// Sends `cmd`, keeping track of the channels joined so a reconnect joins
+    /// them again. `false` if there's no connection, as for `send_line`.
+    pub async fn send(&mut self, cmd: &Command) -> bool {
+        let State::Connected(conn) = &mut self.state else {
+            return false;
+        };
+        let _ = conn.send(cmd).await;
+        match cmd {
+            Command::Join { channel, key } => {
+                if !self.channels.contains(channel) {
+                    self.channels.push(channel.clone());
+                }
+                if let Some(key) = key {
+                    self.options.keys.insert(channel.clone(), key.clone());
+                }
+            }
+            Command::Part(channel) => {
+                self.channels.retain(|c| c != channel);
+                self.options.keys.remove(channel);
+            }
+            // The server hangs up next, and that's not worth reconnecting for.
+            Command::Quit(_) => self.dismissed = true,
+            _ => {}
+        }
+        true
+    }
+
+    /// Sends QUIT and waits briefly for the server to say goodbye and close
+    /// the connection. Anything else it sends meanwhile is dropped. The
+    /// session stays disconnected afterwards.
+    pub async fn quit(&mut self, message: Option<String>) {
+        if self.send(&Command::Quit(message)).await
+            && let State::Connected(conn) = &mut self.state
+        {
+            let _ = timeout(QUIT_TIMEOUT, async { while let Ok(Some(_)) = conn.incoming.next_line().await {} }).await;
+        }
+        self.state = State::Closed;
+    }
+
+    /// Waits for the next thing to report. Cancel-safe, so it can sit in a
+    /// `select!` next to user input.
+    pub async fn next(&mut self) -> Update {
+        loop {
+            match &mut self.state {
+                State::Connected(conn) => match conn.incoming.next_line().await {
+                    Ok(Some(line)) => {
+                        let event = Event::parse(line.as_bytes(), Wire::Text);
+                        if event == Some(Event::notice("disconnected")) || line.starts_with("BYE") {
+                            self.dismissed = true;
+                        }
+                        // The server is draining for a restart; the jitter in
+                        // the backoff keeps everyone from coming back at once.
+                        if let Some(Event::Reconnect(to)) = event {
+                            if let Some(addr) = to {
+                                self.addr = addr;
+                            }
+                            self.attempt = 0;
+                            let retry_in = self.schedule_retry();
+                            return Update::Lost { retry_in: Some(retry_in) };
+                        }
+                        return Update::Line(line);
+                    }
+                    _ if self.dismissed => {
+                        self.state = State::Closed;
+                        return Update::Lost { retry_in: None };
+                    }
+                    _ => {
+                        self.attempt = 0;
+                        let retry_in = self.schedule_retry();
+                        return Update::Lost { retry_in: Some(retry_in) };
+                    }
+                },
+                State::Waiting { at } => {
+                    sleep_until(*at).await;
+                    let (addr, name, channels, options) = (self.addr.clone(), self.name.clone(), self.channels.clone(), self.options.clone());
+                    self.state = State::Connecting(tokio::spawn(async move { connect_with(&addr, &name, &channels, &options).await }));
+                }
+                State::Connecting(task) => {
+                    let result = task.await.unwrap_or_else(|e| Err(ConnectError::Failed(e.into())));
+                    match result {
+                        Ok(conn) => {
+                            self.id = conn.id;
+                            self.attempt = 0;
+                            self.state = State::Connected(Box::new(conn));
+                            return Update::Reconnected { id: self.id };
+                        }
+                        Err(e) => {
+                            let retry_in = self.schedule_retry();
+                            return Update::RetryFailed { error: e.to_string(), retry_in };
+                        }
+                    }
+                }
+                State::Closed => std::future::pending::<()>().await,
+            }
+        }
+    }
+
+    fn schedule_retry(&mut self) -> Duration {
+        let delay = backoff(self.attempt);
+        self.attempt += 1;
+        self.state = State::Waiting { at: Instant::now() + delay };
+        delay
+    }
+}
+
+/// Exponential backoff with jitter: somewhere between half and all of
+/// `FIRST_RETRY * 2^attempt`, capped at `MAX_RETRY`, so clients dropped
+/// together don't all come back at the same moment.
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = FIRST_RETRY.saturating_mul(1 << attempt.min(16)).min(MAX_RETRY);
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = 0.5 + (random % 1000) as f64 / 2000.0;
+    ceiling.mul_f64(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        for attempt in 0..20 {
+            let ceiling = FIRST_RETRY.saturating_mul(1 << attempt.min(16)).min(MAX_RETRY);
+            let delay = backoff(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "attempt {attempt}: {delay:?}");
+        }
+        assert!(backoff(30) <= MAX_RETRY);
+    }
+
+    #[test]
+    fn spots_password_rejections() {
+        assert!(needs_password("ERR nickname is registered; send PASS <password> before NICK"));
+        assert!(needs_password("ERR wrong password"));
+        assert!(!needs_password("ERR nickname in use"));
+        assert!(!needs_password("wrong password"));
+    }
+}
@@ -0,0 +1,45 @@
+//! Channel fanout cost: broadcasting an owned `Event`, which every member's
+//! receive clones, against broadcasting an `Arc<Event>`, which members share.
+//!
+//! cargo bench -p server --bench fanout
+
+use std::{hint::black_box, sync::Arc, time::Instant};
+use tokio::sync::broadcast;
+
+use protocol::Event;
+
+const MEMBERS: usize = 1_000;
+const ROUNDS: u32 = 200;
+
+fn event() -> Event {
+    Event::ChannelMsg {
+        channel: "#bench".into(),
+        id: 1,
+        ts: "2026-01-01T00:00:00Z".into(),
+        from: "alice".into(),
+        from_id: 1,
+        body: "x".repeat(200),
+    }
+}
+
+/// Average time for one message to reach every member.
+fn fanout<T: Clone>(msg: impl Fn() -> T) -> f64 {
+    let (tx, _) = broadcast::channel::<T>(16);
+    let mut members: Vec<_> = (0..MEMBERS).map(|_| tx.subscribe()).collect();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        tx.send(msg()).ok();
+        for rx in &mut members {
+            black_box(rx.try_recv().ok());
+        }
+    }
+    start.elapsed().as_secs_f64() * 1e6 / f64::from(ROUNDS)
+}
+
+fn main() {
+    let owned = fanout(event);
+    let shared = fanout(|| Arc::new(event()));
+    println!("fanout to {MEMBERS} members, {ROUNDS} rounds");
+    println!("  Event:      {owned:>9.1} us/message");
+    println!("  Arc<Event>: {shared:>9.1} us/message ({:.1}x)", owned / shared);
+}
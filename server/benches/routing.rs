@@ -0,0 +1,75 @@
+//! DM routing cost: looking up the recipient and queueing an event for it,
+//! through the sharded sender map directly and through the registry actor.
+//!
+//! cargo bench -p server --bench routing
+
+use std::{hint::black_box, sync::Arc, time::Instant};
+use tokio::sync::oneshot;
+
+use protocol::Event;
+use server::{config::Config, queue, registry::Registry};
+
+const CLIENTS: u64 = 1_000;
+const ITERS: u32 = 200_000;
+
+fn event() -> Event {
+    Event::Dm {
+        id: 1,
+        seq: 1,
+        ts: "2026-01-01T00:00:00Z".into(),
+        from: "alice".into(),
+        from_id: 1,
+        body: "x".repeat(100),
+    }
+}
+
+fn report(label: &str, start: Instant) {
+    let ns = start.elapsed().as_secs_f64() * 1e9 / f64::from(ITERS);
+    println!("  {label:<28} {ns:>8.1} ns");
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let config = Config::default();
+    let reg = Registry::spawn();
+    let mut queues = Vec::new();
+    let mut shutdowns = Vec::new();
+    for id in 1..=CLIENTS {
+        let (tx, rx) = queue::channel(id, &config.queue);
+        let (shutdown, signal) = oneshot::channel();
+        reg.register(id, &format!("user{id}"), tx, shutdown).await.unwrap();
+        queues.push(rx);
+        shutdowns.push(signal);
+    }
+    let names: Vec<String> = (1..=CLIENTS).map(|id| format!("user{id}")).collect();
+    let event = Arc::new(event());
+
+    println!("{CLIENTS} clients, {ITERS} iterations");
+
+    // Each iteration takes the event back off the queue, so every push takes
+    // the normal path rather than the queue-full one.
+    let start = Instant::now();
+    for i in 0..ITERS {
+        let id = u64::from(i) % CLIENTS + 1;
+        reg.sender(black_box(id)).unwrap().push(event.clone()).unwrap();
+        black_box(queues[id as usize - 1].try_recv());
+    }
+    report("sender(id) + push", start);
+
+    let start = Instant::now();
+    for i in 0..ITERS {
+        let n = i as usize % names.len();
+        let id = reg.id_of(black_box(&names[n])).await.unwrap();
+        reg.sender(id).unwrap().push(event.clone()).unwrap();
+        black_box(queues[n].try_recv());
+    }
+    report("id_of(name) + push", start);
+
+    let start = Instant::now();
+    for i in 0..ITERS {
+        let id = u64::from(i) % CLIENTS + 1;
+        reg.deliver_dm("alice", 1, black_box(id), "hello").await.unwrap();
+        black_box(queues[id as usize - 1].try_recv());
+    }
+    report("deliver_dm (stamp + record)", start);
+}
@@ -0,0 +1,161 @@
+//! Which addresses may connect at all, checked as each connection is
+//! accepted, before it gets to say anything. An address in a `deny` range is
+//! turned away; if there are `allow` ranges, so is one in none of them.
+//! `[access]` in the config sets the starting lists, and admin's `ACCESS
+//! ALLOW|DENY <cidr>` adds to them until the server restarts. Connections
+//! already open stay open.
+
+use parking_lot::Mutex;
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::config::AccessConfig;
+
+/// An address range, like `10.0.0.0/8` or `2001:db8::/32`; a bare address
+/// is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    net: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => masked(u32::from(net).into(), 32, self.prefix) == masked(u32::from(ip).into(), 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => masked(net.into(), 128, self.prefix) == masked(ip.into(), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// The top `prefix` of the `bits` low bits of `n`.
+fn masked(n: u128, bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        n >> (bits - prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let net: IpAddr = addr.parse().map_err(|_| format!("{s:?} isn't an address or CIDR range"))?;
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|&p| p <= max).ok_or_else(|| format!("{s:?} needs a prefix length from 0 to {max}"))?,
+            None => max,
+        };
+        Ok(Cidr { net, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.net, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    Allow,
+    Deny,
+}
+
+impl Rule {
+    pub fn label(self) -> &'static str {
+        match self {
+            Rule::Allow => "allow",
+            Rule::Deny => "deny",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Lists {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+#[derive(Default)]
+pub struct Access {
+    lists: Mutex<Lists>,
+}
+
+impl Access {
+    pub fn new(config: &AccessConfig) -> Self {
+        Access { lists: Mutex::new(Lists { allow: config.allow.clone(), deny: config.deny.clone() }) }
+    }
+
+    /// Whether `ip` may connect.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let lists = self.lists.lock();
+        !lists.deny.iter().any(|c| c.contains(ip)) && (lists.allow.is_empty() || lists.allow.iter().any(|c| c.contains(ip)))
+    }
+
+    /// Adds `cidr` to a list; `false` if it was already there.
+    pub fn add(&self, rule: Rule, cidr: Cidr) -> bool {
+        let mut lists = self.lists.lock();
+        let list = match rule {
+            Rule::Allow => &mut lists.allow,
+            Rule::Deny => &mut lists.deny,
+        };
+        if list.contains(&cidr) {
+            return false;
+        }
+        list.push(cidr);
+        true
+    }
+
+    /// Both lists, allow first.
+    pub fn rules(&self) -> Vec<(Rule, Cidr)> {
+        let lists = self.lists.lock();
+        lists.allow.iter().map(|&c| (Rule::Allow, c)).chain(lists.deny.iter().map(|&c| (Rule::Deny, c))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let lan: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(lan.contains(ip("192.168.44.1")) && lan.contains(ip("::ffff:192.168.0.9")));
+        assert!(!lan.contains(ip("192.169.0.1")) && !lan.contains(ip("2001:db8::1")));
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")) && !v6.contains(ip("2001:db9::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("203.0.113.5")));
+        assert!("203.0.113.5".parse::<Cidr>().unwrap().contains(ip("203.0.113.5")));
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = Access::new(&AccessConfig { allow: vec!["10.0.0.0/8".parse().unwrap()], deny: vec!["10.6.6.0/24".parse().unwrap()] });
+        assert!(access.permits(ip("10.1.2.3")));
+        assert!(!access.permits(ip("10.6.6.6")));
+        assert!(!access.permits(ip("192.0.2.1")));
+        assert!(access.add(Rule::Allow, "192.0.2.0/24".parse().unwrap()));
+        assert!(!access.add(Rule::Allow, "192.0.2.0/24".parse().unwrap()));
+        assert!(access.permits(ip("192.0.2.1")));
+        access.add(Rule::Deny, "192.0.2.1".parse().unwrap());
+        assert!(!access.permits(ip("192.0.2.1")));
+        assert_eq!(access.rules().len(), 4);
+
+        assert!(Access::default().permits(ip("192.0.2.1")));
+    }
+}
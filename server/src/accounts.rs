@@ -0,0 +1,502 @@
+//! Registered nicknames. `REGISTER <password>` ties the nickname in use to a
+//! password, after which connecting as it takes `PASS <password>` in the
+//! handshake; `REGISTER` again changes the password.
+//!
+//! Passwords are kept as salted argon2id hashes in `[accounts] file`, one
+//! `name<TAB>hash` line each, written on each change. Hashing takes a while
+//! on purpose, so it happens on the blocking pool.
+//!
+//! Lines written by hand or by older servers may hold `plain:<password>`;
+//! those are hashed when the file is loaded. A hash made with other
+//! parameters than the configured ones still verifies, and is made again
+//! with the current ones the next time its owner logs in.
+//!
+//! `2FA ENABLE` hands out a TOTP secret, which `2FA <code>` confirms; from
+//! then on logging in also takes `2FA <code>` after `PASS`. The secret is
+//! kept in a third column, since codes can't be checked without it, which
+//! is why the file is only readable by the server's user. A code is taken
+//! once: the step it was for is remembered, and it and earlier ones are
+//! refused from then on.
+//!
+//! With `[accounts] challenge_bits` set, registering a nickname that isn't
+//! yet takes a proof of work (`protocol::pow`) first: `REGISTER` is answered
+//! with a `CHALLENGE`, and only a `PROOF` that meets it registers the name.
+//! Changing a password doesn't take one.
+//!
+//! A bot's owner can give its nickname an Ed25519 public key with
+//! `SIGNKEY`, kept in a fourth column; the third is left empty without
+//! two-factor. Messages it sends with `SIGNED` are checked against the key
+//! and delivered with a `VERIFIED` event.
+//!
+//! With a storage key the file is sealed like the others `at_rest` covers,
+//! since the secrets in it are enough to log in as someone. A plain file,
+//! such as one written by hand to add the admin, is read and sealed at once.
+
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::{
+    argon2::{self, Params},
+    at_rest::{self, Key},
+    config::AccountsConfig,
+    info,
+    random,
+    totp,
+    warn,
+};
+use protocol::ed25519;
+
+pub const MIN_PASSWORD: usize = 8;
+pub const MAX_PASSWORD: usize = 256;
+
+const HEADER: &str = "# rustchat accounts v3: name, argon2id hash, TOTP secret if two-factor is on, Ed25519 signing key if set";
+const LEGACY: &str = "plain:";
+/// How long a challenge can be answered for.
+const CHALLENGE_TTL: Duration = Duration::from_secs(600);
+
+/// Why `REGISTER` was turned away.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccountDenied {
+    TooShort,
+    TooLong,
+}
+
+impl fmt::Display for AccountDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountDenied::TooShort => write!(f, "passwords need at least {MIN_PASSWORD} characters"),
+            AccountDenied::TooLong => write!(f, "passwords can have at most {MAX_PASSWORD} characters"),
+        }
+    }
+}
+
+/// A `REGISTER` waiting for its proof of work.
+pub struct Challenge {
+    pub nonce: String,
+    pub bits: u32,
+    password: String,
+    expires: Instant,
+}
+
+impl Challenge {
+    /// The password to register `name` with, if `n` answers the challenge
+    /// in time.
+    pub fn answer(self, name: &str, n: u64, now: Instant) -> Result<String, &'static str> {
+        if now >= self.expires {
+            return Err("the challenge has expired");
+        }
+        if !protocol::pow::verify(&self.nonce, name, self.bits, n) {
+            return Err("that doesn't answer the challenge");
+        }
+        Ok(self.password)
+    }
+}
+
+#[derive(Default)]
+struct Account {
+    hash: String,
+    totp: Option<Vec<u8>>,
+    /// Handed out by `2FA ENABLE` and waiting for a code to confirm it.
+    pending: Option<Vec<u8>>,
+    /// The last step a code was taken for.
+    used_step: u64,
+    signing_key: Option<[u8; ed25519::KEY_LEN]>,
+}
+
+pub struct Accounts {
+    params: Params,
+    challenge_bits: u32,
+    /// By name.
+    accounts: Mutex<HashMap<String, Account>>,
+    file: Option<PathBuf>,
+    key: Option<Key>,
+}
+
+impl Accounts {
+    /// Accounts hashed with `params`, kept in memory only.
+    pub fn new(params: Params) -> Self {
+        Accounts { params, challenge_bits: 0, accounts: Mutex::default(), file: None, key: None }
+    }
+
+    /// The accounts `config` asks for, starting with those already in its
+    /// file, which is sealed with `key` if there is one. A missing file is
+    /// no accounts.
+    pub fn load(config: &AccountsConfig, key: Option<Key>) -> Result<Self> {
+        let mut accounts = Accounts::new(config.params);
+        accounts.challenge_bits = config.challenge_bits;
+        let Some(file) = &config.file else {
+            return Ok(accounts);
+        };
+        accounts.file = Some(file.clone());
+        accounts.key = key;
+        let data = match fs::read(file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(accounts),
+            Err(e) => bail!("failed to read {}: {e}", file.display()),
+        };
+        let text = at_rest::open(key.as_ref(), &data).with_context(|| format!("can't read {}", file.display()))?;
+        let mut migrated = 0;
+        let mut loaded = HashMap::new();
+        for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.starts_with('#') && !l.is_empty()) {
+            let bad = || anyhow!("{}:{}: not an account", file.display(), n + 1);
+            let mut fields = line.split('\t');
+            let (Some(name), Some(stored)) = (fields.next(), fields.next()) else {
+                return Err(bad());
+            };
+            let totp = match fields.next() {
+                Some("") | None => None,
+                Some(secret) => Some(totp::parse_base32(secret).filter(|s| !s.is_empty()).ok_or_else(bad)?),
+            };
+            let signing_key = match fields.next() {
+                Some(key) => Some(parse_key(key).ok_or_else(bad)?),
+                None => None,
+            };
+            let hash = match stored.strip_prefix(LEGACY) {
+                Some(password) => {
+                    migrated += 1;
+                    accounts.hash(password)
+                }
+                None if argon2::params_of(stored).is_some() => stored.to_string(),
+                None => return Err(bad()),
+            };
+            loaded.insert(name.to_string(), Account { hash, totp, signing_key, ..Account::default() });
+        }
+        *accounts.accounts.get_mut() = loaded;
+        if migrated > 0 {
+            info!("ACCOUNTS", "hashed {migrated} plain-text passwords in {}", file.display());
+        }
+        let plain = key.is_some() && !at_rest::is_sealed(&data);
+        if plain {
+            info!("ACCOUNTS", "encrypting {}", file.display());
+        }
+        if migrated > 0 || plain {
+            accounts.save(&accounts.accounts.lock());
+        }
+        Ok(accounts)
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.lock().contains_key(name)
+    }
+
+    /// Forgets `name`'s account, for `PURGE`; `false` if there wasn't one.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut all = self.accounts.lock();
+        if all.remove(name).is_none() {
+            return false;
+        }
+        self.save(&all);
+        true
+    }
+
+    /// Whether logging in as `name` takes a code.
+    pub fn has_two_factor(&self, name: &str) -> bool {
+        self.accounts.lock().get(name).is_some_and(|a| a.totp.is_some())
+    }
+
+    /// Whether `password` is `name`'s. A hash made with other parameters is
+    /// replaced with one made with the current ones.
+    pub async fn verify(self: &Arc<Self>, name: &str, password: &str) -> bool {
+        let Some(stored) = self.accounts.lock().get(name).map(|a| a.hash.clone()) else {
+            return false;
+        };
+        let (accounts, name, password) = (self.clone(), name.to_string(), password.to_string());
+        tokio::task::spawn_blocking(move || {
+            if !argon2::verify(&stored, password.as_bytes()) {
+                return false;
+            }
+            if argon2::params_of(&stored) != Some(accounts.params) {
+                let hash = accounts.hash(&password);
+                let mut all = accounts.accounts.lock();
+                // Unless the password changed meanwhile.
+                if let Some(account) = all.get_mut(&name).filter(|a| a.hash == stored) {
+                    account.hash = hash;
+                    accounts.save(&all);
+                    info!("ACCOUNTS", "rehashed {name}'s password with the current parameters");
+                }
+            }
+            true
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// The challenge registering `name` with `password` takes first, if it
+    /// takes one. A password `set` would turn away is turned away now.
+    pub fn challenge(&self, name: &str, password: &str, now: Instant) -> Result<Option<Challenge>, AccountDenied> {
+        check_length(password)?;
+        if self.challenge_bits == 0 || self.is_registered(name) {
+            return Ok(None);
+        }
+        Ok(Some(Challenge { nonce: random::hex::<16>(), bits: self.challenge_bits, password: password.to_string(), expires: now + CHALLENGE_TTL }))
+    }
+
+    /// Registers `name` with `password`, or changes its password.
+    pub async fn set(self: &Arc<Self>, name: &str, password: &str) -> Result<(), AccountDenied> {
+        check_length(password)?;
+        let (accounts, name, password) = (self.clone(), name.to_string(), password.to_string());
+        let _ = tokio::task::spawn_blocking(move || {
+            let hash = accounts.hash(&password);
+            let mut all = accounts.accounts.lock();
+            all.entry(name).or_default().hash = hash;
+            accounts.save(&all);
+        })
+        .await;
+        Ok(())
+    }
+
+    /// A new TOTP secret for `name`, which a code made from it turns on;
+    /// `None` if they aren't registered.
+    pub fn start_two_factor(&self, name: &str) -> Option<Vec<u8>> {
+        let mut all = self.accounts.lock();
+        let account = all.get_mut(name)?;
+        let secret = random::bytes::<{ totp::SECRET_LEN }>().to_vec();
+        account.pending = Some(secret.clone());
+        Some(secret)
+    }
+
+    /// Turns two-factor on for `name` if `code` is from the secret
+    /// `start_two_factor` gave them.
+    pub fn confirm_two_factor(&self, name: &str, code: &str, now: u64) -> bool {
+        let mut all = self.accounts.lock();
+        let Some(account) = all.get_mut(name) else {
+            return false;
+        };
+        let Some(step) = account.pending.as_deref().and_then(|secret| totp::check(secret, code, now)) else {
+            return false;
+        };
+        account.totp = account.pending.take();
+        account.used_step = step;
+        self.save(&all);
+        true
+    }
+
+    /// Whether `code` is right for `name` now and hasn't been taken before.
+    pub fn check_code(&self, name: &str, code: &str, now: u64) -> bool {
+        let mut all = self.accounts.lock();
+        let Some(account) = all.get_mut(name) else {
+            return false;
+        };
+        match account.totp.as_deref().and_then(|secret| totp::check(secret, code, now)) {
+            Some(step) if step > account.used_step => {
+                account.used_step = step;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Turns two-factor off for `name`, given a current code.
+    pub fn disable_two_factor(&self, name: &str, code: &str, now: u64) -> bool {
+        if !self.check_code(name, code, now) {
+            return false;
+        }
+        let mut all = self.accounts.lock();
+        if let Some(account) = all.get_mut(name) {
+            account.totp = None;
+        }
+        self.save(&all);
+        true
+    }
+
+    /// Sets or removes the key `name`'s signed messages are checked
+    /// against; `false` if they aren't registered.
+    pub fn set_signing_key(&self, name: &str, key: Option<[u8; ed25519::KEY_LEN]>) -> bool {
+        let mut all = self.accounts.lock();
+        let Some(account) = all.get_mut(name) else {
+            return false;
+        };
+        account.signing_key = key;
+        self.save(&all);
+        true
+    }
+
+    pub fn signing_key(&self, name: &str) -> Option<[u8; ed25519::KEY_LEN]> {
+        self.accounts.lock().get(name)?.signing_key
+    }
+
+    fn hash(&self, password: &str) -> String {
+        argon2::hash_encoded(password.as_bytes(), &random::bytes::<16>(), self.params)
+    }
+
+    /// Writes every account to the file, if there is one, readable by the
+    /// server's user only and sealed if there's a key. A failure is logged
+    /// and accounts carry on in memory.
+    fn save(&self, accounts: &HashMap<String, Account>) {
+        let Some(file) = &self.file else { return };
+        let mut names: Vec<_> = accounts.keys().collect();
+        names.sort();
+        let mut text = format!("{HEADER}\n");
+        for name in names {
+            let account = &accounts[name];
+            let secret = account.totp.as_deref().map(totp::base32);
+            match (secret, &account.signing_key) {
+                (secret, Some(key)) => text.push_str(&format!("{name}\t{}\t{}\t{}\n", account.hash, secret.unwrap_or_default(), hex(key))),
+                (Some(secret), None) => text.push_str(&format!("{name}\t{}\t{secret}\n", account.hash)),
+                (None, None) => text.push_str(&format!("{name}\t{}\n", account.hash)),
+            }
+        }
+        let saved = at_rest::seal(self.key.as_ref(), &text).and_then(|data| Ok(at_rest::replace(file, &data)?));
+        if let Err(e) = saved.with_context(|| format!("can't save {}", file.display())) {
+            warn!("ACCOUNTS", "{e:#}");
+        }
+    }
+}
+
+fn check_length(password: &str) -> Result<(), AccountDenied> {
+    match password.chars().count() {
+        n if n < MIN_PASSWORD => Err(AccountDenied::TooShort),
+        n if n > MAX_PASSWORD => Err(AccountDenied::TooLong),
+        _ => Ok(()),
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// An Ed25519 public key from its 64 hex digits, if it is one.
+pub fn parse_key(hex: &str) -> Option<[u8; ed25519::KEY_LEN]> {
+    parse_hex(hex).filter(ed25519::is_public_key)
+}
+
+/// Exactly `N` bytes' worth of hex digits.
+pub fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    if hex.len() != 2 * N || !hex.is_ascii() {
+        return None;
+    }
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Params = Params { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    #[tokio::test]
+    async fn registers_and_verifies() {
+        let accounts = Arc::new(Accounts::new(FAST));
+        assert!(!accounts.is_registered("alice"));
+        assert!(!accounts.verify("alice", "anything").await);
+        assert_eq!(accounts.set("alice", "short").await, Err(AccountDenied::TooShort));
+        accounts.set("alice", "correct horse").await.unwrap();
+        assert!(accounts.is_registered("alice"));
+        assert!(accounts.verify("alice", "correct horse").await);
+        assert!(!accounts.verify("alice", "correct horsf").await);
+        // Salted, so the same password doesn't give the same hash.
+        accounts.set("bob", "correct horse").await.unwrap();
+        let all = accounts.accounts.lock();
+        assert_ne!(all["alice"].hash, all["bob"].hash);
+    }
+
+    #[tokio::test]
+    async fn takes_each_code_once() {
+        let accounts = Arc::new(Accounts::new(FAST));
+        assert_eq!(accounts.start_two_factor("alice"), None);
+        accounts.set("alice", "correct horse").await.unwrap();
+        let secret = accounts.start_two_factor("alice").unwrap();
+        assert_eq!(secret.len(), totp::SECRET_LEN);
+        let now = 1_700_000_000;
+        let code = |at: u64| format!("{:06}", totp::hotp(&secret, at / totp::STEP));
+
+        // Not on until a code confirms it.
+        assert!(!accounts.has_two_factor("alice"));
+        assert!(!accounts.confirm_two_factor("alice", "000000x", now));
+        assert!(accounts.confirm_two_factor("alice", &code(now), now));
+        assert!(accounts.has_two_factor("alice"));
+
+        // The confirming code, and any from before, are spent.
+        assert!(!accounts.check_code("alice", &code(now), now));
+        assert!(!accounts.check_code("alice", &code(now - totp::STEP), now));
+        let later = now + totp::STEP;
+        assert!(accounts.check_code("alice", &code(later), later));
+        assert!(!accounts.check_code("alice", &code(later), later));
+
+        let later = later + totp::STEP;
+        assert!(!accounts.disable_two_factor("alice", "123", later));
+        assert!(accounts.disable_two_factor("alice", &code(later), later));
+        assert!(!accounts.has_two_factor("alice"));
+    }
+
+    #[tokio::test]
+    async fn challenges_new_names() {
+        let accounts = Arc::new(Accounts { challenge_bits: 8, ..Accounts::new(FAST) });
+        let now = Instant::now();
+        assert_eq!(accounts.challenge("alice", "short", now).err(), Some(AccountDenied::TooShort));
+        let challenge = accounts.challenge("alice", "correct horse", now).unwrap().unwrap();
+        let n = protocol::pow::solve(&challenge.nonce, "alice", challenge.bits);
+        let again = accounts.challenge("alice", "correct horse", now).unwrap().unwrap();
+        assert_ne!(again.nonce, challenge.nonce);
+        assert_eq!(again.answer("alice", n, now + CHALLENGE_TTL), Err("the challenge has expired"));
+        assert_eq!(challenge.answer("alice", n, now).as_deref(), Ok("correct horse"));
+
+        // Changing a password takes none.
+        accounts.set("alice", "correct horse").await.unwrap();
+        assert!(accounts.challenge("alice", "battery staple", now).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn migrates_old_credentials() {
+        let file = std::env::temp_dir().join(format!("rustchat-accounts-{}.tsv", std::process::id()));
+        let old = Params { memory_kib: 32, ..FAST };
+        // A line from before two-factor, and one with it.
+        let bob = argon2::hash_encoded(b"battery staple", b"saltsalt", old);
+        fs::write(&file, format!("{HEADER}\nalice\tplain:correct horse\nbob\t{bob}\tGEZDGNBVGY3TQOJQ\n")).unwrap();
+        let accounts = Arc::new(Accounts::load(&AccountsConfig { file: Some(file.clone()), params: FAST, challenge_bits: 0 }, None).unwrap());
+
+        // Plain text is hashed straight away.
+        let saved = fs::read_to_string(&file).unwrap();
+        assert!(!saved.contains("plain:") && !saved.contains("correct horse"));
+        assert!(accounts.verify("alice", "correct horse").await);
+
+        // Old parameters are replaced on login.
+        assert!(!accounts.verify("bob", "wrong").await);
+        assert_eq!(argon2::params_of(&accounts.accounts.lock()["bob"].hash), Some(old));
+        assert!(accounts.verify("bob", "battery staple").await);
+        assert_eq!(argon2::params_of(&accounts.accounts.lock()["bob"].hash), Some(FAST));
+        let reloaded = Arc::new(Accounts::load(&AccountsConfig { file: Some(file.clone()), params: FAST, challenge_bits: 0 }, None).unwrap());
+        assert!(reloaded.verify("bob", "battery staple").await);
+        assert!(reloaded.has_two_factor("bob") && !reloaded.has_two_factor("alice"));
+
+        // A signing key goes in the fourth column, with or without a secret.
+        let key = ed25519::public_key(&[7; 32]);
+        assert_eq!(parse_key(&hex(&key)), Some(key));
+        assert_eq!(parse_key("not hex"), None);
+        assert!(!reloaded.set_signing_key("carol", Some(key)));
+        assert!(reloaded.set_signing_key("alice", Some(key)) && reloaded.set_signing_key("bob", Some(key)));
+        let reloaded = Accounts::load(&AccountsConfig { file: Some(file.clone()), params: FAST, challenge_bits: 0 }, None).unwrap();
+        assert_eq!(reloaded.signing_key("alice"), Some(key));
+        assert!(reloaded.has_two_factor("bob") && !reloaded.has_two_factor("alice"));
+        assert!(reloaded.set_signing_key("alice", None));
+        assert_eq!(reloaded.signing_key("alice"), None);
+
+        // With a key, the file is sealed straight away, and can't be read
+        // without it.
+        let key = Key::parse(&"a1".repeat(32));
+        let config = AccountsConfig { file: Some(file.clone()), params: FAST, challenge_bits: 0 };
+        let sealed = Accounts::load(&config, key).unwrap();
+        let data = fs::read(&file).unwrap();
+        assert!(at_rest::is_sealed(&data) && !String::from_utf8_lossy(&data).contains("alice"));
+        assert!(sealed.has_two_factor("bob"));
+        assert!(Accounts::load(&config, None).is_err());
+        assert!(Accounts::load(&config, key).unwrap().has_two_factor("bob"));
+
+        fs::write(&file, "alice\tnot a hash\n").unwrap();
+        let Err(e) = Accounts::load(&AccountsConfig { file: Some(file.clone()), params: FAST, challenge_bits: 0 }, None) else { panic!("loaded a bad line") };
+        assert!(e.to_string().ends_with(":1: not an account"));
+        fs::remove_file(&file).unwrap();
+    }
+}
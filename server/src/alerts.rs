@@ -0,0 +1,150 @@
+//! Warns operators about slow consumers before they stall anything: a
+//! client whose queue has stayed at `[alerts] slow_queue_depth` or more for
+//! `slow_after` is logged as `[SLOW]` with who it is, and if
+//! `[alerts] webhook` is set the same goes there as a JSON object. Each
+//! stretch of slowness is reported once; the queue has to drop below the
+//! depth before the client can be reported again.
+
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::Arc};
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+use protocol::json::quote;
+
+use crate::{
+    config::AlertsConfig,
+    connections::{Connections, Snapshot},
+    http,
+    info,
+    warn,
+};
+
+/// How often queues are looked at.
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowConsumer {
+    pub id: u64,
+    pub name: String,
+    pub addr: SocketAddr,
+    pub queued: usize,
+    /// How long the queue has been over the depth.
+    pub over_for: Duration,
+}
+
+impl SlowConsumer {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"event\":\"slow_consumer\",\"client_id\":{},\"nick\":{},\"addr\":{},\"queued\":{},\"over_for_secs\":{}}}",
+            self.id,
+            quote(&self.name),
+            quote(&self.addr.to_string()),
+            self.queued,
+            self.over_for.as_secs()
+        )
+    }
+}
+
+impl fmt::Display for SlowConsumer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}, {}) has had a backed-up queue for {}s, {} events now", self.name, self.id, self.addr, self.over_for.as_secs(), self.queued)
+    }
+}
+
+/// Which queues are over the depth, and since when.
+pub struct Watch {
+    config: AlertsConfig,
+    /// By connection ID: when it went over, and whether it's been reported.
+    over: HashMap<u64, (Instant, bool)>,
+}
+
+impl Watch {
+    pub fn new(config: AlertsConfig) -> Self {
+        Watch { config, over: HashMap::new() }
+    }
+
+    /// The connections in `now`'s list that have just been slow for long
+    /// enough.
+    pub fn check(&mut self, connections: &[Snapshot], now: Instant) -> Vec<SlowConsumer> {
+        let mut slow = Vec::new();
+        let mut over = HashMap::new();
+        for c in connections {
+            let Some(name) = c.name.as_ref().filter(|_| c.queued >= self.config.slow_queue_depth) else {
+                continue;
+            };
+            let (since, mut reported) = self.over.get(&c.id).copied().unwrap_or((now, false));
+            if !reported && now.duration_since(since) >= self.config.slow_after {
+                slow.push(SlowConsumer { id: c.id, name: name.clone(), addr: c.addr, queued: c.queued, over_for: now.duration_since(since) });
+                reported = true;
+            }
+            over.insert(c.id, (since, reported));
+        }
+        self.over = over;
+        slow
+    }
+}
+
+/// Looks at every queue each second for as long as the server runs.
+pub async fn run(config: AlertsConfig, connections: Arc<Connections>) {
+    if config.slow_queue_depth == 0 {
+        return;
+    }
+    let webhook = config.webhook.clone();
+    let mut watch = Watch::new(config);
+    let mut ticks = interval(CHECK_EVERY);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let now = Instant::now();
+        for slow in watch.check(&connections.list(now), now) {
+            info!("SLOW", client_id = slow.id, addr = slow.addr, queued = slow.queued; "{slow}");
+            if let Some(url) = webhook.clone() {
+                // Another alert shouldn't wait on a slow webhook.
+                tokio::spawn(async move {
+                    if let Err(e) = http::post_json(&url, &slow.to_json()).await {
+                        warn!("SLOW", "couldn't send the alert for {}: {e}", slow.name);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(id: u64, queued: usize) -> Snapshot {
+        Snapshot {
+            id,
+            name: Some(format!("user{id}")),
+            addr: "192.0.2.4:51234".parse().unwrap(),
+            queued,
+            bytes_in: 0,
+            bytes_out: 0,
+            idle: Duration::ZERO,
+            up: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn reports_queues_that_stay_deep() {
+        let mut watch = Watch::new(AlertsConfig { slow_queue_depth: 10, slow_after: Duration::from_secs(5), webhook: None });
+        let now = Instant::now();
+        let at = |secs| now + Duration::from_secs(secs);
+        assert!(watch.check(&[snapshot(1, 12), snapshot(2, 3)], now).is_empty());
+        assert!(watch.check(&[snapshot(1, 40), snapshot(2, 3)], at(4)).is_empty());
+        let slow = watch.check(&[snapshot(1, 40), snapshot(2, 3)], at(5));
+        assert_eq!(slow, [SlowConsumer { id: 1, name: "user1".into(), addr: "192.0.2.4:51234".parse().unwrap(), queued: 40, over_for: Duration::from_secs(5) }]);
+        assert_eq!(slow[0].to_string(), "user1 (1, 192.0.2.4:51234) has had a backed-up queue for 5s, 40 events now");
+        assert_eq!(
+            slow[0].to_json(),
+            "{\"event\":\"slow_consumer\",\"client_id\":1,\"nick\":\"user1\",\"addr\":\"192.0.2.4:51234\",\"queued\":40,\"over_for_secs\":5}"
+        );
+
+        // Once per stretch; catching up starts the count again.
+        assert!(watch.check(&[snapshot(1, 40)], at(9)).is_empty());
+        assert!(watch.check(&[snapshot(1, 2)], at(10)).is_empty());
+        assert!(watch.check(&[snapshot(1, 40)], at(11)).is_empty());
+        assert_eq!(watch.check(&[snapshot(1, 40)], at(16)).len(), 1);
+    }
+}
@@ -0,0 +1,398 @@
+//! Argon2id (RFC 9106) and the BLAKE2b (RFC 7693) it's built on, for
+//! account passwords. Lanes are filled one after another rather than in
+//! parallel, which gives the same hash; `p` still matters for
+//! interoperability. Hashes are kept as PHC strings,
+//! `$argon2id$v=19$m=<KiB>,t=<passes>,p=<lanes>$<salt>$<hash>`, with
+//! unpadded base64.
+
+use protocol::base64;
+
+/// Bytes of hash kept.
+const TAG_LEN: usize = 32;
+const VERSION: u32 = 0x13;
+/// Argon2id's type number.
+const ARGON2ID: u32 = 2;
+/// 1 KiB blocks, as words.
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    /// Memory, in KiB.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Params {
+    /// Whether Argon2 accepts these.
+    pub fn valid(self) -> bool {
+        (1..=0xff_ffff).contains(&self.parallelism) && self.iterations >= 1 && self.memory_kib >= 8 * self.parallelism
+    }
+}
+
+impl Default for Params {
+    /// OWASP's suggestion for Argon2id.
+    fn default() -> Self {
+        Params { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+    }
+}
+
+/// `password` hashed with `salt` as a PHC string.
+pub fn hash_encoded(password: &[u8], salt: &[u8], params: Params) -> String {
+    let mut tag = [0; TAG_LEN];
+    argon2id(params, password, salt, &[], &[], &mut tag);
+    format!(
+        "$argon2id$v={VERSION}$m={},t={},p={}${}${}",
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        base64::encode(salt).trim_end_matches('='),
+        base64::encode(&tag).trim_end_matches('=')
+    )
+}
+
+/// The parameters a PHC string was made with, if it's one of ours.
+pub fn params_of(encoded: &str) -> Option<Params> {
+    Some(decode(encoded)?.0)
+}
+
+/// Whether `password` is the one `encoded` was made from, in time that
+/// doesn't depend on how much of the hash matches.
+pub fn verify(encoded: &str, password: &[u8]) -> bool {
+    let Some((params, salt, expected)) = decode(encoded) else {
+        return false;
+    };
+    let mut tag = vec![0; expected.len()];
+    argon2id(params, password, &salt, &[], &[], &mut tag);
+    constant_time_eq(&tag, &expected)
+}
+
+fn decode(encoded: &str) -> Option<(Params, Vec<u8>, Vec<u8>)> {
+    let mut parts = encoded.strip_prefix("$argon2id$")?.split('$');
+    if parts.next()? != format!("v={VERSION}") {
+        return None;
+    }
+    let mut params = Params { memory_kib: 0, iterations: 0, parallelism: 0 };
+    for field in parts.next()?.split(',') {
+        let (key, value) = field.split_once('=')?;
+        let value = value.parse().ok()?;
+        match key {
+            "m" => params.memory_kib = value,
+            "t" => params.iterations = value,
+            "p" => params.parallelism = value,
+            _ => return None,
+        }
+    }
+    let (salt, tag) = (unpadded(parts.next()?)?, unpadded(parts.next()?)?);
+    (params.valid() && salt.len() >= 8 && (4..=64).contains(&tag.len()) && parts.next().is_none()).then_some((params, salt, tag))
+}
+
+fn unpadded(text: &str) -> Option<Vec<u8>> {
+    let pad = (4 - text.len() % 4) % 4;
+    base64::decode(&format!("{text}{}", "=".repeat(pad)))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Argon2id with a `secret` key and associated data `ad`, filling `out`.
+fn argon2id(params: Params, password: &[u8], salt: &[u8], secret: &[u8], ad: &[u8], out: &mut [u8]) {
+    let lanes = params.parallelism;
+    let mut h0 = Blake2b::new(64);
+    for n in [lanes, out.len() as u32, params.memory_kib, params.iterations, VERSION, ARGON2ID] {
+        h0.update(&n.to_le_bytes());
+    }
+    for input in [password, salt, secret, ad] {
+        h0.update(&(input.len() as u32).to_le_bytes());
+        h0.update(input);
+    }
+    let h0 = h0.finalize();
+
+    let lanes = lanes as usize;
+    let segment = (params.memory_kib / (SYNC_POINTS * params.parallelism)) as usize;
+    let lane_len = segment * SYNC_POINTS as usize;
+    let mut memory = vec![[0u64; BLOCK_WORDS]; lane_len * lanes];
+    for lane in 0..lanes {
+        for i in 0..2 {
+            let mut bytes = [0; BLOCK_WORDS * 8];
+            hash_long(&[&h0, &(i as u32).to_le_bytes(), &(lane as u32).to_le_bytes()], &mut bytes);
+            memory[lane * lane_len + i] = block_from_bytes(&bytes);
+        }
+    }
+
+    for pass in 0..params.iterations {
+        for slice in 0..SYNC_POINTS as usize {
+            for lane in 0..lanes {
+                fill_segment(&mut memory, params, pass, slice, lane, segment, lane_len);
+            }
+        }
+    }
+
+    let mut last = memory[lane_len - 1];
+    for lane in 1..lanes {
+        xor_into(&mut last, &memory[lane * lane_len + lane_len - 1]);
+    }
+    let bytes: Vec<u8> = last.iter().flat_map(|w| w.to_le_bytes()).collect();
+    hash_long(&[&bytes], out);
+}
+
+fn fill_segment(memory: &mut [[u64; BLOCK_WORDS]], params: Params, pass: u32, slice: usize, lane: usize, segment: usize, lane_len: usize) {
+    let lanes = params.parallelism as usize;
+    let independent = pass == 0 && slice < 2;
+    let zero = [0u64; BLOCK_WORDS];
+    let mut input = [0u64; BLOCK_WORDS];
+    let mut addresses = [0u64; BLOCK_WORDS];
+    if independent {
+        input[..6].copy_from_slice(&[u64::from(pass), lane as u64, slice as u64, memory.len() as u64, u64::from(params.iterations), u64::from(ARGON2ID)]);
+    }
+    let next_addresses = |input: &mut [u64; BLOCK_WORDS], addresses: &mut [u64; BLOCK_WORDS]| {
+        input[6] += 1;
+        let first = compress(&zero, input);
+        *addresses = compress(&zero, &first);
+    };
+
+    let start = if pass == 0 && slice == 0 { 2 } else { 0 };
+    if independent && start == 2 {
+        next_addresses(&mut input, &mut addresses);
+    }
+    for index in start..segment {
+        let current = lane * lane_len + slice * segment + index;
+        let prev = if current.is_multiple_of(lane_len) { current + lane_len - 1 } else { current - 1 };
+        let random = if independent {
+            if index.is_multiple_of(BLOCK_WORDS) {
+                next_addresses(&mut input, &mut addresses);
+            }
+            addresses[index % BLOCK_WORDS]
+        } else {
+            memory[prev][0]
+        };
+        let ref_lane = if pass == 0 && slice == 0 { lane } else { (random >> 32) as usize % lanes };
+        let same_lane = ref_lane == lane;
+        let area = match (pass, same_lane) {
+            (0, _) if slice == 0 => index - 1,
+            (0, true) => slice * segment + index - 1,
+            (0, false) => slice * segment - usize::from(index == 0),
+            (_, true) => lane_len - segment + index - 1,
+            (_, false) => lane_len - segment - usize::from(index == 0),
+        } as u64;
+        let j1 = random & 0xffff_ffff;
+        let relative = area - 1 - ((area * ((j1 * j1) >> 32)) >> 32);
+        let start_position = if pass == 0 || slice == 3 { 0 } else { (slice + 1) * segment };
+        let reference = ref_lane * lane_len + (start_position + relative as usize) % lane_len;
+
+        let block = compress(&memory[prev], &memory[reference]);
+        if pass == 0 {
+            memory[current] = block;
+        } else {
+            xor_into(&mut memory[current], &block);
+        }
+    }
+}
+
+/// Argon2's compression function G.
+fn compress(x: &[u64; BLOCK_WORDS], y: &[u64; BLOCK_WORDS]) -> [u64; BLOCK_WORDS] {
+    let mut r = *x;
+    xor_into(&mut r, y);
+    let mut z = r;
+    for row in 0..8 {
+        let mut v: [usize; 16] = std::array::from_fn(|i| row * 16 + i);
+        permute(&mut z, &mut v);
+    }
+    for column in 0..8 {
+        let mut v: [usize; 16] = std::array::from_fn(|i| (i / 2) * 16 + column * 2 + i % 2);
+        permute(&mut z, &mut v);
+    }
+    xor_into(&mut z, &r);
+    z
+}
+
+/// The BLAKE2b round Argon2 uses, without a message, on the words of `z` at `v`.
+fn permute(z: &mut [u64; BLOCK_WORDS], v: &mut [usize; 16]) {
+    fn gb(z: &mut [u64; BLOCK_WORDS], a: usize, b: usize, c: usize, d: usize) {
+        let mul = |x: u64, y: u64| 2u64.wrapping_mul(x & 0xffff_ffff).wrapping_mul(y & 0xffff_ffff);
+        z[a] = z[a].wrapping_add(z[b]).wrapping_add(mul(z[a], z[b]));
+        z[d] = (z[d] ^ z[a]).rotate_right(32);
+        z[c] = z[c].wrapping_add(z[d]).wrapping_add(mul(z[c], z[d]));
+        z[b] = (z[b] ^ z[c]).rotate_right(24);
+        z[a] = z[a].wrapping_add(z[b]).wrapping_add(mul(z[a], z[b]));
+        z[d] = (z[d] ^ z[a]).rotate_right(16);
+        z[c] = z[c].wrapping_add(z[d]).wrapping_add(mul(z[c], z[d]));
+        z[b] = (z[b] ^ z[c]).rotate_right(63);
+    }
+    for [a, b, c, d] in [[0, 4, 8, 12], [1, 5, 9, 13], [2, 6, 10, 14], [3, 7, 11, 15], [0, 5, 10, 15], [1, 6, 11, 12], [2, 7, 8, 13], [3, 4, 9, 14]] {
+        gb(z, v[a], v[b], v[c], v[d]);
+    }
+}
+
+fn xor_into(a: &mut [u64; BLOCK_WORDS], b: &[u64; BLOCK_WORDS]) {
+    for (x, y) in a.iter_mut().zip(b) {
+        *x ^= y;
+    }
+}
+
+fn block_from_bytes(bytes: &[u8]) -> [u64; BLOCK_WORDS] {
+    std::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()))
+}
+
+/// Argon2's variable-length hash H', of `inputs` one after another.
+fn hash_long(inputs: &[&[u8]], out: &mut [u8]) {
+    let mut first = Blake2b::new(out.len().min(64));
+    first.update(&(out.len() as u32).to_le_bytes());
+    for input in inputs {
+        first.update(input);
+    }
+    let mut v = first.finalize();
+    if out.len() <= 64 {
+        out.copy_from_slice(&v);
+        return;
+    }
+    let mut at = 0;
+    while out.len() - at > 64 {
+        out[at..at + 32].copy_from_slice(&v[..32]);
+        at += 32;
+        let mut next = Blake2b::new((out.len() - at).min(64));
+        next.update(&v);
+        v = next.finalize();
+    }
+    out[at..].copy_from_slice(&v);
+}
+
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// Unkeyed BLAKE2b with up to 64 bytes of output.
+struct Blake2b {
+    h: [u64; 8],
+    /// Bytes compressed so far.
+    t: u128,
+    buf: [u8; 128],
+    /// Bytes in `buf`; a full one waits in case it's the last.
+    len: usize,
+    out_len: usize,
+}
+
+impl Blake2b {
+    fn new(out_len: usize) -> Self {
+        let mut h = IV;
+        h[0] ^= 0x0101_0000 ^ out_len as u64;
+        Blake2b { h, t: 0, buf: [0; 128], len: 0, out_len }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.len == 128 {
+                self.t += 128;
+                self.compress(false);
+                self.len = 0;
+            }
+            let take = (128 - self.len).min(data.len());
+            self.buf[self.len..self.len + take].copy_from_slice(&data[..take]);
+            self.len += take;
+            data = &data[take..];
+        }
+    }
+
+    fn finalize(mut self) -> Vec<u8> {
+        self.t += self.len as u128;
+        self.buf[self.len..].fill(0);
+        self.compress(true);
+        self.h.iter().flat_map(|w| w.to_le_bytes()).take(self.out_len).collect()
+    }
+
+    fn compress(&mut self, last: bool) {
+        let m: [u64; 16] = std::array::from_fn(|i| u64::from_le_bytes(self.buf[i * 8..i * 8 + 8].try_into().unwrap()));
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.t as u64;
+        v[13] ^= (self.t >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        for round in 0..12 {
+            let s = &SIGMA[round % 10];
+            let mut g = |a: usize, b: usize, c: usize, d: usize, x: u64, y: u64| {
+                v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+                v[d] = (v[d] ^ v[a]).rotate_right(32);
+                v[c] = v[c].wrapping_add(v[d]);
+                v[b] = (v[b] ^ v[c]).rotate_right(24);
+                v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+                v[d] = (v[d] ^ v[a]).rotate_right(16);
+                v[c] = v[c].wrapping_add(v[d]);
+                v[b] = (v[b] ^ v[c]).rotate_right(63);
+            };
+            g(0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn blake2b_matches_rfc_7693() {
+        let mut h = Blake2b::new(64);
+        h.update(b"abc");
+        assert_eq!(
+            hex(&h.finalize()),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d17d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+    }
+
+    #[test]
+    fn argon2id_matches_rfc_9106() {
+        let params = Params { memory_kib: 32, iterations: 3, parallelism: 4 };
+        let mut tag = [0; 32];
+        argon2id(params, &[1; 32], &[2; 16], &[3; 8], &[4; 12], &mut tag);
+        assert_eq!(hex(&tag), "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659");
+    }
+
+    #[test]
+    fn verifies_encoded_hashes() {
+        let params = Params { memory_kib: 64, iterations: 1, parallelism: 1 };
+        let encoded = hash_encoded(b"hunter2", b"saltsaltsalt", params);
+        assert!(encoded.starts_with("$argon2id$v=19$m=64,t=1,p=1$c2FsdHNhbHRzYWx0$"));
+        assert_eq!(params_of(&encoded), Some(params));
+        assert!(verify(&encoded, b"hunter2"));
+        assert!(!verify(&encoded, b"hunter3"));
+        assert!(!verify("$argon2i$v=19$m=64,t=1,p=1$c2FsdHNhbHQ$AAAA", b"hunter2"));
+    }
+}
@@ -0,0 +1,117 @@
+//! Encryption at rest for the files that hold what people said, and who
+//! they are: scheduled DMs and reminders, snapshots, exports and accounts. With `[storage] key` set, or the
+//! `RUSTCHAT_STORAGE_KEY` environment variable, they're sealed with
+//! XChaCha20-Poly1305 (`protocol::xchacha`), so a copy of the data directory
+//! doesn't give conversations away. Message history itself is only ever in
+//! memory.
+//!
+//! A sealed file is `MAGIC`, a random nonce, then the sealed text, with
+//! `MAGIC` as associated data. Plain files are still read, and sealed the
+//! next time they're written; a sealed file without the key, or with
+//! another one, is an error rather than nothing. `server unseal <file>`
+//! prints one.
+
+use anyhow::{anyhow, bail, Result};
+use std::{fmt, fs, io::Write, path::Path};
+
+use protocol::xchacha::{self, KEY_LEN, NONCE_LEN};
+
+pub const MAGIC: &[u8] = b"rustchat sealed v1\n";
+/// Where the key is looked for when the config doesn't have one.
+pub const KEY_ENV: &str = "RUSTCHAT_STORAGE_KEY";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    /// A key from its 64 hex digits.
+    pub fn parse(hex: &str) -> Option<Key> {
+        crate::accounts::parse_hex(hex.trim()).map(Key)
+    }
+}
+
+// Keys stay out of logs and panics.
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// What to write for `text`: sealed with `key`, or as it is without one.
+pub fn seal(key: Option<&Key>, text: &str) -> Result<Vec<u8>> {
+    let Some(key) = key else {
+        return Ok(text.as_bytes().to_vec());
+    };
+    let nonce: [u8; NONCE_LEN] = xchacha::random()?;
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&nonce);
+    out.extend(xchacha::seal(&key.0, &nonce, MAGIC, text.as_bytes()));
+    Ok(out)
+}
+
+/// The text of a file `seal` wrote, sealed or not.
+pub fn open(key: Option<&Key>, data: &[u8]) -> Result<String> {
+    let text = match data.strip_prefix(MAGIC) {
+        Some(sealed) => {
+            let Some(key) = key else {
+                bail!("it's encrypted; set [storage] key or {KEY_ENV}");
+            };
+            let (nonce, sealed) = sealed.split_at_checked(NONCE_LEN).ok_or_else(|| anyhow!("it's cut short"))?;
+            xchacha::open(&key.0, nonce.try_into().unwrap(), MAGIC, sealed).ok_or_else(|| anyhow!("it was encrypted with another key, or has been changed"))?
+        }
+        None => data.to_vec(),
+    };
+    String::from_utf8(text).map_err(|_| anyhow!("it isn't UTF-8"))
+}
+
+/// Replaces `file` with `data` in one step, readable by the server's user
+/// only: written beside it, flushed to disk and renamed over it, so a crash
+/// leaves either the old file or the new one and never part of either.
+pub fn replace(file: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = file.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut out = options.open(&tmp)?;
+    out.write_all(data)?;
+    out.sync_all()?;
+    fs::rename(&tmp, file)?;
+    sync_dir(file)
+}
+
+/// Makes a rename or new file in `file`'s directory survive a power cut.
+pub fn sync_dir(file: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")))?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_and_opens() {
+        let key = Key::parse(&"a7".repeat(KEY_LEN)).unwrap();
+        let sealed = seal(Some(&key), "alice\tbob\thi").unwrap();
+        assert!(is_sealed(&sealed) && !sealed.windows(2).any(|w| w == b"hi"));
+        assert_eq!(open(Some(&key), &sealed).unwrap(), "alice\tbob\thi");
+        // Fresh nonces each time.
+        assert_ne!(seal(Some(&key), "alice\tbob\thi").unwrap(), sealed);
+
+        // Plain text passes through either way.
+        assert_eq!(seal(None, "hi").unwrap(), b"hi");
+        assert_eq!(open(Some(&key), b"hi").unwrap(), "hi");
+
+        let other = Key::parse(&"b8".repeat(KEY_LEN)).unwrap();
+        assert!(open(Some(&other), &sealed).unwrap_err().to_string().contains("another key"));
+        assert!(open(None, &sealed).unwrap_err().to_string().contains("encrypted"));
+        assert!(open(Some(&key), &sealed[..MAGIC.len() + 4]).is_err());
+        assert_eq!(Key::parse("abc"), None);
+    }
+}
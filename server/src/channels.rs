@@ -0,0 +1,179 @@
+//! Channel membership for one connection. Each channel is a broadcast queue
+//! owned by the registry; a member forwards it into its own client queue, so
+//! a SAY costs the sender one send however many members there are. Members
+//! share the one `Arc<Event>` rather than each getting a copy.
+
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::Arc,
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+
+use protocol::{Dnd, Event};
+
+use crate::{queue::ClientTx, registry::MAX_HELD};
+
+/// The channels a connection has joined. Leaving, or dropping the whole set
+/// when the connection ends, stops the forwarding tasks.
+pub struct Memberships {
+    joined: HashMap<String, JoinHandle<()>>,
+    filter: Arc<Filter>,
+}
+
+/// Channel messages that mention `me` from someone `me` blocks aren't
+/// forwarded. In do-not-disturb the rest are held, bar mentions if those are
+/// let through.
+struct Filter {
+    me: String,
+    blocked: RwLock<BTreeSet<String>>,
+    hold: Mutex<Hold>,
+}
+
+#[derive(Default)]
+struct Hold {
+    dnd: Dnd,
+    held: VecDeque<Arc<Event>>,
+    /// Held messages pushed out by newer ones.
+    dropped: u64,
+}
+
+impl Filter {
+    /// `event` if it's to be forwarded now.
+    fn pass(&self, event: Arc<Event>) -> Option<Arc<Event>> {
+        let Event::ChannelMsg { from, body, .. } = &*event else {
+            return Some(event);
+        };
+        let mentioned = || mentions(body, &self.me);
+        if self.blocked.read().contains(from) && mentioned() {
+            return None;
+        }
+        let mut hold = self.hold.lock();
+        if hold.dnd.on && !(hold.dnd.mentions && mentioned()) {
+            if hold.held.len() >= MAX_HELD {
+                hold.held.pop_front();
+                hold.dropped += 1;
+            }
+            hold.held.push_back(event);
+            return None;
+        }
+        Some(event)
+    }
+}
+
+impl Memberships {
+    /// For the connection of `me`, who blocks `blocked`.
+    pub fn new(me: &str, blocked: BTreeSet<String>) -> Self {
+        let filter = Filter { me: me.to_string(), blocked: RwLock::new(blocked), hold: Mutex::default() };
+        Memberships { joined: HashMap::new(), filter: Arc::new(filter) }
+    }
+
+    /// Turns do-not-disturb on or off. Turning it off returns what was held
+    /// meanwhile, for the caller to queue.
+    pub fn set_dnd(&self, dnd: Dnd) -> Vec<Arc<Event>> {
+        let mut hold = self.filter.hold.lock();
+        hold.dnd = dnd;
+        if dnd.on {
+            return Vec::new();
+        }
+        let dropped = std::mem::take(&mut hold.dropped);
+        let gap = (dropped > 0).then(|| Arc::new(Event::notice(format!("{dropped} channel messages dropped while in do-not-disturb"))));
+        gap.into_iter().chain(hold.held.drain(..)).collect()
+    }
+
+    /// Takes effect in every joined channel straight away.
+    pub fn set_blocked(&self, blocked: BTreeSet<String>) {
+        *self.filter.blocked.write() = blocked;
+    }
+
+    pub fn contains(&self, channel: &str) -> bool {
+        self.joined.contains_key(channel)
+    }
+
+    /// The joined channels, in no particular order.
+    pub fn channels(&self) -> Vec<String> {
+        self.joined.keys().cloned().collect()
+    }
+
+    pub fn join(&mut self, channel: &str, sub: broadcast::Receiver<Arc<Event>>, tx: ClientTx) {
+        let task = forward(sub, tx, channel.to_string(), self.filter.clone());
+        if let Some(old) = self.joined.insert(channel.to_string(), task) {
+            old.abort();
+        }
+    }
+
+    /// `false` if the connection wasn't in `channel`.
+    pub fn part(&mut self, channel: &str) -> bool {
+        self.joined.remove(channel).map(|task| task.abort()).is_some()
+    }
+}
+
+impl Drop for Memberships {
+    fn drop(&mut self) {
+        for task in self.joined.values() {
+            task.abort();
+        }
+    }
+}
+
+/// A member that falls behind skips what it missed, with a notice, rather than
+/// holding the channel back.
+fn forward(mut sub: broadcast::Receiver<Arc<Event>>, tx: ClientTx, channel: String, filter: Arc<Filter>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let event = match sub.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => Arc::new(Event::notice(format!("missed {n} messages in {channel}"))),
+                Err(RecvError::Closed) => break,
+            };
+            let Some(event) = filter.pass(event) else {
+                continue;
+            };
+            if tx.push(event).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Whether `body` has `name` in it as a whole word, ignoring case.
+fn mentions(body: &str, name: &str) -> bool {
+    let (body, name) = (body.to_lowercase(), name.to_lowercase());
+    let is_word_char = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    body.match_indices(&name)
+        .any(|(i, _)| !is_word_char(body[..i].chars().next_back()) && !is_word_char(body[i + name.len()..].chars().next()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(from: &str, body: &str) -> Arc<Event> {
+        Arc::new(Event::ChannelMsg { channel: "#x".into(), id: 1, ts: String::new(), from: from.into(), from_id: 2, body: body.into() })
+    }
+
+    #[test]
+    fn drops_mentions_from_the_blocked() {
+        let memberships = Memberships::new("alice", BTreeSet::from(["mallory".to_string()]));
+        let filter = &memberships.filter;
+        assert!(filter.pass(msg("mallory", "hey Alice!")).is_none());
+        assert!(filter.pass(msg("mallory", "hey everyone")).is_some());
+        assert!(filter.pass(msg("mallory", "alicemania")).is_some());
+        assert!(filter.pass(msg("bob", "hey alice")).is_some());
+    }
+
+    #[test]
+    fn holds_messages_in_dnd() {
+        let memberships = Memberships::new("alice", BTreeSet::new());
+        let filter = &memberships.filter;
+        assert!(memberships.set_dnd(Dnd { on: true, mentions: true, ..Dnd::default() }).is_empty());
+        assert!(filter.pass(msg("bob", "hello all")).is_none());
+        assert!(filter.pass(msg("bob", "alice, look")).is_some());
+        assert!(filter.pass(Arc::new(Event::notice("missed 3 messages in #x"))).is_some());
+        assert_eq!(memberships.set_dnd(Dnd::default()), [msg("bob", "hello all")]);
+        assert!(filter.pass(msg("bob", "hello again")).is_some());
+    }
+}
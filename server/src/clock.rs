@@ -0,0 +1,124 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parses `YYYY-MM-DD` into unix seconds at 00:00 UTC that day.
+pub fn parse_date(s: &str) -> Option<u64> {
+    let mut p = s.splitn(3, '-');
+    let y: i64 = p.next()?.parse().ok()?;
+    let m: u32 = p.next()?.parse().ok()?;
+    let d: u32 = p.next()?.parse().ok()?;
+    if !(1..=12).contains(&m) || !(1..=days_in_month(y, m)).contains(&d) {
+        return None;
+    }
+    let days = days_from_civil(y, m, d);
+    u64::try_from(days).ok().map(|days| days * SECS_PER_DAY)
+}
+
+/// Same as `parse_date` but returns the last second of that day, for inclusive ranges.
+pub fn parse_date_end(s: &str) -> Option<u64> {
+    parse_date(s).map(|t| t + SECS_PER_DAY - 1)
+}
+
+/// Parses an RFC 3339 timestamp like `2026-10-15T18:00:00Z` or
+/// `2026-10-15T20:00:00.5+02:00` into unix seconds, dropping any fraction.
+pub fn parse_rfc3339(s: &str) -> Option<u64> {
+    let (date, time) = s.split_once(['T', 't'])?;
+    let midnight = parse_date(date)?;
+    let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let at = time.rfind(['+', '-'])?;
+            let (h, m) = time[at + 1..].split_once(':')?;
+            let (h, m): (i64, i64) = (h.parse().ok()?, m.parse().ok()?);
+            if h > 23 || m > 59 {
+                return None;
+            }
+            let offset = h * 3600 + m * 60;
+            (&time[..at], if time.as_bytes()[at] == b'-' { -offset } else { offset })
+        }
+    };
+    let time = time.split_once('.').map_or(time, |(whole, _)| whole);
+    let mut p = time.splitn(3, ':').map(str::parse::<u64>);
+    let (h, m, sec) = (p.next()?.ok()?, p.next()?.ok()?, p.next()?.ok()?);
+    if h > 23 || m > 59 || sec > 60 {
+        return None;
+    }
+    u64::try_from(midnight as i64 + (h * 3600 + m * 60 + sec) as i64 - offset).ok()
+}
+
+/// Formats unix seconds as an RFC 3339 UTC timestamp, e.g. `2026-10-15T08:30:00Z`.
+pub fn rfc3339(secs: u64) -> String {
+    let days = (secs / SECS_PER_DAY) as i64;
+    let rem = secs % SECS_PER_DAY;
+    let (y, m, d) = civil_from_days(days);
+    format!("{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}Z", rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+// Howard Hinnant's days_from_civil, proleptic Gregorian calendar.
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(y: i64, m: u32) -> u32 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// And its inverse, civil_from_days.
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(m <= 2), m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("1970-01-02"), Some(SECS_PER_DAY));
+        assert_eq!(parse_date_end("2026-10-15"), Some(1_792_108_799));
+        assert_eq!(parse_date("2024-02-29"), Some(1_709_164_800));
+        for bad in ["2026-02-29", "2026-02-31", "2026-04-31", "1900-02-29", "2026-13-01", "2026-00-10", "2026-01-00", "2026-1"] {
+            assert_eq!(parse_date(bad), None, "{bad}");
+        }
+        assert!(parse_date("2000-02-29").is_some());
+    }
+
+    #[test]
+    fn parses_rfc3339() {
+        assert_eq!(parse_rfc3339("2026-10-15T08:30:00Z"), Some(1_792_053_000));
+        assert_eq!(parse_rfc3339("2026-10-15T10:30:00.25+02:00"), Some(1_792_053_000));
+        assert_eq!(parse_rfc3339("2026-10-15t03:00:00-05:30"), Some(1_792_053_000));
+        assert_eq!(rfc3339(1_792_053_000), "2026-10-15T08:30:00Z");
+        assert_eq!(parse_rfc3339("2026-10-15T08:30:00"), None);
+        assert_eq!(parse_rfc3339("2026-10-15 08:30:00Z"), None);
+        assert_eq!(parse_rfc3339("2026-10-15T25:00:00Z"), None);
+        assert_eq!(parse_rfc3339("tomorrow"), None);
+    }
+}
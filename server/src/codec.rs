@@ -0,0 +1,311 @@
+//! Wire framing. Shaped like `tokio_util::codec` so either side can be swapped
+//! for the real thing without touching the connection handler.
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Frames (or lines) longer than this are a protocol error, not an allocation.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+pub trait Decoder {
+    type Item;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>>;
+
+    /// Called once the reader has hit EOF, for whatever `decode` left
+    /// behind; by default anything but an empty buffer is a cut-off frame.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(anyhow!("connection closed mid-frame")),
+        }
+    }
+}
+
+pub trait Encoder<I> {
+    fn encode(&mut self, item: I, dst: &mut BytesMut) -> Result<()>;
+}
+
+/// Newline-terminated frames, as spoken by the original text client. A last
+/// line without its newline still counts at EOF.
+#[derive(Clone, Copy)]
+pub struct LinesCodec {
+    max_len: usize,
+    /// How much of the buffer is already known not to hold a newline, so a
+    /// long line arriving in pieces isn't rescanned from the start each time.
+    next_index: usize,
+}
+
+impl LinesCodec {
+    pub fn new(max_len: usize) -> Self {
+        LinesCodec { max_len, next_index: 0 }
+    }
+
+    fn take_line(&mut self, src: &mut BytesMut, len: usize, skip: usize) -> Bytes {
+        self.next_index = 0;
+        let mut line = src.split_to(len + skip);
+        line.truncate(len);
+        if line.last() == Some(&b'\r') {
+            line.truncate(len - 1);
+        }
+        line.freeze()
+    }
+}
+
+impl Decoder for LinesCodec {
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        let Some(pos) = src[self.next_index..].iter().position(|b| *b == b'\n').map(|i| self.next_index + i) else {
+            if src.len() > self.max_len {
+                return Err(anyhow!("line exceeds {} bytes", self.max_len));
+            }
+            self.next_index = src.len();
+            return Ok(None);
+        };
+        if pos > self.max_len {
+            return Err(anyhow!("line exceeds {} bytes", self.max_len));
+        }
+        Ok(Some(self.take_line(src, pos, 1)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        match self.decode(src)? {
+            Some(line) => Ok(Some(line)),
+            None if src.is_empty() => Ok(None),
+            None => Ok(Some(self.take_line(src, src.len(), 0))),
+        }
+    }
+}
+
+impl Encoder<&[u8]> for LinesCodec {
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(item.len() + 1);
+        dst.put_slice(item);
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// Big-endian `u32` length followed by that many payload bytes.
+#[derive(Clone, Copy)]
+pub struct LengthCodec {
+    max_len: usize,
+}
+
+impl LengthCodec {
+    pub fn new(max_len: usize) -> Self {
+        LengthCodec { max_len }
+    }
+}
+
+impl Decoder for LengthCodec {
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len > self.max_len {
+            return Err(anyhow!("frame of {len} bytes exceeds {}", self.max_len));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        Ok(Some(src.split_to(len).freeze()))
+    }
+}
+
+impl Encoder<&[u8]> for LengthCodec {
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<()> {
+        if item.len() > self.max_len {
+            return Err(anyhow!("frame of {} bytes exceeds {}", item.len(), self.max_len));
+        }
+        dst.reserve(4 + item.len());
+        dst.put_u32(item.len() as u32);
+        dst.put_slice(item);
+        Ok(())
+    }
+}
+
+/// Either framing, picked per connection.
+#[derive(Clone, Copy)]
+pub enum Codec {
+    Lines(LinesCodec),
+    Length(LengthCodec),
+}
+
+impl Codec {
+    /// A length-prefixed client's first byte is the high byte of a small
+    /// length, i.e. zero; text handshakes never start with NUL.
+    pub fn detect(first_byte: u8) -> Self {
+        if first_byte == 0 {
+            Codec::Length(LengthCodec::new(MAX_FRAME_LEN))
+        } else {
+            Codec::Lines(LinesCodec::new(MAX_FRAME_LEN))
+        }
+    }
+
+    pub fn is_length(&self) -> bool {
+        matches!(self, Codec::Length(_))
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        match self {
+            Codec::Lines(c) => c.decode(src),
+            Codec::Length(c) => c.decode(src),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        match self {
+            Codec::Lines(c) => c.decode_eof(src),
+            Codec::Length(c) => c.decode_eof(src),
+        }
+    }
+}
+
+impl Encoder<&[u8]> for Codec {
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<()> {
+        match self {
+            Codec::Lines(c) => c.encode(item, dst),
+            Codec::Length(c) => c.encode(item, dst),
+        }
+    }
+}
+
+pub struct FramedRead<R, D> {
+    inner: R,
+    codec: D,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin, D: Decoder> FramedRead<R, D> {
+    pub fn new(inner: R, codec: D) -> Self {
+        FramedRead { inner, codec, buf: BytesMut::with_capacity(4096) }
+    }
+
+    /// Next complete frame, or `None` on a clean EOF between frames.
+    pub async fn next_frame(&mut self) -> Result<Option<D::Item>> {
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buf)? {
+                return Ok(Some(frame));
+            }
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                return self.codec.decode_eof(&mut self.buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lines() {
+        let mut codec = LinesCodec::new(16);
+        let mut buf = BytesMut::from(&b"NICK al"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"ice\r\nPING\n\nPA");
+        assert_eq!(codec.decode(&mut buf).unwrap().as_deref(), Some(&b"NICK alice"[..]));
+        assert_eq!(codec.decode(&mut buf).unwrap().as_deref(), Some(&b"PING"[..]));
+        assert_eq!(codec.decode(&mut buf).unwrap().as_deref(), Some(&b""[..]));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"PA");
+
+        let mut out = BytesMut::new();
+        codec.encode(b"QUIT", &mut out).unwrap();
+        assert_eq!(&out[..], b"QUIT\n");
+    }
+
+    #[test]
+    fn refuses_long_lines() {
+        let mut codec = LinesCodec::new(4);
+        assert_eq!(codec.decode(&mut BytesMut::from(&b"PING\n"[..])).unwrap().as_deref(), Some(&b"PING"[..]));
+        assert!(codec.decode(&mut BytesMut::from(&b"PINGS\n"[..])).is_err());
+        // Without waiting for the newline.
+        assert!(codec.decode(&mut BytesMut::from(&b"PINGS"[..])).is_err());
+    }
+
+    #[test]
+    fn keeps_a_last_line_without_its_newline() {
+        let mut codec = LinesCodec::new(16);
+        let mut buf = BytesMut::from(&b"PING\nQUIT bye\r"[..]);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().as_deref(), Some(&b"PING"[..]));
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().as_deref(), Some(&b"QUIT bye"[..]));
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+        // A cut-off length-prefixed frame is still an error.
+        assert!(LengthCodec::new(16).decode_eof(&mut BytesMut::from(&[0, 0, 0, 5, b'h'][..])).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_lines_to_eof() {
+        let mut frames = FramedRead::new(&b"NICK alice\nQUIT"[..], LinesCodec::new(16));
+        assert_eq!(frames.next_frame().await.unwrap().as_deref(), Some(&b"NICK alice"[..]));
+        assert_eq!(frames.next_frame().await.unwrap().as_deref(), Some(&b"QUIT"[..]));
+        assert_eq!(frames.next_frame().await.unwrap(), None);
+    }
+
+    #[test]
+    fn scans_each_byte_once() {
+        let mut codec = LinesCodec::new(16);
+        let mut buf = BytesMut::new();
+        for &b in b"NICK alice" {
+            buf.put_u8(b);
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+            assert_eq!(codec.next_index, buf.len());
+        }
+        buf.extend_from_slice(b"\nPI");
+        assert_eq!(codec.decode(&mut buf).unwrap().as_deref(), Some(&b"NICK alice"[..]));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.next_index, 2);
+    }
+
+    #[test]
+    fn splits_frames() {
+        let mut codec = LengthCodec::new(16);
+        let mut buf = BytesMut::new();
+        codec.encode(b"hello", &mut buf).unwrap();
+        codec.encode(b"", &mut buf).unwrap();
+        assert_eq!(&buf[..4], [0, 0, 0, 5]);
+
+        // A byte at a time, header and all.
+        let mut partial = BytesMut::new();
+        let mut frames = Vec::new();
+        for &b in buf.iter() {
+            partial.put_u8(b);
+            while let Some(frame) = codec.decode(&mut partial).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, [&b"hello"[..], &b""[..]]);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn refuses_long_frames() {
+        let mut codec = LengthCodec::new(MAX_FRAME_LEN);
+        assert!(codec.encode(&vec![0; MAX_FRAME_LEN + 1], &mut BytesMut::new()).is_err());
+        let mut buf = BytesMut::new();
+        codec.encode(&vec![7; MAX_FRAME_LEN], &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().map(|f| f.len()), Some(MAX_FRAME_LEN));
+        // Refused from the header alone, before the body arrives.
+        let mut buf = BytesMut::from(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes()[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn detects_framing() {
+        assert!(Codec::detect(0).is_length());
+        assert!(!Codec::detect(b'H').is_length());
+    }
+}
@@ -0,0 +1,1252 @@
+//! Server configuration, read from a TOML file: `--config <path>`, otherwise
+//! `server.toml` in the working directory if it exists, otherwise defaults.
+//! Only the subset of TOML in `protocol::toml` is understood.
+//!
+//! Any setting can also be given on the command line as `--section.key value`
+//! (`--permissions.commands.kick admin` for a key in a nested section),
+//! which takes precedence over the file; `--log-format json` is short for
+//! `--log.format json`.
+//!
+//! Persistent channels get a section each, `[channel.rust]` for `#rust`
+//! (`[channel."#rust"]` works too; an unquoted `#` would start a comment).
+//! Extra emoji shortcodes go in `[emoji.shortcodes]`, one `name = "emoji"`
+//! each.
+//!
+//! Secrets (`storage.key` and channel keys) needn't be written into the
+//! file: `env:VAR` takes one from an environment variable and `file:/path`
+//! from a file, less any trailing newline.
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use protocol::toml::Document;
+
+use crate::{access::Cidr, argon2::Params, at_rest::Key, history::Retention, http::HttpUrl, log, permissions::Role, spam::Action};
+
+const DEFAULT_PATH: &str = "server.toml";
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub queue: QueueConfig,
+    pub runtime: RuntimeConfig,
+    pub tcp: TcpConfig,
+    pub timeouts: TimeoutsConfig,
+    pub mdns: MdnsConfig,
+    pub invites: InvitesConfig,
+    pub channels: ChannelsConfig,
+    pub history: HistoryConfig,
+    pub push: PushConfig,
+    pub schedule: ScheduleConfig,
+    pub emoji: EmojiConfig,
+    pub previews: PreviewConfig,
+    pub uploads: UploadConfig,
+    pub voice: VoiceConfig,
+    pub metrics: MetricsConfig,
+    pub accounts: AccountsConfig,
+    pub logins: LoginConfig,
+    pub nicks: NicksConfig,
+    pub access: AccessConfig,
+    pub spam: SpamConfig,
+    pub storage: StorageConfig,
+    pub permissions: PermissionsConfig,
+    pub tracing: TracingConfig,
+    pub alerts: AlertsConfig,
+    pub log: LogConfig,
+    pub drain: DrainConfig,
+    pub snapshot: SnapshotConfig,
+}
+
+/// Per-client outgoing queue.
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub slow_consumer: SlowConsumer,
+}
+
+/// What to do when a client's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumer {
+    /// Make room by discarding the oldest queued event.
+    DropOldest,
+    /// Discard the new event; the client is told how many it missed.
+    DropNewest,
+    /// Discard new events, and disconnect the client once its queue has been
+    /// full for this long.
+    Disconnect(Duration),
+}
+
+/// Tokio runtime sizing; `None` keeps tokio's default.
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeConfig {
+    pub worker_threads: Option<usize>,
+    pub max_blocking_threads: Option<usize>,
+}
+
+/// Listening socket and per-connection socket options.
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    pub backlog: u32,
+    /// Idle time before keepalive probes start; `None` leaves keepalive off.
+    pub keepalive: Option<Duration>,
+    /// Time between probes, and how many go unanswered before the
+    /// connection is dropped; `None` leaves the OS's default.
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_retries: Option<u32>,
+    pub nodelay: bool,
+    /// Accept, read and write chat connections through io_uring; only in a
+    /// server built with the `uring` feature, on Linux.
+    pub io_uring: bool,
+}
+
+/// How long a client may take over each part of a connection.
+#[derive(Debug, Clone)]
+pub struct TimeoutsConfig {
+    /// From connecting to being welcomed.
+    pub handshake: Duration,
+    /// Without sending anything once welcomed.
+    pub idle: Duration,
+    /// For one write to it to go through, so a peer that has gone away
+    /// without closing can't hold its writer forever.
+    pub write: Duration,
+}
+
+/// Announcing the server on the LAN for `client discover`.
+#[derive(Debug, Clone)]
+pub struct MdnsConfig {
+    pub enabled: bool,
+    /// The name clients list it under; defaults to "rustchat on <host>".
+    pub name: Option<String>,
+}
+
+/// Invite links from `INVITE`.
+#[derive(Debug, Clone)]
+pub struct InvitesConfig {
+    /// Turn away clients that don't present a valid invite token.
+    pub required: bool,
+    /// How long an invite lasts unless its creator asks otherwise.
+    pub ttl: Duration,
+}
+
+/// Push notifications from `PUSH`, for DMs to users who are offline.
+#[derive(Debug, Clone)]
+pub struct PushConfig {
+    /// Off unless turned on, since it has the server make requests to URLs
+    /// users choose.
+    pub enabled: bool,
+    /// Least time between two pushes to one user.
+    pub min_interval: Duration,
+    /// Let push URLs point at private and loopback addresses, for an ntfy
+    /// server on the LAN.
+    pub allow_private: bool,
+}
+
+/// Scheduled DMs and reminders.
+#[derive(Debug, Clone)]
+pub struct ScheduleConfig {
+    /// Where waiting ones are kept across restarts; `None` keeps them in
+    /// memory only.
+    pub file: Option<PathBuf>,
+}
+
+/// Roles and what they may do; see `permissions`.
+#[derive(Debug, Clone, Default)]
+pub struct PermissionsConfig {
+    /// Registered nicknames with the moderator role.
+    pub moderators: Vec<String>,
+    /// The role each command needs, by keyword, where it isn't the default.
+    pub commands: BTreeMap<String, Role>,
+}
+
+/// Encryption at rest; see `at_rest`.
+#[derive(Debug, Clone, Default)]
+pub struct StorageConfig {
+    /// Seals the schedule file, snapshots and exports; `None` leaves them as text.
+    pub key: Option<Key>,
+}
+
+/// Span export; see `trace`.
+#[derive(Debug, Clone)]
+pub struct TracingConfig {
+    /// Where to POST spans; `None` records none.
+    pub otlp_url: Option<HttpUrl>,
+    /// The `service.name` they're reported under.
+    pub service_name: String,
+    pub export_interval: Duration,
+}
+
+/// Warnings about slow consumers; see `alerts`.
+#[derive(Debug, Clone)]
+pub struct AlertsConfig {
+    /// Queued events that count as backed up, by default three quarters of
+    /// the capacity; 0 turns alerts off.
+    pub slow_queue_depth: usize,
+    /// How long a queue has to stay backed up to be reported.
+    pub slow_after: Duration,
+    /// Where to POST alerts as well as logging them.
+    pub webhook: Option<HttpUrl>,
+}
+
+/// Restarting without downtime; see `drain`.
+#[derive(Debug, Clone)]
+pub struct DrainConfig {
+    /// How long `DRAIN` waits for clients to go when not told.
+    pub timeout: Duration,
+}
+
+/// Periodic snapshots of what's otherwise only in memory; see `snapshot`.
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    /// `None` takes none, and restores none at startup.
+    pub file: Option<PathBuf>,
+    pub interval: Duration,
+}
+
+/// How log lines are written; see `log`.
+#[derive(Debug, Clone, Default)]
+pub struct LogConfig {
+    pub format: log::Format,
+}
+
+/// Shortcode expansion for connections with the `emoji` cap.
+#[derive(Debug, Clone)]
+pub struct EmojiConfig {
+    /// Off offers no table, so the cap changes nothing.
+    pub enabled: bool,
+    /// Start from the built-in shortcodes; `shortcodes` adds to them and
+    /// overrides them either way.
+    pub builtin: bool,
+    pub shortcodes: BTreeMap<String, String>,
+}
+
+/// Link titles fetched for messages with an `http://` link in them.
+#[derive(Debug, Clone)]
+pub struct PreviewConfig {
+    pub enabled: bool,
+    /// Fetch from private and loopback addresses too; only for testing, or a
+    /// server that isn't on a network anyone else shares.
+    pub allow_private: bool,
+    /// Most of a page read, headers included, looking for its title.
+    pub max_bytes: usize,
+    /// How long a title, or the lack of one, is remembered.
+    pub cache_for: Duration,
+}
+
+/// The HTTP endpoint for uploading files to share in messages.
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    pub enabled: bool,
+    /// Listened on at the chat server's address.
+    pub port: u16,
+    /// Where files and their index are kept.
+    pub dir: PathBuf,
+    /// Largest single file.
+    pub max_file_bytes: u64,
+    /// Total one user may have stored.
+    pub quota_bytes: u64,
+    /// How long a token from `UPLOAD` can be used for.
+    pub token_ttl: Duration,
+}
+
+/// The UDP relay for voice channels.
+#[derive(Debug, Clone)]
+pub struct VoiceConfig {
+    pub enabled: bool,
+    /// Listened on at the chat server's address.
+    pub port: u16,
+}
+
+/// The Prometheus endpoint; see `metrics`.
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Listened on at the chat server's address.
+    pub port: u16,
+}
+
+/// Registered nicknames and how their passwords are hashed.
+#[derive(Debug, Clone)]
+pub struct AccountsConfig {
+    /// Where accounts are kept; `None` keeps them in memory only.
+    pub file: Option<PathBuf>,
+    /// For argon2id; hashes made with others are redone on login.
+    pub params: Params,
+    /// Zero bits of proof of work registering a new nickname takes; 0 takes
+    /// none.
+    pub challenge_bits: u32,
+}
+
+/// Throttling of failed logins to registered nicknames.
+#[derive(Debug, Clone)]
+pub struct LoginConfig {
+    /// Failures that lock a nickname out.
+    pub max_failures: u32,
+    /// Failures that lock an address out, whichever nicknames they were for.
+    pub max_failures_per_ip: u32,
+    /// How long a lockout lasts, and how long failures are remembered.
+    pub lockout: Duration,
+    /// How long the answer to a first failure waits; each one after waits
+    /// twice as long as the last.
+    pub failure_delay: Duration,
+}
+
+/// Limits on logging in under new nicknames, and holding them after.
+#[derive(Debug, Clone)]
+pub struct NicksConfig {
+    /// Logins per address per minute; 0 for no limit.
+    pub logins_per_minute: u32,
+    /// Different nicknames per address per minute; 0 for no limit.
+    pub names_per_minute: u32,
+    /// How long a nickname is kept for its address after disconnecting.
+    pub hold: Duration,
+}
+
+/// Address ranges that may or may not connect; admin can add to them at
+/// runtime.
+#[derive(Debug, Clone, Default)]
+pub struct AccessConfig {
+    /// If any, only addresses in these may connect.
+    pub allow: Vec<Cidr>,
+    /// Never let in, allowed or not.
+    pub deny: Vec<Cidr>,
+}
+
+/// What counts as spam, and what's done about it.
+#[derive(Debug, Clone)]
+pub struct SpamConfig {
+    pub enabled: bool,
+    /// The same message this many times within the window.
+    pub repeats: SpamRule,
+    /// This many people named in one message; the window isn't used.
+    pub mentions: SpamRule,
+    /// DMs to this many different people within the window.
+    pub dms: SpamRule,
+    /// How long `mute` lasts.
+    pub mute: Duration,
+    /// How long a pattern's strikes last without another.
+    pub forgive: Duration,
+}
+
+/// One pattern's limit, and what's done the first, second, ... time it's
+/// reached; the last action repeats.
+#[derive(Debug, Clone)]
+pub struct SpamRule {
+    /// 0 turns the rule off.
+    pub limit: u32,
+    pub window: Duration,
+    pub actions: Vec<Action>,
+}
+
+/// Who may create channels, how many, and how long empty ones live.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelsConfig {
+    /// The role needed to create a channel by joining one that doesn't
+    /// exist yet.
+    pub creators: Role,
+    /// Channels one user may have created at a time; `None` is no limit.
+    pub max_per_user: Option<usize>,
+    /// Created at startup, and again on reload, and never cleaned up.
+    pub persistent: Vec<PersistentChannel>,
+    /// How long any other channel survives once its last member leaves.
+    pub empty_timeout: Duration,
+    /// How much history channels keep unless configured otherwise.
+    pub retention: Retention,
+}
+
+/// A channel from `[channels] persistent` or a `[channel.<name>]` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PersistentChannel {
+    pub name: String,
+    pub topic: Option<String>,
+    /// Needed to join, if set.
+    pub key: Option<String>,
+    /// Nicknames that are operators of the channel.
+    pub ops: Vec<String>,
+    /// Overrides `[channels] retention`.
+    pub retention: Option<Retention>,
+    /// Only its ops, moderators and admin may post; everyone else just reads.
+    pub announce: bool,
+}
+
+impl PersistentChannel {
+    pub fn new(name: &str) -> Self {
+        PersistentChannel { name: name.to_string(), ..PersistentChannel::default() }
+    }
+}
+
+/// Message history kept for `HISTORY`, other than channels'.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryConfig {
+    /// DM threads, for `HISTORY @name`.
+    pub dms: Retention,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { capacity: 64, slow_consumer: SlowConsumer::DropNewest }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig { backlog: 1024, keepalive: None, keepalive_interval: None, keepalive_retries: None, nodelay: true, io_uring: false }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        TimeoutsConfig { handshake: Duration::from_secs(10), idle: Duration::from_secs(300), write: Duration::from_secs(30) }
+    }
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig { enabled: true, name: None }
+    }
+}
+
+impl Default for InvitesConfig {
+    fn default() -> Self {
+        InvitesConfig { required: false, ttl: Duration::from_secs(24 * 3600) }
+    }
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        PushConfig { enabled: false, min_interval: Duration::from_secs(300), allow_private: false }
+    }
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig { file: Some(PathBuf::from("schedule.tsv")) }
+    }
+}
+
+impl Default for EmojiConfig {
+    fn default() -> Self {
+        EmojiConfig { enabled: true, builtin: true, shortcodes: BTreeMap::new() }
+    }
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        PreviewConfig { enabled: false, allow_private: false, max_bytes: 64 * 1024, cache_for: Duration::from_secs(3600) }
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            enabled: false,
+            port: protocol::DEFAULT_PORT + 1,
+            dir: PathBuf::from("uploads"),
+            max_file_bytes: 10 << 20,
+            quota_bytes: 100 << 20,
+            token_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        VoiceConfig { enabled: false, port: protocol::DEFAULT_PORT + 2 }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { enabled: false, port: protocol::DEFAULT_PORT + 3 }
+    }
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig { file: Some(PathBuf::from("accounts.tsv")), params: Params::default(), challenge_bits: 20 }
+    }
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig { max_failures: 5, max_failures_per_ip: 20, lockout: Duration::from_secs(900), failure_delay: Duration::from_millis(500) }
+    }
+}
+
+impl Default for NicksConfig {
+    fn default() -> Self {
+        NicksConfig { logins_per_minute: 10, names_per_minute: 3, hold: Duration::from_secs(60) }
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig { otlp_url: None, service_name: "rustchat".into(), export_interval: Duration::from_secs(5) }
+    }
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig { slow_queue_depth: 48, slow_after: Duration::from_secs(10), webhook: None }
+    }
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        DrainConfig { timeout: Duration::from_secs(60) }
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig { file: Some(PathBuf::from("snapshot.tsv")), interval: Duration::from_secs(60) }
+    }
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        let rule = |limit| SpamRule { limit, window: Duration::from_secs(30), actions: vec![Action::Warn, Action::Mute, Action::Kick] };
+        SpamConfig { enabled: true, repeats: rule(5), mentions: rule(10), dms: rule(10), mute: Duration::from_secs(300), forgive: Duration::from_secs(3600) }
+    }
+}
+
+impl Config {
+    /// Loads the file named by `--config`, or the default path if present,
+    /// then applies `--section.key value` overrides.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut path = None;
+        let mut overrides = Vec::new();
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            let setting = arg.strip_prefix("--").ok_or_else(|| anyhow!("unexpected argument {arg}"))?;
+            let value = it.next().ok_or_else(|| anyhow!("{arg} needs a value"))?;
+            if setting == "config" {
+                path = Some(value.as_str());
+            } else if setting == "log-format" {
+                overrides.push(("log", "format", value.as_str()));
+            } else {
+                let (section, key) = setting.rsplit_once('.').ok_or_else(|| anyhow!("unknown flag {arg}"))?;
+                overrides.push((section, key, value.as_str()));
+            }
+        }
+
+        let path = path.or(Path::new(DEFAULT_PATH).exists().then_some(DEFAULT_PATH));
+        let mut doc = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| anyhow!("failed to read {path}: {e}"))?;
+                Document::parse(&text).map_err(|e| anyhow!("{path}: {e}"))?
+            }
+            None => Document::default(),
+        };
+        for (section, key, value) in overrides {
+            doc.set(section, key, value);
+        }
+        Config::from_document(doc)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Config::from_document(Document::parse(text)?)
+    }
+
+    fn from_document(mut doc: Document) -> Result<Self> {
+        let mut config = Config::default();
+
+        if let Some(capacity) = doc.take_int("queue", "capacity")? {
+            if capacity < 1 {
+                bail!("queue.capacity must be at least 1");
+            }
+            config.queue.capacity = capacity as usize;
+        }
+        let after = doc.take_int("queue", "disconnect_after_secs")?.unwrap_or(10);
+        if let Some(policy) = doc.take_str("queue", "slow_consumer")? {
+            config.queue.slow_consumer = match policy.as_str() {
+                "drop-oldest" => SlowConsumer::DropOldest,
+                "drop-newest" => SlowConsumer::DropNewest,
+                "disconnect" => SlowConsumer::Disconnect(Duration::from_secs(after)),
+                other => bail!("queue.slow_consumer: unknown policy {other:?}"),
+            };
+        }
+
+        config.alerts.slow_queue_depth = match doc.take_int("alerts", "slow_queue_depth")? {
+            Some(depth) => depth as usize,
+            None => (config.queue.capacity * 3 / 4).max(1),
+        };
+        if config.alerts.slow_queue_depth > config.queue.capacity {
+            bail!("alerts.slow_queue_depth can be at most queue.capacity ({})", config.queue.capacity);
+        }
+        if let Some(secs) = doc.take_int("alerts", "slow_after_secs")? {
+            config.alerts.slow_after = Duration::from_secs(secs);
+        }
+        if let Some(url) = doc.take_str("alerts", "webhook")? {
+            config.alerts.webhook = Some(url).filter(|u| !u.is_empty()).map(|u| HttpUrl::parse(&u)).transpose().map_err(|e| anyhow!("alerts.webhook: {e}"))?;
+        }
+
+        for (key, setting) in [
+            ("worker_threads", &mut config.runtime.worker_threads),
+            ("max_blocking_threads", &mut config.runtime.max_blocking_threads),
+        ] {
+            if let Some(n) = doc.take_int("runtime", key)? {
+                if n < 1 {
+                    bail!("runtime.{key} must be at least 1");
+                }
+                *setting = Some(n as usize);
+            }
+        }
+
+        if let Some(backlog) = doc.take_int("tcp", "backlog")? {
+            config.tcp.backlog = u32::try_from(backlog).map_err(|_| anyhow!("tcp.backlog is too large"))?;
+        }
+        if let Some(secs) = doc.take_int("tcp", "keepalive_secs")? {
+            config.tcp.keepalive = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = doc.take_int("tcp", "keepalive_interval_secs")? {
+            if secs == 0 {
+                bail!("tcp.keepalive_interval_secs must be at least 1");
+            }
+            config.tcp.keepalive_interval = Some(Duration::from_secs(secs));
+        }
+        if let Some(retries) = doc.take_int("tcp", "keepalive_retries")? {
+            config.tcp.keepalive_retries = Some(u32::try_from(retries).ok().filter(|&n| n > 0).ok_or_else(|| anyhow!("tcp.keepalive_retries must be from 1 to {}", u32::MAX))?);
+        }
+        if config.tcp.keepalive.is_none() && (config.tcp.keepalive_interval.is_some() || config.tcp.keepalive_retries.is_some()) {
+            bail!("tcp.keepalive_interval_secs and tcp.keepalive_retries need tcp.keepalive_secs");
+        }
+        if let Some(nodelay) = doc.take_bool("tcp", "nodelay")? {
+            config.tcp.nodelay = nodelay;
+        }
+        if let Some(io_uring) = doc.take_bool("tcp", "io_uring")? {
+            if io_uring && !cfg!(all(target_os = "linux", feature = "uring")) {
+                bail!("tcp.io_uring needs a server built with the uring feature, on Linux");
+            }
+            config.tcp.io_uring = io_uring;
+        }
+
+        for (key, timeout) in [("handshake_secs", &mut config.timeouts.handshake), ("idle_secs", &mut config.timeouts.idle), ("write_secs", &mut config.timeouts.write)] {
+            if let Some(secs) = doc.take_int("timeouts", key)? {
+                if secs == 0 {
+                    bail!("timeouts.{key} must be at least 1");
+                }
+                *timeout = Duration::from_secs(secs);
+            }
+        }
+        // Probes only go out on a connection with nothing to read, which
+        // the idle timeout would have closed first.
+        if config.tcp.keepalive.is_some_and(|idle| idle >= config.timeouts.idle) {
+            bail!("tcp.keepalive_secs must be less than timeouts.idle_secs ({})", config.timeouts.idle.as_secs());
+        }
+
+        if let Some(enabled) = doc.take_bool("mdns", "enabled")? {
+            config.mdns.enabled = enabled;
+        }
+        if let Some(name) = doc.take_str("mdns", "name")? {
+            if name.trim().is_empty() || name.len() > 63 {
+                bail!("mdns.name must be 1 to 63 bytes");
+            }
+            config.mdns.name = Some(name);
+        }
+
+        if let Some(required) = doc.take_bool("invites", "required")? {
+            config.invites.required = required;
+        }
+        if let Some(secs) = doc.take_int("invites", "ttl_secs")? {
+            if secs < 1 {
+                bail!("invites.ttl_secs must be at least 1");
+            }
+            config.invites.ttl = Duration::from_secs(secs);
+        }
+
+        if let Some(enabled) = doc.take_bool("push", "enabled")? {
+            config.push.enabled = enabled;
+        }
+        if let Some(secs) = doc.take_int("push", "min_interval_secs")? {
+            config.push.min_interval = Duration::from_secs(secs);
+        }
+        if let Some(allow) = doc.take_bool("push", "allow_private")? {
+            config.push.allow_private = allow;
+        }
+
+        if let Some(file) = doc.take_str("schedule", "file")? {
+            config.schedule.file = Some(PathBuf::from(file)).filter(|f| !f.as_os_str().is_empty());
+        }
+
+        if let Some(enabled) = doc.take_bool("emoji", "enabled")? {
+            config.emoji.enabled = enabled;
+        }
+        if let Some(builtin) = doc.take_bool("emoji", "builtin")? {
+            config.emoji.builtin = builtin;
+        }
+        for name in doc.keys("emoji.shortcodes") {
+            let emoji = doc.take_str("emoji.shortcodes", &name)?.unwrap_or_default();
+            if !crate::emoji::is_name(&name) {
+                bail!("emoji.shortcodes: {name:?} isn't a shortcode name (lowercase letters, digits, _, + and -)");
+            }
+            if emoji.is_empty() || emoji.contains(char::is_whitespace) {
+                bail!("emoji.shortcodes.{name} must be an emoji");
+            }
+            config.emoji.shortcodes.insert(name, emoji);
+        }
+
+        if let Some(enabled) = doc.take_bool("previews", "enabled")? {
+            config.previews.enabled = enabled;
+        }
+        if let Some(allow) = doc.take_bool("previews", "allow_private")? {
+            config.previews.allow_private = allow;
+        }
+        if let Some(bytes) = doc.take_int("previews", "max_bytes")? {
+            if bytes < 1024 {
+                bail!("previews.max_bytes must be at least 1024");
+            }
+            config.previews.max_bytes = bytes as usize;
+        }
+        if let Some(secs) = doc.take_int("previews", "cache_secs")? {
+            config.previews.cache_for = Duration::from_secs(secs);
+        }
+
+        if let Some(enabled) = doc.take_bool("uploads", "enabled")? {
+            config.uploads.enabled = enabled;
+        }
+        if let Some(port) = doc.take_int("uploads", "port")? {
+            config.uploads.port = u16::try_from(port).ok().filter(|&p| p != 0).ok_or_else(|| anyhow!("uploads.port must be 1-65535"))?;
+        }
+        if let Some(dir) = doc.take_str("uploads", "dir")? {
+            if dir.is_empty() {
+                bail!("uploads.dir can't be empty");
+            }
+            config.uploads.dir = PathBuf::from(dir);
+        }
+        if let Some(bytes) = doc.take_int("uploads", "max_file_bytes")? {
+            config.uploads.max_file_bytes = bytes;
+        }
+        if let Some(bytes) = doc.take_int("uploads", "quota_bytes")? {
+            config.uploads.quota_bytes = bytes;
+        }
+        if let Some(secs) = doc.take_int("uploads", "token_ttl_secs")? {
+            if secs < 1 {
+                bail!("uploads.token_ttl_secs must be at least 1");
+            }
+            config.uploads.token_ttl = Duration::from_secs(secs);
+        }
+        if config.uploads.max_file_bytes > config.uploads.quota_bytes {
+            bail!("uploads.max_file_bytes can't be more than uploads.quota_bytes");
+        }
+
+        if let Some(enabled) = doc.take_bool("voice", "enabled")? {
+            config.voice.enabled = enabled;
+        }
+        if let Some(port) = doc.take_int("voice", "port")? {
+            config.voice.port = u16::try_from(port).ok().filter(|&p| p != 0).ok_or_else(|| anyhow!("voice.port must be 1-65535"))?;
+        }
+        if let Some(enabled) = doc.take_bool("metrics", "enabled")? {
+            config.metrics.enabled = enabled;
+        }
+        if let Some(port) = doc.take_int("metrics", "port")? {
+            config.metrics.port = u16::try_from(port).ok().filter(|&p| p != 0).ok_or_else(|| anyhow!("metrics.port must be 1-65535"))?;
+        }
+
+        if let Some(file) = doc.take_str("accounts", "file")? {
+            config.accounts.file = Some(PathBuf::from(file)).filter(|f| !f.as_os_str().is_empty());
+        }
+        if let Some(kib) = doc.take_int("accounts", "memory_kib")? {
+            config.accounts.params.memory_kib = u32::try_from(kib).map_err(|_| anyhow!("accounts.memory_kib is too big"))?;
+        }
+        if let Some(n) = doc.take_int("accounts", "iterations")? {
+            config.accounts.params.iterations = u32::try_from(n).map_err(|_| anyhow!("accounts.iterations is too big"))?;
+        }
+        if let Some(n) = doc.take_int("accounts", "parallelism")? {
+            config.accounts.params.parallelism = u32::try_from(n).map_err(|_| anyhow!("accounts.parallelism is too big"))?;
+        }
+        if let Some(bits) = doc.take_int("accounts", "challenge_bits")? {
+            config.accounts.challenge_bits = u32::try_from(bits).ok().filter(|&b| b <= protocol::pow::MAX_BITS).ok_or_else(|| anyhow!("accounts.challenge_bits can be at most {}", protocol::pow::MAX_BITS))?;
+        }
+        if !config.accounts.params.valid() {
+            bail!("accounts: iterations and parallelism must be at least 1, and memory_kib at least 8 per lane");
+        }
+
+        for (key, max) in [("max_failures", &mut config.logins.max_failures), ("max_failures_per_ip", &mut config.logins.max_failures_per_ip)] {
+            if let Some(n) = doc.take_int("logins", key)? {
+                *max = u32::try_from(n).ok().filter(|&n| n > 0).ok_or_else(|| anyhow!("logins.{key} must be at least 1"))?;
+            }
+        }
+        if let Some(secs) = doc.take_int("logins", "lockout_secs")? {
+            config.logins.lockout = Duration::from_secs(secs);
+        }
+        if let Some(ms) = doc.take_int("logins", "failure_delay_ms")? {
+            config.logins.failure_delay = Duration::from_millis(ms);
+        }
+
+        for (key, max) in [("logins_per_minute", &mut config.nicks.logins_per_minute), ("names_per_minute", &mut config.nicks.names_per_minute)] {
+            if let Some(n) = doc.take_int("nicks", key)? {
+                *max = u32::try_from(n).map_err(|_| anyhow!("nicks.{key} is too big"))?;
+            }
+        }
+        if let Some(secs) = doc.take_int("nicks", "hold_secs")? {
+            config.nicks.hold = Duration::from_secs(secs);
+        }
+
+        for (key, list) in [("allow", &mut config.access.allow), ("deny", &mut config.access.deny)] {
+            for cidr in doc.take_list("access", key)?.unwrap_or_default() {
+                list.push(cidr.parse().map_err(|e| anyhow!("access.{key}: {e}"))?);
+            }
+        }
+
+        if let Some(enabled) = doc.take_bool("spam", "enabled")? {
+            config.spam.enabled = enabled;
+        }
+        for (key, rule) in [("repeats", &mut config.spam.repeats), ("mentions", &mut config.spam.mentions), ("dms", &mut config.spam.dms)] {
+            if let Some(n) = doc.take_int("spam", key)? {
+                rule.limit = u32::try_from(n).map_err(|_| anyhow!("spam.{key} is too big"))?;
+            }
+            if let Some(secs) = doc.take_int("spam", &format!("{key}_window_secs"))? {
+                if secs < 1 {
+                    bail!("spam.{key}_window_secs must be at least 1");
+                }
+                rule.window = Duration::from_secs(secs);
+            }
+            if let Some(actions) = doc.take_list("spam", &format!("{key}_actions"))? {
+                if actions.is_empty() {
+                    bail!("spam.{key}_actions needs at least one action");
+                }
+                rule.actions = actions.iter().map(|a| a.parse()).collect::<Result<_, String>>().map_err(|e| anyhow!("spam.{key}_actions: {e}"))?;
+            }
+        }
+        if let Some(secs) = doc.take_int("spam", "mute_secs")? {
+            config.spam.mute = Duration::from_secs(secs);
+        }
+        if let Some(secs) = doc.take_int("spam", "forgive_secs")? {
+            config.spam.forgive = Duration::from_secs(secs);
+        }
+
+        let key = doc.take_str("storage", "key")?.map(|k| secret("storage.key", k)).transpose()?;
+        let key = key.filter(|k| !k.is_empty()).or_else(|| std::env::var(crate::at_rest::KEY_ENV).ok());
+        if let Some(key) = key {
+            config.storage.key = Some(Key::parse(&key).ok_or_else(|| anyhow!("storage.key must be 64 hex digits"))?);
+        }
+
+        config.permissions.moderators = doc.take_list("permissions", "moderators")?.unwrap_or_default();
+        for command in doc.keys("permissions.commands") {
+            let role = doc.take_str("permissions.commands", &command)?.unwrap_or_default();
+            if !protocol::Command::KEYWORDS.contains(&command.as_str()) {
+                bail!("permissions.commands: {command:?} isn't a command");
+            }
+            config.permissions.commands.insert(command.clone(), role.parse().map_err(|e| anyhow!("permissions.commands.{command}: {e}"))?);
+        }
+
+        if let Some(url) = doc.take_str("tracing", "otlp_url")? {
+            config.tracing.otlp_url = Some(url).filter(|u| !u.is_empty()).map(|u| HttpUrl::parse(&u)).transpose().map_err(|e| anyhow!("tracing.otlp_url: {e}"))?;
+        }
+        if let Some(name) = doc.take_str("tracing", "service_name")? {
+            config.tracing.service_name = name;
+        }
+        if let Some(ms) = doc.take_int("tracing", "export_interval_ms")? {
+            config.tracing.export_interval = Duration::from_millis(ms).max(Duration::from_millis(100));
+        }
+
+        if let Some(secs) = doc.take_int("drain", "timeout_secs")? {
+            if secs == 0 {
+                bail!("drain.timeout_secs must be at least 1");
+            }
+            config.drain.timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(file) = doc.take_str("snapshot", "file")? {
+            config.snapshot.file = Some(PathBuf::from(file)).filter(|f| !f.as_os_str().is_empty());
+        }
+        if let Some(secs) = doc.take_int("snapshot", "interval_secs")? {
+            if secs == 0 {
+                bail!("snapshot.interval_secs must be at least 1");
+            }
+            config.snapshot.interval = Duration::from_secs(secs);
+        }
+
+        if let Some(format) = doc.take_str("log", "format")? {
+            config.log.format = format.parse().map_err(|e| anyhow!("log.format: {e}"))?;
+        }
+
+        if let Some(creators) = doc.take_str("channels", "creators")? {
+            config.channels.creators = creators.parse().map_err(|e| anyhow!("channels.creators: {e}"))?;
+        }
+        if let Some(max) = doc.take_int("channels", "max_per_user")? {
+            config.channels.max_per_user = (max > 0).then_some(max as usize);
+        }
+        if let Some(persistent) = doc.take_list("channels", "persistent")? {
+            if let Some(bad) = persistent.iter().find(|c| !protocol::is_channel_name(c)) {
+                bail!("channels.persistent: {bad:?} is not a channel name");
+            }
+            config.channels.persistent = persistent.iter().map(|name| PersistentChannel::new(name)).collect();
+        }
+        if let Some(secs) = doc.take_int("channels", "empty_timeout_secs")? {
+            config.channels.empty_timeout = Duration::from_secs(secs);
+        }
+        if let Some(retention) = doc.take_str("channels", "retention")? {
+            config.channels.retention = parse_retention("channels.retention", &retention)?;
+        }
+        if let Some(retention) = doc.take_str("history", "dms")? {
+            config.history.dms = parse_retention("history.dms", &retention)?;
+        }
+
+        let sections: Vec<String> = doc.sections().filter(|s| s.starts_with("channel.")).map(str::to_string).collect();
+        for section in sections {
+            let name = section["channel.".len()..].trim_matches('"');
+            let name = if name.starts_with('#') { name.to_string() } else { format!("#{name}") };
+            if !protocol::is_channel_name(&name) {
+                bail!("[{section}]: {name:?} is not a channel name");
+            }
+            if config.channels.persistent.iter().any(|c| c.name == name) {
+                bail!("[{section}]: {name} is defined twice");
+            }
+            let key = doc.take_str(&section, "key")?.map(|k| secret(&format!("{section}.key"), k)).transpose()?;
+            if key.as_deref().is_some_and(|k| k.is_empty() || k.contains(char::is_whitespace)) {
+                bail!("{section}.key must be one word");
+            }
+            config.channels.persistent.push(PersistentChannel {
+                name,
+                topic: doc.take_str(&section, "topic")?.filter(|t| !t.is_empty()),
+                key,
+                ops: doc.take_list(&section, "ops")?.unwrap_or_default(),
+                retention: doc.take_str(&section, "retention")?.map(|r| parse_retention(&format!("{section}.retention"), &r)).transpose()?,
+                announce: doc.take_bool(&section, "announce")?.unwrap_or(false),
+            });
+        }
+
+        doc.finish()?;
+        Ok(config)
+    }
+}
+
+/// A secret setting's value: read from where `env:VAR` or `file:/path`
+/// points, or as it is.
+fn secret(setting: &str, value: String) -> Result<String> {
+    if let Some(var) = value.strip_prefix("env:") {
+        return std::env::var(var).map_err(|_| anyhow!("{setting}: environment variable {var} isn't set"));
+    }
+    if let Some(path) = value.strip_prefix("file:") {
+        let text = std::fs::read_to_string(path).map_err(|e| anyhow!("{setting}: failed to read {path}: {e}"))?;
+        return Ok(text.trim_end_matches(['\n', '\r']).to_string());
+    }
+    Ok(value)
+}
+
+fn parse_retention(setting: &str, value: &str) -> Result<Retention> {
+    Retention::parse(value).ok_or_else(|| anyhow!("{setting}: expected \"none\", \"<n> messages\" or an age like \"7d\", not {value:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_queue_settings() {
+        let config = Config::parse(
+            r#"
+            # slow clients get cut off
+            [queue]
+            capacity = 1_000
+            slow_consumer = "disconnect"  # after a while
+            disconnect_after_secs = 30
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.queue.capacity, 1000);
+        assert_eq!(config.queue.slow_consumer, SlowConsumer::Disconnect(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn parses_alert_settings() {
+        let config = Config::parse("[alerts]\nslow_queue_depth = 20\nslow_after_secs = 3\nwebhook = \"http://alerts.lan/slow\"").unwrap();
+        assert_eq!((config.alerts.slow_queue_depth, config.alerts.slow_after), (20, Duration::from_secs(3)));
+        assert_eq!(config.alerts.webhook.unwrap().path, "/slow");
+        // A queue never gets deeper than its capacity.
+        assert!(Config::parse("[alerts]\nslow_queue_depth = 65").is_err());
+        assert_eq!(Config::parse("[queue]\ncapacity = 1000").unwrap().alerts.slow_queue_depth, 750);
+    }
+
+    #[test]
+    fn parses_runtime_and_tcp_settings() {
+        let config = Config::parse(
+            r#"
+            [runtime]
+            worker_threads = 4
+            [tcp]
+            backlog = 4096
+            keepalive_secs = 60
+            nodelay = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.runtime.worker_threads, Some(4));
+        assert_eq!(config.runtime.max_blocking_threads, None);
+        assert_eq!(config.tcp.backlog, 4096);
+        assert_eq!(config.tcp.keepalive, Some(Duration::from_secs(60)));
+        assert!(!config.tcp.nodelay);
+        assert!(!config.tcp.io_uring);
+        assert_eq!(Config::parse("[tcp]\nio_uring = true").is_ok(), cfg!(all(target_os = "linux", feature = "uring")));
+    }
+
+    #[test]
+    fn parses_timeouts_and_keepalive() {
+        let config = Config::default();
+        assert_eq!((config.timeouts.handshake, config.timeouts.idle, config.timeouts.write), (Duration::from_secs(10), Duration::from_secs(300), Duration::from_secs(30)));
+        let config = Config::parse("[tcp]\nkeepalive_secs = 30\nkeepalive_interval_secs = 5\nkeepalive_retries = 3\n[timeouts]\nhandshake_secs = 5\nidle_secs = 60\nwrite_secs = 15").unwrap();
+        assert_eq!((config.tcp.keepalive_interval, config.tcp.keepalive_retries), (Some(Duration::from_secs(5)), Some(3)));
+        assert_eq!((config.timeouts.handshake, config.timeouts.idle, config.timeouts.write), (Duration::from_secs(5), Duration::from_secs(60), Duration::from_secs(15)));
+
+        assert!(Config::parse("[timeouts]\nwrite_secs = 0").is_err());
+        assert_eq!(Config::parse("[drain]\ntimeout_secs = 120").unwrap().drain.timeout, Duration::from_secs(120));
+        assert!(Config::parse("[drain]\ntimeout_secs = 0").is_err());
+        assert!(Config::parse("[tcp]\nkeepalive_retries = 3").is_err());
+        assert!(Config::parse("[tcp]\nkeepalive_secs = 30\nkeepalive_retries = 0").is_err());
+        assert!(Config::parse("[tcp]\nkeepalive_secs = 300").is_err());
+        assert!(Config::parse("[tcp]\nkeepalive_secs = 30\n[timeouts]\nidle_secs = 20").is_err());
+    }
+
+    #[test]
+    fn parses_mdns_settings() {
+        let config = Config::parse("[mdns]\nname = \"office chat\"").unwrap();
+        assert!(config.mdns.enabled);
+        assert_eq!(config.mdns.name.as_deref(), Some("office chat"));
+        assert!(!Config::parse("[mdns]\nenabled = false").unwrap().mdns.enabled);
+        assert!(Config::parse("[mdns]\nname = \"\"").is_err());
+    }
+
+    #[test]
+    fn parses_channel_settings() {
+        let config = Config::parse(
+            r##"
+            [channels]
+            creators = "moderator"
+            max_per_user = 3
+            persistent = ["#general", "#help"]
+            empty_timeout_secs = 600
+            retention = "7d"
+            "##,
+        )
+        .unwrap();
+        assert_eq!(config.channels.creators, Role::Moderator);
+        assert_eq!(Config::parse("[channels]\ncreators = \"admin\"").unwrap().channels.creators, Role::Admin);
+        assert_eq!(config.channels.max_per_user, Some(3));
+        assert_eq!(config.channels.persistent, [PersistentChannel::new("#general"), PersistentChannel::new("#help")]);
+        assert_eq!(config.channels.empty_timeout, Duration::from_secs(600));
+        assert_eq!(config.channels.retention, Retention::For(Duration::from_secs(7 * 86_400)));
+        assert!(Config::parse("[channels]\nretention = \"forever\"").is_err());
+        assert!(Config::parse("[channels]\ncreators = \"ops\"").is_err());
+        assert!(Config::parse("[channels]\npersistent = [\"general\"]").is_err());
+    }
+
+    #[test]
+    fn parses_channel_sections() {
+        let config = Config::parse(
+            r##"
+            [channel.staff]
+            topic = "staff only, #rules apply"
+            key = "s3cret"
+            ops = ["alice", "bob"]
+            retention = "500 messages"
+            [channel."#lobby"]
+            [channel.news]
+            announce = true
+            "##,
+        )
+        .unwrap();
+        let staff = PersistentChannel {
+            name: "#staff".into(),
+            topic: Some("staff only, #rules apply".into()),
+            key: Some("s3cret".into()),
+            ops: vec!["alice".into(), "bob".into()],
+            retention: Some(Retention::Last(500)),
+            announce: false,
+        };
+        let news = PersistentChannel { announce: true, ..PersistentChannel::new("#news") };
+        assert_eq!(config.channels.persistent, [PersistentChannel::new("#lobby"), news, staff]);
+        assert!(Config::parse("[channel.staff]\nkey = \"two words\"").is_err());
+        assert!(Config::parse("[channel.staff]\nmode = \"secret\"").is_err());
+        assert!(Config::parse("[channels]\npersistent = [\"#a\"]\n[channel.a]").is_err());
+    }
+
+    #[test]
+    fn parses_history_settings() {
+        assert_eq!(Config::default().history.dms, Retention::None);
+        let config = Config::parse("[history]\ndms = \"30d\"").unwrap();
+        assert_eq!(config.history.dms, Retention::For(Duration::from_secs(30 * 86_400)));
+        assert!(Config::parse("[history]\ndms = \"all\"").is_err());
+    }
+
+    #[test]
+    fn parses_invite_settings() {
+        let config = Config::parse("[invites]\nrequired = true\nttl_secs = 3600").unwrap();
+        assert!(config.invites.required);
+        assert_eq!(config.invites.ttl, Duration::from_secs(3600));
+        assert!(Config::parse("[invites]\nttl_secs = 0").is_err());
+    }
+
+    #[test]
+    fn parses_push_settings() {
+        assert!(!Config::parse("").unwrap().push.enabled);
+        let config = Config::parse("[push]\nenabled = true\nmin_interval_secs = 60").unwrap();
+        assert!(config.push.enabled);
+        assert_eq!(config.push.min_interval, Duration::from_secs(60));
+        assert!(!config.push.allow_private);
+        assert!(Config::parse("[push]\nallow_private = true").unwrap().push.allow_private);
+        assert!(Config::parse("[push]\nenabled = \"yes\"").is_err());
+    }
+
+    #[test]
+    fn parses_schedule_settings() {
+        assert_eq!(Config::default().schedule.file, Some(PathBuf::from("schedule.tsv")));
+        assert_eq!(Config::parse("[schedule]\nfile = \"/var/lib/rustchat/schedule.tsv\"").unwrap().schedule.file, Some(PathBuf::from("/var/lib/rustchat/schedule.tsv")));
+        assert_eq!(Config::parse("[schedule]\nfile = \"\"").unwrap().schedule.file, None);
+    }
+
+    #[test]
+    fn parses_snapshot_settings() {
+        let config = Config::default();
+        assert_eq!((config.snapshot.file, config.snapshot.interval), (Some(PathBuf::from("snapshot.tsv")), Duration::from_secs(60)));
+        let config = Config::parse("[snapshot]\nfile = \"/var/lib/rustchat/snapshot.tsv\"\ninterval_secs = 15").unwrap();
+        assert_eq!((config.snapshot.file, config.snapshot.interval), (Some(PathBuf::from("/var/lib/rustchat/snapshot.tsv")), Duration::from_secs(15)));
+        assert_eq!(Config::parse("[snapshot]\nfile = \"\"").unwrap().snapshot.file, None);
+        assert!(Config::parse("[snapshot]\ninterval_secs = 0").is_err());
+    }
+
+    #[test]
+    fn parses_emoji_settings() {
+        let config = Config::parse("[emoji]\nbuiltin = false\n\n[emoji.shortcodes]\nshipit = \"🐿️\"\n+1 = \"👍\"").unwrap();
+        assert!(config.emoji.enabled && !config.emoji.builtin);
+        assert_eq!(config.emoji.shortcodes.get("shipit").map(String::as_str), Some("🐿️"));
+        assert_eq!(config.emoji.shortcodes.len(), 2);
+        assert!(Config::parse("[emoji.shortcodes]\nShip_It = \"🐿️\"").is_err());
+        assert!(Config::parse("[emoji.shortcodes]\nshipit = \"\"").is_err());
+        assert!(Config::parse("[emoji.shortcodes]\nshipit = 3").is_err());
+    }
+
+    #[test]
+    fn parses_preview_settings() {
+        assert!(!Config::default().previews.enabled);
+        let config = Config::parse("[previews]\nenabled = true\nmax_bytes = 16384\ncache_secs = 60").unwrap();
+        assert!(config.previews.enabled && !config.previews.allow_private);
+        assert_eq!(config.previews.max_bytes, 16384);
+        assert_eq!(config.previews.cache_for, Duration::from_secs(60));
+        assert!(Config::parse("[previews]\nmax_bytes = 10").is_err());
+    }
+
+    #[test]
+    fn parses_upload_settings() {
+        assert!(!Config::default().uploads.enabled);
+        let config = Config::parse("[uploads]\nenabled = true\nport = 8000\ndir = \"/var/lib/rustchat\"\nmax_file_bytes = 1000\nquota_bytes = 5000").unwrap();
+        assert!(config.uploads.enabled);
+        assert_eq!(config.uploads.port, 8000);
+        assert_eq!(config.uploads.dir, PathBuf::from("/var/lib/rustchat"));
+        assert_eq!((config.uploads.max_file_bytes, config.uploads.quota_bytes), (1000, 5000));
+        assert!(Config::parse("[uploads]\nport = 70000").is_err());
+        assert!(Config::parse("[uploads]\nmax_file_bytes = 10\nquota_bytes = 5").is_err());
+
+        let config = Config::parse("[voice]\nenabled = true\nport = 6000").unwrap();
+        assert!(config.voice.enabled && config.voice.port == 6000);
+        assert!(Config::parse("[voice]\nport = 0").is_err());
+
+        let config = Config::parse("[metrics]\nenabled = true\nport = 9100").unwrap();
+        assert!(config.metrics.enabled && config.metrics.port == 9100);
+        assert_eq!(Config::default().metrics.port, protocol::DEFAULT_PORT + 3);
+    }
+
+    #[test]
+    fn parses_account_settings() {
+        assert_eq!(Config::default().accounts.file, Some(PathBuf::from("accounts.tsv")));
+        let config = Config::parse("[accounts]\nfile = \"\"\nmemory_kib = 65536\niterations = 3\nparallelism = 4").unwrap();
+        assert_eq!(config.accounts.file, None);
+        assert_eq!(config.accounts.params, Params { memory_kib: 65536, iterations: 3, parallelism: 4 });
+        assert!(Config::parse("[accounts]\niterations = 0").is_err());
+        assert!(Config::parse("[accounts]\nmemory_kib = 16\nparallelism = 4").is_err());
+        assert_eq!(Config::parse("[accounts]\nchallenge_bits = 0").unwrap().accounts.challenge_bits, 0);
+        assert!(Config::parse("[accounts]\nchallenge_bits = 29").is_err());
+
+        let config = Config::parse("[logins]\nmax_failures = 3\nlockout_secs = 60\nfailure_delay_ms = 0").unwrap();
+        assert_eq!((config.logins.max_failures, config.logins.max_failures_per_ip), (3, 20));
+        assert_eq!((config.logins.lockout, config.logins.failure_delay), (Duration::from_secs(60), Duration::ZERO));
+        assert!(Config::parse("[logins]\nmax_failures_per_ip = 0").is_err());
+
+        let config = Config::parse("[nicks]\nlogins_per_minute = 0\nhold_secs = 5").unwrap();
+        assert_eq!((config.nicks.logins_per_minute, config.nicks.names_per_minute, config.nicks.hold), (0, 3, Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn parses_tracing_settings() {
+        assert!(Config::default().tracing.otlp_url.is_none());
+        let config = Config::parse("[tracing]\notlp_url = \"http://localhost:4318/v1/traces\"\nexport_interval_ms = 1000").unwrap();
+        assert_eq!(config.tracing.otlp_url.unwrap().to_string(), "http://localhost:4318/v1/traces");
+        assert_eq!((config.tracing.service_name.as_str(), config.tracing.export_interval), ("rustchat", Duration::from_secs(1)));
+        assert!(Config::parse("[tracing]\notlp_url = \"https://collector\"").is_err());
+    }
+
+    #[test]
+    fn parses_log_format() {
+        assert_eq!(Config::default().log.format, log::Format::Text);
+        assert_eq!(Config::parse("[log]\nformat = \"json\"").unwrap().log.format, log::Format::Json);
+        let args = ["--log-format", "json"].map(String::from);
+        assert_eq!(Config::from_args(&args).unwrap().log.format, log::Format::Json);
+        assert!(Config::parse("[log]\nformat = \"logfmt\"").is_err());
+    }
+
+    #[test]
+    fn parses_access_lists() {
+        let config = Config::parse("[access]\nallow = [\"10.0.0.0/8\", \"::1\"]\ndeny = [\"10.6.6.0/24\"]").unwrap();
+        assert_eq!(config.access.allow.iter().map(|c| c.to_string()).collect::<Vec<_>>(), ["10.0.0.0/8", "::1/128"]);
+        assert_eq!(config.access.deny.len(), 1);
+        assert!(Config::parse("[access]\ndeny = [\"10.0.0.0/40\"]").is_err());
+    }
+
+    #[test]
+    fn parses_spam_rules() {
+        let config = Config::parse("[spam]\nrepeats = 3\nrepeats_window_secs = 10\nrepeats_actions = [\"mute\", \"kick\"]\ndms = 0\nmute_secs = 60").unwrap();
+        assert_eq!((config.spam.repeats.limit, config.spam.repeats.window), (3, Duration::from_secs(10)));
+        assert_eq!(config.spam.repeats.actions, [Action::Mute, Action::Kick]);
+        assert_eq!(config.spam.mentions.actions, [Action::Warn, Action::Mute, Action::Kick]);
+        assert_eq!((config.spam.dms.limit, config.spam.mute), (0, Duration::from_secs(60)));
+        assert!(Config::parse("[spam]\nmentions_actions = [\"ban\"]").is_err());
+        assert!(Config::parse("[spam]\nmentions_actions = []").is_err());
+    }
+
+    #[test]
+    fn parses_permissions() {
+        let config = Config::parse("[permissions]\nmoderators = [\"carol\"]\n[permissions.commands]\nkickid = \"moderator\"\n2fa = \"anyone\"").unwrap();
+        assert_eq!(config.permissions.moderators, ["carol"]);
+        assert_eq!(config.permissions.commands["kickid"], Role::Moderator);
+        assert!(Config::parse("[permissions.commands]\nbanip = \"moderator\"").is_err());
+        assert!(Config::parse("[permissions.commands]\nkick = \"ops\"").is_err());
+    }
+
+    #[test]
+    fn reads_secrets_from_elsewhere() {
+        let file = std::env::temp_dir().join(format!("rustchat-config-key-{}", std::process::id()));
+        std::fs::write(&file, format!("{}\n", "c4".repeat(32))).unwrap();
+        std::env::set_var("RUSTCHAT_TEST_CHANNEL_KEY", "s3cret");
+        let config = Config::parse(&format!("[storage]\nkey = \"file:{}\"\n[channel.staff]\nkey = \"env:RUSTCHAT_TEST_CHANNEL_KEY\"", file.display())).unwrap();
+        assert_eq!(config.storage.key, Key::parse(&"c4".repeat(32)));
+        assert_eq!(config.channels.persistent[0].key.as_deref(), Some("s3cret"));
+        std::fs::remove_file(&file).unwrap();
+
+        let Err(e) = Config::parse("[channel.staff]\nkey = \"env:RUSTCHAT_TEST_UNSET\"") else { panic!("read an unset variable") };
+        assert_eq!(e.to_string(), "channel.staff.key: environment variable RUSTCHAT_TEST_UNSET isn't set");
+        assert!(Config::parse(&format!("[storage]\nkey = \"file:{}\"", file.display())).is_err());
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let mut doc = Document::parse("[queue]\ncapacity = 10\nslow_consumer = \"disconnect\"").unwrap();
+        doc.set("queue", "capacity", "20");
+        doc.set("queue", "slow_consumer", "drop-oldest");
+        let config = Config::from_document(doc).unwrap();
+        assert_eq!(config.queue.capacity, 20);
+        assert_eq!(config.queue.slow_consumer, SlowConsumer::DropOldest);
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        assert!(Config::parse("[queue]\ncapacity = 0").is_err());
+        assert!(Config::parse("[queue]\nslow_consumer = \"block\"").is_err());
+        assert!(Config::parse("[queue]\ncapcity = 10").is_err());
+        assert!(Config::parse("[queue\ncapacity = 10").is_err());
+        assert!(Config::parse("[runtime]\nworker_threads = 0").is_err());
+        assert!(Config::parse("[tcp]\nnodelay = 1").is_err());
+    }
+}
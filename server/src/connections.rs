@@ -0,0 +1,182 @@
+//! What admin's `CONNECTIONS` shows: every connection from its first frame
+//! to its disconnect, with its nickname once it has one, address, queue
+//! depth, bytes read and written, and how long since it last sent anything.
+//! Counters are each connection's own atomics, so keeping them up takes no
+//! lock.
+
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::time::{Duration, Instant};
+
+use protocol::Event;
+
+use crate::{queue::ClientTx, uploads::size};
+
+#[derive(Default)]
+pub struct Connections {
+    all: Mutex<BTreeMap<u64, Arc<Conn>>>,
+}
+
+pub struct Conn {
+    id: u64,
+    addr: SocketAddr,
+    started: Instant,
+    /// Set once the handshake is done.
+    joined: Mutex<Option<(String, ClientTx)>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Milliseconds after `started` of the last frame read.
+    last_read: AtomicU64,
+}
+
+/// Removes its connection from the list when dropped, however the
+/// connection ends.
+pub struct Tracked<'a> {
+    connections: &'a Connections,
+    pub conn: Arc<Conn>,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.connections.all.lock().remove(&self.conn.id);
+    }
+}
+
+impl Connections {
+    pub fn open(&self, id: u64, addr: SocketAddr) -> Tracked<'_> {
+        let conn = Arc::new(Conn {
+            id,
+            addr,
+            started: Instant::now(),
+            joined: Mutex::default(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            last_read: AtomicU64::new(0),
+        });
+        self.all.lock().insert(id, conn.clone());
+        Tracked { connections: self, conn }
+    }
+
+    /// Every connection, oldest first.
+    pub fn list(&self, now: Instant) -> Vec<Snapshot> {
+        let all: Vec<_> = self.all.lock().values().cloned().collect();
+        all.iter().map(|c| c.snapshot(now)).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.all.lock().is_empty()
+    }
+
+    /// Queues `event` for every connection that has finished its handshake.
+    pub fn send_all(&self, event: Event) {
+        let event = Arc::new(event);
+        let all: Vec<_> = self.all.lock().values().cloned().collect();
+        for conn in all {
+            if let Some((_, tx)) = &*conn.joined.lock() {
+                let _ = tx.push(event.clone());
+            }
+        }
+    }
+}
+
+impl Conn {
+    pub fn joined(&self, name: &str, tx: ClientTx) {
+        *self.joined.lock() = Some((name.to_string(), tx));
+    }
+
+    pub fn read(&self, bytes: usize, now: Instant) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_read.store(now.saturating_duration_since(self.started).as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn wrote(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, now: Instant) -> Snapshot {
+        let (name, queued) = match &*self.joined.lock() {
+            Some((name, tx)) => (Some(name.clone()), tx.depth()),
+            None => (None, 0),
+        };
+        let last_read = self.started + Duration::from_millis(self.last_read.load(Ordering::Relaxed));
+        Snapshot {
+            id: self.id,
+            name,
+            addr: self.addr,
+            queued,
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            idle: now.saturating_duration_since(last_read),
+            up: now.saturating_duration_since(self.started),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub id: u64,
+    /// `None` while still in the handshake.
+    pub name: Option<String>,
+    pub addr: SocketAddr,
+    pub queued: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub idle: Duration,
+    pub up: Duration,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}: {} queued, {} in, {} out, idle {}s, up {}s",
+            self.id,
+            self.name.as_deref().unwrap_or("(handshake)"),
+            self.addr,
+            self.queued,
+            size(self.bytes_in),
+            size(self.bytes_out),
+            self.idle.as_secs(),
+            self.up.as_secs()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::QueueConfig, queue};
+
+    #[test]
+    fn lists_connections_until_they_end() {
+        let connections = Connections::default();
+        let addr: SocketAddr = "192.0.2.4:51234".parse().unwrap();
+        let first = connections.open(1, addr);
+        let second = connections.open(2, "192.0.2.5:4000".parse().unwrap());
+        first.conn.read(2048, first.conn.started + Duration::from_secs(5));
+        first.conn.wrote(100);
+        let (tx, _rx) = queue::channel(1, &QueueConfig::default());
+        tx.push(Event::notice("hi")).unwrap();
+        first.conn.joined("alice", tx);
+
+        let now = first.conn.started + Duration::from_secs(30);
+        let list = connections.list(now);
+        assert_eq!(list.iter().map(|s| s.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!((list[0].queued, list[0].bytes_in, list[0].bytes_out), (1, 2048, 100));
+        assert_eq!(list[0].to_string(), "1 alice 192.0.2.4:51234: 1 queued, 2.0 KiB in, 100 B out, idle 25s, up 30s");
+        assert!(list[1].to_string().starts_with("2 (handshake) 192.0.2.5:4000: 0 queued"));
+
+        drop(second);
+        assert_eq!(connections.list(now).len(), 1);
+        drop(first);
+        assert!(connections.list(now).is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+//! Raw DEFLATE (RFC 1951) for per-frame compression. The compressor only emits
+//! fixed-Huffman blocks, which keeps it small; the decompressor accepts any
+//! valid stream so clients can use a stock zlib with `wbits = -15`.
+//!
+//! zstd isn't offered alongside it. Every client library has DEFLATE built
+//! in, while zstd would mean either a C dependency or a decoder several times
+//! the size of this one, with its entropy tables and dictionaries; and on
+//! frames of a few hundred bytes, which is what chat is, the two compress
+//! about as well.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 14;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    out.bits(1, 1); // BFINAL
+    out.bits(1, 2); // BTYPE = fixed Huffman
+
+    let mut chains = Chains { head: vec![usize::MAX; 1 << HASH_BITS], prev: vec![usize::MAX; input.len()] };
+    let mut i = 0;
+    while i < input.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if i + MIN_MATCH <= input.len() {
+            let mut cand = chains.head[hash(input, i)];
+            let max = (input.len() - i).min(MAX_MATCH);
+            for _ in 0..MAX_CHAIN {
+                if cand == usize::MAX || i - cand > WINDOW {
+                    break;
+                }
+                let len = input[cand..].iter().zip(&input[i..i + max]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, i - cand);
+                    if len == max {
+                        break;
+                    }
+                }
+                cand = chains.prev[cand];
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            write_length(&mut out, best_len);
+            write_distance(&mut out, best_dist);
+            for j in i..i + best_len {
+                chains.insert(input, j);
+            }
+            i += best_len;
+        } else {
+            write_literal(&mut out, u16::from(input[i]));
+            chains.insert(input, i);
+            i += 1;
+        }
+    }
+    write_literal(&mut out, 256);
+    out.finish()
+}
+
+/// Hash chains over 3-byte prefixes for finding earlier matches.
+struct Chains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn insert(&mut self, input: &[u8], i: usize) {
+        if i + MIN_MATCH <= input.len() {
+            let h = hash(input, i);
+            self.prev[i] = self.head[h];
+            self.head[h] = i;
+        }
+    }
+}
+
+fn hash(input: &[u8], i: usize) -> usize {
+    let v = u32::from(input[i]) << 16 | u32::from(input[i + 1]) << 8 | u32::from(input[i + 2]);
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_literal(out: &mut BitWriter, sym: u16) {
+    let (code, len) = match sym {
+        0..=143 => (0x30 + sym, 8),
+        144..=255 => (0x190 + sym - 144, 9),
+        256..=279 => (sym - 256, 7),
+        _ => (0xc0 + sym - 280, 8),
+    };
+    out.huffman(code, len);
+}
+
+fn write_length(out: &mut BitWriter, len: usize) {
+    let idx = LEN_BASE.iter().rposition(|&b| usize::from(b) <= len).unwrap_or(0);
+    write_literal(out, 257 + idx as u16);
+    out.bits((len - usize::from(LEN_BASE[idx])) as u32, LEN_EXTRA[idx]);
+}
+
+fn write_distance(out: &mut BitWriter, dist: usize) {
+    let idx = DIST_BASE.iter().rposition(|&b| usize::from(b) <= dist).unwrap_or(0);
+    out.huffman(idx as u16, 5);
+    out.bits((dist - usize::from(DIST_BASE[idx])) as u32, DIST_EXTRA[idx]);
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    n: u8,
+}
+
+impl BitWriter {
+    /// Writes `len` bits of `v`, least significant first.
+    fn bits(&mut self, v: u32, len: u8) {
+        for k in 0..len {
+            self.acc |= ((v >> k) & 1) << self.n;
+            self.n += 1;
+            if self.n == 8 {
+                self.out.push(self.acc as u8);
+                self.acc = 0;
+                self.n = 0;
+            }
+        }
+    }
+
+    /// Huffman codes go most significant bit first.
+    fn huffman(&mut self, code: u16, len: u8) {
+        let rev = u32::from(code.reverse_bits() >> (16 - len));
+        self.bits(rev, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.n > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+/// Inflates `input`, refusing to produce more than `max_out` bytes.
+pub fn decompress(input: &[u8], max_out: usize) -> Option<Vec<u8>> {
+    let mut r = BitReader { buf: input, pos: 0, bit: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let len = usize::from(r.u16()?);
+                let nlen = r.u16()?;
+                if len as u16 != !nlen || out.len() + len > max_out {
+                    return None;
+                }
+                out.extend_from_slice(r.take(len)?);
+            }
+            1 => {
+                let (lit, dist) = fixed_tables();
+                inflate_block(&mut r, &mut out, &lit, &dist, max_out)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut r)?;
+                inflate_block(&mut r, &mut out, &lit, &dist, max_out)?;
+            }
+            _ => return None,
+        }
+        if last {
+            return Some(out);
+        }
+    }
+}
+
+fn inflate_block(r: &mut BitReader, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman, max_out: usize) -> Option<()> {
+    loop {
+        let sym = lit.decode(r)?;
+        match sym {
+            0..=255 => {
+                if out.len() >= max_out {
+                    return None;
+                }
+                out.push(sym as u8);
+            }
+            256 => return Some(()),
+            257..=285 => {
+                let idx = usize::from(sym - 257);
+                let len = usize::from(LEN_BASE[idx]) + r.bits(LEN_EXTRA[idx])? as usize;
+                let didx = usize::from(dist.decode(r)?);
+                if didx >= DIST_BASE.len() {
+                    return None;
+                }
+                let d = usize::from(DIST_BASE[didx]) + r.bits(DIST_EXTRA[didx])? as usize;
+                if d > out.len() || out.len() + len > max_out {
+                    return None;
+                }
+                let start = out.len() - d;
+                for k in 0..len {
+                    out.push(out[start + k]);
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_tables(r: &mut BitReader) -> Option<(Huffman, Huffman)> {
+    let hlit = r.bits(5)? as usize + 257;
+    let hdist = r.bits(5)? as usize + 1;
+    let hclen = r.bits(4)? as usize + 4;
+
+    let mut clens = [0u8; 19];
+    for &idx in &CLEN_ORDER[..hclen] {
+        clens[idx] = r.bits(3)? as u8;
+    }
+    let clen_code = Huffman::new(&clens);
+
+    let mut lengths = vec![0u8; hlit + hdist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let sym = clen_code.decode(r)?;
+        let (val, repeat) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => (*lengths.get(i.checked_sub(1)?)?, 3 + r.bits(2)? as usize),
+            17 => (0, 3 + r.bits(3)? as usize),
+            18 => (0, 11 + r.bits(7)? as usize),
+            _ => return None,
+        };
+        lengths.get_mut(i..i + repeat)?.fill(val);
+        i += repeat;
+    }
+    Some((Huffman::new(&lengths[..hlit]), Huffman::new(&lengths[hlit..])))
+}
+
+/// Canonical Huffman decoding table, decoded one bit at a time.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[usize::from(l)] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for l in 1..16 {
+            offsets[l] = offsets[l - 1] + counts[l - 1];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (sym, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[usize::from(offsets[usize::from(l)])] = sym as u16;
+                offsets[usize::from(l)] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Option<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= r.bits(1)? as i32;
+            let count = i32::from(self.counts[len]);
+            if code - count < first {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    bit: u8,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, n: u8) -> Option<u32> {
+        let mut v = 0;
+        for k in 0..n {
+            let byte = *self.buf.get(self.pos)?;
+            v |= u32::from((byte >> self.bit) & 1) << k;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Some(v)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let s = self.buf.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let long: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8 % 7 + b'a').collect();
+        let inputs: [&[u8]; 5] = [b"", b"x", b"PRIVMSG #rust :hello hello hello hello", &[0; 1000], &long];
+        for input in inputs {
+            let packed = compress(input);
+            assert_eq!(decompress(&packed, input.len()).as_deref(), Some(input));
+        }
+        assert!(compress(&[b'a'; 1000]).len() < 20);
+    }
+
+    #[test]
+    fn reads_what_zlib_writes() {
+        // zlib's stored and dynamic-Huffman blocks, which compress never makes.
+        assert_eq!(decompress(&[1, 5, 0, 250, 255, b'h', b'e', b'l', b'l', b'o'], 100).as_deref(), Some(&b"hello"[..]));
+        let dynamic = [5, 193, 161, 17, 0, 0, 12, 3, 161, 89, 35, 208, 53, 191, 255, 21, 0, 0, 0, 0, 168, 170, 170, 106, 219, 182, 237, 238, 238, 30];
+        let expected = ["e".repeat(34), "t".repeat(13), "a".repeat(8), "o".repeat(5)].concat();
+        assert_eq!(decompress(&dynamic, 100), Some(expected.into_bytes()));
+    }
+
+    #[test]
+    fn refuses_malformed_input() {
+        let packed = compress(b"some text to cut short, some text to cut short");
+        assert_eq!(decompress(&packed[..packed.len() / 2], 1000), None);
+        assert_eq!(decompress(&[], 1000), None);
+        // Block type 3 doesn't exist.
+        assert_eq!(decompress(&[0b111], 1000), None);
+        // A stored block whose length and its complement disagree.
+        assert_eq!(decompress(&[1, 5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o'], 100), None);
+        // A match reaching back before the start of the output.
+        let mut out = BitWriter::default();
+        out.bits(1, 1);
+        out.bits(1, 2);
+        write_length(&mut out, 3);
+        write_distance(&mut out, 1);
+        write_literal(&mut out, 256);
+        assert_eq!(decompress(&out.finish(), 100), None);
+    }
+
+    #[test]
+    fn stops_at_the_output_limit() {
+        let packed = compress(&[b'z'; 10_000]);
+        assert_eq!(decompress(&packed, 10_000).map(|out| out.len()), Some(10_000));
+        assert_eq!(decompress(&packed, 9_999), None);
+        assert_eq!(decompress(&compress(b"hello"), 4), None);
+    }
+}
@@ -0,0 +1,103 @@
+//! Draining for a restart without downtime. Admin's `DRAIN` stops the
+//! server accepting connections and sends everyone connected a `RECONNECT`,
+//! naming another instance if one was given and otherwise leaving them to
+//! come back to this address once the next one is listening on it. The
+//! server exits when the last client has gone or the deadline passes,
+//! whichever is first.
+
+use parking_lot::Mutex;
+use tokio::{
+    sync::Notify,
+    time::{sleep, timeout_at, Duration, Instant},
+};
+
+use crate::connections::Connections;
+
+/// How often the connection count is looked at while draining.
+const CHECK_EVERY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Started {
+    pub deadline: Instant,
+    /// Where clients were told to go.
+    pub to: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Drain {
+    started: Mutex<Option<Started>>,
+    notify: Notify,
+}
+
+impl Drain {
+    /// Starts draining; `false` if it already had.
+    pub fn start(&self, deadline: Instant, to: Option<String>) -> bool {
+        let mut started = self.started.lock();
+        if started.is_some() {
+            return false;
+        }
+        *started = Some(Started { deadline, to });
+        self.notify.notify_waiters();
+        true
+    }
+
+    pub fn started(&self) -> Option<Started> {
+        self.started.lock().clone()
+    }
+
+    /// Waits for `start`.
+    pub async fn wait(&self) -> Started {
+        loop {
+            let notified = self.notify.notified();
+            if let Some(started) = self.started() {
+                return started;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Waits for every connection to end, or for `deadline`; how many were
+/// left.
+pub async fn finish(connections: &Connections, deadline: Instant) -> usize {
+    let _ = timeout_at(deadline, async {
+        while !connections.is_empty() {
+            sleep(CHECK_EVERY).await;
+        }
+    })
+    .await;
+    connections.list(Instant::now()).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn starts_once() {
+        let drain = Drain::default();
+        assert_eq!(drain.started(), None);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let waiting = drain.wait();
+        assert!(drain.start(deadline, Some("chat2.example.com:5555".into())));
+        assert!(!drain.start(deadline, None));
+        let started = Started { deadline, to: Some("chat2.example.com:5555".into()) };
+        assert_eq!(waiting.await, started);
+        assert_eq!(drain.started(), Some(started));
+    }
+
+    #[tokio::test]
+    async fn finishes_when_the_last_connection_ends() {
+        let connections = Connections::default();
+        let tracked = connections.open(1, "192.0.2.4:51234".parse().unwrap());
+        assert_eq!(finish(&connections, Instant::now() + Duration::from_millis(300)).await, 1);
+
+        let start = Instant::now();
+        let (left, ()) = tokio::join!(finish(&connections, start + Duration::from_secs(5)), async {
+            sleep(Duration::from_millis(50)).await;
+            drop(tracked);
+        });
+        assert_eq!(left, 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,166 @@
+//! `:smile:`-style shortcodes, expanded to emoji in DMs and channel messages
+//! as they're written to connections that asked for it with the `emoji` cap.
+//! Messages are kept as they were sent, so history and connections without
+//! the cap see the shortcodes. The table is a built-in set plus, or instead
+//! of, `[emoji.shortcodes]` from the config.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::config::EmojiConfig;
+
+/// Longest shortcode name looked up; anything longer is left alone.
+const MAX_NAME: usize = 32;
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("smile", "😄"),
+    ("grin", "😁"),
+    ("joy", "😂"),
+    ("rofl", "🤣"),
+    ("slightly_smiling_face", "🙂"),
+    ("wink", "😉"),
+    ("blush", "😊"),
+    ("heart_eyes", "😍"),
+    ("kissing_heart", "😘"),
+    ("thinking", "🤔"),
+    ("neutral_face", "😐"),
+    ("rolling_eyes", "🙄"),
+    ("smirk", "😏"),
+    ("sweat_smile", "😅"),
+    ("sob", "😭"),
+    ("cry", "😢"),
+    ("angry", "😠"),
+    ("scream", "😱"),
+    ("flushed", "😳"),
+    ("sleeping", "😴"),
+    ("sunglasses", "😎"),
+    ("upside_down_face", "🙃"),
+    ("exploding_head", "🤯"),
+    ("partying_face", "🥳"),
+    ("skull", "💀"),
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("ok_hand", "👌"),
+    ("wave", "👋"),
+    ("clap", "👏"),
+    ("pray", "🙏"),
+    ("muscle", "💪"),
+    ("raised_hands", "🙌"),
+    ("eyes", "👀"),
+    ("heart", "❤️"),
+    ("broken_heart", "💔"),
+    ("fire", "🔥"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("tada", "🎉"),
+    ("rocket", "🚀"),
+    ("100", "💯"),
+    ("white_check_mark", "✅"),
+    ("x", "❌"),
+    ("warning", "⚠️"),
+    ("question", "❓"),
+    ("bulb", "💡"),
+    ("zap", "⚡"),
+    ("coffee", "☕"),
+    ("tea", "🍵"),
+    ("beer", "🍺"),
+    ("pizza", "🍕"),
+    ("cake", "🍰"),
+    ("crab", "🦀"),
+    ("bug", "🐛"),
+    ("cat", "🐱"),
+    ("dog", "🐶"),
+    ("see_no_evil", "🙈"),
+    ("sun", "☀️"),
+    ("rainbow", "🌈"),
+    ("snowflake", "❄️"),
+    ("shrug", "🤷"),
+    ("facepalm", "🤦"),
+];
+
+/// Whether `name` can be a shortcode: what goes between the colons.
+pub fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"_+-".contains(&b))
+}
+
+#[derive(Debug, Default)]
+pub struct Shortcodes(HashMap<String, String>);
+
+impl Shortcodes {
+    /// The table `config` describes; empty if it's turned off.
+    pub fn new(config: &EmojiConfig) -> Self {
+        if !config.enabled {
+            return Shortcodes::default();
+        }
+        let builtin = BUILTIN.iter().filter(|_| config.builtin).map(|(name, emoji)| (name.to_string(), emoji.to_string()));
+        Shortcodes(builtin.chain(config.shortcodes.iter().map(|(name, emoji)| (name.clone(), emoji.clone()))).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `text` with known shortcodes replaced; unknown ones stay as typed.
+    pub fn expand<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.is_empty() || !text.contains(':') {
+            return Cow::Borrowed(text);
+        }
+        let mut out = String::with_capacity(text.len());
+        let (mut rest, mut changed) = (text, false);
+        while let Some(start) = rest.find(':') {
+            let after = &rest[start + 1..];
+            let known = after.find(':').filter(|&end| is_name(&after[..end])).and_then(|end| Some((end, self.0.get(&after[..end])?)));
+            match known {
+                Some((end, emoji)) => {
+                    out.push_str(&rest[..start]);
+                    out.push_str(emoji);
+                    rest = &after[end + 1..];
+                    changed = true;
+                }
+                // The colon might open the next one, as in "at 10:30 :coffee:".
+                None => {
+                    out.push_str(&rest[..=start]);
+                    rest = after;
+                }
+            }
+        }
+        if !changed {
+            return Cow::Borrowed(text);
+        }
+        out.push_str(rest);
+        Cow::Owned(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Event;
+
+    #[test]
+    fn expands_known_shortcodes() {
+        let codes = Shortcodes::new(&EmojiConfig::default());
+        assert_eq!(codes.expand("ship it :rocket::tada:"), "ship it 🚀🎉");
+        assert_eq!(codes.expand("at 10:30 :coffee:"), "at 10:30 ☕");
+        assert_eq!(codes.expand(":not_an_emoji: :+1:"), ":not_an_emoji: 👍");
+        assert_eq!(codes.expand("std::mem::take"), "std::mem::take");
+        assert!(matches!(codes.expand("no colons"), Cow::Borrowed(_)));
+        assert!(matches!(codes.expand("10:30"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn follows_the_config() {
+        let mut config = EmojiConfig { builtin: false, ..EmojiConfig::default() };
+        config.shortcodes.insert("shipit".into(), "🐿️".into());
+        let codes = Shortcodes::new(&config);
+        assert_eq!(codes.expand(":shipit: :smile:"), "🐿️ :smile:");
+        config.enabled = false;
+        assert!(Shortcodes::new(&config).is_empty());
+
+        let dm = Event::Dm { id: 1, seq: 1, ts: String::new(), from: "bob".into(), from_id: 2, body: "hi :wave:".into() };
+        let codes = Shortcodes::new(&EmojiConfig::default());
+        assert!(matches!(dm.map_body(|b| codes.expand(b)), Some(Event::Dm { body, .. }) if body == "hi 👋"));
+        assert_eq!(Event::notice(":wave:").map_body(|b| codes.expand(b)), None);
+    }
+}
@@ -0,0 +1,323 @@
+use anyhow::{anyhow, Result};
+use std::{fmt::Write as _, path::PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use protocol::{ed25519, json::quote, Event, ExportArgs};
+
+use crate::{
+    accounts,
+    at_rest::{self, Key},
+    clock::{self, now_unix},
+    codec::MAX_FRAME_LEN,
+    history::Entry,
+    profile::Field,
+    http::HttpUrl,
+    schedule::Scheduled,
+    snapshot::Snapshot,
+    uploads::Stored,
+};
+
+const EXPORT_DIR: &str = "exports";
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    fn ext(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// A validated `EXPORT`: known format, dates resolved to a unix time range.
+pub struct Request {
+    pub name: String,
+    pub format: Format,
+    pub since: u64,
+    pub until: u64,
+}
+
+impl Request {
+    pub fn from_args(args: ExportArgs) -> Option<Self> {
+        let since = match &args.since {
+            Some(d) => clock::parse_date(d)?,
+            None => 0,
+        };
+        let until = match &args.until {
+            Some(d) => clock::parse_date_end(d)?,
+            None => u64::MAX,
+        };
+        Some(Request { format: Format::parse(&args.format)?, name: args.name, since, until })
+    }
+}
+
+/// Writes `entries` to `exports/<name>-<unix>.<ext>`, sealed with `key` and
+/// with `.sealed` on the end if there is one, and returns the path. A second
+/// export of the same name within the second gets `-2` after the time, and
+/// so on, rather than replacing the first.
+pub async fn write_export(name: &str, entries: &[Entry], format: Format, key: Option<&Key>) -> Result<PathBuf> {
+    let body = match format {
+        Format::Json => to_json(entries),
+        Format::Csv => to_csv(entries),
+    };
+
+    tokio::fs::create_dir_all(EXPORT_DIR).await?;
+    let safe: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let sealed = if key.is_some() { ".sealed" } else { "" };
+    let data = at_rest::seal(key, &body)?;
+    let now = now_unix();
+    for n in 1.. {
+        let suffix = if n == 1 { String::new() } else { format!("-{n}") };
+        let path = PathBuf::from(EXPORT_DIR).join(format!("{safe}-{now}{suffix}.{}{sealed}", format.ext()));
+        let mut file = match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(anyhow!("failed to create {}: {e}", path.display())),
+        };
+        file.write_all(&data).await.map_err(|e| anyhow!("failed to write {}: {e}", path.display()))?;
+        return Ok(path);
+    }
+    unreachable!("ran out of export names")
+}
+
+pub fn to_json(entries: &[Entry]) -> String {
+    let mut out = String::from("[\n");
+    for (i, e) in entries.iter().enumerate() {
+        out.push_str("  ");
+        out.push_str(&entry_json(e));
+        out.push_str(if i + 1 < entries.len() { ",\n" } else { "\n" });
+    }
+    out.push_str("]\n");
+    out
+}
+
+/// Everything the server holds about one user, for `EXPORTME`.
+pub struct UserData<'a> {
+    pub id: u64,
+    pub name: &'a str,
+    pub registered: bool,
+    pub two_factor: bool,
+    pub signing_key: Option<[u8; ed25519::KEY_LEN]>,
+    /// Their part of the registry: channels they made or operate, profile,
+    /// lists, friends and ephemeral threads.
+    pub state: Snapshot,
+    pub push: Option<HttpUrl>,
+    pub scheduled: Vec<Scheduled>,
+    pub uploads: Vec<(String, Stored)>,
+    pub messages: &'a [Entry],
+}
+
+/// Single-line dump of a [`UserData`]. Channel keys and the avatar's image
+/// are left out; the one isn't theirs and the other they already have.
+pub fn user_dump(data: &UserData) -> String {
+    let name = data.name;
+    let names = |list: &[(String, String)], mine: bool| {
+        let picked = list.iter().filter_map(|(a, b)| if a == name { Some(b) } else if b == name && !mine { Some(a) } else { None });
+        format!("[{}]", picked.map(|n| quote(n)).collect::<Vec<_>>().join(","))
+    };
+    let profile = data.state.profiles.first().map(|(_, p)| p);
+    let mut fields: Vec<String> = Field::ALL.into_iter().filter_map(|f| Some(format!("{}:{}", quote(f.name()), quote(profile?.get(f)?)))).collect();
+    if let Some(avatar) = profile.and_then(|p| p.avatar()) {
+        fields.push(format!("\"avatar\":{{\"format\":{},\"bytes\":{}}}", quote(avatar.format), avatar.len));
+    }
+    let channels: Vec<String> = data
+        .state
+        .channels
+        .iter()
+        .map(|c| format!("{{\"name\":{},\"creator\":{},\"op\":{}}}", quote(&c.name), c.creator.as_deref() == Some(name), c.ops.iter().any(|op| op == name)))
+        .collect();
+    let (sent, received): (Vec<_>, Vec<_>) = data.state.friend_requests.iter().partition(|(from, _)| from == name);
+    let requests = |list: Vec<&(String, String)>, sent: bool| {
+        format!("[{}]", list.iter().map(|(from, to)| quote(if sent { to } else { from })).collect::<Vec<_>>().join(","))
+    };
+    let scheduled: Vec<String> = data
+        .scheduled
+        .iter()
+        .map(|s| format!("{{\"id\":{},\"at\":{},\"kind\":{},\"to\":{},\"body\":{}}}", s.id, s.at, quote(s.kind.label()), quote(&s.to), quote(&s.body)))
+        .collect();
+    let uploads: Vec<String> = data
+        .uploads
+        .iter()
+        .map(|(id, f)| format!("{{\"id\":{},\"name\":{},\"size\":{}}}", quote(id), quote(&f.name), f.size))
+        .collect();
+    let messages: Vec<String> = data.messages.iter().map(entry_json).collect();
+    let optional = |value: Option<String>| value.map_or("null".to_string(), |v| quote(&v));
+    format!(
+        "{{\"id\":{},\"name\":{},\"registered\":{},\"two_factor\":{},\"signing_key\":{},\"profile\":{{{}}},\"watching\":{},\"blocking\":{},\"friends\":{},\"friend_requests\":{{\"sent\":{},\"received\":{}}},\"ephemeral\":{},\"push\":{},\"channels\":[{}],\"scheduled\":[{}],\"uploads\":[{}],\"messages\":[{}]}}",
+        data.id,
+        quote(name),
+        data.registered,
+        data.two_factor,
+        optional(data.signing_key.map(|k| accounts::hex(&k))),
+        fields.join(","),
+        names(&data.state.watching, true),
+        names(&data.state.blocking, true),
+        names(&data.state.friends, false),
+        requests(sent, true),
+        requests(received, false),
+        names(&data.state.ephemeral, false),
+        optional(data.push.as_ref().map(|u| u.to_string())),
+        channels.join(","),
+        scheduled.join(","),
+        uploads.join(","),
+        messages.join(",")
+    )
+}
+
+fn entry_json(e: &Entry) -> String {
+    format!(
+        "{{\"id\":{},\"ts\":{},\"from\":{},\"to\":{},\"body\":{}}}",
+        e.id,
+        e.ts,
+        quote(&e.from),
+        quote(&e.to),
+        quote(&e.body)
+    )
+}
+
+/// A [`user_dump`] as events to send: one `Export` if it fits in a frame,
+/// and otherwise `ExportPart`s. A quarter of a frame leaves room for the
+/// JSON wire to escape every character.
+pub fn dump_events(dump: String) -> Vec<Event> {
+    const PART_LEN: usize = MAX_FRAME_LEN / 4;
+    if dump.len() <= PART_LEN {
+        return vec![Event::Export(dump)];
+    }
+    let mut pieces = Vec::new();
+    let mut rest = dump.as_str();
+    while !rest.is_empty() {
+        let mut end = rest.len().min(PART_LEN);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+    let parts = pieces.len() as u32;
+    pieces.into_iter().zip(1..).map(|(data, part)| Event::ExportPart { part, parts, data: data.to_string() }).collect()
+}
+
+pub fn to_csv(entries: &[Entry]) -> String {
+    let mut out = String::from("id,ts,from,to,body\n");
+    for e in entries {
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{}",
+            e.id,
+            e.ts,
+            csv_field(&e.from),
+            csv_field(&e.to),
+            csv_field(&e.body)
+        );
+    }
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::{Encoder, LengthCodec},
+        schedule::Kind,
+    };
+    use bytes::BytesMut;
+    use protocol::Wire;
+
+    #[test]
+    fn dumps_everything_about_a_user() {
+        let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+        let mut profile = crate::profile::Profile::default();
+        profile.set(Field::Pronouns, Some("she/her".into())).unwrap();
+        let state = Snapshot {
+            profiles: vec![("ada".into(), profile)],
+            watching: vec![pair("ada", "bob")],
+            friends: vec![pair("ada", "carol"), pair("bob", "ada")],
+            friend_requests: vec![pair("ada", "dave"), pair("erin", "ada")],
+            ..Snapshot::default()
+        };
+        let scheduled = vec![Scheduled { id: 3, at: 60, kind: Kind::Reminder, from: "ada".into(), to: "ada".into(), body: "tea".into() }];
+        let uploads = vec![("ab12".into(), Stored { owner: "ada".into(), size: 5, name: "notes.txt".into() })];
+        let messages = [Entry { id: 9, seq: 1, ts: 1, from: "bob".into(), from_id: 2, to: "ada".into(), body: "hi".into() }];
+        let dump = user_dump(&UserData {
+            id: 1,
+            name: "ada",
+            registered: true,
+            two_factor: false,
+            signing_key: None,
+            state,
+            push: HttpUrl::parse("http://example.org/ada").ok(),
+            scheduled,
+            uploads,
+            messages: &messages,
+        });
+        for part in [
+            r#""registered":true,"two_factor":false,"signing_key":null"#,
+            r#""profile":{"pronouns":"she/her"}"#,
+            r#""watching":["bob"],"blocking":[],"friends":["carol","bob"]"#,
+            r#""friend_requests":{"sent":["dave"],"received":["erin"]}"#,
+            r#""push":"http://example.org:80/ada""#,
+            r#""scheduled":[{"id":3,"at":60,"kind":"remind","to":"ada","body":"tea"}]"#,
+            r#""uploads":[{"id":"ab12","name":"notes.txt","size":5}]"#,
+            r#""messages":[{"id":9,"ts":1,"from":"bob","to":"ada","body":"hi"}]"#,
+        ] {
+            assert!(dump.contains(part), "{part} not in {dump}");
+        }
+    }
+
+    #[test]
+    fn sends_big_dumps_in_parts() {
+        let messages: Vec<_> = (0..2000).map(|id| Entry { id, seq: id, ts: 1, from: "bob".into(), from_id: 2, to: "ada".into(), body: "\"quoted\" and ünïcode ✓".repeat(2) }).collect();
+        let dump = user_dump(&UserData {
+            id: 1,
+            name: "ada",
+            registered: false,
+            two_factor: false,
+            signing_key: None,
+            state: Snapshot::default(),
+            push: None,
+            scheduled: Vec::new(),
+            uploads: Vec::new(),
+            messages: &messages,
+        });
+        assert!(dump.len() > MAX_FRAME_LEN);
+
+        let events = dump_events(dump.clone());
+        assert!(events.len() > 1);
+        let mut joined = String::new();
+        for (event, n) in events.iter().zip(1..) {
+            let Event::ExportPart { part, parts, data } = event else { panic!("{event:?}") };
+            assert_eq!((*part, *parts as usize), (n, events.len()));
+            joined.push_str(data);
+            for wire in [Wire::Text, Wire::Json, Wire::Protobuf, Wire::MsgPack] {
+                LengthCodec::new(MAX_FRAME_LEN).encode(&event.encode(wire), &mut BytesMut::new()).unwrap();
+            }
+        }
+        assert_eq!(joined, dump);
+        assert_eq!(dump_events("{}".into()), [Event::Export("{}".into())]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Upgrading without a gap in listening. On SIGUSR2 the server starts its
+//! own executable again with the same arguments, handing the new process
+//! its listening sockets as inherited descriptors named in
+//! `RUSTCHAT_LISTEN_FDS` (`chat=5,uploads=6`). Once the new process says it's
+//! ready the old one drains as `DRAIN` does, its clients reconnecting to the
+//! same address and so to the new process. The sockets are never closed, so
+//! a connection that arrives meanwhile waits in the backlog for whichever
+//! process accepts it. If the new process fails to start, the old one
+//! carries on as if nothing happened.
+//!
+//! Accounts and the schedule are read from disk when the new process
+//! starts, as is a snapshot the old one takes just before starting it (see
+//! `snapshot`); anything the old one changes after that is left to it.
+
+use anyhow::{anyhow, bail, Result};
+
+/// Where the inherited descriptors are named.
+pub const LISTEN_FDS: &str = "RUSTCHAT_LISTEN_FDS";
+/// The descriptor the new process writes a byte to once it's ready.
+pub const READY_FD: &str = "RUSTCHAT_READY_FD";
+
+#[cfg(unix)]
+pub use unix::*;
+
+/// Parses `chat=5,uploads=6`.
+pub fn parse(value: &str) -> Result<Vec<(String, i32)>> {
+    value
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, fd) = entry.split_once('=').ok_or_else(|| anyhow!("{LISTEN_FDS}: expected name=fd, not {entry:?}"))?;
+            let fd = fd.parse().ok().filter(|&fd: &i32| fd > 2).ok_or_else(|| anyhow!("{LISTEN_FDS}: {fd:?} isn't a descriptor"))?;
+            if name.is_empty() {
+                bail!("{LISTEN_FDS}: {entry:?} has no name");
+            }
+            Ok((name.to_string(), fd))
+        })
+        .collect()
+}
+
+pub fn format(fds: &[(&str, i32)]) -> String {
+    fds.iter().map(|(name, fd)| format!("{name}={fd}")).collect::<Vec<_>>().join(",")
+}
+
+/// Sockets inherited from the process this one is taking over from.
+#[derive(Debug, Default)]
+pub struct Inherited {
+    fds: Vec<(String, i32)>,
+    ready: Option<i32>,
+}
+
+impl Inherited {
+    /// What the environment names, taken out of it so nothing started from
+    /// here inherits the names without the descriptors. Call it before any
+    /// threads start.
+    pub fn from_env() -> Result<Self> {
+        if !cfg!(unix) {
+            return Ok(Inherited::default());
+        }
+        let fds = match std::env::var(LISTEN_FDS) {
+            Ok(value) => parse(&value)?,
+            Err(_) => Vec::new(),
+        };
+        let ready = std::env::var(READY_FD).ok().and_then(|fd| fd.parse().ok());
+        std::env::remove_var(LISTEN_FDS);
+        std::env::remove_var(READY_FD);
+        Ok(Inherited { fds, ready })
+    }
+
+    #[cfg(unix)]
+    fn take(&mut self, name: &str) -> Option<std::os::fd::OwnedFd> {
+        let i = self.fds.iter().position(|(n, _)| n == name)?;
+        Some(owned(self.fds.remove(i).1))
+    }
+
+    pub fn tcp(&mut self, name: &str) -> Result<Option<tokio::net::TcpListener>> {
+        #[cfg(unix)]
+        if let Some(fd) = self.take(name) {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true)?;
+            return Ok(Some(tokio::net::TcpListener::from_std(listener)?));
+        }
+        Ok(None)
+    }
+
+    pub fn udp(&mut self, name: &str) -> Result<Option<tokio::net::UdpSocket>> {
+        #[cfg(unix)]
+        if let Some(fd) = self.take(name) {
+            let socket = std::net::UdpSocket::from(fd);
+            socket.set_nonblocking(true)?;
+            return Ok(Some(tokio::net::UdpSocket::from_std(socket)?));
+        }
+        Ok(None)
+    }
+
+    /// Tells the old process it can start draining.
+    pub fn ready(&mut self) {
+        #[cfg(unix)]
+        if let Some(fd) = self.ready.take() {
+            use std::io::Write;
+            let _ = std::os::unix::net::UnixStream::from(owned(fd)).write_all(&[1]);
+        }
+    }
+}
+
+impl Drop for Inherited {
+    /// Closes any that the config no longer has a use for.
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        for (_, fd) in self.fds.drain(..) {
+            drop(owned(fd));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn owned(fd: i32) -> std::os::fd::OwnedFd {
+    use std::os::fd::FromRawFd;
+    // SAFETY: the process that started this one left it open for us, and
+    // each is named once and taken once.
+    unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) }
+}
+
+#[cfg(unix)]
+mod unix {
+    use anyhow::{anyhow, Context, Result};
+    use socket2::SockRef;
+    use std::{
+        os::fd::{AsRawFd, BorrowedFd},
+        process::{Child, Command},
+    };
+    use tokio::{io::AsyncReadExt, time::timeout};
+
+    use super::{format, LISTEN_FDS, READY_FD};
+
+    /// How long the new process has to say it's ready.
+    const READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+    /// Starts this executable again with the same arguments and copies of
+    /// `sockets`, and waits for it to be ready; the new process, or why it
+    /// isn't taking over.
+    pub async fn spawn(sockets: &[(&str, BorrowedFd<'_>)]) -> Result<Child> {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+        // Copies without close-on-exec, so they survive into the new process;
+        // ours close when these are dropped.
+        let mut copies = Vec::new();
+        for (name, fd) in sockets {
+            let copy = SockRef::from(fd).try_clone()?;
+            copy.set_cloexec(false)?;
+            copies.push((*name, copy));
+        }
+        SockRef::from(&theirs).set_cloexec(false)?;
+        let named: Vec<_> = copies.iter().map(|(name, copy)| (*name, copy.as_raw_fd())).collect();
+
+        let exe = std::env::current_exe().context("can't find the server's executable")?;
+        let mut child = Command::new(exe)
+            .args(std::env::args_os().skip(1))
+            .env(LISTEN_FDS, format(&named))
+            .env(READY_FD, theirs.as_raw_fd().to_string())
+            .spawn()
+            .context("can't start the new server")?;
+        drop((copies, theirs));
+
+        ours.set_nonblocking(true)?;
+        let mut ours = tokio::net::UnixStream::from_std(ours)?;
+        let mut byte = [0];
+        match timeout(READY_TIMEOUT, ours.read(&mut byte)).await {
+            Ok(Ok(1)) => Ok(child),
+            outcome => {
+                let _ = child.kill();
+                tokio::task::spawn_blocking(move || child.wait());
+                Err(match outcome {
+                    Err(_) => anyhow!("the new server wasn't ready within {}s", READY_TIMEOUT.as_secs()),
+                    _ => anyhow!("the new server exited before it was ready"),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_descriptors() {
+        assert_eq!(format(&[("chat", 5), ("uploads", 6)]), "chat=5,uploads=6");
+        assert_eq!(parse("chat=5,uploads=6").unwrap(), [("chat".to_string(), 5), ("uploads".to_string(), 6)]);
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("chat").is_err());
+        assert!(parse("chat=1").is_err());
+        assert!(parse("=7").is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use protocol::{Event, SeqRange};
+
+use crate::clock;
+
+// Oldest messages are dropped once the log grows past this.
+const HISTORY_LIMIT: usize = 10_000;
+/// The most a channel or DM thread keeps, whatever its retention says.
+pub const CHANNEL_LIMIT: usize = 1_000;
+
+#[derive(Clone)]
+pub struct Entry {
+    pub id: u64,
+    /// Position in the recipient's stream of DMs, starting at 1.
+    pub seq: u64,
+    pub ts: u64,
+    pub from: String,
+    pub from_id: u64,
+    pub to: String,
+    pub body: String,
+}
+
+impl Entry {
+    /// The DM as it was delivered to the recipient.
+    pub fn to_event(&self) -> Event {
+        Event::Dm {
+            id: self.id,
+            seq: self.seq,
+            ts: clock::rfc3339(self.ts),
+            from: self.from.clone(),
+            from_id: self.from_id,
+            body: self.body.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    entries: VecDeque<Entry>,
+    last_seq: HashMap<String, u64>,
+    /// DM threads for `HISTORY @name`, by the pair of names in order.
+    threads: HashMap<(String, String), MessageLog>,
+    thread_retention: Retention,
+}
+
+impl History {
+    /// `id` and `ts` are the stamps the message was delivered with. Returns
+    /// the message's sequence number for `to`.
+    pub fn record(&mut self, id: u64, ts: u64, from: &str, from_id: u64, to: &str, body: &str) -> u64 {
+        if self.entries.len() >= HISTORY_LIMIT {
+            self.entries.pop_front();
+        }
+        let seq = self.last_seq.entry(to.to_string()).or_default();
+        *seq += 1;
+        let seq = *seq;
+        self.entries.push_back(Entry {
+            id,
+            seq,
+            ts,
+            from: from.to_string(),
+            from_id,
+            to: to.to_string(),
+            body: body.to_string(),
+        });
+        if self.thread_retention != Retention::None {
+            let event = Event::Dm { id, seq, ts: clock::rfc3339(ts), from: from.to_string(), from_id, body: body.to_string() };
+            let retention = self.thread_retention;
+            self.threads.entry(pair(from, to)).or_insert_with(|| MessageLog::new(retention)).record(ts, Arc::new(event));
+        }
+        seq
+    }
+
+    /// Applies to threads already kept, too.
+    pub fn set_thread_retention(&mut self, retention: Retention, now: u64) {
+        self.thread_retention = retention;
+        for log in self.threads.values_mut() {
+            log.set_retention(retention, now);
+        }
+        self.threads.retain(|_, log| !log.is_empty());
+    }
+
+    /// A page of the DMs between `a` and `b`, as for [`MessageLog::page`];
+    /// `None` if DM threads aren't kept.
+    pub fn thread(&mut self, a: &str, b: &str, before: Option<u64>, limit: usize, now: u64) -> Option<Page> {
+        if self.thread_retention == Retention::None {
+            return None;
+        }
+        let Some(log) = self.threads.get_mut(&pair(a, b)) else {
+            return Some(Page::default());
+        };
+        log.prune(now);
+        Some(log.page(before, limit))
+    }
+
+    /// Forgets threads' messages their retention no longer covers.
+    pub fn prune_threads(&mut self, now: u64) {
+        for log in self.threads.values_mut() {
+            log.prune(now);
+        }
+        self.threads.retain(|_, log| !log.is_empty());
+    }
+
+    /// The DM with this ID, if it's still kept.
+    pub fn get(&self, id: u64) -> Option<&Entry> {
+        let i = self.entries.partition_point(|e| e.id < id);
+        self.entries.get(i).filter(|e| e.id == id)
+    }
+
+    /// DMs delivered to `name` whose sequence numbers fall in `range`.
+    pub fn received(&self, name: &str, range: SeqRange) -> Vec<Entry> {
+        let to = range.to.unwrap_or(u64::MAX);
+        self.entries
+            .iter()
+            .filter(|e| e.to == name && e.seq >= range.from && e.seq <= to)
+            .cloned()
+            .collect()
+    }
+
+    /// Every message sent or received by `name` with `since <= ts <= until`.
+    pub fn for_user(&self, name: &str, since: u64, until: u64) -> Vec<Entry> {
+        self.entries
+            .iter()
+            .filter(|e| e.from == name || e.to == name)
+            .filter(|e| e.ts >= since && e.ts <= until)
+            .cloned()
+            .collect()
+    }
+
+    /// Drops every message sent or received by `name`, returning how many were removed.
+    pub fn purge_user(&mut self, name: &str) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.from != name && e.to != name);
+        self.last_seq.remove(name);
+        self.threads.retain(|(a, b), _| a != name && b != name);
+        before - self.entries.len()
+    }
+}
+
+/// How many of a channel's or DM thread's messages are kept for `HISTORY`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Retention {
+    #[default]
+    None,
+    /// The last this many messages.
+    Last(usize),
+    /// Messages younger than this.
+    For(Duration),
+}
+
+impl Retention {
+    /// `none`, `<n> messages`, or an age like `90s`, `30m`, `12h` or `7d`.
+    pub fn parse(s: &str) -> Option<Retention> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("none") {
+            return Some(Retention::None);
+        }
+        if let Some(n) = s.strip_suffix("messages").or_else(|| s.strip_suffix("message")) {
+            return match n.trim().parse() {
+                Ok(0) => Some(Retention::None),
+                Ok(n) => Some(Retention::Last(n)),
+                Err(_) => None,
+            };
+        }
+        let unit = match s.chars().last()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return None,
+        };
+        let n: u64 = s[..s.len() - 1].parse().ok()?;
+        Some(if n == 0 { Retention::None } else { Retention::For(Duration::from_secs(n.checked_mul(unit)?)) })
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retention::None => f.write_str("none"),
+            Retention::Last(1) => f.write_str("1 message"),
+            Retention::Last(n) => write!(f, "{n} messages"),
+            Retention::For(age) => match age.as_secs() {
+                secs if secs % 86_400 == 0 => write!(f, "{}d", secs / 86_400),
+                secs if secs % 3600 == 0 => write!(f, "{}h", secs / 3600),
+                secs if secs % 60 == 0 => write!(f, "{}m", secs / 60),
+                secs => write!(f, "{secs}s"),
+            },
+        }
+    }
+}
+
+/// Some of a channel's or DM thread's kept messages, oldest first.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Page {
+    pub events: Vec<Arc<Event>>,
+    /// There are older ones to page back to.
+    pub more: bool,
+}
+
+/// One channel's or DM thread's kept messages, as the events they were
+/// delivered as.
+#[derive(Default)]
+pub struct MessageLog {
+    retention: Retention,
+    entries: VecDeque<(u64, Arc<Event>)>,
+}
+
+impl MessageLog {
+    pub fn new(retention: Retention) -> Self {
+        MessageLog { retention, entries: VecDeque::new() }
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Applies right away to what's already kept.
+    pub fn set_retention(&mut self, retention: Retention, now: u64) {
+        self.retention = retention;
+        self.prune(now);
+    }
+
+    pub fn record(&mut self, ts: u64, event: Arc<Event>) {
+        if self.retention != Retention::None {
+            self.entries.push_back((ts, event));
+            self.prune(ts);
+        }
+    }
+
+    /// Drops whatever the retention no longer covers.
+    pub fn prune(&mut self, now: u64) {
+        let keep = match self.retention {
+            Retention::None => 0,
+            Retention::Last(n) => n.min(CHANNEL_LIMIT),
+            Retention::For(_) => CHANNEL_LIMIT,
+        };
+        while self.entries.len() > keep {
+            self.entries.pop_front();
+        }
+        if let Retention::For(age) = self.retention {
+            let cutoff = now.saturating_sub(age.as_secs());
+            while self.entries.front().is_some_and(|(ts, _)| *ts < cutoff) {
+                self.entries.pop_front();
+            }
+        }
+    }
+
+    /// Up to `limit` of the newest messages, or of those with IDs below
+    /// `before` if given.
+    pub fn page(&self, before: Option<u64>, limit: usize) -> Page {
+        let end = match before {
+            Some(before) => self.entries.partition_point(|(_, event)| message_id(event) < before),
+            None => self.entries.len(),
+        };
+        let start = end.saturating_sub(limit);
+        Page { events: self.entries.range(start..end).map(|(_, event)| event.clone()).collect(), more: start > 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The messages kept from `since` to `until` inclusive, oldest first, as
+    /// entries addressed to the channel, for `EXPORT`.
+    pub fn between(&self, since: u64, until: u64) -> Vec<Entry> {
+        self.entries
+            .iter()
+            .filter(|(ts, _)| (since..=until).contains(ts))
+            .filter_map(|(ts, event)| match &**event {
+                Event::ChannelMsg { channel, id, from, from_id, body, .. } => {
+                    Some(Entry { id: *id, seq: 0, ts: *ts, from: from.clone(), from_id: *from_id, to: channel.clone(), body: body.clone() })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Everything kept, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Event>> {
+        self.entries.iter().map(|(_, event)| event)
+    }
+
+    /// Forgets everything kept, returning how many messages that was.
+    pub fn purge(&mut self) -> usize {
+        std::mem::take(&mut self.entries).len()
+    }
+}
+
+/// The server-wide ID a message was stamped with; 0 for other events.
+pub fn message_id(event: &Event) -> u64 {
+    match event {
+        Event::Dm { id, .. } | Event::ChannelMsg { id, .. } => *id,
+        _ => 0,
+    }
+}
+
+/// Whether `event` is a message containing `query`, ignoring case, and sent
+/// by `from` if given. `query` must already be lowercase.
+pub fn matches(event: &Event, query: &str, from: Option<&str>) -> bool {
+    let (sender, body) = match event {
+        Event::Dm { from, body, .. } | Event::ChannelMsg { from, body, .. } => (from, body),
+        _ => return false,
+    };
+    from.is_none_or(|f| f == sender) && body.to_lowercase().contains(query)
+}
+
+/// The key for the thread between `a` and `b`, whichever of them sent.
+pub(crate) fn pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_retention() {
+        assert_eq!(Retention::parse("none"), Some(Retention::None));
+        assert_eq!(Retention::parse("100 messages"), Some(Retention::Last(100)));
+        assert_eq!(Retention::parse("7d"), Some(Retention::For(Duration::from_secs(7 * 86_400))));
+        assert_eq!(Retention::parse("0s"), Some(Retention::None));
+        assert_eq!(Retention::parse("forever"), None);
+        assert_eq!(Retention::parse("7 days"), None);
+        for policy in ["none", "1 message", "100 messages", "90s", "30m", "12h", "7d"] {
+            assert_eq!(Retention::parse(policy).unwrap().to_string(), policy);
+        }
+    }
+
+    #[test]
+    fn channel_log_keeps_what_retention_allows() {
+        let msg = |n: u64| Arc::new(Event::ChannelMsg { channel: "#r".into(), id: n, ts: String::new(), from: "bob".into(), from_id: 1, body: n.to_string() });
+        let mut log = MessageLog::new(Retention::Last(2));
+        for n in 1..=3 {
+            log.record(n, msg(n));
+        }
+        assert_eq!(log.page(None, 10).events, [msg(2), msg(3)]);
+        assert_eq!(log.page(None, 1), Page { events: vec![msg(3)], more: true });
+        assert_eq!(log.page(Some(3), 10), Page { events: vec![msg(2)], more: false });
+        assert_eq!(log.page(Some(2), 10), Page::default());
+        let exported: Vec<_> = log.between(3, 9).into_iter().map(|e| (e.id, e.ts, e.to, e.body)).collect();
+        assert_eq!(exported, [(3, 3, "#r".to_string(), "3".to_string())]);
+
+        log.set_retention(Retention::For(Duration::from_secs(60)), 3);
+        log.record(100, msg(100));
+        assert_eq!(log.page(None, 10).events, [msg(100)]);
+        log.set_retention(Retention::None, 100);
+        assert!(log.page(None, 10).events.is_empty());
+        log.record(101, msg(101));
+        assert_eq!(log.purge(), 0);
+    }
+
+    #[test]
+    fn dm_threads_are_shared_by_both_sides() {
+        let mut history = History::default();
+        assert_eq!(history.thread("alice", "bob", None, 10, 10), None);
+        history.record(1, 10, "alice", 1, "bob", "not kept");
+        history.set_thread_retention(Retention::Last(10), 10);
+        history.record(2, 11, "alice", 1, "bob", "hi bob");
+        history.record(3, 12, "bob", 2, "alice", "hi alice");
+        history.record(4, 13, "bob", 2, "carol", "elsewhere");
+
+        let bodies = |page: Page| {
+            page.events.iter().map(|e| if let Event::Dm { body, .. } = &**e { body.clone() } else { panic!("{e:?}") }).collect::<Vec<_>>()
+        };
+        assert_eq!(bodies(history.thread("alice", "bob", None, 10, 13).unwrap()), ["hi bob", "hi alice"]);
+        assert_eq!(bodies(history.thread("bob", "alice", None, 1, 13).unwrap()), ["hi alice"]);
+        assert_eq!(history.thread("alice", "carol", None, 10, 13), Some(Page::default()));
+
+        history.purge_user("alice");
+        assert_eq!(history.thread("bob", "alice", None, 10, 13), Some(Page::default()));
+        assert_eq!(bodies(history.thread("carol", "bob", None, 10, 13).unwrap()), ["elsewhere"]);
+
+        history.set_thread_retention(Retention::None, 13);
+        assert_eq!(history.thread("carol", "bob", None, 10, 13), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Just enough HTTP/1.1 client for the server's outgoing requests: push
+//! notifications, link previews, trace export and alerts. Plain `http://`
+//! only, as there's no TLS here.
+
+use anyhow::{bail, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+    time::{timeout, Duration},
+};
+
+/// Longest URL accepted.
+const MAX_URL: usize = 512;
+/// How long `post_json` waits for an answer.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl HttpUrl {
+    /// `http://host[:port][/path]`; `Err` says what's wrong.
+    pub fn parse(url: &str) -> Result<HttpUrl, String> {
+        if url.len() > MAX_URL {
+            return Err(format!("URLs are at most {MAX_URL} characters"));
+        }
+        let rest = url.strip_prefix("http://").ok_or("URLs start with http://")?;
+        if rest.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err("URLs can't contain spaces".into());
+        }
+        let (authority, path) = rest.find(['/', '?', '#']).map_or((rest, "/"), |i| rest.split_at(i));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("bad port in {url}"))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() || authority.contains('@') {
+            return Err(format!("no host in {url}"));
+        }
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{path}") };
+        // The fragment is the browser's business.
+        let path = path.split('#').next().unwrap_or("/").to_string();
+        Ok(HttpUrl { host: host.to_string(), port, path })
+    }
+}
+
+impl std::fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    head: String,
+    /// As much of it as came within the limit.
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// Sends `request` to `addr` and reads the response, up to `max` bytes of
+/// it all told.
+pub async fn exchange(addr: impl ToSocketAddrs, request: &str, max: usize) -> Result<Response> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.take(max as u64).read_to_end(&mut response).await?;
+    // "HTTP/1.1 200 OK"
+    let status = response.get(9..12).and_then(|s| std::str::from_utf8(s).ok()).and_then(|s| s.parse().ok());
+    let Some(status) = status.filter(|_| response.starts_with(b"HTTP/1.")) else {
+        bail!("not an HTTP response");
+    };
+    let (head, body) = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => {
+            let body = response.split_off(end + 4);
+            (response, body)
+        }
+        None => (response, Vec::new()),
+    };
+    Ok(Response { status, head: String::from_utf8_lossy(&head).into_owned(), body })
+}
+
+/// POSTs `body` as JSON, succeeding on any 2xx answer.
+pub async fn post_json(url: &HttpUrl, body: &str) -> Result<()> {
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        body.len()
+    );
+    // The status line is all that matters.
+    let response = timeout(POST_TIMEOUT, exchange((url.host.as_str(), url.port), &request, 12)).await??;
+    match response.status {
+        200..=299 => Ok(()),
+        code => bail!("{url} answered {code}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_http_urls() {
+        let url = HttpUrl::parse("http://ntfy.lan:8080/alice-dms").unwrap();
+        assert_eq!(url, HttpUrl { host: "ntfy.lan".into(), port: 8080, path: "/alice-dms".into() });
+        assert_eq!(HttpUrl::parse("http://ntfy.lan").unwrap().to_string(), "http://ntfy.lan:80/");
+        assert_eq!(HttpUrl::parse("http://example.com?q=1#top").unwrap().path, "/?q=1");
+        assert!(HttpUrl::parse("https://ntfy.sh/alice").is_err());
+        assert!(HttpUrl::parse("http://:80/x").is_err());
+        assert!(HttpUrl::parse("http://user@host/x").is_err());
+        assert!(HttpUrl::parse("http://host:port/x").is_err());
+    }
+}
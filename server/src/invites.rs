@@ -0,0 +1,91 @@
+//! Invite tokens: handed out by `INVITE`, embedded in a
+//! `rustchat://host:port/#room?token=...` link, and redeemed with `TOKEN`
+//! during the handshake. A token can name a channel to join on arrival,
+//! expires after a while, and a single-use one belongs to the first
+//! nickname that redeems it, so that user can still reconnect with it.
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::random;
+
+#[derive(Default)]
+pub struct Invites {
+    tokens: Mutex<HashMap<String, Invite>>,
+}
+
+struct Invite {
+    channel: Option<String>,
+    once: bool,
+    expires: Instant,
+    used_by: Option<String>,
+}
+
+impl Invites {
+    /// A fresh token, valid for `ttl`.
+    pub fn create(&self, channel: Option<String>, once: bool, ttl: Duration) -> String {
+        let token = random::hex::<16>();
+        let mut tokens = self.tokens.lock();
+        let now = Instant::now();
+        tokens.retain(|_, invite| invite.expires > now);
+        tokens.insert(token.clone(), Invite { channel, once, expires: now + ttl, used_by: None });
+        token
+    }
+
+    /// Checks `token` for `name`, returning the channel it invites to.
+    pub fn redeem(&self, token: &str, name: &str) -> Result<Option<String>, &'static str> {
+        let mut tokens = self.tokens.lock();
+        let invite = tokens.get_mut(token).ok_or("unknown invite")?;
+        if invite.expires <= Instant::now() {
+            tokens.remove(token);
+            return Err("invite has expired");
+        }
+        if invite.once {
+            match &invite.used_by {
+                Some(user) if user != name => return Err("invite has already been used"),
+                Some(_) => {}
+                None => invite.used_by = Some(name.to_string()),
+            }
+        }
+        Ok(invite.channel.clone())
+    }
+}
+
+/// The link for `token` on a server reached at `addr`.
+pub fn link(addr: SocketAddr, channel: Option<&str>, token: &str) -> String {
+    format!("rustchat://{addr}/{}?token={token}", channel.unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn single_use_invites_stick_to_one_name() {
+        let invites = Invites::default();
+        let once = invites.create(Some("#rust".into()), true, Duration::from_secs(60));
+        let open = invites.create(None, false, Duration::from_secs(60));
+        assert_ne!(once, open);
+        assert_eq!(invites.redeem(&once, "alice"), Ok(Some("#rust".into())));
+        assert_eq!(invites.redeem(&once, "alice"), Ok(Some("#rust".into())));
+        assert_eq!(invites.redeem(&once, "bob"), Err("invite has already been used"));
+        assert_eq!(invites.redeem(&open, "bob"), Ok(None));
+        assert_eq!(invites.redeem(&open, "carol"), Ok(None));
+        assert_eq!(invites.redeem("nope", "bob"), Err("unknown invite"));
+
+        let expired = invites.create(None, false, Duration::ZERO);
+        assert_eq!(invites.redeem(&expired, "bob"), Err("invite has expired"));
+    }
+
+    #[test]
+    fn builds_links() {
+        let addr = "192.0.2.2:5555".parse().unwrap();
+        assert_eq!(link(addr, Some("#rust"), "ab12"), "rustchat://192.0.2.2:5555/#rust?token=ab12");
+        assert_eq!(link(addr, None, "ab12"), "rustchat://192.0.2.2:5555/?token=ab12");
+    }
+}
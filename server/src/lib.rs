@@ -0,0 +1,46 @@
+//! The server's building blocks, split from the binary so benchmarks can
+//! drive them directly.
+
+pub mod access;
+pub mod accounts;
+pub mod alerts;
+pub mod argon2;
+pub mod at_rest;
+pub mod channels;
+pub mod clock;
+pub mod codec;
+pub mod config;
+pub mod connections;
+pub mod deflate;
+pub mod drain;
+pub mod emoji;
+pub mod export;
+pub mod handoff;
+pub mod history;
+pub mod http;
+pub mod invites;
+pub mod log;
+pub mod mdns;
+pub mod metrics;
+pub mod nicks;
+pub mod permissions;
+pub mod poll;
+pub mod preview;
+pub mod profile;
+pub mod push;
+pub mod queue;
+pub mod random;
+pub mod registry;
+pub mod schedule;
+pub mod sanitize;
+pub mod senders;
+pub mod snapshot;
+pub mod spam;
+pub mod throttle;
+pub mod totp;
+pub mod trace;
+pub mod transport;
+pub mod uploads;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
+pub mod voice;
@@ -0,0 +1,170 @@
+//! The server's log. `info!` lines go to stdout and `warn!` lines to stderr,
+//! as `[EVENT] message` by default or, with `--log-format json` (`[log]
+//! format`), as one JSON object per line for Loki or ELK to take in:
+//!
+//! `{"timestamp":"2026-10-15T08:30:00.123Z","level":"info","event":"login","client_id":1,"nick":"alice","message":"...","fields":{...}}`
+//!
+//! `client_id` and `nick` come from the connection the line was logged for,
+//! set with `identify` inside `scoped`; `fields` are the ones given before a
+//! `;`, as in `info!("SLOW", queued = n; "...")`.
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Write as _},
+    future::Future,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use protocol::json::quote;
+
+use crate::clock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(format!("expected \"text\" or \"json\", not {other:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+#[derive(Default)]
+struct Client {
+    id: Option<u64>,
+    nick: Option<String>,
+}
+
+tokio::task_local! {
+    static CLIENT: RefCell<Client>;
+}
+
+/// Runs a connection's task with somewhere for `identify` to put who it is.
+pub async fn scoped<F: Future>(f: F) -> F::Output {
+    CLIENT.scope(RefCell::default(), f).await
+}
+
+/// Tags the lines logged from here on in this task with `id`, and `nick`
+/// once there is one.
+pub fn identify(id: u64, nick: Option<&str>) {
+    let _ = CLIENT.try_with(|c| *c.borrow_mut() = Client { id: Some(id), nick: nick.map(str::to_string) });
+}
+
+#[macro_export]
+macro_rules! info {
+    ($event:literal, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::log::emit($crate::log::Level::Info, $event, &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),+], format_args!($($arg)+))
+    };
+    ($event:literal, $($arg:tt)+) => {
+        $crate::log::emit($crate::log::Level::Info, $event, &[], format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($event:literal, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::log::emit($crate::log::Level::Warn, $event, &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),+], format_args!($($arg)+))
+    };
+    ($event:literal, $($arg:tt)+) => {
+        $crate::log::emit($crate::log::Level::Warn, $event, &[], format_args!($($arg)+))
+    };
+}
+
+/// What `info!` and `warn!` call.
+pub fn emit(level: Level, event: &str, fields: &[(&str, &dyn fmt::Display)], message: fmt::Arguments) {
+    let line = if JSON.load(Ordering::Relaxed) {
+        let (id, nick) = CLIENT.try_with(|c| (c.borrow().id, c.borrow().nick.clone())).unwrap_or_default();
+        json_line(SystemTime::now(), level, event, id, nick.as_deref(), fields, &message.to_string())
+    } else {
+        format!("[{event}] {message}")
+    };
+    match level {
+        Level::Info => println!("{line}"),
+        Level::Warn => eprintln!("{line}"),
+    }
+}
+
+fn json_line(at: SystemTime, level: Level, event: &str, id: Option<u64>, nick: Option<&str>, fields: &[(&str, &dyn fmt::Display)], message: &str) -> String {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let timestamp = clock::rfc3339(since.as_secs());
+    let level = match level {
+        Level::Info => "info",
+        Level::Warn => "warn",
+    };
+    let mut out = format!(
+        "{{\"timestamp\":\"{}.{:03}Z\",\"level\":\"{level}\",\"event\":{}",
+        timestamp.trim_end_matches('Z'),
+        since.subsec_millis(),
+        quote(&event.to_ascii_lowercase())
+    );
+    if let Some(id) = id {
+        let _ = write!(out, ",\"client_id\":{id}");
+    }
+    if let Some(nick) = nick {
+        let _ = write!(out, ",\"nick\":{}", quote(nick));
+    }
+    let _ = write!(out, ",\"message\":{}", quote(message));
+    if !fields.is_empty() {
+        let fields: Vec<_> = fields.iter().map(|(key, value)| format!("{}:{}", quote(key), quote(&value.to_string()))).collect();
+        let _ = write!(out, ",\"fields\":{{{}}}", fields.join(","));
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn writes_json_lines() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_792_053_000_123);
+        let line = json_line(at, Level::Warn, "SLOW", Some(7), Some("alice"), &[("queued", &48), ("addr", &"192.0.2.4:51234")], "alice \"fell\" behind");
+        assert_eq!(
+            line,
+            "{\"timestamp\":\"2026-10-15T08:30:00.123Z\",\"level\":\"warn\",\"event\":\"slow\",\"client_id\":7,\"nick\":\"alice\",\
+             \"message\":\"alice \\\"fell\\\" behind\",\"fields\":{\"queued\":\"48\",\"addr\":\"192.0.2.4:51234\"}}"
+        );
+        assert!(protocol::json::parse_object(&line).is_some());
+        assert_eq!(json_line(at, Level::Info, "MDNS", None, None, &[], "up"), "{\"timestamp\":\"2026-10-15T08:30:00.123Z\",\"level\":\"info\",\"event\":\"mdns\",\"message\":\"up\"}");
+        assert_eq!("json".parse(), Ok(Format::Json));
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[tokio::test]
+    async fn knows_whose_connection_it_is() {
+        let who = || CLIENT.try_with(|c| (c.borrow().id, c.borrow().nick.clone())).ok();
+        assert_eq!(who(), None);
+        scoped(async {
+            identify(3, None);
+            assert_eq!(who(), Some((Some(3), None)));
+            identify(3, Some("bob"));
+            assert_eq!(who(), Some((Some(3), Some("bob".into()))));
+        })
+        .await;
+    }
+}
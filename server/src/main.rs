@@ -1,284 +1,1921 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use std::{
-    collections::HashMap,
+    net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
     time::Duration,
 };
+use bytes::{Bytes, BytesMut};
+use socket2::{SockRef, Socket, TcpKeepalive};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
-    sync::{mpsc, oneshot, RwLock},
-    time::timeout,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpSocket,
+    sync::oneshot,
+    task::AbortHandle,
+    time::{sleep, timeout, timeout_at, Instant},
 };
 
-type ClientTx = mpsc::Sender<String>;
-type ShutdownTx = oneshot::Sender<()>;
+use protocol::{
+    caps::{self, Cap, CapCommand, Caps},
+    ed25519,
+    format,
+    mdns::Service,
+    AccessCommand, AvatarCommand, Command, Event, FriendCommand, FriendStatus, Presence, ProfileCommand, Receipt, TwoFactorCommand, VoiceCommand, Wire, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use server::{
+    access::{Access, Cidr, Rule},
+    accounts::{self, Accounts},
+    alerts,
+    at_rest,
+    channels::Memberships,
+    clock,
+    codec::{self, Codec, Encoder, FramedRead},
+    config::{Config, TcpConfig},
+    connections::{Conn, Connections},
+    deflate,
+    drain::{self, Drain, Started},
+    emoji::Shortcodes,
+    export,
+    handoff::{self, Inherited},
+    history::{self, Retention},
+    info,
+    invites::{self, Invites},
+    log,
+    mdns,
+    metrics::Metrics,
+    nicks::Nicks,
+    permissions::Permissions,
+    profile::{Avatar, Field},
+    poll,
+    http::HttpUrl,
+    preview::{self, Previewer},
+    push::{self, Pusher},
+    schedule::{self, Kind, Schedule},
+    snapshot::Snapshots,
+    queue,
+    registry::{BlockFull, Blocked, Delivery, FriendChange, Registry, Said, SayDenied, WatchFull, MAX_BLOCKED, MAX_FRIENDS, MAX_WATCHED},
+    sanitize,
+    spam::{self, Action, Spam, To},
+    throttle::Throttle,
+    trace::Tracer,
+    totp,
+    transport::{Listener, Stream},
+    uploads::{self, Uploads},
+    voice::{Seat, Voice},
+    warn,
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+const MAX_INVITE_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Messages `HISTORY` replays when not asked for a number.
+const HISTORY_REPLAY: usize = 50;
+/// Matches `SEARCH` returns when not asked for a number, and the most it will.
+const SEARCH_RESULTS: usize = 20;
+const MAX_SEARCH_RESULTS: usize = 100;
+/// Submissions the io_uring ring takes at once; each is handed to the kernel
+/// as it's made, so this only needs to cover a burst of them.
+#[cfg(all(target_os = "linux", feature = "uring"))]
+const URING_ENTRIES: u32 = 256;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [command, file, rest @ ..] = args.as_slice() {
+        if command == "unseal" {
+            return unseal(file, rest);
+        }
+    }
+    let config = Arc::new(Config::from_args(&args)?);
+    log::set_format(config.log.format);
+    let inherited = Inherited::from_env()?;
 
-#[derive(Default)]
-struct Registry {
-    by_id: HashMap<u64, ClientTx>,
-    id_by_name: HashMap<String, u64>,
-    name_by_id: HashMap<u64, String>,
-    shutdown: HashMap<u64, ShutdownTx>,
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(n) = config.runtime.worker_threads {
+        runtime.worker_threads(n);
+    }
+    if let Some(n) = config.runtime.max_blocking_threads {
+        runtime.max_blocking_threads(n);
+    }
+    runtime.build()?.block_on(serve(config, inherited))
 }
 
-type Shared = Arc<RwLock<Registry>>;
-static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// `server unseal <file> [flags]`: prints a file sealed with the storage
+/// key that the flags or the config give.
+fn unseal(file: &str, args: &[String]) -> Result<()> {
+    let config = Config::from_args(args)?;
+    let data = std::fs::read(file).with_context(|| format!("can't read {file}"))?;
+    let text = at_rest::open(config.storage.key.as_ref(), &data).with_context(|| format!("can't open {file}"))?;
+    print!("{text}");
+    Ok(())
+}
 
-const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
-const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// The address of the interface the default route goes out of. Connecting a
+/// UDP socket only picks a route and sends nothing, and unlike asking
+/// `ip route` it works on every platform.
+fn primary_ip() -> Result<IpAddr> {
+    let probe = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    probe.connect("1.1.1.1:80")?;
+    Ok(probe.local_addr()?.ip())
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Get primary network IP using Linux `ip route`
-    let output = std::process::Command::new("sh")
-        .arg("-c")
-        .arg("ip route get 1.1.1.1 | awk '{print $7}'")
-        .output()
-        .expect("failed to run ip route");
+async fn serve(config: Arc<Config>, mut inherited: Inherited) -> Result<()> {
+    let ip = primary_ip()?;
+    let bind_addr = SocketAddr::new(ip, protocol::DEFAULT_PORT);
 
-    let ip = String::from_utf8_lossy(&output.stdout)
-        .trim()
-        .to_string();
+    let listener = match inherited.tcp("chat")? {
+        Some(listener) => {
+            info!("SERVER", "taking over {} from the old server", listener.local_addr()?);
+            listener
+        }
+        None => {
+            let socket = if bind_addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            // On Windows SO_REUSEADDR lets another process take over a port that is
+            // in use, rather than just skipping TIME_WAIT.
+            if cfg!(unix) {
+                socket.set_reuseaddr(true)?;
+            }
+            socket.bind(bind_addr)?;
+            let listener = socket.listen(config.tcp.backlog)?;
+            info!("SERVER", "running on {bind_addr}");
+            listener
+        }
+    };
+    // Copies of the listening sockets to hand over on an upgrade, and the
+    // tasks serving the others, to stop once they're handed over.
+    let mut sockets = vec![("chat", SockRef::from(&listener).try_clone()?)];
+    let mut helpers = Vec::new();
+    if let (true, IpAddr::V4(addr)) = (config.mdns.enabled, ip) {
+        let host = mdns::host_label();
+        let name = config.mdns.name.clone().unwrap_or_else(|| format!("rustchat on {host}"));
+        let service = Service { name, host, addr, port: protocol::DEFAULT_PORT };
+        match mdns::spawn(service.clone()) {
+            Ok(()) => info!("MDNS", "announcing \"{}\" as {}.local", service.name, service.host),
+            Err(e) => warn!("MDNS", "can't announce on the LAN: {e}"),
+        }
+    }
 
-    let bind_addr = format!("{ip}:5555");
+    let reg = Registry::with_channels(config.channels.clone());
+    reg.set_dm_retention(config.history.dms).await;
+    #[cfg(unix)]
+    reload_on_hangup(reg.clone())?;
+    let invites = Arc::new(Invites::default());
+    // Someone has to get in to invite everyone else.
+    if config.invites.required {
+        let token = invites.create(None, false, config.invites.ttl);
+        info!("INVITE", "invite-only; first invite: {}", invites::link(bind_addr, None, &token));
+    }
+    let pusher = Arc::new(Pusher::new(config.push.clone()));
+    let schedule = Arc::new(match &config.schedule.file {
+        Some(file) => Schedule::load(file, config.storage.key)?,
+        None => Schedule::default(),
+    });
+    tokio::spawn(schedule.clone().run(reg.clone()));
+    let snapshots = match &config.snapshot.file {
+        Some(file) => {
+            let snapshots = Arc::new(Snapshots::new(file, config.storage.key, reg.clone(), pusher.clone()));
+            snapshots.restore().await?;
+            tokio::spawn(snapshots.clone().run(config.snapshot.interval));
+            Some(snapshots)
+        }
+        None => None,
+    };
+    let shortcodes = Arc::new(Shortcodes::new(&config.emoji));
+    let previewer = Arc::new(Previewer::new(config.previews.clone()));
+    let uploads = Arc::new(Uploads::load(config.uploads.clone(), ip)?);
+    if uploads.enabled() {
+        let addr = SocketAddr::new(ip, config.uploads.port);
+        let listener = match inherited.tcp("uploads")? {
+            Some(listener) => listener,
+            None => tokio::net::TcpListener::bind(addr).await.with_context(|| format!("can't listen for uploads on {addr}"))?,
+        };
+        info!("UPLOAD", "accepting uploads on {}", uploads.upload_url());
+        sockets.push(("uploads", SockRef::from(&listener).try_clone()?));
+        helpers.push(tokio::spawn(uploads.clone().serve(listener)).abort_handle());
+    }
+    let voice = Arc::new(Voice::default());
+    if config.voice.enabled {
+        let addr = SocketAddr::new(ip, config.voice.port);
+        let socket = match inherited.udp("voice")? {
+            Some(socket) => socket,
+            None => tokio::net::UdpSocket::bind(addr).await.with_context(|| format!("can't listen for voice on {addr}"))?,
+        };
+        info!("VOICE", "relaying voice on udp {addr}");
+        sockets.push(("voice", SockRef::from(&socket).try_clone()?));
+        helpers.push(tokio::spawn(voice.clone().run(socket)).abort_handle());
+    }
+    let metrics = Arc::new(Metrics::default());
+    if config.metrics.enabled {
+        let addr = SocketAddr::new(ip, config.metrics.port);
+        let listener = match inherited.tcp("metrics")? {
+            Some(listener) => listener,
+            None => tokio::net::TcpListener::bind(addr).await.with_context(|| format!("can't listen for metrics on {addr}"))?,
+        };
+        info!("METRICS", "serving http://{addr}/metrics");
+        sockets.push(("metrics", SockRef::from(&listener).try_clone()?));
+        helpers.push(tokio::spawn(metrics.clone().serve(listener)).abort_handle());
+    }
+    let accounts = Arc::new(Accounts::load(&config.accounts, config.storage.key)?);
+    let throttle = Arc::new(Throttle::new(config.logins.clone()));
+    let nicks = Arc::new(Nicks::new(config.nicks.clone()));
+    let access = Arc::new(Access::new(&config.access));
+    let spam = Arc::new(Spam::new(config.spam.clone()));
+    let permissions = Arc::new(Permissions::new(&config.permissions));
+    let tracer = Arc::new(Tracer::new(config.tracing.clone()));
+    let connections = Arc::new(Connections::default());
+    let drain = Arc::new(Drain::default());
+    tokio::spawn(alerts::run(config.alerts.clone(), connections.clone()));
+    if let Some(url) = &config.tracing.otlp_url {
+        info!("TRACE", "exporting spans to {url}");
+        tokio::spawn(tracer.clone().run());
+    }
+    #[cfg(unix)]
+    upgrade_on_usr2(sockets, helpers, snapshots.clone(), drain.clone(), connections.clone(), config.drain.timeout)?;
+    let shared = Shared { invites, pusher, schedule, shortcodes, previewer, uploads, voice, accounts, throttle, nicks, access, spam, permissions, tracer, connections, metrics, drain, snapshots: snapshots.clone() };
+    // Anything inherited that the config no longer uses is closed, and the
+    // old server can start draining.
+    inherited.ready();
+    drop(inherited);
 
-    let listener = TcpListener::bind(&bind_addr).await?;
-    println!("Server running on {bind_addr}");
+    let started = accept(listener, &reg, &config, &shared).await?;
+    info!("DRAIN", "no longer accepting connections; waiting for clients to leave");
+    match drain::finish(&shared.connections, started.deadline).await {
+        0 => info!("DRAIN", "the last client has gone; exiting"),
+        left => info!("DRAIN", "deadline passed with {left} clients still connected; exiting"),
+    }
+    if let Some(snapshots) = snapshots {
+        snapshots.take().await?;
+    }
+    Ok(())
+}
 
-    let reg: Shared = Arc::new(RwLock::new(Registry::default()));
+/// Serves connections until a drain starts, through io_uring if the config
+/// asks for it and this kernel has it.
+async fn accept(listener: tokio::net::TcpListener, reg: &Registry, config: &Arc<Config>, shared: &Shared) -> Result<Started> {
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    if config.tcp.io_uring {
+        match server::uring::Ring::new(URING_ENTRIES) {
+            Ok(ring) => {
+                info!("SERVER", "serving connections through io_uring");
+                return accept_from(server::uring::Listener::new(ring, listener.into_std()?), reg, config, shared).await;
+            }
+            Err(e) => warn!("SERVER", "can't set up io_uring ({e}); serving connections through epoll"),
+        }
+    }
+    accept_from(listener, reg, config, shared).await
+}
 
+async fn accept_from<L: Listener>(listener: L, reg: &Registry, config: &Arc<Config>, shared: &Shared) -> Result<Started> {
     loop {
-        let (sock, addr) = listener.accept().await?;
-        println!("Client connected: {addr}");
+        let (sock, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            started = shared.drain.wait() => return Ok(started),
+        };
+        // Dropping the socket closes it before anything is read.
+        if !shared.access.permits(addr.ip()) {
+            info!("ACCESS", "refused {addr}");
+            continue;
+        }
+        info!("CONNECT", "{addr}");
         let reg = reg.clone();
+        let config = config.clone();
+        let shared = shared.clone();
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(sock, reg).await {
-                eprintln!("Client {addr} error: {e}");
+        tokio::spawn(log::scoped(async move {
+            if let Err(e) = handle_client(sock, reg, config, shared).await {
+                warn!("CONNECT", "{addr}: {e}");
             }
-            println!("Client {addr} disconnected");
-        });
+            info!("CONNECT", "{addr} disconnected");
+        }));
     }
 }
 
-async fn handle_client(stream: TcpStream, reg: Shared) -> Result<()> {
-    let _ = stream.set_nodelay(true);
-    let (reader, writer) = stream.into_split();
-    let mut lines = BufReader::new(reader).lines();
+/// What every connection shares besides the registry and config.
+#[derive(Clone)]
+struct Shared {
+    invites: Arc<Invites>,
+    pusher: Arc<Pusher>,
+    schedule: Arc<Schedule>,
+    shortcodes: Arc<Shortcodes>,
+    previewer: Arc<Previewer>,
+    uploads: Arc<Uploads>,
+    voice: Arc<Voice>,
+    accounts: Arc<Accounts>,
+    throttle: Arc<Throttle>,
+    nicks: Arc<Nicks>,
+    access: Arc<Access>,
+    spam: Arc<Spam>,
+    permissions: Arc<Permissions>,
+    tracer: Arc<Tracer>,
+    connections: Arc<Connections>,
+    metrics: Arc<Metrics>,
+    drain: Arc<Drain>,
+    /// Retaken after a `PURGE` so the file on disk forgets too.
+    snapshots: Option<Arc<Snapshots>>,
+}
+
+/// Re-reads the config on SIGHUP and applies its channel and history
+/// settings; anything else in it still needs a restart.
+#[cfg(unix)]
+fn reload_on_hangup(reg: Registry) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match Config::from_args(&std::env::args().skip(1).collect::<Vec<_>>()) {
+                Ok(config) => {
+                    let count = config.channels.persistent.len();
+                    reg.reload_channels(config.channels).await;
+                    reg.set_dm_retention(config.history.dms).await;
+                    info!("RELOAD", "channel and history settings reloaded ({count} persistent channels)");
+                }
+                Err(e) => warn!("RELOAD", "config not reloaded: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Hands the listening sockets to a new server process on SIGUSR2, then
+/// drains; see `handoff`. A snapshot is taken first for the new process to
+/// start from, and none after it has.
+#[cfg(unix)]
+fn upgrade_on_usr2(sockets: Vec<(&'static str, Socket)>, helpers: Vec<AbortHandle>, snapshots: Option<Arc<Snapshots>>, drain: Arc<Drain>, connections: Arc<Connections>, timeout: Duration) -> Result<()> {
+    use std::os::fd::AsFd;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        loop {
+            // Once draining, the copies are dropped so the sockets close
+            // when the server exits.
+            tokio::select! {
+                Some(()) = usr2.recv() => {}
+                _ = drain.wait() => return,
+            }
+            info!("UPGRADE", "starting a new server to take over");
+            if let Some(snapshots) = &snapshots {
+                if let Err(e) = snapshots.take().await {
+                    warn!("UPGRADE", "{e:#}; not upgrading");
+                    continue;
+                }
+            }
+            let fds: Vec<_> = sockets.iter().map(|(name, socket)| (*name, socket.as_fd())).collect();
+            match handoff::spawn(&fds).await {
+                Ok(child) => {
+                    info!("UPGRADE", "server {} has taken over; draining", child.id());
+                    if let Some(snapshots) = &snapshots {
+                        snapshots.stop().await;
+                    }
+                    helpers.iter().for_each(AbortHandle::abort);
+                    if drain.start(Instant::now() + timeout, None) {
+                        connections.send_all(Event::Reconnect(None));
+                    }
+                }
+                Err(e) => warn!("UPGRADE", "{e:#}; carrying on"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_client<S: Stream>(stream: S, reg: Registry, config: Arc<Config>, shared: Shared) -> Result<()> {
+    let Shared { invites, pusher, schedule, shortcodes, previewer, uploads, voice, accounts, throttle, nicks, access, spam, permissions, tracer, connections, metrics, drain, snapshots } = shared;
+    set_socket_options(stream.socket(), &config.tcp);
+    // Invite links point at whichever address this client reached us on.
+    let local_addr = stream.local_addr()?;
+    let peer_addr = stream.peer_addr()?;
+    let peer_ip = peer_addr.ip();
+    let connected_at = Instant::now();
+    let deadline = connected_at + config.timeouts.handshake;
+    let mut connection = tracer.start("connection", None);
+    connection.set("net.peer.ip", peer_ip);
+    let handshake = tracer.start("handshake", Some(&connection));
+
+    // Sniff the framing from the first byte; on timeout fall back to text so
+    // the error below is still readable.
+    let mut first = [0u8; 1];
+    let codec = match timeout_at(deadline, stream.peek(&mut first)).await {
+        Ok(Ok(0)) => return Err(anyhow!("client disconnected before sending a nickname")),
+        Ok(Ok(_)) => Codec::detect(first[0]),
+        Ok(Err(e)) => return Err(anyhow!("failed to read nickname: {e}")),
+        Err(_) => Codec::detect(b'N'),
+    };
+
+    let (reader, mut writer) = stream.into_split();
+    let mut frames = FramedRead::new(reader, codec);
 
     let my_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    log::identify(my_id, None);
+    let tracked = connections.open(my_id, peer_addr);
+    let conn = &tracked.conn;
 
     // Get nickname with a timeout and fast failure feedback.
-    let nick_line = match timeout(HANDSHAKE_TIMEOUT, lines.next_line()).await {
-        Ok(Ok(Some(line))) => line,
-        Ok(Ok(None)) => return Err(anyhow!("client disconnected before sending a nickname")),
-        Ok(Err(e)) => return Err(anyhow!("failed to read nickname: {e}")),
-        Err(_) => {
-            let mut writer = writer;
-            let _ = writer
-                .write_all(b"ERR timeout waiting for NICK\n")
-                .await;
-            return Err(anyhow!("client handshake timed out"));
+    let mut frame = handshake_frame(&mut frames, &mut writer, codec, Wire::Text, conn, deadline).await?;
+
+    // The first frame fixes the message encoding for the whole connection.
+    let wire = Wire::detect(&frame, codec.is_length());
+
+    // Optional HELLO before NICK; clients that skip it speak version 1.
+    let mut version = 1;
+    if let Command::Hello(requested) = Command::parse(&frame, wire) {
+        if requested < MIN_PROTOCOL_VERSION {
+            let err = format!("unsupported protocol version {requested}, need >= {MIN_PROTOCOL_VERSION}");
+            let _ = write_frame(&mut writer, codec, &Event::Error(err).encode(wire)).await;
+            return Err(anyhow!("client requested protocol version {requested}"));
         }
-    };
+        version = requested.min(PROTOCOL_VERSION);
+        write_frame(&mut writer, codec, &Event::Hello { version }.encode(wire)).await?;
 
-    let name = match parse_nick(&nick_line) {
-        Some(n) => n,
-        None => {
-            let mut writer = writer;
-            let _ = writer
-                .write_all(b"ERR expected: NICK <name>\n")
-                .await;
+        frame = handshake_frame(&mut frames, &mut writer, codec, wire, conn, deadline).await?;
+    }
+
+    // Optional capability negotiation, also before NICK.
+    let mut caps = Caps::default();
+    let mut offered = Caps::available(codec.is_length());
+    if shortcodes.is_empty() {
+        offered.remove(Cap::Emoji);
+    }
+    while let Command::Cap(cmd) = Command::parse(&frame, wire) {
+        let reply = match cmd {
+            CapCommand::Ls => Some(Event::Cap { sub: "LS".into(), caps: caps::advertised(offered) }),
+            CapCommand::Req(list) => {
+                let sub = if caps::request(&mut caps, &list, offered) { "ACK" } else { "NAK" };
+                Some(Event::Cap { sub: sub.into(), caps: list })
+            }
+            CapCommand::End => None,
+        };
+        if let Some(reply) = reply {
+            write_frame(&mut writer, codec, &reply.encode(wire)).await?;
+        }
+
+        frame = handshake_frame(&mut frames, &mut writer, codec, wire, conn, deadline).await?;
+    }
+
+    // Optional invite token, checked once we know the nickname.
+    let mut token = None;
+    if let Command::Token(t) = Command::parse(&frame, wire) {
+        token = Some(t);
+        frame = handshake_frame(&mut frames, &mut writer, codec, wire, conn, deadline).await?;
+    }
+
+    // Optional password, checked once we know the nickname.
+    let mut password = None;
+    if let Command::Pass(p) = Command::parse(&frame, wire) {
+        password = Some(p);
+        frame = handshake_frame(&mut frames, &mut writer, codec, wire, conn, deadline).await?;
+    }
+    let mut two_factor = None;
+    if let Command::TwoFactor(TwoFactorCommand::Code(code)) = Command::parse(&frame, wire) {
+        two_factor = Some(code);
+        frame = handshake_frame(&mut frames, &mut writer, codec, wire, conn, deadline).await?;
+    }
+
+    let name = match Command::parse(&frame, wire) {
+        Command::Nick(n) if sanitize::is_clean_name(&n) => n,
+        Command::Nick(_) => {
+            let _ = write_frame(&mut writer, codec, &Event::Error("nickname contains control characters".into()).encode(wire)).await;
+            return Err(anyhow!("nickname with control characters"));
+        }
+        _ => {
+            let _ = write_frame(&mut writer, codec, &Event::Error("expected: NICK <name>".into()).encode(wire)).await;
             return Err(anyhow!("bad nickname command"));
         }
     };
-    println!("[LOGIN] {name} assigned ID {my_id}");
-
-    {
-        let r = reg.read().await;
-        if r.id_by_name.contains_key(&name) {
-            let mut writer = writer;
-            let _ = writer
-                .write_all(b"ERR name already in use\n")
-                .await;
-            return Err(anyhow!("name '{}' already in use", name));
+
+    // Before the invite, so a wrong password doesn't use it up.
+    let registered = accounts.is_registered(&name);
+    if let Some(reason) = Permissions::reserved(&name, registered) {
+        let _ = write_frame(&mut writer, codec, &Event::Error(reason.into()).encode(wire)).await;
+        return Err(anyhow!("{name} refused: {reason}"));
+    }
+    if registered {
+        if let Err(locked) = throttle.check(&name, peer_ip, Instant::now()) {
+            let _ = write_frame(&mut writer, codec, &Event::Error(locked.to_string()).encode(wire)).await;
+            return Err(anyhow!("{name} is locked out: {locked}"));
+        }
+        let verify = tracer.start("accounts.verify", Some(&handshake));
+        let refused = match &password {
+            Some(p) if !accounts.verify(&name, p).await => Some("wrong password"),
+            Some(_) => None,
+            None => Some("nickname is registered; send PASS <password> before NICK"),
+        };
+        let refused = refused.or_else(|| {
+            let code_ok = !accounts.has_two_factor(&name) || two_factor.as_ref().is_some_and(|code| accounts.check_code(&name, code, clock::now_unix()));
+            match &two_factor {
+                _ if code_ok => None,
+                Some(_) => Some("wrong or already used two-factor code"),
+                None => Some("two-factor code needed; send 2FA <code> after PASS"),
+            }
+        });
+        drop(verify);
+        if let Some(reason) = refused {
+            // Leaving a step out is a mistake rather than a guess.
+            if password.is_some() {
+                sleep(throttle.failed(&name, peer_ip, Instant::now())).await;
+            }
+            let _ = write_frame(&mut writer, codec, &Event::Error(reason.into()).encode(wire)).await;
+            return Err(anyhow!("{name} didn't log in: {reason}"));
         }
+        throttle.succeeded(&name);
     }
+    if let Err(refused) = nicks.check(&name, peer_ip, registered, Instant::now()) {
+        let _ = write_frame(&mut writer, codec, &Event::Error(refused.to_string()).encode(wire)).await;
+        return Err(anyhow!("{name} refused: {refused}"));
+    }
+
+    let invited_to = match token.map(|t| invites.redeem(&t, &name)) {
+        Some(Ok(channel)) => channel,
+        Some(Err(reason)) => {
+            let _ = write_frame(&mut writer, codec, &Event::Error(reason.into()).encode(wire)).await;
+            return Err(anyhow!("{name} presented a bad invite: {reason}"));
+        }
+        None if config.invites.required => {
+            let _ = write_frame(&mut writer, codec, &Event::Error("this server is invite-only".into()).encode(wire)).await;
+            return Err(anyhow!("{name} has no invite"));
+        }
+        None => None,
+    };
+
+    // Negotiated JSON and compression take effect from WELCOME onwards.
+    let wire = if caps.has(Cap::Json) && wire == Wire::Text { Wire::Json } else { wire };
+    let compress = caps.has(Cap::Deflate);
+    let ack = caps.has(Cap::Ack);
+    let shortcodes = caps.has(Cap::Emoji).then_some(shortcodes);
+    let formatting = caps.has(Cap::Format);
+    log::identify(my_id, Some(&name));
+    info!(
+        "LOGIN", "{name} assigned ID {my_id} (v{version}, {}{}{})",
+        wire.label(),
+        if codec.is_length() { ", framed" } else { "" },
+        if compress { ", deflate" } else { "" }
+    );
 
-    let (tx, mut rx) = mpsc::channel::<String>(64);
+    let (tx, mut rx) = queue::channel(my_id, &config.queue);
     let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
 
-    let mut writer = writer;
-    let writer_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if writer.write_all(msg.as_bytes()).await.is_err() { break; }
-            if writer.write_all(b"\n").await.is_err() { break; }
+    if reg.register(my_id, &name, tx.clone(), shutdown_tx).await.is_err() {
+        let _ = write_frame(&mut writer, codec, &Event::Error("name already in use".into()).encode(wire)).await;
+        return Err(anyhow!("name '{}' already in use", name));
+    }
+    if registered {
+        reg.set_registered(my_id).await;
+    }
+    conn.joined(&name, tx);
+    metrics.handshake.observe(connected_at.elapsed());
+    connection.set("client.id", my_id);
+    connection.set("nick", &name);
+    drop(handshake);
+
+    let receipts = (reg.clone(), name.clone(), conn.clone(), metrics.clone());
+    let write_timeout = config.timeouts.write;
+    let mut writer_task = tokio::spawn(async move {
+        let (reg, me, conn, metrics) = receipts;
+        let mut writer = BufWriter::new(writer);
+        let mut written = Vec::new();
+        while let Some(event) = rx.recv().await {
+            metrics.queue_wait.observe(rx.waited());
+            // Everything already queued goes out in the same flush.
+            let mut next = Some(event);
+            while let Some(event) = next {
+                next = rx.try_recv();
+                if next.is_some() {
+                    metrics.queue_wait.observe(rx.waited());
+                }
+                match &*event {
+                    // Receipts come whether or not this end asked for them.
+                    Event::Receipt { .. } if !ack => continue,
+                    Event::Dm { id, from_id, .. } => written.push((*id, *from_id)),
+                    _ => {}
+                }
+                let stripped = if formatting { None } else { event.map_body(format::strip) };
+                let expanded = shortcodes.as_ref().and_then(|codes| stripped.as_ref().unwrap_or(&event).map_body(|b| codes.expand(b)));
+                let msg = expanded.as_ref().or(stripped.as_ref()).unwrap_or(&event).encode(wire);
+                let msg = if compress { deflate::compress(&msg) } else { msg };
+                match timeout(write_timeout, write_frame(&mut writer, codec, &msg)).await {
+                    Ok(Ok(())) => conn.wrote(msg.len()),
+                    Ok(Err(_)) => return,
+                    Err(_) => {
+                        warn!("WRITE", "{me} ({my_id}) took over {}s to take a write; disconnecting", write_timeout.as_secs());
+                        return;
+                    }
+                }
+            }
+            match timeout(write_timeout, writer.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    warn!("WRITE", "{me} ({my_id}) took over {}s to take a write; disconnecting", write_timeout.as_secs());
+                    return;
+                }
+            }
+            for (id, from_id) in written.drain(..) {
+                if let Some(tx) = reg.sender(from_id) {
+                    let _ = tx.push(Event::Receipt { id, name: me.clone(), state: Receipt::Written });
+                }
+            }
         }
     });
 
-    {
-        let mut r = reg.write().await;
-        r.by_id.insert(my_id, tx.clone());
-        r.id_by_name.insert(name.clone(), my_id);
-        r.name_by_id.insert(my_id, name.clone());
-        r.shutdown.insert(my_id, shutdown_tx);
+    send_to_id(&reg, my_id, &Event::Welcome { id: my_id, name: name.clone() })?;
+    // Got in just before a DRAIN, and missed being told.
+    if let Some(started) = drain.started() {
+        send_to_id(&reg, my_id, &Event::Reconnect(started.to))?;
+    }
+    send_to_id(&reg, my_id, &Event::notice(format!("commands: {}", protocol::USAGE)))?;
+    // Catch up on requests that came in while away, and on who's around.
+    send_friends(&reg, my_id, reg.friends(&name).await)?;
+    for with in reg.ephemeral(&name).await {
+        send_to_id(&reg, my_id, &Event::Ephemeral { name: with, on: true })?;
+    }
+    schedule.arrived(&reg, &name).await;
+    let held = reg.take_held(&name).await;
+    if !held.is_empty() {
+        send_to_id(&reg, my_id, &Event::notice(match held.len() { 1 => "1 DM was held for you while in do-not-disturb:".to_string(), n => format!("{n} DMs were held for you while in do-not-disturb:") }))?;
+        for dm in &held {
+            send_to_id(&reg, my_id, dm)?;
+        }
     }
 
-    send_to_id(&reg, my_id, &format!("WELCOME {my_id} {name}")).await?;
-    send_to_id(&reg, my_id, "[server] commands: TO <name> <msg> | TOID <id> <msg> | KICK <name> | KICKID <id>").await?;
+    let mut memberships = Memberships::new(&name, reg.blocked(&name).await);
+    // A REGISTER waiting for its proof of work.
+    let mut challenge = None;
+    let _seat = Seat { voice: voice.clone(), reg: reg.clone(), id: my_id, name: name.clone() };
+    if let Some(channel) = invited_to {
+        match (reg.sender(my_id), reg.join(&channel, &name, permissions.role(&name, registered), None).await) {
+            (Some(tx), Some(Ok(joined))) => {
+                memberships.join(&channel, joined.sub, tx);
+                info!("JOIN", "{name} ({my_id}) -> {channel} (invited)");
+                send_to_id(&reg, my_id, &Event::notice(format!("joined {channel}")))?;
+                if let Some(topic) = joined.topic {
+                    send_to_id(&reg, my_id, &Event::notice(format!("topic of {channel}: {topic}")))?;
+                }
+            }
+            (_, Some(Err(denied))) => send_to_id(&reg, my_id, &Event::notice(format!("can't join {channel}: {denied}")))?,
+            _ => {}
+        }
+    }
 
     // Handle commands/messages
     loop {
-        let line_opt = tokio::select! {
-            r = timeout(IDLE_TIMEOUT, lines.next_line()) => match r {
-                Ok(Ok(line)) => line,
+        let frame_opt = tokio::select! {
+            r = timeout(config.timeouts.idle, frames.next_frame()) => match r {
+                Ok(Ok(frame)) => frame,
                 Ok(Err(e)) => return Err(anyhow!(e)),
                 Err(_) => {
-                    send_to_id(&reg, my_id, "[server] timed out due to inactivity").await.ok();
+                    send_to_id(&reg, my_id, &Event::notice("timed out due to inactivity")).ok();
                     None
                 }
             },
             _ = &mut shutdown_rx => {
-                send_to_id(&reg, my_id, "[server] disconnected").await.ok();
+                send_to_id(&reg, my_id, &Event::notice("disconnected")).ok();
                 None
             }
+            // The peer stopped reading, or its queue was closed for being too slow.
+            _ = &mut writer_task => None,
         };
 
-        let Some(line) = line_opt else {
+        let Some(frame) = frame_opt else {
             break;
         };
-        let line = line.trim();
-
-        // ---- KICK BY NAME ----
-        if let Some(target_name) = line.strip_prefix("KICK ") {
-            println!("[ADMIN] {name} ({my_id}) requested kick on {target_name}");
-
-            if let Some(tid) = find_id_by_name(&reg, target_name).await {
-                send_to_id(&reg, tid, "[server] kicked").await.ok();
-                disconnect_client(&reg, tid).await;
-                send_to_id(&reg, my_id, "[server] user kicked").await?;
-            } else {
-                send_to_id(&reg, my_id, "[server] user not found").await?;
+        conn.read(frame.len(), Instant::now());
+        let _routing = metrics.routing.time();
+        let frame = if compress {
+            match deflate::decompress(&frame, codec::MAX_FRAME_LEN) {
+                Some(inflated) => Bytes::from(inflated),
+                None => {
+                    send_to_id(&reg, my_id, &Event::notice("bad compressed frame")).ok();
+                    break;
+                }
             }
+        } else {
+            frame
+        };
+
+        // A signed message goes on as the plain one it wraps, once its
+        // signature checks out; `signed` is the key that made it.
+        let (command, signed) = match Command::parse(&frame, wire) {
+            Command::Signed { target, signature, body } => match check_signature(&accounts, &name, &target, &signature, &body) {
+                Ok(key) if target.starts_with('#') => (Command::Say { channel: target, body }, Some(key)),
+                Ok(key) => (Command::To { name: target, body }, Some(key)),
+                Err(reason) => {
+                    info!("DENIED", "{name} ({my_id}) -> {target}: {reason}");
+                    send_to_id(&reg, my_id, &Event::notice(format!("message not sent: {reason}")))?;
+                    continue;
+                }
+            },
+            command => (command, None),
+        };
+        let mut span = tracer.start(command.keyword().to_ascii_uppercase(), None);
+        span.set("client.id", my_id);
+        span.set("nick", &name);
+        let needed = permissions.needed(&command);
+        let role = permissions.role(&name, accounts.is_registered(&name));
+        if role < needed {
+            send_to_id(&reg, my_id, &Event::notice("permission denied"))?;
+            info!("DENIED", "{name} ({my_id}) tried to use {}, which needs {needed}", command.keyword().to_ascii_uppercase());
             continue;
         }
+        match command {
+            // ---- KICK BY NAME ----
+            Command::Kick(target_name) => {
+                info!("ADMIN", "{name} ({my_id}) requested kick on {target_name}");
+
+                if let Some(tid) = reg.id_of(&target_name).await {
+                    send_to_id(&reg, tid, &Event::notice("kicked")).ok();
+                    reg.disconnect(tid).await;
+                    send_to_id(&reg, my_id, &Event::notice("user kicked"))?;
+                } else {
+                    send_to_id(&reg, my_id, &Event::notice("user not found"))?;
+                }
+            }
+
+            // ---- KICK BY ID ----
+            Command::KickId(tid) => {
+                info!("ADMIN", "{name} ({my_id}) requested kick on ID: {tid:?}");
+
+                if let Some(tid) = tid {
+                    send_to_id(&reg, tid, &Event::notice("kicked")).ok();
+                    reg.disconnect(tid).await;
+                    send_to_id(&reg, my_id, &Event::notice("user kicked"))?;
+                } else {
+                    send_to_id(&reg, my_id, &Event::notice("invalid ID"))?;
+                }
+            }
+
+            // ---- EXPORT HISTORY ----
+            Command::Export(args) => {
+                info!("ADMIN", "{name} ({my_id}) requested export");
 
-        // ---- KICK BY ID ----
-        if let Some(id_str) = line.strip_prefix("KICKID ") {
-            println!("[ADMIN] {name} ({my_id}) requested kick on ID: {id_str}");
+                let Some(req) = args.and_then(export::Request::from_args) else {
+                    send_to_id(&reg, my_id, &Event::notice("usage: EXPORT <name|#chan> <json|csv> [YYYY-MM-DD] [YYYY-MM-DD]"))?;
+                    continue;
+                };
 
-            if name != "admin" {
-                send_to_id(&reg, my_id, "[server] permission denied").await?;
-                println!("[DENIED] {name} ({my_id}) tried to use admin command.");
-                continue;
+                let target = &req.name;
+                let entries = if protocol::is_channel_name(target) {
+                    match reg.channel_entries(target, req.since, req.until).await {
+                        Some(entries) => entries,
+                        None => {
+                            send_to_id(&reg, my_id, &Event::notice(format!("no such channel {target}")))?;
+                            continue;
+                        }
+                    }
+                } else {
+                    reg.history_for(target, req.since, req.until).await
+                };
+                let written = {
+                    let _storage = tracer.start("export.write", Some(&span));
+                    export::write_export(target, &entries, req.format, config.storage.key.as_ref()).await
+                };
+                match written {
+                    Ok(path) => {
+                        info!("EXPORT", "{} messages for {target} -> {}", entries.len(), path.display());
+                        send_to_id(&reg, my_id, &Event::notice(format!("exported {} messages to {}", entries.len(), path.display())))?;
+                    }
+                    Err(e) => {
+                        warn!("EXPORT", "failed for {target}: {e}");
+                        send_to_id(&reg, my_id, &Event::notice("export failed"))?;
+                    }
+                }
             }
 
-            if let Ok(tid) = id_str.parse::<u64>() {
-                send_to_id(&reg, tid, "[server] kicked").await.ok();
-                disconnect_client(&reg, tid).await;
-                send_to_id(&reg, my_id, "[server] user kicked").await?;
-            } else {
-                send_to_id(&reg, my_id, "[server] invalid ID").await?;
+            // ---- EXPORT OWN DATA ----
+            Command::ExportMe => {
+                info!("EXPORT", "{name} ({my_id}) requested own data");
+                let messages = reg.history_for(&name, 0, u64::MAX).await;
+                let dump = export::user_dump(&export::UserData {
+                    id: my_id,
+                    name: &name,
+                    registered: accounts.is_registered(&name),
+                    two_factor: accounts.has_two_factor(&name),
+                    signing_key: accounts.signing_key(&name),
+                    state: reg.user_data(&name).await,
+                    push: pusher.url(&name),
+                    scheduled: schedule.list(&name),
+                    uploads: uploads.owned_by(&name),
+                    messages: &messages,
+                });
+                // In parts if it's too big for one frame, each waiting for
+                // the queue to empty so they don't overflow it.
+                for event in export::dump_events(dump) {
+                    while reg.sender(my_id).is_some_and(|tx| tx.depth() > 0 && !tx.is_closed()) {
+                        sleep(Duration::from_millis(10)).await;
+                    }
+                    send_to_id(&reg, my_id, &event)?;
+                }
             }
-            continue;
-        }
 
-        // ---- MESSAGING ----
-        if let Some((target_name, msg)) = parse_to(line) {
-            let target_id = find_id_by_name(&reg, target_name).await;
+            // ---- PURGE USER ----
+            Command::Purge(target) => {
+                info!("ADMIN", "{name} ({my_id}) requested purge of {target}");
 
-            println!("[MSG] {name} ({my_id}) -> {target_name}: {msg}");
+                if protocol::is_channel_name(&target) {
+                    match reg.purge_channel(&target).await {
+                        Some(removed) => {
+                            info!("PURGE", "{target}: removed {removed} messages");
+                            send_to_id(&reg, my_id, &Event::notice(format!("purged {target} ({removed} messages)")))?;
+                        }
+                        None => send_to_id(&reg, my_id, &Event::notice(format!("no such channel {target}")))?,
+                    }
+                    continue;
+                }
 
-            if let Some(tid) = target_id {
-                let payload = format!("from {name}({my_id}): {msg}");
-                if send_to_id(&reg, tid, &payload).await.is_err() {
-                    send_to_id(&reg, my_id, "[server] target disconnected").await?;
+                if let Some(tid) = reg.id_of(&target).await {
+                    send_to_id(&reg, tid, &Event::notice("your data was purged")).ok();
+                    reg.disconnect(tid).await;
+                }
+                let removed = reg.purge(&target).await;
+                let account = accounts.remove(&target);
+                let scheduled = schedule.purge(&target);
+                pusher.set(&target, None);
+                let files = uploads.purge(&target).unwrap_or_else(|e| {
+                    warn!("PURGE", "{target}: can't delete uploads: {e}");
+                    0
+                });
+                if let Some(snapshots) = &snapshots {
+                    if let Err(e) = snapshots.take().await {
+                        warn!("PURGE", "{target}: can't retake the snapshot: {e}");
+                    }
                 }
-            } else {
-                send_to_id(&reg, my_id, "[server] target not found").await?;
+                info!("PURGE", "{target}: removed {removed} messages, {scheduled} scheduled, {files} uploads, account: {account}");
+                let account = if account { ", and their account" } else { "" };
+                send_to_id(&reg, my_id, &Event::notice(format!("purged {target} ({removed} messages, {scheduled} scheduled, {files} uploads{account})")))?;
             }
-            continue;
-        }
 
-        if let Some((tid, msg)) = parse_toid(line) {
-            let tname = {
-                let r = reg.read().await;
-                r.name_by_id.get(&tid).cloned().unwrap_or_else(|| "?".into())
-            };
+            // ---- MESSAGING ----
+            Command::To { name: target_name, body: msg } => {
+                let msg = sanitize::message(&msg);
+                if !screen(&reg, &spam, &name, my_id, To::Dm(&target_name), &msg).await? {
+                    continue;
+                }
+                let target_id = reg.id_of(&target_name).await;
 
-            println!("[MSG] {name} ({my_id}) -> {tname} ({tid}): {msg}");
+                info!("MSG", "{name} ({my_id}) -> {target_name}: {msg}");
 
-            let payload = format!("from {name}({my_id}): {msg}");
-            if send_to_id(&reg, tid, &payload).await.is_err() {
-                send_to_id(&reg, my_id, "[server] target offline").await?;
+                if let Some(tid) = target_id {
+                    match reg.deliver_dm(&name, my_id, tid, &msg).await {
+                        Ok(Delivery::Queued { id }) => follow_up(&reg, &previewer, &uploads, id, &msg, signed, Audience::Dm(my_id, tid)).await,
+                        Ok(Delivery::Held) => {
+                            send_to_id(&reg, my_id, &Event::notice(format!("{target_name} is in do-not-disturb and will see your message later")))?;
+                            continue;
+                        }
+                        Ok(Delivery::Dropped) => {
+                            send_to_id(&reg, my_id, &Event::notice(format!("{target_name} is in do-not-disturb; ephemeral messages aren't held")))?;
+                            continue;
+                        }
+                        Err(e) => {
+                            let notice = if e.is::<Blocked>() { format!("your message to {target_name} wasn't delivered") } else { "target disconnected".into() };
+                            send_to_id(&reg, my_id, &Event::notice(notice))?;
+                            continue;
+                        }
+                    }
+                    if let Some(away) = reg.away_message(tid).await {
+                        send_to_id(&reg, my_id, &Event::notice(format!("{target_name} is away: {away}")))?;
+                    }
+                    continue;
+                }
+                // Ephemeral messages don't leave the server for later reading,
+                // and a URL kept from before a nickname's account was removed
+                // doesn't count.
+                let pushable = accounts.is_registered(&target_name) && !reg.ephemeral(&name).await.contains(&target_name);
+                let push = if pushable { pusher.due(&target_name) } else { None };
+                if let Some(url) = push {
+                    info!("PUSH", "{name} ({my_id}) -> {target_name} via {url}");
+                    let (title, body, allow_private) = (format!("DM from {name}"), format!("{name}: {msg}"), pusher.allow_private());
+                    tokio::spawn(async move {
+                        if let Err(e) = push::post(&url, &title, &body, allow_private).await {
+                            warn!("PUSH", "failed for {url}: {e}");
+                        }
+                    });
+                    send_to_id(&reg, my_id, &Event::notice(format!("{target_name} is offline; they've been sent a push notification")))?;
+                } else {
+                    send_to_id(&reg, my_id, &Event::notice("target not found"))?;
+                }
             }
-            continue;
-        }
 
-        send_to_id(&reg, my_id, "[server] commands: TO | TOID | KICK | KICKID").await?;
+            Command::Schedule { at, name: target_name, body: msg } => {
+                let Some(at) = clock::parse_rfc3339(&at) else {
+                    send_to_id(&reg, my_id, &Event::notice("times are RFC 3339, like 2026-10-15T18:00:00Z or 2026-10-15T20:00:00+02:00"))?;
+                    continue;
+                };
+                if target_name == name || !sanitize::is_clean_name(&target_name) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("can't schedule messages to {target_name}")))?;
+                    continue;
+                }
+                if reg.ephemeral(&name).await.contains(&target_name) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("DMs with {target_name} are ephemeral, so they can't be scheduled")))?;
+                    continue;
+                }
+                let msg = sanitize::message(&msg);
+                let added = {
+                    let _storage = tracer.start("schedule.add", Some(&span));
+                    schedule.add(clock::now_unix(), at, Kind::Dm, &name, &target_name, &msg)
+                };
+                match added {
+                    Ok(id) => {
+                        info!("SCHEDULE", "{name} ({my_id}) -> {target_name} at {}: #{id}", clock::rfc3339(at));
+                        send_to_id(&reg, my_id, &Event::notice(format!("scheduled message #{id} to {target_name} for {}", clock::rfc3339(at))))?;
+                    }
+                    Err(e) => send_to_id(&reg, my_id, &Event::notice(e.to_string()))?,
+                }
+            }
+            // ---- UPLOADS ----
+            Command::Upload => {
+                if !uploads.enabled() {
+                    send_to_id(&reg, my_id, &Event::notice("uploads are off on this server"))?;
+                    continue;
+                }
+                let token = uploads.grant(&name);
+                let (used, quota) = (uploads.used(&name), uploads.quota());
+                info!("UPLOAD", "{name} ({my_id}) got an upload token");
+                let notice = format!(
+                    "upload with: curl -T <file> -H \"Authorization: Bearer {token}\" {} (token good for {}s; {} of {} used), then paste the link it answers with",
+                    uploads.upload_url(),
+                    uploads.token_ttl().as_secs(),
+                    uploads::size(used),
+                    uploads::size(quota)
+                );
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            Command::Register(password) => match accounts.challenge(&name, &password, Instant::now()) {
+                Ok(Some(c)) => {
+                    info!("ACCOUNTS", "{name} ({my_id}) was sent a {}-bit challenge", c.bits);
+                    send_to_id(&reg, my_id, &Event::Challenge { nonce: c.nonce.clone(), bits: c.bits })?;
+                    challenge = Some(c);
+                }
+                Ok(None) => {
+                    let _storage = tracer.start("accounts.set", Some(&span));
+                    register(&reg, &accounts, &name, my_id, &password).await?
+                }
+                Err(denied) => send_to_id(&reg, my_id, &Event::notice(format!("can't register: {denied}")))?,
+            },
+            Command::Proof(n) => match challenge.take().map(|c| c.answer(&name, n, Instant::now())) {
+                Some(Ok(password)) => {
+                    let _storage = tracer.start("accounts.set", Some(&span));
+                    register(&reg, &accounts, &name, my_id, &password).await?
+                }
+                Some(Err(reason)) => send_to_id(&reg, my_id, &Event::notice(format!("can't register: {reason}; REGISTER again for a new challenge")))?,
+                None => send_to_id(&reg, my_id, &Event::notice("no challenge to answer; REGISTER <password> first"))?,
+            },
+
+            Command::TwoFactor(TwoFactorCommand::Enable) => {
+                let notice = if accounts.has_two_factor(&name) {
+                    "two-factor is already on; 2FA DISABLE <code> first to get a new secret".to_string()
+                } else if let Some(secret) = accounts.start_two_factor(&name) {
+                    format!(
+                        "two-factor secret: {} ({}); add it to an authenticator app, then send 2FA <code> to turn two-factor on",
+                        totp::base32(&secret),
+                        totp::uri("rustchat", &name, &secret)
+                    )
+                } else {
+                    "register first, with REGISTER <password>".to_string()
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            Command::TwoFactor(TwoFactorCommand::Code(code)) => {
+                let notice = if accounts.confirm_two_factor(&name, &code, clock::now_unix()) {
+                    info!("ACCOUNTS", "{name} ({my_id}) turned two-factor on");
+                    "two-factor is on; connect with 2FA <code> after PASS from now on"
+                } else {
+                    "that code doesn't match the secret from 2FA ENABLE"
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            Command::TwoFactor(TwoFactorCommand::Disable(code)) => {
+                let notice = if accounts.disable_two_factor(&name, &code, clock::now_unix()) {
+                    info!("ACCOUNTS", "{name} ({my_id}) turned two-factor off");
+                    "two-factor is off"
+                } else {
+                    "two-factor stays on: that isn't a current code"
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            Command::SignKey(key) => {
+                let key = match key.as_deref().map(accounts::parse_key) {
+                    Some(Some(key)) => Some(key),
+                    Some(None) => {
+                        send_to_id(&reg, my_id, &Event::notice("that isn't an Ed25519 public key in hex"))?;
+                        continue;
+                    }
+                    None => None,
+                };
+                let notice = if !accounts.set_signing_key(&name, key) {
+                    "register first, with REGISTER <password>"
+                } else if key.is_some() {
+                    info!("ACCOUNTS", "{name} ({my_id}) set a signing key");
+                    "signing key set; messages sent with SIGNED are verified against it"
+                } else {
+                    info!("ACCOUNTS", "{name} ({my_id}) removed their signing key");
+                    "signing key removed"
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+            Command::Signed { .. } => unreachable!("unwrapped above"),
+
+            Command::Unlock(target) => {
+                let Some(target) = target else {
+                    let locked = throttle.locked(Instant::now());
+                    if locked.is_empty() {
+                        send_to_id(&reg, my_id, &Event::notice("nobody is locked out"))?;
+                    }
+                    for l in locked {
+                        send_to_id(&reg, my_id, &Event::notice(format!("{} locked out for {}s more", l.what, l.left.as_secs().max(1))))?;
+                    }
+                    continue;
+                };
+                let notice = if throttle.unlock(&target) {
+                    info!("ADMIN", "{name} ({my_id}) unlocked {target}");
+                    format!("{target} unlocked")
+                } else {
+                    format!("{target} has no failed logins")
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            Command::Connections => {
+                for c in connections.list(Instant::now()) {
+                    send_to_id(&reg, my_id, &Event::notice(c.to_string()))?;
+                }
+            }
+
+            Command::Drain { secs, to } => {
+                let wait = secs.map_or(config.drain.timeout, Duration::from_secs);
+                if !drain.start(Instant::now() + wait, to.clone()) {
+                    send_to_id(&reg, my_id, &Event::notice("already draining"))?;
+                    continue;
+                }
+                info!("ADMIN", "{name} ({my_id}) started draining; exiting within {}s", wait.as_secs());
+                send_to_id(&reg, my_id, &Event::notice(format!("draining: no new connections, and exiting once everyone has reconnected elsewhere or in {}s", wait.as_secs())))?;
+                connections.send_all(Event::Reconnect(to));
+            }
+
+            Command::Access(cmd) => {
+                let (rule, cidr) = match cmd {
+                    AccessCommand::List => {
+                        let rules = access.rules();
+                        if rules.is_empty() {
+                            send_to_id(&reg, my_id, &Event::notice("no access lists; anyone may connect"))?;
+                        }
+                        for (rule, cidr) in rules {
+                            send_to_id(&reg, my_id, &Event::notice(format!("{} {cidr}", rule.label())))?;
+                        }
+                        continue;
+                    }
+                    AccessCommand::Allow(cidr) => (Rule::Allow, cidr),
+                    AccessCommand::Deny(cidr) => (Rule::Deny, cidr),
+                };
+                let notice = match cidr.parse::<Cidr>() {
+                    Ok(cidr) if access.add(rule, cidr) => {
+                        info!("ADMIN", "{name} ({my_id}) added {} {cidr}", rule.label());
+                        format!("{} {cidr} added until restart; open connections stay open", rule.label())
+                    }
+                    Ok(cidr) => format!("{cidr} is already on that list"),
+                    Err(e) => e,
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            Command::Schedules => {
+                let list = schedule.list(&name);
+                if list.is_empty() {
+                    send_to_id(&reg, my_id, &Event::notice("no scheduled messages or reminders"))?;
+                }
+                for s in list {
+                    let what = match s.kind {
+                        Kind::Dm => format!("to {}", s.to),
+                        Kind::Reminder if s.to == name => "remind me".to_string(),
+                        Kind::Reminder => format!("remind {}", s.to),
+                    };
+                    send_to_id(&reg, my_id, &Event::notice(format!("#{} at {} {what}: {}", s.id, clock::rfc3339(s.at), s.body)))?;
+                }
+            }
+            Command::Unschedule(id) => {
+                let cancelled = {
+                    let _storage = tracer.start("schedule.cancel", Some(&span));
+                    schedule.cancel(&name, id)
+                };
+                let notice = if cancelled { format!("#{id} cancelled") } else { format!("you have nothing scheduled as #{id}") };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+            Command::Remind { name: target, delay, text } => {
+                let target = target.unwrap_or_else(|| name.clone());
+                let Some(delay) = schedule::parse_delay(&delay) else {
+                    send_to_id(&reg, my_id, &Event::notice("delays look like 90s, 10m, 2h, 3d, 1w or 1h30m"))?;
+                    continue;
+                };
+                if !sanitize::is_clean_name(&target) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("can't remind {target}")))?;
+                    continue;
+                }
+                if target != name && reg.ephemeral(&name).await.contains(&target) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("DMs with {target} are ephemeral, so they can't be scheduled")))?;
+                    continue;
+                }
+                let (text, now) = (sanitize::message(&text), clock::now_unix());
+                let at = now.saturating_add(delay.as_secs());
+                let added = {
+                    let _storage = tracer.start("schedule.add", Some(&span));
+                    schedule.add(now, at, Kind::Reminder, &name, &target, &text)
+                };
+                match added {
+                    Ok(id) => {
+                        info!("REMIND", "{name} ({my_id}) -> {target} at {}: #{id}", clock::rfc3339(at));
+                        let whom = if target == name { "you".to_string() } else { target };
+                        send_to_id(&reg, my_id, &Event::notice(format!("reminder #{id} for {whom} at {}", clock::rfc3339(at))))?;
+                    }
+                    Err(e) => send_to_id(&reg, my_id, &Event::notice(e.to_string()))?,
+                }
+            }
+
+            Command::ToId { id: tid, body: msg } => {
+                let msg = sanitize::message(&msg);
+                let tname = reg.name_of(tid).await.unwrap_or_else(|| "?".into());
+                if !screen(&reg, &spam, &name, my_id, To::Dm(&tname), &msg).await? {
+                    continue;
+                }
+
+                info!("MSG", "{name} ({my_id}) -> {tname} ({tid}): {msg}");
+
+                match reg.deliver_dm(&name, my_id, tid, &msg).await {
+                    Ok(Delivery::Queued { id }) => follow_up(&reg, &previewer, &uploads, id, &msg, signed, Audience::Dm(my_id, tid)).await,
+                    Ok(Delivery::Held) => {
+                        send_to_id(&reg, my_id, &Event::notice(format!("{tname} is in do-not-disturb and will see your message later")))?;
+                        continue;
+                    }
+                    Ok(Delivery::Dropped) => {
+                        send_to_id(&reg, my_id, &Event::notice(format!("{tname} is in do-not-disturb; ephemeral messages aren't held")))?;
+                        continue;
+                    }
+                    Err(e) => {
+                        let notice = if e.is::<Blocked>() { format!("your message to {tname} wasn't delivered") } else { "target offline".into() };
+                        send_to_id(&reg, my_id, &Event::notice(notice))?;
+                        continue;
+                    }
+                }
+                if let Some(away) = reg.away_message(tid).await {
+                    send_to_id(&reg, my_id, &Event::notice(format!("{tname} is away: {away}")))?;
+                }
+            }
+
+            // ---- KEY EXCHANGE ----
+            // Keys are relayed as they are; the server never sees the DMs
+            // they encrypt.
+            Command::Key { name: target_name, key } => {
+                if !protocol::is_public_key(&key) {
+                    send_to_id(&reg, my_id, &Event::notice("keys are 64 hex digits"))?;
+                    continue;
+                }
+
+                info!("KEY", "{name} ({my_id}) -> {target_name}");
+
+                // Refused like a DM, without saying why.
+                if reg.blocked(&target_name).await.contains(&name) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("your key to {target_name} wasn't delivered")))?;
+                    continue;
+                }
+                if let Some(tid) = reg.id_of(&target_name).await {
+                    if send_to_id(&reg, tid, &Event::Key { from: name.clone(), from_id: my_id, key }).is_err() {
+                        send_to_id(&reg, my_id, &Event::notice("target disconnected"))?;
+                    }
+                } else {
+                    send_to_id(&reg, my_id, &Event::notice("target not found"))?;
+                }
+            }
+
+            // ---- AWAY ----
+            Command::Away(message) => {
+                let message = message.map(|m| sanitize::clean(&m)).filter(|m| !m.trim().is_empty());
+                let notice = if message.is_some() { "you are marked as away" } else { "you are no longer marked as away" };
+                info!("AWAY", "{name} ({my_id}): {}", message.as_deref().unwrap_or("back"));
+                reg.set_away(my_id, message).await;
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            // ---- PROFILE ----
+            // Kept with the account, so the next to take an unregistered
+            // nickname can't inherit one.
+            Command::Profile(ProfileCommand::Set { .. }) if !accounts.is_registered(&name) => {
+                send_to_id(&reg, my_id, &Event::notice("profiles are for registered nicknames; REGISTER first"))?;
+            }
+            Command::Profile(ProfileCommand::Set { field, value }) => {
+                let Some(field) = Field::parse(&field) else {
+                    let fields = Field::ALL.map(Field::name).join(", ");
+                    send_to_id(&reg, my_id, &Event::notice(format!("no profile field {field}; there are {fields}")))?;
+                    continue;
+                };
+                let value = value.map(|v| sanitize::clean(&v).trim().to_string()).filter(|v| !v.is_empty());
+                let notice = match reg.set_profile(&name, field, value.clone()).await {
+                    Ok(()) => {
+                        info!("PROFILE", "{name} ({my_id}) {}: {}", field.name(), value.as_deref().unwrap_or("cleared"));
+                        match value {
+                            Some(value) => format!("{field} set to {value}"),
+                            None => format!("{field} cleared"),
+                        }
+                    }
+                    Err(e) => e,
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+            Command::Profile(ProfileCommand::Get(target)) => {
+                let profile = reg.whois(&target).await.profile;
+                if profile.is_empty() {
+                    send_to_id(&reg, my_id, &Event::notice(format!("{target} has no profile")))?;
+                    continue;
+                }
+                send_to_id(&reg, my_id, &Event::notice(format!("profile of {target}:")))?;
+                for line in profile.lines() {
+                    send_to_id(&reg, my_id, &Event::notice(format!("  {line}")))?;
+                }
+            }
+
+            // ---- AVATAR ----
+            Command::Avatar(AvatarCommand::Set(Some(_))) if !accounts.is_registered(&name) => {
+                send_to_id(&reg, my_id, &Event::notice("avatars are for registered nicknames; REGISTER first"))?;
+            }
+            Command::Avatar(AvatarCommand::Set(None)) => {
+                reg.set_avatar(&name, None).await;
+                info!("AVATAR", "{name} ({my_id}) removed their avatar");
+                send_to_id(&reg, my_id, &Event::notice("avatar removed"))?;
+            }
+            Command::Avatar(AvatarCommand::Set(Some(image))) => match Avatar::parse(image) {
+                Ok(avatar) => {
+                    let notice = format!("avatar set: {}, {} bytes", avatar.format, avatar.len);
+                    info!("AVATAR", "{name} ({my_id}) set a {} of {} bytes", avatar.format, avatar.len);
+                    reg.set_avatar(&name, Some(avatar)).await;
+                    send_to_id(&reg, my_id, &Event::notice(notice))?;
+                }
+                Err(e) => send_to_id(&reg, my_id, &Event::notice(e))?,
+            },
+            Command::Avatar(AvatarCommand::Get(target)) => {
+                let event = match reg.whois(&target).await.profile.avatar() {
+                    Some(avatar) => Event::Avatar { name: target, image: avatar.image.clone() },
+                    None => Event::notice(format!("{target} has no avatar")),
+                };
+                send_to_id(&reg, my_id, &event)?;
+            }
+
+            // ---- PRESENCE ----
+            Command::Watch(None) => {
+                let watched = reg.watched(&name).await;
+                if watched.is_empty() {
+                    send_to_id(&reg, my_id, &Event::notice("you aren't watching anyone"))?;
+                }
+                for (target, presence) in watched {
+                    send_to_id(&reg, my_id, &Event::Presence { name: target, presence })?;
+                }
+            }
+            // Persisted on the account, not the nickname.
+            Command::Watch(Some(_)) if !accounts.is_registered(&name) => {
+                send_to_id(&reg, my_id, &Event::notice("watch lists are for registered nicknames; REGISTER first"))?;
+            }
+            Command::Watch(Some(target)) => {
+                if target == name || !sanitize::is_clean_name(&target) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("can't watch {target}")))?;
+                    continue;
+                }
+                match reg.watch(&name, &target).await {
+                    Ok(presence) => {
+                        info!("WATCH", "{name} ({my_id}) watches {target}");
+                        send_to_id(&reg, my_id, &Event::Presence { name: target, presence })?;
+                    }
+                    Err(WatchFull) => send_to_id(&reg, my_id, &Event::notice(format!("you can watch at most {MAX_WATCHED} names")))?,
+                }
+            }
+            Command::Unwatch(target) => {
+                let notice = if reg.unwatch(&name, &target).await { format!("no longer watching {target}") } else { format!("you weren't watching {target}") };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            Command::Read(id) => reg.mark_read(&name, id).await,
+
+            // ---- EPHEMERAL THREADS ----
+            Command::Ephemeral { name: with, on } => {
+                if with == name || !sanitize::is_clean_name(&with) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("can't have a DM thread with {with}")))?;
+                    continue;
+                }
+                if reg.set_ephemeral(&name, &with, on).await {
+                    info!("EPHEMERAL", "{name} ({my_id}) -> {with} {}", if on { "on" } else { "off" });
+                }
+                send_to_id(&reg, my_id, &Event::Ephemeral { name: with, on })?;
+            }
+
+            // ---- PUSH NOTIFICATIONS ----
+            Command::Push(_) if !pusher.enabled() => {
+                send_to_id(&reg, my_id, &Event::notice("push notifications are turned off on this server"))?;
+            }
+            Command::Push(None) => {
+                pusher.set(&name, None);
+                info!("PUSH", "{name} ({my_id}) turned push off");
+                send_to_id(&reg, my_id, &Event::notice("push notifications off"))?;
+            }
+            Command::Push(Some(_)) if !accounts.is_registered(&name) => {
+                send_to_id(&reg, my_id, &Event::notice("push notifications are for registered nicknames; REGISTER first"))?;
+            }
+            Command::Push(Some(url)) => match HttpUrl::parse(&url) {
+                Ok(url) => match push::resolve(&url, pusher.allow_private()).await {
+                    Ok(_) => {
+                        info!("PUSH", "{name} ({my_id}) pushes to {url}");
+                        send_to_id(&reg, my_id, &Event::notice(format!("DMs sent while you're offline will be pushed to {url}")))?;
+                        pusher.set(&name, Some(url));
+                    }
+                    Err(e) => send_to_id(&reg, my_id, &Event::notice(format!("can't push to {url}: {e}")))?,
+                },
+                Err(e) => send_to_id(&reg, my_id, &Event::notice(e))?,
+            },
+
+            // ---- DO NOT DISTURB ----
+            Command::Dnd(dnd) => {
+                info!("DND", "{name} ({my_id}) {}", if dnd.on { "on" } else { "off" });
+                if dnd.on {
+                    let allowed: Vec<_> = [(dnd.friends, "DMs from friends"), (dnd.mentions, "mentions")].into_iter().filter(|(on, _)| *on).map(|(_, what)| what).collect();
+                    let notice = match allowed.as_slice() {
+                        [] => "do-not-disturb on; messages are held until DND off".to_string(),
+                        allowed => format!("do-not-disturb on; messages are held until DND off, except {}", allowed.join(" and ")),
+                    };
+                    send_to_id(&reg, my_id, &Event::notice(notice))?;
+                } else {
+                    send_to_id(&reg, my_id, &Event::notice("do-not-disturb off"))?;
+                }
+                reg.set_dnd(my_id, dnd).await;
+                for event in memberships.set_dnd(dnd) {
+                    send_to_id(&reg, my_id, &event)?;
+                }
+            }
+
+            // ---- BLOCKING ----
+            Command::Block(None) => {
+                let blocked = reg.blocked(&name).await;
+                let notice = if blocked.is_empty() {
+                    "you aren't blocking anyone".to_string()
+                } else {
+                    format!("blocked: {}", blocked.into_iter().collect::<Vec<_>>().join(", "))
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+            // Persisted per account, not per nickname.
+            Command::Block(Some(_)) if !accounts.is_registered(&name) => {
+                send_to_id(&reg, my_id, &Event::notice("block lists are for registered nicknames; REGISTER first"))?;
+            }
+            Command::Block(Some(target)) => {
+                if target == name || !sanitize::is_clean_name(&target) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("can't block {target}")))?;
+                    continue;
+                }
+                match reg.block(&name, &target).await {
+                    Ok(()) => {
+                        info!("BLOCK", "{name} ({my_id}) blocks {target}");
+                        memberships.set_blocked(reg.blocked(&name).await);
+                        send_to_id(&reg, my_id, &Event::notice(format!("blocked {target}")))?;
+                    }
+                    Err(BlockFull) => send_to_id(&reg, my_id, &Event::notice(format!("you can block at most {MAX_BLOCKED} names")))?,
+                }
+            }
+            Command::Unblock(target) => {
+                let notice = if reg.unblock(&name, &target).await {
+                    memberships.set_blocked(reg.blocked(&name).await);
+                    format!("unblocked {target}")
+                } else {
+                    format!("you weren't blocking {target}")
+                };
+                send_to_id(&reg, my_id, &Event::notice(notice))?;
+            }
+
+            // ---- FRIENDS ----
+            Command::Friend(FriendCommand::List) => {
+                let friends = reg.friends(&name).await;
+                if friends.is_empty() {
+                    send_to_id(&reg, my_id, &Event::notice("your friend list is empty"))?;
+                }
+                send_friends(&reg, my_id, friends)?;
+            }
+            // A contact list kept on the server is between accounts, on both
+            // sides, or the next holder of a nickname would inherit it.
+            Command::Friend(FriendCommand::Add(_)) if !accounts.is_registered(&name) => {
+                send_to_id(&reg, my_id, &Event::notice("friend lists are for registered nicknames; REGISTER first"))?;
+            }
+            Command::Friend(FriendCommand::Add(target)) => {
+                if target == name || !sanitize::is_clean_name(&target) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("can't befriend {target}")))?;
+                    continue;
+                }
+                if !accounts.is_registered(&target) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("{target} isn't a registered nickname")))?;
+                    continue;
+                }
+                match reg.add_friend(&name, &target).await {
+                    FriendChange::Asked => {
+                        info!("FRIEND", "{name} ({my_id}) asked {target}");
+                        send_to_id(&reg, my_id, &Event::Friend { name: target, status: FriendStatus::Sent })?;
+                    }
+                    FriendChange::Accepted(presence) => {
+                        info!("FRIEND", "{name} ({my_id}) and {target} are friends");
+                        send_to_id(&reg, my_id, &Event::Friend { name: target.clone(), status: FriendStatus::Friends })?;
+                        send_to_id(&reg, my_id, &Event::Presence { name: target, presence })?;
+                    }
+                    FriendChange::AlreadyFriends => send_to_id(&reg, my_id, &Event::notice(format!("{target} is already your friend")))?,
+                    FriendChange::Full => send_to_id(&reg, my_id, &Event::notice(format!("you can have at most {MAX_FRIENDS} friends and requests")))?,
+                    FriendChange::Removed | FriendChange::NotFound => {}
+                }
+            }
+            Command::Friend(FriendCommand::Remove(target)) => {
+                match reg.remove_friend(&name, &target).await {
+                    FriendChange::Removed => {
+                        info!("FRIEND", "{name} ({my_id}) dropped {target}");
+                        send_to_id(&reg, my_id, &Event::Friend { name: target, status: FriendStatus::Removed })?;
+                    }
+                    _ => send_to_id(&reg, my_id, &Event::notice(format!("{target} isn't on your friend list")))?,
+                }
+            }
+
+            // ---- WHOIS ----
+            Command::Whois(target) => {
+                let whois = reg.whois(&target).await;
+                let status = match (whois.id, &whois.away) {
+                    (None, _) if whois.profile.is_empty() => {
+                        send_to_id(&reg, my_id, &Event::notice(format!("no such user {target}")))?;
+                        continue;
+                    }
+                    (None, _) => format!("{target} is offline"),
+                    (Some(id), None) => format!("{target} is online (id {id})"),
+                    (Some(id), Some(away)) => format!("{target} is online (id {id}), away: {away}"),
+                };
+                send_to_id(&reg, my_id, &Event::notice(status))?;
+                for line in whois.profile.lines() {
+                    send_to_id(&reg, my_id, &Event::notice(format!("  {line}")))?;
+                }
+            }
+
+            // ---- PING ----
+            // Not logged: clients send these as keepalives.
+            Command::Ping(token) => {
+                send_to_id(&reg, my_id, &Event::Pong(sanitize::clean(&token)))?;
+            }
+
+            // ---- QUIT ----
+            Command::Quit(message) => {
+                let message = message.map(|m| sanitize::clean(&m));
+                info!("QUIT", "{name} ({my_id}): {}", message.as_deref().unwrap_or("no message"));
+                send_to_id(&reg, my_id, &Event::notice("goodbye")).ok();
+                break;
+            }
+
+            // ---- JOIN CHANNEL ----
+            Command::Join { channel, key } => {
+                if !protocol::is_channel_name(&channel) {
+                    send_to_id(&reg, my_id, &Event::notice("channel names start with # and have no spaces (max 32 characters)"))?;
+                    continue;
+                }
+                if memberships.contains(&channel) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("already in {channel}")))?;
+                    continue;
+                }
+
+                let (Some(tx), Some(joined)) = (reg.sender(my_id), reg.join(&channel, &name, role, key.as_deref()).await) else {
+                    break;
+                };
+                let joined = match joined {
+                    Ok(joined) => joined,
+                    Err(denied) => {
+                        info!("DENIED", "{name} ({my_id}) can't join {channel}: {denied}");
+                        send_to_id(&reg, my_id, &Event::notice(format!("can't join {channel}: {denied}")))?;
+                        continue;
+                    }
+                };
+                memberships.join(&channel, joined.sub, tx);
+                info!("JOIN", "{name} ({my_id}) -> {channel}");
+                send_to_id(&reg, my_id, &Event::notice(format!("joined {channel}")))?;
+                if let Some(topic) = joined.topic {
+                    send_to_id(&reg, my_id, &Event::notice(format!("topic of {channel}: {topic}")))?;
+                }
+            }
+
+            // ---- CHANNEL TOPIC ----
+            Command::Topic { channel, topic } => {
+                if !memberships.contains(&channel) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("join {channel} first")))?;
+                    continue;
+                }
+                let setting = topic.is_some();
+                let topic = topic.map(|t| sanitize::clean(&t));
+                match reg.topic(&channel, &name, role, topic).await {
+                    Ok(topic) if setting => info!("TOPIC", "{name} ({my_id}) -> {channel}: {}", topic.unwrap_or_default()),
+                    Ok(Some(topic)) => send_to_id(&reg, my_id, &Event::notice(format!("topic of {channel}: {topic}")))?,
+                    Ok(None) => send_to_id(&reg, my_id, &Event::notice(format!("{channel} has no topic")))?,
+                    Err(reason) => {
+                        if setting {
+                            info!("DENIED", "{name} ({my_id}) can't set the topic of {channel}: {reason}");
+                        }
+                        send_to_id(&reg, my_id, &Event::notice(reason))?;
+                    }
+                }
+            }
+
+            // ---- PART CHANNEL ----
+            Command::Part(channel) => {
+                if memberships.part(&channel) {
+                    if voice.channel_of(my_id).as_ref() == Some(&channel) {
+                        voice.leave(my_id);
+                        reg.broadcast(&channel, Event::Voice { channel: channel.clone(), name: name.clone(), joined: false }).await;
+                    }
+                    info!("PART", "{name} ({my_id}) <- {channel}");
+                    send_to_id(&reg, my_id, &Event::notice(format!("left {channel}")))?;
+                } else {
+                    send_to_id(&reg, my_id, &Event::notice(format!("not in {channel}")))?;
+                }
+            }
+
+            // ---- VOICE ----
+            Command::Voice(VoiceCommand::Join(channel)) => {
+                if !config.voice.enabled {
+                    send_to_id(&reg, my_id, &Event::notice("voice is off on this server"))?;
+                    continue;
+                }
+                if !memberships.contains(&channel) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("join {channel} first")))?;
+                    continue;
+                }
+                let joined = voice.join(&channel, my_id, &name);
+                if let Some(left) = joined.left {
+                    reg.broadcast(&left, Event::Voice { channel: left.clone(), name: name.clone(), joined: false }).await;
+                }
+                info!("VOICE", "{name} ({my_id}) -> {channel}");
+                send_to_id(&reg, my_id, &Event::VoiceToken { channel: channel.clone(), port: config.voice.port, token: joined.token })?;
+                for present in joined.present {
+                    send_to_id(&reg, my_id, &Event::Voice { channel: channel.clone(), name: present, joined: true })?;
+                }
+                reg.broadcast(&channel, Event::Voice { channel: channel.clone(), name: name.clone(), joined: true }).await;
+            }
+            Command::Voice(VoiceCommand::Leave) => match voice.leave(my_id) {
+                Some(channel) => {
+                    info!("VOICE", "{name} ({my_id}) <- {channel}");
+                    reg.broadcast(&channel, Event::Voice { channel: channel.clone(), name: name.clone(), joined: false }).await;
+                }
+                None => send_to_id(&reg, my_id, &Event::notice("not in a voice channel"))?,
+            },
+
+            // ---- INVITE LINK ----
+            Command::Invite(args) => {
+                if let Some(channel) = &args.channel {
+                    if !memberships.contains(channel) {
+                        send_to_id(&reg, my_id, &Event::notice(format!("join {channel} first")))?;
+                        continue;
+                    }
+                }
+                let ttl = match args.ttl_secs.map(Duration::from_secs) {
+                    Some(ttl) if ttl.is_zero() || ttl > MAX_INVITE_TTL => {
+                        send_to_id(&reg, my_id, &Event::notice(format!("invites last 1 to {} seconds", MAX_INVITE_TTL.as_secs())))?;
+                        continue;
+                    }
+                    Some(ttl) => ttl,
+                    None => config.invites.ttl,
+                };
+                let token = invites.create(args.channel.clone(), args.once, ttl);
+                info!("INVITE", "{name} ({my_id}): {} for {}s{}", args.channel.as_deref().unwrap_or("server"), ttl.as_secs(), if args.once { ", single use" } else { "" });
+                send_to_id(&reg, my_id, &Event::notice(format!("invite: {}", invites::link(local_addr, args.channel.as_deref(), &token))))?;
+            }
+
+            // ---- CHANNEL / DM HISTORY ----
+            Command::History(args) => {
+                let target = &args.target;
+                let limit = args.limit.map_or(HISTORY_REPLAY, |n| (n as usize).min(history::CHANNEL_LIMIT));
+                let (page, heading) = if let Some(other) = target.strip_prefix('@').filter(|n| !n.is_empty()) {
+                    let Some(page) = reg.thread(&name, other, args.before, limit).await else {
+                        send_to_id(&reg, my_id, &Event::notice("this server keeps no DM history"))?;
+                        continue;
+                    };
+                    (page, format!("history with {other}"))
+                } else if protocol::is_channel_name(target) {
+                    if !memberships.contains(target) {
+                        send_to_id(&reg, my_id, &Event::notice(format!("join {target} first")))?;
+                        continue;
+                    }
+                    (reg.channel_history(target, args.before, limit).await.unwrap_or_default(), format!("history of {target}"))
+                } else {
+                    send_to_id(&reg, my_id, &Event::notice("usage: HISTORY <#chan|@name> [before:<id>] [limit:<n>]"))?;
+                    continue;
+                };
+
+                info!("HISTORY", "{name} ({my_id}): {} messages of {target}", page.events.len());
+                // The cursor for the page before this one, if there is one.
+                let more = match page.events.first() {
+                    Some(oldest) if page.more => format!(", more with before:{}", history::message_id(oldest)),
+                    _ => String::new(),
+                };
+                send_to_id(&reg, my_id, &Event::notice(format!("{heading}: {} messages{more}", page.events.len())))?;
+                let tx = reg.sender(my_id).ok_or_else(|| anyhow!("no such id"))?;
+                for event in page.events {
+                    tx.push(event).map_err(|_| anyhow!("failed to deliver message to {my_id}"))?;
+                }
+            }
+
+            // ---- SEARCH ----
+            // Only the user's own DMs and channels they're in are searched.
+            Command::Search(args) => {
+                if let Some(channel) = &args.channel {
+                    if !memberships.contains(channel) {
+                        send_to_id(&reg, my_id, &Event::notice(format!("join {channel} first")))?;
+                        continue;
+                    }
+                }
+
+                let limit = args.limit.map_or(SEARCH_RESULTS, |n| (n as usize).min(MAX_SEARCH_RESULTS));
+                let query = args.query.clone();
+                let found = reg.search(&name, memberships.channels(), args, limit).await;
+                info!("SEARCH", "{name} ({my_id}): {} matches for {query:?}", found.len());
+                send_to_id(&reg, my_id, &Event::notice(format!("{} matches for \"{query}\"", found.len())))?;
+                let tx = reg.sender(my_id).ok_or_else(|| anyhow!("no such id"))?;
+                for event in found {
+                    tx.push(event).map_err(|_| anyhow!("failed to deliver message to {my_id}"))?;
+                }
+            }
+
+            // ---- HISTORY RETENTION ----
+            Command::Retention { channel, policy } => {
+                info!("ADMIN", "{name} ({my_id}) requested retention of {channel}");
+
+                let retention = match policy.as_deref().map(Retention::parse) {
+                    Some(None) => {
+                        send_to_id(&reg, my_id, &Event::notice("usage: RETENTION <#chan> [none | <n> messages | <n>s|m|h|d]"))?;
+                        continue;
+                    }
+                    Some(retention) => retention,
+                    None => None,
+                };
+                match reg.set_retention(&channel, retention).await {
+                    Some(current) => {
+                        if retention.is_some() {
+                            info!("RETENTION", "{channel}: {current}");
+                        }
+                        let notice = match current {
+                            Retention::None => format!("{channel} keeps no history"),
+                            current => format!("{channel} keeps {current} of history"),
+                        };
+                        send_to_id(&reg, my_id, &Event::notice(notice))?;
+                    }
+                    None => send_to_id(&reg, my_id, &Event::notice(format!("no such channel {channel}")))?,
+                }
+            }
+
+            // ---- CHANNEL MESSAGE ----
+            Command::Say { channel, body: msg } => {
+                if !memberships.contains(&channel) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("join {channel} first")))?;
+                    continue;
+                }
+
+                let msg = sanitize::message(&msg);
+                if !screen(&reg, &spam, &name, my_id, To::Channel, &msg).await? {
+                    continue;
+                }
+                match reg.say(&channel, &name, role, my_id, &msg).await {
+                    Ok(Said { id, reached }) => {
+                        info!("MSG", "{name} ({my_id}) -> {channel} ({reached} members): {msg}");
+                        follow_up(&reg, &previewer, &uploads, id, &msg, signed, Audience::Channel(channel)).await;
+                    }
+                    Err(SayDenied) => {
+                        info!("DENIED", "{name} ({my_id}) -> {channel}: announcement channel");
+                        send_to_id(&reg, my_id, &Event::notice(format!("{channel} is read-only: only its operators can post")))?;
+                    }
+                }
+            }
+
+            // ---- POLLS ----
+            Command::Poll(args) => {
+                if !memberships.contains(&args.channel) {
+                    send_to_id(&reg, my_id, &Event::notice(format!("join {} first", args.channel)))?;
+                    continue;
+                }
+                let duration = match args.duration.as_deref().map(schedule::parse_delay) {
+                    None => poll::DEFAULT_DURATION,
+                    Some(Some(duration)) if duration <= poll::MAX_DURATION => duration,
+                    Some(_) => {
+                        send_to_id(&reg, my_id, &Event::notice("polls run for a delay like 90s, 10m, 2h or 1h30m, and at most 7d"))?;
+                        continue;
+                    }
+                };
+                let options = args.options.iter().map(|o| sanitize::clean(o)).collect();
+                match reg.open_poll(&args.channel, &name, sanitize::clean(&args.question), options, duration).await {
+                    Ok(id) => info!("POLL", "{name} ({my_id}) opened #{id} in {} for {}s", args.channel, duration.as_secs()),
+                    Err(e) => send_to_id(&reg, my_id, &Event::notice(e.to_string()))?,
+                }
+            }
+
+            Command::Vote { poll: id, choice } => {
+                let choice = usize::try_from(choice).unwrap_or(usize::MAX);
+                match reg.vote(id, &name, choice, memberships.channels()).await {
+                    Ok(channel) => send_to_id(&reg, my_id, &Event::notice(format!("your vote in poll #{id} in {channel} is counted")))?,
+                    Err(e) => send_to_id(&reg, my_id, &Event::notice(e.to_string()))?,
+                }
+            }
+
+            // ---- RESEND MISSED MESSAGES ----
+            Command::Resend(range) => {
+                let Some(range) = range else {
+                    send_to_id(&reg, my_id, &Event::notice("usage: RESEND <from_seq> [to_seq]"))?;
+                    continue;
+                };
+
+                let entries = reg.received(&name, range).await;
+                info!("RESEND", "{name} ({my_id}): {} messages from seq {}", entries.len(), range.from);
+                for entry in &entries {
+                    send_to_id(&reg, my_id, &entry.to_event())?;
+                }
+            }
+
+            Command::BadEncoding => {
+                send_to_id(&reg, my_id, &Event::notice("message is not valid UTF-8"))?;
+            }
+
+            // Handshake commands are only valid before WELCOME.
+            Command::Hello(_) | Command::Cap(_) | Command::Token(_) | Command::Pass(_) | Command::Nick(_) | Command::Unknown => {
+                send_to_id(&reg, my_id, &Event::notice(format!("unknown command; commands: {}", protocol::USAGE)))?;
+            }
+        }
     }
 
-    disconnect_client(&reg, my_id).await;
-    let _ = writer_task.await;
+    drop(memberships);
+    reg.disconnect(my_id).await;
+    // REGISTER may have been used since logging in.
+    nicks.left(&name, peer_ip, accounts.is_registered(&name), Instant::now());
+    if !writer_task.is_finished() {
+        let _ = writer_task.await;
+    }
     Ok(())
 }
 
-async fn find_id_by_name(reg: &Shared, name: &str) -> Option<u64> {
-    let r = reg.read().await;
-    r.id_by_name.get(name).copied()
+/// Best effort: a socket that refuses an option still works.
+fn set_socket_options(socket: SockRef<'_>, tcp: &TcpConfig) {
+    let _ = socket.set_tcp_nodelay(tcp.nodelay);
+    if let Some(idle) = tcp.keepalive {
+        let mut keepalive = TcpKeepalive::new().with_time(idle);
+        if let Some(interval) = tcp.keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        if let Some(retries) = tcp.keepalive_retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+        let _ = socket.set_tcp_keepalive(&keepalive);
+    }
 }
 
-async fn disconnect_client(reg: &Shared, id: u64) {
-    let mut r = reg.write().await;
+/// Reads one handshake frame; if the deadline passes first, the client is
+/// told so before the error.
+async fn handshake_frame(frames: &mut FramedRead<impl AsyncRead + Unpin, Codec>, writer: &mut (impl AsyncWrite + Unpin), codec: Codec, wire: Wire, conn: &Conn, deadline: Instant) -> Result<Bytes> {
+    match timeout_at(deadline, frames.next_frame()).await {
+        Ok(Ok(Some(frame))) => {
+            conn.read(frame.len(), Instant::now());
+            Ok(frame)
+        }
+        Ok(Ok(None)) => Err(anyhow!("client disconnected before sending a nickname")),
+        Ok(Err(e)) => Err(anyhow!("failed to read nickname: {e}")),
+        Err(_) => {
+            let _ = write_frame(writer, codec, &Event::Error("timeout waiting for NICK".into()).encode(wire)).await;
+            Err(anyhow!("client handshake timed out"))
+        }
+    }
+}
 
-    if let Some(shutdown) = r.shutdown.remove(&id) {
-        let _ = shutdown.send(());
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), mut codec: Codec, msg: &[u8]) -> Result<()> {
+    let mut out = BytesMut::new();
+    codec.encode(msg, &mut out)?;
+    writer.write_all(&out).await?;
+    Ok(())
+}
+
+/// A `FRIEND` event for each entry, followed by a `PRESENCE` for actual
+/// friends; others' presence isn't theirs to see yet.
+fn send_friends(reg: &Registry, id: u64, friends: Vec<(String, FriendStatus, Presence)>) -> Result<()> {
+    for (name, status, presence) in friends {
+        send_to_id(reg, id, &Event::Friend { name: name.clone(), status })?;
+        if status == FriendStatus::Friends {
+            send_to_id(reg, id, &Event::Presence { name, presence })?;
+        }
     }
+    Ok(())
+}
 
-    if let Some(name) = r.name_by_id.remove(&id) {
-        println!("[DISCONNECT] {name} ({id}) was removed.");
-        r.id_by_name.remove(&name);
+/// Who gets what follows a message: both ends of a DM, or a channel.
+enum Audience {
+    Dm(u64, u64),
+    Channel(String),
+}
+
+impl Audience {
+    async fn send(&self, reg: &Registry, event: Event) {
+        match self {
+            Audience::Dm(from, to) => {
+                send_to_id(reg, *from, &event).ok();
+                if to != from {
+                    send_to_id(reg, *to, &event).ok();
+                }
+            }
+            Audience::Channel(channel) => reg.broadcast(channel, event).await,
+        }
     }
+}
 
-    r.by_id.remove(&id);
+/// The key, in hex, that `signature` over `name`'s message to `target` was
+/// made with, if it was made with `name`'s.
+fn check_signature(accounts: &Accounts, name: &str, target: &str, signature: &str, body: &str) -> Result<String, &'static str> {
+    let key = accounts.signing_key(name).ok_or("no signing key; set one with SIGNKEY <hex>")?;
+    let signature: [u8; ed25519::SIGNATURE_LEN] = accounts::parse_hex(signature).ok_or("the signature isn't 128 hex digits")?;
+    if !ed25519::verify(&key, &ed25519::signed_text(name, target, body), &signature) {
+        return Err("bad signature");
+    }
+    Ok(accounts::hex(&key))
 }
 
-fn parse_nick(line: &str) -> Option<String> {
-    let (cmd, nick) = line.split_once(' ')?;
-    if cmd.eq_ignore_ascii_case("NICK") {
-        Some(nick.trim().to_string())
-    } else {
-        None
+/// Registers `name`, or changes their password, and tells them so.
+async fn register(reg: &Registry, accounts: &Arc<Accounts>, name: &str, my_id: u64, password: &str) -> Result<()> {
+    let changed = accounts.is_registered(name);
+    match accounts.set(name, password).await {
+        Ok(()) => {
+            info!("ACCOUNTS", "{name} ({my_id}) {}", if changed { "changed their password" } else { "registered" });
+            reg.set_registered(my_id).await;
+            let notice = if changed { "password changed".to_string() } else { format!("{name} is registered; connect with PASS <password> before NICK from now on") };
+            send_to_id(reg, my_id, &Event::notice(notice))
+        }
+        Err(denied) => send_to_id(reg, my_id, &Event::notice(format!("can't register: {denied}"))),
     }
 }
 
-fn parse_to(line: &str) -> Option<(&str, &str)> {
-    let mut p = line.splitn(3, ' ');
-    match (p.next(), p.next(), p.next()) {
-        (Some("TO"), Some(name), Some(rest)) => Some((name, rest)),
-        _ => None,
+/// Whether `name`'s message may go out. If not, they're told why, and kicked
+/// if it's come to that.
+async fn screen(reg: &Registry, spam: &Spam, name: &str, my_id: u64, to: To<'_>, body: &str) -> Result<bool> {
+    let now = Instant::now();
+    if let Some(left) = spam.muted(name, now) {
+        send_to_id(reg, my_id, &Event::notice(format!("you're muted for spam for another {}s", left.as_secs().max(1))))?;
+        return Ok(false);
+    }
+    // Only people who are here count, so ordinary words that happen to be
+    // nicknames nobody's using don't.
+    let mut mentioned = 0;
+    if let Some(limit) = spam.mention_limit() {
+        for word in spam::named(body) {
+            if word != name && reg.id_of(word).await.is_some() {
+                mentioned += 1;
+                if mentioned == limit {
+                    break;
+                }
+            }
+        }
+    }
+    let Some(verdict) = spam.check(name, to, body, mentioned, now) else {
+        return Ok(true);
+    };
+    info!("SPAM", "{name} ({my_id}): {}, strike {} -> {}", verdict.pattern, verdict.strike, verdict.action.label());
+    let notice = match verdict.action {
+        Action::Warn => format!("message not sent: {} looks like spam", verdict.pattern),
+        Action::Mute => format!("message not sent: {} looks like spam; you're muted for {}s", verdict.pattern, spam.muted(name, now).unwrap_or_default().as_secs()),
+        Action::Kick => format!("kicked: {} looks like spam", verdict.pattern),
+    };
+    send_to_id(reg, my_id, &Event::notice(notice))?;
+    if verdict.action == Action::Kick {
+        reg.disconnect(my_id).await;
     }
+    Ok(false)
 }
 
-fn parse_toid(line: &str) -> Option<(u64, &str)> {
-    let mut p = line.splitn(3, ' ');
-    match (p.next(), p.next(), p.next()) {
-        (Some("TOID"), Some(id_s), Some(rest)) => id_s.parse::<u64>().ok().map(|id| (id, rest)),
-        _ => None,
+/// Tells `audience` message `id` was signed, if it was, and about the file
+/// it links to, if it links to one, or else starts fetching a preview of
+/// the first link in it.
+async fn follow_up(reg: &Registry, previewer: &Arc<Previewer>, uploads: &Uploads, id: u64, body: &str, signed: Option<String>, audience: Audience) {
+    if let Some(key) = signed {
+        audience.send(reg, Event::Verified { id, key }).await;
+    }
+    if let Some((url, file)) = uploads.attachment(body) {
+        return audience.send(reg, Event::Attachment { id, url, size: file.size, name: file.name }).await;
     }
+    spawn_preview(reg, previewer, id, body, audience);
 }
 
-async fn send_to_id(reg: &Shared, id: u64, msg: &str) -> Result<()> {
-    let tx = {
-        let r = reg.read().await;
-        r.by_id.get(&id).cloned()
-    }.ok_or_else(|| anyhow!("no such id"))?;
+/// Fetches the title of the first link in message `id`'s `body`, if
+/// previews are on and it has one, and sends it to `audience` once it's in.
+fn spawn_preview(reg: &Registry, previewer: &Arc<Previewer>, id: u64, body: &str, audience: Audience) {
+    let Some(url) = preview::find_url(body).filter(|_| previewer.enabled()).map(str::to_string) else {
+        return;
+    };
+    let (reg, previewer) = (reg.clone(), previewer.clone());
+    tokio::spawn(async move {
+        if let Some(title) = previewer.title(&url).await {
+            audience.send(&reg, Event::Preview { id, url, title }).await;
+        }
+    });
+}
 
-    tx.send(msg.to_string())
-        .await
+fn send_to_id(reg: &Registry, id: u64, event: &Event) -> Result<()> {
+    let tx = reg.sender(id).ok_or_else(|| anyhow!("no such id"))?;
+
+    tx.push(event.clone())
         .map_err(|_| anyhow!("failed to deliver message to {id}"))
 }
+
@@ -0,0 +1,81 @@
+//! Announcing the server on the local network over mDNS, so `client discover`
+//! can find it without anyone typing an address. Answers queries for the
+//! service and announces itself once at startup. Port 5353 is shared with
+//! whatever other responder the host runs (Avahi, Bonjour).
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use protocol::mdns::{self, Service};
+
+use crate::warn;
+
+/// RFC 6762 §8.3: announce twice, a second apart, in case the first is lost.
+const ANNOUNCE_GAP: Duration = Duration::from_secs(1);
+
+/// Binds the mDNS port and answers for `service` until the process exits.
+pub fn spawn(service: Service) -> io::Result<()> {
+    let socket = bind(service.addr)?;
+    tokio::spawn(async move {
+        if let Err(e) = respond(socket, service).await {
+            warn!("MDNS", "stopped: {e}");
+        }
+    });
+    Ok(())
+}
+
+fn bind(interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, mdns::PORT)).into())?;
+    socket.join_multicast_v4(&mdns::GROUP, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn respond(socket: UdpSocket, service: Service) -> io::Result<()> {
+    let group = SocketAddrV4::new(mdns::GROUP, mdns::PORT);
+    let announcement = service.response(0, false);
+    for n in 0..2 {
+        if n > 0 {
+            tokio::time::sleep(ANNOUNCE_GAP).await;
+        }
+        socket.send_to(&announcement, group).await?;
+    }
+
+    let mut buf = [0u8; 9000];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Some(query) = mdns::parse_query(&buf[..len]) else {
+            continue;
+        };
+        // Queries from another port are one-shot lookups that only listen
+        // for a direct reply; the unicast bit asks for one too.
+        let legacy = from.port() != mdns::PORT;
+        let reply = service.response(query.id, legacy);
+        let sent = if legacy || query.unicast { socket.send_to(&reply, from).await } else { socket.send_to(&reply, group).await };
+        if let Err(e) = sent {
+            warn!("MDNS", "reply to {from} failed: {e}");
+        }
+    }
+}
+
+/// This machine's name as a DNS label, for the `.local` host record.
+pub fn host_label() -> String {
+    let name = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .unwrap_or_default();
+    let label: String = name.trim().split('.').next().unwrap_or("").chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-').collect();
+    if label.is_empty() { "rustchat".to_string() } else { label.to_ascii_lowercase() }
+}
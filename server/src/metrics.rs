@@ -0,0 +1,162 @@
+//! Latency histograms, served for Prometheus to scrape at `/metrics` on
+//! `[metrics] port` when enabled: how long handshakes take from connecting
+//! to `WELCOME`, how long events wait in a client's queue before they're
+//! written, and how long a command takes from being read to being routed.
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration, Instant},
+};
+
+use crate::warn;
+
+/// Upper bounds of the buckets, in seconds.
+const BUCKETS: [f64; 14] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const MAX_HEAD: usize = 8 * 1024;
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct Histogram {
+    /// Observations at or under each bound; the last counts the rest.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let i = BUCKETS.iter().position(|&b| secs <= b).unwrap_or(BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Observes the time until it's dropped.
+    pub fn time(&self) -> Timer<'_> {
+        Timer { histogram: self, start: Instant::now() }
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            match BUCKETS.get(i) {
+                Some(bound) => writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}"),
+                None => writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}"),
+            }
+            .ok();
+        }
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+    }
+}
+
+pub struct Timer<'a> {
+    histogram: &'a Histogram,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.start.elapsed());
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub handshake: Histogram,
+    pub queue_wait: Histogram,
+    pub routing: Histogram,
+}
+
+impl Metrics {
+    /// The Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.handshake.render(&mut out, "rustchat_handshake_seconds", "Time from connecting to WELCOME.");
+        self.queue_wait.render(&mut out, "rustchat_queue_wait_seconds", "Time events wait in a client's queue before being written.");
+        self.routing.render(&mut out, "rustchat_routing_seconds", "Time from reading a command to having routed it.");
+        out
+    }
+
+    /// Answers scrapes on `listener` for as long as the server runs.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("METRICS", "accept failed: {e}");
+                    continue;
+                }
+            };
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics.handle(stream).await {
+                    warn!("METRICS", "{addr}: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let head = timeout(HEAD_TIMEOUT, read_head(&mut stream)).await.map_err(|_| anyhow!("timed out"))??;
+        let target = head.strip_prefix("GET ").and_then(|rest| rest.split(' ').next()).unwrap_or("");
+        let (status, kind, body) = match target.split('?').next() {
+            Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", self.render()),
+            _ => ("404 Not Found", "text/plain; charset=utf-8", "not found\n".to_string()),
+        };
+        let response = format!("HTTP/1.1 {status}\r\nContent-Type: {kind}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+        stream.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_HEAD {
+            bail!("headers too long");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("closed before the request was complete");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let metrics = Metrics::default();
+        metrics.handshake.observe(Duration::from_micros(300));
+        metrics.handshake.observe(Duration::from_millis(20));
+        metrics.handshake.observe(Duration::from_secs(30));
+        drop(metrics.routing.time());
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE rustchat_handshake_seconds histogram\n"));
+        assert!(text.contains("rustchat_handshake_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("rustchat_handshake_seconds_bucket{le=\"0.025\"} 2\n"));
+        assert!(text.contains("rustchat_handshake_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("rustchat_handshake_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("rustchat_handshake_seconds_sum 30.0203\n"));
+        assert!(text.contains("rustchat_handshake_seconds_count 3\n"));
+        assert!(text.contains("rustchat_routing_seconds_count 1\n"));
+        assert!(text.contains("rustchat_queue_wait_seconds_count 0\n"));
+    }
+}
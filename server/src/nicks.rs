@@ -0,0 +1,144 @@
+//! Keeps nicknames from being churned or sniped. Each address may log in
+//! only so many times a minute, and under only so many different nicknames,
+//! so reconnecting to change names can't be turned into a flood. When
+//! someone disconnects, their nickname is held for them for a while: only a
+//! connection from the same address can take it until then. Registered
+//! nicknames aren't held, since they need their password anyway.
+
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    net::IpAddr,
+};
+use tokio::time::{Duration, Instant};
+
+use crate::config::NicksConfig;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// A login refused before its nickname was taken.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refused {
+    TooManyLogins { left: Duration },
+    TooManyNames { left: Duration },
+    Held { left: Duration },
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refused::TooManyLogins { left } => write!(f, "too many logins from your address; try again in {}s", left.as_secs().max(1)),
+            Refused::TooManyNames { left } => write!(f, "too many nickname changes; try again in {}s, or use a nickname you had", left.as_secs().max(1)),
+            Refused::Held { left } => write!(f, "nickname was just in use and is held for its owner; try again in {}s", left.as_secs().max(1)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Each address's logins in the last minute, oldest first.
+    logins: HashMap<IpAddr, VecDeque<(Instant, String)>>,
+    /// Nicknames held for the address that had them, until when.
+    held: HashMap<String, (IpAddr, Instant)>,
+}
+
+pub struct Nicks {
+    config: NicksConfig,
+    state: Mutex<State>,
+}
+
+impl Nicks {
+    pub fn new(config: NicksConfig) -> Self {
+        Nicks { config, state: Mutex::default() }
+    }
+
+    /// Whether `name` may log in from `ip`; if so, the login is counted.
+    pub fn check(&self, name: &str, ip: IpAddr, registered: bool, now: Instant) -> Result<(), Refused> {
+        let mut state = self.state.lock();
+        forget_old(&mut state, now);
+        if !registered {
+            if let Some(&(owner, until)) = state.held.get(name) {
+                if owner != ip {
+                    return Err(Refused::Held { left: until - now });
+                }
+            }
+        }
+
+        let logins = state.logins.entry(ip).or_default();
+        // Logins are a minute old once the oldest one is.
+        let left = || logins.front().map_or(MINUTE, |&(at, _)| at + MINUTE - now);
+        if self.config.logins_per_minute > 0 && logins.len() >= self.config.logins_per_minute as usize {
+            return Err(Refused::TooManyLogins { left: left() });
+        }
+        let new_name = logins.iter().all(|(_, n)| n != name);
+        let names = logins.iter().map(|(_, n)| n).collect::<HashSet<_>>().len();
+        if self.config.names_per_minute > 0 && new_name && names >= self.config.names_per_minute as usize {
+            return Err(Refused::TooManyNames { left: left() });
+        }
+        logins.push_back((now, name.to_string()));
+        state.held.remove(name);
+        Ok(())
+    }
+
+    /// Holds `name` for `ip`, which just stopped using it.
+    pub fn left(&self, name: &str, ip: IpAddr, registered: bool, now: Instant) {
+        if !registered && !self.config.hold.is_zero() {
+            self.state.lock().held.insert(name.to_string(), (ip, now + self.config.hold));
+        }
+    }
+}
+
+fn forget_old(state: &mut State, now: Instant) {
+    for logins in state.logins.values_mut() {
+        while logins.front().is_some_and(|&(at, _)| now.duration_since(at) >= MINUTE) {
+            logins.pop_front();
+        }
+    }
+    state.logins.retain(|_, logins| !logins.is_empty());
+    state.held.retain(|_, &mut (_, until)| until > now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nicks() -> Nicks {
+        Nicks::new(NicksConfig { logins_per_minute: 4, names_per_minute: 2, hold: Duration::from_secs(30) })
+    }
+
+    #[test]
+    fn limits_logins_and_names() {
+        let n = nicks();
+        let (ip, now) = (IpAddr::from([192, 0, 2, 1]), Instant::now());
+        assert!(n.check("alice", ip, false, now).is_ok());
+        assert!(n.check("bob", ip, false, now + Duration::from_secs(10)).is_ok());
+        // A third name is one change too many, but the old ones are fine.
+        assert_eq!(n.check("carol", ip, false, now + Duration::from_secs(20)), Err(Refused::TooManyNames { left: Duration::from_secs(40) }));
+        assert!(n.check("alice", ip, false, now + Duration::from_secs(20)).is_ok());
+        assert!(n.check("bob", ip, false, now + Duration::from_secs(20)).is_ok());
+        assert_eq!(n.check("alice", ip, false, now + Duration::from_secs(30)), Err(Refused::TooManyLogins { left: Duration::from_secs(30) }));
+        // Other addresses have their own counts.
+        assert!(n.check("carol", IpAddr::from([192, 0, 2, 2]), false, now + Duration::from_secs(30)).is_ok());
+        // A minute on, the first login no longer counts.
+        assert!(n.check("carol", ip, false, now + Duration::from_secs(60)).is_err());
+        assert!(n.check("alice", ip, false, now + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn holds_nicknames_for_their_owner() {
+        let n = nicks();
+        let (ip, other, now) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]), Instant::now());
+        n.left("alice", ip, false, now);
+        assert_eq!(n.check("alice", other, false, now + Duration::from_secs(10)), Err(Refused::Held { left: Duration::from_secs(20) }));
+        // Registered nicknames have their password instead.
+        assert!(n.check("alice", other, true, now + Duration::from_secs(10)).is_ok());
+        // The owner takes it back, and the hold is over.
+        n.left("alice", ip, false, now);
+        assert!(n.check("alice", ip, false, now + Duration::from_secs(10)).is_ok());
+        assert!(n.check("alice", other, false, now + Duration::from_secs(11)).is_ok());
+
+        n.left("bob", ip, false, now);
+        assert!(n.check("bob", other, false, now + Duration::from_secs(30)).is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+//! Who may use which command. Everyone has a role: `admin` is the admin,
+//! registered nicknames listed in `[permissions] moderators` are moderators,
+//! and the rest are anyone. The admin has to be registered too, and since
+//! an unregistered `admin` could register itself, the nickname can only be
+//! used once it's in the accounts file; see `reserved`. Each command needs a role, admin for the admin
+//! commands and anyone for the rest unless `[permissions.commands]` says
+//! otherwise, and is checked here before it runs.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use protocol::Command;
+
+use crate::config::PermissionsConfig;
+
+/// Commands only the admin may use unless configured otherwise.
+pub const ADMIN_COMMANDS: &[&str] = &["kick", "kickid", "export", "purge", "retention", "unlock", "access", "connections", "drain"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    #[default]
+    Anyone,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn label(self) -> &'static str {
+        match self {
+            Role::Anyone => "anyone",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "anyone" => Ok(Role::Anyone),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("expected \"anyone\", \"moderator\" or \"admin\", not {other:?}")),
+        }
+    }
+}
+
+pub struct Permissions {
+    moderators: Vec<String>,
+    /// By keyword; commands not here are anyone's.
+    needed: HashMap<&'static str, Role>,
+}
+
+impl Permissions {
+    pub fn new(config: &PermissionsConfig) -> Self {
+        let mut needed: HashMap<_, _> = ADMIN_COMMANDS.iter().map(|&c| (c, Role::Admin)).collect();
+        for (command, &role) in &config.commands {
+            // The config only holds known keywords.
+            if let Some(&keyword) = Command::KEYWORDS.iter().find(|&&k| k == command) {
+                needed.insert(keyword, role);
+            }
+        }
+        Permissions { moderators: config.moderators.clone(), needed }
+    }
+
+    /// `name`'s role. The admin and moderators have to be registered, or
+    /// anyone could take the nickname.
+    pub fn role(&self, name: &str, registered: bool) -> Role {
+        if !registered {
+            Role::Anyone
+        } else if name == "admin" {
+            Role::Admin
+        } else if self.moderators.iter().any(|m| m == name) {
+            Role::Moderator
+        } else {
+            Role::Anyone
+        }
+    }
+
+    /// Why `name` can't be used by a connection that hasn't logged in to
+    /// it, if it can't: only an account set up in the accounts file may be
+    /// the admin.
+    pub fn reserved(name: &str, registered: bool) -> Option<&'static str> {
+        (name == "admin" && !registered).then_some("the admin nickname has to be registered in [accounts] file before it can be used")
+    }
+
+    /// The role `command` needs.
+    pub fn needed(&self, command: &Command) -> Role {
+        self.needed.get(command.keyword()).copied().unwrap_or(Role::Anyone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_commands_to_roles() {
+        let config = PermissionsConfig {
+            moderators: vec!["mod".into()],
+            commands: [("kickid".to_string(), Role::Moderator), ("poll".to_string(), Role::Moderator)].into(),
+        };
+        let p = Permissions::new(&config);
+        assert_eq!(p.role("admin", true), Role::Admin);
+        assert_eq!(p.role("admin", false), Role::Anyone);
+        assert!(Permissions::reserved("admin", false).is_some());
+        assert_eq!(Permissions::reserved("admin", true), None);
+        assert_eq!(Permissions::reserved("alice", false), None);
+        assert_eq!(p.role("mod", true), Role::Moderator);
+        assert_eq!(p.role("mod", false), Role::Anyone);
+        assert_eq!(p.role("alice", true), Role::Anyone);
+
+        assert_eq!(p.needed(&Command::KickId(Some(3))), Role::Moderator);
+        assert_eq!(p.needed(&Command::Kick("bob".into())), Role::Admin);
+        assert_eq!(p.needed(&Command::Upload), Role::Anyone);
+        let poll = Command::parse(b"POLL #rust \"lunch?\" \"yes\" \"no\"", protocol::Wire::Text);
+        assert_eq!(p.needed(&poll), Role::Moderator);
+        assert!(Role::Admin > Role::Moderator && Role::Moderator > Role::Anyone);
+    }
+}
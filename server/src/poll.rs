@@ -0,0 +1,206 @@
+//! Polls in channels. `POLL #room "question" "opt1" "opt2"` opens one that
+//! members vote on with `VOTE <id> <n>`, once each; when it closes the tally
+//! is announced to the channel. The registry owns the open polls and closes
+//! them as it sweeps, so one can run over its time by up to `SWEEP_EVERY`.
+//! Like channels, they don't survive a restart.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write as _},
+};
+use tokio::time::{Duration, Instant};
+
+/// How long a poll stays open when no time is given.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10 * 60);
+/// The longest a poll may stay open.
+pub const MAX_DURATION: Duration = Duration::from_secs(7 * 86_400);
+pub const MAX_OPTIONS: usize = 10;
+/// Longest question, and longest option, in characters.
+pub const MAX_TEXT: usize = 200;
+/// Polls open at once in one channel.
+pub const MAX_PER_CHANNEL: usize = 5;
+
+/// Why a poll wasn't opened.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PollDenied {
+    Options,
+    TooLong,
+    Full,
+}
+
+impl fmt::Display for PollDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PollDenied::Options => write!(f, "a poll needs between 2 and {MAX_OPTIONS} options"),
+            PollDenied::TooLong => write!(f, "questions and options are at most {MAX_TEXT} characters"),
+            PollDenied::Full => write!(f, "that channel already has {MAX_PER_CHANNEL} open polls"),
+        }
+    }
+}
+
+/// Why a vote wasn't counted.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VoteDenied {
+    /// No open poll with that ID in a channel the voter is in.
+    NoPoll,
+    NoChoice(usize),
+    AlreadyVoted(usize),
+}
+
+impl fmt::Display for VoteDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoteDenied::NoPoll => f.write_str("no open poll with that ID in your channels"),
+            VoteDenied::NoChoice(n) => write!(f, "pick an option from 1 to {n}"),
+            VoteDenied::AlreadyVoted(n) => write!(f, "you already voted for option {n}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Poll {
+    pub id: u64,
+    pub channel: String,
+    pub by: String,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Each voter's option, from 0.
+    votes: HashMap<String, usize>,
+    closes: Instant,
+}
+
+impl Poll {
+    /// What the channel is told when the poll opens.
+    pub fn opened(&self, now: Instant) -> String {
+        let mut line = format!("{} opened poll #{}: {}", self.by, self.id, self.question);
+        for (i, option) in self.options.iter().enumerate() {
+            let _ = write!(line, " | {}) {option}", i + 1);
+        }
+        let mins = self.closes.saturating_duration_since(now).as_secs().div_ceil(60);
+        let _ = write!(line, " | VOTE {} <n> within {mins}m", self.id);
+        line
+    }
+
+    /// Votes per option, in order.
+    pub fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for &choice in self.votes.values() {
+            counts[choice] += 1;
+        }
+        counts
+    }
+
+    /// What the channel is told when the poll closes.
+    pub fn results(&self) -> String {
+        let mut line = format!("poll #{} closed: {}", self.id, self.question);
+        for (option, count) in self.options.iter().zip(self.tally()) {
+            let _ = write!(line, " | {option}: {count}");
+        }
+        let total = self.votes.len();
+        let _ = write!(line, " | {total} vote{}", if total == 1 { "" } else { "s" });
+        line
+    }
+}
+
+#[derive(Debug)]
+pub struct Polls {
+    next_id: u64,
+    open: BTreeMap<u64, Poll>,
+}
+
+impl Default for Polls {
+    fn default() -> Self {
+        Polls { next_id: 1, open: BTreeMap::new() }
+    }
+}
+
+impl Polls {
+    /// Opens a poll in `channel` closing after `duration`, which the caller
+    /// has kept within `MAX_DURATION`.
+    pub fn open(&mut self, now: Instant, channel: &str, by: &str, question: String, options: Vec<String>, duration: Duration) -> Result<&Poll, PollDenied> {
+        if !(2..=MAX_OPTIONS).contains(&options.len()) {
+            return Err(PollDenied::Options);
+        }
+        if std::iter::once(&question).chain(&options).any(|t| t.is_empty() || t.chars().count() > MAX_TEXT) {
+            return Err(PollDenied::TooLong);
+        }
+        if self.open.values().filter(|p| p.channel == channel).count() >= MAX_PER_CHANNEL {
+            return Err(PollDenied::Full);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let poll = Poll { id, channel: channel.to_string(), by: by.to_string(), question, options, votes: HashMap::new(), closes: now + duration };
+        Ok(self.open.entry(id).or_insert(poll))
+    }
+
+    /// Counts `name`'s vote for option `choice`, from 1, if poll `id` is in
+    /// one of `channels`. Returns the poll's channel.
+    pub fn vote(&mut self, id: u64, name: &str, choice: usize, channels: &[String]) -> Result<&str, VoteDenied> {
+        let poll = self.open.get_mut(&id).filter(|p| channels.contains(&p.channel)).ok_or(VoteDenied::NoPoll)?;
+        if !(1..=poll.options.len()).contains(&choice) {
+            return Err(VoteDenied::NoChoice(poll.options.len()));
+        }
+        if let Some(earlier) = poll.votes.get(name) {
+            return Err(VoteDenied::AlreadyVoted(earlier + 1));
+        }
+        poll.votes.insert(name.to_string(), choice - 1);
+        Ok(&poll.channel)
+    }
+
+    /// Removes and returns the polls whose time is up.
+    pub fn take_closed(&mut self, now: Instant) -> Vec<Poll> {
+        let ids: Vec<u64> = self.open.values().filter(|p| p.closes <= now).map(|p| p.id).collect();
+        ids.iter().filter_map(|id| self.open.remove(id)).collect()
+    }
+
+    /// Drops the polls of a channel that's gone.
+    pub fn drop_channel(&mut self, channel: &str) {
+        self.open.retain(|_, p| p.channel != channel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn tallies_one_vote_each() {
+        let now = Instant::now();
+        let mut polls = Polls::default();
+        let id = polls.open(now, "#rust", "alice", "Lunch?".into(), options(&["pizza", "sushi"]), DEFAULT_DURATION).unwrap().id;
+        let member = ["#rust".to_string()];
+
+        assert_eq!(polls.vote(id, "bob", 2, &member), Ok("#rust"));
+        assert_eq!(polls.vote(id, "bob", 1, &member), Err(VoteDenied::AlreadyVoted(2)));
+        assert_eq!(polls.vote(id, "carol", 3, &member), Err(VoteDenied::NoChoice(2)));
+        assert_eq!(polls.vote(id, "carol", 1, &[]), Err(VoteDenied::NoPoll));
+        assert_eq!(polls.vote(id, "carol", 2, &member), Ok("#rust"));
+
+        assert!(polls.take_closed(now).is_empty());
+        let closed = polls.take_closed(now + DEFAULT_DURATION);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].tally(), vec![0, 2]);
+        assert_eq!(closed[0].results(), "poll #1 closed: Lunch? | pizza: 0 | sushi: 2 | 2 votes");
+        assert_eq!(polls.vote(id, "dave", 1, &member), Err(VoteDenied::NoPoll));
+    }
+
+    #[test]
+    fn checks_what_it_opens() {
+        let now = Instant::now();
+        let mut polls = Polls::default();
+        assert_eq!(polls.open(now, "#a", "alice", "q".into(), options(&["one"]), DEFAULT_DURATION).unwrap_err(), PollDenied::Options);
+        assert_eq!(polls.open(now, "#a", "alice", "q".into(), options(&["one", ""]), DEFAULT_DURATION).unwrap_err(), PollDenied::TooLong);
+        for _ in 0..MAX_PER_CHANNEL {
+            polls.open(now, "#a", "alice", "q".into(), options(&["y", "n"]), DEFAULT_DURATION).unwrap();
+        }
+        assert_eq!(polls.open(now, "#a", "alice", "q".into(), options(&["y", "n"]), DEFAULT_DURATION).unwrap_err(), PollDenied::Full);
+        let poll = polls.open(now, "#b", "alice", "Tabs?".into(), options(&["yes", "no"]), Duration::from_secs(90)).unwrap();
+        assert_eq!(poll.opened(now), "alice opened poll #6: Tabs? | 1) yes | 2) no | VOTE 6 <n> within 2m");
+        polls.drop_channel("#a");
+        assert_eq!(polls.take_closed(now + MAX_DURATION).len(), 1);
+    }
+}
@@ -0,0 +1,247 @@
+//! Link previews: when a DM or channel message has an `http://` link in it,
+//! the server fetches the page and sends the people who got the message a
+//! `Preview` event with its title. Off unless the operator turns it on.
+//!
+//! Users choose what gets fetched, so the server only goes to public
+//! addresses on the usual web ports, checks every address a name resolves
+//! to and connects to one it checked, follows no redirects and reads at most
+//! `max_bytes`. Titles are cached, and so are failures, so a link posted
+//! again isn't fetched again.
+
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::{
+    config::PreviewConfig,
+    http::{self, HttpUrl},
+    info,
+    sanitize,
+};
+
+/// For resolving, connecting and reading, together.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const PORTS: &[u16] = &[80, 8080];
+/// Longest title sent, in characters.
+const MAX_TITLE: usize = 200;
+/// Links kept in the cache; the oldest go first.
+const MAX_CACHED: usize = 1000;
+
+pub struct Previewer {
+    config: PreviewConfig,
+    /// By URL: when it was fetched, and its title if it had one.
+    cache: Mutex<HashMap<String, (Instant, Option<String>)>>,
+}
+
+impl Previewer {
+    pub fn new(config: PreviewConfig) -> Self {
+        Previewer { config, cache: Mutex::default() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// The title of the page at `url`, from the cache if it's fresh.
+    pub async fn title(&self, url: &str) -> Option<String> {
+        let now = Instant::now();
+        if let Some((at, title)) = self.cache.lock().get(url) {
+            if now.duration_since(*at) < self.config.cache_for {
+                return title.clone();
+            }
+        }
+        let title = match timeout(FETCH_TIMEOUT, self.fetch(url)).await {
+            Ok(Ok(title)) => Some(title),
+            Ok(Err(e)) => {
+                info!("PREVIEW", "no title for {url}: {e}");
+                None
+            }
+            Err(_) => {
+                info!("PREVIEW", "no title for {url}: timed out");
+                None
+            }
+        };
+        let mut cache = self.cache.lock();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (at, _)| now.duration_since(*at) < self.config.cache_for);
+            if let Some(oldest) = cache.iter().min_by_key(|(_, (at, _))| *at).map(|(url, _)| url.clone()) {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(url.to_string(), (now, title.clone()));
+        title
+    }
+
+    async fn fetch(&self, url: &str) -> Result<String> {
+        let parsed = HttpUrl::parse(url).map_err(anyhow::Error::msg)?;
+        if !PORTS.contains(&parsed.port) {
+            bail!("port {} isn't a web port", parsed.port);
+        }
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((parsed.host.as_str(), parsed.port)).await?.collect();
+        if let Some(addr) = addrs.iter().find(|a| !self.config.allow_private && !is_public(a.ip())) {
+            bail!("{} is not a public address", addr.ip());
+        }
+        let Some(&addr) = addrs.first() else {
+            bail!("{} has no addresses", parsed.host);
+        };
+        let host = if parsed.port == 80 { parsed.host.clone() } else { format!("{}:{}", parsed.host, parsed.port) };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: rustchat-preview\r\nAccept: text/html\r\nConnection: close\r\n\r\n",
+            parsed.path
+        );
+        let response = http::exchange(addr, &request, self.config.max_bytes).await?;
+        if response.status != 200 {
+            bail!("answered {}", response.status);
+        }
+        if response.header("content-type").is_some_and(|t| !t.to_ascii_lowercase().starts_with("text/html")) {
+            bail!("not a web page");
+        }
+        title_of(&String::from_utf8_lossy(&response.body)).ok_or_else(|| anyhow::anyhow!("no title"))
+    }
+}
+
+/// The `http://` links in `text`, without punctuation around them.
+pub fn urls(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace()
+        .map(|word| word.trim_start_matches(['(', '<', '"', '\'']).trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', '"', '\'']))
+        .filter(|word| word.len() > "http://".len() && word.starts_with("http://"))
+}
+
+/// The first of `urls`.
+pub fn find_url(text: &str) -> Option<&str> {
+    urls(text).next()
+}
+
+/// Whether `ip` is on the public internet, so fetching from it can't reach
+/// the server's own network.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (64..128).contains(&b);
+            let reserved = a == 0 || a >= 240;
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast() || shared || reserved)
+        }
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                let first = ip.segments()[0];
+                let unique_local = first & 0xfe00 == 0xfc00;
+                let link_local = first & 0xffc0 == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+            }
+        },
+    }
+}
+
+/// The IPv4 address an IPv6 one stands for: mapped (`::ffff:0:0/96`), the
+/// well-known NAT64 prefix (`64:ff9b::/96`), or 6to4 (`2002::/16`), where the
+/// address that's actually reached is the IPv4 one.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let v4 = |hi: u16, lo: u16| Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo));
+    match s {
+        [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => Some(v4(hi, lo)),
+        [0x2002, hi, lo, ..] => Some(v4(hi, lo)),
+        _ => ip.to_ipv4_mapped(),
+    }
+}
+
+/// The text of the page's `<title>`, tidied up.
+fn title_of(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let title = decode_entities(&html[start..end]);
+    let title = sanitize::clean(&title).split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        return None;
+    }
+    Some(match title.char_indices().nth(MAX_TITLE) {
+        Some((cut, _)) => format!("{}…", &title[..cut]),
+        None => title,
+    })
+}
+
+/// The few entities titles tend to have, and numeric ones.
+fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => ' ',
+                n if n.starts_with("#x") || n.starts_with("#X") => char::from_u32(u32::from_str_radix(&n[2..], 16).ok()?)?,
+                n => char::from_u32(n.strip_prefix('#')?.parse().ok()?)?,
+            };
+            Some((c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_links() {
+        assert_eq!(find_url("see (http://example.com/a?b=c)."), Some("http://example.com/a?b=c"));
+        assert_eq!(find_url("<http://example.com>"), Some("http://example.com"));
+        assert_eq!(find_url("https://example.com and http://"), None);
+        assert_eq!(find_url("no links"), None);
+    }
+
+    #[test]
+    fn keeps_to_public_addresses() {
+        for private in ["127.0.0.1", "10.1.2.3", "192.168.0.1", "172.16.0.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1", "64:ff9b::a9fe:a9fe", "64:ff9b::127.0.0.1", "2002:c0a8:1::1", "2002:7f00:1::"] {
+            assert!(!is_public(private.parse().unwrap()), "{private}");
+        }
+        for public in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946", "64:ff9b::93.184.216.34", "2002:5db8:d822::1"] {
+            assert!(is_public(public.parse().unwrap()), "{public}");
+        }
+    }
+
+    #[test]
+    fn reads_titles() {
+        assert_eq!(title_of("<html><head><TITLE lang=en>\n  Rust &amp; &#x1F980;  </title>").as_deref(), Some("Rust & 🦀"));
+        assert_eq!(title_of("<title></title>"), None);
+        assert_eq!(title_of("<p>no title</p>"), None);
+        assert_eq!(title_of(&format!("<title>{}</title>", "a".repeat(300))).map(|t| t.chars().count()), Some(MAX_TITLE + 1));
+    }
+
+    #[tokio::test]
+    async fn refuses_what_it_shouldnt_fetch() {
+        let open = Previewer::new(PreviewConfig { enabled: true, allow_private: true, ..PreviewConfig::default() });
+        assert!(open.fetch("http://127.0.0.1:22/").await.unwrap_err().to_string().contains("web port"));
+        // Failures are cached like titles.
+        assert_eq!(open.title("http://127.0.0.1:22/").await, None);
+        assert!(open.cache.lock().contains_key("http://127.0.0.1:22/"));
+
+        let strict = Previewer::new(PreviewConfig { enabled: true, ..PreviewConfig::default() });
+        assert!(strict.fetch("http://127.0.0.1/").await.unwrap_err().to_string().contains("not a public address"));
+    }
+}
@@ -0,0 +1,170 @@
+//! Profiles: a few free-form fields users fill in about themselves with
+//! `PROFILE SET`, shown to others by `PROFILE GET` and `WHOIS`, and an avatar
+//! uploaded with `AVATAR SET` for clients that show pictures. There are no
+//! accounts, so a profile belongs to the nickname and lasts as long as the
+//! server runs, or across restarts with `[snapshot] file`.
+
+use std::{collections::BTreeMap, fmt};
+
+use protocol::base64;
+
+/// Longest value any field takes, in characters.
+pub const MAX_LEN: usize = 200;
+/// Largest avatar accepted, decoded.
+pub const AVATAR_MAX: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Field {
+    RealName,
+    Pronouns,
+    Timezone,
+    Bio,
+}
+
+impl Field {
+    pub const ALL: [Field; 4] = [Field::RealName, Field::Pronouns, Field::Timezone, Field::Bio];
+
+    /// The name `PROFILE SET` takes, ignoring case.
+    pub fn parse(name: &str) -> Option<Field> {
+        Field::ALL.into_iter().find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::RealName => "realname",
+            Field::Pronouns => "pronouns",
+            Field::Timezone => "timezone",
+            Field::Bio => "bio",
+        }
+    }
+
+    /// Why `value` won't do for this field, if it won't.
+    fn check(self, value: &str) -> Result<(), String> {
+        if value.chars().count() > MAX_LEN {
+            return Err(format!("{self} is too long (at most {MAX_LEN} characters)"));
+        }
+        // An IANA name like Europe/Berlin or an offset like UTC+2.
+        if self == Field::Timezone && value.contains(char::is_whitespace) {
+            return Err("timezone is one word, like Europe/Berlin or UTC+2".into());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Field::RealName => "real name",
+            other => other.name(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    fields: BTreeMap<Field, String>,
+    avatar: Option<Avatar>,
+}
+
+/// An image kept as the base64 it arrived in, since that's how it's served.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Avatar {
+    pub image: String,
+    pub format: &'static str,
+    pub len: usize,
+}
+
+impl Avatar {
+    /// Checks that `image` is base64 of a PNG, JPEG, GIF or WebP of at most
+    /// `AVATAR_MAX` bytes. Only the signature is looked at, not the pixels.
+    pub fn parse(image: String) -> Result<Avatar, String> {
+        let bytes = base64::decode(&image).ok_or("the avatar isn't valid base64")?;
+        if bytes.len() > AVATAR_MAX {
+            return Err(format!("the avatar is {} bytes; the most allowed is {AVATAR_MAX}", bytes.len()));
+        }
+        let format = match bytes.as_slice() {
+            [0x89, b'P', b'N', b'G', ..] => "PNG",
+            [0xff, 0xd8, 0xff, ..] => "JPEG",
+            [b'G', b'I', b'F', b'8', ..] => "GIF",
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "WebP",
+            _ => return Err("avatars are PNG, JPEG, GIF or WebP images".into()),
+        };
+        Ok(Avatar { image, format, len: bytes.len() })
+    }
+}
+
+impl Profile {
+    pub fn get(&self, field: Field) -> Option<&str> {
+        self.fields.get(&field).map(String::as_str)
+    }
+
+    /// Sets `field`, or clears it when `value` is `None`.
+    pub fn set(&mut self, field: Field, value: Option<String>) -> Result<(), String> {
+        match value {
+            Some(value) => {
+                field.check(&value)?;
+                self.fields.insert(field, value);
+            }
+            None => {
+                self.fields.remove(&field);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn avatar(&self) -> Option<&Avatar> {
+        self.avatar.as_ref()
+    }
+
+    pub fn set_avatar(&mut self, avatar: Option<Avatar>) {
+        self.avatar = avatar;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.avatar.is_none()
+    }
+
+    /// `real name: Ada Lovelace` and so on, in a fixed order, then a line
+    /// about the avatar if there is one.
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        let fields = self.fields.iter().map(|(field, value)| format!("{field}: {value}"));
+        fields.chain(self.avatar.iter().map(|a| format!("avatar: {}, {} bytes", a.format, a.len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_and_clears_fields() {
+        let mut profile = Profile::default();
+        assert_eq!(Field::parse("RealName"), Some(Field::RealName));
+        assert_eq!(Field::parse("age"), None);
+        profile.set(Field::Bio, Some("likes lifetimes".into())).unwrap();
+        profile.set(Field::RealName, Some("Ada Lovelace".into())).unwrap();
+        assert_eq!(profile.lines().collect::<Vec<_>>(), ["real name: Ada Lovelace", "bio: likes lifetimes"]);
+        assert!(profile.set(Field::Timezone, Some("Central European".into())).is_err());
+        assert!(profile.set(Field::Bio, Some("x".repeat(MAX_LEN + 1))).is_err());
+        assert_eq!(profile.get(Field::Bio), Some("likes lifetimes"));
+        profile.set(Field::Bio, None).unwrap();
+        profile.set(Field::RealName, None).unwrap();
+        assert!(profile.is_empty());
+    }
+
+    #[test]
+    fn avatars_are_small_images() {
+        let png = base64::encode(b"\x89PNG\r\n\x1a\n....");
+        let avatar = Avatar::parse(png.clone()).unwrap();
+        assert_eq!((avatar.format, avatar.len, avatar.image.as_str()), ("PNG", 12, png.as_str()));
+        let mut profile = Profile::default();
+        profile.set_avatar(Some(avatar));
+        assert_eq!(profile.lines().collect::<Vec<_>>(), ["avatar: PNG, 12 bytes"]);
+
+        assert!(Avatar::parse(base64::encode(b"GIF89a")).is_ok());
+        assert!(Avatar::parse(base64::encode(b"plain text")).unwrap_err().contains("PNG, JPEG"));
+        assert!(Avatar::parse("not base64!".into()).unwrap_err().contains("base64"));
+        let huge = [&[0xff, 0xd8, 0xff][..], &[0; AVATAR_MAX]].concat();
+        assert!(Avatar::parse(base64::encode(&huge)).unwrap_err().contains("most allowed"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Push notifications for DMs sent to users who are offline. A user opts in
+//! with `PUSH <url>`, naming a topic on an ntfy-style server that turns a
+//! plain POST into a phone notification, and out again with `PUSH` alone.
+//! Like profiles, URLs belong to the nickname for as long as the server runs,
+//! and across restarts with `[snapshot] file`.
+//!
+//! Only `http://` URLs work, as there's no TLS here. The server operator has
+//! to turn pushes on, since they have the server send requests to URLs users
+//! choose; so only registered nicknames get to set one, and like previews
+//! they may only point at public addresses unless `[push] allow_private` is
+//! set, for an ntfy server on the LAN or a local proxy.
+
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+use std::{collections::HashMap, net::SocketAddr};
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::{
+    config::PushConfig,
+    http::{self, HttpUrl},
+    preview,
+};
+
+/// For connecting, sending and hearing back, together.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Pusher {
+    config: PushConfig,
+    urls: Mutex<HashMap<String, HttpUrl>>,
+    /// When each nickname was last pushed to, for `min_interval`.
+    last: Mutex<HashMap<String, Instant>>,
+}
+
+impl Pusher {
+    pub fn new(config: PushConfig) -> Self {
+        Pusher { config, urls: Mutex::default(), last: Mutex::default() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn allow_private(&self) -> bool {
+        self.config.allow_private
+    }
+
+    /// Sets where to notify `name`, or stops notifying them with `None`.
+    pub fn set(&self, name: &str, url: Option<HttpUrl>) {
+        match url {
+            Some(url) => self.urls.lock().insert(name.to_string(), url),
+            None => self.urls.lock().remove(name),
+        };
+    }
+
+    pub fn url(&self, name: &str) -> Option<HttpUrl> {
+        self.urls.lock().get(name).cloned()
+    }
+
+    /// Everyone's URL, by nickname.
+    pub fn urls(&self) -> Vec<(String, HttpUrl)> {
+        let mut urls: Vec<_> = self.urls.lock().iter().map(|(name, url)| (name.clone(), url.clone())).collect();
+        urls.sort_by(|a, b| a.0.cmp(&b.0));
+        urls
+    }
+
+    /// Where to notify `name` now, if they opted in and haven't been notified
+    /// in the last `min_interval`. Counts as notifying them.
+    pub fn due(&self, name: &str) -> Option<HttpUrl> {
+        let url = self.urls.lock().get(name).cloned().filter(|_| self.config.enabled)?;
+        let now = Instant::now();
+        let mut last = self.last.lock();
+        if last.get(name).is_some_and(|at| now.duration_since(*at) < self.config.min_interval) {
+            return None;
+        }
+        last.insert(name.to_string(), now);
+        Some(url)
+    }
+}
+
+/// The address to reach `url` at. Unless `allow_private`, every address its
+/// host resolves to has to be public, so a push can't reach into the
+/// server's own network.
+pub async fn resolve(url: &HttpUrl, allow_private: bool) -> Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((url.host.as_str(), url.port)).await?.collect();
+    if let Some(addr) = addrs.iter().find(|a| !allow_private && !preview::is_public(a.ip())) {
+        bail!("{} is not a public address", addr.ip());
+    }
+    match addrs.first() {
+        Some(&addr) => Ok(addr),
+        None => bail!("{} has no addresses", url.host),
+    }
+}
+
+/// POSTs `body` to `url` with `title` in a `Title` header, as ntfy takes it.
+/// The address is checked again each time, as a name can change where it
+/// points after `PUSH`.
+pub async fn post(url: &HttpUrl, title: &str, body: &str, allow_private: bool) -> Result<()> {
+    let addr = resolve(url, allow_private).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nTitle: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        title.replace(['\r', '\n'], " "),
+        body.len()
+    );
+    // The status line is all that matters.
+    let response = timeout(POST_TIMEOUT, http::exchange(addr, &request, 12)).await??;
+    match response.status {
+        200..=299 => Ok(()),
+        code => bail!("{url} answered {code}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn rate_limits_and_needs_opting_in() {
+        let pusher = Pusher::new(PushConfig { enabled: true, min_interval: Duration::from_secs(60), allow_private: false });
+        assert_eq!(pusher.due("alice"), None);
+        pusher.set("alice", Some(HttpUrl::parse("http://ntfy.lan/a").unwrap()));
+        assert!(pusher.due("alice").is_some());
+        assert_eq!(pusher.due("alice"), None);
+        pusher.set("alice", None);
+        assert_eq!(pusher.due("alice"), None);
+
+        let off = Pusher::new(PushConfig::default());
+        off.set("bob", Some(HttpUrl::parse("http://ntfy.lan/b").unwrap()));
+        assert_eq!(off.due("bob"), None);
+    }
+
+    #[tokio::test]
+    async fn posts_the_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"hi bob") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let url = HttpUrl::parse(&format!("http://127.0.0.1:{port}/bob")).unwrap();
+        assert!(post(&url, "DM from alice", "hi bob", false).await.unwrap_err().to_string().contains("not a public address"));
+        post(&url, "DM from alice", "hi bob", true).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /bob HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nTitle: DM from alice\r\n"));
+    }
+}
@@ -0,0 +1,212 @@
+//! Bounded per-client event queue. Unlike an mpsc channel, pushing never
+//! waits: when the queue is full the configured slow-consumer policy decides
+//! what gives, so one stalled client can't hold up whoever is sending to it.
+//! Events are shared, so fanning one out to many queues doesn't copy it.
+
+use parking_lot::Mutex;
+use std::{collections::VecDeque, sync::Arc};
+use tokio::{
+    sync::Notify,
+    time::{Duration, Instant},
+};
+
+use protocol::Event;
+
+use crate::{
+    config::{QueueConfig, SlowConsumer},
+    info,
+};
+
+/// The sending side; cheap to clone.
+#[derive(Clone)]
+pub struct ClientTx(Arc<Shared>);
+
+/// The receiving side, owned by the client's writer task.
+pub struct ClientRx {
+    shared: Arc<Shared>,
+    /// How long the last event received had been queued.
+    waited: Duration,
+}
+
+/// The client is gone, or was disconnected for being too slow.
+#[derive(Debug)]
+pub struct Closed;
+
+struct Shared {
+    id: u64,
+    capacity: usize,
+    policy: SlowConsumer,
+    state: Mutex<State>,
+    ready: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    /// With when each was queued.
+    events: VecDeque<(Instant, Arc<Event>)>,
+    closed: bool,
+    /// Events dropped since the client was last told about it.
+    dropped: u64,
+    full_since: Option<Instant>,
+}
+
+pub fn channel(id: u64, config: &QueueConfig) -> (ClientTx, ClientRx) {
+    let shared = Arc::new(Shared {
+        id,
+        capacity: config.capacity,
+        policy: config.slow_consumer,
+        state: Mutex::default(),
+        ready: Notify::new(),
+    });
+    (ClientTx(shared.clone()), ClientRx { shared, waited: Duration::ZERO })
+}
+
+impl ClientTx {
+    /// Queues `event`, applying the slow-consumer policy if the queue is full.
+    /// A dropped event still counts as success; only a closed queue fails.
+    pub fn push(&self, event: impl Into<Arc<Event>>) -> Result<(), Closed> {
+        let event = event.into();
+        let shared = &self.0;
+        let mut state = shared.state.lock();
+        if state.closed {
+            return Err(Closed);
+        }
+
+        if state.events.len() < shared.capacity {
+            state.full_since = None;
+            if state.dropped > 0 && shared.policy != SlowConsumer::DropOldest {
+                let gap = Event::notice(format!("{} messages dropped because your connection fell behind", state.dropped));
+                state.events.push_back((Instant::now(), Arc::new(gap)));
+                state.dropped = 0;
+            }
+            state.events.push_back((Instant::now(), event));
+        } else {
+            match shared.policy {
+                SlowConsumer::DropOldest => {
+                    state.events.pop_front();
+                    state.events.push_back((Instant::now(), event));
+                }
+                SlowConsumer::DropNewest => {}
+                SlowConsumer::Disconnect(after) => {
+                    let since = *state.full_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= after {
+                        info!("SLOW", "client {} queue full for {}s, disconnecting", shared.id, after.as_secs());
+                        state.closed = true;
+                        drop(state);
+                        shared.ready.notify_one();
+                        return Err(Closed);
+                    }
+                }
+            }
+            state.dropped += 1;
+        }
+        drop(state);
+        shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Events waiting to be written.
+    pub fn depth(&self) -> usize {
+        self.0.state.lock().events.len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.state.lock().closed
+    }
+
+    /// Stops accepting events; the writer still drains what is queued.
+    pub fn close(&self) {
+        self.0.state.lock().closed = true;
+        self.0.ready.notify_one();
+    }
+}
+
+impl ClientRx {
+    /// The next event if one is already queued.
+    pub fn try_recv(&mut self) -> Option<Arc<Event>> {
+        let (at, event) = self.shared.state.lock().events.pop_front()?;
+        self.waited = at.elapsed();
+        Some(event)
+    }
+
+    /// How long the event last received had been waiting.
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+
+    /// The next event, or `None` once the queue is closed and drained.
+    pub async fn recv(&mut self) -> Option<Arc<Event>> {
+        loop {
+            {
+                let mut state = self.shared.state.lock();
+                if let Some((at, event)) = state.events.pop_front() {
+                    self.waited = at.elapsed();
+                    return Some(event);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+}
+
+impl Drop for ClientRx {
+    fn drop(&mut self) {
+        self.shared.state.lock().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(policy: SlowConsumer) -> (ClientTx, ClientRx) {
+        channel(1, &QueueConfig { capacity: 2, slow_consumer: policy })
+    }
+
+    async fn drain(rx: &mut ClientRx) -> Vec<Event> {
+        let mut out = Vec::new();
+        while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(10), rx.recv()).await {
+            out.push(Arc::unwrap_or_clone(event));
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest() {
+        let (tx, mut rx) = queue(SlowConsumer::DropOldest);
+        for n in ["1", "2", "3"] {
+            tx.push(Event::notice(n)).unwrap();
+        }
+        assert_eq!(drain(&mut rx).await, vec![Event::notice("2"), Event::notice("3")]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_marks_the_gap() {
+        let (tx, mut rx) = queue(SlowConsumer::DropNewest);
+        for n in ["1", "2", "3", "4"] {
+            tx.push(Event::notice(n)).unwrap();
+        }
+        assert_eq!(drain(&mut rx).await, vec![Event::notice("1"), Event::notice("2")]);
+
+        tx.push(Event::notice("5")).unwrap();
+        assert_eq!(
+            drain(&mut rx).await,
+            vec![Event::notice("2 messages dropped because your connection fell behind"), Event::notice("5")]
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_after_grace() {
+        let (tx, mut rx) = queue(SlowConsumer::Disconnect(Duration::ZERO));
+        tx.push(Event::notice("1")).unwrap();
+        tx.push(Event::notice("2")).unwrap();
+        assert!(tx.push(Event::notice("3")).is_err());
+        assert!(tx.is_closed());
+        // What was already queued still goes out.
+        assert_eq!(drain(&mut rx).await, vec![Event::notice("1"), Event::notice("2")]);
+        assert!(rx.recv().await.is_none());
+    }
+}
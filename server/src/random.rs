@@ -0,0 +1,29 @@
+//! Values that mustn't be guessable: password salts, TOTP secrets, challenge
+//! nonces, and invite, upload and voice tokens. All of them come straight
+//! from the OS's generator (`protocol::xchacha::random`).
+
+use protocol::xchacha;
+
+/// `N` fresh bytes. A system that can't supply them can't keep anything
+/// here secret, so that's a panic rather than a weaker fallback.
+pub fn bytes<const N: usize>() -> [u8; N] {
+    xchacha::random().expect("can't read random bytes from the OS")
+}
+
+/// `N` fresh bytes as hex, for tokens that go in text.
+pub fn hex<const N: usize>() -> String {
+    crate::accounts::hex(&bytes::<N>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_repeats() {
+        let token = hex::<16>();
+        assert_eq!(token.len(), 32);
+        assert_ne!(hex::<16>(), token);
+        assert_ne!(bytes::<20>(), [0; 20]);
+    }
+}
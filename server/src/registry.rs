@@ -0,0 +1,1695 @@
+//! The connection registry runs as its own task. It owns the name/ID maps and
+//! the message history; connections talk to it through a cloneable handle, so
+//! every lookup and mutation is applied in order without a shared lock. Client
+//! senders live in a sharded map the handle reads directly, keeping the send
+//! path off the actor.
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{hash_map, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::{interval, Instant},
+};
+
+use protocol::{Dnd, Event, FriendStatus, Presence, Receipt, SearchArgs, SeqRange};
+
+use crate::{
+    clock,
+    config::{ChannelsConfig, PersistentChannel},
+    history::{self, Entry, History, MessageLog, Page, Retention},
+    info,
+    permissions::Role,
+    poll::{PollDenied, Polls, VoteDenied},
+    profile::{Avatar, Field, Profile},
+    queue::ClientTx,
+    senders::Senders,
+    snapshot::{self, ChannelState, Snapshot},
+};
+
+pub type ShutdownTx = oneshot::Sender<()>;
+
+const REQUEST_QUEUE: usize = 256;
+/// Messages a channel keeps for members that are behind before they skip ahead.
+const CHANNEL_BACKLOG: usize = 128;
+/// How often empty channels are looked for.
+const SWEEP_EVERY: Duration = Duration::from_secs(5);
+/// Names one user may watch.
+pub const MAX_WATCHED: usize = 100;
+/// Friends plus unanswered requests one user may have.
+pub const MAX_FRIENDS: usize = 200;
+/// Names one user may block.
+pub const MAX_BLOCKED: usize = 500;
+/// DMs held for a connection in do-not-disturb. Older ones are dropped, but
+/// stay in history for RESEND.
+pub const MAX_HELD: usize = 1000;
+
+enum Request {
+    Register { id: u64, name: String, tx: ClientTx, shutdown: ShutdownTx, reply: oneshot::Sender<Result<(), NameTaken>> },
+    Disconnect { id: u64, reply: oneshot::Sender<()> },
+    SetRegistered { id: u64, reply: oneshot::Sender<()> },
+    IdOf { name: String, reply: oneshot::Sender<Option<u64>> },
+    NameOf { id: u64, reply: oneshot::Sender<Option<String>> },
+    DeliverDm { from: String, from_id: u64, to: u64, body: String, reply: oneshot::Sender<Result<Delivery>> },
+    ForUser { name: String, since: u64, until: u64, reply: oneshot::Sender<Vec<Entry>> },
+    Received { name: String, range: SeqRange, reply: oneshot::Sender<Vec<Entry>> },
+    Purge { name: String, reply: oneshot::Sender<usize> },
+    UserData { name: String, reply: oneshot::Sender<Snapshot> },
+    TakeHeld { name: String, reply: oneshot::Sender<Vec<Event>> },
+    Join { channel: String, name: String, role: Role, key: Option<String>, reply: oneshot::Sender<Result<Joined, JoinDenied>> },
+    Topic { channel: String, name: String, role: Role, topic: Option<String>, reply: oneshot::Sender<Result<Option<String>, &'static str>> },
+    ReloadChannels { config: ChannelsConfig, reply: oneshot::Sender<()> },
+    ChannelHistory { channel: String, before: Option<u64>, limit: usize, reply: oneshot::Sender<Option<Page>> },
+    ChannelEntries { channel: String, since: u64, until: u64, reply: oneshot::Sender<Option<Vec<Entry>>> },
+    SetRetention { channel: String, retention: Option<Retention>, reply: oneshot::Sender<Option<Retention>> },
+    PurgeChannel { channel: String, reply: oneshot::Sender<Option<usize>> },
+    Search { name: String, channels: Vec<String>, args: SearchArgs, limit: usize, reply: oneshot::Sender<Vec<Arc<Event>>> },
+    SetDmRetention { retention: Retention, reply: oneshot::Sender<()> },
+    Thread { name: String, with: String, before: Option<u64>, limit: usize, reply: oneshot::Sender<Option<Page>> },
+    Broadcast { channel: String, event: Event, reply: oneshot::Sender<()> },
+    Say { channel: String, from: String, role: Role, from_id: u64, body: String, reply: oneshot::Sender<Result<Said, SayDenied>> },
+    SetAway { id: u64, message: Option<String>, reply: oneshot::Sender<()> },
+    SetDnd { id: u64, dnd: Dnd, reply: oneshot::Sender<()> },
+    Read { name: String, id: u64, reply: oneshot::Sender<()> },
+    AwayMessage { id: u64, reply: oneshot::Sender<Option<String>> },
+    SetProfile { name: String, field: Field, value: Option<String>, reply: oneshot::Sender<Result<(), String>> },
+    SetAvatar { name: String, avatar: Option<Avatar>, reply: oneshot::Sender<()> },
+    Whois { name: String, reply: oneshot::Sender<Whois> },
+    Watch { name: String, target: String, reply: oneshot::Sender<Result<Presence, WatchFull>> },
+    Unwatch { name: String, target: String, reply: oneshot::Sender<bool> },
+    Watched { name: String, reply: oneshot::Sender<Vec<(String, Presence)>> },
+    Block { name: String, target: String, reply: oneshot::Sender<Result<(), BlockFull>> },
+    Unblock { name: String, target: String, reply: oneshot::Sender<bool> },
+    Blocked { name: String, reply: oneshot::Sender<BTreeSet<String>> },
+    AddFriend { name: String, target: String, reply: oneshot::Sender<FriendChange> },
+    RemoveFriend { name: String, target: String, reply: oneshot::Sender<FriendChange> },
+    Friends { name: String, reply: oneshot::Sender<Vec<(String, FriendStatus, Presence)>> },
+    SetEphemeral { name: String, with: String, on: bool, reply: oneshot::Sender<bool> },
+    Ephemeral { name: String, reply: oneshot::Sender<Vec<String>> },
+    OpenPoll { channel: String, by: String, question: String, options: Vec<String>, duration: Duration, reply: oneshot::Sender<Result<u64, PollDenied>> },
+    Vote { id: u64, name: String, choice: usize, channels: Vec<String>, reply: oneshot::Sender<Result<String, VoteDenied>> },
+    Snapshot { reply: oneshot::Sender<Snapshot> },
+    Restore { snapshot: Snapshot, reply: oneshot::Sender<()> },
+}
+
+/// Another connection already holds the nickname.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NameTaken;
+
+/// A channel subscription, and the channel's topic to show the new member.
+pub struct Joined {
+    pub sub: broadcast::Receiver<Arc<Event>>,
+    pub topic: Option<String>,
+}
+
+/// Why a join was turned away: the channel has a key that wasn't given, or
+/// it doesn't exist yet and the policy doesn't let this user create it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinDenied {
+    BadKey,
+    NotAllowed,
+    /// The user already has this many, the most allowed.
+    TooMany(usize),
+}
+
+impl fmt::Display for JoinDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinDenied::BadKey => f.write_str("wrong or missing key"),
+            JoinDenied::NotAllowed => f.write_str("only admin can create channels"),
+            JoinDenied::TooMany(n) => write!(f, "you already created {n} channels, the most allowed"),
+        }
+    }
+}
+
+/// What `WHOIS` shows about a nickname.
+#[derive(Debug, Default)]
+pub struct Whois {
+    /// The connection holding the name, if anyone is online with it.
+    pub id: Option<u64>,
+    pub away: Option<String>,
+    pub profile: Profile,
+}
+
+/// What `FRIEND ADD` or `FRIEND REMOVE` did. The other side hears about it
+/// from the registry; the caller tells the user.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FriendChange {
+    Asked,
+    /// They had asked already, so now it's mutual; with their presence.
+    Accepted(Presence),
+    AlreadyFriends,
+    /// `MAX_FRIENDS` reached.
+    Full,
+    Removed,
+    /// Neither friends nor a request either way.
+    NotFound,
+}
+
+/// The watch list already has `MAX_WATCHED` names on it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WatchFull;
+
+/// What became of a DM that `deliver_dm` accepted.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Queued { id: u64 },
+    /// The recipient is in do-not-disturb; they get it when that's over.
+    Held,
+    /// The thread is ephemeral and the recipient is in do-not-disturb, so it
+    /// was dropped rather than held.
+    Dropped,
+}
+
+/// The block list already has `MAX_BLOCKED` names on it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockFull;
+
+/// Why `deliver_dm` failed when the recipient blocks the sender. The sender
+/// isn't told that, only that the message didn't get through.
+#[derive(Debug)]
+pub struct Blocked;
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the recipient blocks the sender")
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// Only the operators of an announcement channel may post in it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SayDenied;
+
+/// A channel message `say` sent: its ID and how many members it reached.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Said {
+    pub id: u64,
+    pub reached: usize,
+}
+
+/// Handle to the registry task.
+#[derive(Clone)]
+pub struct Registry {
+    tx: mpsc::Sender<Request>,
+    senders: Arc<Senders>,
+}
+
+#[derive(Default)]
+struct State {
+    senders: Arc<Senders>,
+    id_by_name: HashMap<String, u64>,
+    name_by_id: HashMap<u64, String>,
+    shutdown: HashMap<u64, ShutdownTx>,
+    history: History,
+    channels: HashMap<String, Channel>,
+    channel_config: ChannelsConfig,
+    /// Away messages of connections marked away.
+    away: HashMap<u64, String>,
+    /// Connections in do-not-disturb, with the DMs held for them.
+    dnd: HashMap<u64, (Dnd, VecDeque<Event>)>,
+    /// DMs still held for registered nicknames that disconnected in
+    /// do-not-disturb, or restored from a snapshot; given to them when they
+    /// next log in.
+    held: HashMap<String, VecDeque<Event>>,
+    /// Connections logged in to registered nicknames. Anyone can take an
+    /// unregistered one once it's free, so nothing of theirs is held.
+    registered: HashSet<u64>,
+    /// By nickname, kept when they disconnect.
+    profiles: HashMap<String, Profile>,
+    /// Who each nickname watches, kept like profiles.
+    watching: HashMap<String, BTreeSet<String>>,
+    /// Who each nickname blocks, kept like profiles.
+    blocking: HashMap<String, BTreeSet<String>>,
+    /// Both ways round: if bob is in alice's set, alice is in bob's.
+    friends: HashMap<String, BTreeSet<String>>,
+    /// Unanswered friend requests, as (from, to).
+    friend_requests: BTreeSet<(String, String)>,
+    /// DM threads whose messages aren't kept, as name pairs in order.
+    ephemeral: BTreeSet<(String, String)>,
+    /// Open polls, closed as the channels are swept.
+    polls: Polls,
+    next_msg_id: u64,
+}
+
+struct Channel {
+    tx: broadcast::Sender<Arc<Event>>,
+    /// From the config; never cleaned up.
+    persistent: bool,
+    /// Who created it by joining, counted against their limit.
+    creator: Option<String>,
+    topic: Option<String>,
+    key: Option<String>,
+    /// May change the topic, and post if it's an announcement channel.
+    ops: BTreeSet<String>,
+    announce: bool,
+    /// Goes with the channel when it's cleaned up.
+    log: MessageLog,
+    empty_since: Option<Instant>,
+}
+
+impl Channel {
+    fn new(creator: Option<String>, retention: Retention) -> Self {
+        let ops = creator.iter().cloned().collect();
+        let (tx, log) = (broadcast::channel(CHANNEL_BACKLOG).0, MessageLog::new(retention));
+        Channel { tx, persistent: false, creator, topic: None, key: None, ops, announce: false, log, empty_since: None }
+    }
+
+    fn configure(&mut self, def: &PersistentChannel, retention: Retention) {
+        self.persistent = true;
+        self.creator = None;
+        self.topic = def.topic.clone();
+        self.key = def.key.clone();
+        self.ops = def.ops.iter().cloned().collect();
+        self.announce = def.announce;
+        self.log.set_retention(def.retention.unwrap_or(retention), clock::now_unix());
+    }
+}
+
+impl Registry {
+    pub fn spawn() -> Self {
+        Registry::with_channels(ChannelsConfig::default())
+    }
+
+    pub fn with_channels(channel_config: ChannelsConfig) -> Self {
+        let (tx, mut rx) = mpsc::channel(REQUEST_QUEUE);
+        let senders = Arc::new(Senders::default());
+        let mut state = State { senders: senders.clone(), ..State::default() };
+        state.reload_channels(channel_config);
+        tokio::spawn(async move {
+            let mut sweep = interval(SWEEP_EVERY);
+            loop {
+                tokio::select! {
+                    req = rx.recv() => match req {
+                        Some(req) => state.handle(req),
+                        None => break,
+                    },
+                    _ = sweep.tick() => state.sweep(Instant::now()),
+                }
+            }
+        });
+        Registry { tx, senders }
+    }
+
+    async fn call<R>(&self, make: impl FnOnce(oneshot::Sender<R>) -> Request) -> Option<R> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(make(reply)).await.ok()?;
+        rx.await.ok()
+    }
+
+    /// Claims `name` for the connection and adds it. The check and the claim
+    /// are one step, so of several logins racing for a name exactly one wins.
+    pub async fn register(&self, id: u64, name: &str, tx: ClientTx, shutdown: ShutdownTx) -> Result<(), NameTaken> {
+        let name = name.to_string();
+        self.call(|reply| Request::Register { id, name, tx, shutdown, reply }).await.unwrap_or(Err(NameTaken))
+    }
+
+    /// Marks the connection away with `message`, or back with `None`.
+    pub async fn set_away(&self, id: u64, message: Option<String>) {
+        self.call(|reply| Request::SetAway { id, message, reply }).await;
+    }
+
+    /// Turns do-not-disturb on or off for the connection. Turning it off
+    /// queues the DMs held meanwhile.
+    pub async fn set_dnd(&self, id: u64, dnd: Dnd) {
+        self.call(|reply| Request::SetDnd { id, dnd, reply }).await;
+    }
+
+    /// Makes `name`'s DM thread with `with` ephemeral, or not, telling `with`
+    /// if it changed. Returns whether it did.
+    pub async fn set_ephemeral(&self, name: &str, with: &str, on: bool) -> bool {
+        let (name, with) = (name.to_string(), with.to_string());
+        self.call(|reply| Request::SetEphemeral { name, with, on, reply }).await.unwrap_or(false)
+    }
+
+    /// Who `name`'s ephemeral DM threads are with.
+    pub async fn ephemeral(&self, name: &str) -> Vec<String> {
+        let name = name.to_string();
+        self.call(|reply| Request::Ephemeral { name, reply }).await.unwrap_or_default()
+    }
+
+    /// Opens a poll in `channel`, announcing it there, and returns its ID.
+    /// The tally is announced when it closes after `duration`.
+    pub async fn open_poll(&self, channel: &str, by: &str, question: String, options: Vec<String>, duration: Duration) -> Result<u64, PollDenied> {
+        let (channel, by) = (channel.to_string(), by.to_string());
+        self.call(|reply| Request::OpenPoll { channel, by, question, options, duration, reply }).await.unwrap_or(Err(PollDenied::Full))
+    }
+
+    /// Counts `name`'s vote for option `choice`, from 1, in poll `id` if it's
+    /// open in one of `channels`; returns the poll's channel.
+    pub async fn vote(&self, id: u64, name: &str, choice: usize, channels: Vec<String>) -> Result<String, VoteDenied> {
+        let name = name.to_string();
+        self.call(|reply| Request::Vote { id, name, choice, channels, reply }).await.unwrap_or(Err(VoteDenied::NoPoll))
+    }
+
+    /// Sends a read receipt to whoever sent `name` the DM `id`.
+    pub async fn mark_read(&self, name: &str, id: u64) {
+        let name = name.to_string();
+        self.call(|reply| Request::Read { name, id, reply }).await;
+    }
+
+    pub async fn away_message(&self, id: u64) -> Option<String> {
+        self.call(|reply| Request::AwayMessage { id, reply }).await.flatten()
+    }
+
+    /// Sets or clears one of `name`'s profile fields; `Err` says what's wrong
+    /// with the value.
+    pub async fn set_profile(&self, name: &str, field: Field, value: Option<String>) -> Result<(), String> {
+        let name = name.to_string();
+        self.call(|reply| Request::SetProfile { name, field, value, reply }).await.unwrap_or_else(|| Err("registry is gone".into()))
+    }
+
+    /// Replaces `name`'s avatar, or removes it with `None`.
+    pub async fn set_avatar(&self, name: &str, avatar: Option<Avatar>) {
+        let name = name.to_string();
+        self.call(|reply| Request::SetAvatar { name, avatar, reply }).await;
+    }
+
+    /// Adds `target` to `name`'s watch list, returning their presence now.
+    pub async fn watch(&self, name: &str, target: &str) -> Result<Presence, WatchFull> {
+        let (name, target) = (name.to_string(), target.to_string());
+        self.call(|reply| Request::Watch { name, target, reply }).await.unwrap_or(Err(WatchFull))
+    }
+
+    /// `false` if `name` wasn't watching `target`.
+    pub async fn unwatch(&self, name: &str, target: &str) -> bool {
+        let (name, target) = (name.to_string(), target.to_string());
+        self.call(|reply| Request::Unwatch { name, target, reply }).await.unwrap_or(false)
+    }
+
+    /// Everyone on `name`'s watch list, with their presence.
+    pub async fn watched(&self, name: &str) -> Vec<(String, Presence)> {
+        let name = name.to_string();
+        self.call(|reply| Request::Watched { name, reply }).await.unwrap_or_default()
+    }
+
+    /// Adds `target` to `name`'s block list.
+    pub async fn block(&self, name: &str, target: &str) -> Result<(), BlockFull> {
+        let (name, target) = (name.to_string(), target.to_string());
+        self.call(|reply| Request::Block { name, target, reply }).await.unwrap_or(Err(BlockFull))
+    }
+
+    /// `false` if `name` wasn't blocking `target`.
+    pub async fn unblock(&self, name: &str, target: &str) -> bool {
+        let (name, target) = (name.to_string(), target.to_string());
+        self.call(|reply| Request::Unblock { name, target, reply }).await.unwrap_or(false)
+    }
+
+    pub async fn blocked(&self, name: &str) -> BTreeSet<String> {
+        let name = name.to_string();
+        self.call(|reply| Request::Blocked { name, reply }).await.unwrap_or_default()
+    }
+
+    /// Asks `target` to be `name`'s friend, or accepts their request.
+    pub async fn add_friend(&self, name: &str, target: &str) -> FriendChange {
+        let (name, target) = (name.to_string(), target.to_string());
+        self.call(|reply| Request::AddFriend { name, target, reply }).await.unwrap_or(FriendChange::NotFound)
+    }
+
+    /// Unfriends, or withdraws or declines a request, whichever applies.
+    pub async fn remove_friend(&self, name: &str, target: &str) -> FriendChange {
+        let (name, target) = (name.to_string(), target.to_string());
+        self.call(|reply| Request::RemoveFriend { name, target, reply }).await.unwrap_or(FriendChange::NotFound)
+    }
+
+    /// `name`'s friends and requests either way, with everyone's presence.
+    pub async fn friends(&self, name: &str) -> Vec<(String, FriendStatus, Presence)> {
+        let name = name.to_string();
+        self.call(|reply| Request::Friends { name, reply }).await.unwrap_or_default()
+    }
+
+    pub async fn whois(&self, name: &str) -> Whois {
+        let name = name.to_string();
+        self.call(|reply| Request::Whois { name, reply }).await.unwrap_or_default()
+    }
+
+    /// Signals the connection to shut down and forgets it.
+    pub async fn disconnect(&self, id: u64) {
+        self.call(|reply| Request::Disconnect { id, reply }).await;
+    }
+
+    /// Marks the connection as logged in to a registered nickname, so DMs
+    /// held for it in do-not-disturb outlive it.
+    pub async fn set_registered(&self, id: u64) {
+        self.call(|reply| Request::SetRegistered { id, reply }).await;
+    }
+
+    pub async fn id_of(&self, name: &str) -> Option<u64> {
+        let name = name.to_string();
+        self.call(|reply| Request::IdOf { name, reply }).await.flatten()
+    }
+
+    pub async fn name_of(&self, id: u64) -> Option<String> {
+        self.call(|reply| Request::NameOf { id, reply }).await.flatten()
+    }
+
+    /// Doesn't go through the actor, so it never waits behind other requests.
+    pub fn sender(&self, id: u64) -> Option<ClientTx> {
+        self.senders.get(id)
+    }
+
+    /// Stamps, records and queues a DM in one step, so the recipient sees its
+    /// sequence numbers in order. A DM the slow-consumer policy drops stays in
+    /// history for RESEND, as does one held for do-not-disturb.
+    pub async fn deliver_dm(&self, from: &str, from_id: u64, to: u64, body: &str) -> Result<Delivery> {
+        let (from, body) = (from.to_string(), body.to_string());
+        self.call(|reply| Request::DeliverDm { from, from_id, to, body, reply })
+            .await
+            .unwrap_or_else(|| Err(anyhow!("registry is gone")))
+    }
+
+    /// Every message sent or received by `name` with `since <= ts <= until`.
+    pub async fn history_for(&self, name: &str, since: u64, until: u64) -> Vec<Entry> {
+        let name = name.to_string();
+        self.call(|reply| Request::ForUser { name, since, until, reply }).await.unwrap_or_default()
+    }
+
+    /// DMs delivered to `name` whose sequence numbers fall in `range`.
+    pub async fn received(&self, name: &str, range: SeqRange) -> Vec<Entry> {
+        let name = name.to_string();
+        self.call(|reply| Request::Received { name, range, reply }).await.unwrap_or_default()
+    }
+
+    /// Subscribes `name`, who has `role`, to `channel`, creating it if
+    /// needed and the channel policy lets them.
+    pub async fn join(&self, channel: &str, name: &str, role: Role, key: Option<&str>) -> Option<Result<Joined, JoinDenied>> {
+        let (channel, name, key) = (channel.to_string(), name.to_string(), key.map(str::to_string));
+        self.call(|reply| Request::Join { channel, name, role, key, reply }).await
+    }
+
+    /// `channel`'s topic, after setting it to `topic` if given. Only the
+    /// channel's operators, moderators and admin may set it; members are told
+    /// who did.
+    pub async fn topic(&self, channel: &str, name: &str, role: Role, topic: Option<String>) -> Result<Option<String>, &'static str> {
+        let (channel, name) = (channel.to_string(), name.to_string());
+        self.call(|reply| Request::Topic { channel, name, role, topic, reply }).await.unwrap_or(Err("registry is gone"))
+    }
+
+    /// Applies a reloaded `[channels]` config: persistent channels that are
+    /// missing are created, existing ones take their topic, key and ops from
+    /// the config, and ones no longer listed are cleaned up like any other
+    /// once empty.
+    pub async fn reload_channels(&self, config: ChannelsConfig) {
+        self.call(|reply| Request::ReloadChannels { config, reply }).await;
+    }
+
+    /// Up to `limit` of `channel`'s newest kept messages, or of those with
+    /// IDs below `before`; `None` if there's no such channel.
+    pub async fn channel_history(&self, channel: &str, before: Option<u64>, limit: usize) -> Option<Page> {
+        let channel = channel.to_string();
+        self.call(|reply| Request::ChannelHistory { channel, before, limit, reply }).await.flatten()
+    }
+
+    /// `channel`'s kept messages with `since <= ts <= until`, for `EXPORT`;
+    /// `None` if there's no such channel.
+    pub async fn channel_entries(&self, channel: &str, since: u64, until: u64) -> Option<Vec<Entry>> {
+        let channel = channel.to_string();
+        self.call(|reply| Request::ChannelEntries { channel, since, until, reply }).await.flatten()
+    }
+
+    /// `channel`'s retention, after changing it to `retention` if given.
+    /// Already kept messages the new setting doesn't cover are dropped.
+    pub async fn set_retention(&self, channel: &str, retention: Option<Retention>) -> Option<Retention> {
+        let channel = channel.to_string();
+        self.call(|reply| Request::SetRetention { channel, retention, reply }).await.flatten()
+    }
+
+    /// Up to `limit` of the newest messages matching `args` among `name`'s
+    /// DMs and the history of `channels`, oldest first. An `args.channel`
+    /// leaves the DMs out.
+    pub async fn search(&self, name: &str, channels: Vec<String>, args: SearchArgs, limit: usize) -> Vec<Arc<Event>> {
+        let name = name.to_string();
+        self.call(|reply| Request::Search { name, channels, args, limit, reply }).await.unwrap_or_default()
+    }
+
+    /// How much of each DM thread to keep for [`Registry::thread`].
+    pub async fn set_dm_retention(&self, retention: Retention) {
+        self.call(|reply| Request::SetDmRetention { retention, reply }).await;
+    }
+
+    /// A page of the kept DMs between `name` and `with`, in either
+    /// direction, as for `channel_history`; `None` if DM threads aren't kept.
+    pub async fn thread(&self, name: &str, with: &str, before: Option<u64>, limit: usize) -> Option<Page> {
+        let (name, with) = (name.to_string(), with.to_string());
+        self.call(|reply| Request::Thread { name, with, before, limit, reply }).await.flatten()
+    }
+
+    /// Drops `channel`'s kept messages, returning how many were removed.
+    pub async fn purge_channel(&self, channel: &str) -> Option<usize> {
+        let channel = channel.to_string();
+        self.call(|reply| Request::PurgeChannel { channel, reply }).await.flatten()
+    }
+
+    /// Stamps a message from `from`, who has `role`, and broadcasts it to
+    /// `channel`.
+    pub async fn say(&self, channel: &str, from: &str, role: Role, from_id: u64, body: &str) -> Result<Said, SayDenied> {
+        let (channel, from, body) = (channel.to_string(), from.to_string(), body.to_string());
+        self.call(|reply| Request::Say { channel, from, role, from_id, body, reply }).await.unwrap_or(Ok(Said::default()))
+    }
+
+    /// Sends `event` to everyone in `channel`, if it exists.
+    pub async fn broadcast(&self, channel: &str, event: Event) {
+        let channel = channel.to_string();
+        self.call(|reply| Request::Broadcast { channel, event, reply }).await;
+    }
+
+    /// Drops `name`'s history, profile, lists, friendships, ephemeral
+    /// threads and held DMs, and takes them off channels' operators,
+    /// returning how many messages were removed.
+    pub async fn purge(&self, name: &str) -> usize {
+        let name = name.to_string();
+        self.call(|reply| Request::Purge { name, reply }).await.unwrap_or_default()
+    }
+
+    /// What the registry holds about `name` besides messages, for
+    /// `EXPORTME`: the snapshot's records that are theirs.
+    pub async fn user_data(&self, name: &str) -> Snapshot {
+        let name = name.to_string();
+        self.call(|reply| Request::UserData { name, reply }).await.unwrap_or_default()
+    }
+
+    /// The DMs held for `name` since they disconnected in do-not-disturb, or
+    /// from before a restart, oldest first; they're theirs now.
+    pub async fn take_held(&self, name: &str) -> Vec<Event> {
+        let name = name.to_string();
+        self.call(|reply| Request::TakeHeld { name, reply }).await.unwrap_or_default()
+    }
+
+    /// Channels and what belongs to each nickname, for `snapshot`; push URLs
+    /// and the time are left for the caller.
+    pub async fn snapshot(&self) -> Snapshot {
+        self.call(|reply| Request::Snapshot { reply }).await.unwrap_or_default()
+    }
+
+    /// Puts back what `snapshot` took, at startup. Persistent channels keep
+    /// the config's key and operators.
+    pub async fn restore(&self, snapshot: Snapshot) {
+        self.call(|reply| Request::Restore { snapshot, reply }).await;
+    }
+}
+
+impl State {
+    fn handle(&mut self, req: Request) {
+        // A dropped reply receiver just means the caller went away.
+        match req {
+            Request::Register { id, name, tx, shutdown, reply } => {
+                let _ = reply.send(self.register(id, name, tx, shutdown));
+            }
+            Request::Disconnect { id, reply } => {
+                self.disconnect(id);
+                let _ = reply.send(());
+            }
+            Request::SetRegistered { id, reply } => {
+                if self.name_by_id.contains_key(&id) {
+                    self.registered.insert(id);
+                }
+                let _ = reply.send(());
+            }
+            Request::IdOf { name, reply } => {
+                let _ = reply.send(self.id_by_name.get(&name).copied());
+            }
+            Request::NameOf { id, reply } => {
+                let _ = reply.send(self.name_by_id.get(&id).cloned());
+            }
+            Request::DeliverDm { from, from_id, to, body, reply } => {
+                let _ = reply.send(self.deliver_dm(&from, from_id, to, &body));
+            }
+            Request::ForUser { name, since, until, reply } => {
+                let _ = reply.send(self.history.for_user(&name, since, until));
+            }
+            Request::Received { name, range, reply } => {
+                let _ = reply.send(self.history.received(&name, range));
+            }
+            Request::Purge { name, reply } => {
+                let _ = reply.send(self.purge(&name));
+            }
+            Request::UserData { name, reply } => {
+                let _ = reply.send(self.user_data(&name));
+            }
+            Request::TakeHeld { name, reply } => {
+                let _ = reply.send(self.held.remove(&name).map(Vec::from).unwrap_or_default());
+            }
+            Request::Join { channel, name, role, key, reply } => {
+                let _ = reply.send(self.join(channel, name, role, key));
+            }
+            Request::Broadcast { channel, event, reply } => {
+                if let Some(existing) = self.channels.get(&channel) {
+                    let _ = existing.tx.send(Arc::new(event));
+                }
+                let _ = reply.send(());
+            }
+            Request::Topic { channel, name, role, topic, reply } => {
+                let _ = reply.send(self.topic(&channel, &name, role, topic));
+            }
+            Request::ReloadChannels { config, reply } => {
+                self.reload_channels(config);
+                let _ = reply.send(());
+            }
+            Request::ChannelHistory { channel, before, limit, reply } => {
+                let _ = reply.send(self.channels.get_mut(&channel).map(|c| {
+                    c.log.prune(clock::now_unix());
+                    c.log.page(before, limit)
+                }));
+            }
+            Request::ChannelEntries { channel, since, until, reply } => {
+                let _ = reply.send(self.channels.get_mut(&channel).map(|c| {
+                    c.log.prune(clock::now_unix());
+                    c.log.between(since, until)
+                }));
+            }
+            Request::SetRetention { channel, retention, reply } => {
+                let _ = reply.send(self.channels.get_mut(&channel).map(|c| {
+                    if let Some(retention) = retention {
+                        c.log.set_retention(retention, clock::now_unix());
+                    }
+                    c.log.retention()
+                }));
+            }
+            Request::PurgeChannel { channel, reply } => {
+                let _ = reply.send(self.channels.get_mut(&channel).map(|c| c.log.purge()));
+            }
+            Request::Search { name, channels, args, limit, reply } => {
+                let _ = reply.send(self.search(&name, &channels, &args, limit));
+            }
+            Request::SetDmRetention { retention, reply } => {
+                self.history.set_thread_retention(retention, clock::now_unix());
+                let _ = reply.send(());
+            }
+            Request::Thread { name, with, before, limit, reply } => {
+                let _ = reply.send(self.history.thread(&name, &with, before, limit, clock::now_unix()));
+            }
+            Request::Say { channel, from, role, from_id, body, reply } => {
+                let _ = reply.send(self.say(&channel, &from, role, from_id, &body));
+            }
+            Request::SetAway { id, message, reply } => {
+                match message {
+                    Some(message) => self.away.insert(id, message),
+                    None => self.away.remove(&id),
+                };
+                if let Some(name) = self.name_by_id.get(&id).cloned() {
+                    self.tell_watchers(&name);
+                }
+                let _ = reply.send(());
+            }
+            Request::SetDnd { id, dnd, reply } => {
+                if dnd.on {
+                    self.dnd.entry(id).or_default().0 = dnd;
+                } else if let Some((_, held)) = self.dnd.remove(&id) {
+                    if let Some(tx) = self.senders.get(id) {
+                        for event in held {
+                            let _ = tx.push(event);
+                        }
+                    }
+                }
+                let _ = reply.send(());
+            }
+            Request::SetEphemeral { name, with, on, reply } => {
+                let key = history::pair(&name, &with);
+                let changed = if on { self.ephemeral.insert(key) } else { self.ephemeral.remove(&key) };
+                if changed {
+                    self.tell(&with, Event::Ephemeral { name, on });
+                }
+                let _ = reply.send(changed);
+            }
+            Request::Ephemeral { name, reply } => {
+                let with = self.ephemeral.iter().filter_map(|(a, b)| if *a == name { Some(b) } else if *b == name { Some(a) } else { None });
+                let _ = reply.send(with.cloned().collect());
+            }
+            Request::OpenPoll { channel, by, question, options, duration, reply } => {
+                let now = Instant::now();
+                let opened = self.polls.open(now, &channel, &by, question, options, duration).map(|poll| (poll.id, poll.opened(now)));
+                let _ = reply.send(opened.map(|(id, line)| {
+                    if let Some(existing) = self.channels.get(&channel) {
+                        let _ = existing.tx.send(Arc::new(Event::notice(line)));
+                    }
+                    id
+                }));
+            }
+            Request::Vote { id, name, choice, channels, reply } => {
+                let _ = reply.send(self.polls.vote(id, &name, choice, &channels).map(str::to_string));
+            }
+            Request::Snapshot { reply } => {
+                let _ = reply.send(self.snapshot());
+            }
+            Request::Restore { snapshot, reply } => {
+                self.restore(snapshot, Instant::now());
+                let _ = reply.send(());
+            }
+            Request::Read { name, id, reply } => {
+                if let Some(from) = self.history.get(id).filter(|e| e.to == name).map(|e| e.from.clone()) {
+                    self.tell(&from, Event::Receipt { id, name, state: Receipt::Read });
+                }
+                let _ = reply.send(());
+            }
+            Request::AwayMessage { id, reply } => {
+                let _ = reply.send(self.away.get(&id).cloned());
+            }
+            Request::SetProfile { name, field, value, reply } => {
+                let profile = self.profiles.entry(name.clone()).or_default();
+                let _ = reply.send(profile.set(field, value));
+                if profile.is_empty() {
+                    self.profiles.remove(&name);
+                }
+            }
+            Request::SetAvatar { name, avatar, reply } => {
+                let profile = self.profiles.entry(name.clone()).or_default();
+                profile.set_avatar(avatar);
+                if profile.is_empty() {
+                    self.profiles.remove(&name);
+                }
+                let _ = reply.send(());
+            }
+            Request::Watch { name, target, reply } => {
+                let presence = self.presence(&target);
+                let watched = self.watching.entry(name).or_default();
+                if watched.len() >= MAX_WATCHED && !watched.contains(&target) {
+                    let _ = reply.send(Err(WatchFull));
+                } else {
+                    watched.insert(target);
+                    let _ = reply.send(Ok(presence));
+                }
+            }
+            Request::Unwatch { name, target, reply } => {
+                let Some(watched) = self.watching.get_mut(&name) else {
+                    let _ = reply.send(false);
+                    return;
+                };
+                let _ = reply.send(watched.remove(&target));
+                if watched.is_empty() {
+                    self.watching.remove(&name);
+                }
+            }
+            Request::Watched { name, reply } => {
+                let watched = self.watching.get(&name).into_iter().flatten();
+                let _ = reply.send(watched.map(|target| (target.clone(), self.presence(target))).collect());
+            }
+            Request::Block { name, target, reply } => {
+                let blocked = self.blocking.entry(name).or_default();
+                if blocked.len() >= MAX_BLOCKED && !blocked.contains(&target) {
+                    let _ = reply.send(Err(BlockFull));
+                } else {
+                    blocked.insert(target);
+                    let _ = reply.send(Ok(()));
+                }
+            }
+            Request::Unblock { name, target, reply } => {
+                let Some(blocked) = self.blocking.get_mut(&name) else {
+                    let _ = reply.send(false);
+                    return;
+                };
+                let _ = reply.send(blocked.remove(&target));
+                if blocked.is_empty() {
+                    self.blocking.remove(&name);
+                }
+            }
+            Request::Blocked { name, reply } => {
+                let _ = reply.send(self.blocking.get(&name).cloned().unwrap_or_default());
+            }
+            Request::AddFriend { name, target, reply } => {
+                let _ = reply.send(self.add_friend(name, target));
+            }
+            Request::RemoveFriend { name, target, reply } => {
+                let _ = reply.send(self.remove_friend(name, target));
+            }
+            Request::Friends { name, reply } => {
+                let friends = self.friends.get(&name).into_iter().flatten().map(|f| (f.clone(), FriendStatus::Friends));
+                let requests = self.friend_requests.iter().filter_map(|(from, to)| match (from == &name, to == &name) {
+                    (true, _) => Some((to.clone(), FriendStatus::Sent)),
+                    (_, true) => Some((from.clone(), FriendStatus::Received)),
+                    _ => None,
+                });
+                let list = friends.chain(requests).map(|(other, status)| {
+                    let presence = self.presence(&other);
+                    (other, status, presence)
+                });
+                let _ = reply.send(list.collect());
+            }
+            Request::Whois { name, reply } => {
+                let id = self.id_by_name.get(&name).copied();
+                let away = id.and_then(|id| self.away.get(&id).cloned());
+                let _ = reply.send(Whois { id, away, profile: self.profiles.get(&name).cloned().unwrap_or_default() });
+            }
+        }
+    }
+
+    fn register(&mut self, id: u64, name: String, tx: ClientTx, shutdown: ShutdownTx) -> Result<(), NameTaken> {
+        self.claim(id, name.clone())?;
+        self.senders.insert(id, tx);
+        self.shutdown.insert(id, shutdown);
+        self.tell_watchers(&name);
+        Ok(())
+    }
+
+    fn presence(&self, name: &str) -> Presence {
+        match self.id_by_name.get(name) {
+            None => Presence::Offline,
+            Some(id) => self.away.get(id).map_or(Presence::Online, |away| Presence::Away(away.clone())),
+        }
+    }
+
+    /// Queues `name`'s current presence for everyone online watching them,
+    /// and their friends.
+    fn tell_watchers(&self, name: &str) {
+        let watchers = self.watching.iter().filter(|(_, watched)| watched.contains(name)).map(|(watcher, _)| watcher);
+        let watchers: BTreeSet<_> = watchers.chain(self.friends.get(name).into_iter().flatten()).collect();
+        let event = Event::Presence { name: name.to_string(), presence: self.presence(name) };
+        for watcher in watchers {
+            self.tell(watcher, event.clone());
+        }
+    }
+
+    /// Queues `event` for `name` if they're online.
+    fn tell(&self, name: &str, event: Event) {
+        if let Some(tx) = self.id_by_name.get(name).and_then(|id| self.senders.get(*id)) {
+            let _ = tx.push(event);
+        }
+    }
+
+    fn add_friend(&mut self, name: String, target: String) -> FriendChange {
+        if self.friends.get(&name).is_some_and(|f| f.contains(&target)) {
+            return FriendChange::AlreadyFriends;
+        }
+        if self.friend_requests.remove(&(target.clone(), name.clone())) {
+            self.friends.entry(name.clone()).or_default().insert(target.clone());
+            self.friends.entry(target.clone()).or_default().insert(name.clone());
+            self.tell(&target, Event::Friend { name: name.clone(), status: FriendStatus::Friends });
+            self.tell(&target, Event::Presence { name: name.clone(), presence: self.presence(&name) });
+            return FriendChange::Accepted(self.presence(&target));
+        }
+        let key = (name, target);
+        if !self.friend_requests.contains(&key) {
+            let (name, target) = &key;
+            let requests = self.friend_requests.iter().filter(|(from, _)| from == name).count();
+            if self.friends.get(name).map_or(0, BTreeSet::len) + requests >= MAX_FRIENDS {
+                return FriendChange::Full;
+            }
+            self.tell(target, Event::Friend { name: name.clone(), status: FriendStatus::Received });
+            self.friend_requests.insert(key);
+        }
+        FriendChange::Asked
+    }
+
+    fn remove_friend(&mut self, name: String, target: String) -> FriendChange {
+        let was_friend = self.friends.get_mut(&name).is_some_and(|f| f.remove(&target));
+        if was_friend {
+            if let Some(theirs) = self.friends.get_mut(&target) {
+                theirs.remove(&name);
+            }
+            self.friends.retain(|_, f| !f.is_empty());
+        }
+        let had_request = self.friend_requests.remove(&(name.clone(), target.clone())) | self.friend_requests.remove(&(target.clone(), name.clone()));
+        if !was_friend && !had_request {
+            return FriendChange::NotFound;
+        }
+        self.tell(&target, Event::Friend { name, status: FriendStatus::Removed });
+        FriendChange::Removed
+    }
+
+    fn claim(&mut self, id: u64, name: String) -> Result<(), NameTaken> {
+        match self.id_by_name.entry(name) {
+            hash_map::Entry::Occupied(_) => Err(NameTaken),
+            hash_map::Entry::Vacant(slot) => {
+                self.name_by_id.insert(id, slot.key().clone());
+                slot.insert(id);
+                Ok(())
+            }
+        }
+    }
+
+    fn disconnect(&mut self, id: u64) {
+        if let Some(shutdown) = self.shutdown.remove(&id) {
+            let _ = shutdown.send(());
+        }
+
+        if let Some(tx) = self.senders.remove(id) {
+            tx.close();
+        }
+        self.away.remove(&id);
+        let held = self.dnd.remove(&id).map(|(_, held)| held).unwrap_or_default();
+        let registered = self.registered.remove(&id);
+
+        if let Some(name) = self.name_by_id.remove(&id) {
+            info!("DISCONNECT", "{name} ({id}) was removed.");
+            self.id_by_name.remove(&name);
+            self.tell_watchers(&name);
+            // Still in history for the sender; the next to take an
+            // unregistered name mustn't be handed them.
+            if registered {
+                self.keep_held(&name, held);
+            }
+        }
+    }
+
+    /// Keeps `dms` for `name` until they next log in, behind any already
+    /// kept, and at most `MAX_HELD` of them.
+    fn keep_held(&mut self, name: &str, dms: impl IntoIterator<Item = Event>) {
+        let held = self.held.entry(name.to_string()).or_default();
+        held.extend(dms);
+        held.drain(..held.len().saturating_sub(MAX_HELD));
+        if held.is_empty() {
+            self.held.remove(name);
+        }
+    }
+
+    fn join(&mut self, channel: String, name: String, role: Role, key: Option<String>) -> Result<Joined, JoinDenied> {
+        self.sweep(Instant::now());
+        if let Some(existing) = self.channels.get(&channel) {
+            if existing.key.is_some() && existing.key != key {
+                return Err(JoinDenied::BadKey);
+            }
+            return Ok(Joined { sub: existing.tx.subscribe(), topic: existing.topic.clone() });
+        }
+
+        let config = &self.channel_config;
+        if role < config.creators {
+            return Err(JoinDenied::NotAllowed);
+        }
+        if let Some(max) = config.max_per_user {
+            if self.channels.values().filter(|c| c.creator.as_deref() == Some(name.as_str())).count() >= max {
+                return Err(JoinDenied::TooMany(max));
+            }
+        }
+        info!("CHANNEL", "{name} created {channel}");
+        let created = Channel::new(Some(name), config.retention);
+        let sub = created.tx.subscribe();
+        self.channels.insert(channel, created);
+        Ok(Joined { sub, topic: None })
+    }
+
+    fn topic(&mut self, channel: &str, name: &str, role: Role, topic: Option<String>) -> Result<Option<String>, &'static str> {
+        let existing = self.channels.get_mut(channel).ok_or("no such channel")?;
+        let Some(topic) = topic else {
+            return Ok(existing.topic.clone());
+        };
+        if role < Role::Moderator && !existing.ops.contains(name) {
+            return Err("only channel operators can change the topic");
+        }
+        let _ = existing.tx.send(Arc::new(Event::notice(format!("{name} set the topic of {channel}: {topic}"))));
+        existing.topic = Some(topic);
+        Ok(existing.topic.clone())
+    }
+
+    fn search(&self, name: &str, channels: &[String], args: &SearchArgs, limit: usize) -> Vec<Arc<Event>> {
+        let query = args.query.to_lowercase();
+        let from = args.from.as_deref();
+        let dms = if args.channel.is_none() { self.history.for_user(name, 0, u64::MAX) } else { Vec::new() };
+        let mut found: Vec<Arc<Event>> = dms.iter().map(|e| Arc::new(e.to_event())).filter(|e| history::matches(e, &query, from)).collect();
+        for channel in channels.iter().filter(|c| args.channel.as_ref().is_none_or(|only| only == *c)) {
+            if let Some(c) = self.channels.get(channel) {
+                found.extend(c.log.iter().filter(|e| history::matches(e, &query, from)).cloned());
+            }
+        }
+        found.sort_by_key(|e| history::message_id(e));
+        found.split_off(found.len().saturating_sub(limit))
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let mut channels: Vec<_> = self
+            .channels
+            .iter()
+            .map(|(name, c)| ChannelState {
+                name: name.clone(),
+                creator: c.creator.clone(),
+                topic: c.topic.clone(),
+                key: c.key.clone(),
+                ops: c.ops.iter().cloned().collect(),
+                retention: c.log.retention(),
+            })
+            .collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut profiles: Vec<_> = self.profiles.iter().map(|(name, p)| (name.clone(), p.clone())).collect();
+        profiles.sort_by(|a, b| a.0.cmp(&b.0));
+        let pairs = |sets: &HashMap<String, BTreeSet<String>>| {
+            let mut pairs: Vec<_> = sets.iter().flat_map(|(name, set)| set.iter().map(|other| (name.clone(), other.clone()))).collect();
+            pairs.sort();
+            pairs
+        };
+        Snapshot {
+            channels,
+            profiles,
+            watching: pairs(&self.watching),
+            blocking: pairs(&self.blocking),
+            friends: pairs(&self.friends).into_iter().filter(|(a, b)| a < b).collect(),
+            friend_requests: self.friend_requests.iter().cloned().collect(),
+            ephemeral: self.ephemeral.iter().cloned().collect(),
+            held: self.held_dms(),
+            ..Snapshot::default()
+        }
+    }
+
+    /// Every DM held for do-not-disturb for a registered nickname, connected
+    /// or not, by recipient.
+    fn held_dms(&self) -> Vec<(String, Event)> {
+        let connected = self.dnd.iter().filter(|(id, _)| self.registered.contains(id)).filter_map(|(id, (_, held))| Some((self.name_by_id.get(id)?, held)));
+        // Kept ones first, as older than any held since; the sort is stable.
+        let mut by_name: Vec<_> = self.held.iter().chain(connected).collect();
+        by_name.sort_by(|a, b| a.0.cmp(b.0));
+        by_name.into_iter().flat_map(|(name, held)| held.iter().map(|dm| (name.clone(), dm.clone()))).collect()
+    }
+
+    fn user_data(&self, name: &str) -> Snapshot {
+        let mut data = self.snapshot();
+        let theirs = |(a, b): &(String, String)| a == name || b == name;
+        data.channels.retain(|c| c.creator.as_deref() == Some(name) || c.ops.iter().any(|op| op == name));
+        data.profiles.retain(|(n, _)| n == name);
+        data.watching.retain(|(n, _)| n == name);
+        data.blocking.retain(|(n, _)| n == name);
+        data.friends.retain(theirs);
+        data.friend_requests.retain(theirs);
+        data.ephemeral.retain(theirs);
+        data.held.retain(|(to, dm)| to == name || matches!(dm, Event::Dm { from, .. } if from == name));
+        data
+    }
+
+    fn purge(&mut self, name: &str) -> usize {
+        let removed = self.history.purge_user(name);
+        self.profiles.remove(name);
+        self.watching.remove(name);
+        self.blocking.remove(name);
+        for friend in self.friends.remove(name).unwrap_or_default() {
+            if let Some(theirs) = self.friends.get_mut(&friend) {
+                theirs.remove(name);
+            }
+        }
+        self.friend_requests.retain(|(from, to)| from != name && to != name);
+        self.ephemeral.retain(|(a, b)| a != name && b != name);
+        self.held.remove(name);
+        for held in self.dnd.values_mut().map(|(_, held)| held).chain(self.held.values_mut()) {
+            held.retain(|e| !matches!(e, Event::Dm { from, .. } if from == name));
+        }
+        self.held.retain(|_, held| !held.is_empty());
+        for channel in self.channels.values_mut() {
+            channel.ops.remove(name);
+            if channel.creator.as_deref() == Some(name) {
+                channel.creator = None;
+            }
+        }
+        removed
+    }
+
+    fn restore(&mut self, snapshot: Snapshot, now: Instant) {
+        let unix_now = clock::now_unix();
+        for state in snapshot.channels {
+            match self.channels.get_mut(&state.name) {
+                Some(existing) if existing.persistent => {
+                    existing.topic = state.topic;
+                    existing.log.set_retention(state.retention, unix_now);
+                }
+                Some(_) => {}
+                None => {
+                    let mut channel = Channel::new(state.creator, state.retention);
+                    channel.topic = state.topic;
+                    channel.key = state.key;
+                    channel.ops = state.ops.into_iter().collect();
+                    // Counted as empty once its members have had time to
+                    // come back.
+                    channel.empty_since = Some(now + snapshot::RESTORE_GRACE);
+                    self.channels.insert(state.name, channel);
+                }
+            }
+        }
+        self.profiles.extend(snapshot.profiles);
+        for (name, target) in snapshot.watching {
+            self.watching.entry(name).or_default().insert(target);
+        }
+        for (name, target) in snapshot.blocking {
+            self.blocking.entry(name).or_default().insert(target);
+        }
+        for (a, b) in snapshot.friends {
+            self.friends.entry(a.clone()).or_default().insert(b.clone());
+            self.friends.entry(b).or_default().insert(a);
+        }
+        self.friend_requests.extend(snapshot.friend_requests);
+        self.ephemeral.extend(snapshot.ephemeral);
+        for (name, dm) in snapshot.held {
+            self.keep_held(&name, [dm]);
+        }
+    }
+
+    fn reload_channels(&mut self, config: ChannelsConfig) {
+        for channel in self.channels.values_mut() {
+            channel.persistent = false;
+        }
+        for def in &config.persistent {
+            let channel = self.channels.entry(def.name.clone()).or_insert_with(|| Channel::new(None, config.retention));
+            channel.configure(def, config.retention);
+        }
+        self.channel_config = config;
+    }
+
+    /// Removes channels that have been empty for the configured timeout,
+    /// other than persistent ones. Emptiness is only noticed here, so a
+    /// channel can outlive the timeout by up to `SWEEP_EVERY`. Kept messages
+    /// past their retention go at the same time.
+    fn sweep(&mut self, now: Instant) {
+        let timeout = self.channel_config.empty_timeout;
+        let unix_now = clock::now_unix();
+        self.history.prune_threads(unix_now);
+        for poll in self.polls.take_closed(now) {
+            info!("POLL", "#{} in {} closed with {:?}", poll.id, poll.channel, poll.tally());
+            if let Some(channel) = self.channels.get(&poll.channel) {
+                let _ = channel.tx.send(Arc::new(Event::notice(poll.results())));
+            }
+        }
+        let polls = &mut self.polls;
+        self.channels.retain(|name, channel| {
+            channel.log.prune(unix_now);
+            if channel.persistent || channel.tx.receiver_count() > 0 {
+                channel.empty_since = None;
+                return true;
+            }
+            let since = *channel.empty_since.get_or_insert(now);
+            // `since` is ahead of now for a channel restored from a snapshot.
+            let expired = now >= since + timeout;
+            if expired {
+                info!("CHANNEL", "{name} removed after being empty for {}s", now.duration_since(since).as_secs());
+                polls.drop_channel(name);
+            }
+            !expired
+        });
+    }
+
+    fn say(&mut self, channel: &str, from: &str, role: Role, from_id: u64, body: &str) -> Result<Said, SayDenied> {
+        let Some(Channel { tx, log, ops, announce, .. }) = self.channels.get_mut(channel) else {
+            return Ok(Said::default());
+        };
+        if *announce && role < Role::Moderator && !ops.contains(from) {
+            return Err(SayDenied);
+        }
+        self.next_msg_id += 1;
+        let ts = clock::now_unix();
+        let event = Arc::new(Event::ChannelMsg {
+            channel: channel.to_string(),
+            id: self.next_msg_id,
+            ts: clock::rfc3339(ts),
+            from: from.to_string(),
+            from_id,
+            body: body.to_string(),
+        });
+        log.record(ts, event.clone());
+        Ok(Said { id: self.next_msg_id, reached: tx.send(event).unwrap_or(0) })
+    }
+
+    fn deliver_dm(&mut self, from: &str, from_id: u64, to: u64, body: &str) -> Result<Delivery> {
+        let tx = self.senders.get(to).filter(|tx| !tx.is_closed()).ok_or_else(|| anyhow!("no such id"))?;
+        let to_name = self.name_by_id.get(&to).cloned().unwrap_or_default();
+        if self.blocking.get(&to_name).is_some_and(|b| b.contains(from)) {
+            return Err(Blocked.into());
+        }
+
+        self.next_msg_id += 1;
+        let (id, ts) = (self.next_msg_id, clock::now_unix());
+        // Ephemeral DMs aren't kept, so they have no place in the recipient's
+        // sequence for RESEND.
+        let ephemeral = self.ephemeral.contains(&history::pair(from, &to_name));
+        let seq = if ephemeral { 0 } else { self.history.record(id, ts, from, from_id, &to_name, body) };
+        let event = Event::Dm { id, seq, ts: clock::rfc3339(ts), from: from.to_string(), from_id, body: body.to_string() };
+        if let Some((dnd, held)) = self.dnd.get_mut(&to) {
+            let friend = self.friends.get(&to_name).is_some_and(|f| f.contains(from));
+            if !(dnd.friends && friend) {
+                if ephemeral {
+                    return Ok(Delivery::Dropped);
+                }
+                if held.len() >= MAX_HELD {
+                    held.pop_front();
+                }
+                held.push_back(event);
+                return Ok(Delivery::Held);
+            }
+        }
+        // Ahead of the recipient's `Written`, which their writer sends.
+        if let Some(sender) = self.senders.get(from_id) {
+            let _ = sender.push(Event::Receipt { id, name: to_name, state: Receipt::Queued });
+        }
+        tx.push(event).map_err(|_| anyhow!("failed to deliver message to {to}"))?;
+        Ok(Delivery::Queued { id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, queue};
+
+    fn channels() -> (ClientTx, ShutdownTx) {
+        (queue::channel(0, &Config::default().queue).0, oneshot::channel().0)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn simultaneous_logins_claim_a_name_once() {
+        let reg = Registry::spawn();
+        let logins: Vec<_> = (1..=32)
+            .map(|id| {
+                let reg = reg.clone();
+                tokio::spawn(async move {
+                    let (tx, shutdown) = channels();
+                    reg.register(id, "bob", tx, shutdown).await
+                })
+            })
+            .collect();
+
+        let mut won = 0;
+        for login in logins {
+            if login.await.unwrap().is_ok() {
+                won += 1;
+            }
+        }
+        assert_eq!(won, 1);
+        assert!(reg.id_of("bob").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn name_is_free_again_after_disconnect() {
+        let reg = Registry::spawn();
+        let (tx, shutdown) = channels();
+        reg.register(1, "bob", tx, shutdown).await.unwrap();
+        let (tx, shutdown) = channels();
+        assert_eq!(reg.register(2, "bob", tx, shutdown).await, Err(NameTaken));
+
+        reg.disconnect(1).await;
+        let (tx, shutdown) = channels();
+        assert_eq!(reg.register(2, "bob", tx, shutdown).await, Ok(()));
+        assert_eq!(reg.id_of("bob").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn dnd_holds_dms_but_friends_if_allowed() {
+        let reg = Registry::spawn();
+        let (tx, mut alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        reg.add_friend("alice", "bob").await;
+        reg.add_friend("bob", "alice").await;
+        while alice.try_recv().is_some() {}
+
+        reg.set_dnd(1, Dnd { on: true, friends: true, mentions: false }).await;
+        assert_eq!(reg.deliver_dm("carol", 3, 1, "one").await.unwrap(), Delivery::Held);
+        assert!(matches!(reg.deliver_dm("bob", 2, 1, "two").await.unwrap(), Delivery::Queued { .. }));
+        assert_eq!(reg.deliver_dm("carol", 3, 1, "three").await.unwrap(), Delivery::Held);
+        reg.set_dnd(1, Dnd::default()).await;
+        let bodies: Vec<_> = std::iter::from_fn(|| alice.try_recv())
+            .map(|e| match &*e {
+                Event::Dm { body, .. } => body.clone(),
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(bodies, ["two", "one", "three"]);
+    }
+
+    #[tokio::test]
+    async fn held_dms_outlive_the_connection_and_a_restart() {
+        let reg = Registry::spawn();
+        let (tx, _alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        reg.set_registered(1).await;
+        reg.set_dnd(1, Dnd { on: true, friends: false, mentions: false }).await;
+        assert_eq!(reg.deliver_dm("carol", 3, 1, "one").await.unwrap(), Delivery::Held);
+        reg.disconnect(1).await;
+        let snapshot = reg.snapshot().await;
+        assert!(matches!(&snapshot.held[..], [(to, Event::Dm { body, .. })] if to == "alice" && body == "one"));
+
+        let reg = Registry::spawn();
+        reg.restore(snapshot).await;
+        assert!(reg.take_held("bob").await.is_empty());
+        let held = reg.take_held("alice").await;
+        assert!(matches!(&held[..], [Event::Dm { from, body, .. }] if from == "carol" && body == "one"));
+        assert!(reg.take_held("alice").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn held_dms_for_an_unregistered_nickname_go_with_it() {
+        let reg = Registry::spawn();
+        let (tx, _alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        reg.set_dnd(1, Dnd { on: true, friends: false, mentions: false }).await;
+        assert_eq!(reg.deliver_dm("carol", 3, 1, "secret").await.unwrap(), Delivery::Held);
+        assert!(reg.snapshot().await.held.is_empty());
+        reg.disconnect(1).await;
+
+        // Someone else takes the name.
+        let (tx, _mallory) = queue::channel(2, &Config::default().queue);
+        reg.register(2, "alice", tx, oneshot::channel().0).await.unwrap();
+        assert!(reg.take_held("alice").await.is_empty());
+        assert!(reg.snapshot().await.held.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_receipts_reach_the_sender() {
+        let reg = Registry::spawn();
+        let (tx, mut alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        let (tx, mut bob) = queue::channel(2, &Config::default().queue);
+        reg.register(2, "bob", tx, oneshot::channel().0).await.unwrap();
+        reg.deliver_dm("alice", 1, 2, "hi").await.unwrap();
+        let Event::Dm { id, .. } = *bob.try_recv().unwrap() else { panic!() };
+        assert_eq!(*alice.try_recv().unwrap(), Event::Receipt { id, name: "bob".into(), state: Receipt::Queued });
+
+        // Only the recipient can mark it read.
+        reg.mark_read("carol", id).await;
+        assert!(alice.try_recv().is_none());
+        reg.mark_read("bob", id).await;
+        assert_eq!(*alice.try_recv().unwrap(), Event::Receipt { id, name: "bob".into(), state: Receipt::Read });
+    }
+
+    #[tokio::test]
+    async fn ephemeral_threads_are_not_kept() {
+        let reg = Registry::spawn();
+        let (tx, mut alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        let (tx, mut bob) = queue::channel(2, &Config::default().queue);
+        reg.register(2, "bob", tx, oneshot::channel().0).await.unwrap();
+
+        assert!(reg.set_ephemeral("alice", "bob", true).await);
+        assert!(!reg.set_ephemeral("bob", "alice", true).await);
+        assert_eq!(*bob.try_recv().unwrap(), Event::Ephemeral { name: "alice".into(), on: true });
+        assert_eq!(reg.ephemeral("bob").await, ["alice"]);
+
+        assert!(matches!(reg.deliver_dm("alice", 1, 2, "gone soon").await.unwrap(), Delivery::Queued { .. }));
+        assert!(matches!(*bob.try_recv().unwrap(), Event::Dm { seq: 0, .. }));
+        assert!(reg.history_for("bob", 0, u64::MAX).await.is_empty());
+
+        // Nor held for later.
+        reg.set_dnd(2, Dnd { on: true, ..Dnd::default() }).await;
+        assert_eq!(reg.deliver_dm("alice", 1, 2, "later?").await.unwrap(), Delivery::Dropped);
+        reg.set_dnd(2, Dnd::default()).await;
+        assert!(bob.try_recv().is_none());
+
+        // Either side can turn it off again.
+        while alice.try_recv().is_some() {}
+        assert!(reg.set_ephemeral("bob", "alice", false).await);
+        assert_eq!(*alice.try_recv().unwrap(), Event::Ephemeral { name: "bob".into(), on: false });
+        reg.deliver_dm("alice", 1, 2, "kept").await.unwrap();
+        assert_eq!(reg.history_for("bob", 0, u64::MAX).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn away_until_back_or_gone() {
+        let reg = Registry::spawn();
+        let (tx, shutdown) = channels();
+        reg.register(1, "bob", tx, shutdown).await.unwrap();
+        reg.set_away(1, Some("lunch".into())).await;
+        assert_eq!(reg.away_message(1).await, Some("lunch".into()));
+        reg.set_away(1, None).await;
+        assert_eq!(reg.away_message(1).await, None);
+
+        reg.set_away(1, Some("lunch".into())).await;
+        reg.disconnect(1).await;
+        assert_eq!(reg.away_message(1).await, None);
+    }
+
+    #[tokio::test]
+    async fn profiles_outlive_the_connection() {
+        let reg = Registry::spawn();
+        let (tx, shutdown) = channels();
+        reg.register(1, "bob", tx, shutdown).await.unwrap();
+        reg.set_away(1, Some("lunch".into())).await;
+        reg.set_profile("bob", Field::Pronouns, Some("he/him".into())).await.unwrap();
+        assert!(reg.set_profile("bob", Field::Timezone, Some("not a zone".into())).await.is_err());
+        let whois = reg.whois("bob").await;
+        assert_eq!((whois.id, whois.away.as_deref()), (Some(1), Some("lunch")));
+        assert_eq!(whois.profile.get(Field::Pronouns), Some("he/him"));
+
+        reg.disconnect(1).await;
+        let whois = reg.whois("bob").await;
+        assert_eq!((whois.id, whois.profile.get(Field::Pronouns)), (None, Some("he/him")));
+        assert!(reg.whois("nobody").await.profile.is_empty());
+    }
+
+    #[tokio::test]
+    async fn watchers_hear_presence_changes() {
+        let reg = Registry::spawn();
+        let (tx, mut alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        assert_eq!(reg.watch("alice", "bob").await, Ok(Presence::Offline));
+
+        let (tx, shutdown) = channels();
+        reg.register(2, "bob", tx, shutdown).await.unwrap();
+        reg.set_away(2, Some("lunch".into())).await;
+        reg.disconnect(2).await;
+        let heard: Vec<_> = std::iter::from_fn(|| alice.try_recv())
+            .map(|e| match &*e {
+                Event::Presence { presence, .. } => presence.clone(),
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(heard, [Presence::Online, Presence::Away("lunch".into()), Presence::Offline]);
+
+        assert_eq!(reg.watched("alice").await, [("bob".to_string(), Presence::Offline)]);
+        assert!(reg.unwatch("alice", "bob").await);
+        assert!(!reg.unwatch("alice", "bob").await);
+        assert!(reg.watched("alice").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn blocked_senders_are_refused() {
+        let reg = Registry::spawn();
+        let (tx, mut alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        reg.block("alice", "mallory").await.unwrap();
+        reg.block("alice", "mallory").await.unwrap();
+        assert_eq!(reg.blocked("alice").await, BTreeSet::from(["mallory".to_string()]));
+
+        assert!(reg.deliver_dm("mallory", 2, 1, "hi").await.unwrap_err().is::<Blocked>());
+        assert!(alice.try_recv().is_none());
+        assert!(reg.history_for("mallory", 0, u64::MAX).await.is_empty());
+
+        assert!(reg.unblock("alice", "mallory").await);
+        assert!(!reg.unblock("alice", "mallory").await);
+        reg.deliver_dm("mallory", 2, 1, "hi").await.unwrap();
+        assert!(alice.try_recv().is_some());
+    }
+
+    #[tokio::test]
+    async fn friends_need_both_sides() {
+        let reg = Registry::spawn();
+        let (tx, mut alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        let (tx, mut bob) = queue::channel(1, &Config::default().queue);
+        reg.register(2, "bob", tx, oneshot::channel().0).await.unwrap();
+        let drain = |rx: &mut queue::ClientRx| std::iter::from_fn(|| rx.try_recv()).map(|e| (*e).clone()).collect::<Vec<_>>();
+
+        assert_eq!(reg.add_friend("alice", "bob").await, FriendChange::Asked);
+        assert_eq!(reg.add_friend("alice", "bob").await, FriendChange::Asked);
+        assert_eq!(drain(&mut bob), [Event::Friend { name: "alice".into(), status: FriendStatus::Received }]);
+        assert_eq!(reg.friends("bob").await, [("alice".to_string(), FriendStatus::Received, Presence::Online)]);
+
+        assert_eq!(reg.add_friend("bob", "alice").await, FriendChange::Accepted(Presence::Online));
+        assert_eq!(reg.add_friend("bob", "alice").await, FriendChange::AlreadyFriends);
+        assert_eq!(
+            drain(&mut alice),
+            [Event::Friend { name: "bob".into(), status: FriendStatus::Friends }, Event::Presence { name: "bob".into(), presence: Presence::Online }]
+        );
+        // Friends hear about each other without watching.
+        reg.disconnect(2).await;
+        assert_eq!(drain(&mut alice), [Event::Presence { name: "bob".into(), presence: Presence::Offline }]);
+
+        assert_eq!(reg.remove_friend("bob", "alice").await, FriendChange::Removed);
+        assert_eq!(drain(&mut alice), [Event::Friend { name: "bob".into(), status: FriendStatus::Removed }]);
+        assert_eq!(reg.remove_friend("bob", "alice").await, FriendChange::NotFound);
+        assert!(reg.friends("alice").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn channel_policy_limits_creation() {
+        let reg = Registry::with_channels(ChannelsConfig {
+            creators: Role::Moderator,
+            max_per_user: Some(1),
+            persistent: vec![PersistentChannel::new("#general")],
+            empty_timeout: Duration::from_secs(60),
+            ..ChannelsConfig::default()
+        });
+        assert!(reg.join("#general", "bob", Role::Anyone, None).await.unwrap().is_ok());
+        assert_eq!(reg.join("#new", "bob", Role::Anyone, None).await.unwrap().err(), Some(JoinDenied::NotAllowed));
+        // It's the role that counts, not the nickname.
+        assert_eq!(reg.join("#new", "admin", Role::Anyone, None).await.unwrap().err(), Some(JoinDenied::NotAllowed));
+        let _mods = reg.join("#mods", "mod", Role::Moderator, None).await.unwrap().unwrap();
+        let _ops = reg.join("#ops", "admin", Role::Admin, None).await.unwrap().unwrap();
+        assert_eq!(reg.join("#more", "admin", Role::Admin, None).await.unwrap().err(), Some(JoinDenied::TooMany(1)));
+        assert!(reg.join("#ops", "bob", Role::Anyone, None).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn configured_channels_have_keys_topics_and_ops() {
+        let staff = PersistentChannel {
+            name: "#staff".into(),
+            topic: Some("rota is up".into()),
+            key: Some("s3cret".into()),
+            ops: vec!["alice".into()],
+            ..PersistentChannel::default()
+        };
+        let reg = Registry::with_channels(ChannelsConfig { persistent: vec![staff.clone()], ..ChannelsConfig::default() });
+        assert_eq!(reg.join("#staff", "bob", Role::Anyone, None).await.unwrap().err(), Some(JoinDenied::BadKey));
+        assert_eq!(reg.join("#staff", "bob", Role::Anyone, Some("guess")).await.unwrap().err(), Some(JoinDenied::BadKey));
+        let joined = reg.join("#staff", "bob", Role::Anyone, Some("s3cret")).await.unwrap().unwrap();
+        assert_eq!(joined.topic.as_deref(), Some("rota is up"));
+
+        assert_eq!(reg.topic("#staff", "bob", Role::Anyone, Some("mine now".into())).await, Err("only channel operators can change the topic"));
+        assert_eq!(reg.topic("#staff", "alice", Role::Anyone, Some("new rota".into())).await, Ok(Some("new rota".into())));
+        let mut sub = joined.sub;
+        assert_eq!(*sub.recv().await.unwrap(), Event::notice("alice set the topic of #staff: new rota"));
+        assert!(reg.topic("#staff", "mod", Role::Moderator, Some("moderated".into())).await.is_ok());
+
+        // A reload puts the configured topic back and drops the key.
+        let reloaded = PersistentChannel { key: None, ..staff };
+        reg.reload_channels(ChannelsConfig { persistent: vec![reloaded], ..ChannelsConfig::default() }).await;
+        assert_eq!(reg.topic("#staff", "bob", Role::Anyone, None).await, Ok(Some("rota is up".into())));
+        assert!(reg.join("#staff", "carol", Role::Anyone, None).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn channels_keep_history_as_configured() {
+        let config = ChannelsConfig {
+            persistent: vec![PersistentChannel { retention: Some(Retention::Last(2)), ..PersistentChannel::new("#kept") }],
+            ..ChannelsConfig::default()
+        };
+        let reg = Registry::with_channels(config);
+        let _bob = reg.join("#kept", "bob", Role::Anyone, None).await.unwrap().unwrap();
+        let _tmp = reg.join("#tmp", "bob", Role::Anyone, None).await.unwrap().unwrap();
+        for body in ["one", "two", "three"] {
+            reg.say("#kept", "bob", Role::Anyone, 1, body).await.unwrap();
+            reg.say("#tmp", "bob", Role::Anyone, 1, body).await.unwrap();
+        }
+        let bodies = |events: Vec<Arc<Event>>| {
+            events.iter().map(|e| match &**e {
+                Event::ChannelMsg { body, .. } => body.clone(),
+                other => panic!("{other:?}"),
+            }).collect::<Vec<_>>()
+        };
+        let page = reg.channel_history("#kept", None, 10).await.unwrap();
+        assert!(!page.more);
+        assert_eq!(bodies(page.events), ["two", "three"]);
+        let page = reg.channel_history("#kept", None, 1).await.unwrap();
+        assert!(page.more);
+        let before = history::message_id(&page.events[0]);
+        assert_eq!(bodies(reg.channel_history("#kept", Some(before), 10).await.unwrap().events), ["two"]);
+        assert_eq!(reg.channel_history("#tmp", None, 10).await.unwrap(), Page::default());
+        assert!(reg.channel_history("#nope", None, 10).await.is_none());
+
+        assert_eq!(reg.set_retention("#kept", Some(Retention::Last(1))).await, Some(Retention::Last(1)));
+        assert_eq!(bodies(reg.channel_history("#kept", None, 10).await.unwrap().events), ["three"]);
+        assert_eq!(reg.purge_channel("#kept").await, Some(1));
+        assert_eq!(reg.channel_history("#kept", None, 10).await.unwrap(), Page::default());
+    }
+
+    #[tokio::test]
+    async fn searches_own_dms_and_given_channels() {
+        let reg = Registry::with_channels(ChannelsConfig { retention: Retention::Last(100), ..ChannelsConfig::default() });
+        let mut queues = Vec::new();
+        for (id, name) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+            let (tx, rx) = queue::channel(id, &Config::default().queue);
+            reg.register(id, name, tx, oneshot::channel().0).await.unwrap();
+            queues.push(rx);
+        }
+        let _sub = reg.join("#rust", "bob", Role::Anyone, None).await.unwrap().unwrap();
+        let _other = reg.join("#other", "bob", Role::Anyone, None).await.unwrap().unwrap();
+        reg.deliver_dm("alice", 1, 2, "Lunch at noon?").await.unwrap();
+        reg.deliver_dm("carol", 3, 1, "lunch is on me").await.unwrap();
+        reg.say("#rust", "bob", Role::Anyone, 2, "lunch and learn: lifetimes").await.unwrap();
+        reg.say("#other", "bob", Role::Anyone, 2, "secret lunch").await.unwrap();
+
+        let bodies = |events: Vec<Arc<Event>>| {
+            events.iter().map(|e| match &**e {
+                Event::Dm { body, .. } | Event::ChannelMsg { body, .. } => body.clone(),
+                other => panic!("{other:?}"),
+            }).collect::<Vec<_>>()
+        };
+        let search = |query: &str| SearchArgs { query: query.into(), ..SearchArgs::default() };
+        let rust = vec!["#rust".to_string()];
+        assert_eq!(bodies(reg.search("bob", rust.clone(), search("LUNCH"), 10).await), ["Lunch at noon?", "lunch and learn: lifetimes"]);
+        assert_eq!(bodies(reg.search("bob", rust.clone(), search("lunch"), 1).await), ["lunch and learn: lifetimes"]);
+        let from_alice = SearchArgs { from: Some("alice".into()), ..search("lunch") };
+        assert_eq!(bodies(reg.search("bob", rust.clone(), from_alice, 10).await), ["Lunch at noon?"]);
+        let in_rust = SearchArgs { channel: Some("#rust".into()), ..search("lunch") };
+        assert_eq!(bodies(reg.search("bob", rust, in_rust, 10).await), ["lunch and learn: lifetimes"]);
+    }
+
+    #[tokio::test]
+    async fn only_ops_post_in_announcement_channels() {
+        let news = PersistentChannel { ops: vec!["alice".into()], announce: true, ..PersistentChannel::new("#news") };
+        let reg = Registry::with_channels(ChannelsConfig { persistent: vec![news], ..ChannelsConfig::default() });
+        let mut sub = reg.join("#news", "bob", Role::Anyone, None).await.unwrap().unwrap().sub;
+        assert_eq!(reg.say("#news", "bob", Role::Anyone, 2, "hello?").await, Err(SayDenied));
+        assert_eq!(reg.say("#news", "alice", Role::Anyone, 1, "release is out").await.map(|said| said.reached), Ok(1));
+        assert!(reg.say("#news", "admin", Role::Admin, 3, "maintenance at noon").await.is_ok());
+        assert!(reg.say("#news", "mod", Role::Moderator, 4, "please keep it civil").await.is_ok());
+        assert_eq!(reg.say("#news", "admin", Role::Anyone, 5, "not really").await, Err(SayDenied));
+        assert!(matches!(&*sub.recv().await.unwrap(), Event::ChannelMsg { from, .. } if from == "alice"));
+    }
+
+    #[test]
+    fn empty_channels_expire() {
+        let config = ChannelsConfig { persistent: vec![PersistentChannel::new("#general")], empty_timeout: Duration::from_secs(60), ..ChannelsConfig::default() };
+        let mut state = State::default();
+        state.reload_channels(config.clone());
+        drop(state.join("#tmp".into(), "bob".into(), Role::Anyone, None).unwrap());
+
+        let now = Instant::now();
+        state.sweep(now);
+        state.sweep(now + Duration::from_secs(59));
+        assert!(state.channels.contains_key("#tmp"));
+        state.sweep(now + Duration::from_secs(60));
+        assert!(!state.channels.contains_key("#tmp"));
+        assert!(state.channels.contains_key("#general"));
+
+        // Dropped from the config, it goes the same way.
+        state.reload_channels(ChannelsConfig { persistent: Vec::new(), ..config });
+        state.sweep(now + Duration::from_secs(61));
+        state.sweep(now + Duration::from_secs(121));
+        assert!(!state.channels.contains_key("#general"));
+    }
+
+    #[test]
+    fn restores_a_snapshot() {
+        let config = ChannelsConfig { persistent: vec![PersistentChannel { key: Some("s3cret".into()), ..PersistentChannel::new("#general") }], ..ChannelsConfig::default() };
+        let mut state = State::default();
+        state.reload_channels(config.clone());
+        drop(state.join("#tmp".into(), "bob".into(), Role::Anyone, None).unwrap());
+        state.topic("#tmp", "bob", Role::Anyone, Some("scratch".into())).unwrap();
+        state.topic("#general", "admin", Role::Admin, Some("welcome".into())).unwrap();
+        state.profiles.entry("bob".into()).or_default().set(Field::Bio, Some("hi".into())).unwrap();
+        state.friends.insert("alice".into(), ["bob".into()].into());
+        state.friends.insert("bob".into(), ["alice".into()].into());
+        state.watching.insert("alice".into(), ["carol".into()].into());
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.friends, [("alice".to_string(), "bob".to_string())]);
+
+        let mut restored = State::default();
+        restored.reload_channels(ChannelsConfig { persistent: vec![PersistentChannel::new("#general")], ..config });
+        let now = Instant::now();
+        restored.restore(snapshot.clone(), now);
+        assert_eq!(restored.snapshot(), Snapshot { channels: vec![ChannelState { key: None, ..snapshot.channels[0].clone() }, snapshot.channels[1].clone()], ..snapshot });
+        assert_eq!(restored.channels["#tmp"].creator.as_deref(), Some("bob"));
+
+        // Nobody came back to it.
+        restored.sweep(now + snapshot::RESTORE_GRACE - Duration::from_secs(1));
+        assert!(restored.channels.contains_key("#tmp"));
+        restored.sweep(now + snapshot::RESTORE_GRACE);
+        assert!(!restored.channels.contains_key("#tmp"));
+    }
+
+    #[test]
+    fn purge_forgets_a_nickname() {
+        let mut state = State::default();
+        drop(state.join("#den".into(), "bob".into(), Role::Anyone, None).unwrap());
+        state.profiles.entry("bob".into()).or_default().set(Field::Bio, Some("hi".into())).unwrap();
+        state.friends.insert("alice".into(), ["bob".into()].into());
+        state.friends.insert("bob".into(), ["alice".into()].into());
+        state.blocking.insert("bob".into(), ["mallory".into()].into());
+        state.friend_requests.insert(("bob".into(), "carol".into()));
+        state.ephemeral.insert(("alice".into(), "bob".into()));
+        state.history.record(1, 0, "bob", 2, "alice", "hi");
+        let data = state.user_data("bob");
+        assert_eq!((data.channels.len(), data.profiles.len(), data.friends.len(), data.friend_requests.len(), data.blocking.len()), (1, 1, 1, 1, 1));
+        assert!(state.user_data("carol").friends.is_empty());
+
+        assert_eq!(state.purge("bob"), 1);
+        let data = state.user_data("bob");
+        assert_eq!(data, Snapshot::default());
+        assert_eq!(state.friends.get("alice"), Some(&BTreeSet::new()));
+        assert_eq!(state.channels["#den"].creator, None);
+    }
+}
@@ -0,0 +1,113 @@
+//! Keeps clients from writing terminal escape sequences into each other's
+//! screens, or hiding what they wrote behind bidi overrides and zero-width
+//! characters.
+
+use std::borrow::Cow;
+
+use protocol::format;
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+const CSI: char = '\u{9b}';
+
+/// Drops ANSI escape sequences, control characters and `is_invisible`
+/// ones; tabs become spaces.
+pub fn clean(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ESC => match chars.next() {
+                // CSI: parameters and intermediates up to a final byte in @..~
+                Some('[') => skip_csi(&mut chars),
+                // OSC/DCS/PM/APC strings run until BEL or ST (ESC \)
+                Some(']' | 'P' | '^' | '_') => {
+                    while let Some(c) = chars.next() {
+                        if c == BEL || (c == ESC && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                // Two-character escapes like ESC c
+                _ => {}
+            },
+            CSI => skip_csi(&mut chars),
+            '\t' => out.push(' '),
+            c if c.is_control() || is_invisible(c) => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A message body `clean`ed, with its formatting kept within
+/// `format::MAX_SPANS` spans.
+pub fn message(s: &str) -> String {
+    let cleaned = clean(s);
+    match format::limit(&cleaned, format::MAX_SPANS) {
+        Cow::Borrowed(_) => cleaned,
+        Cow::Owned(limited) => limited,
+    }
+}
+
+/// Bidi embeddings, overrides, isolates and marks, which can make text read
+/// in a different order than it was written, and zero-width characters,
+/// which can make two different names look alike.
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' | '\u{200e}' | '\u{200f}' | '\u{061c}' | '\u{200b}'..='\u{200d}' | '\u{2060}' | '\u{feff}')
+}
+
+fn skip_csi(chars: &mut impl Iterator<Item = char>) {
+    for c in chars {
+        if ('@'..='~').contains(&c) {
+            break;
+        }
+    }
+}
+
+/// Nicknames must not need cleaning at all.
+pub fn is_clean_name(name: &str) -> bool {
+    !name.chars().any(|c| c.is_control() || is_invisible(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_escapes() {
+        assert_eq!(clean("\x1b[1;31mred\x1b[0m text"), "red text");
+        assert_eq!(clean("\x1b]0;new title\x07hi"), "hi");
+        assert_eq!(clean("\x1b]8;;http://evil\x1b\\link\x1b]8;;\x1b\\"), "link");
+        assert_eq!(clean("\x1bPq#0;2;0;0;0\x1b\\after"), "after");
+        assert_eq!(clean("\u{9b}2Jwiped"), "wiped");
+        assert_eq!(clean("reset\x1bc done"), "reset done");
+        // Cut short, they swallow the rest rather than leak a fragment.
+        assert_eq!(clean("ok\x1b[31"), "ok");
+        assert_eq!(clean("ok\x1b]0;title"), "ok");
+    }
+
+    #[test]
+    fn strips_control_characters() {
+        assert_eq!(clean("a\tb\rc\x08d\x00e\u{85}f"), "a bcdef");
+        assert_eq!(clean("héllo wörld ✓"), "héllo wörld ✓");
+    }
+
+    #[test]
+    fn strips_bidi_and_zero_width_characters() {
+        assert_eq!(clean("invoice_\u{202e}fdp.exe"), "invoice_fdp.exe");
+        assert_eq!(clean("\u{2067}abc\u{2069} \u{200f}x\u{200e}\u{61c}"), "abc x");
+        assert_eq!(clean("a\u{200b}d\u{200c}m\u{200d}i\u{2060}n\u{feff}"), "admin");
+        assert_eq!(clean("שלום"), "שלום");
+    }
+
+    #[test]
+    fn checks_names() {
+        assert!(is_clean_name("alice_92"));
+        assert!(!is_clean_name("ali\x1b[2Jce"));
+        assert!(!is_clean_name("bob\n"));
+        assert!(!is_clean_name("ad\u{200b}min"));
+        assert!(!is_clean_name("\u{202e}nimda"));
+        assert!(!is_clean_name("\u{feff}alice"));
+    }
+}
@@ -0,0 +1,442 @@
+//! Scheduled DMs and reminders. `SCHEDULE <time> TO <name> <msg>` keeps a
+//! message on the server until its time comes, when a timer task sends it
+//! like any other DM; `REMIND me|<name> in <delay> <text>` does the same with
+//! a reminder. `SCHEDULES` lists the ones still waiting and `UNSCHEDULE <id>`
+//! drops one. A reminder that comes due while its recipient is offline waits
+//! for them to log in; a scheduled DM is dropped.
+//!
+//! With `[schedule] file` set, everything waiting is written there on each
+//! change and read back at startup, so it survives restarts: one line per
+//! entry, tab-separated, which cleaned text never contains. With a storage
+//! key the file is sealed; see `at_rest`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    sync::Notify,
+    time::{sleep, Duration},
+};
+
+use protocol::Event;
+
+use crate::{
+    at_rest::{self, Key},
+    clock,
+    info,
+    registry::{Blocked, Delivery, Registry},
+    warn,
+};
+
+/// Messages and reminders one user may have waiting.
+pub const MAX_PER_USER: usize = 50;
+/// How far ahead a message may be scheduled.
+pub const MAX_AHEAD: Duration = Duration::from_secs(365 * 86_400);
+
+const HEADER: &str = "# rustchat schedule v1: id, unix time, kind, from, to, text";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Dm,
+    Reminder,
+}
+
+impl Kind {
+    pub fn label(self) -> &'static str {
+        match self {
+            Kind::Dm => "dm",
+            Kind::Reminder => "remind",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scheduled {
+    pub id: u64,
+    /// Unix seconds.
+    pub at: u64,
+    pub kind: Kind,
+    pub from: String,
+    pub to: String,
+    pub body: String,
+}
+
+/// Why `SCHEDULE` or `REMIND` was turned away.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScheduleDenied {
+    Past,
+    TooFar,
+    /// `MAX_PER_USER` already waiting.
+    Full,
+}
+
+impl fmt::Display for ScheduleDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleDenied::Past => f.write_str("that time has already passed"),
+            ScheduleDenied::TooFar => write!(f, "messages can be scheduled at most {} days ahead", MAX_AHEAD.as_secs() / 86_400),
+            ScheduleDenied::Full => write!(f, "you already have {MAX_PER_USER} scheduled messages and reminders, the most allowed"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Schedule {
+    pending: Mutex<Pending>,
+    /// Wakes the timer when a message is added, in case it's the next due.
+    added: Notify,
+    file: Option<PathBuf>,
+    key: Option<Key>,
+}
+
+#[derive(Default)]
+struct Pending {
+    next_id: u64,
+    by_id: BTreeMap<u64, Scheduled>,
+    /// Reminders that came due while their recipient was offline.
+    waiting: BTreeMap<u64, Scheduled>,
+}
+
+impl Pending {
+    fn all(&self) -> impl Iterator<Item = &Scheduled> {
+        self.by_id.values().chain(self.waiting.values())
+    }
+}
+
+impl Schedule {
+    /// A schedule kept in `file`, sealed with `key` if there is one,
+    /// starting with what's already there. A missing file is an empty
+    /// schedule.
+    pub fn load(file: &Path, key: Option<Key>) -> Result<Self> {
+        let mut pending = Pending::default();
+        let mut plain = false;
+        match fs::read(file) {
+            Ok(data) => {
+                let text = at_rest::open(key.as_ref(), &data).with_context(|| format!("can't read {}", file.display()))?;
+                plain = !at_rest::is_sealed(&data);
+                for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.starts_with('#') && !l.is_empty()) {
+                    let s = parse_line(line).ok_or_else(|| anyhow!("{}:{}: not a scheduled message", file.display(), n + 1))?;
+                    pending.next_id = pending.next_id.max(s.id);
+                    pending.by_id.insert(s.id, s);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => bail!("failed to read {}: {e}", file.display()),
+        }
+        let schedule = Schedule { pending: Mutex::new(pending), added: Notify::new(), file: Some(file.to_path_buf()), key };
+        if plain && key.is_some() {
+            info!("SCHEDULE", "encrypting {}", file.display());
+            schedule.save(&schedule.pending.lock());
+        }
+        Ok(schedule)
+    }
+
+    /// Keeps `body` for sending to `to` at `at`, returning its ID.
+    pub fn add(&self, now: u64, at: u64, kind: Kind, from: &str, to: &str, body: &str) -> Result<u64, ScheduleDenied> {
+        if at < now {
+            return Err(ScheduleDenied::Past);
+        }
+        if at - now > MAX_AHEAD.as_secs() {
+            return Err(ScheduleDenied::TooFar);
+        }
+        let mut pending = self.pending.lock();
+        if pending.all().filter(|s| s.from == from).count() >= MAX_PER_USER {
+            return Err(ScheduleDenied::Full);
+        }
+        pending.next_id += 1;
+        let id = pending.next_id;
+        pending.by_id.insert(id, Scheduled { id, at, kind, from: from.to_string(), to: to.to_string(), body: body.to_string() });
+        self.save(&pending);
+        drop(pending);
+        self.added.notify_one();
+        Ok(id)
+    }
+
+    /// `from`'s waiting messages and reminders, soonest first.
+    pub fn list(&self, from: &str) -> Vec<Scheduled> {
+        let mut list: Vec<_> = self.pending.lock().all().filter(|s| s.from == from).cloned().collect();
+        list.sort_by_key(|s| (s.at, s.id));
+        list
+    }
+
+    /// Drops `from`'s message or reminder `id`; `false` if they have none by
+    /// that ID.
+    pub fn cancel(&self, from: &str, id: u64) -> bool {
+        let mut pending = self.pending.lock();
+        let Pending { by_id, waiting, .. } = &mut *pending;
+        let Some(map) = [by_id, waiting].into_iter().find(|m| m.get(&id).is_some_and(|s| s.from == from)) else {
+            return false;
+        };
+        map.remove(&id);
+        self.save(&pending);
+        true
+    }
+
+    /// Drops everything `name` scheduled and everything waiting for them,
+    /// for `PURGE`, returning how many.
+    pub fn purge(&self, name: &str) -> usize {
+        let mut pending = self.pending.lock();
+        let Pending { by_id, waiting, .. } = &mut *pending;
+        let before = by_id.len() + waiting.len();
+        for map in [by_id, waiting] {
+            map.retain(|_, s| s.from != name && s.to != name);
+        }
+        let removed = before - pending.by_id.len() - pending.waiting.len();
+        if removed > 0 {
+            self.save(&pending);
+        }
+        removed
+    }
+
+    /// Sends `name` the reminders that came due while they were away.
+    pub async fn arrived(&self, reg: &Registry, name: &str) {
+        let due: Vec<_> = {
+            let mut pending = self.pending.lock();
+            let ids: Vec<u64> = pending.waiting.values().filter(|s| s.to == name).map(|s| s.id).collect();
+            let due = ids.iter().filter_map(|id| pending.waiting.remove(id)).collect();
+            self.save(&pending);
+            due
+        };
+        for s in due {
+            self.send(reg, s).await;
+        }
+    }
+
+    fn take_due(&self, now: u64) -> Vec<Scheduled> {
+        let mut pending = self.pending.lock();
+        let due: Vec<u64> = pending.by_id.values().filter(|s| s.at <= now).map(|s| s.id).collect();
+        let mut due: Vec<_> = due.iter().filter_map(|id| pending.by_id.remove(id)).collect();
+        due.sort_by_key(|s| (s.at, s.id));
+        if !due.is_empty() {
+            self.save(&pending);
+        }
+        due
+    }
+
+    fn next_at(&self) -> Option<u64> {
+        self.pending.lock().by_id.values().map(|s| s.at).min()
+    }
+
+    /// Writes everything waiting to the file, if there is one. A failure is
+    /// logged and the schedule carries on in memory.
+    fn save(&self, pending: &Pending) {
+        let Some(file) = &self.file else { return };
+        let mut text = format!("{HEADER}\n");
+        for s in pending.all() {
+            text.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\n", s.id, s.at, s.kind.label(), s.from, s.to, s.body));
+        }
+        let tmp = file.with_extension("tmp");
+        let saved = at_rest::seal(self.key.as_ref(), &text).and_then(|data| Ok(fs::write(&tmp, data).and_then(|()| fs::rename(&tmp, file))?));
+        if let Err(e) = saved.with_context(|| format!("can't save {}", file.display())) {
+            warn!("SCHEDULE", "{e:#}");
+        }
+    }
+
+    /// Sends each message when its time comes, for as long as the server
+    /// runs.
+    pub async fn run(self: Arc<Self>, reg: Registry) {
+        loop {
+            match self.next_at() {
+                Some(at) => {
+                    let wait = Duration::from_secs(at.saturating_sub(clock::now_unix()));
+                    tokio::select! {
+                        _ = sleep(wait) => {}
+                        _ = self.added.notified() => {}
+                    }
+                }
+                None => self.added.notified().await,
+            }
+            for scheduled in self.take_due(clock::now_unix()) {
+                self.send(&reg, scheduled).await;
+            }
+        }
+    }
+
+    /// Delivers like `TO` would, then tells the sender how it went if they're
+    /// online and it wasn't a reminder to themselves.
+    async fn send(&self, reg: &Registry, s: Scheduled) {
+        // No receipts for reminders to oneself.
+        let from_id = if s.from == s.to { 0 } else { reg.id_of(&s.from).await.unwrap_or(0) };
+        let (what, body) = match s.kind {
+            Kind::Dm => ("scheduled message", s.body.clone()),
+            Kind::Reminder => ("reminder", format!("reminder: {}", s.body)),
+        };
+        let failed = match reg.id_of(&s.to).await {
+            None if s.kind == Kind::Reminder => {
+                let mut pending = self.pending.lock();
+                pending.waiting.insert(s.id, s.clone());
+                self.save(&pending);
+                Some(format!("{} is offline, so it waits until they're back", s.to))
+            }
+            None => Some(format!("{} is offline", s.to)),
+            Some(to) => match reg.deliver_dm(&s.from, from_id, to, &body).await {
+                Ok(Delivery::Queued { .. } | Delivery::Held) => None,
+                Ok(Delivery::Dropped) => Some(format!("{} is in do-not-disturb", s.to)),
+                Err(e) if e.is::<Blocked>() => Some(String::new()),
+                Err(_) => Some(format!("{} disconnected", s.to)),
+            },
+        };
+        let notice = match failed {
+            None => format!("{what} #{} sent to {}", s.id, s.to),
+            Some(why) if why.is_empty() => format!("{what} #{} to {} wasn't delivered", s.id, s.to),
+            Some(why) => format!("{what} #{} to {} wasn't delivered: {why}", s.id, s.to),
+        };
+        if s.from == s.to {
+            return;
+        }
+        if let Some(tx) = reg.sender(from_id) {
+            let _ = tx.push(Event::notice(notice));
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<Scheduled> {
+    let mut f = line.splitn(6, '\t');
+    let (id, at, kind) = (f.next()?.parse().ok()?, f.next()?.parse().ok()?, f.next()?);
+    let kind = [Kind::Dm, Kind::Reminder].into_iter().find(|k| k.label() == kind)?;
+    let (from, to, body) = (f.next()?.to_string(), f.next()?.to_string(), f.next()?.to_string());
+    Some(Scheduled { id, at, kind, from, to, body })
+}
+
+/// A delay like `90s`, `10m`, `2h`, `3d`, `1w` or `1h30m`.
+pub fn parse_delay(s: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let n: u64 = rest[..digits].parse().ok()?;
+        let unit = match rest[digits..].chars().next()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            'w' => 7 * 86_400,
+            _ => return None,
+        };
+        total = total.checked_add(n.checked_mul(unit)?)?;
+        rest = &rest[digits + 1..];
+    }
+    (total > 0).then(|| Duration::from_secs(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, queue};
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn adds_lists_and_cancels() {
+        let schedule = Schedule::default();
+        let now = 1_000_000;
+        assert_eq!(schedule.add(now, now - 1, Kind::Dm, "alice", "bob", "late"), Err(ScheduleDenied::Past));
+        assert_eq!(schedule.add(now, now + MAX_AHEAD.as_secs() + 1, Kind::Dm, "alice", "bob", "early"), Err(ScheduleDenied::TooFar));
+        let later = schedule.add(now, now + 60, Kind::Dm, "alice", "bob", "later").unwrap();
+        let sooner = schedule.add(now, now + 10, Kind::Reminder, "alice", "carol", "sooner").unwrap();
+        assert_eq!(schedule.list("alice").iter().map(|s| s.id).collect::<Vec<_>>(), [sooner, later]);
+        assert!(schedule.list("bob").is_empty());
+
+        assert!(!schedule.cancel("bob", later));
+        assert!(schedule.cancel("alice", later));
+        assert!(!schedule.cancel("alice", later));
+        assert_eq!(schedule.next_at(), Some(now + 10));
+        assert!(schedule.take_due(now + 9).is_empty());
+        assert_eq!(schedule.take_due(now + 10).len(), 1);
+
+        for _ in 0..MAX_PER_USER {
+            schedule.add(now, now + 1, Kind::Dm, "alice", "bob", "spam").unwrap();
+        }
+        assert_eq!(schedule.add(now, now + 1, Kind::Dm, "alice", "bob", "spam"), Err(ScheduleDenied::Full));
+        schedule.add(now, now + 1, Kind::Dm, "carol", "dave", "unrelated").unwrap();
+        assert_eq!(schedule.purge("bob"), MAX_PER_USER);
+        assert!(schedule.list("alice").is_empty());
+        assert_eq!(schedule.list("carol").len(), 1);
+    }
+
+    #[test]
+    fn parses_delays() {
+        assert_eq!(parse_delay("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_delay("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_delay("2w"), Some(Duration::from_secs(14 * 86_400)));
+        assert_eq!(parse_delay("0m"), None);
+        assert_eq!(parse_delay("10"), None);
+        assert_eq!(parse_delay("m"), None);
+        assert_eq!(parse_delay("soon"), None);
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let file = std::env::temp_dir().join(format!("rustchat-schedule-test-{}.tsv", std::process::id()));
+        let schedule = Schedule::load(&file, None).unwrap();
+        schedule.add(100, 200, Kind::Dm, "alice", "bob smith", "hi: there").unwrap();
+        let id = schedule.add(100, 300, Kind::Reminder, "bob smith", "bob smith", "stretch").unwrap();
+        let gone = schedule.add(100, 400, Kind::Dm, "alice", "carol", "never mind").unwrap();
+        schedule.cancel("alice", gone);
+
+        let reloaded = Schedule::load(&file, None).unwrap();
+        assert_eq!(reloaded.list("alice"), schedule.list("alice"));
+        assert_eq!(reloaded.list("bob smith")[0].kind, Kind::Reminder);
+        assert_eq!(reloaded.add(100, 500, Kind::Dm, "alice", "bob", "new").unwrap(), id + 1);
+        fs::write(&file, "1\tsoon\tdm\ta\tb\tc\n").unwrap();
+        assert!(Schedule::load(&file, None).is_err());
+
+        // With a key, a plain file is sealed straight away.
+        let key = Key::parse(&"5e".repeat(32));
+        fs::write(&file, "1\t200\tdm\talice\tbob\thi there\n").unwrap();
+        let sealed = Schedule::load(&file, key).unwrap();
+        assert!(at_rest::is_sealed(&fs::read(&file).unwrap()));
+        assert_eq!(Schedule::load(&file, key).unwrap().list("alice"), sealed.list("alice"));
+        assert!(Schedule::load(&file, None).is_err());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sends_when_due() {
+        let reg = Registry::spawn();
+        let (tx, mut alice) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "alice", tx, oneshot::channel().0).await.unwrap();
+        let (tx, mut bob) = queue::channel(2, &Config::default().queue);
+        reg.register(2, "bob", tx, oneshot::channel().0).await.unwrap();
+        let schedule = Arc::new(Schedule::default());
+        tokio::spawn(schedule.clone().run(reg.clone()));
+
+        let now = clock::now_unix();
+        let id = schedule.add(now, now, Kind::Dm, "alice", "bob", "on time").unwrap();
+        schedule.add(now, now, Kind::Dm, "alice", "carol", "nobody home").unwrap();
+        let mut got = Vec::new();
+        while got.len() < 4 {
+            tokio::select! {
+                Some(e) = alice.recv() => got.push((*e).clone()),
+                Some(e) = bob.recv() => got.push((*e).clone()),
+            }
+        }
+        assert!(got.iter().any(|e| matches!(e, Event::Dm { from, body, .. } if from == "alice" && body == "on time")));
+        assert!(got.contains(&Event::notice(format!("scheduled message #{id} sent to bob"))));
+        assert!(got.contains(&Event::notice(format!("scheduled message #{} to carol wasn't delivered: carol is offline", id + 1))));
+        assert!(schedule.list("alice").is_empty());
+    }
+
+    #[tokio::test]
+    async fn reminders_wait_for_their_recipient() {
+        let reg = Registry::spawn();
+        let schedule = Arc::new(Schedule::default());
+        tokio::spawn(schedule.clone().run(reg.clone()));
+        let now = clock::now_unix();
+        schedule.add(now, now, Kind::Reminder, "bob", "bob", "water the plants").unwrap();
+        while schedule.pending.lock().waiting.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(schedule.list("bob").len(), 1);
+
+        let (tx, mut bob) = queue::channel(1, &Config::default().queue);
+        reg.register(1, "bob", tx, oneshot::channel().0).await.unwrap();
+        schedule.arrived(&reg, "bob").await;
+        assert!(matches!(&*bob.recv().await.unwrap(), Event::Dm { body, .. } if body == "reminder: water the plants"));
+        assert!(schedule.list("bob").is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+//! Client senders keyed by connection ID, split across shards so lookups on
+//! the send path only ever contend with joins and leaves hashing to the same
+//! shard.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+
+use crate::queue::ClientTx;
+
+const SHARDS: usize = 16;
+
+pub struct Senders {
+    shards: [RwLock<HashMap<u64, ClientTx>>; SHARDS],
+}
+
+impl Default for Senders {
+    fn default() -> Self {
+        Senders { shards: std::array::from_fn(|_| RwLock::default()) }
+    }
+}
+
+impl Senders {
+    fn shard(&self, id: u64) -> &RwLock<HashMap<u64, ClientTx>> {
+        &self.shards[(id % SHARDS as u64) as usize]
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClientTx> {
+        self.shard(id).read().get(&id).cloned()
+    }
+
+    pub fn insert(&self, id: u64, tx: ClientTx) {
+        self.shard(id).write().insert(id, tx);
+    }
+
+    pub fn remove(&self, id: u64) -> Option<ClientTx> {
+        self.shard(id).write().remove(&id)
+    }
+}
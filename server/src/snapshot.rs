@@ -0,0 +1,315 @@
+//! Snapshots of what the server otherwise keeps only in memory: channels
+//! made by joining them, with their topics, keys, operators and retention,
+//! the topics and retention of persistent ones, and what belongs to each
+//! nickname: profiles, watch and block lists, friends and friend requests,
+//! ephemeral threads, push URLs, and DMs held for do-not-disturb for
+//! registered nicknames, which are given to their recipient when they next
+//! log in. With `[snapshot] file` set, all of it is written there every `interval_secs` and as the server exits, and read
+//! back at startup, so a crash loses at most one interval of it. Accounts
+//! and the schedule, reminders waiting for their recipient included, are
+//! written on each change already; message history stays in memory only.
+//!
+//! Each snapshot goes to a file beside the last one, is flushed to disk and
+//! then renamed over it, so a crash while writing leaves the last one whole.
+//! One record a line, its kind first, tab-separated like the schedule; an
+//! empty field is one that isn't set. Channel keys are in it, so it's only
+//! readable by the server's user, and sealed with a storage key if there is
+//! one; see `at_rest`. Restored channels that nobody rejoins are cleaned up
+//! `RESTORE_GRACE` later than they otherwise would be.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    sync::Mutex,
+    time::{interval_at, Duration, Instant},
+};
+
+use protocol::Event;
+
+use crate::{
+    at_rest::{self, Key},
+    clock,
+    history::Retention,
+    http::HttpUrl,
+    info,
+    profile::{Avatar, Field, Profile},
+    push::Pusher,
+    registry::Registry,
+    warn,
+};
+
+/// How long a restored channel waits for its members to come back before
+/// it counts as empty.
+pub const RESTORE_GRACE: Duration = Duration::from_secs(300);
+
+const HEADER: &str = "# rustchat snapshot v1: one record a line, its kind first";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Unix seconds.
+    pub taken: u64,
+    /// By name.
+    pub channels: Vec<ChannelState>,
+    pub profiles: Vec<(String, Profile)>,
+    /// As (name, who they watch).
+    pub watching: Vec<(String, String)>,
+    /// As (name, who they block).
+    pub blocking: Vec<(String, String)>,
+    /// Each pair once, in order.
+    pub friends: Vec<(String, String)>,
+    /// As (from, to).
+    pub friend_requests: Vec<(String, String)>,
+    /// Name pairs in order.
+    pub ephemeral: Vec<(String, String)>,
+    pub push: Vec<(String, HttpUrl)>,
+    /// DMs held for do-not-disturb, as (recipient, DM), oldest first.
+    pub held: Vec<(String, Event)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelState {
+    pub name: String,
+    pub creator: Option<String>,
+    pub topic: Option<String>,
+    pub key: Option<String>,
+    pub ops: Vec<String>,
+    pub retention: Retention,
+}
+
+impl Snapshot {
+    /// How many nicknames it has anything for.
+    pub fn nicknames(&self) -> usize {
+        let pairs = self.watching.iter().chain(&self.blocking).chain(&self.friends).chain(&self.friend_requests).chain(&self.ephemeral);
+        let names = pairs.flat_map(|(a, b)| [a, b]).chain(self.profiles.iter().map(|(name, _)| name)).chain(self.push.iter().map(|(name, _)| name)).chain(self.held.iter().map(|(name, _)| name));
+        names.collect::<BTreeSet<_>>().len()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{HEADER}\ntaken\t{}\n", self.taken);
+        for c in &self.channels {
+            let field = |value: &Option<String>| value.clone().unwrap_or_default();
+            text.push_str(&format!("channel\t{}\t{}\t{}\t{}\t{}\n", c.name, field(&c.creator), field(&c.topic), field(&c.key), c.retention));
+            for op in &c.ops {
+                text.push_str(&format!("op\t{}\t{op}\n", c.name));
+            }
+        }
+        for (name, profile) in &self.profiles {
+            for field in Field::ALL {
+                if let Some(value) = profile.get(field) {
+                    text.push_str(&format!("profile\t{name}\t{}\t{value}\n", field.name()));
+                }
+            }
+            if let Some(avatar) = profile.avatar() {
+                text.push_str(&format!("avatar\t{name}\t{}\n", avatar.image));
+            }
+        }
+        let pairs = [("watch", &self.watching), ("block", &self.blocking), ("friend", &self.friends), ("request", &self.friend_requests), ("ephemeral", &self.ephemeral)];
+        for (kind, pairs) in pairs {
+            for (a, b) in pairs {
+                text.push_str(&format!("{kind}\t{a}\t{b}\n"));
+            }
+        }
+        for (name, url) in &self.push {
+            text.push_str(&format!("push\t{name}\t{url}\n"));
+        }
+        for (to, dm) in &self.held {
+            if let Event::Dm { id, seq, ts, from, from_id, body } = dm {
+                text.push_str(&format!("held\t{to}\t{id}\t{seq}\t{ts}\t{from}\t{from_id}\t{body}\n"));
+            }
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        let mut profiles: BTreeMap<String, Profile> = BTreeMap::new();
+        for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.starts_with('#') && !l.is_empty()) {
+            let bad = |why: &str| anyhow!("line {}: {why}", n + 1);
+            let fields: Vec<&str> = line.split('\t').collect();
+            let set = |value: &str| Some(value.to_string()).filter(|v| !v.is_empty());
+            let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+            match fields.as_slice() {
+                ["taken", at] => snapshot.taken = at.parse().map_err(|_| bad("not a time"))?,
+                ["channel", name, creator, topic, key, retention] => snapshot.channels.push(ChannelState {
+                    name: name.to_string(),
+                    creator: set(creator),
+                    topic: set(topic),
+                    key: set(key),
+                    ops: Vec::new(),
+                    retention: Retention::parse(retention).ok_or_else(|| bad("not a retention"))?,
+                }),
+                ["op", channel, name] => {
+                    let c = snapshot.channels.iter_mut().find(|c| c.name == *channel).ok_or_else(|| bad("an operator of a channel it doesn't have"))?;
+                    c.ops.push(name.to_string());
+                }
+                ["profile", name, field, value] => {
+                    let field = Field::parse(field).ok_or_else(|| bad("not a profile field"))?;
+                    profiles.entry(name.to_string()).or_default().set(field, Some(value.to_string())).map_err(|e| bad(&e))?;
+                }
+                ["avatar", name, image] => {
+                    let avatar = Avatar::parse(image.to_string()).map_err(|e| bad(&e))?;
+                    profiles.entry(name.to_string()).or_default().set_avatar(Some(avatar));
+                }
+                ["watch", a, b] => snapshot.watching.push(pair(a, b)),
+                ["block", a, b] => snapshot.blocking.push(pair(a, b)),
+                ["friend", a, b] => snapshot.friends.push(pair(a, b)),
+                ["request", a, b] => snapshot.friend_requests.push(pair(a, b)),
+                ["ephemeral", a, b] => snapshot.ephemeral.push(pair(a, b)),
+                ["push", name, url] => snapshot.push.push((name.to_string(), HttpUrl::parse(url).map_err(|e| bad(&e))?)),
+                ["held", to, id, seq, ts, from, from_id, body] => {
+                    let number = |n: &str| n.parse::<u64>().map_err(|_| bad("not a number"));
+                    let dm = Event::Dm { id: number(id)?, seq: number(seq)?, ts: ts.to_string(), from: from.to_string(), from_id: number(from_id)?, body: body.to_string() };
+                    snapshot.held.push((to.to_string(), dm));
+                }
+                _ => return Err(bad("not a snapshot record")),
+            }
+        }
+        snapshot.profiles = profiles.into_iter().collect();
+        Ok(snapshot)
+    }
+}
+
+/// The last snapshot written to `file`, or `None` if there isn't one.
+pub fn load(file: &Path, key: Option<&Key>) -> Result<Option<Snapshot>> {
+    let data = match fs::read(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!("failed to read {}: {e}", file.display()),
+    };
+    let text = at_rest::open(key, &data).with_context(|| format!("can't read {}", file.display()))?;
+    Snapshot::parse(&text).with_context(|| format!("{} isn't a snapshot", file.display())).map(Some)
+}
+
+/// Replaces `file` with `snapshot` in one step; see `at_rest::replace`.
+pub fn save(file: &Path, key: Option<&Key>, snapshot: &Snapshot) -> Result<()> {
+    Ok(at_rest::replace(file, &at_rest::seal(key, &snapshot.to_text())?)?)
+}
+
+/// Takes snapshots of the registry and push URLs for as long as the server
+/// runs.
+pub struct Snapshots {
+    file: PathBuf,
+    key: Option<Key>,
+    reg: Registry,
+    pusher: Arc<Pusher>,
+    /// Held while one is taken; `true` once another process has taken over,
+    /// after which this one's state is stale and isn't written.
+    stopped: Mutex<bool>,
+}
+
+impl Snapshots {
+    pub fn new(file: &Path, key: Option<Key>, reg: Registry, pusher: Arc<Pusher>) -> Self {
+        Snapshots { file: file.to_path_buf(), key, reg, pusher, stopped: Mutex::new(false) }
+    }
+
+    /// Puts back what the last snapshot had, if there is one.
+    pub async fn restore(&self) -> Result<()> {
+        let Some(mut snapshot) = load(&self.file, self.key.as_ref())? else { return Ok(()) };
+        let (channels, nicknames) = (snapshot.channels.len(), snapshot.nicknames());
+        let age = clock::now_unix().saturating_sub(snapshot.taken);
+        for (name, url) in std::mem::take(&mut snapshot.push) {
+            self.pusher.set(&name, Some(url));
+        }
+        self.reg.restore(snapshot).await;
+        info!("SNAPSHOT", "restored {channels} channels and {nicknames} nicknames' settings and held DMs from {}, taken {age}s ago", self.file.display());
+        Ok(())
+    }
+
+    /// Writes a snapshot, unless another process has taken over.
+    pub async fn take(&self) -> Result<()> {
+        let stopped = self.stopped.lock().await;
+        if *stopped {
+            return Ok(());
+        }
+        let mut snapshot = self.reg.snapshot().await;
+        snapshot.taken = clock::now_unix();
+        snapshot.push = self.pusher.urls();
+        let (file, key) = (self.file.clone(), self.key);
+        tokio::task::spawn_blocking(move || save(&file, key.as_ref(), &snapshot))
+            .await?
+            .with_context(|| format!("can't save {}", self.file.display()))
+    }
+
+    /// Stops taking them, for when a new process has restored the last one
+    /// and is keeping its own.
+    pub async fn stop(&self) {
+        *self.stopped.lock().await = true;
+    }
+
+    /// Takes one every `every`. A failure is logged and the next one tried
+    /// as usual.
+    pub async fn run(self: Arc<Self>, every: Duration) {
+        let mut ticks = interval_at(Instant::now() + every, every);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = self.take().await {
+                warn!("SNAPSHOT", "{e:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::base64;
+
+    fn example() -> Snapshot {
+        let mut profile = Profile::default();
+        profile.set(Field::RealName, Some("Ada Lovelace".into())).unwrap();
+        profile.set_avatar(Some(Avatar::parse(base64::encode(b"GIF89a")).unwrap()));
+        Snapshot {
+            taken: 1_700_000_000,
+            channels: vec![
+                ChannelState { name: "#rust".into(), creator: Some("alice".into()), topic: Some("lifetimes".into()), key: None, ops: vec!["alice".into(), "bob".into()], retention: Retention::Last(500) },
+                ChannelState { name: "#staff".into(), creator: None, topic: None, key: Some("s3cret".into()), ops: Vec::new(), retention: Retention::For(Duration::from_secs(7 * 86_400)) },
+            ],
+            profiles: vec![("alice".into(), profile)],
+            watching: vec![("alice".into(), "bob".into())],
+            blocking: vec![("bob".into(), "mallory".into())],
+            friends: vec![("alice".into(), "carol".into())],
+            friend_requests: vec![("dave".into(), "alice".into())],
+            ephemeral: vec![("alice".into(), "bob".into())],
+            push: vec![("alice".into(), HttpUrl::parse("http://ntfy.lan/alice").unwrap())],
+            held: vec![("erin".into(), Event::Dm { id: 7, seq: 3, ts: "2026-10-15T08:30:00Z".into(), from: "bob".into(), from_id: 2, body: "call me: it's urgent".into() })],
+        }
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let snapshot = example();
+        let text = snapshot.to_text();
+        assert!(text.contains("channel\t#rust\talice\tlifetimes\t\t500 messages\nop\t#rust\talice\nop\t#rust\tbob\n"));
+        assert_eq!(Snapshot::parse(&text).unwrap(), snapshot);
+        assert!(text.contains("held\terin\t7\t3\t2026-10-15T08:30:00Z\tbob\t2\tcall me: it's urgent\n"));
+        assert_eq!(snapshot.nicknames(), 6);
+        assert_eq!(Snapshot::parse("").unwrap(), Snapshot::default());
+
+        assert!(Snapshot::parse("op\t#rust\talice").unwrap_err().to_string().contains("line 1"));
+        assert!(Snapshot::parse("friend\talice").is_err());
+        assert!(Snapshot::parse("profile\talice\tage\t36").is_err());
+        assert!(Snapshot::parse("mystery\tvalue").is_err());
+        assert!(Snapshot::parse("held\terin\tseven\t3\tnow\tbob\t2\thi").is_err());
+    }
+
+    #[test]
+    fn replaces_the_file_whole() {
+        let file = std::env::temp_dir().join(format!("rustchat-snapshot-test-{}.tsv", std::process::id()));
+        let _ = fs::remove_file(&file);
+        assert_eq!(load(&file, None).unwrap(), None);
+
+        let key = Key::parse(&"5d".repeat(32)).unwrap();
+        save(&file, Some(&key), &Snapshot::default()).unwrap();
+        save(&file, Some(&key), &example()).unwrap();
+        assert!(!file.with_extension("tmp").exists());
+        assert!(!fs::read(&file).unwrap().windows(6).any(|w| w == b"s3cret"));
+        assert_eq!(load(&file, Some(&key)).unwrap(), Some(example()));
+        assert!(load(&file, None).is_err());
+        fs::remove_file(&file).unwrap();
+    }
+}
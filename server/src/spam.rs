@@ -0,0 +1,273 @@
+//! Catches the usual ways of flooding a chat from the messages themselves:
+//! the same text over and over, one message naming a crowd, and DMs to more
+//! people than anyone talks to at once. Each pattern has its own limit and
+//! its own list of what happens the first time it's tripped, the second,
+//! and so on, the last repeating; `warn`, `mute` for a while, or `kick`. A
+//! message that trips a limit isn't sent. Strikes are forgotten after a
+//! while without one, and everything is kept by nickname, so reconnecting
+//! doesn't start over.
+
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+};
+use tokio::time::{Duration, Instant};
+
+use crate::config::{SpamConfig, SpamRule};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Warn,
+    Mute,
+    Kick,
+}
+
+impl Action {
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::Warn => "warn",
+            Action::Mute => "mute",
+            Action::Kick => "kick",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "warn" => Ok(Action::Warn),
+            "mute" => Ok(Action::Mute),
+            "kick" => Ok(Action::Kick),
+            other => Err(format!("expected \"warn\", \"mute\" or \"kick\", not {other:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pattern {
+    Repeats,
+    Mentions,
+    Dms,
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Pattern::Repeats => "sending the same message again and again",
+            Pattern::Mentions => "naming too many people at once",
+            Pattern::Dms => "messaging too many people at once",
+        })
+    }
+}
+
+/// What to do about a message, and why.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verdict {
+    pub pattern: Pattern,
+    pub action: Action,
+    /// Which time running into `pattern` this is, from 1.
+    pub strike: u32,
+}
+
+/// Where a message is going.
+pub enum To<'a> {
+    Dm(&'a str),
+    Channel,
+}
+
+#[derive(Default)]
+struct Sender {
+    /// Recent messages, normalized, oldest first.
+    said: VecDeque<(Instant, String)>,
+    /// Recent DMs' recipients, oldest first.
+    dmed: VecDeque<(Instant, String)>,
+    strikes: HashMap<Pattern, (u32, Instant)>,
+    muted_until: Option<Instant>,
+}
+
+impl Sender {
+    /// Whether there's nothing left worth remembering.
+    fn idle(&self, now: Instant, config: &SpamConfig) -> bool {
+        let recent = |list: &VecDeque<(Instant, String)>, window| list.back().is_some_and(|(at, _)| now.duration_since(*at) < window);
+        self.muted_until.is_none_or(|until| until <= now)
+            && !self.strikes.values().any(|&(_, last)| now.duration_since(last) < config.forgive)
+            && !recent(&self.said, config.repeats.window)
+            && !recent(&self.dmed, config.dms.window)
+    }
+}
+
+pub struct Spam {
+    config: SpamConfig,
+    senders: Mutex<HashMap<String, Sender>>,
+}
+
+impl Spam {
+    pub fn new(config: SpamConfig) -> Self {
+        Spam { config, senders: Mutex::default() }
+    }
+
+    /// How many people a message can name before it counts as spam, if
+    /// anything is checked; callers can stop counting there.
+    pub fn mention_limit(&self) -> Option<usize> {
+        (self.config.enabled && self.config.mentions.limit > 0).then_some(self.config.mentions.limit as usize)
+    }
+
+    /// How much longer `name` is muted for, if they are.
+    pub fn muted(&self, name: &str, now: Instant) -> Option<Duration> {
+        let until = self.senders.lock().get(name)?.muted_until?;
+        (until > now).then(|| until - now)
+    }
+
+    /// Looks at a message from `name` naming `mentioned` people, before it's
+    /// sent. `None` lets it through.
+    pub fn check(&self, name: &str, to: To<'_>, body: &str, mentioned: usize, now: Instant) -> Option<Verdict> {
+        if !self.config.enabled {
+            return None;
+        }
+        let mut senders = self.senders.lock();
+        senders.retain(|_, s| !s.idle(now, &self.config));
+        let sender = senders.entry(name.to_string()).or_default();
+        let (repeats, dms) = (&self.config.repeats, &self.config.dms);
+        forget(&mut sender.said, now, repeats.window);
+        forget(&mut sender.dmed, now, dms.window);
+
+        let text = normalize(body);
+        let tripped = if repeats.limit > 0 && sender.said.iter().filter(|(_, said)| *said == text).count() + 1 >= repeats.limit as usize {
+            sender.said.clear();
+            Some(Pattern::Repeats)
+        } else if self.config.mentions.limit > 0 && mentioned >= self.config.mentions.limit as usize {
+            Some(Pattern::Mentions)
+        } else if let (To::Dm(target), true) = (&to, dms.limit > 0) {
+            let mut seen: Vec<&str> = sender.dmed.iter().map(|(_, n)| n.as_str()).collect();
+            seen.sort_unstable();
+            seen.dedup();
+            if !seen.contains(target) && seen.len() + 1 >= dms.limit as usize {
+                sender.dmed.clear();
+                Some(Pattern::Dms)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        let Some(pattern) = tripped else {
+            sender.said.push_back((now, text));
+            if let To::Dm(target) = to {
+                sender.dmed.push_back((now, target.to_string()));
+            }
+            return None;
+        };
+        let rule = self.rule(pattern);
+        let strikes = sender.strikes.entry(pattern).or_insert((0, now));
+        if now.duration_since(strikes.1) >= self.config.forgive {
+            strikes.0 = 0;
+        }
+        *strikes = (strikes.0 + 1, now);
+        let strike = strikes.0;
+        let action = rule.actions.get(strike as usize - 1).or(rule.actions.last()).copied().unwrap_or(Action::Warn);
+        if action == Action::Mute {
+            sender.muted_until = Some(now + self.config.mute);
+        }
+        Some(Verdict { pattern, action, strike })
+    }
+
+    fn rule(&self, pattern: Pattern) -> &SpamRule {
+        match pattern {
+            Pattern::Repeats => &self.config.repeats,
+            Pattern::Mentions => &self.config.mentions,
+            Pattern::Dms => &self.config.dms,
+        }
+    }
+}
+
+fn forget(recent: &mut VecDeque<(Instant, String)>, now: Instant, window: Duration) {
+    while recent.front().is_some_and(|(at, _)| now.duration_since(*at) >= window) {
+        recent.pop_front();
+    }
+}
+
+/// Case and spacing don't make a message different.
+fn normalize(body: &str) -> String {
+    body.split_whitespace().map(str::to_lowercase).collect::<Vec<_>>().join(" ")
+}
+
+/// The distinct words in `body` that could be nicknames, `@` or not.
+pub fn named(body: &str) -> Vec<&str> {
+    let mut words: Vec<&str> = body
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .map(|w| w.trim_start_matches('@').trim_end_matches([':', '.', '!', '?']))
+        .filter(|w| !w.is_empty() && !w.starts_with('#'))
+        .collect();
+    words.sort_unstable();
+    words.dedup();
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spam() -> Spam {
+        let rule = |limit, actions: &[Action]| SpamRule { limit, window: Duration::from_secs(60), actions: actions.to_vec() };
+        Spam::new(SpamConfig {
+            enabled: true,
+            repeats: rule(3, &[Action::Warn, Action::Mute, Action::Kick]),
+            mentions: rule(4, &[Action::Mute]),
+            dms: rule(3, &[Action::Warn, Action::Kick]),
+            mute: Duration::from_secs(30),
+            forgive: Duration::from_secs(600),
+        })
+    }
+
+    #[test]
+    fn escalates_on_repeats() {
+        let (s, now) = (spam(), Instant::now());
+        let send = |body: &str, at: Instant| s.check("bob", To::Channel, body, 0, at).map(|v| v.action);
+        assert_eq!(send("buy now", now), None);
+        assert_eq!(send("BUY  now", now), None);
+        assert_eq!(send("hello", now), None);
+        assert_eq!(send("buy now", now), Some(Action::Warn));
+        // A tripped limit starts counting again.
+        assert_eq!(send("buy now", now), None);
+        assert_eq!(send("buy now", now), None);
+        assert_eq!(send("buy now", now), Some(Action::Mute));
+        assert_eq!(s.muted("bob", now + Duration::from_secs(10)), Some(Duration::from_secs(20)));
+        assert_eq!(s.muted("bob", now + Duration::from_secs(30)), None);
+        // Outside the window they don't add up.
+        let later = now + Duration::from_secs(100);
+        assert_eq!(send("buy now", later), None);
+        assert_eq!(send("buy now", later + Duration::from_secs(61)), None);
+        // Strikes wear off.
+        let much_later = now + Duration::from_secs(1000);
+        for _ in 0..2 {
+            assert_eq!(send("again", much_later), None);
+        }
+        assert_eq!(s.check("bob", To::Channel, "again", 0, much_later), Some(Verdict { pattern: Pattern::Repeats, action: Action::Warn, strike: 1 }));
+    }
+
+    #[test]
+    fn catches_crowds() {
+        let (s, now) = (spam(), Instant::now());
+        assert_eq!(s.check("bob", To::Channel, "hi all", 3, now), None);
+        assert_eq!(s.check("bob", To::Channel, "hi all!", 4, now).unwrap().action, Action::Mute);
+        assert!(s.muted("bob", now).is_some());
+
+        for target in ["a", "b", "a"] {
+            assert_eq!(s.check("eve", To::Dm(target), target, 0, now), None);
+        }
+        assert_eq!(s.check("eve", To::Dm("c"), "x", 0, now).unwrap().pattern, Pattern::Dms);
+        for target in ["d", "e"] {
+            assert_eq!(s.check("eve", To::Dm(target), target, 0, now), None);
+        }
+        // The last action repeats.
+        assert_eq!(s.check("eve", To::Dm("f"), "x", 0, now).unwrap().action, Action::Kick);
+        assert_eq!(s.check("eve", To::Dm("g"), "x", 0, now), None);
+
+        assert_eq!(named("@alice, bob: hi alice #rust"), ["alice", "bob", "hi"]);
+    }
+}
@@ -0,0 +1,170 @@
+//! Slows down password guessing. Each failed login, a wrong `PASS` or
+//! two-factor code, counts against the nickname and against the address it
+//! came from, and the answer to it waits twice as long as the one before,
+//! up to `MAX_DELAY`. Enough failures lock the nickname or the address out
+//! for a while, right password or not; `UNLOCK` lets admin end that early.
+//! Counts are forgotten after a lockout period without failures, and a
+//! nickname's after a successful login.
+
+use parking_lot::Mutex;
+use std::{collections::HashMap, fmt, net::IpAddr};
+use tokio::time::{Duration, Instant};
+
+use crate::{config::LoginConfig, info};
+
+/// Longest a failed login's answer waits.
+const MAX_DELAY: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Name(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Name(name) => f.write_str(name),
+            Key::Ip(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+struct Record {
+    failures: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// A login refused without checking the password.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Locked {
+    /// The nickname or address.
+    pub what: String,
+    pub left: Duration,
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many failed logins for {}; try again in {}s", self.what, self.left.as_secs().max(1))
+    }
+}
+
+pub struct Throttle {
+    config: LoginConfig,
+    records: Mutex<HashMap<Key, Record>>,
+}
+
+impl Throttle {
+    pub fn new(config: LoginConfig) -> Self {
+        Throttle { config, records: Mutex::default() }
+    }
+
+    /// Whether `name` may try logging in from `ip` at all.
+    pub fn check(&self, name: &str, ip: IpAddr, now: Instant) -> Result<(), Locked> {
+        let mut records = self.records.lock();
+        self.forget_old(&mut records, now);
+        for key in [Key::Name(name.to_string()), Key::Ip(ip)] {
+            if let Some(until) = records.get(&key).and_then(|r| r.locked_until).filter(|&until| until > now) {
+                return Err(Locked { what: key.to_string(), left: until - now });
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a failed login, returning how long to wait before saying so.
+    pub fn failed(&self, name: &str, ip: IpAddr, now: Instant) -> Duration {
+        let mut records = self.records.lock();
+        self.forget_old(&mut records, now);
+        let mut worst = 0;
+        for (key, limit) in [(Key::Name(name.to_string()), self.config.max_failures), (Key::Ip(ip), self.config.max_failures_per_ip)] {
+            let record = records.entry(key.clone()).or_insert(Record { failures: 0, last: now, locked_until: None });
+            record.failures += 1;
+            record.last = now;
+            if record.failures >= limit && record.locked_until.is_none_or(|until| until <= now) {
+                record.locked_until = Some(now + self.config.lockout);
+                info!("LOGIN", "locked out {key} for {}s after {} failed logins", self.config.lockout.as_secs(), record.failures);
+            }
+            worst = worst.max(record.failures);
+        }
+        let doublings = worst.saturating_sub(1).min(16);
+        self.config.failure_delay.saturating_mul(1 << doublings).min(MAX_DELAY)
+    }
+
+    /// Forgets `name`'s failures after they got in. The address's stay, so
+    /// logging in to one account can't clear guesses at others.
+    pub fn succeeded(&self, name: &str) {
+        self.records.lock().remove(&Key::Name(name.to_string()));
+    }
+
+    /// Ends the lockout of a nickname or address, and forgets its failures;
+    /// `false` if it had none.
+    pub fn unlock(&self, what: &str) -> bool {
+        let key = match what.parse() {
+            Ok(ip) => Key::Ip(ip),
+            Err(_) => Key::Name(what.to_string()),
+        };
+        self.records.lock().remove(&key).is_some()
+    }
+
+    /// The nicknames and addresses locked out now.
+    pub fn locked(&self, now: Instant) -> Vec<Locked> {
+        let mut records = self.records.lock();
+        self.forget_old(&mut records, now);
+        let mut locked: Vec<_> = records
+            .iter()
+            .filter_map(|(key, r)| r.locked_until.filter(|&until| until > now).map(|until| Locked { what: key.to_string(), left: until - now }))
+            .collect();
+        locked.sort_by(|a, b| a.what.cmp(&b.what));
+        locked
+    }
+
+    fn forget_old(&self, records: &mut HashMap<Key, Record>, now: Instant) {
+        records.retain(|_, r| r.locked_until.is_some_and(|until| until > now) || now.duration_since(r.last) < self.config.lockout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> Throttle {
+        Throttle::new(LoginConfig { max_failures: 3, max_failures_per_ip: 5, lockout: Duration::from_secs(60), failure_delay: Duration::from_millis(500) })
+    }
+
+    #[test]
+    fn backs_off_then_locks_out() {
+        let t = throttle();
+        let (ip, now) = (IpAddr::from([192, 0, 2, 1]), Instant::now());
+        assert_eq!(t.failed("alice", ip, now), Duration::from_millis(500));
+        assert_eq!(t.failed("alice", ip, now), Duration::from_secs(1));
+        assert!(t.check("alice", ip, now).is_ok());
+        assert_eq!(t.failed("alice", ip, now), Duration::from_secs(2));
+        let locked = t.check("alice", IpAddr::from([192, 0, 2, 9]), now).unwrap_err();
+        assert_eq!(locked, Locked { what: "alice".into(), left: Duration::from_secs(60) });
+
+        // Guessing at other names from the same address runs into its limit.
+        t.failed("bob", ip, now);
+        t.failed("carol", ip, now);
+        assert_eq!(t.check("dave", ip, now).unwrap_err().what, "192.0.2.1");
+        assert_eq!(t.locked(now).iter().map(|l| l.what.as_str()).collect::<Vec<_>>(), ["192.0.2.1", "alice"]);
+
+        // Lockouts end by themselves, or when admin says so.
+        assert!(t.unlock("192.0.2.1"));
+        assert!(!t.unlock("192.0.2.1"));
+        assert!(t.check("dave", ip, now).is_ok());
+        assert!(t.check("alice", ip, now + Duration::from_secs(61)).is_ok());
+    }
+
+    #[test]
+    fn forgets_failures() {
+        let t = throttle();
+        let (ip, now) = (IpAddr::from([192, 0, 2, 1]), Instant::now());
+        t.failed("alice", ip, now);
+        t.failed("alice", ip, now);
+        t.succeeded("alice");
+        // The address's count stays.
+        assert_eq!(t.failed("alice", ip, now), Duration::from_secs(2));
+        assert_eq!(t.failed("alice", ip, now + Duration::from_secs(120)), Duration::from_millis(500));
+    }
+}
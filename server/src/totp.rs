@@ -0,0 +1,162 @@
+//! Time-based one-time passwords (RFC 6238) as authenticator apps make them:
+//! HMAC-SHA1, 30-second steps, six digits. Secrets are shown and kept in
+//! unpadded base32, the form apps take them in.
+
+/// Seconds each code is good for.
+pub const STEP: u64 = 30;
+pub const DIGITS: u32 = 6;
+/// Bytes of secret, as RFC 4226 recommends.
+pub const SECRET_LEN: usize = 20;
+/// Steps either side of now whose codes are still taken, for clock drift.
+const SKEW: u64 = 1;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// The code for `secret` in step `counter`.
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mac = hmac_sha1(secret, &counter.to_be_bytes());
+    let at = usize::from(mac[19] & 0xf);
+    let n = u32::from_be_bytes(mac[at..at + 4].try_into().unwrap()) & 0x7fff_ffff;
+    n % 10u32.pow(DIGITS)
+}
+
+/// The step `code` is right for at `now` (Unix seconds), if it's right for
+/// one close enough.
+pub fn check(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let step = now / STEP;
+    (step.saturating_sub(SKEW)..=step + SKEW).find(|&s| hotp(secret, s) == code)
+}
+
+/// An `otpauth://` URI for `name`'s secret, for apps to read from a QR code.
+pub fn uri(issuer: &str, name: &str, secret: &[u8]) -> String {
+    let label = escape(&format!("{issuer}:{name}"));
+    format!("otpauth://totp/{label}?secret={}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={STEP}", base32(secret), escape(issuer))
+}
+
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+pub fn base32(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buf, mut bits) = (0u32, 0);
+    for &b in bytes {
+        buf = (buf << 8) | u32::from(b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buf >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buf << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+pub fn parse_base32(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buf, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())? as u32;
+        buf = (buf << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..20].copy_from_slice(&sha1(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = sha1(&[pad(0x36), message.to_vec()].concat());
+    sha1(&[pad(0x5c), inner.to_vec()].concat())
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0; 20];
+    for (bytes, word) in out.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_rfcs() {
+        let hex = |bytes: [u8; 20]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(hex(sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(hmac_sha1(b"key", b"The quick brown fox jumps over the lazy dog")), "de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9");
+        // RFC 6238's SHA-1 vectors, cut to six digits.
+        let secret = b"12345678901234567890";
+        for (time, code) in [(59, 287082), (1111111109, 81804), (1234567890, 5924), (2000000000, 279037)] {
+            assert_eq!(hotp(secret, time / STEP), code, "{time}");
+        }
+        assert_eq!(check(secret, "287082", 59), Some(1));
+        assert_eq!(check(secret, "287082", 59 + STEP), Some(1));
+        assert_eq!(check(secret, "287082", 59 + 2 * STEP), None);
+        assert_eq!(check(secret, "28708", 59), None);
+    }
+
+    #[test]
+    fn encodes_secrets() {
+        assert_eq!(base32(b"12345678901234567890"), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(parse_base32("gezdgnbvgy3tqojqgezdgnbvgy3tqojq").as_deref(), Some(b"12345678901234567890".as_slice()));
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(parse_base32("0"), None);
+        assert_eq!(
+            uri("rustchat", "alice smith", b"12345678901234567890"),
+            "otpauth://totp/rustchat:alice%20smith?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=rustchat&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Optional tracing of where the server's time goes, exported over OTLP/HTTP
+//! as JSON to a collector such as Jaeger or Tempo (`[tracing] otlp_url`,
+//! e.g. `http://localhost:4318/v1/traces`). Each connection is a trace, with
+//! its handshake as a span; each command is a trace of its own, with spans
+//! for the storage it touches, so a message's latency can be broken down.
+//! Finished spans are batched and sent every `export_interval`; a batch the
+//! collector doesn't take is dropped rather than kept.
+
+use parking_lot::Mutex;
+use std::{
+    collections::hash_map::RandomState,
+    fmt::{self, Write as _},
+    hash::BuildHasher,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{accounts::hex, config::TracingConfig, http, warn};
+
+/// Finished spans kept between exports; past this they're dropped.
+const MAX_BUFFERED: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Ids {
+    trace: [u8; 16],
+    span: [u8; 8],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finished {
+    ids: Ids,
+    parent: Option<[u8; 8]>,
+    name: String,
+    /// Unix nanoseconds.
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, String)>,
+}
+
+#[derive(Default)]
+pub struct Tracer {
+    config: TracingConfig,
+    spans: Mutex<Vec<Finished>>,
+    /// Seeded from the OS once per process, as invite tokens are.
+    keys: [RandomState; 2],
+    counter: AtomicU64,
+}
+
+impl Tracer {
+    pub fn new(config: TracingConfig) -> Self {
+        Tracer { config, ..Tracer::default() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.otlp_url.is_some()
+    }
+
+    /// Starts a span, in `parent`'s trace or a new one; it ends when dropped.
+    /// Without an OTLP URL it records nothing.
+    pub fn start(&self, name: impl Into<String>, parent: Option<&Span>) -> Span<'_> {
+        if !self.enabled() {
+            return Span { tracer: self, open: None };
+        }
+        let parent = parent.and_then(|p| p.open.as_ref()).map(|p| p.ids);
+        let ids = Ids { trace: parent.map_or_else(|| self.trace_id(), |p| p.trace), span: self.next().to_be_bytes() };
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let open = Open { ids, parent: parent.map(|p| p.span), name: name.into(), start, started: Instant::now(), attributes: Vec::new() };
+        Span { tracer: self, open: Some(open) }
+    }
+
+    fn next(&self) -> u64 {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        self.keys[0].hash_one(n)
+    }
+
+    fn trace_id(&self) -> [u8; 16] {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut id = [0; 16];
+        id[..8].copy_from_slice(&self.keys[0].hash_one(n).to_be_bytes());
+        id[8..].copy_from_slice(&self.keys[1].hash_one(n).to_be_bytes());
+        id
+    }
+
+    /// Sends what's finished every `export_interval`.
+    pub async fn run(self: Arc<Self>) {
+        let Some(url) = self.config.otlp_url.clone() else {
+            return;
+        };
+        let mut ticks = interval(self.config.export_interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let spans = std::mem::take(&mut *self.spans.lock());
+            if spans.is_empty() {
+                continue;
+            }
+            if let Err(e) = http::post_json(&url, &encode(&self.config.service_name, &spans)).await {
+                warn!("TRACE", "dropped {} spans: {e}", spans.len());
+            }
+        }
+    }
+}
+
+struct Open {
+    ids: Ids,
+    parent: Option<[u8; 8]>,
+    name: String,
+    start: u64,
+    started: Instant,
+    attributes: Vec<(&'static str, String)>,
+}
+
+pub struct Span<'a> {
+    tracer: &'a Tracer,
+    open: Option<Open>,
+}
+
+impl Span<'_> {
+    pub fn set(&mut self, key: &'static str, value: impl fmt::Display) {
+        if let Some(open) = &mut self.open {
+            open.attributes.push((key, value.to_string()));
+        }
+    }
+}
+
+impl Drop for Span<'_> {
+    fn drop(&mut self) {
+        let Some(open) = self.open.take() else {
+            return;
+        };
+        let end = open.start + open.started.elapsed().as_nanos() as u64;
+        let mut spans = self.tracer.spans.lock();
+        if spans.len() < MAX_BUFFERED {
+            spans.push(Finished { ids: open.ids, parent: open.parent, name: open.name, start: open.start, end, attributes: open.attributes });
+        }
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` in its JSON form.
+pub fn encode(service: &str, spans: &[Finished]) -> String {
+    let quote = protocol::json::quote;
+    let attribute = |key: &str, value: &str| format!("{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}", quote(key), quote(value));
+    let mut out = format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"rustchat-server\"}},\"spans\":[",
+        attribute("service.name", service)
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"traceId\":\"{}\",\"spanId\":\"{}\",", hex(&span.ids.trace), hex(&span.ids.span));
+        if let Some(parent) = span.parent {
+            let _ = write!(out, "\"parentSpanId\":\"{}\",", hex(&parent));
+        }
+        // Kind 2 is a server span.
+        let _ = write!(out, "\"name\":{},\"kind\":2,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[", quote(&span.name), span.start, span.end);
+        out.push_str(&span.attributes.iter().map(|(k, v)| attribute(k, v)).collect::<Vec<_>>().join(","));
+        out.push_str("]}");
+    }
+    out.push_str("]}]}]}");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpUrl;
+
+    #[test]
+    fn records_spans_in_traces() {
+        let off = Tracer::default();
+        drop(off.start("SAY", None));
+        assert!(off.spans.lock().is_empty());
+
+        let tracer = Tracer::new(TracingConfig { otlp_url: Some(HttpUrl::parse("http://localhost:4318/v1/traces").unwrap()), ..TracingConfig::default() });
+        let mut command = tracer.start("SAY", None);
+        command.set("nick", "alice");
+        drop(tracer.start("schedule.add", Some(&command)));
+        drop(command);
+        drop(tracer.start("TO", None));
+
+        let spans = tracer.spans.lock().clone();
+        let [child, parent, other] = &spans[..] else { panic!("{spans:?}") };
+        assert_eq!((child.ids.trace, child.parent), (parent.ids.trace, Some(parent.ids.span)));
+        assert_ne!(other.ids.trace, parent.ids.trace);
+        assert!(parent.parent.is_none() && parent.start <= child.start && child.end <= parent.end);
+
+        let json = encode("rustchat", &spans[1..2]);
+        let object = protocol::json::parse_object(&json).unwrap();
+        assert!(object.contains_key("resourceSpans"));
+        assert!(json.contains(&format!("\"traceId\":\"{}\"", hex(&parent.ids.trace))));
+        assert!(json.contains("\"name\":\"SAY\"") && json.contains("{\"key\":\"nick\",\"value\":{\"stringValue\":\"alice\"}}"));
+        assert!(!json.contains("parentSpanId"));
+    }
+}
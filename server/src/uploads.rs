@@ -0,0 +1,536 @@
+//! File sharing over HTTP. `UPLOAD` in chat gets a user a token, good for a
+//! while, to `PUT` or `POST` files to `/upload/<name>` with
+//! `Authorization: Bearer <token>`. The answer is a short link, `/f/<id>`,
+//! to paste in a message: anyone with it can `GET` the file, and a message
+//! with one in it is followed by an `ATTACHMENT` event giving the file's name
+//! and size.
+//!
+//! Files are kept in `[uploads] dir` under their ID, and `index.tsv` there
+//! lists each one's owner, size and name, so quotas survive restarts. Names
+//! are only ever metadata; nothing on disk is named after them. A file is
+//! written to `<id>.part` as it arrives and only renamed to its ID, and
+//! indexed, once it's all there and synced.
+
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration, Instant},
+};
+
+use crate::{at_rest, config::UploadConfig, info, preview, random, warn};
+
+const INDEX: &str = "index.tsv";
+const HEADER: &str = "# rustchat uploads v1: id, owner, size, name";
+/// Longest request line and headers accepted.
+const MAX_HEAD: usize = 8 * 1024;
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// For the body, however large it is.
+const BODY_TIMEOUT: Duration = Duration::from_secs(120);
+/// Longest file name, in characters.
+const MAX_NAME: usize = 100;
+
+/// An uploaded file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stored {
+    pub owner: String,
+    pub size: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadDenied {
+    Unauthorized,
+    BadName,
+    /// The most a file may be.
+    TooLarge(u64),
+    /// What's left of the user's quota.
+    OverQuota(u64),
+}
+
+impl UploadDenied {
+    fn status(self) -> &'static str {
+        match self {
+            UploadDenied::Unauthorized => "401 Unauthorized",
+            UploadDenied::BadName => "400 Bad Request",
+            UploadDenied::TooLarge(_) => "413 Content Too Large",
+            UploadDenied::OverQuota(_) => "507 Insufficient Storage",
+        }
+    }
+}
+
+impl fmt::Display for UploadDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadDenied::Unauthorized => write!(f, "missing or expired token: ask for one with UPLOAD"),
+            UploadDenied::BadName => write!(f, "file names are 1-{MAX_NAME} characters, without slashes or control characters"),
+            UploadDenied::TooLarge(max) => write!(f, "files are at most {max} bytes"),
+            UploadDenied::OverQuota(left) => write!(f, "that's over your quota: {left} bytes left"),
+        }
+    }
+}
+
+pub struct Uploads {
+    config: UploadConfig,
+    /// `http://host:port`, which links start with.
+    base: String,
+    /// Owner and expiry by token.
+    tokens: Mutex<HashMap<String, (String, Instant)>>,
+    files: Mutex<HashMap<String, Stored>>,
+}
+
+impl Uploads {
+    /// Uploads served from `host`, starting with the files already in the
+    /// directory if they're enabled.
+    pub fn load(config: UploadConfig, host: IpAddr) -> Result<Self> {
+        let mut files = HashMap::new();
+        if config.enabled {
+            fs::create_dir_all(&config.dir).with_context(|| format!("can't create {}", config.dir.display()))?;
+            let index = config.dir.join(INDEX);
+            match fs::read_to_string(&index) {
+                Ok(text) => {
+                    for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.starts_with('#') && !l.is_empty()) {
+                        let (id, stored) = parse_line(line).ok_or_else(|| anyhow!("{}:{}: not an upload", index.display(), n + 1))?;
+                        files.insert(id, stored);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => bail!("failed to read {}: {e}", index.display()),
+            }
+        }
+        let base = format!("http://{}", std::net::SocketAddr::new(host, config.port));
+        Ok(Uploads { config, base, tokens: Mutex::default(), files: Mutex::new(files) })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Where files are uploaded to; the name goes on the end.
+    pub fn upload_url(&self) -> String {
+        format!("{}/upload/", self.base)
+    }
+
+    pub fn quota(&self) -> u64 {
+        self.config.quota_bytes
+    }
+
+    pub fn token_ttl(&self) -> Duration {
+        self.config.token_ttl
+    }
+
+    /// A fresh token for `owner` to upload with.
+    pub fn grant(&self, owner: &str) -> String {
+        let token = random::hex::<16>();
+        let mut tokens = self.tokens.lock();
+        let now = Instant::now();
+        tokens.retain(|_, (_, expires)| *expires > now);
+        tokens.insert(token.clone(), (owner.to_string(), now + self.config.token_ttl));
+        token
+    }
+
+    /// Bytes `owner` has stored.
+    pub fn used(&self, owner: &str) -> u64 {
+        self.files.lock().values().filter(|f| f.owner == owner).map(|f| f.size).sum()
+    }
+
+    /// The first link in `text` to an uploaded file, and the file.
+    pub fn attachment(&self, text: &str) -> Option<(String, Stored)> {
+        let prefix = format!("{}/f/", self.base);
+        let files = self.files.lock();
+        preview::urls(text).find_map(|url| Some((url.to_string(), files.get(url.strip_prefix(&prefix)?)?.clone())))
+    }
+
+    /// `owner`'s files, by ID.
+    pub fn owned_by(&self, owner: &str) -> Vec<(String, Stored)> {
+        let mut owned: Vec<_> = self.files.lock().iter().filter(|(_, f)| f.owner == owner).map(|(id, f)| (id.clone(), f.clone())).collect();
+        owned.sort_by(|a, b| a.0.cmp(&b.0));
+        owned
+    }
+
+    /// Deletes everything `owner` uploaded and revokes their tokens,
+    /// returning how many files went.
+    pub fn purge(&self, owner: &str) -> Result<usize> {
+        self.tokens.lock().retain(|_, (holder, _)| holder != owner);
+        let mut files = self.files.lock();
+        let ids: Vec<String> = files.iter().filter(|(_, f)| f.owner == owner).map(|(id, _)| id.clone()).collect();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        files.retain(|_, f| f.owner != owner);
+        // Kept from the index rather than `files`, which also holds uploads
+        // still on their way in.
+        let index = self.config.dir.join(INDEX);
+        let text = match fs::read_to_string(&index) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => bail!("failed to read {}: {e}", index.display()),
+        };
+        let mut kept = format!("{HEADER}\n");
+        for line in text.lines().filter(|l| !l.starts_with('#') && !l.is_empty()) {
+            if parse_line(line).is_some_and(|(_, stored)| stored.owner != owner) {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        at_rest::replace(&index, kept.as_bytes()).with_context(|| format!("can't write {}", index.display()))?;
+        drop(files);
+        for id in &ids {
+            if let Err(e) = fs::remove_file(self.config.dir.join(id)) {
+                warn!("UPLOAD", "can't delete {id}: {e}");
+            }
+        }
+        Ok(ids.len())
+    }
+
+    /// Who `token` belongs to, if it's still good.
+    fn owner(&self, token: &str) -> Result<String, UploadDenied> {
+        match self.tokens.lock().get(token) {
+            Some((owner, expires)) if *expires > Instant::now() => Ok(owner.clone()),
+            _ => Err(UploadDenied::Unauthorized),
+        }
+    }
+
+    /// Makes room for `owner` to store `size` bytes as `name`, returning the
+    /// file's new ID. Counting it now keeps two uploads at once from both
+    /// fitting in the same space.
+    fn reserve(&self, owner: &str, name: &str, size: u64) -> Result<String, UploadDenied> {
+        if size > self.config.max_file_bytes {
+            return Err(UploadDenied::TooLarge(self.config.max_file_bytes));
+        }
+        let id = random::hex::<8>();
+        let mut files = self.files.lock();
+        let used: u64 = files.values().filter(|f| f.owner == owner).map(|f| f.size).sum();
+        let left = self.config.quota_bytes.saturating_sub(used);
+        if size > left {
+            return Err(UploadDenied::OverQuota(left));
+        }
+        files.insert(id.clone(), Stored { owner: owner.to_string(), size, name: name.to_string() });
+        Ok(id)
+    }
+
+    /// Where the file reserved as `id` is written while it's on its way in.
+    fn partial(&self, id: &str) -> PathBuf {
+        self.config.dir.join(format!("{id}.part"))
+    }
+
+    /// Moves the file reserved as `id` into place from `partial` and adds it
+    /// to the index, both synced to disk; on failure the space is given back.
+    async fn store(&self, id: &str) -> Result<()> {
+        let file = self.config.dir.join(id);
+        let written = async {
+            tokio::fs::rename(self.partial(id), &file).await?;
+            at_rest::sync_dir(&file)?;
+            // Held while appending so a purge can't rewrite the index under us.
+            let files = self.files.lock();
+            let Some(stored) = files.get(id) else {
+                bail!("upload {id} went missing");
+            };
+            let index = self.config.dir.join(INDEX);
+            let mut out = fs::OpenOptions::new().create(true).append(true).open(&index)?;
+            let created = out.metadata()?.len() == 0;
+            if created {
+                writeln!(out, "{HEADER}")?;
+            }
+            writeln!(out, "{id}\t{}\t{}\t{}", stored.owner, stored.size, stored.name)?;
+            out.sync_all()?;
+            if created {
+                at_rest::sync_dir(&index)?;
+            }
+            Ok(())
+        }
+        .await;
+        if written.is_err() {
+            self.files.lock().remove(id);
+            tokio::fs::remove_file(&file).await.ok();
+        }
+        written
+    }
+
+    /// Answers HTTP requests on `listener` for as long as the server runs.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("UPLOAD", "accept failed: {e}");
+                    continue;
+                }
+            };
+            let uploads = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = uploads.handle(stream).await {
+                    warn!("UPLOAD", "{addr}: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let (head, rest) = timeout(HEAD_TIMEOUT, read_head(&mut stream)).await.map_err(|_| anyhow!("timed out"))??;
+        let Some(request) = Request::parse(&head) else {
+            return respond(&mut stream, "400 Bad Request", "", b"bad request\n").await;
+        };
+        match request.method {
+            "PUT" | "POST" if request.path.starts_with("/upload/") => self.receive(&mut stream, &request, rest).await,
+            "GET" | "HEAD" if request.path.starts_with("/f/") => self.send(&mut stream, &request).await,
+            _ => respond(&mut stream, "404 Not Found", "", b"not found\n").await,
+        }
+    }
+
+    async fn receive(&self, stream: &mut TcpStream, request: &Request<'_>, mut rest: Vec<u8>) -> Result<()> {
+        let token = request.header("authorization").and_then(|a| a.strip_prefix("Bearer ")).unwrap_or_default();
+        let Some(len) = request.header("content-length").and_then(|l| l.parse::<u64>().ok()) else {
+            return respond(stream, "411 Length Required", "", b"uploads need a Content-Length\n").await;
+        };
+        let reserved = self.owner(token).and_then(|owner| {
+            let name = file_name(&request.path["/upload/".len()..]).ok_or(UploadDenied::BadName)?;
+            Ok((self.reserve(&owner, &name, len)?, owner, name))
+        });
+        let (id, owner, name) = match reserved {
+            Ok(reserved) => reserved,
+            Err(denied) => return respond(stream, denied.status(), "", format!("{denied}\n").as_bytes()).await,
+        };
+        // Straight to disk, so a large file isn't held in memory.
+        let partial = self.partial(&id);
+        let read = async {
+            if request.header("expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) {
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+            }
+            rest.truncate(len as usize);
+            let mut out = tokio::fs::File::create(&partial).await?;
+            out.write_all(&rest).await?;
+            let left = len - rest.len() as u64;
+            if tokio::io::copy(&mut (&mut *stream).take(left), &mut out).await? < left {
+                bail!("closed before the body was complete");
+            }
+            out.sync_all().await?;
+            anyhow::Ok(())
+        };
+        let stored = match timeout(BODY_TIMEOUT, read).await {
+            Ok(Ok(())) => self.store(&id).await,
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(anyhow!("timed out")),
+        };
+        if let Err(e) = stored {
+            // A failed read leaves the reservation and the partial file; a
+            // failed store has already cleaned up.
+            self.files.lock().remove(&id);
+            tokio::fs::remove_file(&partial).await.ok();
+            respond(stream, "500 Internal Server Error", "", b"upload failed\n").await.ok();
+            bail!("upload of {name:?} by {owner} failed: {e}");
+        }
+        info!("UPLOAD", "{owner} uploaded {name:?} ({len} bytes) as {id}");
+        respond(stream, "201 Created", "", format!("{}/f/{id}\n", self.base).as_bytes()).await
+    }
+
+    async fn send(&self, stream: &mut TcpStream, request: &Request<'_>) -> Result<()> {
+        let id = &request.path["/f/".len()..];
+        let stored = self.files.lock().get(id).cloned();
+        let Some(stored) = stored else {
+            return respond(stream, "404 Not Found", "", b"not found\n").await;
+        };
+        let body = if request.method == "HEAD" { Vec::new() } else { tokio::fs::read(self.config.dir.join(id)).await? };
+        let headers = format!(
+            "Content-Type: application/octet-stream\r\nContent-Disposition: attachment; filename=\"{}\"\r\nX-Content-Type-Options: nosniff\r\n",
+            stored.name.replace(['"', '\\'], "_")
+        );
+        respond_sized(stream, "200 OK", &headers, stored.size, &body).await
+    }
+}
+
+/// The size of a file in words.
+pub fn size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+struct Request<'a> {
+    method: &'a str,
+    path: String,
+    head: &'a str,
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> Option<Self> {
+        let mut parts = head.lines().next()?.split(' ');
+        let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+        if !version.starts_with("HTTP/1.") {
+            return None;
+        }
+        // Any query is ignored.
+        let path = target.split('?').next()?.to_string();
+        Some(Request { method, path, head })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// The request line and headers, and whatever of the body came with them.
+async fn read_head(stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((String::from_utf8(buf)?, rest));
+        }
+        if buf.len() > MAX_HEAD {
+            bail!("headers too long");
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("closed before the request was complete");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, headers: &str, body: &[u8]) -> Result<()> {
+    let headers = format!("Content-Type: text/plain; charset=utf-8\r\n{headers}");
+    respond_sized(stream, status, &headers, body.len() as u64, body).await
+}
+
+async fn respond_sized(stream: &mut TcpStream, status: &str, headers: &str, len: u64, body: &[u8]) -> Result<()> {
+    let head = format!("HTTP/1.1 {status}\r\n{headers}Content-Length: {len}\r\nConnection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    Ok(())
+}
+
+/// The name at the end of an upload URL, percent-decoded, if it's usable.
+fn file_name(raw: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut it = raw.bytes();
+    while let Some(b) = it.next() {
+        if b == b'%' {
+            let hex = [it.next()?, it.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    let name = String::from_utf8(bytes).ok()?;
+    let name = name.trim();
+    let usable = (1..=MAX_NAME).contains(&name.chars().count()) && !name.starts_with('.') && !name.contains(['/', '\\']) && !name.contains(char::is_control);
+    usable.then(|| name.to_string())
+}
+
+fn parse_line(line: &str) -> Option<(String, Stored)> {
+    let mut fields = line.splitn(4, '\t');
+    let (id, owner, size, name) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((id.to_string(), Stored { owner: owner.to_string(), size: size.parse().ok()?, name: name.to_string() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str) -> UploadConfig {
+        let dir = std::env::temp_dir().join(format!("rustchat-uploads-test-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        UploadConfig { enabled: true, port: 0, dir, max_file_bytes: 100, quota_bytes: 150, token_ttl: Duration::from_secs(60) }
+    }
+
+    #[test]
+    fn checks_names() {
+        assert_eq!(file_name("holiday%20photo.jpg").as_deref(), Some("holiday photo.jpg"));
+        assert_eq!(file_name("caf%C3%A9.txt").as_deref(), Some("café.txt"));
+        for bad in ["", "%2e%2e%2fetc", "a%2Fb", ".hidden", "a%0Ab", "%zz", &"x".repeat(MAX_NAME + 1)] {
+            assert_eq!(file_name(bad), None, "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_to_quotas_across_restarts() {
+        let config = config("quota");
+        let uploads = Uploads::load(config.clone(), "127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(uploads.reserve("alice", "big", 101), Err(UploadDenied::TooLarge(100)));
+        let id = uploads.reserve("alice", "notes.txt", 100).unwrap();
+        fs::write(uploads.partial(&id), [b'x'; 100]).unwrap();
+        uploads.store(&id).await.unwrap();
+        assert!(!uploads.partial(&id).exists() && config.dir.join(&id).exists());
+        assert_eq!(uploads.reserve("alice", "more", 60), Err(UploadDenied::OverQuota(50)));
+        assert!(uploads.reserve("bob", "his", 60).is_ok());
+
+        let link = format!("see http://127.0.0.1:0/f/{id}.");
+        let (url, stored) = uploads.attachment(&link).unwrap();
+        assert_eq!(url, format!("http://127.0.0.1:0/f/{id}"));
+        assert_eq!(stored, Stored { owner: "alice".into(), size: 100, name: "notes.txt".into() });
+        assert_eq!(uploads.attachment("http://127.0.0.1:0/f/0000"), None);
+
+        // Bob's reservation was never stored, so it's gone after a restart.
+        let reloaded = Uploads::load(config.clone(), "127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!((reloaded.used("alice"), reloaded.used("bob")), (100, 0));
+        assert_eq!(reloaded.owned_by("alice"), vec![(id.clone(), stored)]);
+
+        let token = reloaded.grant("alice");
+        assert_eq!(reloaded.purge("alice").unwrap(), 1);
+        assert_eq!(reloaded.owner(&token), Err(UploadDenied::Unauthorized));
+        assert!(!config.dir.join(&id).exists());
+        let reloaded = Uploads::load(config.clone(), "127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(reloaded.used("alice"), 0);
+        fs::remove_dir_all(&config.dir).ok();
+    }
+
+    #[tokio::test]
+    async fn uploads_and_serves_files() {
+        let config = config("http");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let uploads = Arc::new(Uploads::load(UploadConfig { port: addr.port(), ..config.clone() }, addr.ip()).unwrap());
+        tokio::spawn(uploads.clone().serve(listener));
+        let exchange = |request: String| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let put = |token: &str, body: &str| format!("PUT /upload/a%20b.txt HTTP/1.1\r\nAuthorization: Bearer {token}\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+        assert!(exchange(put("nope", "hello")).await.starts_with("HTTP/1.1 401"));
+        let token = uploads.grant("alice");
+        let response = exchange(put(&token, "hello")).await;
+        assert!(response.starts_with("HTTP/1.1 201"), "{response}");
+        let link = response.lines().last().unwrap().to_string();
+        assert!(link.starts_with(&format!("http://{addr}/f/")));
+        assert!(exchange(put(&token, &"x".repeat(101))).await.starts_with("HTTP/1.1 413"));
+
+        let path = link.split_once(&addr.to_string()).unwrap().1;
+        let response = exchange(format!("GET {path} HTTP/1.1\r\n\r\n")).await;
+        assert!(response.contains("filename=\"a b.txt\"") && response.ends_with("\r\n\r\nhello"), "{response}");
+        assert!(exchange("GET /f/0000 HTTP/1.1\r\n\r\n".into()).await.starts_with("HTTP/1.1 404"));
+
+        // A body cut short leaves nothing behind, on disk or in the quota.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("PUT /upload/cut.txt HTTP/1.1\r\nAuthorization: Bearer {token}\r\nContent-Length: 50\r\n\r\nhello").as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        assert_eq!(uploads.used("alice"), 5);
+        assert!(!fs::read_dir(&config.dir).unwrap().any(|e| e.unwrap().path().extension().is_some_and(|x| x == "part")));
+        fs::remove_dir_all(&config.dir).ok();
+    }
+}
@@ -0,0 +1,610 @@
+//! Chat connections' accept, read and write through io_uring, for the
+//! `uring` feature on Linux. One ring is shared by every connection: tasks
+//! submit from whichever worker they run on, and a thread of its own reaps
+//! completions and hands each to the task waiting on it. The kernel owns a
+//! buffer from submission to completion, so buffers live with the driver
+//! until then and an operation whose future is dropped is cancelled rather
+//! than forgotten.
+//!
+//! There's no crate for this offline, so the ring is set up by hand from the
+//! kernel's ABI; `Ring::new` fails on kernels without io_uring (before 5.6,
+//! or with it turned off) and the server goes back to tokio's sockets.
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::oneshot,
+};
+
+use crate::transport;
+
+const OP_NOP: u8 = 0;
+const OP_ACCEPT: u8 = 13;
+const OP_ASYNC_CANCEL: u8 = 14;
+const OP_SEND: u8 = 26;
+const OP_RECV: u8 = 27;
+
+const ENTER_GETEVENTS: u32 = 1;
+const OFF_SQ_RING: i64 = 0;
+const OFF_CQ_RING: i64 = 0x800_0000;
+const OFF_SQES: i64 = 0x1000_0000;
+
+/// `user_data` of completions nobody waits for.
+const CANCELLED: u64 = 0;
+const STOP: u64 = 1;
+
+/// Bytes asked for by each read; one such buffer is held per connection
+/// while it waits for something to arrive.
+const READ_SIZE: usize = 4096;
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A shared mapping of one of the ring's regions.
+struct Map {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Map {
+    fn new(fd: &OwnedFd, len: usize, offset: i64) -> io::Result<Map> {
+        // SAFETY: a fresh shared mapping of the ring fd, at an offset the
+        // kernel defines, that nothing else aliases.
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd.as_raw_fd(), offset) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Map { ptr: ptr.cast(), len })
+    }
+
+    /// The field `offset` bytes in, which the kernel put there.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: every offset comes from the kernel's `Params` and is
+        // inside the length it gave for the region.
+        unsafe { self.ptr.add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `new`, and only the driver points into it.
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+struct Sq {
+    head: *const AtomicU32,
+    tail: *const AtomicU32,
+    mask: u32,
+    array: *mut u32,
+    sqes: *mut Sqe,
+}
+
+struct Cq {
+    head: *const AtomicU32,
+    tail: *const AtomicU32,
+    mask: u32,
+    cqes: *const Cqe,
+}
+
+/// An operation the kernel hasn't finished with.
+struct Pending {
+    done: Option<oneshot::Sender<(i32, Vec<u8>)>>,
+    /// Where the kernel reads or writes until it completes.
+    buf: Vec<u8>,
+    accept: bool,
+}
+
+struct Driver {
+    fd: OwnedFd,
+    sq: Mutex<Sq>,
+    /// Only the reaping thread touches it.
+    cq: Cq,
+    pending: Mutex<HashMap<u64, Pending>>,
+    next_id: AtomicU64,
+    stopping: AtomicBool,
+    _maps: [Map; 3],
+}
+
+// SAFETY: the raw pointers all point into `_maps`, which live as long as the
+// driver; the submission side is only used under its mutex and the
+// completion side only from the reaping thread.
+unsafe impl Send for Driver {}
+unsafe impl Sync for Driver {}
+
+fn enter(fd: &OwnedFd, to_submit: u32, min_complete: u32, flags: u32) -> io::Result<u32> {
+    loop {
+        // SAFETY: no signal mask is passed, so the last two arguments are unused.
+        let n = unsafe { libc::syscall(libc::SYS_io_uring_enter, fd.as_raw_fd(), to_submit, min_complete, flags, ptr::null::<u8>(), 0usize) };
+        if n >= 0 {
+            return Ok(n as u32);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => {}
+            // Completions are backed up; the reaper will catch up.
+            Some(libc::EAGAIN | libc::EBUSY) => std::thread::yield_now(),
+            _ => return Err(e),
+        }
+    }
+}
+
+impl Driver {
+    fn new(entries: u32) -> io::Result<Driver> {
+        let mut params = Params::default();
+        // SAFETY: `params` is the struct the kernel expects, and it outlives the call.
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: a new fd that nothing else owns.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let sq_map = Map::new(&fd, sq_len, OFF_SQ_RING)?;
+        let cq_map = Map::new(&fd, cq_len, OFF_CQ_RING)?;
+        let sqe_map = Map::new(&fd, params.sq_entries as usize * size_of::<Sqe>(), OFF_SQES)?;
+        // SAFETY: the masks are plain values the kernel wrote before returning.
+        let (sq_mask, cq_mask) = unsafe { (*sq_map.at::<u32>(params.sq_off.ring_mask), *cq_map.at::<u32>(params.cq_off.ring_mask)) };
+        let sq = Sq { head: sq_map.at(params.sq_off.head), tail: sq_map.at(params.sq_off.tail), mask: sq_mask, array: sq_map.at(params.sq_off.array), sqes: sqe_map.at(0) };
+        let cq = Cq { head: cq_map.at(params.cq_off.head), tail: cq_map.at(params.cq_off.tail), mask: cq_mask, cqes: cq_map.at(params.cq_off.cqes) };
+        Ok(Driver { fd, sq: Mutex::new(sq), cq, pending: Mutex::new(HashMap::new()), next_id: AtomicU64::new(STOP + 1), stopping: AtomicBool::new(false), _maps: [sq_map, cq_map, sqe_map] })
+    }
+
+    /// Queues `sqe` and has the kernel take it straight away, so the queue
+    /// never holds more than one entry for long.
+    fn submit(&self, sqe: Sqe) -> io::Result<()> {
+        let sq = self.sq.lock();
+        // SAFETY: the pointers are into the live mapping; the tail and the
+        // entries are ours to write while we hold the lock, and the kernel
+        // only reads an entry once the tail has moved past it.
+        unsafe {
+            let tail = (*sq.tail).load(Ordering::Relaxed);
+            let index = tail & sq.mask;
+            sq.sqes.add(index as usize).write(sqe);
+            sq.array.add(index as usize).write(index);
+            (*sq.tail).store(tail.wrapping_add(1), Ordering::Release);
+            let queued = tail.wrapping_add(1).wrapping_sub((*sq.head).load(Ordering::Acquire));
+            enter(&self.fd, queued, 0, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Hands completions to whoever waits on them until the ring is stopped
+    /// and nothing is left in flight.
+    fn reap(&self) {
+        loop {
+            if let Err(e) = enter(&self.fd, 0, 1, ENTER_GETEVENTS) {
+                crate::warn!("URING", "can't wait for completions: {e}");
+                return;
+            }
+            let cq = &self.cq;
+            // SAFETY: the head is ours to move; entries between it and the
+            // kernel's tail are complete and stay put until we do.
+            unsafe {
+                let mut head = (*cq.head).load(Ordering::Relaxed);
+                let tail = (*cq.tail).load(Ordering::Acquire);
+                while head != tail {
+                    let cqe = cq.cqes.add((head & cq.mask) as usize).read();
+                    head = head.wrapping_add(1);
+                    self.complete(cqe);
+                }
+                (*cq.head).store(head, Ordering::Release);
+            }
+            if self.stopping.load(Ordering::Relaxed) && self.pending.lock().is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn complete(&self, cqe: Cqe) {
+        match cqe.user_data {
+            CANCELLED => return,
+            STOP => return self.stopping.store(true, Ordering::Relaxed),
+            _ => {}
+        }
+        let Some(op) = self.pending.lock().remove(&cqe.user_data) else {
+            return;
+        };
+        let Some(done) = op.done else {
+            return;
+        };
+        if let Err((res, _)) = done.send((cqe.res, op.buf)) {
+            // Accepted after the accept was given up on.
+            if op.accept && res >= 0 {
+                // SAFETY: the kernel just made this fd, and nothing else knows it.
+                drop(unsafe { OwnedFd::from_raw_fd(res) });
+            }
+        }
+    }
+}
+
+/// A handle on the ring; the reaping thread stops once the last is dropped.
+#[derive(Clone)]
+pub struct Ring(Arc<Handle>);
+
+struct Handle(Arc<Driver>);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _ = self.0.submit(Sqe { opcode: OP_NOP, user_data: STOP, ..Sqe::default() });
+    }
+}
+
+impl Ring {
+    /// Sets up a ring with room for `entries` submissions at once, and
+    /// starts its reaping thread.
+    pub fn new(entries: u32) -> io::Result<Ring> {
+        let driver = Arc::new(Driver::new(entries)?);
+        let reaper = driver.clone();
+        std::thread::Builder::new().name("uring".into()).spawn(move || reaper.reap())?;
+        Ok(Ring(Arc::new(Handle(driver))))
+    }
+
+    fn driver(&self) -> &Driver {
+        &self.0 .0
+    }
+
+    fn start(&self, mut sqe: Sqe, buf: Vec<u8>, accept: bool) -> Op {
+        let driver = self.driver();
+        let id = driver.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        sqe.user_data = id;
+        // In the map before the kernel sees it, since it may finish at once.
+        driver.pending.lock().insert(id, Pending { done: Some(tx), buf, accept });
+        if let Err(e) = driver.submit(sqe) {
+            // The entry may still go in with a later submission, so its
+            // buffer stays where it is until then.
+            if let Some(done) = driver.pending.lock().get_mut(&id).and_then(|op| op.done.take()) {
+                let _ = done.send((-e.raw_os_error().unwrap_or(libc::EIO), Vec::new()));
+            }
+        }
+        Op { ring: self.clone(), id, done: rx, finished: false }
+    }
+
+    fn accept(&self, fd: RawFd) -> Op {
+        self.start(Sqe { opcode: OP_ACCEPT, fd, op_flags: libc::SOCK_CLOEXEC as u32, ..Sqe::default() }, Vec::new(), true)
+    }
+
+    fn recv(&self, fd: RawFd, mut buf: Vec<u8>, len: usize, flags: i32) -> Op {
+        buf.clear();
+        buf.resize(len, 0);
+        let sqe = Sqe { opcode: OP_RECV, fd, addr: buf.as_mut_ptr() as u64, len: len as u32, op_flags: flags as u32, ..Sqe::default() };
+        self.start(sqe, buf, false)
+    }
+
+    fn send(&self, fd: RawFd, buf: Vec<u8>) -> Op {
+        let sqe = Sqe { opcode: OP_SEND, fd, addr: buf.as_ptr() as u64, len: buf.len() as u32, op_flags: libc::MSG_NOSIGNAL as u32, ..Sqe::default() };
+        self.start(sqe, buf, false)
+    }
+}
+
+/// One submitted operation: its result and buffer once the kernel is done.
+/// Dropping it first cancels it.
+struct Op {
+    ring: Ring,
+    id: u64,
+    done: oneshot::Receiver<(i32, Vec<u8>)>,
+    finished: bool,
+}
+
+impl Future for Op {
+    type Output = (io::Result<usize>, Vec<u8>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = ready!(Pin::new(&mut self.done).poll(cx));
+        self.finished = true;
+        Poll::Ready(match result {
+            Ok((res, buf)) if res < 0 => (Err(io::Error::from_raw_os_error(-res)), buf),
+            Ok((res, buf)) => (Ok(res as usize), buf),
+            Err(_) => (Err(io::Error::other("io_uring driver has stopped")), Vec::new()),
+        })
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.ring.driver().submit(Sqe { opcode: OP_ASYNC_CANCEL, fd: -1, addr: self.id, user_data: CANCELLED, ..Sqe::default() });
+        }
+    }
+}
+
+pub struct Listener {
+    ring: Ring,
+    socket: TcpListener,
+}
+
+impl Listener {
+    pub fn new(ring: Ring, socket: TcpListener) -> Self {
+        Listener { ring, socket }
+    }
+}
+
+impl transport::Listener for Listener {
+    type Stream = Stream;
+
+    async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        let fd = self.ring.accept(self.socket.as_raw_fd()).await.0?;
+        // SAFETY: the kernel just made this fd for us.
+        let socket = unsafe { TcpStream::from_raw_fd(fd as RawFd) };
+        let addr = socket.peer_addr()?;
+        Ok((Stream { ring: self.ring.clone(), socket: Arc::new(socket) }, addr))
+    }
+}
+
+pub struct Stream {
+    ring: Ring,
+    socket: Arc<TcpStream>,
+}
+
+impl transport::Stream for Stream {
+    type Read = ReadHalf;
+    type Write = WriteHalf;
+
+    fn socket(&self) -> SockRef<'_> {
+        SockRef::from(&*self.socket)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    async fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (n, data) = self.ring.recv(self.socket.as_raw_fd(), Vec::new(), buf.len(), libc::MSG_PEEK).await;
+        let n = n?;
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn into_split(self) -> (ReadHalf, WriteHalf) {
+        let read = ReadHalf { ring: self.ring.clone(), socket: self.socket.clone(), op: None, buf: Vec::new(), pos: 0 };
+        (read, WriteHalf { ring: self.ring, socket: self.socket, op: None, spare: Vec::new() })
+    }
+}
+
+pub struct ReadHalf {
+    ring: Ring,
+    socket: Arc<TcpStream>,
+    op: Option<Op>,
+    /// What the last read brought, from `pos` on not yet handed out.
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos == this.buf.len() {
+            let op = match &mut this.op {
+                Some(op) => op,
+                None => {
+                    this.pos = 0;
+                    this.op.insert(this.ring.recv(this.socket.as_raw_fd(), std::mem::take(&mut this.buf), READ_SIZE, 0))
+                }
+            };
+            let (n, mut buf) = ready!(Pin::new(op).poll(cx));
+            this.op = None;
+            let n = n?;
+            buf.truncate(n);
+            (this.buf, this.pos) = (buf, 0);
+        }
+        let n = out.remaining().min(this.buf.len() - this.pos);
+        out.put_slice(&this.buf[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Takes each write whole and sends it in the background; the next write,
+/// flush or shutdown waits for it to have gone.
+pub struct WriteHalf {
+    ring: Ring,
+    socket: Arc<TcpStream>,
+    op: Option<Op>,
+    spare: Vec<u8>,
+}
+
+impl WriteHalf {
+    fn poll_sent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(op) = &mut self.op {
+            let (n, mut buf) = ready!(Pin::new(op).poll(cx));
+            self.op = None;
+            match n? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n if n < buf.len() => {
+                    buf.drain(..n);
+                    self.op = Some(self.ring.send(self.socket.as_raw_fd(), buf));
+                }
+                _ => {
+                    buf.clear();
+                    self.spare = buf;
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_sent(cx))?;
+        let mut buf = std::mem::take(&mut self.spare);
+        buf.extend_from_slice(data);
+        self.op = Some(self.ring.send(self.socket.as_raw_fd(), buf));
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_sent(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_sent(cx))?;
+        Poll::Ready(self.socket.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Listener as _, Stream as _};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn pair(ring: &Ring) -> (Stream, tokio::net::TcpStream) {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        // As the server's is, having been tokio's first.
+        socket.set_nonblocking(true).unwrap();
+        let listener = Listener::new(ring.clone(), socket);
+        let client = tokio::net::TcpStream::connect(listener.socket.local_addr().unwrap());
+        let (accepted, client) = tokio::join!(listener.accept(), client);
+        let (stream, addr) = accepted.unwrap();
+        let client = client.unwrap();
+        assert_eq!(addr, client.local_addr().unwrap());
+        (stream, client)
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes() {
+        let ring = Ring::new(8).unwrap();
+        let (stream, mut client) = pair(&ring).await;
+        client.write_all(b"NICK alice\n").await.unwrap();
+        let mut first = [0u8; 4];
+        assert_eq!(stream.peek(&mut first).await.unwrap(), 4);
+        assert_eq!(&first, b"NICK");
+
+        let (mut reader, mut writer) = stream.into_split();
+        let mut line = [0u8; 11];
+        reader.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"NICK alice\n");
+        // Everything read so far has been handed out, and the next read waits.
+        let late = async {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            client.write_all(b"PING\n").await.unwrap();
+        };
+        let (read, ()) = tokio::join!(reader.read(&mut line), late);
+        assert_eq!(&line[..read.unwrap()], b"PING\n");
+
+        // More than the socket buffers hold, so sends come back short.
+        let big: Vec<u8> = (0..8 << 20).map(|i| i as u8).collect();
+        let sending = async {
+            writer.write_all(&big).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut got = Vec::new();
+        let receiving = client.read_to_end(&mut got);
+        let ((), read) = tokio::join!(sending, receiving);
+        assert_eq!(read.unwrap(), big.len());
+        assert!(got == big);
+
+        drop(client);
+        assert_eq!(reader.read(&mut line).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_dropped_operations() {
+        let ring = Ring::new(8).unwrap();
+        let (stream, _client) = pair(&ring).await;
+        let (mut reader, _writer) = stream.into_split();
+        let mut buf = [0u8; 1];
+        let waited = tokio::time::timeout(std::time::Duration::from_millis(50), reader.read(&mut buf)).await;
+        assert!(waited.is_err());
+        drop(reader);
+
+        let listener = Listener::new(ring.clone(), TcpListener::bind("127.0.0.1:0").unwrap());
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), listener.accept()).await.is_err());
+        // Both cancellations land, and nothing is left for the kernel to finish.
+        for _ in 0..100 {
+            if ring.driver().pending.lock().is_empty() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{} operations still pending", ring.driver().pending.lock().len());
+    }
+}
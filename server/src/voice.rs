@@ -0,0 +1,190 @@
+//! Experimental voice channels. `VOICE JOIN <#chan>` puts a member of the
+//! channel in its voice session and hands them a token; Opus frames they send
+//! to the UDP port with it are relayed to everyone else in the session, and
+//! the channel hears about comings and goings as `Voice` events. The server
+//! never decodes audio. See `protocol::voice` for the datagrams.
+//!
+//! The token stands in for the chat connection it was given to: whoever
+//! sends with it is that member, from wherever they send, so clients behind
+//! NAT can move. It stops working when they leave, part the channel or
+//! disconnect.
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+use protocol::{
+    voice::{self, TOKEN_LEN},
+    Event,
+};
+
+use crate::{random, registry::Registry};
+
+/// Datagrams one member may send a second; Opus sends 50 at 20 ms a frame.
+const MAX_PER_SECOND: u32 = 100;
+
+type Token = [u8; TOKEN_LEN];
+
+struct Member {
+    id: u64,
+    name: String,
+    channel: String,
+    /// Where they last sent from; nothing is relayed to them until they have.
+    addr: Option<SocketAddr>,
+    window: Instant,
+    sent: u32,
+}
+
+#[derive(Default)]
+struct Sessions {
+    members: HashMap<Token, Member>,
+    by_id: HashMap<u64, Token>,
+}
+
+/// The answer to joining a session.
+pub struct Joined {
+    pub token: String,
+    /// The session left for this one, if any.
+    pub left: Option<String>,
+    /// Who was in the session already.
+    pub present: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Voice {
+    sessions: Mutex<Sessions>,
+}
+
+impl Voice {
+    /// Puts connection `id`, called `name`, in `channel`'s session.
+    pub fn join(&self, channel: &str, id: u64, name: &str) -> Joined {
+        let token = random::bytes::<TOKEN_LEN>();
+        let mut sessions = self.sessions.lock();
+        let left = sessions.by_id.remove(&id).and_then(|old| sessions.members.remove(&old)).map(|m| m.channel);
+        let present = sessions.members.values().filter(|m| m.channel == channel).map(|m| m.name.clone()).collect();
+        let member = Member { id, name: name.to_string(), channel: channel.to_string(), addr: None, window: Instant::now(), sent: 0 };
+        sessions.members.insert(token, member);
+        sessions.by_id.insert(id, token);
+        Joined { token: voice::token_hex(&token), left, present }
+    }
+
+    /// Takes connection `id` out of its session, returning which it was.
+    pub fn leave(&self, id: u64) -> Option<String> {
+        let mut sessions = self.sessions.lock();
+        let token = sessions.by_id.remove(&id)?;
+        sessions.members.remove(&token).map(|m| m.channel)
+    }
+
+    /// The session connection `id` is in.
+    pub fn channel_of(&self, id: u64) -> Option<String> {
+        let sessions = self.sessions.lock();
+        sessions.by_id.get(&id).and_then(|t| sessions.members.get(t)).map(|m| m.channel.clone())
+    }
+
+    /// What to send where for a datagram from `from`: nothing if its token
+    /// is unknown, it's only saying where to send to, or its sender is over
+    /// the rate.
+    fn route(&self, packet: &[u8], from: SocketAddr, now: Instant) -> Option<(Vec<u8>, Vec<SocketAddr>)> {
+        let (token, frame) = voice::split_outgoing(packet)?;
+        let mut sessions = self.sessions.lock();
+        let member = sessions.members.get_mut(&token)?;
+        member.addr = Some(from);
+        if now.duration_since(member.window) >= Duration::from_secs(1) {
+            (member.window, member.sent) = (now, 0);
+        }
+        member.sent += 1;
+        if frame.is_empty() || member.sent > MAX_PER_SECOND {
+            return None;
+        }
+        let (id, channel) = (member.id, member.channel.clone());
+        let to = sessions.members.values().filter(|m| m.channel == channel && m.id != id).filter_map(|m| m.addr).collect();
+        Some((voice::relayed(id, frame), to))
+    }
+
+    /// Relays datagrams on `socket` for as long as the server runs.
+    pub async fn run(self: Arc<Self>, socket: UdpSocket) {
+        let mut buf = vec![0; TOKEN_LEN + voice::MAX_FRAME + 1];
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Windows reports an earlier send's ICMP unreachable here.
+                Err(_) => continue,
+            };
+            let Some((packet, to)) = self.route(&buf[..n], from, Instant::now()) else {
+                continue;
+            };
+            for addr in to {
+                let _ = socket.send_to(&packet, addr).await;
+            }
+        }
+    }
+}
+
+/// Takes a connection out of its voice session when it ends, however it
+/// ends, and tells the channel.
+pub struct Seat {
+    pub voice: Arc<Voice>,
+    pub reg: Registry,
+    pub id: u64,
+    pub name: String,
+}
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        let Some(channel) = self.voice.leave(self.id) else {
+            return;
+        };
+        let (reg, name) = (self.reg.clone(), self.name.clone());
+        tokio::spawn(async move {
+            reg.broadcast(&channel, Event::Voice { channel: channel.clone(), name, joined: false }).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relays_within_a_session() {
+        let voice = Voice::default();
+        let addr = |port| SocketAddr::from(([192, 0, 2, 1], port));
+        let alice = voice::parse_token(&voice.join("#standup", 1, "alice").token).unwrap();
+        let bob = voice.join("#standup", 2, "bob");
+        assert_eq!(bob.present, ["alice"]);
+        let bob = voice::parse_token(&bob.token).unwrap();
+        let carol = voice::parse_token(&voice.join("#other", 3, "carol").token).unwrap();
+        let now = Instant::now();
+
+        // Nobody else has said where they are yet.
+        assert_eq!(voice.route(&voice::outgoing(&alice, b"hi"), addr(1), now), Some((voice::relayed(1, b"hi"), vec![])));
+        assert_eq!(voice.route(&voice::outgoing(&bob, b""), addr(2), now), None);
+        voice.route(&voice::outgoing(&carol, b""), addr(3), now);
+        assert_eq!(voice.route(&voice::outgoing(&alice, b"hi"), addr(1), now), Some((voice::relayed(1, b"hi"), vec![addr(2)])));
+        assert_eq!(voice.route(&voice::outgoing(&[0; TOKEN_LEN], b"hi"), addr(9), now), None);
+
+        // Joining elsewhere leaves, and the old token stops working.
+        let moved = voice.join("#other", 2, "bob");
+        assert_eq!((moved.left.as_deref(), moved.present), (Some("#standup"), vec!["carol".to_string()]));
+        assert_eq!(voice.route(&voice::outgoing(&bob, b"hi"), addr(2), now), None);
+        assert_eq!(voice.leave(2).as_deref(), Some("#other"));
+        assert_eq!(voice.leave(2), None);
+    }
+
+    #[test]
+    fn limits_the_rate() {
+        let voice = Voice::default();
+        let token = voice::parse_token(&voice.join("#standup", 1, "alice").token).unwrap();
+        let (from, now) = (SocketAddr::from(([192, 0, 2, 1], 1)), Instant::now());
+        let relayed = (0..MAX_PER_SECOND + 10).filter(|_| voice.route(&voice::outgoing(&token, b"x"), from, now).is_some()).count();
+        assert_eq!(relayed, MAX_PER_SECOND as usize);
+        assert!(voice.route(&voice::outgoing(&token, b"x"), from, now + Duration::from_secs(1)).is_some());
+    }
+}